@@ -18,6 +18,10 @@
 
 #include <algorithm>
 
+#include "fp8_kv_cache.cuh"
+#include "int8_kv_cache.cuh"
+#include "quantized_qk.cuh"
+
 #ifndef USE_ROCM
 #define WARP_SIZE 32
 #else
@@ -27,6 +31,14 @@
 #define MIN(a, b) ((a) < (b) ? (a) : (b))
 #define DIVIDE_ROUND_UP(a, b) (((a) + (b) - 1) / (b))
 
+// Storage format of the KV cache.
+enum class KVCacheDtype {
+  kAuto,      // Same as the attention data type.
+  kFp8E5M2,   // Unscaled FP8 (e5m2).
+  kFp8E4M3,   // FP8 (e4m3) with per-head scales.
+  kInt8,      // INT8 with per-token scales stored in the blocks, see `int8_kv_cache.cuh`.
+};
+
 
 // Utility function for attention softmax.
 template<int NUM_WARPS>
@@ -64,54 +76,128 @@ inline __device__ float block_sum(float* red_smem, float sum) {
   return VLLM_SHFL_SYNC(sum, 0);
 }
 
+// Rotate a key, split into the vectors of a thread as in `paged_attention_kernel`, by `delta`
+// positions of a rotate-half (GPT-NeoX) rotary embedding. Element i of the first half of the head
+// pairs with element i + HEAD_SIZE / 2, which the same thread holds NUM_VECS / 2 vectors later.
+template<typename scalar_t, typename K_vec, int HEAD_SIZE, int VEC_SIZE, int NUM_VECS, int THREAD_GROUP_SIZE>
+inline __device__ void rotate_key(
+  K_vec* k_vecs,
+  const int thread_group_offset,
+  const float delta,
+  const float rope_theta) {
+  static_assert(NUM_VECS % 2 == 0, "The halves of the head must be held by the same thread.");
+#pragma unroll
+  for (int j = 0; j < NUM_VECS / 2; j++) {
+    scalar_t* x1 = reinterpret_cast<scalar_t*>(&k_vecs[j]);
+    scalar_t* x2 = reinterpret_cast<scalar_t*>(&k_vecs[j + NUM_VECS / 2]);
+    const int vec_idx = thread_group_offset + j * THREAD_GROUP_SIZE;
+#pragma unroll
+    for (int e = 0; e < VEC_SIZE; e++) {
+      const int i = vec_idx * VEC_SIZE + e;
+      const float inv_freq = powf(rope_theta, -2.f * i / HEAD_SIZE);
+      float sin, cos;
+      sincosf(delta * inv_freq, &sin, &cos);
+      const float a = to_float(x1[e]);
+      const float b = to_float(x2[e]);
+      from_float(x1[e], a * cos - b * sin);
+      from_float(x2[e], b * cos + a * sin);
+    }
+  }
+}
+
+// The context length seen by query row `query_idx`. With several query tokens per sequence
+// (e.g. the candidates verified by speculative decoding), all of them are already in the cache
+// and the i-th one attends to the first `context_len - num_query_tokens + 1 + i` tokens.
+inline __device__ int query_context_len(
+  const int* __restrict__ context_lens,
+  const int query_idx,
+  const int num_query_tokens) {
+  const int num_later_tokens = num_query_tokens - 1 - query_idx % num_query_tokens;
+  return context_lens[query_idx / num_query_tokens] - num_later_tokens;
+}
+
+// Whether query row `query_idx` skips the context token `token_idx` because of a tree mask. The
+// query tokens of a sequence are then the nodes of a tree of candidates (e.g. of Medusa), and bit
+// j of the mask of the i-th query token is set if it attends to the j-th one, i.e. to itself and
+// its ancestors. The tokens before the query tokens are attended by all of them.
+inline __device__ bool tree_mask_excludes(
+  const int64_t* __restrict__ tree_mask,
+  const int* __restrict__ context_lens,
+  const int query_idx,
+  const int num_query_tokens,
+  const int token_idx) {
+  if (tree_mask == nullptr) {
+    return false;
+  }
+  const int query_start = context_lens[query_idx / num_query_tokens] - num_query_tokens;
+  if (token_idx < query_start) {
+    return false;
+  }
+  const unsigned long long mask = static_cast<unsigned long long>(tree_mask[query_idx % num_query_tokens]);
+  return ((mask >> (token_idx - query_start)) & 1ull) == 0;
+}
+
 // TODO(woosuk): Merge the last two dimensions of the grid.
-// Grid: (num_heads, num_seqs, max_num_partitions).
+// Grid: (num_heads, num_seqs * num_query_tokens, max_num_splits).
 template<
   typename scalar_t,
   typename cache_t,
   int HEAD_SIZE,
   int BLOCK_SIZE,
   int NUM_THREADS,
-  bool IS_FP8_E5M2_KV_CACHE,
+  KVCacheDtype KV_DTYPE,
   int PARTITION_SIZE = 0> // Zero means no partitioning.
 __device__ void paged_attention_kernel(
-  float* __restrict__ exp_sums,           // [num_seqs, num_heads, max_num_partitions]
-  float* __restrict__ max_logits,         // [num_seqs, num_heads, max_num_partitions]
-  scalar_t* __restrict__ out,             // [num_seqs, num_heads, max_num_partitions, head_size]
-  const scalar_t* __restrict__ q,         // [num_seqs, num_heads, head_size]
-  const cache_t* __restrict__ k_cache,    // [num_blocks, num_kv_heads, head_size/x, block_size, x]
-  const cache_t* __restrict__ v_cache,    // [num_blocks, num_kv_heads, head_size, block_size]
+  float* __restrict__ exp_sums,           // [num_queries, num_heads, max_num_splits]
+  float* __restrict__ max_logits,         // [num_queries, num_heads, max_num_splits]
+  scalar_t* __restrict__ out,             // [num_queries, num_heads, max_num_splits, head_size]
+  const scalar_t* __restrict__ q,         // [num_queries, num_heads, head_size]
+  const cache_t* __restrict__ k_cache,    // [num_blocks, num_kv_heads, head_size/x, block_size, x] or NHD
+  const cache_t* __restrict__ v_cache,    // [num_blocks, num_kv_heads, head_size, block_size] or NHD
   const int num_kv_heads,                 // [num_heads]
   const float scale,
   const int* __restrict__ block_tables,   // [num_seqs, max_num_blocks_per_seq]
   const int* __restrict__ context_lens,   // [num_seqs]
   const int max_num_blocks_per_seq,
   const float* __restrict__ alibi_slopes, // [num_heads]
+  const float* __restrict__ kv_scales,    // [2, num_kv_heads], FP8 (e4m3) cache only
+  const int* __restrict__ block_position_shifts, // [num_seqs, max_num_blocks_per_seq]
   const int q_stride,
   const int kv_block_stride,
-  const int kv_head_stride) {
-  const int seq_idx = blockIdx.y;
-  const int partition_idx = blockIdx.z;
-  const int max_num_partitions = gridDim.z;
+  const int kv_head_stride,
+  const int k_token_stride,               // Stride of the tokens of a key block.
+  const int k_x_stride,                   // Stride of the x-element chunks of a key head.
+  const int v_token_stride,               // Stride of the tokens of a value block.
+  const int v_dim_stride,                 // Stride of the elements of a value head.
+  const int sliding_window,               // Zero means no sliding window.
+  const float rope_theta,
+  const float logits_soft_cap,            // Zero means no soft-capping.
+  const int num_query_tokens,             // num_queries = num_seqs * num_query_tokens
+  const int partitions_per_split,         // Partitions merged by each thread block of V2.
+  const int qk_compute,                   // `quantized_qk::QKCompute` of the scores.
+  const int64_t* __restrict__ tree_mask) { // [num_query_tokens], see `tree_mask_excludes`.
+  // The query row, the block table is shared by the query tokens of a sequence.
+  const int query_idx = blockIdx.y;
+  const int seq_idx = query_idx / num_query_tokens;
+  // With split-K (flash-decoding), each thread block of V2 merges the partial softmaxes of
+  // `partitions_per_split` consecutive partitions and writes a single partial output.
+  const int split_idx = blockIdx.z;
+  const int max_num_splits = gridDim.z;
   constexpr bool USE_PARTITIONING = PARTITION_SIZE > 0;
-  const int context_len = context_lens[seq_idx];
-  if (USE_PARTITIONING && partition_idx * PARTITION_SIZE >= context_len) {
+  const int context_len = query_context_len(context_lens, query_idx, num_query_tokens);
+  const int first_partition_idx = USE_PARTITIONING ? split_idx * partitions_per_split : 0;
+  if (USE_PARTITIONING && first_partition_idx * PARTITION_SIZE >= context_len) {
     // No work to do. Terminate the thread block.
     return;
   }
+  const int end_partition_idx = USE_PARTITIONING
+    ? MIN(first_partition_idx + partitions_per_split, DIVIDE_ROUND_UP(context_len, PARTITION_SIZE))
+    : 1;
 
   const int num_context_blocks = DIVIDE_ROUND_UP(context_len, BLOCK_SIZE);
   const int num_blocks_per_partition = USE_PARTITIONING ? PARTITION_SIZE / BLOCK_SIZE : num_context_blocks;
-
-  // [start_block_idx, end_block_idx) is the range of blocks to process.
-  const int start_block_idx = USE_PARTITIONING ? partition_idx * num_blocks_per_partition : 0;
-  const int end_block_idx = MIN(start_block_idx + num_blocks_per_partition, num_context_blocks);
-  const int num_blocks = end_block_idx - start_block_idx;
-
-  // [start_token_idx, end_token_idx) is the range of tokens to process.
-  const int start_token_idx = start_block_idx * BLOCK_SIZE;
-  const int end_token_idx = MIN(start_token_idx + num_blocks * BLOCK_SIZE, context_len);
-  const int num_tokens = end_token_idx - start_token_idx;
+  // Tokens before `window_start` are outside of the sliding window and are masked out.
+  const int window_start = sliding_window > 0 ? MAX(context_len - sliding_window, 0) : 0;
 
   constexpr int THREAD_GROUP_SIZE = MAX(WARP_SIZE / BLOCK_SIZE, 1);
   constexpr int NUM_THREAD_GROUPS = NUM_THREADS / THREAD_GROUP_SIZE; // Note: This assumes THREAD_GROUP_SIZE divides NUM_THREADS
@@ -127,6 +213,9 @@ __device__ void paged_attention_kernel(
   const int num_queries_per_kv = num_heads / num_kv_heads;
   const int kv_head_idx = head_idx / num_queries_per_kv;
   const float alibi_slope = alibi_slopes == nullptr ? 0.f : alibi_slopes[head_idx];
+  // The keys and values of an FP8 (e4m3) cache are stored divided by their per-head scales.
+  const float k_scale = kv_scales == nullptr ? 1.f : kv_scales[kv_head_idx];
+  const float v_scale = kv_scales == nullptr ? 1.f : kv_scales[num_kv_heads + kv_head_idx];
 
   // A vector type to store a part of a key or a query.
   // The vector size is configured in such a way that the threads in a thread group
@@ -139,6 +228,8 @@ __device__ void paged_attention_kernel(
 #ifdef ENABLE_FP8_E5M2
   using Quant_vec = typename Vec<cache_t, VEC_SIZE>::Type;
 #endif
+  using Fp8_K_vec = fp8_e4m3::Fp8Vec<VEC_SIZE>;
+  using Int8_K_vec = int8_kv::Int8Vec<VEC_SIZE>;
 
   constexpr int NUM_ELEMS_PER_THREAD = HEAD_SIZE / THREAD_GROUP_SIZE;
   constexpr int NUM_VECS_PER_THREAD = NUM_ELEMS_PER_THREAD / VEC_SIZE;
@@ -152,7 +243,7 @@ __device__ void paged_attention_kernel(
   // has 0, 4, 8, ... th vectors of the query, and the second thread has 1, 5, 9, ...
   // th vectors of the query, and so on.
   // NOTE(woosuk): Because q is split from a qkv tensor, it may not be contiguous.
-  const scalar_t* q_ptr = q + seq_idx * q_stride + head_idx * HEAD_SIZE;
+  const scalar_t* q_ptr = q + query_idx * q_stride + head_idx * HEAD_SIZE;
   __shared__ Q_vec q_vecs[THREAD_GROUP_SIZE][NUM_VECS_PER_THREAD];
 #pragma unroll
   for (int i = thread_group_idx; i < NUM_VECS_PER_THREAD; i += NUM_THREAD_GROUPS) {
@@ -161,6 +252,35 @@ __device__ void paged_attention_kernel(
   }
   __syncthreads(); // TODO(naed90): possible speedup if this is replaced with a memory wall right before we use q_vecs
 
+  // With quantized scores, the query of the head is quantized with its own scale. Each thread
+  // group offset holds the elements of its query vectors, in the order of `k_vecs`.
+  const quantized_qk::QKCompute qk_mode = static_cast<quantized_qk::QKCompute>(qk_compute);
+  __shared__ __align__(16) uint8_t q_quant[THREAD_GROUP_SIZE][NUM_ELEMS_PER_THREAD];
+  __shared__ float q_quant_scale;
+  if (qk_mode != quantized_qk::QKCompute::kFull) {
+    if (warp_idx == 0) {
+      float absmax = 0.f;
+      for (int i = lane; i < HEAD_SIZE; i += WARP_SIZE) {
+        absmax = fmaxf(absmax, fabsf(to_float(q_ptr[i])));
+      }
+#pragma unroll
+      for (int mask = WARP_SIZE / 2; mask >= 1; mask /= 2) {
+        absmax = fmaxf(absmax, VLLM_SHFL_XOR_SYNC(absmax, mask));
+      }
+      if (lane == 0) {
+        q_quant_scale = quantized_qk::absmax_scale(absmax, qk_mode);
+      }
+    }
+    __syncthreads();
+    for (int i = thread_idx; i < HEAD_SIZE; i += NUM_THREADS) {
+      const int vec_idx = i / VEC_SIZE;
+      q_quant[vec_idx % THREAD_GROUP_SIZE][(vec_idx / THREAD_GROUP_SIZE) * VEC_SIZE + i % VEC_SIZE] =
+        quantized_qk::quantize(to_float(q_ptr[i]), q_quant_scale, qk_mode);
+    }
+    __syncthreads();
+  }
+
+
   // Memory planning.
   extern __shared__ char shared_mem[];
   // NOTE(woosuk): We use FP32 for the softmax logits for better accuracy.
@@ -171,116 +291,6 @@ __device__ void paged_attention_kernel(
   // x == THREAD_GROUP_SIZE * VEC_SIZE
   // Each thread group fetches x elements from the key at a time.
   constexpr int x = 16 / sizeof(cache_t);
-  float qk_max = -FLT_MAX;
-
-  // Iterate over the key blocks.
-  // Each warp fetches a block of keys for each iteration.
-  // Each thread group in a warp fetches a key from the block, and computes
-  // dot product with the query.
-  const int* block_table = block_tables + seq_idx * max_num_blocks_per_seq;
-  for (int block_idx = start_block_idx + warp_idx; block_idx < end_block_idx; block_idx += NUM_WARPS) {
-    // NOTE(woosuk): The block number is stored in int32. However, we cast it to int64
-    // because int32 can lead to overflow when this variable is multiplied by large numbers
-    // (e.g., kv_block_stride).
-    const int64_t physical_block_number = static_cast<int64_t>(block_table[block_idx]);
-
-    // Load a key to registers.
-    // Each thread in a thread group has a different part of the key.
-    // For example, if the the thread group size is 4, then the first thread in the group
-    // has 0, 4, 8, ... th vectors of the key, and the second thread has 1, 5, 9, ... th
-    // vectors of the key, and so on.
-    for (int i = 0; i < NUM_TOKENS_PER_THREAD_GROUP; i++) {
-      const int physical_block_offset = (thread_group_idx + i * WARP_SIZE) % BLOCK_SIZE;
-      const int token_idx = block_idx * BLOCK_SIZE + physical_block_offset;
-      K_vec k_vecs[NUM_VECS_PER_THREAD];
-
-#pragma unroll
-      for (int j = 0; j < NUM_VECS_PER_THREAD; j++) {
-        const cache_t* k_ptr = k_cache + physical_block_number * kv_block_stride
-                                       + kv_head_idx * kv_head_stride
-                                       + physical_block_offset * x;
-        const int vec_idx = thread_group_offset + j * THREAD_GROUP_SIZE;
-        const int offset1 = (vec_idx * VEC_SIZE) / x;
-        const int offset2 = (vec_idx * VEC_SIZE) % x;
-        if constexpr (IS_FP8_E5M2_KV_CACHE) {
-#ifdef ENABLE_FP8_E5M2
-          Quant_vec k_vec_quant = *reinterpret_cast<const Quant_vec*>(k_ptr + offset1 * BLOCK_SIZE * x + offset2);
-          // Vector conversion from Quant_vec to K_vec.
-          k_vecs[j] = fp8_e5m2_unscaled::vec_conversion<K_vec, Quant_vec>(k_vec_quant);
-#else
-          assert(false);
-#endif
-        } else {
-          k_vecs[j] = *reinterpret_cast<const K_vec*>(k_ptr + offset1 * BLOCK_SIZE * x + offset2);
-        }
-      }
-
-      // Compute dot product.
-      // This includes a reduction across the threads in the same thread group.
-      float qk = scale * Qk_dot<scalar_t, THREAD_GROUP_SIZE>::dot(q_vecs[thread_group_offset], k_vecs);
-      // Add the ALiBi bias if slopes are given.
-      qk += (alibi_slope != 0) ? alibi_slope * (token_idx - context_len + 1) : 0;
-
-      if (thread_group_offset == 0) {
-        // Store the partial reductions to shared memory.
-        // NOTE(woosuk): It is required to zero out the masked logits.
-        const bool mask = token_idx >= context_len;
-        logits[token_idx - start_token_idx] = mask ? 0.f : qk;
-        // Update the max value.
-        qk_max = mask ? qk_max : fmaxf(qk_max, qk);
-      }
-    }
-  }
-
-  // Perform reduction across the threads in the same warp to get the
-  // max qk value for each "warp" (not across the thread block yet).
-  // The 0-th thread of each thread group already has its max qk value.
-#pragma unroll
-  for (int mask = WARP_SIZE / 2; mask >= THREAD_GROUP_SIZE; mask /= 2) {
-    qk_max = fmaxf(qk_max, VLLM_SHFL_XOR_SYNC(qk_max, mask));
-  }
-  if (lane == 0) {
-    red_smem[warp_idx] = qk_max;
-  }
-  __syncthreads();
-
-  // TODO(woosuk): Refactor this part.
-  // Get the max qk value for the sequence.
-  qk_max = lane < NUM_WARPS ? red_smem[lane] : -FLT_MAX;
-#pragma unroll
-  for (int mask = NUM_WARPS / 2; mask >= 1; mask /= 2) {
-    qk_max = fmaxf(qk_max, VLLM_SHFL_XOR_SYNC(qk_max, mask));
-  }
-  // Broadcast the max qk value to all threads.
-  qk_max = VLLM_SHFL_SYNC(qk_max, 0);
-
-  // Get the sum of the exp values.
-  float exp_sum = 0.f;
-  for (int i = thread_idx; i < num_tokens; i += NUM_THREADS) {
-    float val = __expf(logits[i] - qk_max);
-    logits[i] = val;
-    exp_sum += val;
-  }
-  exp_sum = block_sum<NUM_WARPS>(&red_smem[NUM_WARPS], exp_sum);
-
-  // Compute softmax.
-  const float inv_sum = __fdividef(1.f, exp_sum + 1e-6f);
-  for (int i = thread_idx; i < num_tokens; i += NUM_THREADS) {
-    logits[i] *= inv_sum;
-  }
-  __syncthreads();
-
-  // If partitioning is enabled, store the max logit and exp_sum.
-  if (USE_PARTITIONING && thread_idx == 0) {
-    float* max_logits_ptr = max_logits + seq_idx * num_heads * max_num_partitions
-                                       + head_idx * max_num_partitions
-                                       + partition_idx;
-    *max_logits_ptr = qk_max;
-    float* exp_sums_ptr = exp_sums + seq_idx * num_heads * max_num_partitions
-                                   + head_idx * max_num_partitions
-                                   + partition_idx;
-    *exp_sums_ptr = exp_sum;
-  }
 
   // Each thread will fetch 16 bytes from the value cache at a time.
   constexpr int V_VEC_SIZE = MIN(16 / sizeof(scalar_t), BLOCK_SIZE);
@@ -289,209 +299,485 @@ __device__ void paged_attention_kernel(
 #ifdef ENABLE_FP8_E5M2
   using V_quant_vec = typename Vec<cache_t, V_VEC_SIZE>::Type;
 #endif
+  using Fp8_V_vec = fp8_e4m3::Fp8Vec<V_VEC_SIZE>;
+  using Int8_V_vec = int8_kv::Int8Vec<V_VEC_SIZE>;
   using Float_L_vec = typename FloatVec<L_vec>::Type;
 
   constexpr int NUM_V_VECS_PER_ROW = BLOCK_SIZE / V_VEC_SIZE;
   constexpr int NUM_ROWS_PER_ITER = WARP_SIZE / NUM_V_VECS_PER_ROW;
   constexpr int NUM_ROWS_PER_THREAD = DIVIDE_ROUND_UP(HEAD_SIZE, NUM_ROWS_PER_ITER);
 
-  // NOTE(woosuk): We use FP32 for the accumulator for better accuracy.
-  float accs[NUM_ROWS_PER_THREAD];
+  // The running softmax of the split over its partitions: the max logit, the exp sum and the
+  // output normalized by the exp sum. Only warp 0 holds the output.
+  float split_max_logit = -FLT_MAX;
+  float split_exp_sum = 0.f;
+  float split_accs[NUM_ROWS_PER_THREAD];
 #pragma unroll
   for (int i = 0; i < NUM_ROWS_PER_THREAD; i++) {
-    accs[i] = 0.f;
+    split_accs[i] = 0.f;
   }
 
   scalar_t zero_value;
   zero(zero_value);
-  for (int block_idx = start_block_idx + warp_idx; block_idx < end_block_idx; block_idx += NUM_WARPS) {
-    // NOTE(woosuk): The block number is stored in int32. However, we cast it to int64
-    // because int32 can lead to overflow when this variable is multiplied by large numbers
-    // (e.g., kv_block_stride).
-    const int64_t physical_block_number = static_cast<int64_t>(block_table[block_idx]);
-    const int physical_block_offset = (lane % NUM_V_VECS_PER_ROW) * V_VEC_SIZE;
-    const int token_idx = block_idx * BLOCK_SIZE + physical_block_offset;
-    L_vec logits_vec;
-    from_float(logits_vec, *reinterpret_cast<Float_L_vec*>(logits + token_idx - start_token_idx));
-
-    const cache_t* v_ptr = v_cache + physical_block_number * kv_block_stride
-                                   + kv_head_idx * kv_head_stride;
+  const int* block_table = block_tables + seq_idx * max_num_blocks_per_seq;
+  for (int partition_idx = first_partition_idx; partition_idx < end_partition_idx; partition_idx++) {
+    // [start_block_idx, end_block_idx) is the range of blocks to process. Blocks which lie
+    // entirely before the sliding window are skipped.
+    const int partition_start_block_idx = partition_idx * num_blocks_per_partition;
+    const int start_block_idx = MAX(partition_start_block_idx, window_start / BLOCK_SIZE);
+    const int end_block_idx = MIN(partition_start_block_idx + num_blocks_per_partition, num_context_blocks);
+    if (start_block_idx >= end_block_idx) {
+      // The whole partition is outside of the sliding window, it does not contribute.
+      continue;
+    }
+    const int num_blocks = end_block_idx - start_block_idx;
+
+    // [start_token_idx, end_token_idx) is the range of tokens to process.
+    const int start_token_idx = start_block_idx * BLOCK_SIZE;
+    const int end_token_idx = MIN(start_token_idx + num_blocks * BLOCK_SIZE, context_len);
+    const int num_tokens = end_token_idx - start_token_idx;
+    float qk_max = -FLT_MAX;
+
+    // Iterate over the key blocks.
+    // Each warp fetches a block of keys for each iteration.
+    // Each thread group in a warp fetches a key from the block, and computes
+    // dot product with the query.
+    for (int block_idx = start_block_idx + warp_idx; block_idx < end_block_idx; block_idx += NUM_WARPS) {
+      // NOTE(woosuk): The block number is stored in int32. However, we cast it to int64
+      // because int32 can lead to overflow when this variable is multiplied by large numbers
+      // (e.g., kv_block_stride).
+      const int64_t physical_block_number = static_cast<int64_t>(block_table[block_idx]);
+
+      // Load a key to registers.
+      // Each thread in a thread group has a different part of the key.
+      // For example, if the the thread group size is 4, then the first thread in the group
+      // has 0, 4, 8, ... th vectors of the key, and the second thread has 1, 5, 9, ... th
+      // vectors of the key, and so on.
+      for (int i = 0; i < NUM_TOKENS_PER_THREAD_GROUP; i++) {
+        const int physical_block_offset = (thread_group_idx + i * WARP_SIZE) % BLOCK_SIZE;
+        const int token_idx = block_idx * BLOCK_SIZE + physical_block_offset;
+        K_vec k_vecs[NUM_VECS_PER_THREAD];
+        float k_token_scale = 1.f;
+        const int shift = block_position_shifts == nullptr
+          ? 0 : block_position_shifts[seq_idx * max_num_blocks_per_seq + block_idx];
+        // The quantized key for quantized scores. The keys of an INT8 cache are scored as stored,
+        // with their per-token scales, unless they have to be rotated first.
+        __align__(16) uint8_t k_quant[NUM_ELEMS_PER_THREAD];
+        const bool raw_int8_keys = KV_DTYPE == KVCacheDtype::kInt8
+          && qk_mode == quantized_qk::QKCompute::kInt8 && shift == 0;
+        if constexpr (KV_DTYPE == KVCacheDtype::kInt8) {
+          const cache_t* k_scales_ptr = k_cache + physical_block_number * kv_block_stride
+                                                + kv_head_idx * kv_head_stride
+                                                + (HEAD_SIZE / x) * BLOCK_SIZE * x;
+          k_token_scale = reinterpret_cast<const float*>(k_scales_ptr)[physical_block_offset];
+        }
+
 #pragma unroll
-    for (int i = 0; i < NUM_ROWS_PER_THREAD; i++) {
-      const int row_idx = lane / NUM_V_VECS_PER_ROW + i * NUM_ROWS_PER_ITER;
-      if (row_idx < HEAD_SIZE) {
-        const int offset = row_idx * BLOCK_SIZE + physical_block_offset;
-        V_vec v_vec;
-        if constexpr (IS_FP8_E5M2_KV_CACHE) {
+        for (int j = 0; j < NUM_VECS_PER_THREAD; j++) {
+          const cache_t* k_ptr = k_cache + physical_block_number * kv_block_stride
+                                         + kv_head_idx * kv_head_stride
+                                         + physical_block_offset * k_token_stride;
+          const int vec_idx = thread_group_offset + j * THREAD_GROUP_SIZE;
+          const int offset1 = (vec_idx * VEC_SIZE) / x;
+          const int offset2 = (vec_idx * VEC_SIZE) % x;
+          if constexpr (KV_DTYPE == KVCacheDtype::kFp8E5M2) {
 #ifdef ENABLE_FP8_E5M2
-          V_quant_vec v_quant_vec = *reinterpret_cast<const V_quant_vec*>(v_ptr + offset);
-          // Vector conversion from V_quant_vec to V_vec.
-          v_vec = fp8_e5m2_unscaled::vec_conversion<V_vec, V_quant_vec>(v_quant_vec);
+            Quant_vec k_vec_quant = *reinterpret_cast<const Quant_vec*>(k_ptr + offset1 * k_x_stride + offset2);
+            // Vector conversion from Quant_vec to K_vec.
+            k_vecs[j] = fp8_e5m2_unscaled::vec_conversion<K_vec, Quant_vec>(k_vec_quant);
 #else
-          assert(false);
+            assert(false);
 #endif
-        } else {
-          v_vec = *reinterpret_cast<const V_vec*>(v_ptr + offset);
+          } else if constexpr (KV_DTYPE == KVCacheDtype::kFp8E4M3) {
+            Fp8_K_vec k_vec_quant = *reinterpret_cast<const Fp8_K_vec*>(k_ptr + offset1 * k_x_stride + offset2);
+            k_vecs[j] = fp8_e4m3::scaled_vec_conversion<scalar_t, K_vec>(k_vec_quant, k_scale);
+          } else if constexpr (KV_DTYPE == KVCacheDtype::kInt8) {
+            Int8_K_vec k_vec_quant = *reinterpret_cast<const Int8_K_vec*>(k_ptr + offset1 * k_x_stride + offset2);
+            if (raw_int8_keys) {
+#pragma unroll
+              for (int e = 0; e < VEC_SIZE; e++) {
+                k_quant[j * VEC_SIZE + e] = static_cast<uint8_t>(k_vec_quant.data[e]);
+              }
+            } else {
+              k_vecs[j] = int8_kv::scaled_vec_conversion<scalar_t, K_vec>(k_vec_quant, k_token_scale);
+            }
+          } else {
+            k_vecs[j] = *reinterpret_cast<const K_vec*>(k_ptr + offset1 * k_x_stride + offset2);
+          }
         }
-        if (block_idx == num_context_blocks - 1) {
-          // NOTE(woosuk): When v_vec contains the tokens that are out of the context,
-          // we should explicitly zero out the values since they may contain NaNs.
-          // See https://github.com/vllm-project/vllm/issues/641#issuecomment-1682544472
-          scalar_t* v_vec_ptr = reinterpret_cast<scalar_t*>(&v_vec);
+
+        // The keys of blocks whose position changed since they were cached (e.g. after evicting
+        // blocks behind attention sinks) are rotated to their current position.
+        if (shift != 0) {
+          rotate_key<scalar_t, K_vec, HEAD_SIZE, VEC_SIZE, NUM_VECS_PER_THREAD, THREAD_GROUP_SIZE>(
+            k_vecs, thread_group_offset, static_cast<float>(-shift), rope_theta);
+        }
+
+        // Compute dot product.
+        // This includes a reduction across the threads in the same thread group.
+        float qk;
+        if (qk_mode == quantized_qk::QKCompute::kFull) {
+          qk = scale * Qk_dot<scalar_t, THREAD_GROUP_SIZE>::dot(q_vecs[thread_group_offset], k_vecs);
+        } else {
+          float k_quant_scale = k_token_scale;
+          if (!raw_int8_keys) {
+            // The keys of a block are held by one warp and share a scale. Padding tokens past the
+            // context are left out of it.
+            float absmax = 0.f;
+            if (token_idx < context_len) {
+#pragma unroll
+              for (int j = 0; j < NUM_VECS_PER_THREAD; j++) {
+                absmax = fmaxf(absmax, quantized_qk::vec_absmax<scalar_t, K_vec, VEC_SIZE>(k_vecs[j]));
+              }
+            }
+#pragma unroll
+            for (int mask = WARP_SIZE / 2; mask >= 1; mask /= 2) {
+              absmax = fmaxf(absmax, VLLM_SHFL_XOR_SYNC(absmax, mask));
+            }
+            k_quant_scale = quantized_qk::absmax_scale(absmax, qk_mode);
+#pragma unroll
+            for (int j = 0; j < NUM_VECS_PER_THREAD; j++) {
+              const scalar_t* k_elems = reinterpret_cast<const scalar_t*>(&k_vecs[j]);
 #pragma unroll
-          for (int j = 0; j < V_VEC_SIZE; j++) {
-            v_vec_ptr[j] = token_idx + j < context_len ? v_vec_ptr[j] : zero_value;
+              for (int e = 0; e < VEC_SIZE; e++) {
+                k_quant[j * VEC_SIZE + e] = quantized_qk::quantize(to_float(k_elems[e]), k_quant_scale, qk_mode);
+              }
+            }
           }
+          qk = scale * q_quant_scale * k_quant_scale
+            * quantized_qk::dot<THREAD_GROUP_SIZE, NUM_ELEMS_PER_THREAD>(q_quant[thread_group_offset], k_quant, qk_mode);
+        }
+        // Soft-cap the scores to (-logits_soft_cap, logits_soft_cap), e.g. for Gemma-2.
+        if (logits_soft_cap > 0.f) {
+          qk = logits_soft_cap * tanhf(qk / logits_soft_cap);
+        }
+        // Add the ALiBi bias if slopes are given.
+        qk += (alibi_slope != 0) ? alibi_slope * (token_idx - context_len + 1) : 0;
+
+        if (thread_group_offset == 0) {
+          // Store the partial reductions to shared memory.
+          // NOTE(woosuk): It is required to zero out the masked logits.
+          // Tokens before the sliding window are within `num_tokens`, their logits are set to
+          // -FLT_MAX so that they vanish in the softmax.
+          // Tokens outside of the tree of candidates of the query token vanish the same way.
+          const bool out_of_window = token_idx < window_start;
+          const bool out_of_tree = token_idx < context_len
+            && tree_mask_excludes(tree_mask, context_lens, query_idx, num_query_tokens, token_idx);
+          const bool mask = token_idx >= context_len || out_of_window || out_of_tree;
+          logits[token_idx - start_token_idx] = out_of_window || out_of_tree ? -FLT_MAX : (mask ? 0.f : qk);
+          // Update the max value.
+          qk_max = mask ? qk_max : fmaxf(qk_max, qk);
         }
-        accs[i] += dot(logits_vec, v_vec);
       }
     }
-  }
 
-  // Perform reduction within each warp.
+    // Perform reduction across the threads in the same warp to get the
+    // max qk value for each "warp" (not across the thread block yet).
+    // The 0-th thread of each thread group already has its max qk value.
 #pragma unroll
-  for (int i = 0; i < NUM_ROWS_PER_THREAD; i++) {
-    float acc = accs[i];
+    for (int mask = WARP_SIZE / 2; mask >= THREAD_GROUP_SIZE; mask /= 2) {
+      qk_max = fmaxf(qk_max, VLLM_SHFL_XOR_SYNC(qk_max, mask));
+    }
+    if (lane == 0) {
+      red_smem[warp_idx] = qk_max;
+    }
+    __syncthreads();
+
+    // TODO(woosuk): Refactor this part.
+    // Get the max qk value for the sequence.
+    qk_max = lane < NUM_WARPS ? red_smem[lane] : -FLT_MAX;
 #pragma unroll
-    for (int mask = NUM_V_VECS_PER_ROW / 2; mask >= 1; mask /= 2) {
-      acc += VLLM_SHFL_XOR_SYNC(acc, mask);
+    for (int mask = NUM_WARPS / 2; mask >= 1; mask /= 2) {
+      qk_max = fmaxf(qk_max, VLLM_SHFL_XOR_SYNC(qk_max, mask));
     }
-    accs[i] = acc;
-  }
+    // Broadcast the max qk value to all threads.
+    qk_max = VLLM_SHFL_SYNC(qk_max, 0);
+
+    // Get the sum of the exp values.
+    float exp_sum = 0.f;
+    for (int i = thread_idx; i < num_tokens; i += NUM_THREADS) {
+      float val = __expf(logits[i] - qk_max);
+      logits[i] = val;
+      exp_sum += val;
+    }
+    exp_sum = block_sum<NUM_WARPS>(&red_smem[NUM_WARPS], exp_sum);
 
-  // NOTE(woosuk): A barrier is required because the shared memory space for logits
-  // is reused for the output.
-  __syncthreads();
+    // Compute softmax.
+    const float inv_sum = __fdividef(1.f, exp_sum + 1e-6f);
+    for (int i = thread_idx; i < num_tokens; i += NUM_THREADS) {
+      logits[i] *= inv_sum;
+    }
+    __syncthreads();
 
-  // Perform reduction across warps.
-  float* out_smem = reinterpret_cast<float*>(shared_mem);
+    // NOTE(woosuk): We use FP32 for the accumulator for better accuracy.
+    float accs[NUM_ROWS_PER_THREAD];
 #pragma unroll
-  for (int i = NUM_WARPS; i > 1; i /= 2) {
-    int mid = i / 2;
-    // Upper warps write to shared memory.
-    if (warp_idx >= mid && warp_idx < i) {
-      float* dst = &out_smem[(warp_idx - mid) * HEAD_SIZE];
+    for (int i = 0; i < NUM_ROWS_PER_THREAD; i++) {
+      accs[i] = 0.f;
+    }
+
+    for (int block_idx = start_block_idx + warp_idx; block_idx < end_block_idx; block_idx += NUM_WARPS) {
+      // NOTE(woosuk): The block number is stored in int32. However, we cast it to int64
+      // because int32 can lead to overflow when this variable is multiplied by large numbers
+      // (e.g., kv_block_stride).
+      const int64_t physical_block_number = static_cast<int64_t>(block_table[block_idx]);
+      const int physical_block_offset = (lane % NUM_V_VECS_PER_ROW) * V_VEC_SIZE;
+      const int token_idx = block_idx * BLOCK_SIZE + physical_block_offset;
+      L_vec logits_vec;
+      from_float(logits_vec, *reinterpret_cast<Float_L_vec*>(logits + token_idx - start_token_idx));
+
+      const cache_t* v_ptr = v_cache + physical_block_number * kv_block_stride
+                                     + kv_head_idx * kv_head_stride;
+      // The INT8 scales of the V_VEC_SIZE tokens of this thread.
+      const float* v_token_scales = reinterpret_cast<const float*>(v_ptr + HEAD_SIZE * BLOCK_SIZE)
+                                    + physical_block_offset;
 #pragma unroll
       for (int i = 0; i < NUM_ROWS_PER_THREAD; i++) {
         const int row_idx = lane / NUM_V_VECS_PER_ROW + i * NUM_ROWS_PER_ITER;
-        if (row_idx < HEAD_SIZE && lane % NUM_V_VECS_PER_ROW == 0) {
-          dst[row_idx] = accs[i];
+        if (row_idx < HEAD_SIZE) {
+          const int offset = row_idx * v_dim_stride + physical_block_offset * v_token_stride;
+          V_vec v_vec;
+          if constexpr (KV_DTYPE == KVCacheDtype::kFp8E5M2) {
+#ifdef ENABLE_FP8_E5M2
+            V_quant_vec v_quant_vec = *reinterpret_cast<const V_quant_vec*>(v_ptr + offset);
+            // Vector conversion from V_quant_vec to V_vec.
+            v_vec = fp8_e5m2_unscaled::vec_conversion<V_vec, V_quant_vec>(v_quant_vec);
+#else
+            assert(false);
+#endif
+          } else if constexpr (KV_DTYPE == KVCacheDtype::kFp8E4M3) {
+            Fp8_V_vec v_vec_quant = *reinterpret_cast<const Fp8_V_vec*>(v_ptr + offset);
+            v_vec = fp8_e4m3::scaled_vec_conversion<scalar_t, V_vec>(v_vec_quant, v_scale);
+          } else if constexpr (KV_DTYPE == KVCacheDtype::kInt8) {
+            Int8_V_vec v_vec_quant = *reinterpret_cast<const Int8_V_vec*>(v_ptr + offset);
+            v_vec = int8_kv::scaled_vec_conversion<scalar_t, V_vec>(v_vec_quant, v_token_scales);
+          } else if (v_token_stride == 1) {
+            v_vec = *reinterpret_cast<const V_vec*>(v_ptr + offset);
+          } else {
+            // The values of consecutive tokens are not contiguous in the NHD layout.
+            scalar_t* v_vec_ptr = reinterpret_cast<scalar_t*>(&v_vec);
+#pragma unroll
+            for (int j = 0; j < V_VEC_SIZE; j++) {
+              v_vec_ptr[j] = v_ptr[offset + j * v_token_stride];
+            }
+          }
+          if (block_idx == num_context_blocks - 1) {
+            // NOTE(woosuk): When v_vec contains the tokens that are out of the context,
+            // we should explicitly zero out the values since they may contain NaNs.
+            // See https://github.com/vllm-project/vllm/issues/641#issuecomment-1682544472
+            scalar_t* v_vec_ptr = reinterpret_cast<scalar_t*>(&v_vec);
+#pragma unroll
+            for (int j = 0; j < V_VEC_SIZE; j++) {
+              v_vec_ptr[j] = token_idx + j < context_len ? v_vec_ptr[j] : zero_value;
+            }
+          }
+          accs[i] += dot(logits_vec, v_vec);
         }
       }
     }
+
+    // Perform reduction within each warp.
+#pragma unroll
+    for (int i = 0; i < NUM_ROWS_PER_THREAD; i++) {
+      float acc = accs[i];
+#pragma unroll
+      for (int mask = NUM_V_VECS_PER_ROW / 2; mask >= 1; mask /= 2) {
+        acc += VLLM_SHFL_XOR_SYNC(acc, mask);
+      }
+      accs[i] = acc;
+    }
+
+    // NOTE(woosuk): A barrier is required because the shared memory space for logits
+    // is reused for the output.
     __syncthreads();
 
-    // Lower warps update the output.
-    if (warp_idx < mid) {
-      const float* src = &out_smem[warp_idx * HEAD_SIZE];
+    // Perform reduction across warps.
+    float* out_smem = reinterpret_cast<float*>(shared_mem);
 #pragma unroll
-      for (int i = 0; i < NUM_ROWS_PER_THREAD; i++) {
-        const int row_idx = lane / NUM_V_VECS_PER_ROW + i * NUM_ROWS_PER_ITER;
-        if (row_idx < HEAD_SIZE && lane % NUM_V_VECS_PER_ROW == 0) {
-          accs[i] += src[row_idx];
+    for (int i = NUM_WARPS; i > 1; i /= 2) {
+      int mid = i / 2;
+      // Upper warps write to shared memory.
+      if (warp_idx >= mid && warp_idx < i) {
+        float* dst = &out_smem[(warp_idx - mid) * HEAD_SIZE];
+#pragma unroll
+        for (int i = 0; i < NUM_ROWS_PER_THREAD; i++) {
+          const int row_idx = lane / NUM_V_VECS_PER_ROW + i * NUM_ROWS_PER_ITER;
+          if (row_idx < HEAD_SIZE && lane % NUM_V_VECS_PER_ROW == 0) {
+            dst[row_idx] = accs[i];
+          }
+        }
+      }
+      __syncthreads();
+
+      // Lower warps update the output.
+      if (warp_idx < mid) {
+        const float* src = &out_smem[warp_idx * HEAD_SIZE];
+#pragma unroll
+        for (int i = 0; i < NUM_ROWS_PER_THREAD; i++) {
+          const int row_idx = lane / NUM_V_VECS_PER_ROW + i * NUM_ROWS_PER_ITER;
+          if (row_idx < HEAD_SIZE && lane % NUM_V_VECS_PER_ROW == 0) {
+            accs[i] += src[row_idx];
+          }
         }
       }
+      __syncthreads();
+    }
+
+    // Merge the partition into the running softmax of the split.
+    if (split_exp_sum == 0.f) {
+      split_max_logit = qk_max;
+      split_exp_sum = exp_sum;
+#pragma unroll
+      for (int i = 0; i < NUM_ROWS_PER_THREAD; i++) {
+        split_accs[i] = accs[i];
+      }
+    } else {
+      const float new_max_logit = fmaxf(split_max_logit, qk_max);
+      const float split_scale = split_exp_sum * __expf(split_max_logit - new_max_logit);
+      const float partition_scale = exp_sum * __expf(qk_max - new_max_logit);
+      split_exp_sum = split_scale + partition_scale;
+      const float inv_split_exp_sum = __fdividef(1.f, split_exp_sum);
+#pragma unroll
+      for (int i = 0; i < NUM_ROWS_PER_THREAD; i++) {
+        split_accs[i] = (split_accs[i] * split_scale + accs[i] * partition_scale) * inv_split_exp_sum;
+      }
+      split_max_logit = new_max_logit;
     }
-    __syncthreads();
+  }
+
+  // If partitioning is enabled, store the max logit and exp_sum of the split. A split whose
+  // partitions are all outside of the sliding window stores an empty softmax.
+  if (USE_PARTITIONING && thread_idx == 0) {
+    const int offset = query_idx * num_heads * max_num_splits + head_idx * max_num_splits + split_idx;
+    max_logits[offset] = split_max_logit;
+    exp_sums[offset] = split_exp_sum;
   }
 
   // Write the final output.
   if (warp_idx == 0) {
-    scalar_t* out_ptr = out + seq_idx * num_heads * max_num_partitions * HEAD_SIZE
-                            + head_idx * max_num_partitions * HEAD_SIZE
-                            + partition_idx * HEAD_SIZE;
+    scalar_t* out_ptr = out + query_idx * num_heads * max_num_splits * HEAD_SIZE
+                            + head_idx * max_num_splits * HEAD_SIZE
+                            + split_idx * HEAD_SIZE;
 #pragma unroll
     for (int i = 0; i < NUM_ROWS_PER_THREAD; i++) {
       const int row_idx = lane / NUM_V_VECS_PER_ROW + i * NUM_ROWS_PER_ITER;
       if (row_idx < HEAD_SIZE && lane % NUM_V_VECS_PER_ROW == 0) {
-        from_float(*(out_ptr + row_idx), accs[i]);
+        from_float(*(out_ptr + row_idx), split_accs[i]);
       }
     }
   }
 }
 
-// Grid: (num_heads, num_seqs, 1).
+// Grid: (num_heads, num_seqs * num_query_tokens, 1).
 template<
   typename scalar_t,
   typename cache_t,
   int HEAD_SIZE,
   int BLOCK_SIZE,
   int NUM_THREADS,
-  bool IS_FP8_E5M2_KV_CACHE>
+  KVCacheDtype KV_DTYPE>
 __global__ void paged_attention_v1_kernel(
-  scalar_t* __restrict__ out,             // [num_seqs, num_heads, head_size]
-  const scalar_t* __restrict__ q,         // [num_seqs, num_heads, head_size]
-  const cache_t* __restrict__ k_cache,    // [num_blocks, num_kv_heads, head_size/x, block_size, x]
-  const cache_t* __restrict__ v_cache,    // [num_blocks, num_kv_heads, head_size, block_size]
+  scalar_t* __restrict__ out,             // [num_queries, num_heads, head_size]
+  const scalar_t* __restrict__ q,         // [num_queries, num_heads, head_size]
+  const cache_t* __restrict__ k_cache,    // [num_blocks, num_kv_heads, head_size/x, block_size, x] or NHD
+  const cache_t* __restrict__ v_cache,    // [num_blocks, num_kv_heads, head_size, block_size] or NHD
   const int num_kv_heads,                 // [num_heads]
   const float scale,
   const int* __restrict__ block_tables,   // [num_seqs, max_num_blocks_per_seq]
   const int* __restrict__ context_lens,   // [num_seqs]
   const int max_num_blocks_per_seq,
   const float* __restrict__ alibi_slopes, // [num_heads]
+  const float* __restrict__ kv_scales,    // [2, num_kv_heads], FP8 (e4m3) cache only
+  const int* __restrict__ block_position_shifts, // [num_seqs, max_num_blocks_per_seq]
   const int q_stride,
   const int kv_block_stride,
-  const int kv_head_stride) {
-  paged_attention_kernel<scalar_t, cache_t, HEAD_SIZE, BLOCK_SIZE, NUM_THREADS, IS_FP8_E5M2_KV_CACHE>(
+  const int kv_head_stride,
+  const int k_token_stride,               // Stride of the tokens of a key block.
+  const int k_x_stride,                   // Stride of the x-element chunks of a key head.
+  const int v_token_stride,               // Stride of the tokens of a value block.
+  const int v_dim_stride,                 // Stride of the elements of a value head.
+  const int sliding_window,               // Zero means no sliding window.
+  const float rope_theta,
+  const float logits_soft_cap,            // Zero means no soft-capping.
+  const int num_query_tokens) {
+  paged_attention_kernel<scalar_t, cache_t, HEAD_SIZE, BLOCK_SIZE, NUM_THREADS, KV_DTYPE>(
     /* exp_sums */ nullptr, /* max_logits */ nullptr,
     out, q, k_cache, v_cache, num_kv_heads, scale, block_tables, context_lens,
-    max_num_blocks_per_seq, alibi_slopes, q_stride, kv_block_stride, kv_head_stride);
+    max_num_blocks_per_seq, alibi_slopes, kv_scales, block_position_shifts, q_stride,
+    kv_block_stride, kv_head_stride, k_token_stride, k_x_stride, v_token_stride, v_dim_stride,
+    sliding_window, rope_theta, logits_soft_cap, num_query_tokens, /* partitions_per_split */ 1,
+    /* qk_compute */ 0, /* tree_mask */ nullptr);
 }
 
-// Grid: (num_heads, num_seqs, max_num_partitions).
+// Grid: (num_heads, num_seqs * num_query_tokens, max_num_splits).
 template<
   typename scalar_t,
   typename cache_t,
   int HEAD_SIZE,
   int BLOCK_SIZE,
   int NUM_THREADS,
-  bool IS_FP8_E5M2_KV_CACHE,
+  KVCacheDtype KV_DTYPE,
   int PARTITION_SIZE>
 __global__ void paged_attention_v2_kernel(
-  float* __restrict__ exp_sums,           // [num_seqs, num_heads, max_num_partitions]
-  float* __restrict__ max_logits,         // [num_seqs, num_heads, max_num_partitions]
-  scalar_t* __restrict__ tmp_out,         // [num_seqs, num_heads, max_num_partitions, head_size]
-  const scalar_t* __restrict__ q,         // [num_seqs, num_heads, head_size]
-  const cache_t* __restrict__ k_cache,    // [num_blocks, num_kv_heads, head_size/x, block_size, x]
-  const cache_t* __restrict__ v_cache,    // [num_blocks, num_kv_heads, head_size, block_size]
+  float* __restrict__ exp_sums,           // [num_queries, num_heads, max_num_splits]
+  float* __restrict__ max_logits,         // [num_queries, num_heads, max_num_splits]
+  scalar_t* __restrict__ tmp_out,         // [num_queries, num_heads, max_num_splits, head_size]
+  const scalar_t* __restrict__ q,         // [num_queries, num_heads, head_size]
+  const cache_t* __restrict__ k_cache,    // [num_blocks, num_kv_heads, head_size/x, block_size, x] or NHD
+  const cache_t* __restrict__ v_cache,    // [num_blocks, num_kv_heads, head_size, block_size] or NHD
   const int num_kv_heads,                 // [num_heads]
   const float scale,
   const int* __restrict__ block_tables,   // [num_seqs, max_num_blocks_per_seq]
   const int* __restrict__ context_lens,   // [num_seqs]
   const int max_num_blocks_per_seq,
   const float* __restrict__ alibi_slopes, // [num_heads]
+  const float* __restrict__ kv_scales,    // [2, num_kv_heads], FP8 (e4m3) cache only
+  const int* __restrict__ block_position_shifts, // [num_seqs, max_num_blocks_per_seq]
   const int q_stride,
   const int kv_block_stride,
-  const int kv_head_stride) {
-  paged_attention_kernel<scalar_t, cache_t, HEAD_SIZE, BLOCK_SIZE, NUM_THREADS, IS_FP8_E5M2_KV_CACHE, PARTITION_SIZE>(
+  const int kv_head_stride,
+  const int k_token_stride,               // Stride of the tokens of a key block.
+  const int k_x_stride,                   // Stride of the x-element chunks of a key head.
+  const int v_token_stride,               // Stride of the tokens of a value block.
+  const int v_dim_stride,                 // Stride of the elements of a value head.
+  const int sliding_window,               // Zero means no sliding window.
+  const float rope_theta,
+  const float logits_soft_cap,            // Zero means no soft-capping.
+  const int num_query_tokens,
+  const int partitions_per_split) {
+  paged_attention_kernel<scalar_t, cache_t, HEAD_SIZE, BLOCK_SIZE, NUM_THREADS, KV_DTYPE, PARTITION_SIZE>(
     exp_sums, max_logits, tmp_out, q, k_cache, v_cache, num_kv_heads, scale,
-    block_tables, context_lens, max_num_blocks_per_seq, alibi_slopes,
-    q_stride, kv_block_stride, kv_head_stride);
+    block_tables, context_lens, max_num_blocks_per_seq, alibi_slopes, kv_scales,
+    block_position_shifts, q_stride, kv_block_stride, kv_head_stride, k_token_stride, k_x_stride,
+    v_token_stride, v_dim_stride, sliding_window, rope_theta, logits_soft_cap, num_query_tokens,
+    partitions_per_split, /* qk_compute */ 0, /* tree_mask */ nullptr);
 }
 
-// Grid: (num_heads, num_seqs).
+// Grid: (num_heads, num_seqs * num_query_tokens).
 template<
   typename scalar_t,
   int HEAD_SIZE,
   int NUM_THREADS,
   int PARTITION_SIZE>
-__global__ void paged_attention_v2_reduce_kernel(
-  scalar_t* __restrict__ out,             // [num_seqs, num_heads, head_size]
-  const float* __restrict__ exp_sums,     // [num_seqs, num_heads, max_num_partitions]
-  const float* __restrict__ max_logits,   // [num_seqs, num_heads, max_num_partitions]
-  const scalar_t* __restrict__ tmp_out,   // [num_seqs, num_heads, max_num_partitions, head_size]
+__device__ void paged_attention_v2_reduce_kernel_impl(
+  scalar_t* __restrict__ out,             // [num_queries, num_heads, head_size]
+  const float* __restrict__ exp_sums,     // [num_queries, num_heads, max_num_splits]
+  const float* __restrict__ max_logits,   // [num_queries, num_heads, max_num_splits]
+  const scalar_t* __restrict__ tmp_out,   // [num_queries, num_heads, max_num_splits, head_size]
   const int* __restrict__ context_lens,   // [num_seqs]
-  const int max_num_partitions) {
+  const int max_num_splits,
+  const int num_query_tokens,
+  const int partitions_per_split) {
   const int num_heads = gridDim.x;
   const int head_idx = blockIdx.x;
-  const int seq_idx = blockIdx.y;
-  const int context_len = context_lens[seq_idx];
-  const int num_partitions = DIVIDE_ROUND_UP(context_len, PARTITION_SIZE);
-  if (num_partitions == 1) {
+  const int query_idx = blockIdx.y;
+  const int context_len = query_context_len(context_lens, query_idx, num_query_tokens);
+  const int num_splits = DIVIDE_ROUND_UP(DIVIDE_ROUND_UP(context_len, PARTITION_SIZE), partitions_per_split);
+  if (num_splits == 1) {
     // No need to reduce. Only copy tmp_out to out.
-    scalar_t* out_ptr = out + seq_idx * num_heads * HEAD_SIZE + head_idx * HEAD_SIZE;
-    const scalar_t* tmp_out_ptr = tmp_out + seq_idx * num_heads * max_num_partitions * HEAD_SIZE
-                                          + head_idx * max_num_partitions * HEAD_SIZE;
+    scalar_t* out_ptr = out + query_idx * num_heads * HEAD_SIZE + head_idx * HEAD_SIZE;
+    const scalar_t* tmp_out_ptr = tmp_out + query_idx * num_heads * max_num_splits * HEAD_SIZE
+                                          + head_idx * max_num_splits * HEAD_SIZE;
     for (int i = threadIdx.x; i < HEAD_SIZE; i += blockDim.x) {
       out_ptr[i] = tmp_out_ptr[i];
     }
@@ -503,17 +789,17 @@ __global__ void paged_attention_v2_reduce_kernel(
   const int warp_idx = threadIdx.x / WARP_SIZE;
   const int lane = threadIdx.x % WARP_SIZE;
 
-  // Size: 2 * num_partitions.
+  // Size: 2 * num_splits.
   extern __shared__ char shared_mem[];
   // Workspace for reduction.
   __shared__ float red_smem[2 * NUM_WARPS];
 
   // Load max logits to shared memory.
   float* shared_max_logits = reinterpret_cast<float*>(shared_mem);
-  const float* max_logits_ptr = max_logits + seq_idx * num_heads * max_num_partitions
-                                           + head_idx * max_num_partitions;
+  const float* max_logits_ptr = max_logits + query_idx * num_heads * max_num_splits
+                                           + head_idx * max_num_splits;
   float max_logit = -FLT_MAX;
-  for (int i = threadIdx.x; i < num_partitions; i += blockDim.x) {
+  for (int i = threadIdx.x; i < num_splits; i += blockDim.x) {
     const float l = max_logits_ptr[i];
     shared_max_logits[i] = l;
     max_logit = fmaxf(max_logit, l);
@@ -540,11 +826,11 @@ __global__ void paged_attention_v2_reduce_kernel(
   max_logit = VLLM_SHFL_SYNC(max_logit, 0);
 
   // Load rescaled exp sums to shared memory.
-  float* shared_exp_sums = reinterpret_cast<float*>(shared_mem + sizeof(float) * num_partitions);
-  const float* exp_sums_ptr = exp_sums + seq_idx * num_heads * max_num_partitions
-                                       + head_idx * max_num_partitions;
+  float* shared_exp_sums = reinterpret_cast<float*>(shared_mem + sizeof(float) * num_splits);
+  const float* exp_sums_ptr = exp_sums + query_idx * num_heads * max_num_splits
+                                       + head_idx * max_num_splits;
   float global_exp_sum = 0.0f;
-  for (int i = threadIdx.x; i < num_partitions; i += blockDim.x) {
+  for (int i = threadIdx.x; i < num_splits; i += blockDim.x) {
     float l = shared_max_logits[i];
     float rescaled_exp_sum = exp_sums_ptr[i] * expf(l - max_logit);
     global_exp_sum += rescaled_exp_sum;
@@ -555,19 +841,137 @@ __global__ void paged_attention_v2_reduce_kernel(
   const float inv_global_exp_sum = __fdividef(1.0f, global_exp_sum + 1e-6f);
 
   // Aggregate tmp_out to out.
-  const scalar_t* tmp_out_ptr = tmp_out + seq_idx * num_heads * max_num_partitions * HEAD_SIZE
-                                        + head_idx * max_num_partitions * HEAD_SIZE;
-  scalar_t* out_ptr = out + seq_idx * num_heads * HEAD_SIZE + head_idx * HEAD_SIZE;
+  const scalar_t* tmp_out_ptr = tmp_out + query_idx * num_heads * max_num_splits * HEAD_SIZE
+                                        + head_idx * max_num_splits * HEAD_SIZE;
+  scalar_t* out_ptr = out + query_idx * num_heads * HEAD_SIZE + head_idx * HEAD_SIZE;
 #pragma unroll
   for (int i = threadIdx.x; i < HEAD_SIZE; i += NUM_THREADS) {
     float acc = 0.0f;
-    for (int j = 0; j < num_partitions; ++j) {
+    for (int j = 0; j < num_splits; ++j) {
       acc += to_float(tmp_out_ptr[j * HEAD_SIZE + i]) * shared_exp_sums[j] * inv_global_exp_sum;
     }
     from_float(out_ptr[i], acc);
   }
 }
 
+// Scalar arguments of the paged attention kernels, packed to keep the entry points below the
+// argument count supported by the launcher.
+// The token, chunk and element strides describe the layout of the cache blocks: keys as
+// [num_blocks, num_kv_heads, head_size/x, block_size, x] and values as
+// [num_blocks, num_kv_heads, head_size, block_size], or both as NHD
+// ([num_blocks, block_size, num_kv_heads, head_size]), which is only used with an unquantized cache.
+struct PagedAttentionParams {
+  int num_kv_heads;
+  float scale;
+  int max_num_blocks_per_seq;
+  int q_stride;
+  int kv_block_stride;
+  int kv_head_stride;
+  int k_token_stride;
+  int k_x_stride;
+  int v_token_stride;
+  int v_dim_stride;
+  int sliding_window;
+  float rope_theta;
+  float logits_soft_cap;
+  int num_query_tokens;
+  // Number of consecutive partitions merged by each thread block of V2 (split-K).
+  int partitions_per_split;
+  // `quantized_qk::QKCompute` of the attention scores.
+  int qk_compute;
+  // The tree mask of the query tokens, null for causal attention, see `tree_mask_excludes`.
+  const int64_t* tree_mask;
+};
+
+#define PAGED_ATTENTION_NUM_THREADS 128
+#define PAGED_ATTENTION_PARTITION_SIZE 512
+
+// Entry points are named `<kernel>_<dtype>_h<head size>_b<block size>[_fp8_e5m2|_fp8_e4m3|_int8]`, and
+// `paged_attention_v2_reduce_kernel_<dtype>_h<head size>`.
+#define INSTANTIATE_PAGED_ATTENTION(NAME, T, CACHE_T, HEAD_SIZE, BLOCK_SIZE, KV_DTYPE, SUFFIX)             \
+  extern "C" __global__ void paged_attention_v1_kernel_##NAME##_h##HEAD_SIZE##_b##BLOCK_SIZE##SUFFIX(      \
+    T* __restrict__ out,                                                                                   \
+    const T* __restrict__ q,                                                                               \
+    const CACHE_T* __restrict__ k_cache,                                                                   \
+    const CACHE_T* __restrict__ v_cache,                                                                   \
+    const int* __restrict__ block_tables,                                                                  \
+    const int* __restrict__ context_lens,                                                                  \
+    const float* __restrict__ alibi_slopes,                                                                \
+    const float* __restrict__ kv_scales,                                                                   \
+    const int* __restrict__ block_position_shifts,                                                         \
+    const PagedAttentionParams params) {                                                                   \
+    paged_attention_kernel<T, CACHE_T, HEAD_SIZE, BLOCK_SIZE, PAGED_ATTENTION_NUM_THREADS,                 \
+      KV_DTYPE>(                                                                                           \
+      nullptr, nullptr, out, q, k_cache, v_cache, params.num_kv_heads, params.scale, block_tables,         \
+      context_lens, params.max_num_blocks_per_seq, alibi_slopes, kv_scales, block_position_shifts,         \
+      params.q_stride, params.kv_block_stride, params.kv_head_stride, params.k_token_stride,               \
+      params.k_x_stride, params.v_token_stride, params.v_dim_stride, params.sliding_window,                \
+      params.rope_theta, params.logits_soft_cap, params.num_query_tokens, 1, params.qk_compute,            \
+      params.tree_mask);                                                                                   \
+  }                                                                                                        \
+  extern "C" __global__ void paged_attention_v2_kernel_##NAME##_h##HEAD_SIZE##_b##BLOCK_SIZE##SUFFIX(      \
+    float* __restrict__ exp_sums,                                                                          \
+    float* __restrict__ max_logits,                                                                        \
+    T* __restrict__ tmp_out,                                                                               \
+    const T* __restrict__ q,                                                                               \
+    const CACHE_T* __restrict__ k_cache,                                                                   \
+    const CACHE_T* __restrict__ v_cache,                                                                   \
+    const int* __restrict__ block_tables,                                                                  \
+    const int* __restrict__ context_lens,                                                                  \
+    const float* __restrict__ alibi_slopes,                                                                \
+    const float* __restrict__ kv_scales,                                                                   \
+    const int* __restrict__ block_position_shifts,                                                         \
+    const PagedAttentionParams params) {                                                                   \
+    paged_attention_kernel<T, CACHE_T, HEAD_SIZE, BLOCK_SIZE, PAGED_ATTENTION_NUM_THREADS,                 \
+      KV_DTYPE, PAGED_ATTENTION_PARTITION_SIZE>(                                                           \
+      exp_sums, max_logits, tmp_out, q, k_cache, v_cache, params.num_kv_heads, params.scale,               \
+      block_tables, context_lens, params.max_num_blocks_per_seq, alibi_slopes, kv_scales,                  \
+      block_position_shifts, params.q_stride, params.kv_block_stride, params.kv_head_stride,               \
+      params.k_token_stride, params.k_x_stride, params.v_token_stride, params.v_dim_stride,                \
+      params.sliding_window, params.rope_theta, params.logits_soft_cap, params.num_query_tokens,           \
+      params.partitions_per_split, params.qk_compute, params.tree_mask);                                   \
+  }
+
+#define INSTANTIATE_PAGED_ATTENTION_REDUCE(NAME, T, HEAD_SIZE)                                             \
+  extern "C" __global__ void paged_attention_v2_reduce_kernel_##NAME##_h##HEAD_SIZE(                       \
+    T* __restrict__ out,                                                                                   \
+    const float* __restrict__ exp_sums,                                                                    \
+    const float* __restrict__ max_logits,                                                                  \
+    const T* __restrict__ tmp_out,                                                                         \
+    const int* __restrict__ context_lens,                                                                  \
+    const int max_num_splits,                                                                              \
+    const int num_query_tokens,                                                                            \
+    const int partitions_per_split) {                                                                      \
+    paged_attention_v2_reduce_kernel_impl<T, HEAD_SIZE, PAGED_ATTENTION_NUM_THREADS,                       \
+      PAGED_ATTENTION_PARTITION_SIZE>(out, exp_sums, max_logits, tmp_out, context_lens,                    \
+      max_num_splits, num_query_tokens, partitions_per_split);                                             \
+  }
+
+#define INSTANTIATE_PAGED_ATTENTION_BLOCK_SIZES(NAME, T, CACHE_T, HEAD_SIZE, KV_DTYPE, SUFFIX)             \
+  INSTANTIATE_PAGED_ATTENTION(NAME, T, CACHE_T, HEAD_SIZE, 8, KV_DTYPE, SUFFIX)                            \
+  INSTANTIATE_PAGED_ATTENTION(NAME, T, CACHE_T, HEAD_SIZE, 16, KV_DTYPE, SUFFIX)                           \
+  INSTANTIATE_PAGED_ATTENTION(NAME, T, CACHE_T, HEAD_SIZE, 32, KV_DTYPE, SUFFIX)
+
+#define INSTANTIATE_PAGED_ATTENTION_HEAD_SIZE(NAME, T, HEAD_SIZE)                                          \
+  INSTANTIATE_PAGED_ATTENTION_BLOCK_SIZES(NAME, T, T, HEAD_SIZE, KVCacheDtype::kAuto, )                    \
+  INSTANTIATE_PAGED_ATTENTION_BLOCK_SIZES(NAME, T, uint8_t, HEAD_SIZE, KVCacheDtype::kFp8E5M2, _fp8_e5m2)  \
+  INSTANTIATE_PAGED_ATTENTION_BLOCK_SIZES(NAME, T, uint8_t, HEAD_SIZE, KVCacheDtype::kFp8E4M3, _fp8_e4m3)  \
+  INSTANTIATE_PAGED_ATTENTION_BLOCK_SIZES(NAME, T, uint8_t, HEAD_SIZE, KVCacheDtype::kInt8, _int8)         \
+  INSTANTIATE_PAGED_ATTENTION_REDUCE(NAME, T, HEAD_SIZE)
+
+#define INSTANTIATE_PAGED_ATTENTION_HEAD_SIZES(NAME, T)                                                    \
+  INSTANTIATE_PAGED_ATTENTION_HEAD_SIZE(NAME, T, 64)                                                       \
+  INSTANTIATE_PAGED_ATTENTION_HEAD_SIZE(NAME, T, 80)                                                       \
+  INSTANTIATE_PAGED_ATTENTION_HEAD_SIZE(NAME, T, 96)                                                       \
+  INSTANTIATE_PAGED_ATTENTION_HEAD_SIZE(NAME, T, 112)                                                      \
+  INSTANTIATE_PAGED_ATTENTION_HEAD_SIZE(NAME, T, 128)                                                      \
+  INSTANTIATE_PAGED_ATTENTION_HEAD_SIZE(NAME, T, 192)                                                      \
+  INSTANTIATE_PAGED_ATTENTION_HEAD_SIZE(NAME, T, 256)
+
+INSTANTIATE_PAGED_ATTENTION_HEAD_SIZES(f32, float)
+INSTANTIATE_PAGED_ATTENTION_HEAD_SIZES(f16, uint16_t)
+INSTANTIATE_PAGED_ATTENTION_HEAD_SIZES(bf16, __nv_bfloat16)
+
 #undef WARP_SIZE
 #undef MAX
 #undef MIN
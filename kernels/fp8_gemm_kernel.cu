@@ -0,0 +1,144 @@
+#include <stdint.h>
+#include <cuda_fp16.h>
+#include <cuda_bf16.h>
+#include <cuda_fp8.h>
+
+#define TILE_SIZE 16
+
+inline __device__ float fp8_e4m3_to_float(uint8_t x) {
+  __half_raw raw = __nv_cvt_fp8_to_halfraw(x, __NV_E4M3);
+  return __half2float(__half(raw));
+}
+
+// Round to the nearest e4m3 value, saturating at +-448.
+inline __device__ float round_to_fp8_e4m3(float x) {
+  return fp8_e4m3_to_float(__nv_cvt_float_to_fp8(x, __NV_SATFINITE, __NV_E4M3));
+}
+
+inline __device__ float to_float(float x) { return x; }
+inline __device__ float to_float(__half x) { return __half2float(x); }
+inline __device__ float to_float(__nv_bfloat16 x) { return __bfloat162float(x); }
+
+inline __device__ void from_float(float& dst, float x) { dst = x; }
+inline __device__ void from_float(__half& dst, float x) { dst = __float2half(x); }
+inline __device__ void from_float(__nv_bfloat16& dst, float x) { dst = __float2bfloat16(x); }
+
+// out = x @ (dequant(weight) * weight_scale)^T, dequantizing the FP8 (e4m3) weight tile by tile
+// in shared memory. On sm_89+ the e4m3 conversion is a single hardware instruction.
+template<typename scalar_t>
+__device__ void fp8_gemm(
+  const scalar_t* __restrict__ x,         // [m, k]
+  const uint8_t* __restrict__ weight,     // [n, k]
+  const float* __restrict__ weight_scale, // [n] if per_channel else [1]
+  scalar_t* __restrict__ out,             // [m, n]
+  const int m,
+  const int n,
+  const int k,
+  const bool per_channel) {
+  __shared__ float x_tile[TILE_SIZE][TILE_SIZE];
+  __shared__ float w_tile[TILE_SIZE][TILE_SIZE];
+
+  const int row = blockIdx.y * TILE_SIZE + threadIdx.y;
+  const int col = blockIdx.x * TILE_SIZE + threadIdx.x;
+  const int w_row = blockIdx.x * TILE_SIZE + threadIdx.y;
+
+  float acc = 0.f;
+  for (int tile = 0; tile < k; tile += TILE_SIZE) {
+    const int x_col = tile + threadIdx.x;
+    x_tile[threadIdx.y][threadIdx.x] = (row < m && x_col < k) ? to_float(x[(int64_t)row * k + x_col]) : 0.f;
+    w_tile[threadIdx.y][threadIdx.x] = (w_row < n && x_col < k) ? fp8_e4m3_to_float(weight[(int64_t)w_row * k + x_col]) : 0.f;
+    __syncthreads();
+
+#pragma unroll
+    for (int i = 0; i < TILE_SIZE; ++i) {
+      acc += x_tile[threadIdx.y][i] * w_tile[threadIdx.x][i];
+    }
+    __syncthreads();
+  }
+
+  if (row < m && col < n) {
+    const float scale = per_channel ? weight_scale[col] : weight_scale[0];
+    from_float(out[(int64_t)row * n + col], acc * scale);
+  }
+}
+
+// W8A8: out = (quant(x / x_scale) @ dequant(weight)^T) * x_scale * weight_scale, quantizing the
+// activations to FP8 (e4m3) with a per-token or per-tensor scale while loading their tile, so
+// both operands of the product are FP8 values as in the FP8 GEMM of the checkpoint's calibration.
+template<typename scalar_t>
+__device__ void fp8_w8a8_gemm(
+  const scalar_t* __restrict__ x,         // [m, k]
+  const float* __restrict__ x_scale,      // [m] if per_token else [1]
+  const uint8_t* __restrict__ weight,     // [n, k]
+  const float* __restrict__ weight_scale, // [n] if per_channel else [1]
+  scalar_t* __restrict__ out,             // [m, n]
+  const int m,
+  const int n,
+  const int k,
+  const bool per_token,
+  const bool per_channel) {
+  __shared__ float x_tile[TILE_SIZE][TILE_SIZE];
+  __shared__ float w_tile[TILE_SIZE][TILE_SIZE];
+
+  const int row = blockIdx.y * TILE_SIZE + threadIdx.y;
+  const int col = blockIdx.x * TILE_SIZE + threadIdx.x;
+  const int w_row = blockIdx.x * TILE_SIZE + threadIdx.y;
+  const float row_scale = row < m ? (per_token ? x_scale[row] : x_scale[0]) : 0.f;
+  const float inv_row_scale = row_scale > 0.f ? 1.f / row_scale : 0.f;
+
+  float acc = 0.f;
+  for (int tile = 0; tile < k; tile += TILE_SIZE) {
+    const int x_col = tile + threadIdx.x;
+    x_tile[threadIdx.y][threadIdx.x] = (row < m && x_col < k) ? round_to_fp8_e4m3(to_float(x[(int64_t)row * k + x_col]) * inv_row_scale) : 0.f;
+    w_tile[threadIdx.y][threadIdx.x] = (w_row < n && x_col < k) ? fp8_e4m3_to_float(weight[(int64_t)w_row * k + x_col]) : 0.f;
+    __syncthreads();
+
+#pragma unroll
+    for (int i = 0; i < TILE_SIZE; ++i) {
+      acc += x_tile[threadIdx.y][i] * w_tile[threadIdx.x][i];
+    }
+    __syncthreads();
+  }
+
+  if (row < m && col < n) {
+    const float scale = per_channel ? weight_scale[col] : weight_scale[0];
+    from_float(out[(int64_t)row * n + col], acc * row_scale * scale);
+  }
+}
+
+#define DEFINE_FP8_GEMM_KERNEL(suffix, scalar_t)                                 \
+  extern "C" __global__ void fp8_gemm_kernel_##suffix(                         \
+    const scalar_t* __restrict__ x,                                            \
+    const uint8_t* __restrict__ weight,                                        \
+    const float* __restrict__ weight_scale,                                    \
+    scalar_t* __restrict__ out,                                                \
+    const int m,                                                               \
+    const int n,                                                               \
+    const int k,                                                               \
+    const bool per_channel) {                                                  \
+    fp8_gemm<scalar_t>(x, weight, weight_scale, out, m, n, k, per_channel);    \
+  }
+
+DEFINE_FP8_GEMM_KERNEL(f32, float)
+DEFINE_FP8_GEMM_KERNEL(f16, __half)
+DEFINE_FP8_GEMM_KERNEL(bf16, __nv_bfloat16)
+
+#define DEFINE_FP8_W8A8_GEMM_KERNEL(suffix, scalar_t)                            \
+  extern "C" __global__ void fp8_w8a8_gemm_kernel_##suffix(                    \
+    const scalar_t* __restrict__ x,                                            \
+    const float* __restrict__ x_scale,                                         \
+    const uint8_t* __restrict__ weight,                                        \
+    const float* __restrict__ weight_scale,                                    \
+    scalar_t* __restrict__ out,                                                \
+    const int m,                                                               \
+    const int n,                                                               \
+    const int k,                                                               \
+    const bool per_token,                                                      \
+    const bool per_channel) {                                                  \
+    fp8_w8a8_gemm<scalar_t>(                                                   \
+      x, x_scale, weight, weight_scale, out, m, n, k, per_token, per_channel); \
+  }
+
+DEFINE_FP8_W8A8_GEMM_KERNEL(f32, float)
+DEFINE_FP8_W8A8_GEMM_KERNEL(f16, __half)
+DEFINE_FP8_W8A8_GEMM_KERNEL(bf16, __nv_bfloat16)
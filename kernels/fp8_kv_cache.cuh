@@ -0,0 +1,50 @@
+#pragma once
+
+// Conversions between the FP8 (e4m3) KV cache and the attention data types. Values are stored
+// divided by a per-head scale so that the range of each head fits into e4m3 (max 448).
+
+#include <stdint.h>
+#include <cuda_fp16.h>
+#include <cuda_bf16.h>
+#include <cuda_fp8.h>
+
+namespace fp8_e4m3 {
+
+// A vector of `N` e4m3 values, loaded from the cache with a single access.
+template<int N>
+struct alignas(N) Fp8Vec {
+  uint8_t data[N];
+};
+
+inline __device__ uint8_t quantize(const float x, const float scale) {
+  return static_cast<uint8_t>(__nv_cvt_float_to_fp8(x / scale, __NV_SATFINITE, __NV_E4M3));
+}
+
+inline __device__ float dequantize(const uint8_t x, const float scale) {
+  const __half_raw raw = __nv_cvt_fp8_to_halfraw(static_cast<__nv_fp8_storage_t>(x), __NV_E4M3);
+  return __half2float(__half(raw)) * scale;
+}
+
+// f16 values are passed around as their raw bits.
+inline __device__ float to_float(const float x) { return x; }
+inline __device__ float to_float(const uint16_t x) { return __half2float(__ushort_as_half(x)); }
+inline __device__ float to_float(const __nv_bfloat16 x) { return __bfloat162float(x); }
+
+inline __device__ void from_float(float& dst, const float x) { dst = x; }
+inline __device__ void from_float(uint16_t& dst, const float x) { dst = __half_as_ushort(__float2half(x)); }
+inline __device__ void from_float(__nv_bfloat16& dst, const float x) { dst = __float2bfloat16(x); }
+
+// Dequantize `N` e4m3 values into `Tout`, a vector of `N` `scalar_t`.
+template<typename scalar_t, typename Tout, int N>
+inline __device__ Tout scaled_vec_conversion(const Fp8Vec<N>& x, const float scale) {
+  static_assert(sizeof(Tout) == N * sizeof(scalar_t), "Mismatched vector sizes.");
+  Tout out;
+  scalar_t* out_ptr = reinterpret_cast<scalar_t*>(&out);
+#pragma unroll
+  for (int i = 0; i < N; i++) {
+    from_float(out_ptr[i], dequantize(x.data[i], scale));
+  }
+  return out;
+}
+
+} // namespace fp8_e4m3
@@ -0,0 +1,85 @@
+#include <stdint.h>
+#include <cuda_fp16.h>
+#include <cuda_bf16.h>
+
+#define TILE_SIZE 16
+
+inline __device__ float to_float(float x) { return x; }
+inline __device__ float to_float(__half x) { return __half2float(x); }
+inline __device__ float to_float(__nv_bfloat16 x) { return __bfloat162float(x); }
+
+inline __device__ void from_float(float& dst, float x) { dst = x; }
+inline __device__ void from_float(__half& dst, float x) { dst = __float2half(x); }
+inline __device__ void from_float(__nv_bfloat16& dst, float x) { dst = __float2bfloat16(x); }
+
+// Dequantize the 4-bit weight of input row `k_idx` and output column `n_idx`. Eight values are
+// packed into each 32-bit word along the input dimension, the GPTQ layout. AWQ weights are
+// repacked at load time.
+inline __device__ float int4_dequant(
+  const uint32_t* __restrict__ qweight,
+  const float* __restrict__ zeros,
+  const float* __restrict__ scales,
+  const uint32_t* __restrict__ g_idx,
+  const int n,
+  const int k_idx,
+  const int n_idx) {
+  const int64_t group = g_idx[k_idx];
+  const int q = (qweight[(int64_t)(k_idx / 8) * n + n_idx] >> ((k_idx % 8) * 4)) & 0xF;
+  return ((float)q - zeros[group * n + n_idx]) * scales[group * n + n_idx];
+}
+
+// out = x @ dequant(weight), dequantizing the 4-bit weight tile by tile in shared memory.
+template<typename scalar_t>
+__device__ void int4_gemm(
+  const scalar_t* __restrict__ x,       // [m, k]
+  const uint32_t* __restrict__ qweight, // [k / 8, n]
+  const float* __restrict__ zeros,      // [num_groups, n]
+  const float* __restrict__ scales,     // [num_groups, n]
+  const uint32_t* __restrict__ g_idx,   // [k], the group of each input row
+  scalar_t* __restrict__ out,           // [m, n]
+  const int m,
+  const int n,
+  const int k) {
+  __shared__ float x_tile[TILE_SIZE][TILE_SIZE];
+  __shared__ float w_tile[TILE_SIZE][TILE_SIZE];
+
+  const int row = blockIdx.y * TILE_SIZE + threadIdx.y;
+  const int col = blockIdx.x * TILE_SIZE + threadIdx.x;
+  const int w_col = blockIdx.x * TILE_SIZE + threadIdx.y;
+
+  float acc = 0.f;
+  for (int tile = 0; tile < k; tile += TILE_SIZE) {
+    const int x_col = tile + threadIdx.x;
+    x_tile[threadIdx.y][threadIdx.x] = (row < m && x_col < k) ? to_float(x[(int64_t)row * k + x_col]) : 0.f;
+    w_tile[threadIdx.y][threadIdx.x] = (w_col < n && x_col < k) ? int4_dequant(qweight, zeros, scales, g_idx, n, x_col, w_col) : 0.f;
+    __syncthreads();
+
+#pragma unroll
+    for (int i = 0; i < TILE_SIZE; ++i) {
+      acc += x_tile[threadIdx.y][i] * w_tile[threadIdx.x][i];
+    }
+    __syncthreads();
+  }
+
+  if (row < m && col < n) {
+    from_float(out[(int64_t)row * n + col], acc);
+  }
+}
+
+#define DEFINE_INT4_GEMM_KERNEL(suffix, scalar_t)                                \
+  extern "C" __global__ void int4_gemm_kernel_##suffix(                        \
+    const scalar_t* __restrict__ x,                                            \
+    const uint32_t* __restrict__ qweight,                                      \
+    const float* __restrict__ zeros,                                           \
+    const float* __restrict__ scales,                                          \
+    const uint32_t* __restrict__ g_idx,                                        \
+    scalar_t* __restrict__ out,                                                \
+    const int m,                                                               \
+    const int n,                                                               \
+    const int k) {                                                             \
+    int4_gemm<scalar_t>(x, qweight, zeros, scales, g_idx, out, m, n, k);       \
+  }
+
+DEFINE_INT4_GEMM_KERNEL(f32, float)
+DEFINE_INT4_GEMM_KERNEL(f16, __half)
+DEFINE_INT4_GEMM_KERNEL(bf16, __nv_bfloat16)
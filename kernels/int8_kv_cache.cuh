@@ -0,0 +1,72 @@
+#pragma once
+
+// Conversions between the INT8 KV cache and the attention data types. Each token and head is
+// quantized symmetrically with its own scale (absmax / 127), which is computed when the token is
+// written, so no calibration is needed.
+//
+// The f32 scales of a block are stored alongside its values: the key blocks have one extra row
+// of x (= 16) bytes per head, and the value blocks x extra byte rows per head, so that both
+// caches have the same strides. The first 4 * block_size bytes of these rows hold the scales of
+// the tokens of the block.
+
+#include <stdint.h>
+
+#include "fp8_kv_cache.cuh"
+
+namespace int8_kv {
+
+using fp8_e4m3::from_float;
+using fp8_e4m3::to_float;
+
+// A vector of `N` int8 values, loaded from the cache with a single access.
+template<int N>
+struct alignas(N) Int8Vec {
+  int8_t data[N];
+};
+
+inline __device__ uint8_t quantize(const float x, const float scale) {
+  const float q = fminf(fmaxf(rintf(x / scale), -127.f), 127.f);
+  return static_cast<uint8_t>(static_cast<int8_t>(q));
+}
+
+// The scale of the `n` values at `x`, reduced across the calling warp.
+template<typename scalar_t>
+inline __device__ float absmax_scale(const scalar_t* x, const int n, const int lane, const int warp_size) {
+  float absmax = 0.f;
+  for (int i = lane; i < n; i += warp_size) {
+    absmax = fmaxf(absmax, fabsf(to_float(x[i])));
+  }
+#pragma unroll
+  for (int mask = 16; mask >= 1; mask /= 2) {
+    absmax = fmaxf(absmax, __shfl_xor_sync(uint32_t(-1), absmax, mask));
+  }
+  return absmax > 0.f ? absmax / 127.f : 1.f;
+}
+
+// Dequantize `N` int8 values of one token into `Tout`, a vector of `N` `scalar_t`.
+template<typename scalar_t, typename Tout, int N>
+inline __device__ Tout scaled_vec_conversion(const Int8Vec<N>& x, const float scale) {
+  static_assert(sizeof(Tout) == N * sizeof(scalar_t), "Mismatched vector sizes.");
+  Tout out;
+  scalar_t* out_ptr = reinterpret_cast<scalar_t*>(&out);
+#pragma unroll
+  for (int i = 0; i < N; i++) {
+    from_float(out_ptr[i], static_cast<float>(x.data[i]) * scale);
+  }
+  return out;
+}
+
+// Dequantize `N` int8 values of consecutive tokens, each with its own scale.
+template<typename scalar_t, typename Tout, int N>
+inline __device__ Tout scaled_vec_conversion(const Int8Vec<N>& x, const float* scales) {
+  static_assert(sizeof(Tout) == N * sizeof(scalar_t), "Mismatched vector sizes.");
+  Tout out;
+  scalar_t* out_ptr = reinterpret_cast<scalar_t*>(&out);
+#pragma unroll
+  for (int i = 0; i < N; i++) {
+    from_float(out_ptr[i], static_cast<float>(x.data[i]) * scales[i]);
+  }
+  return out;
+}
+
+} // namespace int8_kv
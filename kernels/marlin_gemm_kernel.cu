@@ -0,0 +1,120 @@
+#include <stdint.h>
+#include <cuda_fp16.h>
+#include <cuda_bf16.h>
+#include <mma.h>
+
+using namespace nvcuda;
+
+// The tile of the output computed by a block of 4 warps, each warp computing 16 x 32 with two
+// 16 x 16 x 16 tensor core fragments.
+#define BLOCK_M 32
+#define BLOCK_N 64
+#define BLOCK_K 32
+#define NUM_THREADS 128
+
+inline __device__ void from_float(__half& dst, float x) { dst = __float2half(x); }
+inline __device__ void from_float(__nv_bfloat16& dst, float x) { dst = __float2bfloat16(x); }
+
+// Marlin-style INT4 x FP16/BF16 GEMM: out = x @ dequant(qweight). The weight is repacked at load
+// time so that the 32 x 64 weight tile of each K step is a contiguous run of 256 words, which the
+// block loads with coalesced reads, dequantizes into shared memory and multiplies on the tensor
+// cores. Requires k % BLOCK_K == 0 and n % BLOCK_N == 0.
+template<typename scalar_t>
+__device__ void marlin_gemm(
+  const scalar_t* __restrict__ x,       // [m, k]
+  const uint32_t* __restrict__ qweight, // [k / BLOCK_K, n / BLOCK_N, BLOCK_K / 8, BLOCK_N]
+  const float* __restrict__ zeros,      // [num_groups, n]
+  const float* __restrict__ scales,     // [num_groups, n]
+  const uint32_t* __restrict__ g_idx,   // [k], the group of each input row
+  scalar_t* __restrict__ out,           // [m, n]
+  const int m,
+  const int n,
+  const int k) {
+  __shared__ __align__(32) scalar_t a_tile[BLOCK_M][BLOCK_K];
+  __shared__ __align__(32) scalar_t b_tile[BLOCK_K][BLOCK_N];
+  __shared__ __align__(32) float c_tile[BLOCK_M][BLOCK_N];
+
+  const int warp = threadIdx.x / 32;
+  const int warp_m = warp / 2;
+  const int warp_n = warp % 2;
+  const int row0 = blockIdx.y * BLOCK_M;
+  const int col0 = blockIdx.x * BLOCK_N;
+  const int num_n_tiles = n / BLOCK_N;
+
+  wmma::fragment<wmma::accumulator, 16, 16, 16, float> acc[2];
+  wmma::fill_fragment(acc[0], 0.f);
+  wmma::fill_fragment(acc[1], 0.f);
+
+  for (int k0 = 0; k0 < k; k0 += BLOCK_K) {
+    for (int i = threadIdx.x; i < BLOCK_M * BLOCK_K; i += NUM_THREADS) {
+      const int r = i / BLOCK_K;
+      const int c = i % BLOCK_K;
+      if (row0 + r < m) {
+        a_tile[r][c] = x[(int64_t)(row0 + r) * k + k0 + c];
+      } else {
+        from_float(a_tile[r][c], 0.f);
+      }
+    }
+    const uint32_t* tile_words =
+      qweight + ((int64_t)(k0 / BLOCK_K) * num_n_tiles + blockIdx.x) * (BLOCK_K / 8 * BLOCK_N);
+    for (int w = threadIdx.x; w < BLOCK_K / 8 * BLOCK_N; w += NUM_THREADS) {
+      const uint32_t word = tile_words[w];
+      const int kk = w / BLOCK_N * 8;
+      const int nn = w % BLOCK_N;
+#pragma unroll
+      for (int j = 0; j < 8; ++j) {
+        const int64_t g = (int64_t)g_idx[k0 + kk + j] * n + col0 + nn;
+        const float q = (float)((word >> (j * 4)) & 0xF);
+        from_float(b_tile[kk + j][nn], (q - zeros[g]) * scales[g]);
+      }
+    }
+    __syncthreads();
+
+#pragma unroll
+    for (int kk = 0; kk < BLOCK_K; kk += 16) {
+      wmma::fragment<wmma::matrix_a, 16, 16, 16, scalar_t, wmma::row_major> a;
+      wmma::load_matrix_sync(a, &a_tile[warp_m * 16][kk], BLOCK_K);
+#pragma unroll
+      for (int f = 0; f < 2; ++f) {
+        wmma::fragment<wmma::matrix_b, 16, 16, 16, scalar_t, wmma::row_major> b;
+        wmma::load_matrix_sync(b, &b_tile[kk][warp_n * 32 + f * 16], BLOCK_N);
+        wmma::mma_sync(acc[f], a, b, acc[f]);
+      }
+    }
+    __syncthreads();
+  }
+
+#pragma unroll
+  for (int f = 0; f < 2; ++f) {
+    wmma::store_matrix_sync(
+      &c_tile[warp_m * 16][warp_n * 32 + f * 16], acc[f], BLOCK_N, wmma::mem_row_major);
+  }
+  __syncthreads();
+  for (int i = threadIdx.x; i < BLOCK_M * BLOCK_N; i += NUM_THREADS) {
+    const int r = i / BLOCK_N;
+    const int c = i % BLOCK_N;
+    if (row0 + r < m) {
+      from_float(out[(int64_t)(row0 + r) * n + col0 + c], c_tile[r][c]);
+    }
+  }
+}
+
+#define DEFINE_MARLIN_GEMM_KERNEL(suffix, scalar_t)                              \
+  extern "C" __global__ void marlin_gemm_kernel_##suffix(                      \
+    const scalar_t* __restrict__ x,                                            \
+    const uint32_t* __restrict__ qweight,                                      \
+    const float* __restrict__ zeros,                                           \
+    const float* __restrict__ scales,                                          \
+    const uint32_t* __restrict__ g_idx,                                        \
+    scalar_t* __restrict__ out,                                                \
+    const int m,                                                               \
+    const int n,                                                               \
+    const int k) {                                                             \
+    marlin_gemm<scalar_t>(x, qweight, zeros, scales, g_idx, out, m, n, k);     \
+  }
+
+DEFINE_MARLIN_GEMM_KERNEL(f16, __half)
+// BF16 tensor core fragments require Ampere.
+#if __CUDA_ARCH__ >= 800
+DEFINE_MARLIN_GEMM_KERNEL(bf16, __nv_bfloat16)
+#endif
@@ -0,0 +1,199 @@
+// Decode attention of multi-head latent attention (MLA), as used by DeepSeek-V2/V3, over the paged
+// latent cache. Each cache entry holds the normalized latent KV (`kv_lora_rank` elements) followed
+// by the rotated rope key shared by all heads. The query of each head is absorbed into the latent
+// space, so it attends to the whole entry and its output is the softmax-weighted sum of the
+// latents, which the caller expands into the value head.
+
+#include <float.h>
+#include <stdint.h>
+#include <cuda_fp16.h>
+#include <cuda_bf16.h>
+
+#define WARP_SIZE 32
+#define MIN(a, b) ((a) < (b) ? (a) : (b))
+
+// f16 values are passed around as their raw bits.
+inline __device__ float to_float(const float x) { return x; }
+inline __device__ float to_float(const uint16_t x) { return __half2float(__ushort_as_half(x)); }
+inline __device__ float to_float(const __nv_bfloat16 x) { return __bfloat162float(x); }
+
+inline __device__ void from_float(float& dst, const float x) { dst = x; }
+inline __device__ void from_float(uint16_t& dst, const float x) { dst = __half_as_ushort(__float2half(x)); }
+inline __device__ void from_float(__nv_bfloat16& dst, const float x) { dst = __float2bfloat16(x); }
+
+// Reduce `x` over the thread block with `op`, the result is returned to every thread.
+template<int NUM_WARPS, typename Op>
+inline __device__ float block_reduce(float* red_smem, float x, Op op) {
+  const int warp = threadIdx.x / WARP_SIZE;
+  const int lane = threadIdx.x % WARP_SIZE;
+#pragma unroll
+  for (int mask = WARP_SIZE / 2; mask >= 1; mask /= 2) {
+    x = op(x, __shfl_xor_sync(0xffffffff, x, mask));
+  }
+  if (lane == 0) {
+    red_smem[warp] = x;
+  }
+  __syncthreads();
+  x = red_smem[lane < NUM_WARPS ? lane : 0];
+#pragma unroll
+  for (int mask = NUM_WARPS / 2; mask >= 1; mask /= 2) {
+    x = op(x, __shfl_xor_sync(0xffffffff, x, mask));
+  }
+  x = __shfl_sync(0xffffffff, x, 0);
+  // `red_smem` is reused by the next reduction.
+  __syncthreads();
+  return x;
+}
+
+// Grid: (num_heads, num_seqs * num_query_tokens).
+// Each thread block walks the context of its query in chunks of CHUNK_SIZE tokens with an online
+// softmax: the warps score the tokens of a chunk, one token per warp at a time, then every thread
+// accumulates its latent dimensions over the chunk.
+template<typename scalar_t, int NUM_THREADS, int CHUNK_SIZE, int MAX_KV_LORA_RANK>
+__device__ void mla_decode_kernel(
+  scalar_t* __restrict__ out,             // [num_queries, num_heads, kv_lora_rank]
+  const scalar_t* __restrict__ q,         // [num_queries, num_heads, entry_size]
+  const scalar_t* __restrict__ kv_cache,  // [num_blocks, block_size, entry_size]
+  const int* __restrict__ block_tables,   // [num_seqs, max_num_blocks_per_seq]
+  const int* __restrict__ context_lens,   // [num_seqs]
+  const int max_num_blocks_per_seq,
+  const int block_size,
+  const int kv_lora_rank,
+  const int entry_size,                   // kv_lora_rank + qk_rope_head_dim
+  const int q_stride,
+  const float scale,
+  const int num_query_tokens) {           // num_queries = num_seqs * num_query_tokens
+  constexpr int NUM_WARPS = NUM_THREADS / WARP_SIZE;
+  constexpr int NUM_DIMS_PER_THREAD = MAX_KV_LORA_RANK / NUM_THREADS;
+  const int head_idx = blockIdx.x;
+  const int num_heads = gridDim.x;
+  const int query_idx = blockIdx.y;
+  const int seq_idx = query_idx / num_query_tokens;
+  const int thread_idx = threadIdx.x;
+  const int warp_idx = thread_idx / WARP_SIZE;
+  const int lane = thread_idx % WARP_SIZE;
+  // The i-th of the query tokens of a sequence attends to the first
+  // `context_len - num_query_tokens + 1 + i` tokens.
+  const int context_len = context_lens[seq_idx] - (num_query_tokens - 1 - query_idx % num_query_tokens);
+  const int* block_table = block_tables + seq_idx * max_num_blocks_per_seq;
+
+  extern __shared__ char shared_mem[];
+  float* q_smem = reinterpret_cast<float*>(shared_mem);  // [entry_size]
+  float* logits = q_smem + entry_size;                    // [CHUNK_SIZE]
+  __shared__ float red_smem[NUM_WARPS];
+
+  const scalar_t* q_ptr = q + query_idx * q_stride + head_idx * entry_size;
+  for (int i = thread_idx; i < entry_size; i += NUM_THREADS) {
+    q_smem[i] = to_float(q_ptr[i]) * scale;
+  }
+  __syncthreads();
+
+  float max_logit = -FLT_MAX;
+  float exp_sum = 0.f;
+  float accs[NUM_DIMS_PER_THREAD];
+#pragma unroll
+  for (int i = 0; i < NUM_DIMS_PER_THREAD; i++) {
+    accs[i] = 0.f;
+  }
+
+  for (int chunk_start = 0; chunk_start < context_len; chunk_start += CHUNK_SIZE) {
+    const int chunk_len = MIN(CHUNK_SIZE, context_len - chunk_start);
+
+    for (int i = warp_idx; i < chunk_len; i += NUM_WARPS) {
+      const int token_idx = chunk_start + i;
+      const int64_t physical_block_number = static_cast<int64_t>(block_table[token_idx / block_size]);
+      const scalar_t* k_ptr = kv_cache + (physical_block_number * block_size + token_idx % block_size) * entry_size;
+      float qk = 0.f;
+      for (int j = lane; j < entry_size; j += WARP_SIZE) {
+        qk += q_smem[j] * to_float(k_ptr[j]);
+      }
+#pragma unroll
+      for (int mask = WARP_SIZE / 2; mask >= 1; mask /= 2) {
+        qk += __shfl_xor_sync(0xffffffff, qk, mask);
+      }
+      if (lane == 0) {
+        logits[i] = qk;
+      }
+    }
+    __syncthreads();
+
+    float chunk_max = -FLT_MAX;
+    for (int i = thread_idx; i < chunk_len; i += NUM_THREADS) {
+      chunk_max = fmaxf(chunk_max, logits[i]);
+    }
+    chunk_max = block_reduce<NUM_WARPS>(red_smem, chunk_max, [](float a, float b) { return fmaxf(a, b); });
+    const float new_max_logit = fmaxf(max_logit, chunk_max);
+    const float rescale = __expf(max_logit - new_max_logit);
+
+    float chunk_sum = 0.f;
+    for (int i = thread_idx; i < chunk_len; i += NUM_THREADS) {
+      const float p = __expf(logits[i] - new_max_logit);
+      logits[i] = p;
+      chunk_sum += p;
+    }
+    chunk_sum = block_reduce<NUM_WARPS>(red_smem, chunk_sum, [](float a, float b) { return a + b; });
+    exp_sum = exp_sum * rescale + chunk_sum;
+    max_logit = new_max_logit;
+
+    // Consecutive threads read consecutive latent dimensions of a token.
+#pragma unroll
+    for (int d = 0; d < NUM_DIMS_PER_THREAD; d++) {
+      accs[d] *= rescale;
+    }
+    for (int i = 0; i < chunk_len; i++) {
+      const int token_idx = chunk_start + i;
+      const int64_t physical_block_number = static_cast<int64_t>(block_table[token_idx / block_size]);
+      const scalar_t* v_ptr = kv_cache + (physical_block_number * block_size + token_idx % block_size) * entry_size;
+      const float p = logits[i];
+#pragma unroll
+      for (int d = 0; d < NUM_DIMS_PER_THREAD; d++) {
+        const int dim = thread_idx + d * NUM_THREADS;
+        if (dim < kv_lora_rank) {
+          accs[d] += p * to_float(v_ptr[dim]);
+        }
+      }
+    }
+    // The logits of the chunk are overwritten by the next one.
+    __syncthreads();
+  }
+
+  const float inv_sum = __fdividef(1.f, exp_sum + 1e-6f);
+  scalar_t* out_ptr = out + (query_idx * num_heads + head_idx) * kv_lora_rank;
+#pragma unroll
+  for (int d = 0; d < NUM_DIMS_PER_THREAD; d++) {
+    const int dim = thread_idx + d * NUM_THREADS;
+    if (dim < kv_lora_rank) {
+      from_float(out_ptr[dim], accs[d] * inv_sum);
+    }
+  }
+}
+
+#define MLA_NUM_THREADS 128
+#define MLA_CHUNK_SIZE 256
+#define MLA_MAX_KV_LORA_RANK 512
+
+#define INSTANTIATE_MLA_DECODE(NAME, T)                                                                    \
+  extern "C" __global__ void mla_decode_kernel_##NAME(                                                     \
+    T* __restrict__ out,                                                                                   \
+    const T* __restrict__ q,                                                                               \
+    const T* __restrict__ kv_cache,                                                                        \
+    const int* __restrict__ block_tables,                                                                  \
+    const int* __restrict__ context_lens,                                                                  \
+    const int max_num_blocks_per_seq,                                                                      \
+    const int block_size,                                                                                  \
+    const int kv_lora_rank,                                                                                \
+    const int entry_size,                                                                                  \
+    const int q_stride,                                                                                    \
+    const float scale,                                                                                     \
+    const int num_query_tokens) {                                                                          \
+    mla_decode_kernel<T, MLA_NUM_THREADS, MLA_CHUNK_SIZE, MLA_MAX_KV_LORA_RANK>(out, q, kv_cache,          \
+      block_tables, context_lens, max_num_blocks_per_seq, block_size, kv_lora_rank, entry_size, q_stride,  \
+      scale, num_query_tokens);                                                                            \
+  }
+
+INSTANTIATE_MLA_DECODE(f32, float)
+INSTANTIATE_MLA_DECODE(f16, uint16_t)
+INSTANTIATE_MLA_DECODE(bf16, __nv_bfloat16)
+
+#undef WARP_SIZE
+#undef MIN
@@ -0,0 +1,72 @@
+#include <stdint.h>
+#include <cuda_fp16.h>
+#include <cuda_bf16.h>
+
+#define TILE_SIZE 16
+
+inline __device__ float to_float(float x) { return x; }
+inline __device__ float to_float(__half x) { return __half2float(x); }
+inline __device__ float to_float(__nv_bfloat16 x) { return __bfloat162float(x); }
+
+inline __device__ void from_float(float& dst, float x) { dst = x; }
+inline __device__ void from_float(__half& dst, float x) { dst = __float2half(x); }
+inline __device__ void from_float(__nv_bfloat16& dst, float x) { dst = __float2bfloat16(x); }
+
+// Grouped GEMM over the experts of a mixture-of-experts layer: out[row] = x[row_x_idx[row]] @
+// weight[expert]^T. The rows are grouped by expert and each group is padded to a multiple of
+// TILE_SIZE, so that all rows of a tile share the expert `tile_experts[tile]` and its weight tile
+// is loaded once. Padding rows (row_x_idx = -1) are written as zeros.
+template<typename scalar_t>
+__device__ void moe_grouped_gemm(
+  const scalar_t* __restrict__ x,         // [num_x_rows, k]
+  const scalar_t* __restrict__ weight,    // [num_experts, n, k]
+  const int* __restrict__ row_x_idx,      // [num_rows]
+  const int* __restrict__ tile_experts,   // [num_rows / TILE_SIZE]
+  scalar_t* __restrict__ out,             // [num_rows, n]
+  const int num_rows,
+  const int n,
+  const int k) {
+  __shared__ float x_tile[TILE_SIZE][TILE_SIZE];
+  __shared__ float w_tile[TILE_SIZE][TILE_SIZE];
+
+  const int row = blockIdx.y * TILE_SIZE + threadIdx.y;
+  const int col = blockIdx.x * TILE_SIZE + threadIdx.x;
+  const int w_row = blockIdx.x * TILE_SIZE + threadIdx.y;
+  const int x_row = row < num_rows ? row_x_idx[row] : -1;
+  const scalar_t* expert_weight = weight + (int64_t)tile_experts[blockIdx.y] * n * k;
+
+  float acc = 0.f;
+  for (int tile = 0; tile < k; tile += TILE_SIZE) {
+    const int x_col = tile + threadIdx.x;
+    x_tile[threadIdx.y][threadIdx.x] = (x_row >= 0 && x_col < k) ? to_float(x[(int64_t)x_row * k + x_col]) : 0.f;
+    w_tile[threadIdx.y][threadIdx.x] = (w_row < n && x_col < k) ? to_float(expert_weight[(int64_t)w_row * k + x_col]) : 0.f;
+    __syncthreads();
+
+#pragma unroll
+    for (int i = 0; i < TILE_SIZE; ++i) {
+      acc += x_tile[threadIdx.y][i] * w_tile[threadIdx.x][i];
+    }
+    __syncthreads();
+  }
+
+  if (row < num_rows && col < n) {
+    from_float(out[(int64_t)row * n + col], acc);
+  }
+}
+
+#define DEFINE_MOE_GROUPED_GEMM_KERNEL(suffix, scalar_t)                                           \
+  extern "C" __global__ void moe_grouped_gemm_kernel_##suffix(                                     \
+    const scalar_t* __restrict__ x,                                                                \
+    const scalar_t* __restrict__ weight,                                                           \
+    const int* __restrict__ row_x_idx,                                                             \
+    const int* __restrict__ tile_experts,                                                          \
+    scalar_t* __restrict__ out,                                                                    \
+    const int num_rows,                                                                            \
+    const int n,                                                                                   \
+    const int k) {                                                                                 \
+    moe_grouped_gemm<scalar_t>(x, weight, row_x_idx, tile_experts, out, num_rows, n, k);           \
+  }
+
+DEFINE_MOE_GROUPED_GEMM_KERNEL(f32, float)
+DEFINE_MOE_GROUPED_GEMM_KERNEL(f16, __half)
+DEFINE_MOE_GROUPED_GEMM_KERNEL(bf16, __nv_bfloat16)
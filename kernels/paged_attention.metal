@@ -0,0 +1,302 @@
+// Metal ports of the cache, rotary embedding and decode attention kernels, compiled at runtime
+// by `backend::metal`. Only caches in the model data type are supported.
+
+#include <metal_stdlib>
+using namespace metal;
+
+#define MAX_THREADS 1024
+#define SIMD_SIZE 32
+
+// Number of context tokens whose logits are held in threadgroup memory at once. The attention
+// kernel runs an online softmax over chunks of this size, so the context length is unbounded.
+#define LOGITS_CHUNK_SIZE 1024
+
+struct CacheParams {
+  int key_stride;
+  int value_stride;
+  int num_heads;
+  int head_size;
+  int block_size;
+  int x;
+};
+
+struct RotaryParams {
+  int rot_dim;
+  int query_stride;
+  int key_stride;
+  int num_heads;
+  int num_kv_heads;
+  int head_size;
+  int is_neox;
+};
+
+// Mirrors `PagedAttentionParams` in `attention_kernel.cu`, without the key rotation.
+struct PagedAttentionParams {
+  int num_kv_heads;
+  float scale;
+  int max_num_blocks_per_seq;
+  int q_stride;
+  int kv_block_stride;
+  int kv_head_stride;
+  int sliding_window;
+  int head_size;
+  int block_size;
+  int x;
+  int has_alibi;
+  float logits_soft_cap;  // Zero means no soft-capping.
+};
+
+// One threadgroup per token.
+template <typename T>
+[[kernel]] void reshape_and_cache(
+    device const T* key [[buffer(0)]],          // [num_tokens, num_heads, head_size]
+    device const T* value [[buffer(1)]],        // [num_tokens, num_heads, head_size]
+    device T* key_cache [[buffer(2)]],          // [num_blocks, num_heads, head_size/x, block_size, x]
+    device T* value_cache [[buffer(3)]],        // [num_blocks, num_heads, head_size, block_size]
+    device const long* slot_mapping [[buffer(4)]], // [num_tokens]
+    constant CacheParams& p [[buffer(5)]],
+    uint token_idx [[threadgroup_position_in_grid]],
+    uint tid [[thread_position_in_threadgroup]],
+    uint num_threads [[threads_per_threadgroup]]) {
+  const long slot_idx = slot_mapping[token_idx];
+  if (slot_idx < 0) {
+    // Padding token.
+    return;
+  }
+  const long block_idx = slot_idx / p.block_size;
+  const long block_offset = slot_idx % p.block_size;
+
+  const int n = p.num_heads * p.head_size;
+  for (int i = tid; i < n; i += num_threads) {
+    const int head_idx = i / p.head_size;
+    const int head_offset = i % p.head_size;
+    const int x_idx = head_offset / p.x;
+    const int x_offset = head_offset % p.x;
+
+    const long head_base = (block_idx * p.num_heads + head_idx) * p.head_size * p.block_size;
+    const long tgt_key_idx = head_base + (x_idx * p.block_size + block_offset) * p.x + x_offset;
+    const long tgt_value_idx = head_base + head_offset * p.block_size + block_offset;
+    key_cache[tgt_key_idx] = key[token_idx * p.key_stride + i];
+    value_cache[tgt_value_idx] = value[token_idx * p.value_stride + i];
+  }
+}
+
+// One threadgroup per (block pair). `block_mapping` holds (src, dst) pairs.
+template <typename T>
+[[kernel]] void copy_blocks(
+    device T* cache [[buffer(0)]],
+    device const long* block_mapping [[buffer(1)]],
+    constant int& numel_per_block [[buffer(2)]],
+    uint pair_idx [[threadgroup_position_in_grid]],
+    uint tid [[thread_position_in_threadgroup]],
+    uint num_threads [[threads_per_threadgroup]]) {
+  const long src_offset = block_mapping[2 * pair_idx] * numel_per_block;
+  const long dst_offset = block_mapping[2 * pair_idx + 1] * numel_per_block;
+  for (int i = tid; i < numel_per_block; i += num_threads) {
+    cache[dst_offset + i] = cache[src_offset + i];
+  }
+}
+
+template <typename T>
+inline void apply_rotary_embedding(
+    device T* arr,
+    device const T* cos_ptr,
+    device const T* sin_ptr,
+    int rot_offset,
+    int embed_dim,
+    bool is_neox) {
+  int x_index, y_index;
+  float cos_v, sin_v;
+  if (is_neox) {
+    // GPT-NeoX style rotary embedding.
+    x_index = rot_offset;
+    y_index = embed_dim + rot_offset;
+    cos_v = float(cos_ptr[x_index]);
+    sin_v = float(sin_ptr[x_index]);
+  } else {
+    // GPT-J style rotary embedding.
+    x_index = 2 * rot_offset;
+    y_index = 2 * rot_offset + 1;
+    cos_v = float(cos_ptr[x_index / 2]);
+    sin_v = float(sin_ptr[x_index / 2]);
+  }
+
+  const float x = float(arr[x_index]);
+  const float y = float(arr[y_index]);
+  arr[x_index] = T(x * cos_v - y * sin_v);
+  arr[y_index] = T(y * cos_v + x * sin_v);
+}
+
+// One threadgroup per token.
+template <typename T>
+[[kernel]] void rotary_embedding(
+    device const long* positions [[buffer(0)]], // [num_tokens]
+    device T* query [[buffer(1)]],              // [num_tokens, num_heads, head_size]
+    device T* key [[buffer(2)]],                // [num_tokens, num_kv_heads, head_size]
+    device const T* cos_sin_cache [[buffer(3)]], // [max_position, 2, rot_dim // 2]
+    constant RotaryParams& p [[buffer(4)]],
+    uint token_idx [[threadgroup_position_in_grid]],
+    uint tid [[thread_position_in_threadgroup]],
+    uint num_threads [[threads_per_threadgroup]]) {
+  device const T* cache_ptr = cos_sin_cache + positions[token_idx] * p.rot_dim;
+  const int embed_dim = p.rot_dim / 2;
+  device const T* cos_ptr = cache_ptr;
+  device const T* sin_ptr = cache_ptr + embed_dim;
+
+  const int nq = p.num_heads * embed_dim;
+  for (int i = tid; i < nq; i += num_threads) {
+    const long token_head = token_idx * p.query_stride + (i / embed_dim) * p.head_size;
+    apply_rotary_embedding(query + token_head, cos_ptr, sin_ptr, i % embed_dim, embed_dim, p.is_neox);
+  }
+
+  const int nk = p.num_kv_heads * embed_dim;
+  for (int i = tid; i < nk; i += num_threads) {
+    const long token_head = token_idx * p.key_stride + (i / embed_dim) * p.head_size;
+    apply_rotary_embedding(key + token_head, cos_ptr, sin_ptr, i % embed_dim, embed_dim, p.is_neox);
+  }
+}
+
+// Reduce `value` over the threadgroup, `scratch` holds one value per simdgroup.
+inline float threadgroup_max(float value, threadgroup float* scratch, uint tid, uint num_threads) {
+  value = simd_max(value);
+  if (tid % SIMD_SIZE == 0) {
+    scratch[tid / SIMD_SIZE] = value;
+  }
+  threadgroup_barrier(mem_flags::mem_threadgroup);
+  value = -INFINITY;
+  for (uint i = 0; i < (num_threads + SIMD_SIZE - 1) / SIMD_SIZE; i++) {
+    value = max(value, scratch[i]);
+  }
+  threadgroup_barrier(mem_flags::mem_threadgroup);
+  return value;
+}
+
+inline float threadgroup_sum(float value, threadgroup float* scratch, uint tid, uint num_threads) {
+  value = simd_sum(value);
+  if (tid % SIMD_SIZE == 0) {
+    scratch[tid / SIMD_SIZE] = value;
+  }
+  threadgroup_barrier(mem_flags::mem_threadgroup);
+  value = 0.f;
+  for (uint i = 0; i < (num_threads + SIMD_SIZE - 1) / SIMD_SIZE; i++) {
+    value += scratch[i];
+  }
+  threadgroup_barrier(mem_flags::mem_threadgroup);
+  return value;
+}
+
+// Decode attention, one threadgroup per (head, sequence). Each thread owns one channel of the
+// output, so the threadgroup must have at least `head_size` threads.
+template <typename T>
+[[kernel]] void paged_attention(
+    device T* out [[buffer(0)]],                   // [num_seqs, num_heads, head_size]
+    device const T* q [[buffer(1)]],               // [num_seqs, num_heads, head_size]
+    device const T* k_cache [[buffer(2)]],         // [num_blocks, num_kv_heads, head_size/x, block_size, x]
+    device const T* v_cache [[buffer(3)]],         // [num_blocks, num_kv_heads, head_size, block_size]
+    device const uint* block_tables [[buffer(4)]], // [num_seqs, max_num_blocks_per_seq]
+    device const uint* context_lens [[buffer(5)]], // [num_seqs]
+    device const float* alibi_slopes [[buffer(6)]], // [num_heads]
+    constant PagedAttentionParams& p [[buffer(7)]],
+    threadgroup float* logits [[threadgroup(0)]],   // [LOGITS_CHUNK_SIZE]
+    uint2 group [[threadgroup_position_in_grid]],
+    uint2 num_groups [[threadgroups_per_grid]],
+    uint tid [[thread_index_in_threadgroup]],
+    uint num_threads [[threads_per_threadgroup]]) {
+  threadgroup float q_shared[256];
+  threadgroup float scratch[MAX_THREADS / SIMD_SIZE];
+
+  const int head_idx = group.x;
+  const int seq_idx = group.y;
+  const int num_heads = num_groups.x;
+  const int kv_head_idx = head_idx / (num_heads / p.num_kv_heads);
+  const int context_len = context_lens[seq_idx];
+  const float alibi_slope = p.has_alibi ? alibi_slopes[head_idx] : 0.f;
+  // Tokens before the sliding window are masked out.
+  const int start_token =
+      p.sliding_window > 0 ? max(context_len - p.sliding_window, 0) : 0;
+
+  device const T* q_ptr = q + seq_idx * p.q_stride + head_idx * p.head_size;
+  for (int i = tid; i < p.head_size; i += num_threads) {
+    q_shared[i] = float(q_ptr[i]);
+  }
+  threadgroup_barrier(mem_flags::mem_threadgroup);
+
+  device const uint* block_table = block_tables + seq_idx * p.max_num_blocks_per_seq;
+  const long head_offset = long(kv_head_idx) * p.kv_head_stride;
+
+  float running_max = -INFINITY;
+  float running_sum = 0.f;
+  float acc = 0.f;
+  for (int chunk_start = start_token; chunk_start < context_len; chunk_start += LOGITS_CHUNK_SIZE) {
+    const int chunk_len = min(LOGITS_CHUNK_SIZE, context_len - chunk_start);
+
+    float chunk_max = -INFINITY;
+    for (int i = tid; i < chunk_len; i += num_threads) {
+      const int token_idx = chunk_start + i;
+      const long block_base = long(block_table[token_idx / p.block_size]) * p.kv_block_stride + head_offset;
+      const int block_offset = token_idx % p.block_size;
+      float qk = 0.f;
+      for (int d = 0; d < p.head_size; d++) {
+        const long k_idx = block_base + ((d / p.x) * p.block_size + block_offset) * p.x + d % p.x;
+        qk += q_shared[d] * float(k_cache[k_idx]);
+      }
+      qk *= p.scale;
+      if (p.logits_soft_cap > 0.f) {
+        qk = p.logits_soft_cap * tanh(qk / p.logits_soft_cap);
+      }
+      qk += alibi_slope * (token_idx - context_len + 1);
+      logits[i] = qk;
+      chunk_max = max(chunk_max, qk);
+    }
+    chunk_max = threadgroup_max(chunk_max, scratch, tid, num_threads);
+
+    const float new_max = max(running_max, chunk_max);
+    float chunk_sum = 0.f;
+    for (int i = tid; i < chunk_len; i += num_threads) {
+      const float v = exp(logits[i] - new_max);
+      logits[i] = v;
+      chunk_sum += v;
+    }
+    chunk_sum = threadgroup_sum(chunk_sum, scratch, tid, num_threads);
+
+    const float correction = exp(running_max - new_max);
+    running_sum = running_sum * correction + chunk_sum;
+    running_max = new_max;
+    if (int(tid) < p.head_size) {
+      float chunk_acc = 0.f;
+      for (int i = 0; i < chunk_len; i++) {
+        const int token_idx = chunk_start + i;
+        const long block_base = long(block_table[token_idx / p.block_size]) * p.kv_block_stride + head_offset;
+        const long v_idx = block_base + long(tid) * p.block_size + token_idx % p.block_size;
+        chunk_acc += logits[i] * float(v_cache[v_idx]);
+      }
+      acc = acc * correction + chunk_acc;
+    }
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+  }
+
+  if (int(tid) < p.head_size) {
+    const float inv_sum = 1.f / (running_sum + 1e-6f);
+    out[(long(seq_idx) * num_heads + head_idx) * p.head_size + tid] = T(acc * inv_sum);
+  }
+}
+
+#define INSTANTIATE(T, NAME)                                                                     \
+  template [[host_name("reshape_and_cache_" #NAME)]] [[kernel]] void reshape_and_cache<T>(      \
+      device const T*, device const T*, device T*, device T*, device const long*,               \
+      constant CacheParams&, uint, uint, uint);                                                 \
+  template [[host_name("copy_blocks_" #NAME)]] [[kernel]] void copy_blocks<T>(                  \
+      device T*, device const long*, constant int&, uint, uint, uint);                          \
+  template [[host_name("rotary_embedding_" #NAME)]] [[kernel]] void rotary_embedding<T>(        \
+      device const long*, device T*, device T*, device const T*, constant RotaryParams&, uint,  \
+      uint, uint);                                                                              \
+  template [[host_name("paged_attention_" #NAME)]] [[kernel]] void paged_attention<T>(          \
+      device T*, device const T*, device const T*, device const T*, device const uint*,         \
+      device const uint*, device const float*, constant PagedAttentionParams&,                  \
+      threadgroup float*, uint2, uint2, uint, uint);
+
+INSTANTIATE(float, f32)
+INSTANTIATE(half, f16)
+#if defined(__HAVE_BFLOAT__)
+INSTANTIATE(bfloat, bf16)
+#endif
@@ -0,0 +1,92 @@
+#pragma once
+
+// Quantized computation of the attention scores (SageAttention-style). The query of each head
+// and the keys of each cache block are quantized symmetrically with their own scale (absmax / the
+// largest value of the format), the products are accumulated in int32 (INT8, with dp4a) or f32
+// (FP8 e4m3) and rescaled by both scales. The softmax and the value product stay in full
+// precision.
+
+#include <stdint.h>
+
+#include "fp8_kv_cache.cuh"
+
+namespace quantized_qk {
+
+// Mirrors `AttentionComputeDtype::kernel_mode`.
+enum class QKCompute : int {
+  kFull = 0,
+  kInt8 = 1,
+  kFp8E4M3 = 2,
+};
+
+using fp8_e4m3::to_float;
+
+inline __device__ float max_value(const QKCompute mode) {
+  return mode == QKCompute::kInt8 ? 127.f : 448.f;
+}
+
+// The scale of a set of values whose absolute maximum is `absmax`.
+inline __device__ float absmax_scale(const float absmax, const QKCompute mode) {
+  return absmax > 0.f ? absmax / max_value(mode) : 1.f;
+}
+
+inline __device__ uint8_t quantize(const float x, const float scale, const QKCompute mode) {
+  if (mode == QKCompute::kInt8) {
+    const float q = fminf(fmaxf(rintf(x / scale), -127.f), 127.f);
+    return static_cast<uint8_t>(static_cast<int8_t>(q));
+  }
+  return fp8_e4m3::quantize(x, scale);
+}
+
+// The absolute maximum of the `N` elements of `x`, a vector of `scalar_t`.
+template<typename scalar_t, typename T, int N>
+inline __device__ float vec_absmax(const T& x) {
+  const scalar_t* x_ptr = reinterpret_cast<const scalar_t*>(&x);
+  float absmax = 0.f;
+#pragma unroll
+  for (int i = 0; i < N; i++) {
+    absmax = fmaxf(absmax, fabsf(to_float(x_ptr[i])));
+  }
+  return absmax;
+}
+
+// The dot product of `N` int8 values, with dp4a where the values come in groups of 4.
+template<int N>
+inline __device__ int dot_int8(const int8_t* a, const int8_t* b) {
+  int acc = 0;
+  if constexpr (N % 4 == 0) {
+#pragma unroll
+    for (int i = 0; i < N; i += 4) {
+      acc = __dp4a(*reinterpret_cast<const int*>(a + i), *reinterpret_cast<const int*>(b + i), acc);
+    }
+  } else {
+#pragma unroll
+    for (int i = 0; i < N; i++) {
+      acc += static_cast<int>(a[i]) * static_cast<int>(b[i]);
+    }
+  }
+  return acc;
+}
+
+// The unscaled dot product of the quantized query `q` and key `k` held by a thread, `N` values
+// each, reduced across the `THREAD_GROUP_SIZE` threads holding the rest of the head.
+template<int THREAD_GROUP_SIZE, int N>
+inline __device__ float dot(const uint8_t* q, const uint8_t* k, const QKCompute mode) {
+  float sum;
+  if (mode == QKCompute::kInt8) {
+    sum = static_cast<float>(dot_int8<N>(reinterpret_cast<const int8_t*>(q), reinterpret_cast<const int8_t*>(k)));
+  } else {
+    sum = 0.f;
+#pragma unroll
+    for (int i = 0; i < N; i++) {
+      sum += fp8_e4m3::dequantize(q[i], 1.f) * fp8_e4m3::dequantize(k[i], 1.f);
+    }
+  }
+#pragma unroll
+  for (int mask = THREAD_GROUP_SIZE / 2; mask >= 1; mask /= 2) {
+    sum += __shfl_xor_sync(uint32_t(-1), sum, mask);
+  }
+  return sum;
+}
+
+} // namespace quantized_qk
@@ -1,11 +1,14 @@
 #include <stdint.h>
 
+#include "fp8_kv_cache.cuh"
+#include "int8_kv_cache.cuh"
+
 template<typename scalar_t>
 __device__ void reshape_and_cache_internal_kernel(
   const scalar_t* __restrict__ key,           // [num_tokens, num_heads, head_size]
   const scalar_t* __restrict__ value,         // [num_tokens, num_heads, head_size]
   scalar_t* __restrict__ key_cache,           // [num_blocks, num_heads, head_size/x, block_size, x]
-  scalar_t* __restrict__ value_cache,         // [num_blocks, num_heads, head_size, blosudo ck_size]
+  scalar_t* __restrict__ value_cache,         // [num_blocks, num_heads, head_size, block_size]
   const int64_t* __restrict__ slot_mapping,   // [num_tokens]
   const int key_stride,
   const int value_stride,
@@ -152,3 +155,241 @@ extern "C" __global__ void reshape_and_cache_kernel_bf16(
   const int x) {
   reshape_and_cache_internal_kernel<int16_t>(key, value, key_cache, value_cache, slot_mapping, key_stride, value_stride, num_heads, head_size, block_size, x);
 }
+
+// Write the new keys and values into caches with the NHD layout, where both caches are
+// [num_blocks, block_size, num_heads, head_size] and each token is written contiguously.
+template<typename scalar_t>
+__device__ void reshape_and_cache_nhd_internal_kernel(
+  const scalar_t* __restrict__ key,           // [num_tokens, num_heads, head_size]
+  const scalar_t* __restrict__ value,         // [num_tokens, num_heads, head_size]
+  scalar_t* __restrict__ key_cache,           // [num_blocks, block_size, num_heads, head_size]
+  scalar_t* __restrict__ value_cache,         // [num_blocks, block_size, num_heads, head_size]
+  const int64_t* __restrict__ slot_mapping,   // [num_tokens]
+  const int key_stride,
+  const int value_stride,
+  const int num_heads,
+  const int head_size) {
+  const int64_t token_idx = blockIdx.x;
+  const int64_t slot_idx = slot_mapping[token_idx];
+  if (slot_idx < 0) {
+    // Padding token that should be ignored.
+    return;
+  }
+
+  const int n = num_heads * head_size;
+  for (int i = threadIdx.x; i < n; i += blockDim.x) {
+    key_cache[slot_idx * n + i] = key[token_idx * key_stride + i];
+    value_cache[slot_idx * n + i] = value[token_idx * value_stride + i];
+  }
+}
+
+#define INSTANTIATE_RESHAPE_AND_CACHE_NHD(NAME, T)                                                \
+  extern "C" __global__ void reshape_and_cache_nhd_kernel_##NAME(                                 \
+    const T* __restrict__ key,                                                                    \
+    const T* __restrict__ value,                                                                  \
+    T* __restrict__ key_cache,                                                                    \
+    T* __restrict__ value_cache,                                                                  \
+    const int64_t* __restrict__ slot_mapping,                                                     \
+    const int key_stride,                                                                         \
+    const int value_stride,                                                                       \
+    const int num_heads,                                                                          \
+    const int head_size) {                                                                        \
+    reshape_and_cache_nhd_internal_kernel<T>(key, value, key_cache, value_cache, slot_mapping,    \
+      key_stride, value_stride, num_heads, head_size);                                            \
+  }
+
+INSTANTIATE_RESHAPE_AND_CACHE_NHD(f32, float)
+INSTANTIATE_RESHAPE_AND_CACHE_NHD(f16, uint16_t)
+INSTANTIATE_RESHAPE_AND_CACHE_NHD(bf16, uint16_t)
+
+// Write the new latent KV entries of multi-head latent attention into the cache, where each entry
+// is the normalized latent (`kv_lora_rank` elements) followed by the rotated rope key.
+template<typename scalar_t>
+__device__ void reshape_and_cache_mla_internal_kernel(
+  const scalar_t* __restrict__ kv_latent,     // [num_tokens, kv_lora_rank]
+  const scalar_t* __restrict__ k_pe,          // [num_tokens, qk_rope_head_dim]
+  scalar_t* __restrict__ kv_cache,            // [num_blocks, block_size, 1, entry_size]
+  const int64_t* __restrict__ slot_mapping,   // [num_tokens]
+  const int kv_latent_stride,
+  const int k_pe_stride,
+  const int kv_lora_rank,
+  const int entry_size) {
+  const int64_t token_idx = blockIdx.x;
+  const int64_t slot_idx = slot_mapping[token_idx];
+  if (slot_idx < 0) {
+    // Padding token that should be ignored.
+    return;
+  }
+
+  for (int i = threadIdx.x; i < entry_size; i += blockDim.x) {
+    kv_cache[slot_idx * entry_size + i] = i < kv_lora_rank
+      ? kv_latent[token_idx * kv_latent_stride + i]
+      : k_pe[token_idx * k_pe_stride + i - kv_lora_rank];
+  }
+}
+
+#define INSTANTIATE_RESHAPE_AND_CACHE_MLA(NAME, T)                                                \
+  extern "C" __global__ void reshape_and_cache_mla_kernel_##NAME(                                 \
+    const T* __restrict__ kv_latent,                                                              \
+    const T* __restrict__ k_pe,                                                                   \
+    T* __restrict__ kv_cache,                                                                     \
+    const int64_t* __restrict__ slot_mapping,                                                     \
+    const int kv_latent_stride,                                                                   \
+    const int k_pe_stride,                                                                        \
+    const int kv_lora_rank,                                                                       \
+    const int entry_size) {                                                                       \
+    reshape_and_cache_mla_internal_kernel<T>(kv_latent, k_pe, kv_cache, slot_mapping,             \
+      kv_latent_stride, k_pe_stride, kv_lora_rank, entry_size);                                   \
+  }
+
+INSTANTIATE_RESHAPE_AND_CACHE_MLA(f32, float)
+INSTANTIATE_RESHAPE_AND_CACHE_MLA(f16, uint16_t)
+INSTANTIATE_RESHAPE_AND_CACHE_MLA(bf16, uint16_t)
+
+// Quantize the new keys and values to FP8 (e4m3) while writing them into the cache. The scales
+// are per head, the key scales followed by the value scales. A null pointer means a scale of 1.
+template<typename scalar_t>
+__device__ void reshape_and_cache_fp8_internal_kernel(
+  const scalar_t* __restrict__ key,           // [num_tokens, num_heads, head_size]
+  const scalar_t* __restrict__ value,         // [num_tokens, num_heads, head_size]
+  uint8_t* __restrict__ key_cache,            // [num_blocks, num_heads, head_size/x, block_size, x]
+  uint8_t* __restrict__ value_cache,          // [num_blocks, num_heads, head_size, block_size]
+  const int64_t* __restrict__ slot_mapping,   // [num_tokens]
+  const float* __restrict__ kv_scales,        // [2, num_heads]
+  const int key_stride,
+  const int value_stride,
+  const int num_heads,
+  const int head_size,
+  const int block_size,
+  const int x) {
+  const int64_t token_idx = blockIdx.x;
+  const int64_t slot_idx = slot_mapping[token_idx];
+  if (slot_idx < 0) {
+    // Padding token that should be ignored.
+    return;
+  }
+
+  const int64_t block_idx = slot_idx / block_size;
+  const int64_t block_offset = slot_idx % block_size;
+
+  const int n = num_heads * head_size;
+  for (int i = threadIdx.x; i < n; i += blockDim.x) {
+    const int64_t src_key_idx = token_idx * key_stride + i;
+    const int64_t src_value_idx = token_idx * value_stride + i;
+
+    const int head_idx = i / head_size;
+    const int head_offset = i % head_size;
+    const int x_idx = head_offset / x;
+    const int x_offset = head_offset % x;
+
+    const int64_t tgt_key_idx = block_idx * num_heads * (head_size / x) * block_size * x
+                                + head_idx * (head_size / x) * block_size * x
+                                + x_idx * block_size * x
+                                + block_offset * x
+                                + x_offset;
+    const int64_t tgt_value_idx = block_idx * num_heads * head_size * block_size
+                                  + head_idx * head_size * block_size
+                                  + head_offset * block_size
+                                  + block_offset;
+    const float k_scale = kv_scales == nullptr ? 1.f : kv_scales[head_idx];
+    const float v_scale = kv_scales == nullptr ? 1.f : kv_scales[num_heads + head_idx];
+    key_cache[tgt_key_idx] = fp8_e4m3::quantize(fp8_e4m3::to_float(key[src_key_idx]), k_scale);
+    value_cache[tgt_value_idx] = fp8_e4m3::quantize(fp8_e4m3::to_float(value[src_value_idx]), v_scale);
+  }
+}
+
+#define INSTANTIATE_RESHAPE_AND_CACHE_FP8(NAME, T)                                                \
+  extern "C" __global__ void reshape_and_cache_fp8_kernel_##NAME(                                 \
+    const T* __restrict__ key,                                                                    \
+    const T* __restrict__ value,                                                                  \
+    uint8_t* __restrict__ key_cache,                                                              \
+    uint8_t* __restrict__ value_cache,                                                            \
+    const int64_t* __restrict__ slot_mapping,                                                     \
+    const float* __restrict__ kv_scales,                                                          \
+    const int key_stride,                                                                         \
+    const int value_stride,                                                                       \
+    const int num_heads,                                                                          \
+    const int head_size,                                                                          \
+    const int block_size,                                                                         \
+    const int x) {                                                                                \
+    reshape_and_cache_fp8_internal_kernel<T>(key, value, key_cache, value_cache, slot_mapping,    \
+      kv_scales, key_stride, value_stride, num_heads, head_size, block_size, x);                  \
+  }
+
+INSTANTIATE_RESHAPE_AND_CACHE_FP8(f32, float)
+INSTANTIATE_RESHAPE_AND_CACHE_FP8(f16, uint16_t)
+INSTANTIATE_RESHAPE_AND_CACHE_FP8(bf16, __nv_bfloat16)
+
+// Quantize the new keys and values to INT8 while writing them into the cache, with a scale per
+// token and head. Each warp handles one head at a time, see `int8_kv_cache.cuh` for the layout.
+template<typename scalar_t>
+__device__ void reshape_and_cache_int8_internal_kernel(
+  const scalar_t* __restrict__ key,           // [num_tokens, num_heads, head_size]
+  const scalar_t* __restrict__ value,         // [num_tokens, num_heads, head_size]
+  uint8_t* __restrict__ key_cache,            // [num_blocks, num_heads, head_size/x + 1, block_size, x]
+  uint8_t* __restrict__ value_cache,          // [num_blocks, num_heads, head_size + x, block_size]
+  const int64_t* __restrict__ slot_mapping,   // [num_tokens]
+  const int key_stride,
+  const int value_stride,
+  const int num_heads,
+  const int head_size,
+  const int block_size,
+  const int x) {
+  constexpr int WARP_SIZE = 32;
+  const int64_t token_idx = blockIdx.x;
+  const int64_t slot_idx = slot_mapping[token_idx];
+  if (slot_idx < 0) {
+    // Padding token that should be ignored.
+    return;
+  }
+
+  const int64_t block_idx = slot_idx / block_size;
+  const int64_t block_offset = slot_idx % block_size;
+
+  const int warp_idx = threadIdx.x / WARP_SIZE;
+  const int lane = threadIdx.x % WARP_SIZE;
+  const int num_warps = blockDim.x / WARP_SIZE;
+  const int key_rows = head_size / x + 1;
+  const int value_rows = head_size + x;
+
+  for (int head_idx = warp_idx; head_idx < num_heads; head_idx += num_warps) {
+    const scalar_t* src_key = key + token_idx * key_stride + head_idx * head_size;
+    const scalar_t* src_value = value + token_idx * value_stride + head_idx * head_size;
+    const float k_scale = int8_kv::absmax_scale(src_key, head_size, lane, WARP_SIZE);
+    const float v_scale = int8_kv::absmax_scale(src_value, head_size, lane, WARP_SIZE);
+
+    uint8_t* key_head = key_cache + (block_idx * num_heads + head_idx) * key_rows * block_size * x;
+    uint8_t* value_head = value_cache + (block_idx * num_heads + head_idx) * value_rows * block_size;
+    for (int i = lane; i < head_size; i += WARP_SIZE) {
+      const int64_t tgt_key_idx = (i / x) * block_size * x + block_offset * x + i % x;
+      const int64_t tgt_value_idx = i * block_size + block_offset;
+      key_head[tgt_key_idx] = int8_kv::quantize(int8_kv::to_float(src_key[i]), k_scale);
+      value_head[tgt_value_idx] = int8_kv::quantize(int8_kv::to_float(src_value[i]), v_scale);
+    }
+    if (lane == 0) {
+      reinterpret_cast<float*>(key_head + (head_size / x) * block_size * x)[block_offset] = k_scale;
+      reinterpret_cast<float*>(value_head + head_size * block_size)[block_offset] = v_scale;
+    }
+  }
+}
+
+#define INSTANTIATE_RESHAPE_AND_CACHE_INT8(NAME, T)                                               \
+  extern "C" __global__ void reshape_and_cache_int8_kernel_##NAME(                                \
+    const T* __restrict__ key,                                                                    \
+    const T* __restrict__ value,                                                                  \
+    uint8_t* __restrict__ key_cache,                                                              \
+    uint8_t* __restrict__ value_cache,                                                            \
+    const int64_t* __restrict__ slot_mapping,                                                     \
+    const int key_stride,                                                                         \
+    const int value_stride,                                                                       \
+    const int num_heads,                                                                          \
+    const int head_size,                                                                          \
+    const int block_size,                                                                         \
+    const int x) {                                                                                \
+    reshape_and_cache_int8_internal_kernel<T>(key, value, key_cache, value_cache, slot_mapping,   \
+      key_stride, value_stride, num_heads, head_size, block_size, x);                             \
+  }
+
+INSTANTIATE_RESHAPE_AND_CACHE_INT8(f32, float)
+INSTANTIATE_RESHAPE_AND_CACHE_INT8(f16, uint16_t)
+INSTANTIATE_RESHAPE_AND_CACHE_INT8(bf16, __nv_bfloat16)
@@ -0,0 +1,167 @@
+#include <stdint.h>
+#include <cuda_fp16.h>
+#include <cuda_bf16.h>
+
+// Applies the rotary embedding to the new queries and keys and writes the rotated keys and the
+// values into the paged caches in the same pass, instead of `rotary_embedding_kernel` followed by
+// `reshape_and_cache_kernel`. The rotated keys are also written back to `key`, as they are used by
+// the prompt attention.
+
+namespace rope_cache {
+
+// f16 values are passed around as their raw bits.
+inline __device__ float to_float(const float x) { return x; }
+inline __device__ float to_float(const uint16_t x) { return __half2float(__ushort_as_half(x)); }
+inline __device__ float to_float(const __nv_bfloat16 x) { return __bfloat162float(x); }
+
+inline __device__ void from_float(float& dst, const float x) { dst = x; }
+inline __device__ void from_float(uint16_t& dst, const float x) { dst = __half_as_ushort(__float2half(x)); }
+inline __device__ void from_float(__nv_bfloat16& dst, const float x) { dst = __float2bfloat16(x); }
+
+} // namespace rope_cache
+
+// Mirrors `RotaryEmbeddingAndCacheParams` in `src/backend/layers.rs`.
+struct RotaryEmbeddingAndCacheParams {
+  int rot_dim;
+  int query_stride;
+  int key_stride;
+  int value_stride;
+  int num_heads;
+  int num_kv_heads;
+  int head_size;
+  int block_size;
+  int x;
+};
+
+// The indices of the pair of elements of a head rotated together.
+template<bool IS_NEOX>
+inline __device__ void rotary_indices(const int rot_offset, const int embed_dim, int& x_index, int& y_index) {
+  if (IS_NEOX) {
+    // GPT-NeoX style rotary embedding.
+    x_index = rot_offset;
+    y_index = embed_dim + rot_offset;
+  } else {
+    // GPT-J style rotary embedding.
+    x_index = 2 * rot_offset;
+    y_index = 2 * rot_offset + 1;
+  }
+}
+
+template<typename scalar_t>
+inline __device__ void rotate_pair(
+  const scalar_t* __restrict__ src,
+  const float cos,
+  const float sin,
+  const int x_index,
+  const int y_index,
+  scalar_t& x_out,
+  scalar_t& y_out) {
+  const float x = rope_cache::to_float(src[x_index]);
+  const float y = rope_cache::to_float(src[y_index]);
+  rope_cache::from_float(x_out, x * cos - y * sin);
+  rope_cache::from_float(y_out, y * cos + x * sin);
+}
+
+template<typename scalar_t, bool IS_NEOX>
+__device__ void rotary_embedding_and_cache_internal_kernel(
+  const int64_t* __restrict__ positions,        // [num_tokens]
+  scalar_t* __restrict__ query,                 // [num_tokens, num_heads, head_size]
+  scalar_t* __restrict__ key,                   // [num_tokens, num_kv_heads, head_size]
+  const scalar_t* __restrict__ value,           // [num_tokens, num_kv_heads, head_size]
+  scalar_t* __restrict__ key_cache,             // [num_blocks, num_kv_heads, head_size/x, block_size, x]
+  scalar_t* __restrict__ value_cache,           // [num_blocks, num_kv_heads, head_size, block_size]
+  const int64_t* __restrict__ slot_mapping,     // [num_tokens]
+  const scalar_t* __restrict__ cos_sin_cache,   // [max_position, rot_dim]
+  const RotaryEmbeddingAndCacheParams params) {
+  // Each thread block is responsible for one token.
+  const int64_t token_idx = blockIdx.x;
+  const int64_t pos = positions[token_idx];
+  const scalar_t* cos_ptr = cos_sin_cache + pos * params.rot_dim;
+  const int embed_dim = params.rot_dim / 2;
+  const scalar_t* sin_ptr = cos_ptr + embed_dim;
+  const int head_size = params.head_size;
+
+  const int nq = params.num_heads * embed_dim;
+  for (int i = threadIdx.x; i < nq; i += blockDim.x) {
+    const int head_idx = i / embed_dim;
+    const int rot_offset = i % embed_dim;
+    int x_index, y_index;
+    rotary_indices<IS_NEOX>(rot_offset, embed_dim, x_index, y_index);
+    scalar_t* head = query + token_idx * params.query_stride + head_idx * head_size;
+    rotate_pair<scalar_t>(head, rope_cache::to_float(cos_ptr[rot_offset]),
+      rope_cache::to_float(sin_ptr[rot_offset]), x_index, y_index, head[x_index], head[y_index]);
+  }
+
+  const int64_t slot_idx = slot_mapping[token_idx];
+  // Padding tokens are rotated but not cached.
+  const bool cached = slot_idx >= 0;
+  const int64_t block_idx = slot_idx / params.block_size;
+  const int64_t block_offset = slot_idx % params.block_size;
+  const int x = params.x;
+  const int64_t key_block_stride = (int64_t) params.num_kv_heads * head_size * params.block_size;
+  auto key_cache_idx = [&](const int head_idx, const int head_offset) {
+    return block_idx * key_block_stride
+      + (int64_t) head_idx * head_size * params.block_size
+      + (head_offset / x) * params.block_size * x
+      + block_offset * x
+      + head_offset % x;
+  };
+
+  const int nk = params.num_kv_heads * embed_dim;
+  for (int i = threadIdx.x; i < nk; i += blockDim.x) {
+    const int head_idx = i / embed_dim;
+    const int rot_offset = i % embed_dim;
+    int x_index, y_index;
+    rotary_indices<IS_NEOX>(rot_offset, embed_dim, x_index, y_index);
+    scalar_t* head = key + token_idx * params.key_stride + head_idx * head_size;
+    scalar_t x_out, y_out;
+    rotate_pair<scalar_t>(head, rope_cache::to_float(cos_ptr[rot_offset]),
+      rope_cache::to_float(sin_ptr[rot_offset]), x_index, y_index, x_out, y_out);
+    head[x_index] = x_out;
+    head[y_index] = y_out;
+    if (cached) {
+      key_cache[key_cache_idx(head_idx, x_index)] = x_out;
+      key_cache[key_cache_idx(head_idx, y_index)] = y_out;
+    }
+  }
+
+  if (!cached) {
+    return;
+  }
+  const int n = params.num_kv_heads * head_size;
+  for (int i = threadIdx.x; i < n; i += blockDim.x) {
+    const int head_idx = i / head_size;
+    const int head_offset = i % head_size;
+    // The rotated part of the keys was written above.
+    if (head_offset >= params.rot_dim) {
+      key_cache[key_cache_idx(head_idx, head_offset)] = key[token_idx * params.key_stride + i];
+    }
+    const int64_t tgt_value_idx = block_idx * key_block_stride
+                                  + (int64_t) head_idx * head_size * params.block_size
+                                  + head_offset * params.block_size
+                                  + block_offset;
+    value_cache[tgt_value_idx] = value[token_idx * params.value_stride + i];
+  }
+}
+
+#define INSTANTIATE_ROTARY_EMBEDDING_AND_CACHE(NAME, T, IS_NEOX, SUFFIX)                                   \
+  extern "C" __global__ void rotary_embedding_and_cache_kernel_##NAME##SUFFIX(                             \
+    const int64_t* __restrict__ positions,                                                                 \
+    T* __restrict__ query,                                                                                 \
+    T* __restrict__ key,                                                                                   \
+    const T* __restrict__ value,                                                                           \
+    T* __restrict__ key_cache,                                                                             \
+    T* __restrict__ value_cache,                                                                           \
+    const int64_t* __restrict__ slot_mapping,                                                              \
+    const T* __restrict__ cos_sin_cache,                                                                   \
+    const RotaryEmbeddingAndCacheParams params) {                                                          \
+    rotary_embedding_and_cache_internal_kernel<T, IS_NEOX>(positions, query, key, value, key_cache,        \
+      value_cache, slot_mapping, cos_sin_cache, params);                                                   \
+  }
+
+INSTANTIATE_ROTARY_EMBEDDING_AND_CACHE(f32, float, false, )
+INSTANTIATE_ROTARY_EMBEDDING_AND_CACHE(f16, uint16_t, false, )
+INSTANTIATE_ROTARY_EMBEDDING_AND_CACHE(bf16, __nv_bfloat16, false, )
+INSTANTIATE_ROTARY_EMBEDDING_AND_CACHE(f32, float, true, _neox)
+INSTANTIATE_ROTARY_EMBEDDING_AND_CACHE(f16, uint16_t, true, _neox)
+INSTANTIATE_ROTARY_EMBEDDING_AND_CACHE(bf16, __nv_bfloat16, true, _neox)
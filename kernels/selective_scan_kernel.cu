@@ -0,0 +1,175 @@
+#include <stdint.h>
+#include <cuda_fp16.h>
+#include <cuda_bf16.h>
+
+// The largest convolution width and SSM state size kept in registers.
+#define MAX_CONV_WIDTH 8
+#define MAX_D_STATE 64
+
+inline __device__ float to_float(float x) { return x; }
+inline __device__ float to_float(__half x) { return __half2float(x); }
+inline __device__ float to_float(__nv_bfloat16 x) { return __bfloat162float(x); }
+
+inline __device__ void from_float(float& dst, float x) { dst = x; }
+inline __device__ void from_float(__half& dst, float x) { dst = __float2half(x); }
+inline __device__ void from_float(__nv_bfloat16& dst, float x) { dst = __float2bfloat16(x); }
+
+// The sizes of a selective scan, passed by value. Mirrors `SelectiveScanParams` in `layers.rs`.
+struct SelectiveScanParams {
+  int dim;
+  int d_state;
+  int num_prompts;
+};
+
+inline __device__ float silu(float x) { return x / (1.f + __expf(-x)); }
+
+inline __device__ float softplus(float x) { return x <= 20.f ? log1pf(__expf(x)) : x; }
+
+// Causal depthwise convolution of the Mamba mixer followed by SiLU, scanned over the tokens of
+// each sequence. One thread handles one channel of one sequence and carries the last
+// `width - 1` inputs from the convolution state of the sequence's slot, which is updated in
+// place. Prompts (the first `num_prompts` sequences) and sequences without a slot (-1) start
+// from zero inputs.
+template<typename scalar_t>
+__device__ void causal_conv1d(
+  const scalar_t* __restrict__ x,         // [num_tokens, dim]
+  const scalar_t* __restrict__ weight,    // [dim, width]
+  const scalar_t* __restrict__ bias,      // [dim] or null
+  scalar_t* __restrict__ conv_state,      // [num_slots, dim, width - 1]
+  const int* __restrict__ cu_seqlens,     // [num_seqs + 1]
+  const int64_t* __restrict__ slots,      // [num_seqs]
+  scalar_t* __restrict__ out,             // [num_tokens, dim]
+  const int dim,
+  const int width,
+  const int num_prompts) {
+  const int seq = blockIdx.x;
+  const int c = blockIdx.y * blockDim.x + threadIdx.x;
+  if (c >= dim) {
+    return;
+  }
+  const int64_t slot = slots[seq];
+  scalar_t* state = slot >= 0 ? conv_state + (slot * dim + c) * (width - 1) : nullptr;
+  const bool has_state = state != nullptr && seq >= num_prompts;
+
+  float window[MAX_CONV_WIDTH];
+  float w[MAX_CONV_WIDTH];
+  for (int i = 0; i < width; ++i) {
+    w[i] = to_float(weight[(int64_t)c * width + i]);
+  }
+  for (int i = 0; i < width - 1; ++i) {
+    window[i] = has_state ? to_float(state[i]) : 0.f;
+  }
+  const float b = bias != nullptr ? to_float(bias[c]) : 0.f;
+
+  for (int t = cu_seqlens[seq]; t < cu_seqlens[seq + 1]; ++t) {
+    window[width - 1] = to_float(x[(int64_t)t * dim + c]);
+    float acc = b;
+    for (int i = 0; i < width; ++i) {
+      acc += w[i] * window[i];
+    }
+    from_float(out[(int64_t)t * dim + c], silu(acc));
+    for (int i = 0; i < width - 1; ++i) {
+      window[i] = window[i + 1];
+    }
+  }
+
+  if (state != nullptr) {
+    for (int i = 0; i < width - 1; ++i) {
+      from_float(state[i], window[i]);
+    }
+  }
+}
+
+// Selective scan of the Mamba mixer: h = exp(dt * A) * h + dt * B * u and
+// y = (C . h + D * u) * silu(z), with dt = softplus(delta), scanned over the tokens of each
+// sequence. One thread handles one channel of one sequence and keeps its `d_state` states in
+// registers, starting from and writing back to the SSM state of the sequence's slot.
+template<typename scalar_t>
+__device__ void selective_scan(
+  const scalar_t* __restrict__ u,         // [num_tokens, dim]
+  const scalar_t* __restrict__ delta,     // [num_tokens, dim]
+  const float* __restrict__ A,            // [dim, d_state]
+  const scalar_t* __restrict__ B,         // [num_tokens, d_state]
+  const scalar_t* __restrict__ C,         // [num_tokens, d_state]
+  const float* __restrict__ D,            // [dim]
+  const scalar_t* __restrict__ z,         // [num_tokens, dim]
+  float* __restrict__ ssm_state,          // [num_slots, dim, d_state]
+  const int* __restrict__ cu_seqlens,     // [num_seqs + 1]
+  const int64_t* __restrict__ slots,      // [num_seqs]
+  scalar_t* __restrict__ out,             // [num_tokens, dim]
+  const SelectiveScanParams params) {
+  const int dim = params.dim;
+  const int d_state = params.d_state;
+  const int num_prompts = params.num_prompts;
+  const int seq = blockIdx.x;
+  const int c = blockIdx.y * blockDim.x + threadIdx.x;
+  if (c >= dim) {
+    return;
+  }
+  const int64_t slot = slots[seq];
+  float* state = slot >= 0 ? ssm_state + (slot * dim + c) * d_state : nullptr;
+  const bool has_state = state != nullptr && seq >= num_prompts;
+
+  float h[MAX_D_STATE];
+  float a[MAX_D_STATE];
+  for (int n = 0; n < d_state; ++n) {
+    h[n] = has_state ? state[n] : 0.f;
+    a[n] = A[(int64_t)c * d_state + n];
+  }
+  const float d = D[c];
+
+  for (int t = cu_seqlens[seq]; t < cu_seqlens[seq + 1]; ++t) {
+    const int64_t idx = (int64_t)t * dim + c;
+    const float dt = softplus(to_float(delta[idx]));
+    const float u_t = to_float(u[idx]);
+    float y = 0.f;
+    for (int n = 0; n < d_state; ++n) {
+      const int64_t bc_idx = (int64_t)t * d_state + n;
+      h[n] = __expf(dt * a[n]) * h[n] + dt * to_float(B[bc_idx]) * u_t;
+      y += h[n] * to_float(C[bc_idx]);
+    }
+    y += d * u_t;
+    from_float(out[idx], y * silu(to_float(z[idx])));
+  }
+
+  if (state != nullptr) {
+    for (int n = 0; n < d_state; ++n) {
+      state[n] = h[n];
+    }
+  }
+}
+
+#define DEFINE_SSM_KERNELS(suffix, scalar_t)                                                       \
+  extern "C" __global__ void causal_conv1d_kernel_##suffix(                                        \
+    const scalar_t* __restrict__ x,                                                                \
+    const scalar_t* __restrict__ weight,                                                           \
+    const scalar_t* __restrict__ bias,                                                             \
+    scalar_t* __restrict__ conv_state,                                                             \
+    const int* __restrict__ cu_seqlens,                                                            \
+    const int64_t* __restrict__ slots,                                                             \
+    scalar_t* __restrict__ out,                                                                    \
+    const int dim,                                                                                 \
+    const int width,                                                                               \
+    const int num_prompts) {                                                                       \
+    causal_conv1d<scalar_t>(                                                                       \
+      x, weight, bias, conv_state, cu_seqlens, slots, out, dim, width, num_prompts);               \
+  }                                                                                                \
+  extern "C" __global__ void selective_scan_kernel_##suffix(                                       \
+    const scalar_t* __restrict__ u,                                                                \
+    const scalar_t* __restrict__ delta,                                                            \
+    const float* __restrict__ A,                                                                   \
+    const scalar_t* __restrict__ B,                                                                \
+    const scalar_t* __restrict__ C,                                                                \
+    const float* __restrict__ D,                                                                   \
+    const scalar_t* __restrict__ z,                                                                \
+    float* __restrict__ ssm_state,                                                                 \
+    const int* __restrict__ cu_seqlens,                                                            \
+    const int64_t* __restrict__ slots,                                                             \
+    scalar_t* __restrict__ out,                                                                    \
+    const SelectiveScanParams params) {                                                            \
+    selective_scan<scalar_t>(u, delta, A, B, C, D, z, ssm_state, cu_seqlens, slots, out, params);  \
+  }
+
+DEFINE_SSM_KERNELS(f32, float)
+DEFINE_SSM_KERNELS(f16, __half)
+DEFINE_SSM_KERNELS(bf16, __nv_bfloat16)
@@ -1,7 +1,9 @@
 use std::{collections::HashMap, iter::zip, ptr::NonNull};
 
 use candle_core::{
-    cuda_backend::cudarc::driver::{CudaSlice, DevicePtr, LaunchAsync, LaunchConfig},
+    cuda_backend::cudarc::driver::{
+        result as cudarc_result, sys as cudarc_sys, CudaSlice, DevicePtr, LaunchAsync, LaunchConfig,
+    },
     DType, Device, IndexOp, Storage, Tensor,
 };
 
@@ -13,20 +15,61 @@ use crate::{
     try_api,
 };
 
-use super::{RESHAPE_AND_CACHE_KERNEL, RESHAPE_AND_CACHE_PTX};
+use super::{
+    cpu::{copy_blocks_cpu, reshape_and_cache_cpu, swap_blocks_cpu},
+    RESHAPE_AND_CACHE_FP8_KERNEL, RESHAPE_AND_CACHE_INT8_KERNEL, RESHAPE_AND_CACHE_KERNEL,
+    RESHAPE_AND_CACHE_MLA_KERNEL, RESHAPE_AND_CACHE_NHD_KERNEL, RESHAPE_AND_CACHE_PTX,
+};
+
+/// Whether `key_cache` has the NHD layout ([num_blocks, block_size, num_heads, head_size]) rather
+/// than [num_blocks, num_heads, head_size/x, block_size, x], see `KVCacheLayout`.
+pub fn is_nhd_layout(key_cache: &Tensor) -> bool {
+    key_cache.rank() == 4
+}
+
+/// The `(block_size, num_heads)` of a key cache in either layout.
+pub fn key_cache_block_dims(key_cache: &Tensor) -> (usize, usize) {
+    let dims = key_cache.dims();
+    if is_nhd_layout(key_cache) {
+        (dims[1], dims[2])
+    } else {
+        (dims[3], dims[1])
+    }
+}
 
+/// Write the new `key` and `value` of each token into the paged caches at the slot given by
+/// `slot_mapping` (`block_number * block_size + block_offset`). Negative slots are padding and
+/// are skipped. Tensors which are not on a CUDA device use the reference implementation.
+///
 /// # Safety
 /// Unsafe due to passing pointers
 pub unsafe fn reshape_and_cache(
     key: Tensor,              // [num_tokens, num_heads, head_size]
     value: Tensor,            // [num_tokens, num_heads, head_size]
-    key_cache: &mut Tensor,   // [num_blocks, num_heads, head_size/x, block_size, x]
-    value_cache: &mut Tensor, // [num_blocks, num_heads, head_size, block_size]
+    key_cache: &mut Tensor,   // [num_blocks, num_heads, head_size/x, block_size, x] or NHD
+    value_cache: &mut Tensor, // [num_blocks, num_heads, head_size, block_size] or NHD
     slot_mapping: Tensor,     // [num_tokens]
 ) -> Result<(), APIError> {
     let cache_dev = key.device();
+    let nhd_layout = is_nhd_layout(key_cache);
+    if nhd_layout && !cache_dev.is_cuda() {
+        return Err(APIError::new_str(
+            "The NHD KV cache layout is only supported by the CUDA kernels.",
+        ));
+    }
+    #[cfg(feature = "metal")]
+    if let Device::Metal(dev) = cache_dev {
+        return super::metal::reshape_and_cache_metal(
+            &key,
+            &value,
+            key_cache,
+            value_cache,
+            &slot_mapping,
+            dev,
+        );
+    }
     let Device::Cuda(dev) = cache_dev else {
-        panic!("Expected the key to be on a CUDA device.")
+        return reshape_and_cache_cpu(&key, &value, key_cache, value_cache, &slot_mapping);
     };
 
     if slot_mapping.dtype() != DType::I64 {
@@ -94,8 +137,14 @@ pub unsafe fn reshape_and_cache(
     let num_tokens = key.dims()[0];
     let num_heads = key.dims()[1];
     let head_size = key.dims()[2];
-    let block_size = key_cache.dims()[3];
-    let x = key_cache.dims()[4];
+
+    // Each thread block reads the slot of its token.
+    if slot_mapping.elem_count() != num_tokens {
+        return Err(APIError::new(format!(
+            "`slot_mapping` has {} slots for {num_tokens} tokens.",
+            slot_mapping.elem_count()
+        )));
+    }
 
     let key_stride = key.stride()[0];
     let value_stride = value.stride()[0];
@@ -114,7 +163,11 @@ pub unsafe fn reshape_and_cache(
 
     let kernel = try_api!(get_or_load_func(
         RESHAPE_AND_CACHE_PTX,
-        RESHAPE_AND_CACHE_KERNEL,
+        if nhd_layout {
+            RESHAPE_AND_CACHE_NHD_KERNEL
+        } else {
+            RESHAPE_AND_CACHE_KERNEL
+        },
         key.dtype(),
         None,
         dev
@@ -124,7 +177,31 @@ pub unsafe fn reshape_and_cache(
     let value_ptr = dispatch_get_cuda_pointer(value);
     let key_cache_ptr = dispatch_get_cuda_pointer(key_cache.clone());
     let value_cache_ptr = dispatch_get_cuda_pointer(value_cache.clone());
+    let slot_mapping_ptr = dispatch_get_cuda_pointer(slot_mapping);
+
+    if nhd_layout {
+        try_api!(unsafe {
+            kernel.launch_on_stream(
+                &stream,
+                launch_conf,
+                (
+                    key_ptr,
+                    value_ptr,
+                    key_cache_ptr,
+                    value_cache_ptr,
+                    slot_mapping_ptr,
+                    key_stride as i32,
+                    value_stride as i32,
+                    num_heads as i32,
+                    head_size as i32,
+                ),
+            )
+        });
+        return Ok(());
+    }
 
+    let block_size = key_cache.dims()[3];
+    let x = key_cache.dims()[4];
     try_api!(unsafe {
         kernel.launch_on_stream(
             &stream,
@@ -134,12 +211,94 @@ pub unsafe fn reshape_and_cache(
                 value_ptr,
                 key_cache_ptr,
                 value_cache_ptr,
-                key_stride,
-                value_stride,
-                num_heads,
-                head_size,
-                block_size,
-                x,
+                slot_mapping_ptr,
+                key_stride as i32,
+                value_stride as i32,
+                num_heads as i32,
+                head_size as i32,
+                block_size as i32,
+                x as i32,
+            ),
+        )
+    });
+
+    Ok(())
+}
+
+/// Like `reshape_and_cache`, but quantizes the keys and values to FP8 (e4m3) while writing them
+/// into U8 caches. Each head is divided by its scale in `kv_scales` ([2, num_heads], the key
+/// scales followed by the value scales) before quantization, a scale of 1 is used if not given.
+///
+/// # Safety
+/// Unsafe due to passing pointers
+pub unsafe fn reshape_and_cache_fp8(
+    key: Tensor,              // [num_tokens, num_heads, head_size]
+    value: Tensor,            // [num_tokens, num_heads, head_size]
+    key_cache: &mut Tensor,   // [num_blocks, num_heads, head_size/x, block_size, x]
+    value_cache: &mut Tensor, // [num_blocks, num_heads, head_size, block_size]
+    slot_mapping: Tensor,     // [num_tokens]
+    kv_scales: Option<&Tensor>,
+) -> Result<(), APIError> {
+    let Device::Cuda(dev) = key.device() else {
+        return Err(APIError::new_str(
+            "The FP8 KV cache is only supported on CUDA devices.",
+        ));
+    };
+    _check_quantized_cache_inputs(&key, &value, key_cache, value_cache, &slot_mapping, "FP8")?;
+
+    let num_tokens = key.dims()[0];
+    let num_heads = key.dims()[1];
+    let head_size = key.dims()[2];
+    let block_size = key_cache.dims()[3];
+    let x = key_cache.dims()[4];
+
+    let key_stride = key.stride()[0];
+    let value_stride = value.stride()[0];
+
+    let stream = try_api!(dev.fork_default_stream());
+
+    let launch_conf = LaunchConfig {
+        grid_dim: (num_tokens.try_into().unwrap(), 1u32, 1u32),
+        block_dim: (
+            512.min((num_heads * head_size).try_into().unwrap()),
+            1u32,
+            1u32,
+        ),
+        shared_mem_bytes: 0,
+    };
+
+    let kernel = try_api!(get_or_load_func(
+        RESHAPE_AND_CACHE_PTX,
+        RESHAPE_AND_CACHE_FP8_KERNEL,
+        key.dtype(),
+        None,
+        dev
+    ));
+
+    // Keep the converted scales alive until the launch has been queued.
+    let kv_scales = try_api!(kv_scales.map(|x| x.to_dtype(DType::F32)).transpose());
+    let kv_scales_ptr = kv_scales
+        .as_ref()
+        .map(|x| dispatch_get_cuda_pointer(x.clone()))
+        .unwrap_or(0);
+
+    try_api!(unsafe {
+        kernel.launch_on_stream(
+            &stream,
+            launch_conf,
+            (
+                dispatch_get_cuda_pointer(key),
+                dispatch_get_cuda_pointer(value),
+                dispatch_get_cuda_pointer(key_cache.clone()),
+                dispatch_get_cuda_pointer(value_cache.clone()),
+                dispatch_get_cuda_pointer(slot_mapping),
+                kv_scales_ptr,
+                key_stride as i32,
+                value_stride as i32,
+                num_heads as i32,
+                head_size as i32,
+                block_size as i32,
+                x as i32,
             ),
         )
     });
@@ -147,6 +306,292 @@ pub unsafe fn reshape_and_cache(
     Ok(())
 }
 
+/// Like `reshape_and_cache`, but quantizes the keys and values to INT8 while writing them into
+/// U8 caches. Each token and head gets its own scale, which is stored in the block next to the
+/// quantized values (see `kernels/int8_kv_cache.cuh`).
+///
+/// # Safety
+/// Unsafe due to passing pointers
+pub unsafe fn reshape_and_cache_int8(
+    key: Tensor,              // [num_tokens, num_heads, head_size]
+    value: Tensor,            // [num_tokens, num_heads, head_size]
+    key_cache: &mut Tensor,   // [num_blocks, num_heads, head_size/x + 1, block_size, x]
+    value_cache: &mut Tensor, // [num_blocks, num_heads, head_size + x, block_size]
+    slot_mapping: Tensor,     // [num_tokens]
+) -> Result<(), APIError> {
+    let Device::Cuda(dev) = key.device() else {
+        return Err(APIError::new_str(
+            "The INT8 KV cache is only supported on CUDA devices.",
+        ));
+    };
+    _check_quantized_cache_inputs(&key, &value, key_cache, value_cache, &slot_mapping, "INT8")?;
+
+    let num_tokens = key.dims()[0];
+    let num_heads = key.dims()[1];
+    let head_size = key.dims()[2];
+    let block_size = key_cache.dims()[3];
+    let x = key_cache.dims()[4];
+
+    let key_stride = key.stride()[0];
+    let value_stride = value.stride()[0];
+
+    let stream = try_api!(dev.fork_default_stream());
+
+    // One warp per head, as each head is reduced to its scale.
+    let launch_conf = LaunchConfig {
+        grid_dim: (num_tokens.try_into().unwrap(), 1u32, 1u32),
+        block_dim: (512.min((num_heads * 32).try_into().unwrap()), 1u32, 1u32),
+        shared_mem_bytes: 0,
+    };
+
+    let kernel = try_api!(get_or_load_func(
+        RESHAPE_AND_CACHE_PTX,
+        RESHAPE_AND_CACHE_INT8_KERNEL,
+        key.dtype(),
+        None,
+        dev
+    ));
+
+    try_api!(unsafe {
+        kernel.launch_on_stream(
+            &stream,
+            launch_conf,
+            (
+                dispatch_get_cuda_pointer(key),
+                dispatch_get_cuda_pointer(value),
+                dispatch_get_cuda_pointer(key_cache.clone()),
+                dispatch_get_cuda_pointer(value_cache.clone()),
+                dispatch_get_cuda_pointer(slot_mapping),
+                key_stride as i32,
+                value_stride as i32,
+                num_heads as i32,
+                head_size as i32,
+                block_size as i32,
+                x as i32,
+            ),
+        )
+    });
+
+    Ok(())
+}
+
+/// Validate the inputs of the kernels writing into a quantized (U8) cache.
+fn _check_quantized_cache_inputs(
+    key: &Tensor,
+    value: &Tensor,
+    key_cache: &Tensor,
+    value_cache: &Tensor,
+    slot_mapping: &Tensor,
+    format: &str,
+) -> Result<(), APIError> {
+    if is_nhd_layout(key_cache) {
+        return Err(APIError::new(format!(
+            "The {format} KV cache does not support the NHD cache layout."
+        )));
+    }
+    if slot_mapping.dtype() != DType::I64 {
+        return Err(APIError::new(format!(
+            "`slot_mapping` has {:?} type, expected I64 type.",
+            slot_mapping.dtype()
+        )));
+    }
+    if slot_mapping.elem_count() != key.dims()[0] {
+        return Err(APIError::new(format!(
+            "`slot_mapping` has {} slots for {} tokens.",
+            slot_mapping.elem_count(),
+            key.dims()[0]
+        )));
+    }
+    if !matches!(key.dtype(), DType::F32 | DType::F16 | DType::BF16) || key.dtype() != value.dtype()
+    {
+        return Err(APIError::new(format!(
+            "Cannot quantize `key` and `value` of types {:?} and {:?} to {format}.",
+            key.dtype(),
+            value.dtype()
+        )));
+    }
+    if key_cache.dtype() != DType::U8 || value_cache.dtype() != DType::U8 {
+        return Err(APIError::new(format!(
+            "Expected U8 {format} caches, got {:?} and {:?}.",
+            key_cache.dtype(),
+            value_cache.dtype()
+        )));
+    }
+    Ok(())
+}
+
+/// Write the new latent KV entries of multi-head latent attention into the latent cache at the
+/// slot given by `slot_mapping`, see `paged_attention::mla`. Each entry is the normalized latent
+/// followed by the rotated rope key. Negative slots are padding and are skipped.
+///
+/// # Safety
+/// Unsafe due to passing pointers
+pub unsafe fn reshape_and_cache_mla(
+    kv_latent: Tensor,     // [num_tokens, kv_lora_rank]
+    k_pe: Tensor,          // [num_tokens, qk_rope_head_dim]
+    kv_cache: &mut Tensor, // [num_blocks, block_size, 1, kv_lora_rank + qk_rope_head_dim]
+    slot_mapping: Tensor,  // [num_tokens]
+) -> Result<(), APIError> {
+    let Device::Cuda(dev) = kv_cache.device() else {
+        return Err(APIError::new_str(
+            "The latent KV cache is only supported by the CUDA kernels.",
+        ));
+    };
+    if slot_mapping.dtype() != DType::I64 {
+        return Err(APIError::new(format!(
+            "`slot_mapping` has {:?} type, expected I64 type.",
+            slot_mapping.dtype()
+        )));
+    }
+    if kv_latent.dtype() != k_pe.dtype() || kv_latent.dtype() != kv_cache.dtype() {
+        return Err(APIError::new(format!(
+            "`kv_latent`, `k_pe` and `kv_cache` have different data types, got {:?}, {:?} and {:?} respectively.",
+            kv_latent.dtype(),
+            k_pe.dtype(),
+            kv_cache.dtype()
+        )));
+    }
+    if !kv_latent.device().same_device(kv_cache.device())
+        || !k_pe.device().same_device(kv_cache.device())
+    {
+        return Err(APIError::new(format!(
+            "`kv_latent` and `k_pe` must be on the device of `kv_cache`, got {:?} and {:?}.",
+            kv_latent.device(),
+            k_pe.device()
+        )));
+    }
+
+    let (num_tokens, kv_lora_rank) = try_api!(kv_latent.dims2());
+    let (_, qk_rope_head_dim) = try_api!(k_pe.dims2());
+    let entry_size = try_api!(kv_cache.dim(3));
+    if kv_lora_rank + qk_rope_head_dim != entry_size {
+        return Err(APIError::new(format!(
+            "Cannot cache a latent of {kv_lora_rank} and a rope key of {qk_rope_head_dim} elements in entries of {entry_size} elements."
+        )));
+    }
+    if slot_mapping.elem_count() != num_tokens {
+        return Err(APIError::new(format!(
+            "`slot_mapping` has {} slots for {num_tokens} tokens.",
+            slot_mapping.elem_count()
+        )));
+    }
+
+    let kv_latent_stride = kv_latent.stride()[0];
+    let k_pe_stride = k_pe.stride()[0];
+
+    let stream = try_api!(dev.fork_default_stream());
+    let launch_conf = LaunchConfig {
+        grid_dim: (num_tokens.try_into().unwrap(), 1u32, 1u32),
+        block_dim: (512.min(entry_size.try_into().unwrap()), 1u32, 1u32),
+        shared_mem_bytes: 0,
+    };
+    let kernel = try_api!(get_or_load_func(
+        RESHAPE_AND_CACHE_PTX,
+        RESHAPE_AND_CACHE_MLA_KERNEL,
+        kv_latent.dtype(),
+        None,
+        dev
+    ));
+
+    try_api!(unsafe {
+        kernel.launch_on_stream(
+            &stream,
+            launch_conf,
+            (
+                dispatch_get_cuda_pointer(kv_latent),
+                dispatch_get_cuda_pointer(k_pe),
+                dispatch_get_cuda_pointer(kv_cache.clone()),
+                dispatch_get_cuda_pointer(slot_mapping),
+                kv_latent_stride as i32,
+                k_pe_stride as i32,
+                kv_lora_rank as i32,
+                entry_size as i32,
+            ),
+        )
+    });
+
+    Ok(())
+}
+
+/// Gather the cached keys and values of one sequence into contiguous tensors, for attention
+/// implementations which do not read the paged cache directly. Works on any device.
+///
+/// Returns `(key, value)`, each of shape [context_len, num_heads, head_size].
+pub fn gather_cached_kv(
+    key_cache: &Tensor,   // [num_blocks, num_heads, head_size/x, block_size, x] or NHD
+    value_cache: &Tensor, // [num_blocks, num_heads, head_size, block_size] or NHD
+    block_table: &[usize],
+    context_len: usize,
+) -> Result<(Tensor, Tensor), APIError> {
+    if is_nhd_layout(key_cache) {
+        // The blocks are already [block_size, num_heads, head_size].
+        let blocks = try_api!(Tensor::new(
+            block_table.iter().map(|x| *x as u32).collect::<Vec<_>>(),
+            key_cache.device()
+        ));
+        let gather = |cache: &Tensor| -> Result<Tensor, APIError> {
+            let gathered = try_api!(try_api!(cache.index_select(&blocks, 0)).flatten(0, 1));
+            gathered.narrow(0, 0, context_len).map_err(APIError::from)
+        };
+        return Ok((gather(key_cache)?, gather(value_cache)?));
+    }
+    let (_num_blocks, num_heads, head_split, block_size, x) = try_api!(key_cache.dims5());
+    let head_size = head_split * x;
+    let mut keys = Vec::new();
+    let mut values = Vec::new();
+    for block in block_table {
+        // [num_heads, head_size/x, block_size, x] -> [block_size, num_heads, head_size]
+        let key = try_api!(try_api!(key_cache.i(*block)).permute((2, 0, 1, 3)));
+        keys.push(try_api!(key.reshape((block_size, num_heads, head_size))));
+        // [num_heads, head_size, block_size] -> [block_size, num_heads, head_size]
+        let value = try_api!(try_api!(value_cache.i(*block)).permute((2, 0, 1)));
+        values.push(try_api!(value.contiguous()));
+    }
+    let key = try_api!(try_api!(Tensor::cat(&keys, 0)).narrow(0, 0, context_len));
+    let value = try_api!(try_api!(Tensor::cat(&values, 0)).narrow(0, 0, context_len));
+    Ok((key, value))
+}
+
+/// Gather the cached keys and values of the given token slots (`block_number * block_size +
+/// block_offset`) into contiguous tensors. Works on any device.
+///
+/// Returns `(key, value)`, each of shape [num_slots, num_heads, head_size].
+pub fn gather_cached_slots(
+    key_cache: &Tensor,   // [num_blocks, num_heads, head_size/x, block_size, x] or NHD
+    value_cache: &Tensor, // [num_blocks, num_heads, head_size, block_size] or NHD
+    slots: &[usize],
+) -> Result<(Tensor, Tensor), APIError> {
+    let (block_size, num_heads) = key_cache_block_dims(key_cache);
+    let nhd_layout = is_nhd_layout(key_cache);
+    let mut keys = Vec::with_capacity(slots.len());
+    let mut values = Vec::with_capacity(slots.len());
+    for slot in slots {
+        let (block, offset) = (slot / block_size, slot % block_size);
+        let (key, value) = if nhd_layout {
+            (
+                try_api!(key_cache.i((block, offset))),
+                try_api!(value_cache.i((block, offset))),
+            )
+        } else {
+            // [num_heads, head_size/x, x] and [num_heads, head_size]
+            (
+                try_api!(try_api!(key_cache.i((block, .., .., offset))).reshape((num_heads, ()))),
+                try_api!(value_cache.i((block, .., .., offset))),
+            )
+        };
+        keys.push(key);
+        values.push(value);
+    }
+    Ok((
+        try_api!(Tensor::stack(&keys, 0)),
+        try_api!(Tensor::stack(&values, 0)),
+    ))
+}
+
+/// For each `(src, dsts)` pair in `block_mapping`, copy block `src` to every block in `dsts`
+/// in each layer's key and value cache. Used for copy-on-write of shared blocks. Caches which
+/// are not on a CUDA device use the reference implementation.
+///
 /// # Safety
 /// Unsafe due to passing pointers
 pub unsafe fn copy_blocks(
@@ -154,10 +599,15 @@ pub unsafe fn copy_blocks(
     value_caches: Vec<&mut Tensor>,
     block_mapping: HashMap<usize, Vec<usize>>,
 ) -> Result<(), APIError> {
-    let cache_dev = key_caches.first().unwrap().device();
-    let Device::Cuda(dev) = cache_dev else {
-        panic!("Expected the key caches to be on a CUDA device.")
+    let cache_dev = key_caches.first().unwrap().device().clone();
+    #[cfg(feature = "metal")]
+    if let Device::Metal(dev) = &cache_dev {
+        return super::metal::copy_blocks_metal(key_caches, value_caches, block_mapping, dev);
+    }
+    let Device::Cuda(dev) = &cache_dev else {
+        return copy_blocks_cpu(key_caches, value_caches, block_mapping);
     };
+    let cache_dev = &cache_dev;
     if !cache_dev.same_device(value_caches.first().unwrap().device()) {
         return Err(APIError::new(format!(
             "`key` and `value` caches have different devices, got {:?} and {:?} respectively.",
@@ -265,6 +715,8 @@ pub unsafe fn copy_blocks(
     Ok(())
 }
 
+/// Copy whole blocks from `src` to `dst` (`src block -> dst block`), possibly across devices.
+/// Used to swap blocks between the GPU and CPU caches.
 pub fn swap_blocks(
     src: Tensor,
     dst: &mut Tensor,
@@ -314,29 +766,9 @@ pub fn swap_blocks(
                 try_api!(dst_dev.htod_sync_copy_into(&src_slice[src_offset..src_offset+block_size_in_bytes], &mut dst_slice));
             }
         }
-        (Device::Cuda(src_dev), Device::Cpu) => {
-            // Pending on huggingface/candle#1467
-            todo!();
-            /*let (src_storage, src_layout) = src.storage_and_layout();
-            let (dst_storage, dst_layout) = dst.storage_mut_and_layout();
-            assert!(matches!(&*src_storage, Storage::Cuda(_)));
-            assert!(matches!(&*dst_storage, Storage::Cpu(_)));
-            let Storage::Cuda(src_storage) = &*src_storage else { unreachable!() };
-            let Storage::Cpu(dst_storage) = &*dst_storage else { unreachable!() };
-            let src_ptr = src_storage.as_cuda_slice::<u8>().map_err(APIError::from)?.device_ptr() + TryInto::<u64>::try_into(src_layout.start_offset()).unwrap();
-            let dst_slice: &[u8] = try_api!(dst_storage.as_slice());
-            let ptr = dst_slice.as_ptr() as *mut u8;
-            // Safety:
-            let dst_slice = unsafe { slice::from_raw_parts_mut(ptr, dst_slice.len()) };
-
-            for (src_block_number, dst_block_number) in block_mapping {
-                let src_offset: u64 = (src_block_number * block_size_in_bytes).try_into().unwrap();
-                let dst_offset: u64 = (dst_block_number * block_size_in_bytes).try_into().unwrap();
-                // u8s because we copy by bytes
-                let src_slice: CudaSlice<u8> = unsafe { src_dev.upgrade_device_ptr(src_ptr+src_offset, block_size_in_bytes) };
-                
-                try_api!(src_dev.dtoh_sync_copy_into(&src_slice, dst_slice));
-            }*/
+        (Device::Cuda(_), Device::Cpu) | (Device::Cpu, Device::Cpu) => {
+            // Pending on huggingface/candle#1467 for a direct device to host copy.
+            swap_blocks_cpu(&src, dst, &block_mapping)?;
         }
         (src, dst) => {
             return Err(APIError::new(format!("Tensors must be on either the GPU or CPU to swap,, got {src:?} (src) and {dst:?} (dst).")))
@@ -345,3 +777,53 @@ pub fn swap_blocks(
 
     Ok(())
 }
+
+fn can_access_peer(src_ordinal: usize, dst_ordinal: usize) -> Result<bool, APIError> {
+    let src = try_api!(cudarc_result::device::get(src_ordinal.try_into().unwrap()));
+    let dst = try_api!(cudarc_result::device::get(dst_ordinal.try_into().unwrap()));
+    let mut can_access = 0;
+    try_api!(unsafe { cudarc_sys::cuDeviceCanAccessPeer(&mut can_access, dst, src) }.result());
+    Ok(can_access != 0)
+}
+
+/// Copy whole blocks from the cache `src` on one CUDA device to the cache `dst` on another
+/// (`src block -> dst block`), used to migrate running sequences between GPUs. The copy goes
+/// over the peer-to-peer link when the devices support it and is staged through host memory
+/// otherwise.
+pub fn migrate_blocks(
+    src: &Tensor,
+    dst: &mut Tensor,
+    block_mapping: &HashMap<usize, usize>,
+) -> Result<(), APIError> {
+    let ordinals = match (src.device(), dst.device()) {
+        (Device::Cuda(src_dev), Device::Cuda(dst_dev)) => {
+            Some((src_dev.ordinal(), dst_dev.ordinal()))
+        }
+        _ => None,
+    };
+    match ordinals {
+        Some((src_ordinal, dst_ordinal)) if src_ordinal == dst_ordinal => {
+            swap_blocks(src.clone(), dst, block_mapping.clone())
+        }
+        Some((src_ordinal, dst_ordinal)) if can_access_peer(src_ordinal, dst_ordinal)? => {
+            let block_size_in_bytes =
+                src.dtype().size_in_bytes() * src.dims()[1..].iter().product::<usize>();
+            let src_ptr = dispatch_get_cuda_pointer(src.clone());
+            let dst_ptr = dispatch_get_cuda_pointer(dst.clone());
+            for (src_block_number, dst_block_number) in block_mapping {
+                let src_offset: u64 = (src_block_number * block_size_in_bytes).try_into().unwrap();
+                let dst_offset: u64 = (dst_block_number * block_size_in_bytes).try_into().unwrap();
+                // With unified addressing, a device to device copy across GPUs uses the peer link.
+                try_api!(unsafe {
+                    cudarc_result::memcpy_dtod_sync(
+                        dst_ptr + dst_offset,
+                        src_ptr + src_offset,
+                        block_size_in_bytes,
+                    )
+                });
+            }
+            Ok(())
+        }
+        _ => swap_blocks_cpu(src, dst, block_mapping),
+    }
+}
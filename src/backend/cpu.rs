@@ -0,0 +1,607 @@
+//! CPU implementations of the cache, rotary embedding, decode attention and state-space
+//! operations, used when the tensors live on the CPU. Like the CUDA kernels, they write into the
+//! caches in place, so the engine runs without a GPU. Block swaps between devices use candle
+//! tensor ops.
+
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, IndexOp, Storage, Tensor, WithDType};
+use half::{bf16, f16};
+use rayon::prelude::*;
+
+use crate::{openai::responses::APIError, try_api};
+
+use super::check_tree_mask;
+
+/// The element types of the caches, converted to `f32` for the attention math.
+trait CacheElem: WithDType {
+    fn to_f32(self) -> f32;
+}
+
+impl CacheElem for f32 {
+    fn to_f32(self) -> f32 {
+        self
+    }
+}
+
+impl CacheElem for f16 {
+    fn to_f32(self) -> f32 {
+        f16::to_f32(self)
+    }
+}
+
+impl CacheElem for bf16 {
+    fn to_f32(self) -> f32 {
+        bf16::to_f32(self)
+    }
+}
+
+/// Call `$func::<T>($args)` with `T` the element type of `$dtype`.
+macro_rules! dispatch_cache_dtype {
+    ($dtype:expr, $func:ident($($args:expr),*)) => {
+        match $dtype {
+            DType::F32 => $func::<f32>($($args),*),
+            DType::F16 => $func::<f16>($($args),*),
+            DType::BF16 => $func::<bf16>($($args),*),
+            other => Err(APIError::new(format!(
+                "Unsupported data type {other:?} for the CPU kernels."
+            ))),
+        }
+    };
+}
+
+/// The elements of a CPU tensor, from its first element to the end of its storage.
+fn cpu_slice<T: WithDType>(tensor: &Tensor) -> Result<&[T], APIError> {
+    let (storage, layout) = tensor.storage_and_layout();
+    let Storage::Cpu(storage) = &*storage else {
+        return Err(APIError::new(format!(
+            "Expected a CPU tensor, got {:?}.",
+            tensor.device()
+        )));
+    };
+    let slice = &try_api!(storage.as_slice::<T>())[layout.start_offset()..];
+    // The storage is kept alive by `tensor`, the guard only protects against concurrent
+    // mutation through candle, which the engine does not do for the caches.
+    Ok(unsafe { std::slice::from_raw_parts(slice.as_ptr(), slice.len()) })
+}
+
+/// A mutable view of a CPU tensor, to write into it in place.
+///
+/// # Safety
+/// No other view of the storage of `tensor` may be alive.
+#[allow(clippy::mut_from_ref)]
+unsafe fn cpu_slice_mut<T: WithDType>(tensor: &Tensor) -> Result<&mut [T], APIError> {
+    let slice = cpu_slice::<T>(tensor)?;
+    Ok(std::slice::from_raw_parts_mut(
+        slice.as_ptr() as *mut T,
+        slice.len(),
+    ))
+}
+
+/// Write `key` and `value` into the paged caches at the slots given by `slot_mapping`.
+/// Slots which are negative (padding) are skipped.
+///
+/// - key: [num_tokens, num_heads, head_size]
+/// - value: [num_tokens, num_heads, head_size]
+/// - key_cache: [num_blocks, num_heads, head_size/x, block_size, x]
+/// - value_cache: [num_blocks, num_heads, head_size, block_size]
+/// - slot_mapping: [num_tokens]
+pub fn reshape_and_cache_cpu(
+    key: &Tensor,
+    value: &Tensor,
+    key_cache: &mut Tensor,
+    value_cache: &mut Tensor,
+    slot_mapping: &Tensor,
+) -> Result<(), APIError> {
+    if key.dtype() != key_cache.dtype() || value.dtype() != value_cache.dtype() {
+        return Err(APIError::new_str(
+            "The CPU kernels only support caches in the model data type.",
+        ));
+    }
+    let key = try_api!(key.contiguous());
+    let value = try_api!(value.contiguous());
+    let slots = try_api!(try_api!(slot_mapping.to_dtype(DType::I64)).to_vec1::<i64>());
+    dispatch_cache_dtype!(
+        key.dtype(),
+        reshape_and_cache_typed(&key, &value, key_cache, value_cache, &slots)
+    )
+}
+
+fn reshape_and_cache_typed<T: WithDType>(
+    key: &Tensor,
+    value: &Tensor,
+    key_cache: &Tensor,
+    value_cache: &Tensor,
+    slots: &[i64],
+) -> Result<(), APIError> {
+    let (_num_blocks, num_heads, head_split, block_size, x) = try_api!(key_cache.dims5());
+    let head_size = head_split * x;
+    let key = cpu_slice::<T>(key)?;
+    let value = cpu_slice::<T>(value)?;
+    let key_cache = unsafe { cpu_slice_mut::<T>(key_cache)? };
+    let value_cache = unsafe { cpu_slice_mut::<T>(value_cache)? };
+
+    for (token, slot) in slots.iter().enumerate() {
+        if *slot < 0 {
+            continue;
+        }
+        let block = *slot as usize / block_size;
+        let offset = *slot as usize % block_size;
+        for head in 0..num_heads {
+            let src = (token * num_heads + head) * head_size;
+            let dst = (block * num_heads + head) * head_size * block_size;
+            for (i, chunk) in key[src..src + head_size].chunks_exact(x).enumerate() {
+                let k_dst = dst + (i * block_size + offset) * x;
+                key_cache[k_dst..k_dst + x].copy_from_slice(chunk);
+            }
+            for (i, v) in value[src..src + head_size].iter().enumerate() {
+                value_cache[dst + i * block_size + offset] = *v;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// For each `(src, dsts)` pair, copy block `src` into every block in `dsts`, in each layer.
+pub fn copy_blocks_cpu(
+    key_caches: Vec<&mut Tensor>,
+    value_caches: Vec<&mut Tensor>,
+    block_mapping: HashMap<usize, Vec<usize>>,
+) -> Result<(), APIError> {
+    for cache in key_caches.into_iter().chain(value_caches) {
+        dispatch_cache_dtype!(cache.dtype(), copy_blocks_typed(cache, &block_mapping))?;
+    }
+    Ok(())
+}
+
+fn copy_blocks_typed<T: WithDType>(
+    cache: &Tensor,
+    block_mapping: &HashMap<usize, Vec<usize>>,
+) -> Result<(), APIError> {
+    let numel_per_block = cache.dims()[1..].iter().product::<usize>();
+    let cache = unsafe { cpu_slice_mut::<T>(cache)? };
+    for (src, dsts) in block_mapping {
+        let src = src * numel_per_block;
+        for dst in dsts {
+            cache.copy_within(src..src + numel_per_block, dst * numel_per_block);
+        }
+    }
+    Ok(())
+}
+
+/// Copy block `src` of `src` into block `dst` of `dst` for each mapping, moving the data to
+/// the device of `dst`.
+pub fn swap_blocks_cpu(
+    src: &Tensor,
+    dst: &mut Tensor,
+    block_mapping: &HashMap<usize, usize>,
+) -> Result<(), APIError> {
+    for (src_block, dst_block) in block_mapping {
+        let block = try_api!(try_api!(src.i(*src_block..*src_block + 1)).to_device(dst.device()));
+        let mut ranges = vec![*dst_block..*dst_block + 1];
+        ranges.extend(dst.dims()[1..].iter().map(|dim| 0..*dim));
+        *dst = try_api!(dst.slice_assign(&ranges, &block));
+    }
+    Ok(())
+}
+
+/// Apply the rotary embedding to `query` and `key`, see `rotary_embedding`.
+///
+/// - positions: [num_tokens]
+/// - query: [num_tokens, num_heads * head_size]
+/// - key: [num_tokens, num_kv_heads * head_size]
+/// - cos_sin_cache: [max_position, rot_dim], the cosines followed by the sines
+pub fn rotary_embedding_cpu(
+    positions: &Tensor,
+    query: &mut Tensor,
+    key: &mut Tensor,
+    head_size: usize,
+    cos_sin_cache: &Tensor,
+    is_neox: bool,
+) -> Result<(), APIError> {
+    let positions = try_api!(try_api!(positions.flatten_all()).to_vec1::<i64>());
+    let cos_sin_cache = try_api!(try_api!(cos_sin_cache.to_dtype(DType::F32)).to_vec2::<f32>());
+    for x in [query, key] {
+        let dtype = x.dtype();
+        let shape = x.shape().clone();
+        let row_len = shape.dims().last().unwrap();
+        let mut data =
+            try_api!(try_api!(try_api!(x.to_dtype(DType::F32)).flatten_all()).to_vec1::<f32>());
+        for (row, position) in data.chunks_exact_mut(*row_len).zip(&positions) {
+            let cos_sin = &cos_sin_cache[*position as usize];
+            let embed_dim = cos_sin.len() / 2;
+            let (cos, sin) = cos_sin.split_at(embed_dim);
+            for head in row.chunks_exact_mut(head_size) {
+                for rot_offset in 0..embed_dim {
+                    // GPT-NeoX style rotates the halves of the head, GPT-J style adjacent pairs.
+                    let (x_index, y_index) = if is_neox {
+                        (rot_offset, embed_dim + rot_offset)
+                    } else {
+                        (2 * rot_offset, 2 * rot_offset + 1)
+                    };
+                    let (a, b) = (head[x_index], head[y_index]);
+                    head[x_index] = a * cos[rot_offset] - b * sin[rot_offset];
+                    head[y_index] = b * cos[rot_offset] + a * sin[rot_offset];
+                }
+            }
+        }
+        *x = try_api!(try_api!(Tensor::from_vec(data, shape, &Device::Cpu)).to_dtype(dtype));
+    }
+    Ok(())
+}
+
+/// Decode attention over the paged caches, see `paged_attention_v1`. Each (query token, head)
+/// pair is computed on its own thread. Returns the output, shape = [num_seqs * num_query_tokens,
+/// num_heads, head_size].
+#[allow(clippy::too_many_arguments)]
+pub fn paged_attention_cpu(
+    query: &Tensor,       // [num_seqs * num_query_tokens, num_heads, head_size]
+    key_cache: &Tensor,   // [num_blocks, num_kv_heads, head_size/x, block_size, x]
+    value_cache: &Tensor, // [num_blocks, num_kv_heads, head_size, block_size]
+    num_key_value_heads: usize,
+    scale: f32,
+    block_tables: &Tensor, // [num_seqs, max_num_blocks_per_seq]
+    context_lens: &Tensor, // [num_seqs]
+    alibi_slopes: Option<&Tensor>,
+    block_position_shifts: Option<&Tensor>, // [num_seqs, max_num_blocks_per_seq]
+    tree_mask: Option<&Tensor>,             // [num_query_tokens]
+    rope_theta: Option<f32>,
+    sliding_window: Option<usize>,
+    logits_soft_cap: Option<f32>,
+) -> Result<Tensor, APIError> {
+    let (num_queries, num_heads, head_size) = try_api!(query.dims3());
+    let num_seqs = try_api!(context_lens.dim(0));
+    if num_seqs == 0 || num_queries % num_seqs != 0 {
+        return Err(APIError::new(format!(
+            "Cannot split {num_queries} query tokens between {num_seqs} sequences."
+        )));
+    }
+    if let Some(tree_mask) = tree_mask {
+        check_tree_mask(tree_mask, num_queries / num_seqs)?;
+    }
+    let to_vec2 = |x: &Tensor| -> Result<Vec<Vec<i64>>, APIError> {
+        Ok(try_api!(try_api!(
+            try_api!(x.reshape((num_seqs, ()))).to_dtype(DType::I64)
+        )
+        .to_vec2::<i64>()))
+    };
+    let inputs = AttentionInputs {
+        query: try_api!(try_api!(try_api!(query.to_dtype(DType::F32)).flatten_all()).to_vec1()),
+        block_tables: to_vec2(block_tables)?,
+        context_lens: try_api!(try_api!(context_lens.to_dtype(DType::I64)).to_vec1()),
+        alibi_slopes: match alibi_slopes {
+            Some(slopes) => Some(try_api!(try_api!(slopes.to_dtype(DType::F32)).to_vec1())),
+            None => None,
+        },
+        // Keys are only re-rotated for models with rotary embeddings, as in the CUDA kernels.
+        rotation: match (block_position_shifts, rope_theta) {
+            (Some(shifts), Some(rope_theta)) => Some((to_vec2(shifts)?, rope_theta)),
+            _ => None,
+        },
+        tree_mask: match tree_mask {
+            Some(mask) => Some(try_api!(try_api!(mask.to_dtype(DType::I64)).to_vec1())),
+            None => None,
+        },
+        num_kv_heads: num_key_value_heads,
+        num_query_tokens: num_queries / num_seqs,
+        num_heads,
+        head_size,
+        scale,
+        sliding_window: sliding_window.unwrap_or(0),
+        logits_soft_cap,
+    };
+    let out = dispatch_cache_dtype!(
+        key_cache.dtype(),
+        paged_attention_typed(&inputs, key_cache, value_cache)
+    )?;
+    let out = try_api!(Tensor::from_vec(
+        out,
+        (num_queries, num_heads, head_size),
+        &Device::Cpu
+    ));
+    out.to_dtype(query.dtype()).map_err(APIError::from)
+}
+
+struct AttentionInputs {
+    query: Vec<f32>,
+    block_tables: Vec<Vec<i64>>,
+    context_lens: Vec<i64>,
+    alibi_slopes: Option<Vec<f32>>,
+    /// The block position shifts and the base of the rotary embedding.
+    rotation: Option<(Vec<Vec<i64>>, f32)>,
+    /// The mask of each query token of a sequence, see `paged_attention_v1`.
+    tree_mask: Option<Vec<i64>>,
+    num_kv_heads: usize,
+    num_query_tokens: usize,
+    num_heads: usize,
+    head_size: usize,
+    scale: f32,
+    sliding_window: usize,
+    logits_soft_cap: Option<f32>,
+}
+
+fn paged_attention_typed<T: CacheElem>(
+    inputs: &AttentionInputs,
+    key_cache: &Tensor,
+    value_cache: &Tensor,
+) -> Result<Vec<f32>, APIError> {
+    let (_num_blocks, _, head_split, block_size, x) = try_api!(key_cache.dims5());
+    let (block_stride, head_stride) = (key_cache.stride()[0], key_cache.stride()[1]);
+    let key_cache = cpu_slice::<T>(key_cache)?;
+    let value_cache = cpu_slice::<T>(value_cache)?;
+    let head_size = inputs.head_size;
+    debug_assert_eq!(head_split * x, head_size);
+    let queries_per_kv = inputs.num_heads / inputs.num_kv_heads;
+
+    let mut out = vec![0f32; inputs.query.len()];
+    out.par_chunks_mut(head_size)
+        .enumerate()
+        .for_each(|(idx, out)| {
+            let (query, head) = (idx / inputs.num_heads, idx % inputs.num_heads);
+            let seq = query / inputs.num_query_tokens;
+            let kv_head = head / queries_per_kv;
+            let q = &inputs.query[idx * head_size..(idx + 1) * head_size];
+            let block_table = &inputs.block_tables[seq];
+            // The later query tokens of the sequence are masked out, see `paged_attention_v1`.
+            let num_later_tokens = inputs.num_query_tokens - 1 - query % inputs.num_query_tokens;
+            let context_len = inputs.context_lens[seq] as usize - num_later_tokens;
+            let window_start = if inputs.sliding_window > 0 {
+                context_len.saturating_sub(inputs.sliding_window)
+            } else {
+                0
+            };
+            let alibi_slope = inputs.alibi_slopes.as_ref().map_or(0., |s| s[head]);
+            // The query tokens outside of the tree mask of the query token are skipped.
+            let query_start = inputs.context_lens[seq] as usize - inputs.num_query_tokens;
+            let tree_mask = inputs
+                .tree_mask
+                .as_ref()
+                .map(|masks| masks[query % inputs.num_query_tokens] as u64);
+            let attends = |token: usize| match tree_mask {
+                Some(mask) if token >= query_start => (mask >> (token - query_start)) & 1 == 1,
+                _ => true,
+            };
+
+            let mut k = vec![0f32; head_size];
+            let mut logits = Vec::with_capacity(context_len - window_start);
+            for token in window_start..context_len {
+                let block = block_table[token / block_size] as usize;
+                let offset = token % block_size;
+                let base = block * block_stride + kv_head * head_stride;
+                for (i, k_i) in k.iter_mut().enumerate() {
+                    let idx = base + ((i / x) * block_size + offset) * x + i % x;
+                    *k_i = key_cache[idx].to_f32();
+                }
+                if let Some((shifts, rope_theta)) = &inputs.rotation {
+                    let shift = shifts[seq][token / block_size];
+                    if shift != 0 {
+                        rotate_key(&mut k, -(shift as f32), *rope_theta);
+                    }
+                }
+                let mut qk = q.iter().zip(&k).map(|(q, k)| q * k).sum::<f32>() * inputs.scale;
+                if let Some(cap) = inputs.logits_soft_cap {
+                    qk = cap * (qk / cap).tanh();
+                }
+                if !attends(token) {
+                    qk = f32::NEG_INFINITY;
+                }
+                logits.push(qk + alibi_slope * (token as f32 - context_len as f32 + 1.));
+            }
+
+            let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            let mut sum = 0.;
+            for logit in logits.iter_mut() {
+                *logit = (*logit - max).exp();
+                sum += *logit;
+            }
+            let inv_sum = 1. / (sum + 1e-6);
+
+            for (token, weight) in (window_start..context_len).zip(&logits) {
+                let block = block_table[token / block_size] as usize;
+                let offset = token % block_size;
+                let base = block * block_stride + kv_head * head_stride;
+                let weight = weight * inv_sum;
+                for (i, out_i) in out.iter_mut().enumerate() {
+                    *out_i += weight * value_cache[base + i * block_size + offset].to_f32();
+                }
+            }
+        });
+    Ok(out)
+}
+
+/// Rotate a key cached at another position by `delta` positions, see `rotate_key` in
+/// `attention_kernel.cu`.
+fn rotate_key(k: &mut [f32], delta: f32, rope_theta: f32) {
+    let head_size = k.len();
+    let (x1, x2) = k.split_at_mut(head_size / 2);
+    for (i, (a, b)) in x1.iter_mut().zip(x2.iter_mut()).enumerate() {
+        let inv_freq = rope_theta.powf(-2. * i as f32 / head_size as f32);
+        let (sin, cos) = (delta * inv_freq).sin_cos();
+        let (a0, b0) = (*a, *b);
+        *a = a0 * cos - b0 * sin;
+        *b = b0 * cos + a0 * sin;
+    }
+}
+
+/// A sequence of a state-space kernel: its tokens, its state slot and whether it continues from
+/// the state in its slot instead of a zero state.
+struct SsmSequence {
+    tokens: std::ops::Range<usize>,
+    slot: Option<usize>,
+    has_state: bool,
+}
+
+fn ssm_sequences(
+    cu_seqlens: &Tensor,
+    slots: &Tensor,
+    num_prompts: usize,
+) -> Result<Vec<SsmSequence>, APIError> {
+    let cu_seqlens = try_api!(cu_seqlens.to_vec1::<u32>());
+    let slots = try_api!(slots.to_vec1::<i64>());
+    Ok(slots
+        .iter()
+        .enumerate()
+        .map(|(i, slot)| {
+            let slot = usize::try_from(*slot).ok();
+            SsmSequence {
+                tokens: cu_seqlens[i] as usize..cu_seqlens[i + 1] as usize,
+                slot,
+                has_state: slot.is_some() && i >= num_prompts,
+            }
+        })
+        .collect())
+}
+
+fn to_vec_f32(x: &Tensor) -> Result<Vec<f32>, APIError> {
+    Ok(try_api!(try_api!(
+        try_api!(x.to_dtype(DType::F32)).flatten_all()
+    )
+    .to_vec1::<f32>()))
+}
+
+fn silu(x: f32) -> f32 {
+    x / (1. + (-x).exp())
+}
+
+/// The causal convolution of a Mamba mixer, see `causal_conv1d`.
+///
+/// - x: [num_tokens, dim]
+/// - weight: [dim, width]
+/// - bias: [dim]
+/// - conv_state: [num_slots, dim, width - 1]
+pub fn causal_conv1d_cpu(
+    x: &Tensor,
+    weight: &Tensor,
+    bias: Option<&Tensor>,
+    conv_state: &Tensor,
+    cu_seqlens: &Tensor,
+    slots: &Tensor,
+    num_prompts: usize,
+) -> Result<Tensor, APIError> {
+    let (num_tokens, dim) = try_api!(x.dims2());
+    let (_, width) = try_api!(weight.dims2());
+    let inputs = ConvInputs {
+        x: to_vec_f32(x)?,
+        weight: to_vec_f32(weight)?,
+        bias: match bias {
+            Some(bias) => to_vec_f32(bias)?,
+            None => vec![0.; dim],
+        },
+        seqs: ssm_sequences(cu_seqlens, slots, num_prompts)?,
+        dim,
+        width,
+    };
+    let out = dispatch_cache_dtype!(conv_state.dtype(), causal_conv1d_typed(&inputs, conv_state))?;
+    let out = try_api!(Tensor::from_vec(out, (num_tokens, dim), &Device::Cpu));
+    out.to_dtype(x.dtype()).map_err(APIError::from)
+}
+
+struct ConvInputs {
+    x: Vec<f32>,
+    weight: Vec<f32>,
+    bias: Vec<f32>,
+    seqs: Vec<SsmSequence>,
+    dim: usize,
+    width: usize,
+}
+
+fn causal_conv1d_typed<T: CacheElem>(
+    inputs: &ConvInputs,
+    conv_state: &Tensor,
+) -> Result<Vec<f32>, APIError> {
+    let (dim, width) = (inputs.dim, inputs.width);
+    let conv_state = unsafe { cpu_slice_mut::<T>(conv_state)? };
+    let mut out = vec![0.; inputs.x.len()];
+    for seq in &inputs.seqs {
+        for c in 0..dim {
+            let state = seq.slot.map(|slot| (slot * dim + c) * (width - 1));
+            let mut window = vec![0.; width];
+            if let (Some(state), true) = (state, seq.has_state) {
+                for (i, w) in window[..width - 1].iter_mut().enumerate() {
+                    *w = conv_state[state + i].to_f32();
+                }
+            }
+            let weight = &inputs.weight[c * width..(c + 1) * width];
+            for t in seq.tokens.clone() {
+                window[width - 1] = inputs.x[t * dim + c];
+                let acc =
+                    inputs.bias[c] + weight.iter().zip(&window).map(|(w, x)| w * x).sum::<f32>();
+                out[t * dim + c] = silu(acc);
+                window.rotate_left(1);
+            }
+            if let Some(state) = state {
+                for (i, w) in window[..width - 1].iter().enumerate() {
+                    conv_state[state + i] = T::from_f64(f64::from(*w));
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// The selective scan of a Mamba mixer, see `selective_scan`.
+///
+/// - u, delta, z: [num_tokens, dim]
+/// - a: [dim, d_state] (f32)
+/// - b, c: [num_tokens, d_state]
+/// - d: [dim] (f32)
+/// - ssm_state: [num_slots, dim, d_state] (f32)
+#[allow(clippy::too_many_arguments)]
+pub fn selective_scan_cpu(
+    u: &Tensor,
+    delta: &Tensor,
+    a: &Tensor,
+    b: &Tensor,
+    c: &Tensor,
+    d: &Tensor,
+    z: &Tensor,
+    ssm_state: &Tensor,
+    cu_seqlens: &Tensor,
+    slots: &Tensor,
+    num_prompts: usize,
+) -> Result<Tensor, APIError> {
+    let (num_tokens, dim) = try_api!(u.dims2());
+    let (_, d_state) = try_api!(a.dims2());
+    let (u_data, delta, a, b, c, d, z) = (
+        to_vec_f32(u)?,
+        to_vec_f32(delta)?,
+        to_vec_f32(a)?,
+        to_vec_f32(b)?,
+        to_vec_f32(c)?,
+        to_vec_f32(d)?,
+        to_vec_f32(z)?,
+    );
+    let ssm_state = unsafe { cpu_slice_mut::<f32>(ssm_state)? };
+    let mut out = vec![0.; num_tokens * dim];
+    for seq in ssm_sequences(cu_seqlens, slots, num_prompts)? {
+        for ch in 0..dim {
+            let state = seq.slot.map(|slot| (slot * dim + ch) * d_state);
+            let mut h = match (state, seq.has_state) {
+                (Some(state), true) => ssm_state[state..state + d_state].to_vec(),
+                _ => vec![0.; d_state],
+            };
+            let a = &a[ch * d_state..(ch + 1) * d_state];
+            for t in seq.tokens.clone() {
+                let idx = t * dim + ch;
+                let dt = match delta[idx] {
+                    x if x <= 20. => x.exp().ln_1p(),
+                    x => x,
+                };
+                let u_t = u_data[idx];
+                let b = &b[t * d_state..(t + 1) * d_state];
+                let c = &c[t * d_state..(t + 1) * d_state];
+                let mut y = 0.;
+                for (((h, a), b), c) in h.iter_mut().zip(a).zip(b).zip(c) {
+                    *h = (dt * a).exp() * *h + dt * b * u_t;
+                    y += *h * c;
+                }
+                out[idx] = (y + d[ch] * u_t) * silu(z[idx]);
+            }
+            if let Some(state) = state {
+                ssm_state[state..state + d_state].copy_from_slice(&h);
+            }
+        }
+    }
+    let out = try_api!(Tensor::from_vec(out, (num_tokens, dim), &Device::Cpu));
+    out.to_dtype(u.dtype()).map_err(APIError::from)
+}
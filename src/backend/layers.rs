@@ -1,15 +1,25 @@
 use candle_core::{
-    cuda_backend::cudarc::driver::{LaunchAsync, LaunchConfig},
+    cuda_backend::cudarc::driver::{result as cudarc_result, sys as cudarc_sys},
+    cuda_backend::cudarc::driver::{DeviceRepr, LaunchAsync, LaunchConfig},
     DType, Device, Tensor,
 };
 
 use crate::{
-    backend::{get_or_load_func, ROTARY_EMBDEDDING_KERNEL, ROTARY_EMBDEDDING_PTX},
+    backend::{
+        get_or_load_func, reshape_and_cache, CAUSAL_CONV1D_KERNEL, FP8_GEMM_KERNEL, FP8_GEMM_PTX,
+        FP8_W8A8_GEMM_KERNEL, INT4_GEMM_KERNEL, INT4_GEMM_PTX, MARLIN_GEMM_KERNEL, MARLIN_GEMM_PTX,
+        MOE_GROUPED_GEMM_KERNEL, MOE_PTX, ROTARY_EMBDEDDING_KERNEL, ROTARY_EMBDEDDING_PTX,
+        ROTARY_EMBEDDING_AND_CACHE_KERNEL, ROTARY_EMBEDDING_AND_CACHE_PTX, SELECTIVE_SCAN_KERNEL,
+        SELECTIVE_SCAN_PTX,
+    },
     openai::responses::APIError,
     try_api,
 };
 
-use super::dispatch_get_cuda_pointer;
+use super::{
+    cpu::{causal_conv1d_cpu, rotary_embedding_cpu, selective_scan_cpu},
+    dispatch_get_cuda_pointer,
+};
 
 /// # Safety
 /// Unsafe due to passing pointers
@@ -22,6 +32,21 @@ pub unsafe fn rotary_embedding(
     is_neox: bool,
 ) -> Result<(), APIError> {
     let positions_dev = positions.device().clone();
+    if positions_dev.is_cpu() {
+        return rotary_embedding_cpu(&positions, query, key, head_size, &cos_sin_cache, is_neox);
+    }
+    #[cfg(feature = "metal")]
+    if let Device::Metal(dev) = &positions_dev {
+        return super::metal::rotary_embedding_metal(
+            &positions,
+            query,
+            key,
+            head_size,
+            &cos_sin_cache,
+            is_neox,
+            dev,
+        );
+    }
     let Device::Cuda(dev) = positions_dev else {
         panic!("Expected the positions to be on a CUDA device.")
     };
@@ -121,3 +146,948 @@ pub unsafe fn rotary_embedding(
 
     Ok(())
 }
+
+/// Mirrors `RotaryEmbeddingAndCacheParams` in `rotary_embedding_and_cache_kernel.cu`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RotaryEmbeddingAndCacheParams {
+    rot_dim: i32,
+    query_stride: i32,
+    key_stride: i32,
+    value_stride: i32,
+    num_heads: i32,
+    num_kv_heads: i32,
+    head_size: i32,
+    block_size: i32,
+    x: i32,
+}
+
+unsafe impl DeviceRepr for RotaryEmbeddingAndCacheParams {}
+
+/// Apply the rotary embedding to `query` and `key` like `rotary_embedding`, and write the rotated
+/// keys and the values into the paged caches like `reshape_and_cache`, in a single kernel. Tensors
+/// which are not on a CUDA device run the two operations one after the other.
+///
+/// # Safety
+/// Unsafe due to passing pointers
+#[allow(clippy::too_many_arguments)]
+pub unsafe fn rotary_embedding_and_cache(
+    positions: Tensor,        // [num_tokens]
+    query: &mut Tensor,       // [num_tokens, num_heads, head_size]
+    key: &mut Tensor,         // [num_tokens, num_kv_heads, head_size]
+    value: Tensor,            // [num_tokens, num_kv_heads, head_size]
+    key_cache: &mut Tensor,   // [num_blocks, num_kv_heads, head_size/x, block_size, x]
+    value_cache: &mut Tensor, // [num_blocks, num_kv_heads, head_size, block_size]
+    slot_mapping: Tensor,     // [num_tokens]
+    cos_sin_cache: Tensor,    // [max_position, rot_dim]
+    is_neox: bool,
+) -> Result<(), APIError> {
+    let Device::Cuda(dev) = positions.device().clone() else {
+        let (num_tokens, num_heads, head_size) = try_api!(query.dims3());
+        let num_kv_heads = try_api!(key.dim(1));
+        // The reference implementations take the heads of a token as one row.
+        let mut query_rows = try_api!(query.reshape((num_tokens, num_heads * head_size)));
+        let mut key_rows = try_api!(key.reshape((num_tokens, num_kv_heads * head_size)));
+        rotary_embedding(
+            positions,
+            &mut query_rows,
+            &mut key_rows,
+            head_size,
+            cos_sin_cache,
+            is_neox,
+        )?;
+        *query = try_api!(query_rows.reshape((num_tokens, num_heads, head_size)));
+        *key = try_api!(key_rows.reshape((num_tokens, num_kv_heads, head_size)));
+        return reshape_and_cache(key.clone(), value, key_cache, value_cache, slot_mapping);
+    };
+
+    for (name, tensor) in [("positions", &positions), ("slot_mapping", &slot_mapping)] {
+        if tensor.dtype() != DType::I64 {
+            return Err(APIError::new(format!(
+                "`{name}` has {:?} type, expected I64 type.",
+                tensor.dtype()
+            )));
+        }
+    }
+    for (name, tensor) in [
+        ("key", &*key),
+        ("value", &value),
+        ("key_cache", &*key_cache),
+        ("value_cache", &*value_cache),
+        ("cos_sin_cache", &cos_sin_cache),
+    ] {
+        if tensor.dtype() != query.dtype() {
+            return Err(APIError::new(format!(
+                "`query` and `{name}` have different data types, got {:?} and {:?} respectively.",
+                query.dtype(),
+                tensor.dtype()
+            )));
+        }
+        if !tensor.device().same_device(query.device()) {
+            return Err(APIError::new(format!(
+                "`query` and `{name}` have different devices, got {:?} and {:?} respectively.",
+                query.device(),
+                tensor.device()
+            )));
+        }
+    }
+    if !matches!(query.dtype(), DType::F32 | DType::F16 | DType::BF16) {
+        return Err(APIError::new(format!(
+            "Unsupported data type {:?}",
+            query.dtype()
+        )));
+    }
+
+    let (num_tokens, num_heads, head_size) = try_api!(query.dims3());
+    let num_kv_heads = try_api!(key.dim(1));
+    let rot_dim = try_api!(cos_sin_cache.dim(1));
+    let params = RotaryEmbeddingAndCacheParams {
+        rot_dim: rot_dim as i32,
+        query_stride: query.stride()[0] as i32,
+        key_stride: key.stride()[0] as i32,
+        value_stride: value.stride()[0] as i32,
+        num_heads: num_heads as i32,
+        num_kv_heads: num_kv_heads as i32,
+        head_size: head_size as i32,
+        block_size: try_api!(key_cache.dim(3)) as i32,
+        x: try_api!(key_cache.dim(4)) as i32,
+    };
+
+    let launch_conf = LaunchConfig {
+        grid_dim: (num_tokens.try_into().unwrap(), 1u32, 1u32),
+        block_dim: (
+            512.min(
+                (num_heads.max(num_kv_heads) * head_size)
+                    .try_into()
+                    .unwrap(),
+            ),
+            1u32,
+            1u32,
+        ),
+        shared_mem_bytes: 0,
+    };
+
+    let kernel = try_api!(get_or_load_func(
+        ROTARY_EMBEDDING_AND_CACHE_PTX,
+        ROTARY_EMBEDDING_AND_CACHE_KERNEL,
+        query.dtype(),
+        is_neox.then_some("_neox"),
+        &dev
+    ));
+
+    let positions_ptr = dispatch_get_cuda_pointer(positions);
+    let query_ptr = dispatch_get_cuda_pointer(query.clone());
+    let key_ptr = dispatch_get_cuda_pointer(key.clone());
+    let value_ptr = dispatch_get_cuda_pointer(value);
+    let key_cache_ptr = dispatch_get_cuda_pointer(key_cache.clone());
+    let value_cache_ptr = dispatch_get_cuda_pointer(value_cache.clone());
+    let slot_mapping_ptr = dispatch_get_cuda_pointer(slot_mapping);
+    let cos_sin_cache_ptr = dispatch_get_cuda_pointer(cos_sin_cache);
+
+    let stream = try_api!(dev.fork_default_stream());
+
+    try_api!(unsafe {
+        kernel.launch_on_stream(
+            &stream,
+            launch_conf,
+            (
+                positions_ptr,
+                query_ptr,
+                key_ptr,
+                value_ptr,
+                key_cache_ptr,
+                value_cache_ptr,
+                slot_mapping_ptr,
+                cos_sin_cache_ptr,
+                params,
+            ),
+        )
+    });
+
+    Ok(())
+}
+
+/// The (major, minor) compute capability of the CUDA device with the given ordinal.
+pub fn compute_capability(ordinal: usize) -> Result<(i32, i32), APIError> {
+    let device = try_api!(cudarc_result::device::get(ordinal.try_into().unwrap()));
+    let mut major = 0;
+    let mut minor = 0;
+    try_api!(unsafe {
+        cudarc_sys::cuDeviceGetAttribute(
+            &mut major,
+            cudarc_sys::CUdevice_attribute::CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MAJOR,
+            device,
+        )
+    }
+    .result());
+    try_api!(unsafe {
+        cudarc_sys::cuDeviceGetAttribute(
+            &mut minor,
+            cudarc_sys::CUdevice_attribute::CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MINOR,
+            device,
+        )
+    }
+    .result());
+    Ok((major, minor))
+}
+
+/// Number of streaming multiprocessors of the CUDA device with the given ordinal.
+pub fn multiprocessor_count(ordinal: usize) -> Result<usize, APIError> {
+    let device = try_api!(cudarc_result::device::get(ordinal.try_into().unwrap()));
+    let mut count = 0;
+    try_api!(unsafe {
+        cudarc_sys::cuDeviceGetAttribute(
+            &mut count,
+            cudarc_sys::CUdevice_attribute::CU_DEVICE_ATTRIBUTE_MULTIPROCESSOR_COUNT,
+            device,
+        )
+    }
+    .result());
+    Ok(count as usize)
+}
+
+/// The name of the CUDA device with the given ordinal, e.g. "NVIDIA A100-SXM4-80GB".
+pub fn device_name(ordinal: usize) -> Result<String, APIError> {
+    let device = try_api!(cudarc_result::device::get(ordinal.try_into().unwrap()));
+    let mut name = [0 as std::ffi::c_char; 256];
+    try_api!(
+        unsafe { cudarc_sys::cuDeviceGetName(name.as_mut_ptr(), name.len() as i32, device) }
+            .result()
+    );
+    let name = unsafe { std::ffi::CStr::from_ptr(name.as_ptr()) };
+    Ok(name.to_string_lossy().into_owned())
+}
+
+/// Compute `x @ (weight * weight_scale)^T` where `weight` holds FP8 (e4m3) values stored as U8,
+/// without materializing the dequantized weight.
+///
+/// - x: [m, k]
+/// - weight: [n, k], U8
+/// - weight_scale: [n] (per channel) or [1] (per tensor), F32
+///
+/// # Safety
+/// Unsafe due to passing pointers
+pub unsafe fn fp8_gemm(
+    x: &Tensor,
+    weight: &Tensor,
+    weight_scale: &Tensor,
+) -> Result<Tensor, APIError> {
+    let Device::Cuda(dev) = x.device().clone() else {
+        panic!("Expected `x` to be on a CUDA device.")
+    };
+
+    if weight.dtype() != DType::U8 {
+        return Err(APIError::new(format!(
+            "`weight` has {:?} type, expected U8 type.",
+            weight.dtype()
+        )));
+    }
+
+    if weight_scale.dtype() != DType::F32 {
+        return Err(APIError::new(format!(
+            "`weight_scale` has {:?} type, expected F32 type.",
+            weight_scale.dtype()
+        )));
+    }
+
+    let (m, k) = try_api!(x.dims2());
+    let (n, weight_k) = try_api!(weight.dims2());
+    if k != weight_k {
+        return Err(APIError::new(format!(
+            "`x` has {k} columns but `weight` has {weight_k} columns."
+        )));
+    }
+    let per_channel = weight_scale.elem_count() == n && n != 1;
+
+    let x = try_api!(x.contiguous());
+    let out = try_api!(Tensor::zeros((m, n), x.dtype(), x.device()));
+
+    const TILE_SIZE: u32 = 16;
+    let launch_conf = LaunchConfig {
+        grid_dim: (
+            (n as u32).div_ceil(TILE_SIZE),
+            (m as u32).div_ceil(TILE_SIZE),
+            1u32,
+        ),
+        block_dim: (TILE_SIZE, TILE_SIZE, 1u32),
+        shared_mem_bytes: 0,
+    };
+
+    let x_ptr = dispatch_get_cuda_pointer(x.clone());
+    let weight_ptr = dispatch_get_cuda_pointer(weight.clone());
+    let weight_scale_ptr = dispatch_get_cuda_pointer(weight_scale.clone());
+    let out_ptr = dispatch_get_cuda_pointer(out.clone());
+
+    let stream = try_api!(dev.fork_default_stream());
+
+    let kernel = try_api!(get_or_load_func(
+        FP8_GEMM_PTX,
+        FP8_GEMM_KERNEL,
+        x.dtype(),
+        None,
+        &dev
+    ));
+
+    try_api!(unsafe {
+        kernel.launch_on_stream(
+            &stream,
+            launch_conf,
+            (
+                x_ptr,
+                weight_ptr,
+                weight_scale_ptr,
+                out_ptr,
+                m as i32,
+                n as i32,
+                k as i32,
+                per_channel,
+            ),
+        )
+    });
+
+    Ok(out)
+}
+
+/// Compute `x @ (weight * weight_scale)^T` like `fp8_gemm`, with the activations quantized to FP8
+/// (e4m3) as well: `(quant(x / x_scale) @ weight^T) * x_scale * weight_scale`.
+///
+/// - x: [m, k]
+/// - x_scale: [m] (per token) or [1] (per tensor), F32
+/// - weight: [n, k], U8
+/// - weight_scale: [n] (per channel) or [1] (per tensor), F32
+///
+/// # Safety
+/// Unsafe due to passing pointers
+pub unsafe fn fp8_w8a8_gemm(
+    x: &Tensor,
+    x_scale: &Tensor,
+    weight: &Tensor,
+    weight_scale: &Tensor,
+) -> Result<Tensor, APIError> {
+    let Device::Cuda(dev) = x.device().clone() else {
+        panic!("Expected `x` to be on a CUDA device.")
+    };
+
+    if weight.dtype() != DType::U8 {
+        return Err(APIError::new(format!(
+            "`weight` has {:?} type, expected U8 type.",
+            weight.dtype()
+        )));
+    }
+
+    if x_scale.dtype() != DType::F32 || weight_scale.dtype() != DType::F32 {
+        return Err(APIError::new(format!(
+            "`x_scale` and `weight_scale` have {:?} and {:?} types, expected F32 type.",
+            x_scale.dtype(),
+            weight_scale.dtype()
+        )));
+    }
+
+    let (m, k) = try_api!(x.dims2());
+    let (n, weight_k) = try_api!(weight.dims2());
+    if k != weight_k {
+        return Err(APIError::new(format!(
+            "`x` has {k} columns but `weight` has {weight_k} columns."
+        )));
+    }
+    let per_token = x_scale.elem_count() == m && m != 1;
+    let per_channel = weight_scale.elem_count() == n && n != 1;
+
+    let x = try_api!(x.contiguous());
+    let x_scale = try_api!(x_scale.contiguous());
+    let out = try_api!(Tensor::zeros((m, n), x.dtype(), x.device()));
+
+    const TILE_SIZE: u32 = 16;
+    let launch_conf = LaunchConfig {
+        grid_dim: (
+            (n as u32).div_ceil(TILE_SIZE),
+            (m as u32).div_ceil(TILE_SIZE),
+            1u32,
+        ),
+        block_dim: (TILE_SIZE, TILE_SIZE, 1u32),
+        shared_mem_bytes: 0,
+    };
+
+    let x_ptr = dispatch_get_cuda_pointer(x.clone());
+    let x_scale_ptr = dispatch_get_cuda_pointer(x_scale.clone());
+    let weight_ptr = dispatch_get_cuda_pointer(weight.clone());
+    let weight_scale_ptr = dispatch_get_cuda_pointer(weight_scale.clone());
+    let out_ptr = dispatch_get_cuda_pointer(out.clone());
+
+    let stream = try_api!(dev.fork_default_stream());
+
+    let kernel = try_api!(get_or_load_func(
+        FP8_GEMM_PTX,
+        FP8_W8A8_GEMM_KERNEL,
+        x.dtype(),
+        None,
+        &dev
+    ));
+
+    try_api!(unsafe {
+        kernel.launch_on_stream(
+            &stream,
+            launch_conf,
+            (
+                x_ptr,
+                x_scale_ptr,
+                weight_ptr,
+                weight_scale_ptr,
+                out_ptr,
+                m as i32,
+                n as i32,
+                k as i32,
+                per_token,
+                per_channel,
+            ),
+        )
+    });
+
+    Ok(out)
+}
+
+/// Compute `x @ dequant(qweight)` for a 4-bit GPTQ or AWQ weight, dequantizing it inside the
+/// GEMM: `(q - zeros[group]) * scales[group]`.
+///
+/// - x: [m, k]
+/// - qweight: [k / 8, n], U32, eight 4-bit values packed along k
+/// - zeros: [num_groups, n], F32
+/// - scales: [num_groups, n], F32
+/// - g_idx: [k], U32, the group of each input row
+///
+/// # Safety
+/// Unsafe due to passing pointers
+pub unsafe fn int4_gemm(
+    x: &Tensor,
+    qweight: &Tensor,
+    zeros: &Tensor,
+    scales: &Tensor,
+    g_idx: &Tensor,
+) -> Result<Tensor, APIError> {
+    let Device::Cuda(dev) = x.device().clone() else {
+        panic!("Expected `x` to be on a CUDA device.")
+    };
+
+    for (name, tensor, dtype) in [
+        ("qweight", qweight, DType::U32),
+        ("zeros", zeros, DType::F32),
+        ("scales", scales, DType::F32),
+        ("g_idx", g_idx, DType::U32),
+    ] {
+        if tensor.dtype() != dtype {
+            return Err(APIError::new(format!(
+                "`{name}` has {:?} type, expected {dtype:?} type.",
+                tensor.dtype()
+            )));
+        }
+    }
+
+    let (m, k) = try_api!(x.dims2());
+    let (packed_k, n) = try_api!(qweight.dims2());
+    if k != packed_k * 8 || try_api!(g_idx.dims1()) != k {
+        return Err(APIError::new(format!(
+            "`x` has {k} columns but `qweight` holds {} rows.",
+            packed_k * 8
+        )));
+    }
+
+    let x = try_api!(x.contiguous());
+    let out = try_api!(Tensor::zeros((m, n), x.dtype(), x.device()));
+
+    const TILE_SIZE: u32 = 16;
+    let launch_conf = LaunchConfig {
+        grid_dim: (
+            (n as u32).div_ceil(TILE_SIZE),
+            (m as u32).div_ceil(TILE_SIZE),
+            1u32,
+        ),
+        block_dim: (TILE_SIZE, TILE_SIZE, 1u32),
+        shared_mem_bytes: 0,
+    };
+
+    let x_ptr = dispatch_get_cuda_pointer(x.clone());
+    let qweight_ptr = dispatch_get_cuda_pointer(qweight.clone());
+    let zeros_ptr = dispatch_get_cuda_pointer(zeros.clone());
+    let scales_ptr = dispatch_get_cuda_pointer(scales.clone());
+    let g_idx_ptr = dispatch_get_cuda_pointer(g_idx.clone());
+    let out_ptr = dispatch_get_cuda_pointer(out.clone());
+
+    let stream = try_api!(dev.fork_default_stream());
+
+    let kernel = try_api!(get_or_load_func(
+        INT4_GEMM_PTX,
+        INT4_GEMM_KERNEL,
+        x.dtype(),
+        None,
+        &dev
+    ));
+
+    try_api!(unsafe {
+        kernel.launch_on_stream(
+            &stream,
+            launch_conf,
+            (
+                x_ptr,
+                qweight_ptr,
+                zeros_ptr,
+                scales_ptr,
+                g_idx_ptr,
+                out_ptr,
+                m as i32,
+                n as i32,
+                k as i32,
+            ),
+        )
+    });
+
+    Ok(out)
+}
+
+/// The output tile of a block of `marlin_gemm`, in rows and columns, and its K step.
+pub const MARLIN_TILE: (usize, usize, usize) = (32, 64, 32);
+
+/// Compute `x @ dequant(qweight)` like `int4_gemm` with the Marlin-style tensor core kernel, for
+/// a weight repacked by `marlin_repack`. Requires F16 or BF16 (Ampere or newer) activations.
+///
+/// - x: [m, k]
+/// - qweight: [k / 32, n / 64, 4, 64], U32, the words of each 32 x 64 tile of the weight
+/// - zeros, scales, g_idx: as for `int4_gemm`
+///
+/// # Safety
+/// Unsafe due to passing pointers
+pub unsafe fn marlin_gemm(
+    x: &Tensor,
+    qweight: &Tensor,
+    zeros: &Tensor,
+    scales: &Tensor,
+    g_idx: &Tensor,
+) -> Result<Tensor, APIError> {
+    let Device::Cuda(dev) = x.device().clone() else {
+        panic!("Expected `x` to be on a CUDA device.")
+    };
+    if !matches!(x.dtype(), DType::F16 | DType::BF16) {
+        return Err(APIError::new(format!(
+            "`x` has {:?} type, expected F16 or BF16 type.",
+            x.dtype()
+        )));
+    }
+
+    let (block_m, block_n, block_k) = MARLIN_TILE;
+    let (m, k) = try_api!(x.dims2());
+    let n = try_api!(scales.dim(1));
+    if qweight.elem_count() * 8 != k * n || k % block_k != 0 || n % block_n != 0 {
+        return Err(APIError::new(format!(
+            "`x` has {k} columns, which does not match the repacked `qweight` of {n} columns."
+        )));
+    }
+
+    let x = try_api!(x.contiguous());
+    let out = try_api!(Tensor::zeros((m, n), x.dtype(), x.device()));
+
+    let launch_conf = LaunchConfig {
+        grid_dim: ((n / block_n) as u32, m.div_ceil(block_m) as u32, 1u32),
+        block_dim: (128, 1, 1),
+        shared_mem_bytes: 0,
+    };
+
+    let x_ptr = dispatch_get_cuda_pointer(x.clone());
+    let qweight_ptr = dispatch_get_cuda_pointer(qweight.clone());
+    let zeros_ptr = dispatch_get_cuda_pointer(zeros.clone());
+    let scales_ptr = dispatch_get_cuda_pointer(scales.clone());
+    let g_idx_ptr = dispatch_get_cuda_pointer(g_idx.clone());
+    let out_ptr = dispatch_get_cuda_pointer(out.clone());
+
+    let stream = try_api!(dev.fork_default_stream());
+
+    let kernel = try_api!(get_or_load_func(
+        MARLIN_GEMM_PTX,
+        MARLIN_GEMM_KERNEL,
+        x.dtype(),
+        None,
+        &dev
+    ));
+
+    try_api!(unsafe {
+        kernel.launch_on_stream(
+            &stream,
+            launch_conf,
+            (
+                x_ptr,
+                qweight_ptr,
+                zeros_ptr,
+                scales_ptr,
+                g_idx_ptr,
+                out_ptr,
+                m as i32,
+                n as i32,
+                k as i32,
+            ),
+        )
+    });
+
+    Ok(out)
+}
+
+/// Repack a 4-bit weight of the `int4_gemm` layout, [k / 8, n], into the tiles of `marlin_gemm`.
+pub fn marlin_repack(qweight: &Tensor) -> Result<Tensor, APIError> {
+    let (_, block_n, block_k) = MARLIN_TILE;
+    let (packed_k, n) = try_api!(qweight.dims2());
+    let words_per_tile = block_k / 8;
+    try_api!(try_api!(qweight.reshape((
+        packed_k / words_per_tile,
+        words_per_tile,
+        n / block_n,
+        block_n
+    )))
+    .permute((0, 2, 1, 3)))
+    .contiguous()
+    .map_err(APIError::from)
+}
+
+/// The number of rows sharing an expert in `moe_grouped_gemm`.
+pub const MOE_TILE_SIZE: usize = 16;
+
+/// Multiply the rows of `x` selected by `row_x_idx` with the weights of their experts:
+/// `out[row] = x[row_x_idx[row]] @ weight[expert]^T`, with `weight` of shape
+/// `[num_experts, n, k]`. The rows are grouped by expert, each group padded to a multiple of
+/// `MOE_TILE_SIZE`, and `tile_experts` holds the expert of each tile. Padding rows have
+/// `row_x_idx == u32::MAX` and are zero in the output.
+///
+/// # Safety
+/// Unsafe due to passing pointers
+pub unsafe fn moe_grouped_gemm(
+    x: &Tensor,
+    weight: &Tensor,
+    row_x_idx: &Tensor,
+    tile_experts: &Tensor,
+) -> Result<Tensor, APIError> {
+    let Device::Cuda(dev) = x.device().clone() else {
+        panic!("Expected `x` to be on a CUDA device.")
+    };
+
+    if weight.dtype() != x.dtype() {
+        return Err(APIError::new(format!(
+            "`weight` has {:?} type, expected {:?} type.",
+            weight.dtype(),
+            x.dtype()
+        )));
+    }
+    if row_x_idx.dtype() != DType::U32 || tile_experts.dtype() != DType::U32 {
+        return Err(APIError::new_str(
+            "`row_x_idx` and `tile_experts` must have U32 type.",
+        ));
+    }
+
+    let (_, k) = try_api!(x.dims2());
+    let (_, n, weight_k) = try_api!(weight.dims3());
+    if k != weight_k {
+        return Err(APIError::new(format!(
+            "`x` has {k} columns but `weight` has {weight_k} columns."
+        )));
+    }
+    let num_rows = try_api!(row_x_idx.dims1());
+    let num_tiles = try_api!(tile_experts.dims1());
+    if num_rows != num_tiles * MOE_TILE_SIZE {
+        return Err(APIError::new(format!(
+            "{num_rows} rows do not fill {num_tiles} tiles of {MOE_TILE_SIZE} rows."
+        )));
+    }
+
+    let x = try_api!(x.contiguous());
+    let weight = try_api!(weight.contiguous());
+    let out = try_api!(Tensor::zeros((num_rows, n), x.dtype(), x.device()));
+
+    let tile_size = MOE_TILE_SIZE as u32;
+    let launch_conf = LaunchConfig {
+        grid_dim: ((n as u32).div_ceil(tile_size), num_tiles as u32, 1u32),
+        block_dim: (tile_size, tile_size, 1u32),
+        shared_mem_bytes: 0,
+    };
+
+    let x_ptr = dispatch_get_cuda_pointer(x.clone());
+    let weight_ptr = dispatch_get_cuda_pointer(weight);
+    let row_x_idx_ptr = dispatch_get_cuda_pointer(row_x_idx.clone());
+    let tile_experts_ptr = dispatch_get_cuda_pointer(tile_experts.clone());
+    let out_ptr = dispatch_get_cuda_pointer(out.clone());
+
+    let stream = try_api!(dev.fork_default_stream());
+
+    let kernel = try_api!(get_or_load_func(
+        MOE_PTX,
+        MOE_GROUPED_GEMM_KERNEL,
+        x.dtype(),
+        None,
+        &dev
+    ));
+
+    try_api!(unsafe {
+        kernel.launch_on_stream(
+            &stream,
+            launch_conf,
+            (
+                x_ptr,
+                weight_ptr,
+                row_x_idx_ptr,
+                tile_experts_ptr,
+                out_ptr,
+                num_rows as i32,
+                n as i32,
+                k as i32,
+            ),
+        )
+    });
+
+    Ok(out)
+}
+
+/// Threads per block of the state-space kernels, each thread scans one channel of a sequence.
+const SSM_NUM_THREADS: usize = 128;
+/// The largest convolution width and SSM state size of the state-space kernels, see
+/// `MAX_CONV_WIDTH` and `MAX_D_STATE` in `selective_scan_kernel.cu`.
+pub const SSM_MAX_CONV_WIDTH: usize = 8;
+pub const SSM_MAX_D_STATE: usize = 64;
+
+/// The number of sequences of the step, checking the layout of the state metadata.
+fn ssm_num_seqs(num_tokens: usize, cu_seqlens: &Tensor, slots: &Tensor) -> Result<usize, APIError> {
+    if cu_seqlens.dtype() != DType::U32 || slots.dtype() != DType::I64 {
+        return Err(APIError::new(format!(
+            "`cu_seqlens` and `slots` have {:?} and {:?} types, expected U32 and I64 types.",
+            cu_seqlens.dtype(),
+            slots.dtype()
+        )));
+    }
+    let num_seqs = try_api!(slots.dims1());
+    if try_api!(cu_seqlens.dims1()) != num_seqs + 1 {
+        return Err(APIError::new(format!(
+            "`cu_seqlens` must hold {} offsets for {num_seqs} sequences.",
+            num_seqs + 1
+        )));
+    }
+    if num_tokens == 0 {
+        return Err(APIError::new_str(
+            "The state-space kernels need at least one token.",
+        ));
+    }
+    Ok(num_seqs)
+}
+
+/// The causal depthwise convolution of a Mamba mixer followed by SiLU. The tokens of each
+/// sequence are scanned in order, continuing from the convolution state of its slot, which is
+/// updated in place. See `StateMetadata` for `cu_seqlens`, `slots` and `num_prompts`.
+///
+/// - x: [num_tokens, dim]
+/// - weight: [dim, width]
+/// - bias: [dim]
+/// - conv_state: [num_slots, dim, width - 1]
+///
+/// Returns the output, shape = [num_tokens, dim].
+///
+/// # Safety
+/// Unsafe due to passing pointers
+pub unsafe fn causal_conv1d(
+    x: &Tensor,
+    weight: &Tensor,
+    bias: Option<&Tensor>,
+    conv_state: &Tensor,
+    cu_seqlens: &Tensor,
+    slots: &Tensor,
+    num_prompts: usize,
+) -> Result<Tensor, APIError> {
+    let (num_tokens, dim) = try_api!(x.dims2());
+    let (weight_dim, width) = try_api!(weight.dims2());
+    if weight_dim != dim || width > SSM_MAX_CONV_WIDTH {
+        return Err(APIError::new(format!(
+            "Unsupported convolution weight of shape {:?} for {dim} channels, expected at most {SSM_MAX_CONV_WIDTH} taps.",
+            weight.dims()
+        )));
+    }
+    let num_seqs = ssm_num_seqs(num_tokens, cu_seqlens, slots)?;
+    let x = try_api!(x.contiguous());
+    let weight = try_api!(weight.contiguous());
+    if x.device().is_cpu() {
+        return causal_conv1d_cpu(
+            &x,
+            &weight,
+            bias,
+            conv_state,
+            cu_seqlens,
+            slots,
+            num_prompts,
+        );
+    }
+    let Device::Cuda(dev) = x.device().clone() else {
+        return Err(APIError::new_str(
+            "The state-space kernels only support CPU and CUDA devices.",
+        ));
+    };
+    if weight.dtype() != x.dtype() || conv_state.dtype() != x.dtype() {
+        return Err(APIError::new(format!(
+            "`weight` and `conv_state` have {:?} and {:?} types, expected {:?} type.",
+            weight.dtype(),
+            conv_state.dtype(),
+            x.dtype()
+        )));
+    }
+    let out = try_api!(Tensor::zeros((num_tokens, dim), x.dtype(), x.device()));
+
+    let launch_conf = LaunchConfig {
+        grid_dim: (num_seqs as u32, dim.div_ceil(SSM_NUM_THREADS) as u32, 1u32),
+        block_dim: (SSM_NUM_THREADS as u32, 1u32, 1u32),
+        shared_mem_bytes: 0,
+    };
+
+    let bias_ptr = match bias {
+        Some(bias) => dispatch_get_cuda_pointer(try_api!(bias.contiguous())),
+        None => 0u64,
+    };
+    let stream = try_api!(dev.fork_default_stream());
+    let kernel = try_api!(get_or_load_func(
+        SELECTIVE_SCAN_PTX,
+        CAUSAL_CONV1D_KERNEL,
+        x.dtype(),
+        None,
+        &dev
+    ));
+
+    try_api!(unsafe {
+        kernel.launch_on_stream(
+            &stream,
+            launch_conf,
+            (
+                dispatch_get_cuda_pointer(x),
+                dispatch_get_cuda_pointer(weight),
+                bias_ptr,
+                dispatch_get_cuda_pointer(conv_state.clone()),
+                dispatch_get_cuda_pointer(cu_seqlens.clone()),
+                dispatch_get_cuda_pointer(slots.clone()),
+                dispatch_get_cuda_pointer(out.clone()),
+                dim as i32,
+                width as i32,
+                num_prompts as i32,
+            ),
+        )
+    });
+
+    Ok(out)
+}
+
+/// Mirrors `SelectiveScanParams` in `selective_scan_kernel.cu`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SelectiveScanParams {
+    dim: i32,
+    d_state: i32,
+    num_prompts: i32,
+}
+
+unsafe impl DeviceRepr for SelectiveScanParams {}
+
+/// The selective scan of a Mamba mixer: `h = exp(dt * A) * h + dt * B * u` and
+/// `y = (C . h + D * u) * silu(z)` with `dt = softplus(delta)`. The tokens of each sequence are
+/// scanned in order, continuing from the SSM state of its slot, which is updated in place. See
+/// `StateMetadata` for `cu_seqlens`, `slots` and `num_prompts`.
+///
+/// - u, delta, z: [num_tokens, dim]
+/// - a: [dim, d_state] (f32), the negative decay rates `-exp(A_log)`
+/// - b, c: [num_tokens, d_state]
+/// - d: [dim] (f32)
+/// - ssm_state: [num_slots, dim, d_state] (f32)
+///
+/// Returns the output, shape = [num_tokens, dim].
+///
+/// # Safety
+/// Unsafe due to passing pointers
+#[allow(clippy::too_many_arguments)]
+pub unsafe fn selective_scan(
+    u: &Tensor,
+    delta: &Tensor,
+    a: &Tensor,
+    b: &Tensor,
+    c: &Tensor,
+    d: &Tensor,
+    z: &Tensor,
+    ssm_state: &Tensor,
+    cu_seqlens: &Tensor,
+    slots: &Tensor,
+    num_prompts: usize,
+) -> Result<Tensor, APIError> {
+    let (num_tokens, dim) = try_api!(u.dims2());
+    let (_, d_state) = try_api!(a.dims2());
+    if d_state > SSM_MAX_D_STATE {
+        return Err(APIError::new(format!(
+            "Unsupported SSM state size: {d_state}, expected at most {SSM_MAX_D_STATE}."
+        )));
+    }
+    if a.dtype() != DType::F32 || d.dtype() != DType::F32 || ssm_state.dtype() != DType::F32 {
+        return Err(APIError::new_str(
+            "`a`, `d` and `ssm_state` must have F32 type.",
+        ));
+    }
+    let num_seqs = ssm_num_seqs(num_tokens, cu_seqlens, slots)?;
+    let [u, delta, a, b, c, d, z] =
+        [u, delta, a, b, c, d, z].map(|x| x.contiguous().map_err(APIError::from));
+    let (u, delta, a, b, c, d, z) = (u?, delta?, a?, b?, c?, d?, z?);
+    if u.device().is_cpu() {
+        return selective_scan_cpu(
+            &u,
+            &delta,
+            &a,
+            &b,
+            &c,
+            &d,
+            &z,
+            ssm_state,
+            cu_seqlens,
+            slots,
+            num_prompts,
+        );
+    }
+    let Device::Cuda(dev) = u.device().clone() else {
+        return Err(APIError::new_str(
+            "The state-space kernels only support CPU and CUDA devices.",
+        ));
+    };
+    let out = try_api!(Tensor::zeros((num_tokens, dim), u.dtype(), u.device()));
+
+    let launch_conf = LaunchConfig {
+        grid_dim: (num_seqs as u32, dim.div_ceil(SSM_NUM_THREADS) as u32, 1u32),
+        block_dim: (SSM_NUM_THREADS as u32, 1u32, 1u32),
+        shared_mem_bytes: 0,
+    };
+
+    let stream = try_api!(dev.fork_default_stream());
+    let kernel = try_api!(get_or_load_func(
+        SELECTIVE_SCAN_PTX,
+        SELECTIVE_SCAN_KERNEL,
+        u.dtype(),
+        None,
+        &dev
+    ));
+
+    try_api!(unsafe {
+        kernel.launch_on_stream(
+            &stream,
+            launch_conf,
+            (
+                dispatch_get_cuda_pointer(u),
+                dispatch_get_cuda_pointer(delta),
+                dispatch_get_cuda_pointer(a),
+                dispatch_get_cuda_pointer(b),
+                dispatch_get_cuda_pointer(c),
+                dispatch_get_cuda_pointer(d),
+                dispatch_get_cuda_pointer(z),
+                dispatch_get_cuda_pointer(ssm_state.clone()),
+                dispatch_get_cuda_pointer(cu_seqlens.clone()),
+                dispatch_get_cuda_pointer(slots.clone()),
+                dispatch_get_cuda_pointer(out.clone()),
+                SelectiveScanParams {
+                    dim: dim as i32,
+                    d_state: d_state as i32,
+                    num_prompts: num_prompts as i32,
+                },
+            ),
+        )
+    });
+
+    Ok(out)
+}
@@ -0,0 +1,348 @@
+//! Metal implementations of the cache, rotary embedding and decode attention kernels, used when
+//! the tensors live on a Metal device. The kernels in `kernels/paged_attention.metal` are
+//! compiled once at runtime.
+
+use std::{collections::HashMap, ffi::c_void, sync::Mutex};
+
+use ::metal::{
+    Buffer, CompileOptions, ComputeCommandEncoderRef, ComputePipelineState, Library, MTLSize,
+};
+use candle_core::{DType, Device, MetalDevice, Storage, Tensor};
+
+use crate::{openai::responses::APIError, try_api};
+
+const METAL_SOURCE: &str = include_str!("../../kernels/paged_attention.metal");
+
+/// Threads per threadgroup of the attention kernel, at least the largest supported head size.
+const ATTENTION_NUM_THREADS: u64 = 256;
+const MAX_HEAD_SIZE: usize = 256;
+/// Mirrors `LOGITS_CHUNK_SIZE` in `paged_attention.metal`.
+const LOGITS_CHUNK_SIZE: u64 = 1024;
+const NUM_THREADS: u64 = 512;
+
+/// Mirrors `CacheParams` in `paged_attention.metal`.
+#[repr(C)]
+struct CacheParams {
+    key_stride: i32,
+    value_stride: i32,
+    num_heads: i32,
+    head_size: i32,
+    block_size: i32,
+    x: i32,
+}
+
+/// Mirrors `RotaryParams` in `paged_attention.metal`.
+#[repr(C)]
+struct RotaryParams {
+    rot_dim: i32,
+    query_stride: i32,
+    key_stride: i32,
+    num_heads: i32,
+    num_kv_heads: i32,
+    head_size: i32,
+    is_neox: i32,
+}
+
+/// Mirrors `PagedAttentionParams` in `paged_attention.metal`.
+#[repr(C)]
+struct PagedAttentionParams {
+    num_kv_heads: i32,
+    scale: f32,
+    max_num_blocks_per_seq: i32,
+    q_stride: i32,
+    kv_block_stride: i32,
+    kv_head_stride: i32,
+    sliding_window: i32,
+    head_size: i32,
+    block_size: i32,
+    x: i32,
+    has_alibi: i32,
+    logits_soft_cap: f32,
+}
+
+struct Kernels {
+    library: Library,
+    pipelines: HashMap<String, ComputePipelineState>,
+}
+
+static KERNELS: Mutex<Option<Kernels>> = Mutex::new(None);
+
+fn get_or_load_pipeline(
+    kernel_base: &str,
+    dtype: DType,
+    dev: &MetalDevice,
+) -> Result<ComputePipelineState, APIError> {
+    let spec = match dtype {
+        DType::F32 => "f32",
+        DType::F16 => "f16",
+        DType::BF16 => "bf16",
+        other => {
+            return Err(APIError::new(format!(
+                "Unsupported data type {other:?} for the Metal kernels."
+            )))
+        }
+    };
+    let name = format!("{kernel_base}_{spec}");
+
+    let mut kernels = KERNELS.lock().unwrap();
+    if kernels.is_none() {
+        let library = try_api!(dev
+            .device()
+            .new_library_with_source(METAL_SOURCE, &CompileOptions::new()));
+        *kernels = Some(Kernels {
+            library,
+            pipelines: HashMap::new(),
+        });
+    }
+    let kernels = kernels.as_mut().unwrap();
+    if let Some(pipeline) = kernels.pipelines.get(&name) {
+        return Ok(pipeline.clone());
+    }
+    let function = try_api!(kernels.library.get_function(&name, None));
+    let pipeline = try_api!(dev
+        .device()
+        .new_compute_pipeline_state_with_function(&function));
+    kernels.pipelines.insert(name, pipeline.clone());
+    Ok(pipeline)
+}
+
+/// The Metal buffer of `tensor` and the byte offset of its first element.
+fn get_metal_buffer(tensor: &Tensor) -> (Buffer, u64) {
+    let (storage, layout) = tensor.storage_and_layout();
+    match &*storage {
+        Storage::Metal(metal_storage) => (
+            metal_storage.buffer().clone(),
+            (layout.start_offset() * tensor.dtype().size_in_bytes()) as u64,
+        ),
+        other => panic!("Unsupported storage `{:?}`", other),
+    }
+}
+
+fn set_tensor(encoder: &ComputeCommandEncoderRef, index: u64, tensor: &Tensor) {
+    let (buffer, offset) = get_metal_buffer(tensor);
+    encoder.set_buffer(index, Some(&buffer), offset);
+}
+
+fn set_params<P>(encoder: &ComputeCommandEncoderRef, index: u64, params: &P) {
+    encoder.set_bytes(
+        index,
+        std::mem::size_of::<P>() as u64,
+        params as *const P as *const c_void,
+    );
+}
+
+fn grid(width: usize, height: usize) -> MTLSize {
+    MTLSize {
+        width: width as u64,
+        height: height as u64,
+        depth: 1,
+    }
+}
+
+/// Metal version of `reshape_and_cache`, see there.
+pub fn reshape_and_cache_metal(
+    key: &Tensor,
+    value: &Tensor,
+    key_cache: &mut Tensor,
+    value_cache: &mut Tensor,
+    slot_mapping: &Tensor,
+    dev: &MetalDevice,
+) -> Result<(), APIError> {
+    if key.dtype() != key_cache.dtype() || value.dtype() != value_cache.dtype() {
+        return Err(APIError::new_str(
+            "The Metal kernels only support caches in the model data type.",
+        ));
+    }
+    let (num_tokens, num_heads, head_size) = try_api!(key.dims3());
+    let (_, _, _, block_size, x) = try_api!(key_cache.dims5());
+    let key = try_api!(key.contiguous());
+    let value = try_api!(value.contiguous());
+    let slot_mapping = try_api!(slot_mapping.to_dtype(DType::I64));
+    let params = CacheParams {
+        key_stride: key.stride()[0] as i32,
+        value_stride: value.stride()[0] as i32,
+        num_heads: num_heads as i32,
+        head_size: head_size as i32,
+        block_size: block_size as i32,
+        x: x as i32,
+    };
+
+    let pipeline = get_or_load_pipeline("reshape_and_cache", key.dtype(), dev)?;
+    let command_buffer = try_api!(dev.command_buffer());
+    let encoder = command_buffer.new_compute_command_encoder();
+    encoder.set_compute_pipeline_state(&pipeline);
+    set_tensor(encoder, 0, &key);
+    set_tensor(encoder, 1, &value);
+    set_tensor(encoder, 2, key_cache);
+    set_tensor(encoder, 3, value_cache);
+    set_tensor(encoder, 4, &slot_mapping);
+    set_params(encoder, 5, &params);
+    encoder.dispatch_thread_groups(
+        grid(num_tokens, 1),
+        grid(NUM_THREADS.min((num_heads * head_size) as u64) as usize, 1),
+    );
+    encoder.end_encoding();
+    Ok(())
+}
+
+/// Metal version of `copy_blocks`, see there.
+pub fn copy_blocks_metal(
+    key_caches: Vec<&mut Tensor>,
+    value_caches: Vec<&mut Tensor>,
+    block_mapping: HashMap<usize, Vec<usize>>,
+    dev: &MetalDevice,
+) -> Result<(), APIError> {
+    let pairs = block_mapping
+        .iter()
+        .flat_map(|(src, dsts)| dsts.iter().flat_map(|dst| [*src as i64, *dst as i64]))
+        .collect::<Vec<_>>();
+    let num_pairs = pairs.len() / 2;
+    if num_pairs == 0 {
+        return Ok(());
+    }
+    let block_mapping = try_api!(Tensor::from_vec(
+        pairs,
+        (num_pairs * 2,),
+        &Device::Metal(dev.clone())
+    ));
+
+    let command_buffer = try_api!(dev.command_buffer());
+    for cache in key_caches.into_iter().chain(value_caches) {
+        let numel_per_block = cache.dims()[1..].iter().product::<usize>() as i32;
+        let pipeline = get_or_load_pipeline("copy_blocks", cache.dtype(), dev)?;
+        let encoder = command_buffer.new_compute_command_encoder();
+        encoder.set_compute_pipeline_state(&pipeline);
+        set_tensor(encoder, 0, cache);
+        set_tensor(encoder, 1, &block_mapping);
+        set_params(encoder, 2, &numel_per_block);
+        encoder.dispatch_thread_groups(
+            grid(num_pairs, 1),
+            grid(NUM_THREADS.min(numel_per_block as u64) as usize, 1),
+        );
+        encoder.end_encoding();
+    }
+    Ok(())
+}
+
+/// Metal version of `rotary_embedding`, see there.
+pub fn rotary_embedding_metal(
+    positions: &Tensor,
+    query: &mut Tensor,
+    key: &mut Tensor,
+    head_size: usize,
+    cos_sin_cache: &Tensor,
+    is_neox: bool,
+    dev: &MetalDevice,
+) -> Result<(), APIError> {
+    let num_tokens = query.shape().elem_count() / query.shape().dims().last().unwrap();
+    let rot_dim = cos_sin_cache.dims()[1];
+    let num_heads = query.shape().dims().last().unwrap() / head_size;
+    let num_kv_heads = key.shape().dims().last().unwrap() / head_size;
+    let params = RotaryParams {
+        rot_dim: rot_dim as i32,
+        query_stride: query.stride()[query.stride().len() - 2] as i32,
+        key_stride: key.stride()[key.stride().len() - 2] as i32,
+        num_heads: num_heads as i32,
+        num_kv_heads: num_kv_heads as i32,
+        head_size: head_size as i32,
+        is_neox: is_neox as i32,
+    };
+
+    let pipeline = get_or_load_pipeline("rotary_embedding", query.dtype(), dev)?;
+    let command_buffer = try_api!(dev.command_buffer());
+    let encoder = command_buffer.new_compute_command_encoder();
+    encoder.set_compute_pipeline_state(&pipeline);
+    set_tensor(encoder, 0, positions);
+    set_tensor(encoder, 1, query);
+    set_tensor(encoder, 2, key);
+    set_tensor(encoder, 3, cos_sin_cache);
+    set_params(encoder, 4, &params);
+    encoder.dispatch_thread_groups(
+        grid(num_tokens, 1),
+        grid(
+            NUM_THREADS.min((num_heads * rot_dim / 2) as u64) as usize,
+            1,
+        ),
+    );
+    encoder.end_encoding();
+    Ok(())
+}
+
+/// Metal version of `paged_attention_v1`. The kernel runs an online softmax over the context,
+/// so it also serves the long contexts of `paged_attention_v2`. Quantized caches and re-rotated
+/// keys are not supported.
+#[allow(clippy::too_many_arguments)]
+pub fn paged_attention_metal(
+    query: &Tensor,
+    key_cache: &Tensor,
+    value_cache: &Tensor,
+    num_key_value_heads: i32,
+    scale: f32,
+    block_tables: Tensor,
+    context_lens: Tensor,
+    block_size: usize,
+    alibi_slopes: Option<Tensor>,
+    sliding_window: Option<usize>,
+    logits_soft_cap: Option<f32>,
+    kv_cache_dtype: &str,
+    dev: &MetalDevice,
+) -> Result<Tensor, APIError> {
+    if kv_cache_dtype != "auto" {
+        return Err(APIError::new(format!(
+            "The KV cache data type {kv_cache_dtype} is not supported by the Metal kernels."
+        )));
+    }
+    let (num_seqs, num_heads, head_size) = try_api!(query.dims3());
+    if try_api!(context_lens.dim(0)) != num_seqs {
+        return Err(APIError::new_str(
+            "Several query tokens per sequence are not supported by the Metal kernels.",
+        ));
+    }
+    if head_size > MAX_HEAD_SIZE {
+        return Err(APIError::new(format!("Unsupported head size: {head_size}")));
+    }
+    let x = try_api!(key_cache.dims5()).4;
+    let block_tables =
+        try_api!(try_api!(block_tables.reshape((num_seqs, ()))).to_dtype(DType::U32));
+    let context_lens = try_api!(context_lens.to_dtype(DType::U32));
+    let out = try_api!(query.zeros_like());
+    let params = PagedAttentionParams {
+        num_kv_heads: num_key_value_heads,
+        scale,
+        max_num_blocks_per_seq: block_tables.dims()[1] as i32,
+        q_stride: query.stride()[0] as i32,
+        kv_block_stride: key_cache.stride()[0] as i32,
+        kv_head_stride: key_cache.stride()[1] as i32,
+        sliding_window: sliding_window.unwrap_or(0) as i32,
+        head_size: head_size as i32,
+        block_size: block_size as i32,
+        x: x as i32,
+        has_alibi: alibi_slopes.is_some() as i32,
+        logits_soft_cap: logits_soft_cap.unwrap_or(0.),
+    };
+    // The kernel does not read the slopes without ALiBi, any buffer will do.
+    let alibi_slopes = match alibi_slopes {
+        Some(alibi_slopes) => try_api!(alibi_slopes.to_dtype(DType::F32)),
+        None => try_api!(context_lens.to_dtype(DType::F32)),
+    };
+
+    let pipeline = get_or_load_pipeline("paged_attention", query.dtype(), dev)?;
+    let command_buffer = try_api!(dev.command_buffer());
+    let encoder = command_buffer.new_compute_command_encoder();
+    encoder.set_compute_pipeline_state(&pipeline);
+    set_tensor(encoder, 0, &out);
+    set_tensor(encoder, 1, query);
+    set_tensor(encoder, 2, key_cache);
+    set_tensor(encoder, 3, value_cache);
+    set_tensor(encoder, 4, &block_tables);
+    set_tensor(encoder, 5, &context_lens);
+    set_tensor(encoder, 6, &alibi_slopes);
+    set_params(encoder, 7, &params);
+    encoder.set_threadgroup_memory_length(0, LOGITS_CHUNK_SIZE * std::mem::size_of::<f32>() as u64);
+    encoder.dispatch_thread_groups(
+        grid(num_heads, num_seqs),
+        grid(ATTENTION_NUM_THREADS as usize, 1),
+    );
+    encoder.end_encoding();
+    Ok(out)
+}
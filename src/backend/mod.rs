@@ -1,5 +1,8 @@
 mod cache;
+pub mod cpu;
 mod layers;
+#[cfg(feature = "metal")]
+mod metal;
 mod paged_attention;
 
 const COPY_BLOCKS_PTX: &str = "kernels/copy_blocks_kernel.ptx";
@@ -10,10 +13,57 @@ const RESHAPE_AND_CACHE_PTX: &str = "kernels/reshape_and_cache_kernel.ptx";
 
 const RESHAPE_AND_CACHE_KERNEL: &str = "reshape_and_cache_kernel";
 
+const RESHAPE_AND_CACHE_NHD_KERNEL: &str = "reshape_and_cache_nhd_kernel";
+
+const RESHAPE_AND_CACHE_MLA_KERNEL: &str = "reshape_and_cache_mla_kernel";
+
+const RESHAPE_AND_CACHE_FP8_KERNEL: &str = "reshape_and_cache_fp8_kernel";
+const RESHAPE_AND_CACHE_INT8_KERNEL: &str = "reshape_and_cache_int8_kernel";
+
 const ROTARY_EMBDEDDING_PTX: &str = "kernels/rotary_embedding_kernel.ptx";
 
 const ROTARY_EMBDEDDING_KERNEL: &str = "rotary_embedding_kernel";
 
+const ROTARY_EMBEDDING_AND_CACHE_PTX: &str = "kernels/rotary_embedding_and_cache_kernel.ptx";
+
+const ROTARY_EMBEDDING_AND_CACHE_KERNEL: &str = "rotary_embedding_and_cache_kernel";
+
+const PAGED_ATTENTION_PTX: &str = "kernels/attention_kernel.ptx";
+
+const PAGED_ATTENTION_V1_KERNEL: &str = "paged_attention_v1_kernel";
+
+const PAGED_ATTENTION_V2_KERNEL: &str = "paged_attention_v2_kernel";
+
+const PAGED_ATTENTION_REDUCE_KERNEL: &str = "paged_attention_v2_reduce_kernel";
+
+const MLA_ATTENTION_PTX: &str = "kernels/mla_attention_kernel.ptx";
+
+const MLA_DECODE_KERNEL: &str = "mla_decode_kernel";
+
+const FP8_GEMM_PTX: &str = "kernels/fp8_gemm_kernel.ptx";
+
+const FP8_GEMM_KERNEL: &str = "fp8_gemm_kernel";
+
+const FP8_W8A8_GEMM_KERNEL: &str = "fp8_w8a8_gemm_kernel";
+
+const INT4_GEMM_PTX: &str = "kernels/int4_gemm_kernel.ptx";
+
+const INT4_GEMM_KERNEL: &str = "int4_gemm_kernel";
+
+const MARLIN_GEMM_PTX: &str = "kernels/marlin_gemm_kernel.ptx";
+
+const MARLIN_GEMM_KERNEL: &str = "marlin_gemm_kernel";
+
+const MOE_PTX: &str = "kernels/moe_kernel.ptx";
+
+const MOE_GROUPED_GEMM_KERNEL: &str = "moe_grouped_gemm_kernel";
+
+const SELECTIVE_SCAN_PTX: &str = "kernels/selective_scan_kernel.ptx";
+
+const CAUSAL_CONV1D_KERNEL: &str = "causal_conv1d_kernel";
+
+const SELECTIVE_SCAN_KERNEL: &str = "selective_scan_kernel";
+
 pub fn get_or_load_func(
     ptx_file: &'static str,
     kernel_base: &str,
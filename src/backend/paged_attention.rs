@@ -1,49 +1,303 @@
-use candle_core::{cuda_backend::cudarc::driver::CudaFunction, DType, Tensor};
+use candle_core::{
+    cuda_backend::cudarc::driver::{DeviceRepr, LaunchAsync, LaunchConfig},
+    DType, Device, Tensor,
+};
 
-use candle_core::cuda_backend::cudarc::driver::sys as cudarc_sys;
+use crate::{
+    backend::{
+        cpu::paged_attention_cpu, dispatch_get_cuda_pointer, get_or_load_func, is_nhd_layout,
+        multiprocessor_count, MLA_ATTENTION_PTX, MLA_DECODE_KERNEL, PAGED_ATTENTION_PTX,
+        PAGED_ATTENTION_REDUCE_KERNEL, PAGED_ATTENTION_V1_KERNEL, PAGED_ATTENTION_V2_KERNEL,
+    },
+    openai::responses::APIError,
+    try_api,
+};
 
-use crate::openai::responses::APIError;
+const WARP_SIZE: usize = 32;
+
+/// Threads per block of the paged attention kernels, see `PAGED_ATTENTION_NUM_THREADS`.
+const NUM_THREADS: usize = 128;
+
+/// Number of context tokens handled by one thread block of the V2 kernel, see
+/// `PAGED_ATTENTION_PARTITION_SIZE`.
+pub const PARTITION_SIZE: usize = 512;
+
+/// Thread blocks per SM the splits of the V2 kernel aim for, see `num_kv_splits`.
+const TARGET_BLOCKS_PER_SM: usize = 4;
 
-fn set_max_dynamic_shared_memory_size(func: CudaFunction, size: usize) {
-    // let attr = cudarc_sys::CUfunction_attribute::CU_FUNC_ATTRIBUTE_SHARED_SIZE_BYTES;
-    // func.set_attribute(attr, size.try_into().unwrap());
+/// The head sizes and block sizes the kernels are instantiated for.
+const SUPPORTED_HEAD_SIZES: [usize; 7] = [64, 80, 96, 112, 128, 192, 256];
+const SUPPORTED_BLOCK_SIZES: [usize; 3] = [8, 16, 32];
+
+/// The most query tokens per sequence with a tree mask, one bit of the mask per query token.
+pub const MAX_TREE_QUERY_TOKENS: usize = 64;
+
+/// Precision of the attention scores (query-key products) of the paged attention kernels. The
+/// softmax and the value product always run in full precision.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum AttentionComputeDtype {
+    /// The data type of the model.
+    #[default]
+    Full,
+    /// INT8 (SageAttention-style): the query of each head and the keys of each cache block are
+    /// quantized with their own scale and multiplied with dp4a. The keys of an INT8 KV cache are
+    /// used as stored, which skips their dequantization.
+    Int8,
+    /// FP8 (e4m3) with the same per-head and per-block scales.
+    Fp8,
 }
 
-const WARP_SIZE: usize = 32;
+impl AttentionComputeDtype {
+    /// Mirrors `quantized_qk::QKCompute` in `quantized_qk.cuh`.
+    fn kernel_mode(&self) -> i32 {
+        match self {
+            Self::Full => 0,
+            Self::Int8 => 1,
+            Self::Fp8 => 2,
+        }
+    }
+
+    /// Quantized scores are only computed by the CUDA kernels.
+    fn check_device(&self, device: &Device) -> Result<(), APIError> {
+        if *self != Self::Full && !device.is_cuda() {
+            return Err(APIError::new(format!(
+                "{self:?} attention compute is only supported by the CUDA kernels."
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Mirrors `PagedAttentionParams` in `attention_kernel.cu`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct PagedAttentionParams {
+    num_kv_heads: i32,
+    scale: f32,
+    max_num_blocks_per_seq: i32,
+    q_stride: i32,
+    kv_block_stride: i32,
+    kv_head_stride: i32,
+    k_token_stride: i32,
+    k_x_stride: i32,
+    v_token_stride: i32,
+    v_dim_stride: i32,
+    sliding_window: i32,
+    rope_theta: f32,
+    logits_soft_cap: f32,
+    num_query_tokens: i32,
+    partitions_per_split: i32,
+    qk_compute: i32,
+    tree_mask: u64,
+}
+
+unsafe impl DeviceRepr for PagedAttentionParams {}
+
+/// The inputs shared by both kernel versions, validated and converted to the kernel layout.
+struct PagedAttentionInputs {
+    out: Tensor,
+    block_tables: Tensor,
+    context_lens: Tensor,
+    alibi_slopes_ptr: u64,
+    kv_scales_ptr: u64,
+    block_position_shifts: Option<Tensor>,
+    /// Kept alive until the launch, `params` holds its pointer.
+    _tree_mask: Option<Tensor>,
+    params: PagedAttentionParams,
+    /// The query rows, `num_query_tokens` per sequence.
+    num_queries: usize,
+    num_heads: usize,
+    head_size: usize,
+    suffix: String,
+}
 
 #[allow(clippy::too_many_arguments)]
-fn paged_attention_v1_launcher(
-    query: Tensor,            // [num_seqs, num_heads, head_size]
-    key_cache: Tensor,        // [num_blocks, num_heads, head_size/x, block_size, x]
-    value_cache: Tensor,      // [num_blocks, num_heads, head_size, block_size]
-    num_key_value_heads: i32, // [num_heads]
+fn prepare_inputs(
+    query: &Tensor,
+    key_cache: &Tensor,
+    num_key_value_heads: i32,
     scale: f32,
-    block_tables: Tensor, // [num_seqs, max_num_blocks_per_seq]
-    context_lens: Tensor, // [num_seqs]
+    block_tables: Tensor,
+    context_lens: Tensor,
     block_size: usize,
-    max_context_len: usize,
     alibi_slopes: Option<Tensor>,
-    dtype: DType,
-    is_fp8_e5m2_kv_cache: bool,
-) -> Tensor {
-    let num_seqs = query.shape().dims()[0];
-    let num_heads = query.shape().dims()[1];
-    let head_size = query.shape().dims()[2];
-    let max_num_blocks_per_seq = block_tables.shape().dims()[1];
-    let q_stride = query.stride()[0];
-    let kv_block_stride = key_cache.stride()[0];
-    let kv_head_stride = key_cache.stride()[1];
+    kv_scales: Option<Tensor>,
+    block_position_shifts: Option<Tensor>,
+    tree_mask: Option<Tensor>,
+    rope_theta: Option<f32>,
+    sliding_window: Option<usize>,
+    logits_soft_cap: Option<f32>,
+    kv_cache_dtype: &str,
+    attention_compute: AttentionComputeDtype,
+) -> Result<PagedAttentionInputs, APIError> {
+    let query_dtype = query.dtype();
+    if !matches!(query_dtype, DType::F32 | DType::F16 | DType::BF16) {
+        return Err(APIError::new(format!(
+            "Unsupported data type {:?}",
+            query_dtype
+        )));
+    }
+    let kv_cache_suffix = match kv_cache_dtype {
+        "auto" => "",
+        "fp8_e5m2" => "_fp8_e5m2",
+        "fp8_e4m3" => "_fp8_e4m3",
+        "int8" => "_int8",
+        other => {
+            return Err(APIError::new(format!(
+                "Unsupported KV cache data type {other}"
+            )))
+        }
+    };
 
+    let (num_queries, num_heads, head_size) = try_api!(query.dims3());
+    let num_seqs = try_api!(context_lens.dim(0));
+    if num_seqs == 0 || num_queries % num_seqs != 0 {
+        return Err(APIError::new(format!(
+            "Cannot split {num_queries} query tokens between {num_seqs} sequences."
+        )));
+    }
+    let num_query_tokens = num_queries / num_seqs;
+    if !SUPPORTED_HEAD_SIZES.contains(&head_size) {
+        return Err(APIError::new(format!(
+            "Unsupported head size: {head_size}, expected one of {SUPPORTED_HEAD_SIZES:?}."
+        )));
+    }
+    // The strides of the tokens of a block and of the elements of a head within the caches,
+    // which the kernels use to support both cache layouts.
+    let nhd_layout = is_nhd_layout(key_cache);
+    if nhd_layout && kv_cache_dtype != "auto" {
+        return Err(APIError::new(format!(
+            "The KV cache data type {kv_cache_dtype} does not support the NHD cache layout."
+        )));
+    }
+    // Keys are loaded in chunks of `x` elements (16 bytes).
+    let x = 16 / key_cache.dtype().size_in_bytes();
+    let stride = key_cache.stride();
+    let (num_cache_heads, k_token_stride, k_x_stride, v_token_stride, v_dim_stride) = if nhd_layout
+    {
+        (try_api!(key_cache.dim(2)), stride[1], x, stride[1], 1)
+    } else {
+        (try_api!(key_cache.dim(1)), x, block_size * x, 1, block_size)
+    };
+    // Each KV head is shared by `num_heads / num_kv_heads` query heads, the kernels map the query
+    // heads onto their KV head so the cache only holds `num_kv_heads` heads.
+    if num_cache_heads != num_key_value_heads as usize
+        || num_heads % num_key_value_heads as usize != 0
+    {
+        return Err(APIError::new(format!(
+            "Cannot map {num_heads} query heads onto {num_key_value_heads} KV heads with a cache of {num_cache_heads} heads."
+        )));
+    }
+    if !SUPPORTED_BLOCK_SIZES.contains(&block_size) {
+        return Err(APIError::new(format!(
+            "Unsupported block size: {block_size}"
+        )));
+    }
     let thread_group_size = 1.max(WARP_SIZE / block_size);
     debug_assert_eq!(head_size % thread_group_size, 0);
-    todo!();
+
+    // The kernels index the block tables and context lengths as int32.
+    let block_tables =
+        try_api!(try_api!(block_tables.reshape((num_seqs, ()))).to_dtype(DType::U32));
+    let context_lens = try_api!(context_lens.to_dtype(DType::U32));
+    let max_num_blocks_per_seq = block_tables.dims()[1];
+    // Keys are only re-rotated for models with rotary embeddings, other position encodings (e.g.
+    // ALiBi) are relative to the positions within the block table.
+    let block_position_shifts = match (block_position_shifts, rope_theta) {
+        (Some(shifts), Some(_)) => {
+            let shifts = try_api!(try_api!(shifts.reshape((num_seqs, ()))).to_dtype(DType::U32));
+            if shifts.dims() != block_tables.dims() {
+                return Err(APIError::new(format!(
+                    "`block_position_shifts` has shape {:?}, expected the shape of the block tables {:?}.",
+                    shifts.dims(),
+                    block_tables.dims()
+                )));
+            }
+            Some(shifts)
+        }
+        _ => None,
+    };
+
+    let tree_mask = match tree_mask {
+        Some(tree_mask) => {
+            check_tree_mask(&tree_mask, num_query_tokens)?;
+            Some(try_api!(tree_mask.to_dtype(DType::I64)))
+        }
+        None => None,
+    };
+
+    let alibi_slopes_ptr = match alibi_slopes {
+        Some(alibi_slopes) => {
+            dispatch_get_cuda_pointer(try_api!(alibi_slopes.to_dtype(DType::F32)))
+        }
+        None => 0,
+    };
+    // The per-head scales of an FP8 (e4m3) cache, the kernels use a scale of 1 if not given.
+    let kv_scales_ptr = match kv_scales {
+        Some(kv_scales) => dispatch_get_cuda_pointer(try_api!(kv_scales.to_dtype(DType::F32))),
+        None => 0,
+    };
+
+    Ok(PagedAttentionInputs {
+        out: try_api!(query.zeros_like()),
+        block_tables,
+        context_lens,
+        alibi_slopes_ptr,
+        kv_scales_ptr,
+        block_position_shifts,
+        _tree_mask: tree_mask.clone(),
+        params: PagedAttentionParams {
+            num_kv_heads: num_key_value_heads,
+            scale,
+            max_num_blocks_per_seq: max_num_blocks_per_seq as i32,
+            q_stride: query.stride()[0] as i32,
+            kv_block_stride: stride[0] as i32,
+            kv_head_stride: stride[if nhd_layout { 2 } else { 1 }] as i32,
+            k_token_stride: k_token_stride as i32,
+            k_x_stride: k_x_stride as i32,
+            v_token_stride: v_token_stride as i32,
+            v_dim_stride: v_dim_stride as i32,
+            sliding_window: sliding_window.unwrap_or(0) as i32,
+            rope_theta: rope_theta.unwrap_or(0.),
+            logits_soft_cap: logits_soft_cap.unwrap_or(0.),
+            num_query_tokens: num_query_tokens as i32,
+            partitions_per_split: 1,
+            qk_compute: attention_compute.kernel_mode(),
+            tree_mask: tree_mask.map_or(0, dispatch_get_cuda_pointer),
+        },
+        num_queries,
+        num_heads,
+        head_size,
+        suffix: format!("_h{head_size}_b{block_size}{kv_cache_suffix}"),
+    })
+}
+
+/// Whether `tree_mask` holds one mask per query token of a sequence, see `paged_attention_v1`.
+pub(crate) fn check_tree_mask(tree_mask: &Tensor, num_query_tokens: usize) -> Result<(), APIError> {
+    if tree_mask.dims() != [num_query_tokens] || num_query_tokens > MAX_TREE_QUERY_TOKENS {
+        return Err(APIError::new(format!(
+            "`tree_mask` has shape {:?}, expected [{num_query_tokens}] with at most {MAX_TREE_QUERY_TOKENS} query tokens.",
+            tree_mask.dims()
+        )));
+    }
+    Ok(())
 }
 
+/// Single-pass decode attention: one thread block per (head, query token) covers the whole context.
+///
+/// The query may hold several consecutive tokens per sequence, e.g. the candidates verified by
+/// speculative decoding, which are all scored in one launch. They must already be cached and the
+/// i-th of `num_query_tokens` tokens attends to the first
+/// `context_len - num_query_tokens + 1 + i` tokens of its sequence. With a `tree_mask`, the query
+/// tokens are the nodes of a tree of candidates in topological order, and bit j of the i-th mask
+/// (i64) is set if the i-th token attends to the j-th one, i.e. to itself and its ancestors.
+///
+/// Returns the output, shape = [num_seqs * num_query_tokens, num_heads, head_size].
 #[allow(clippy::too_many_arguments)]
 pub fn paged_attention_v1(
-    query: Tensor,            // [num_seqs, num_heads, head_size]
-    key_cache: Tensor,        // [num_blocks, num_heads, head_size/x, block_size, x]
-    value_cache: Tensor,      // [num_blocks, num_heads, head_size, block_size]
+    query: Tensor,            // [num_seqs * num_query_tokens, num_heads, head_size]
+    key_cache: Tensor,        // [num_blocks, num_heads, head_size/x, block_size, x] or NHD
+    value_cache: Tensor,      // [num_blocks, num_heads, head_size, block_size] or NHD
     num_key_value_heads: i32, // [num_heads]
     scale: f32,
     block_tables: Tensor, // [num_seqs, max_num_blocks_per_seq]
@@ -51,75 +305,479 @@ pub fn paged_attention_v1(
     block_size: usize,
     max_context_len: usize,
     alibi_slopes: Option<Tensor>,
+    kv_scales: Option<Tensor>,
+    block_position_shifts: Option<Tensor>, // [num_seqs, max_num_blocks_per_seq]
+    tree_mask: Option<Tensor>,             // [num_query_tokens]
+    rope_theta: Option<f32>,
+    sliding_window: Option<usize>,
+    logits_soft_cap: Option<f32>,
     kv_cache_dtype: &str,
+    attention_compute: AttentionComputeDtype,
 ) -> Result<Tensor, APIError> {
-    let query_dtype = query.dtype();
-    if kv_cache_dtype == "auto" {
-        match query_dtype {
-            DType::F32 | DType::F16 | DType::BF16 => Ok(paged_attention_v1_launcher(
-                query,
-                key_cache,
-                value_cache,
-                num_key_value_heads,
-                scale,
-                block_tables,
-                context_lens,
-                block_size,
-                max_context_len,
-                alibi_slopes,
-                query_dtype,
-                false,
-            )),
-            _ => Err(APIError::new(format!(
-                "Unsupported data type {:?}",
-                query_dtype
-            ))),
+    if is_nhd_layout(&key_cache) && !query.device().is_cuda() {
+        return Err(APIError::new_str(
+            "The NHD KV cache layout is only supported by the CUDA kernels.",
+        ));
+    }
+    attention_compute.check_device(query.device())?;
+    if query.device().is_cpu() {
+        if kv_cache_dtype != "auto" {
+            return Err(APIError::new(format!(
+                "The KV cache data type {kv_cache_dtype} is not supported by the CPU kernels."
+            )));
         }
-    } else if kv_cache_dtype == "fp8_e5m2" {
-        match query_dtype {
-            DType::F32 | DType::F16 | DType::BF16 => Ok(paged_attention_v1_launcher(
-                query,
-                key_cache,
-                value_cache,
-                num_key_value_heads,
-                scale,
-                block_tables,
-                context_lens,
-                block_size,
-                max_context_len,
-                alibi_slopes,
-                query_dtype,
-                true,
-            )),
-            _ => Err(APIError::new(format!(
-                "Unsupported data type {:?}",
-                query_dtype
-            ))),
+        return paged_attention_cpu(
+            &query,
+            &key_cache,
+            &value_cache,
+            num_key_value_heads as usize,
+            scale,
+            &block_tables,
+            &context_lens,
+            alibi_slopes.as_ref(),
+            block_position_shifts.as_ref(),
+            tree_mask.as_ref(),
+            rope_theta,
+            sliding_window,
+            logits_soft_cap,
+        );
+    }
+    #[cfg(feature = "metal")]
+    if let Device::Metal(dev) = query.device() {
+        if block_position_shifts.is_some() && rope_theta.is_some() {
+            return Err(APIError::new_str(
+                "Attention sinks are not supported by the Metal kernels.",
+            ));
         }
-    } else {
-        Err(APIError::new(format!(
-            "Unsupported data type {:?}",
-            query_dtype
-        )))
+        if tree_mask.is_some() {
+            return Err(APIError::new_str(
+                "Tree attention is not supported by the Metal kernels.",
+            ));
+        }
+        return super::metal::paged_attention_metal(
+            &query,
+            &key_cache,
+            &value_cache,
+            num_key_value_heads,
+            scale,
+            block_tables,
+            context_lens,
+            block_size,
+            alibi_slopes,
+            sliding_window,
+            logits_soft_cap,
+            kv_cache_dtype,
+            dev,
+        );
     }
+    let Device::Cuda(dev) = query.device().clone() else {
+        panic!("Expected the query to be on a CUDA device.")
+    };
+    let inputs = prepare_inputs(
+        &query,
+        &key_cache,
+        num_key_value_heads,
+        scale,
+        block_tables,
+        context_lens,
+        block_size,
+        alibi_slopes,
+        kv_scales,
+        block_position_shifts,
+        tree_mask,
+        rope_theta,
+        sliding_window,
+        logits_soft_cap,
+        kv_cache_dtype,
+        attention_compute,
+    )?;
+
+    let num_warps = NUM_THREADS / WARP_SIZE;
+    let padded_max_context_len = max_context_len.div_ceil(block_size) * block_size;
+    let logits_size = padded_max_context_len * std::mem::size_of::<f32>();
+    let outputs_size = (num_warps / 2) * inputs.head_size * std::mem::size_of::<f32>();
+
+    let launch_conf = LaunchConfig {
+        grid_dim: (inputs.num_heads as u32, inputs.num_queries as u32, 1u32),
+        block_dim: (NUM_THREADS as u32, 1u32, 1u32),
+        shared_mem_bytes: logits_size.max(outputs_size) as u32,
+    };
+
+    let stream = try_api!(dev.fork_default_stream());
+    let kernel = try_api!(get_or_load_func(
+        PAGED_ATTENTION_PTX,
+        PAGED_ATTENTION_V1_KERNEL,
+        query.dtype(),
+        Some(&inputs.suffix),
+        &dev
+    ));
+
+    try_api!(unsafe {
+        kernel.launch_on_stream(
+            &stream,
+            launch_conf,
+            (
+                dispatch_get_cuda_pointer(inputs.out.clone()),
+                dispatch_get_cuda_pointer(query),
+                dispatch_get_cuda_pointer(key_cache),
+                dispatch_get_cuda_pointer(value_cache),
+                dispatch_get_cuda_pointer(inputs.block_tables),
+                dispatch_get_cuda_pointer(inputs.context_lens),
+                inputs.alibi_slopes_ptr,
+                inputs.kv_scales_ptr,
+                inputs
+                    .block_position_shifts
+                    .clone()
+                    .map_or(0, dispatch_get_cuda_pointer),
+                inputs.params,
+            ),
+        )
+    });
+
+    Ok(inputs.out)
+}
+
+/// The number of splits of the context of each query for `paged_attention_v2`: enough thread
+/// blocks to fill every SM even when decoding a single sequence, but no more, so that the partial
+/// outputs stay few and cheap to reduce. At most one split per partition.
+pub fn num_kv_splits(
+    num_queries: usize,
+    num_heads: usize,
+    max_context_len: usize,
+    device: &Device,
+) -> Result<usize, APIError> {
+    let max_num_partitions = max_context_len.div_ceil(PARTITION_SIZE).max(1);
+    let Device::Cuda(dev) = device else {
+        return Ok(max_num_partitions);
+    };
+    let target_blocks = multiprocessor_count(dev.ordinal())? * TARGET_BLOCKS_PER_SM;
+    Ok(target_blocks
+        .div_ceil(num_queries * num_heads)
+        .clamp(1, max_num_partitions))
 }
 
+/// Split-K (flash-decoding) decode attention for long contexts. The context is split into
+/// partitions of `PARTITION_SIZE` tokens and the partitions into `max_num_splits` contiguous
+/// splits, see `num_kv_splits`. Each split is handled by its own thread block, which merges the
+/// softmaxes of its partitions and writes its partial output with its max logit and exp sum to
+/// `tmp_out`, `max_logits` and `exp_sums`. A second kernel then rescales and reduces the splits
+/// into the output, so that the reduction over a long context is parallelized across the SMs even
+/// for a single sequence.
+///
+/// Like `paged_attention_v1`, the query may hold several tokens per sequence.
+///
+/// - exp_sums: [num_seqs * num_query_tokens, num_heads, max_num_splits], F32
+/// - max_logits: [num_seqs * num_query_tokens, num_heads, max_num_splits], F32
+///
+/// Returns the output, shape = [num_seqs * num_query_tokens, num_heads, head_size].
 #[allow(clippy::too_many_arguments)]
 pub fn paged_attention_v2(
-    _exp_sums: Tensor,
-    _max_logits: Tensor,
-    _query: Tensor,
-    _key_cache: Tensor,
-    _value_cache: Tensor,
-    _num_key_value_heads: i32,
-    _scale: f32,
-    _block_tables: Tensor,
-    _context_lens: Tensor,
-    _block_size: usize,
-    _max_context_len: usize,
-    _alibi_slopes: Option<Tensor>,
-) -> Tensor {
-    todo!();
+    exp_sums: Tensor,
+    max_logits: Tensor,
+    query: Tensor,            // [num_seqs * num_query_tokens, num_heads, head_size]
+    key_cache: Tensor,        // [num_blocks, num_heads, head_size/x, block_size, x] or NHD
+    value_cache: Tensor,      // [num_blocks, num_heads, head_size, block_size] or NHD
+    num_key_value_heads: i32, // [num_heads]
+    scale: f32,
+    block_tables: Tensor, // [num_seqs, max_num_blocks_per_seq]
+    context_lens: Tensor, // [num_seqs]
+    block_size: usize,
+    max_context_len: usize,
+    alibi_slopes: Option<Tensor>,
+    kv_scales: Option<Tensor>,
+    block_position_shifts: Option<Tensor>, // [num_seqs, max_num_blocks_per_seq]
+    tree_mask: Option<Tensor>,             // [num_query_tokens]
+    rope_theta: Option<f32>,
+    sliding_window: Option<usize>,
+    logits_soft_cap: Option<f32>,
+    kv_cache_dtype: &str,
+    attention_compute: AttentionComputeDtype,
+) -> Result<Tensor, APIError> {
+    if is_nhd_layout(&key_cache) && !query.device().is_cuda() {
+        return Err(APIError::new_str(
+            "The NHD KV cache layout is only supported by the CUDA kernels.",
+        ));
+    }
+    attention_compute.check_device(query.device())?;
+    if query.device().is_cpu() {
+        if kv_cache_dtype != "auto" {
+            return Err(APIError::new(format!(
+                "The KV cache data type {kv_cache_dtype} is not supported by the CPU kernels."
+            )));
+        }
+        return paged_attention_cpu(
+            &query,
+            &key_cache,
+            &value_cache,
+            num_key_value_heads as usize,
+            scale,
+            &block_tables,
+            &context_lens,
+            alibi_slopes.as_ref(),
+            block_position_shifts.as_ref(),
+            tree_mask.as_ref(),
+            rope_theta,
+            sliding_window,
+            logits_soft_cap,
+        );
+    }
+    #[cfg(feature = "metal")]
+    if let Device::Metal(dev) = query.device() {
+        if block_position_shifts.is_some() && rope_theta.is_some() {
+            return Err(APIError::new_str(
+                "Attention sinks are not supported by the Metal kernels.",
+            ));
+        }
+        if tree_mask.is_some() {
+            return Err(APIError::new_str(
+                "Tree attention is not supported by the Metal kernels.",
+            ));
+        }
+        return super::metal::paged_attention_metal(
+            &query,
+            &key_cache,
+            &value_cache,
+            num_key_value_heads,
+            scale,
+            block_tables,
+            context_lens,
+            block_size,
+            alibi_slopes,
+            sliding_window,
+            logits_soft_cap,
+            kv_cache_dtype,
+            dev,
+        );
+    }
+    let Device::Cuda(dev) = query.device().clone() else {
+        panic!("Expected the query to be on a CUDA device.")
+    };
+    if PARTITION_SIZE % block_size != 0 {
+        return Err(APIError::new(format!(
+            "The partition size {PARTITION_SIZE} must be a multiple of the block size {block_size}."
+        )));
+    }
+    let inputs = prepare_inputs(
+        &query,
+        &key_cache,
+        num_key_value_heads,
+        scale,
+        block_tables,
+        context_lens,
+        block_size,
+        alibi_slopes,
+        kv_scales,
+        block_position_shifts,
+        tree_mask,
+        rope_theta,
+        sliding_window,
+        logits_soft_cap,
+        kv_cache_dtype,
+        attention_compute,
+    )?;
+    let mut params = inputs.params;
+    let max_num_splits = try_api!(exp_sums.dim(2));
+    if max_num_splits == 0 {
+        return Err(APIError::new_str(
+            "`exp_sums` and `max_logits` must hold at least one split.",
+        ));
+    }
+    let max_num_partitions = max_context_len.div_ceil(PARTITION_SIZE);
+    params.partitions_per_split = max_num_partitions.div_ceil(max_num_splits).max(1) as i32;
+    let tmp_out = try_api!(Tensor::zeros(
+        (
+            inputs.num_queries,
+            inputs.num_heads,
+            max_num_splits,
+            inputs.head_size
+        ),
+        query.dtype(),
+        query.device(),
+    ));
+
+    let num_warps = NUM_THREADS / WARP_SIZE;
+    let logits_size = PARTITION_SIZE * std::mem::size_of::<f32>();
+    let outputs_size = (num_warps / 2) * inputs.head_size * std::mem::size_of::<f32>();
+
+    let stream = try_api!(dev.fork_default_stream());
+
+    let launch_conf = LaunchConfig {
+        grid_dim: (
+            inputs.num_heads as u32,
+            inputs.num_queries as u32,
+            max_num_splits as u32,
+        ),
+        block_dim: (NUM_THREADS as u32, 1u32, 1u32),
+        shared_mem_bytes: logits_size.max(outputs_size) as u32,
+    };
+    let kernel = try_api!(get_or_load_func(
+        PAGED_ATTENTION_PTX,
+        PAGED_ATTENTION_V2_KERNEL,
+        query.dtype(),
+        Some(&inputs.suffix),
+        &dev
+    ));
+    try_api!(unsafe {
+        kernel.launch_on_stream(
+            &stream,
+            launch_conf,
+            (
+                dispatch_get_cuda_pointer(exp_sums.clone()),
+                dispatch_get_cuda_pointer(max_logits.clone()),
+                dispatch_get_cuda_pointer(tmp_out.clone()),
+                dispatch_get_cuda_pointer(query.clone()),
+                dispatch_get_cuda_pointer(key_cache),
+                dispatch_get_cuda_pointer(value_cache),
+                dispatch_get_cuda_pointer(inputs.block_tables),
+                dispatch_get_cuda_pointer(inputs.context_lens.clone()),
+                inputs.alibi_slopes_ptr,
+                inputs.kv_scales_ptr,
+                inputs
+                    .block_position_shifts
+                    .clone()
+                    .map_or(0, dispatch_get_cuda_pointer),
+                params,
+            ),
+        )
+    });
+
+    let reduce_launch_conf = LaunchConfig {
+        grid_dim: (inputs.num_heads as u32, inputs.num_queries as u32, 1u32),
+        block_dim: (NUM_THREADS as u32, 1u32, 1u32),
+        shared_mem_bytes: (2 * max_num_splits * std::mem::size_of::<f32>()) as u32,
+    };
+    let reduce_kernel = try_api!(get_or_load_func(
+        PAGED_ATTENTION_PTX,
+        PAGED_ATTENTION_REDUCE_KERNEL,
+        query.dtype(),
+        Some(&format!("_h{}", inputs.head_size)),
+        &dev
+    ));
+    // Launched on the same stream, so it runs after the splits are written.
+    try_api!(unsafe {
+        reduce_kernel.launch_on_stream(
+            &stream,
+            reduce_launch_conf,
+            (
+                dispatch_get_cuda_pointer(inputs.out.clone()),
+                dispatch_get_cuda_pointer(exp_sums),
+                dispatch_get_cuda_pointer(max_logits),
+                dispatch_get_cuda_pointer(tmp_out),
+                dispatch_get_cuda_pointer(inputs.context_lens),
+                max_num_splits as i32,
+                params.num_query_tokens,
+                params.partitions_per_split,
+            ),
+        )
+    });
+
+    Ok(inputs.out)
+}
+
+/// Threads per block, context tokens scored per chunk and the largest latent rank of the MLA
+/// decode kernel, see `MLA_NUM_THREADS`, `MLA_CHUNK_SIZE` and `MLA_MAX_KV_LORA_RANK`.
+const MLA_NUM_THREADS: usize = 128;
+const MLA_CHUNK_SIZE: usize = 256;
+const MLA_MAX_KV_LORA_RANK: usize = 512;
+
+/// Decode attention of multi-head latent attention over the latent cache, see
+/// `paged_attention::mla`. The query of each head is already absorbed into the latent space and
+/// attends to whole cache entries (latent and rope key) as a single shared KV head. The output is
+/// the attention-weighted sum of the latents, which the caller expands into the value heads.
+///
+/// Like `paged_attention_v1`, the query may hold several tokens per sequence.
+///
+/// Returns the output, shape = [num_seqs * num_query_tokens, num_heads, kv_lora_rank].
+pub fn mla_decode_attention(
+    query: Tensor, // [num_seqs * num_query_tokens, num_heads, kv_lora_rank + qk_rope_head_dim]
+    kv_cache: Tensor, // [num_blocks, block_size, 1, kv_lora_rank + qk_rope_head_dim]
+    block_tables: Tensor, // [num_seqs, max_num_blocks_per_seq]
+    context_lens: Tensor, // [num_seqs]
+    kv_lora_rank: usize,
+    scale: f32,
+) -> Result<Tensor, APIError> {
+    let Device::Cuda(dev) = query.device().clone() else {
+        return Err(APIError::new_str(
+            "Multi-head latent attention is only supported by the CUDA kernels.",
+        ));
+    };
+    let dtype = query.dtype();
+    if !matches!(dtype, DType::F32 | DType::F16 | DType::BF16) || dtype != kv_cache.dtype() {
+        return Err(APIError::new(format!(
+            "Unsupported data types {dtype:?} (query) and {:?} (cache).",
+            kv_cache.dtype()
+        )));
+    }
+    let (num_queries, num_heads, entry_size) = try_api!(query.dims3());
+    let (_num_blocks, block_size, _, cache_entry_size) = try_api!(kv_cache.dims4());
+    if entry_size != cache_entry_size || kv_lora_rank > entry_size {
+        return Err(APIError::new(format!(
+            "The query entries of {entry_size} elements do not match the cache entries of {cache_entry_size} elements."
+        )));
+    }
+    if kv_lora_rank > MLA_MAX_KV_LORA_RANK {
+        return Err(APIError::new(format!(
+            "Unsupported latent rank: {kv_lora_rank}, expected at most {MLA_MAX_KV_LORA_RANK}."
+        )));
+    }
+    let num_seqs = try_api!(context_lens.dim(0));
+    if num_seqs == 0 || num_queries % num_seqs != 0 {
+        return Err(APIError::new(format!(
+            "Cannot split {num_queries} query tokens between {num_seqs} sequences."
+        )));
+    }
+    let num_query_tokens = num_queries / num_seqs;
+
+    // The kernel indexes the block tables and context lengths as int32.
+    let block_tables =
+        try_api!(try_api!(block_tables.reshape((num_seqs, ()))).to_dtype(DType::U32));
+    let context_lens = try_api!(context_lens.to_dtype(DType::U32));
+    let max_num_blocks_per_seq = block_tables.dims()[1];
+    let query = try_api!(query.contiguous());
+    let q_stride = query.stride()[0];
+    let out = try_api!(Tensor::zeros(
+        (num_queries, num_heads, kv_lora_rank),
+        dtype,
+        query.device(),
+    ));
+
+    let launch_conf = LaunchConfig {
+        grid_dim: (num_heads as u32, num_queries as u32, 1u32),
+        block_dim: (MLA_NUM_THREADS as u32, 1u32, 1u32),
+        shared_mem_bytes: ((entry_size + MLA_CHUNK_SIZE) * std::mem::size_of::<f32>()) as u32,
+    };
+
+    let stream = try_api!(dev.fork_default_stream());
+    let kernel = try_api!(get_or_load_func(
+        MLA_ATTENTION_PTX,
+        MLA_DECODE_KERNEL,
+        dtype,
+        None,
+        &dev
+    ));
+
+    try_api!(unsafe {
+        kernel.launch_on_stream(
+            &stream,
+            launch_conf,
+            (
+                dispatch_get_cuda_pointer(out.clone()),
+                dispatch_get_cuda_pointer(query),
+                dispatch_get_cuda_pointer(kv_cache),
+                dispatch_get_cuda_pointer(block_tables),
+                dispatch_get_cuda_pointer(context_lens),
+                max_num_blocks_per_seq as i32,
+                block_size as i32,
+                kv_lora_rank as i32,
+                entry_size as i32,
+                q_stride as i32,
+                scale,
+                num_query_tokens as i32,
+            ),
+        )
+    });
+
+    Ok(out)
 }
 
 /*
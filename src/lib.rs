@@ -2,12 +2,26 @@
 
 use clap::Subcommand;
 use openai::pipelines::{
+    get_pipeline_factory,
     llama::{LlamaLoader, LlamaSpecificConfig},
-    ModelLoader,
+    pooling::PoolingMode,
+    registered_pipelines, ModelLoader,
 };
 
-#[derive(Debug, Subcommand)]
+#[derive(Debug, Clone, Subcommand)]
 pub enum ModelSelected {
+    /// Select any supported model. The architecture is detected from the `architectures` or
+    /// `model_type` of the config of the checkpoint.
+    Auto {
+        /// Control the application of repeat penalty for the last n tokens
+        #[arg(long, default_value_t = 64)]
+        repeat_last_n: usize,
+
+        /// Huggingface model id of the checkpoint, e.g. mistralai/Mistral-7B-Instruct-v0.2
+        #[arg(long)]
+        model: String,
+    },
+
     /// Select the llama7b model.
     Llama7b {
         /// Control the application of repeat penalty for the last n tokens
@@ -28,41 +42,512 @@ pub enum ModelSelected {
         #[arg(long)]
         repeat_last_n: usize,
     },
+
+    /// Select the mixtral8x7b mixture-of-experts model.
+    Mixtral8x7b {
+        /// Control the application of repeat penalty for the last n tokens
+        #[arg(long)]
+        repeat_last_n: usize,
+    },
+
+    /// Select a Phi-3 model. The size, context length (4k or 128k with LongRoPE) and sliding
+    /// window are read from the config of the checkpoint.
+    Phi3 {
+        /// Control the application of repeat penalty for the last n tokens
+        #[arg(long)]
+        repeat_last_n: usize,
+
+        /// Huggingface model id of the checkpoint, e.g. microsoft/Phi-3-medium-128k-instruct
+        #[arg(long, default_value = "microsoft/Phi-3-mini-4k-instruct")]
+        model: String,
+    },
+
+    /// Select a Qwen2 or Qwen2.5 model, from 0.5B to 72B. The size is read from the config of
+    /// the checkpoint.
+    Qwen2 {
+        /// Control the application of repeat penalty for the last n tokens
+        #[arg(long)]
+        repeat_last_n: usize,
+
+        /// Huggingface model id of the checkpoint, e.g. Qwen/Qwen2.5-72B-Instruct
+        #[arg(long, default_value = "Qwen/Qwen2.5-7B-Instruct")]
+        model: String,
+    },
+
+    /// Select a Gemma or Gemma-2 model. The version and size are read from the config of the
+    /// checkpoint.
+    Gemma {
+        /// Control the application of repeat penalty for the last n tokens
+        #[arg(long)]
+        repeat_last_n: usize,
+
+        /// Huggingface model id of the checkpoint, e.g. google/gemma-7b-it
+        #[arg(long, default_value = "google/gemma-2-9b-it")]
+        model: String,
+    },
+
+    /// Select a StarCoder2 code model. Literal prompts with a `suffix` are completed with
+    /// fill-in-the-middle.
+    #[command(name = "starcoder2")]
+    StarCoder2 {
+        /// Control the application of repeat penalty for the last n tokens
+        #[arg(long)]
+        repeat_last_n: usize,
+
+        /// Huggingface model id of the checkpoint, e.g. bigcode/starcoder2-15b
+        #[arg(long, default_value = "bigcode/starcoder2-7b")]
+        model: String,
+    },
+
+    /// Select a DeepSeek-V2, V2.5 or V3 mixture-of-experts model with multi-head latent
+    /// attention. The size is read from the config of the checkpoint.
+    #[command(name = "deepseek")]
+    DeepSeek {
+        /// Control the application of repeat penalty for the last n tokens
+        #[arg(long)]
+        repeat_last_n: usize,
+
+        /// Huggingface model id of the checkpoint, e.g. deepseek-ai/DeepSeek-V2.5
+        #[arg(long, default_value = "deepseek-ai/DeepSeek-V2-Lite-Chat")]
+        model: String,
+
+        /// Split the routed experts over this many CUDA devices, starting with the device of
+        /// the model
+        #[arg(long, default_value_t = 1)]
+        expert_parallel_size: usize,
+    },
+
+    /// Select a Cohere Command-R or Command-R+ model. The size is read from the config of the
+    /// checkpoint.
+    #[command(name = "command-r")]
+    CommandR {
+        /// Control the application of repeat penalty for the last n tokens
+        #[arg(long)]
+        repeat_last_n: usize,
+
+        /// Huggingface model id of the checkpoint, e.g. CohereForAI/c4ai-command-r-plus
+        #[arg(long, default_value = "CohereForAI/c4ai-command-r-v01")]
+        model: String,
+    },
+
+    /// Select a Baichuan-2 model. The 7B model uses RoPE, the 13B model ALiBi.
+    Baichuan2 {
+        /// Control the application of repeat penalty for the last n tokens
+        #[arg(long)]
+        repeat_last_n: usize,
+
+        /// Huggingface model id of the checkpoint, e.g. baichuan-inc/Baichuan2-13B-Chat
+        #[arg(long, default_value = "baichuan-inc/Baichuan2-7B-Chat")]
+        model: String,
+    },
+
+    /// Select an OLMo model in the Transformers format. The size is read from the config of the
+    /// checkpoint.
+    Olmo {
+        /// Control the application of repeat penalty for the last n tokens
+        #[arg(long)]
+        repeat_last_n: usize,
+
+        /// Huggingface model id of the checkpoint, e.g. allenai/OLMo-1B-hf
+        #[arg(long, default_value = "allenai/OLMo-7B-Instruct-hf")]
+        model: String,
+    },
+
+    /// Select a Mamba state-space model in the Transformers format. The sequences hold a fixed
+    /// size recurrent state instead of KV cache blocks.
+    Mamba {
+        /// Control the application of repeat penalty for the last n tokens
+        #[arg(long)]
+        repeat_last_n: usize,
+
+        /// Huggingface model id of the checkpoint, e.g. state-spaces/mamba-130m-hf
+        #[arg(long, default_value = "state-spaces/mamba-2.8b-hf")]
+        model: String,
+    },
+
+    /// Select a T5 or FLAN-T5 encoder-decoder model. The requests are encoded once and the
+    /// decoder generates from the decoder start token, attending the encoder outputs.
+    T5 {
+        /// Control the application of repeat penalty for the last n tokens
+        #[arg(long)]
+        repeat_last_n: usize,
+
+        /// Huggingface model id of the checkpoint, e.g. google/flan-t5-base
+        #[arg(long, default_value = "google/flan-t5-large")]
+        model: String,
+    },
+
+    /// Select a LLaVA vision-language model in the Transformers format. Images are passed as
+    /// `image_url` parts of the chat messages.
+    Llava {
+        /// Control the application of repeat penalty for the last n tokens
+        #[arg(long)]
+        repeat_last_n: usize,
+
+        /// Huggingface model id of the checkpoint, e.g. llava-hf/llava-1.5-13b-hf
+        #[arg(long, default_value = "llava-hf/llava-1.5-7b-hf")]
+        model: String,
+    },
+
+    /// Select a Qwen-VL vision-language model. Images are passed as `image_url` parts of the chat
+    /// messages.
+    QwenVl {
+        /// Control the application of repeat penalty for the last n tokens
+        #[arg(long)]
+        repeat_last_n: usize,
+
+        /// Huggingface model id of the checkpoint, which must provide a `tokenizer.json`
+        #[arg(long, default_value = "Qwen/Qwen-VL-Chat")]
+        model: String,
+    },
+
+    /// Select a BGE embedding model, served on `/v1/embeddings`. Retrieval queries should be
+    /// prefixed with the instruction of the model card.
+    Bge {
+        /// Huggingface model id of the checkpoint, e.g. BAAI/bge-m3
+        #[arg(long, default_value = "BAAI/bge-base-en-v1.5")]
+        model: String,
+
+        /// Pooling of checkpoints without a sentence-transformers pooling config
+        #[arg(long, value_enum, default_value_t = PoolingMode::Cls)]
+        pooling: PoolingMode,
+    },
+
+    /// Select an E5 embedding model, served on `/v1/embeddings`. The inputs must be prefixed
+    /// with "query: " or "passage: ".
+    E5 {
+        /// Huggingface model id of the checkpoint, e.g. intfloat/multilingual-e5-large
+        #[arg(long, default_value = "intfloat/e5-base-v2")]
+        model: String,
+
+        /// Pooling of checkpoints without a sentence-transformers pooling config
+        #[arg(long, value_enum, default_value_t = PoolingMode::Mean)]
+        pooling: PoolingMode,
+    },
+
+    /// Select a GTE embedding model, served on `/v1/embeddings`.
+    Gte {
+        /// Huggingface model id of the checkpoint, e.g. thenlper/gte-large
+        #[arg(long, default_value = "thenlper/gte-base")]
+        model: String,
+
+        /// Pooling of checkpoints without a sentence-transformers pooling config
+        #[arg(long, value_enum, default_value_t = PoolingMode::Mean)]
+        pooling: PoolingMode,
+    },
+
+    /// Select a GGUF checkpoint of the llama.cpp ecosystem (Llama, Mistral, Qwen2, Gemma or
+    /// Phi-3). The architecture and config are read from the metadata of the file.
+    Gguf {
+        /// Control the application of repeat penalty for the last n tokens
+        #[arg(long)]
+        repeat_last_n: usize,
+
+        /// Huggingface model id of the repository holding the GGUF file, e.g.
+        /// TheBloke/Llama-2-7B-Chat-GGUF
+        #[arg(long)]
+        model: String,
+
+        /// The GGUF file in the repository, e.g. llama-2-7b-chat.Q4_K_M.gguf
+        #[arg(long)]
+        file: String,
+
+        /// Huggingface model id of the original model, whose `tokenizer.json` is used, e.g.
+        /// meta-llama/Llama-2-7b-chat-hf
+        #[arg(long)]
+        tokenizer_model: String,
+    },
+
+    /// Select a cross-encoder reranker with a single relevance label, served on `/v1/rerank`.
+    Reranker {
+        /// Huggingface model id of the checkpoint, e.g. cross-encoder/ms-marco-MiniLM-L-6-v2
+        #[arg(long, default_value = "BAAI/bge-reranker-base")]
+        model: String,
+    },
+
+    /// Select a pipeline registered by an embedding crate with `register_pipeline`.
+    Plugin {
+        /// The name the pipeline is registered under
+        #[arg(long)]
+        pipeline: String,
+
+        /// Huggingface model id of the checkpoint
+        #[arg(long)]
+        model: String,
+    },
 }
 
 impl ToString for ModelSelected {
     fn to_string(&self) -> String {
         match self {
+            ModelSelected::Auto { .. } => "auto".to_string(),
             ModelSelected::Llama7b { repeat_last_n: _ } => "llama7b".to_string(),
             ModelSelected::Llama13b { repeat_last_n: _ } => "llama13b".to_string(),
             ModelSelected::Llama70b { repeat_last_n: _ } => "llama70b".to_string(),
+            ModelSelected::Mixtral8x7b { repeat_last_n: _ } => "mixtral8x7b".to_string(),
+            ModelSelected::Phi3 { .. } => "phi3".to_string(),
+            ModelSelected::Qwen2 { .. } => "qwen2".to_string(),
+            ModelSelected::Gemma { .. } => "gemma".to_string(),
+            ModelSelected::StarCoder2 { .. } => "starcoder2".to_string(),
+            ModelSelected::DeepSeek { .. } => "deepseek".to_string(),
+            ModelSelected::CommandR { .. } => "command-r".to_string(),
+            ModelSelected::Baichuan2 { .. } => "baichuan2".to_string(),
+            ModelSelected::Olmo { .. } => "olmo".to_string(),
+            ModelSelected::Mamba { .. } => "mamba".to_string(),
+            ModelSelected::T5 { .. } => "t5".to_string(),
+            ModelSelected::Llava { .. } => "llava".to_string(),
+            ModelSelected::QwenVl { .. } => "qwen-vl".to_string(),
+            ModelSelected::Bge { .. } => "bge".to_string(),
+            ModelSelected::E5 { .. } => "e5".to_string(),
+            ModelSelected::Gte { .. } => "gte".to_string(),
+            ModelSelected::Gguf { .. } => "gguf".to_string(),
+            ModelSelected::Reranker { .. } => "reranker".to_string(),
+            ModelSelected::Plugin { pipeline, .. } => pipeline.clone(),
         }
     }
 }
 
 pub fn get_model_loader<'a>(selected_model: ModelSelected) -> (Box<dyn ModelLoader<'a>>, String) {
     match selected_model {
-        ModelSelected::Llama7b { repeat_last_n } => (
+        ModelSelected::Auto {
+            repeat_last_n,
+            model,
+        } => (
             Box::new(LlamaLoader::new(
                 LlamaSpecificConfig::new(repeat_last_n),
-                "llama7b".to_string(),
+                model.clone(),
             )),
+            model,
+        ),
+        ModelSelected::Llama7b { repeat_last_n } => (
+            Box::new(
+                LlamaLoader::new(
+                    LlamaSpecificConfig::new(repeat_last_n),
+                    "llama7b".to_string(),
+                )
+                .with_architecture("llama"),
+            ),
             "meta-llama/Llama-2-7b-chat-hf".to_string(),
         ),
         ModelSelected::Llama13b { repeat_last_n } => (
-            Box::new(LlamaLoader::new(
-                LlamaSpecificConfig::new(repeat_last_n),
-                "llama13b".to_string(),
-            )),
+            Box::new(
+                LlamaLoader::new(
+                    LlamaSpecificConfig::new(repeat_last_n),
+                    "llama13b".to_string(),
+                )
+                .with_architecture("llama"),
+            ),
             "meta-llama/Llama-2-13b-chat-hf".to_string(),
         ),
         ModelSelected::Llama70b { repeat_last_n } => (
-            Box::new(LlamaLoader::new(
-                LlamaSpecificConfig::new(repeat_last_n),
-                "llama70b".to_string(),
-            )),
+            Box::new(
+                LlamaLoader::new(
+                    LlamaSpecificConfig::new(repeat_last_n),
+                    "llama70b".to_string(),
+                )
+                .with_architecture("llama"),
+            ),
             "meta-llama/Llama-2-70b-chat-hf".to_string(),
         ),
+        ModelSelected::Mixtral8x7b { repeat_last_n } => (
+            Box::new(
+                LlamaLoader::new(
+                    LlamaSpecificConfig::new(repeat_last_n),
+                    "mixtral8x7b".to_string(),
+                )
+                .with_architecture("mixtral"),
+            ),
+            "mistralai/Mixtral-8x7B-Instruct-v0.1".to_string(),
+        ),
+        ModelSelected::Phi3 {
+            repeat_last_n,
+            model,
+        } => (
+            Box::new(
+                LlamaLoader::new(LlamaSpecificConfig::new(repeat_last_n), "phi3".to_string())
+                    .with_architecture("phi3"),
+            ),
+            model,
+        ),
+        ModelSelected::Qwen2 {
+            repeat_last_n,
+            model,
+        } => (
+            Box::new(
+                LlamaLoader::new(LlamaSpecificConfig::new(repeat_last_n), "qwen2".to_string())
+                    .with_architecture("qwen2"),
+            ),
+            model,
+        ),
+        ModelSelected::Gemma {
+            repeat_last_n,
+            model,
+        } => (
+            Box::new(
+                LlamaLoader::new(LlamaSpecificConfig::new(repeat_last_n), "gemma".to_string())
+                    .with_architecture("gemma"),
+            ),
+            model,
+        ),
+        ModelSelected::StarCoder2 {
+            repeat_last_n,
+            model,
+        } => (
+            Box::new(
+                LlamaLoader::new(
+                    LlamaSpecificConfig::new(repeat_last_n),
+                    "starcoder2".to_string(),
+                )
+                .with_architecture("starcoder2"),
+            ),
+            model,
+        ),
+        ModelSelected::DeepSeek {
+            repeat_last_n,
+            model,
+            expert_parallel_size,
+        } => (
+            Box::new(
+                LlamaLoader::new(
+                    LlamaSpecificConfig::new(repeat_last_n),
+                    "deepseek".to_string(),
+                )
+                .with_architecture("deepseek")
+                .with_config_override("expert_parallel_size", expert_parallel_size.into()),
+            ),
+            model,
+        ),
+        ModelSelected::CommandR {
+            repeat_last_n,
+            model,
+        } => (
+            Box::new(
+                LlamaLoader::new(
+                    LlamaSpecificConfig::new(repeat_last_n),
+                    "command-r".to_string(),
+                )
+                .with_architecture("cohere"),
+            ),
+            model,
+        ),
+        ModelSelected::Baichuan2 {
+            repeat_last_n,
+            model,
+        } => (
+            Box::new(
+                LlamaLoader::new(
+                    LlamaSpecificConfig::new(repeat_last_n),
+                    "baichuan2".to_string(),
+                )
+                .with_architecture("baichuan"),
+            ),
+            model,
+        ),
+        ModelSelected::Olmo {
+            repeat_last_n,
+            model,
+        } => (
+            Box::new(
+                LlamaLoader::new(LlamaSpecificConfig::new(repeat_last_n), "olmo".to_string())
+                    .with_architecture("olmo"),
+            ),
+            model,
+        ),
+        ModelSelected::Mamba {
+            repeat_last_n,
+            model,
+        } => (
+            Box::new(
+                LlamaLoader::new(LlamaSpecificConfig::new(repeat_last_n), "mamba".to_string())
+                    .with_architecture("mamba"),
+            ),
+            model,
+        ),
+        ModelSelected::T5 {
+            repeat_last_n,
+            model,
+        } => (
+            Box::new(
+                LlamaLoader::new(LlamaSpecificConfig::new(repeat_last_n), "t5".to_string())
+                    .with_architecture("t5"),
+            ),
+            model,
+        ),
+        ModelSelected::Llava {
+            repeat_last_n,
+            model,
+        } => (
+            Box::new(
+                LlamaLoader::new(LlamaSpecificConfig::new(repeat_last_n), "llava".to_string())
+                    .with_architecture("llava"),
+            ),
+            model,
+        ),
+        ModelSelected::QwenVl {
+            repeat_last_n,
+            model,
+        } => (
+            Box::new(
+                LlamaLoader::new(
+                    LlamaSpecificConfig::new(repeat_last_n),
+                    "qwen-vl".to_string(),
+                )
+                .with_architecture("qwen_vl"),
+            ),
+            model,
+        ),
+        // Embedding models generate nothing, there is no repetition to penalize.
+        ModelSelected::Bge { model, pooling } => (
+            Box::new(
+                LlamaLoader::new(LlamaSpecificConfig::new(0), "bge".to_string())
+                    .with_architecture("bert")
+                    .with_pooling(pooling),
+            ),
+            model,
+        ),
+        ModelSelected::E5 { model, pooling } => (
+            Box::new(
+                LlamaLoader::new(LlamaSpecificConfig::new(0), "e5".to_string())
+                    .with_architecture("bert")
+                    .with_pooling(pooling),
+            ),
+            model,
+        ),
+        ModelSelected::Gte { model, pooling } => (
+            Box::new(
+                LlamaLoader::new(LlamaSpecificConfig::new(0), "gte".to_string())
+                    .with_architecture("bert")
+                    .with_pooling(pooling),
+            ),
+            model,
+        ),
+        ModelSelected::Gguf {
+            repeat_last_n,
+            model,
+            file,
+            tokenizer_model,
+        } => (
+            Box::new(
+                LlamaLoader::new(LlamaSpecificConfig::new(repeat_last_n), "gguf".to_string())
+                    .with_gguf(&file, &tokenizer_model),
+            ),
+            model,
+        ),
+        ModelSelected::Reranker { model } => (
+            Box::new(
+                LlamaLoader::new(LlamaSpecificConfig::new(0), "reranker".to_string())
+                    .with_architecture("bert"),
+            ),
+            model,
+        ),
+        ModelSelected::Plugin { pipeline, model } => {
+            let Some(factory) = get_pipeline_factory(&pipeline) else {
+                let mut registered = registered_pipelines();
+                registered.sort();
+                panic!("No pipeline is registered under `{pipeline}`, registered pipelines: {registered:?}.");
+            };
+            (factory.loader(&pipeline), model)
+        }
     }
 }
 
@@ -73,4 +558,10 @@ pub fn log_warning(message: &str) {
 pub mod backend;
 pub mod openai;
 pub mod paged_attention;
+pub mod profiling;
 pub mod scheduler;
+
+pub use openai::pipelines::{
+    async_llm_engine::{AsyncLLMEngine, RequestOutputStream},
+    llm_engine::{CompletionOutput, LLMEngine, RequestOutput},
+};
@@ -1,15 +1,41 @@
-use std::sync::{Arc, Mutex};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
 use actix_web::middleware::Logger;
 use actix_web::web::Data;
 use actix_web::{App, HttpServer};
-use candle_core::{DType, Device};
-use candle_vllm::openai::openai_server::chat_completions;
-use candle_vllm::openai::pipelines::llm_engine::LLMEngine;
+use candle_core::Device;
+use candle_vllm::backend::AttentionComputeDtype;
+use candle_vllm::openai::conversation_store::ConversationStore;
+use candle_vllm::openai::models::lora::{LoraAdapter, LoraMode};
+use candle_vllm::openai::models::quantization::InSituQuantization;
+use candle_vllm::openai::openai_server::{
+    append_conversation, cancel_request, chat_completions, create_conversation,
+    delete_conversation, embeddings, get_conversation, get_metrics, list_models, reload_model,
+    rerank,
+};
+use candle_vllm::openai::pipelines::eagle::EagleHead;
+use candle_vllm::openai::pipelines::llm_engine::{LLMEngine, WARMUP_MAX_TOKENS};
+use candle_vllm::openai::pipelines::medusa::{MedusaHeads, TypicalAcceptance};
+use candle_vllm::openai::pipelines::prompt_lookup::PromptLookup;
+use candle_vllm::openai::pipelines::{LoadOptions, ModelDtype};
+use candle_vllm::openai::placement::{PlacementStrategy, ReplicaPlacer};
+use candle_vllm::openai::playground::playground;
+use candle_vllm::openai::requests::ReloadRequest;
 use candle_vllm::openai::responses::APIError;
-use candle_vllm::openai::OpenAIServerData;
-use candle_vllm::scheduler::cache_engine::CacheConfig;
-use candle_vllm::scheduler::SchedulerConfig;
+use candle_vllm::openai::utils::GIB;
+use candle_vllm::openai::{LoadEngines, ModelReloader, OpenAIServerData, PipelineConfig, Replica};
+use candle_vllm::paged_attention::attention_backend::AttentionBackendKind;
+use candle_vllm::profiling;
+use candle_vllm::scheduler::autotune::autotune;
+use candle_vllm::scheduler::cache_engine::{
+    AttentionSinks, CacheConfig, KVCacheDtype, KVCacheLayout,
+};
+use candle_vllm::scheduler::observer::TraceExporter;
+use candle_vllm::scheduler::preset::EnginePreset;
+use candle_vllm::scheduler::sequence::CancellationReason;
+use candle_vllm::scheduler::{SchedulerConfig, SchedulingPolicy};
 use candle_vllm::{get_model_loader, ModelSelected};
 use clap::Parser;
 
@@ -20,11 +46,16 @@ struct Args {
     #[arg(long)]
     hf_token: Option<String>,
 
-    /// Huggingface token file (optional). If neither `hf_token` or `hf_token_path` are specified this is used with the value
-    /// of `~/.cache/huggingface/token`
+    /// Huggingface token file (optional). If neither `hf_token` or `hf_token_path` are specified, the `HF_TOKEN`
+    /// environment variable or else `$HF_HOME/token` (`~/.cache/huggingface/token`) is used, if any
     #[arg(long)]
     hf_token_path: Option<String>,
 
+    /// Branch, tag or commit of the model repository to load, `main` by default. Downloads are
+    /// cached in the Huggingface cache (`HF_HUB_CACHE` or `HF_HOME`) and resumed if interrupted
+    #[arg(long)]
+    revision: Option<String>,
+
     /// Port to serve on (localhost:port)
     #[arg(long)]
     port: u16,
@@ -33,44 +64,678 @@ struct Args {
     #[arg(long)]
     verbose: bool,
 
+    /// Run the model and the KV cache on the CPU instead of the first CUDA device
+    #[arg(long)]
+    cpu: bool,
+
     #[clap(subcommand)]
     command: ModelSelected,
 
+    /// Further models to serve next to the model of the subcommand, Huggingface model ids whose
+    /// architecture is detected from their config. Each model has its own engines, schedulers and
+    /// KV caches, and requests are routed to it by their `model` field
+    #[arg(long, value_delimiter = ',')]
+    extra_models: Vec<String>,
+
+    /// CUDA device of each of `--extra-models`, the first CUDA device if not given
+    #[arg(long, value_delimiter = ',')]
+    extra_model_devices: Vec<usize>,
+
+    /// Key the admin endpoints require as bearer token. Reloading the model is disabled without it
+    #[arg(long)]
+    admin_key: Option<String>,
+
+    /// Huggingface model ids and LoRA adapters a reload may load, besides `--lora`
+    #[arg(long, value_delimiter = ',', requires = "admin_key")]
+    reload_allowlist: Vec<String>,
+
+    /// Scheduler preset, whose settings can be overridden by the flags below
+    #[arg(long, value_enum, default_value_t = EnginePreset::Balanced)]
+    preset: EnginePreset,
+
     /// Maximum number of sequences to allow
-    #[arg(long, default_value_t = 256)]
-    max_num_seqs: usize,
+    #[arg(long)]
+    max_num_seqs: Option<usize>,
+
+    /// Maximum number of tokens processed in one step
+    #[arg(long)]
+    max_num_batched_tokens: Option<usize>,
+
+    /// Cap the share of prefill work while requests are decoding (e.g. 0.3)
+    #[arg(long)]
+    max_prefill_fraction: Option<f32>,
+
+    /// Maximum number of prompt tokens prefilled in one step
+    #[arg(long)]
+    max_num_prefill_tokens: Option<usize>,
 
     /// Size of a block
     #[arg(long, default_value_t = 16)]
     block_size: usize,
+
+    /// Host memory (GiB) reserved for swapped out KV cache blocks
+    #[arg(long, default_value_t = 4.)]
+    swap_space_gb: f64,
+
+    /// Reuse cached KV blocks of prompts sharing a prefix
+    #[arg(long)]
+    enable_prefix_caching: bool,
+
+    /// Maximum number of prefix cache blocks per tenant (API key)
+    #[arg(long)]
+    prefix_cache_quota: Option<usize>,
+
+    /// Storage type of the KV cache. `int8` and `fp8` halve its memory and require a CUDA device
+    #[arg(long, value_enum, default_value_t = KVCacheDtype::Auto)]
+    kv_cache_dtype: KVCacheDtype,
+
+    /// Memory layout of the KV cache blocks. `nhd` requires a CUDA device and an unquantized cache
+    #[arg(long, value_enum, default_value_t = KVCacheLayout::Split)]
+    kv_cache_layout: KVCacheLayout,
+
+    /// Benchmark the block sizes, KV cache layouts and attention kernels for the model at
+    /// startup and use the fastest, overriding `--block-size` and `--kv-cache-layout`. The result
+    /// is cached per GPU and model in the user cache directory
+    #[arg(long)]
+    autotune: bool,
+
+    /// Run dummy requests of every `--warmup-batch-sizes` and `--warmup-prompt-lens` bucket at
+    /// startup, so that the first requests do not pay for kernel loading and allocations
+    #[arg(long)]
+    warmup: bool,
+
+    /// Numbers of concurrent requests of the warmup, capped by `--max-num-seqs`
+    #[arg(long, value_delimiter = ',', default_values_t = [1, 8, 32])]
+    warmup_batch_sizes: Vec<usize>,
+
+    /// Prompt lengths of the warmup, capped by the context length and `--max-num-batched-tokens`
+    #[arg(long, value_delimiter = ',', default_values_t = [128, 512, 2048])]
+    warmup_prompt_lens: Vec<usize>,
+
+    /// Attention backend to prefer over the automatic choice. A backend which does not support
+    /// the device or a phase (prompt or decode) falls back to the automatic choice for it
+    #[arg(long, value_enum, default_value_t = AttentionBackendKind::Auto)]
+    attention_backend: AttentionBackendKind,
+
+    /// Precision of the attention scores of the decode kernels. `int8` and `fp8` quantize the
+    /// query and the keys with per-head and per-block scales (SageAttention-style) for faster
+    /// decoding, the softmax and the value product stay in full precision
+    #[arg(long, value_enum, default_value_t = AttentionComputeDtype::Full)]
+    attention_compute_dtype: AttentionComputeDtype,
+
+    /// Data type of the weights and activations. `auto` follows the `torch_dtype` of the
+    /// checkpoint, so BF16 checkpoints run in BF16 on devices which support it
+    #[arg(long, value_enum, default_value_t = ModelDtype::Auto)]
+    dtype: ModelDtype,
+
+    /// Quantize the linear layers of an unquantized checkpoint while loading it. `q8_0` and
+    /// `q4_k` run with the quantized matmul kernels, `nf4` is dequantized in each forward pass.
+    /// Pre-quantized checkpoints keep their own format
+    #[arg(long, value_enum)]
+    quantize: Option<InSituQuantization>,
+
+    /// PEFT LoRA adapter applied to the model, a local directory or a Huggingface repository
+    #[arg(long)]
+    lora: Option<String>,
+
+    /// Whether the LoRA adapter is merged into the weights while loading or applied at runtime
+    #[arg(long, value_enum, default_value_t = LoraMode::Merge)]
+    lora_mode: LoraMode,
+
+    /// Draft model of speculative decoding, a Huggingface model id of a small model sharing the
+    /// tokenizer of the model. Its architecture is detected from its config
+    #[arg(long)]
+    speculative_model: Option<String>,
+
+    /// Data type of the draft model, `--dtype` by default
+    #[arg(long, value_enum, requires = "speculative_model")]
+    draft_dtype: Option<ModelDtype>,
+
+    /// Quantize the linear layers of the draft model while loading it, see `--quantize`. The
+    /// draft model is not quantized by default
+    #[arg(long, value_enum, requires = "speculative_model")]
+    draft_quantize: Option<InSituQuantization>,
+
+    /// Device of the draft model, `cpu` or a CUDA device ordinal, the device of each replica by
+    /// default. On another device, the draft model caches the tokens of each step while the model
+    /// runs
+    #[arg(long, requires = "speculative_model")]
+    draft_device: Option<DraftDevice>,
+
+    /// Number of tokens the draft model, the EAGLE head or prompt lookup proposes in each decode
+    /// step, which the model verifies in a single forward pass. With `--medusa`, the number of
+    /// candidates in the tree (at most 63)
+    #[arg(long, default_value_t = 5)]
+    num_speculative_tokens: usize,
+
+    /// Medusa decoding heads of the model, a local directory or a Huggingface repository with
+    /// `config.json` and `medusa_lm_head.safetensors`. The model verifies a tree of their
+    /// candidates in each decode step
+    #[arg(long, conflicts_with = "speculative_model")]
+    medusa: Option<String>,
+
+    /// Accept the Medusa candidates which the model finds typical enough instead of only the
+    /// tokens it samples, for more accepted tokens when sampling with a temperature
+    #[arg(long, requires = "medusa")]
+    typical_acceptance: bool,
+
+    /// Probability above which a candidate is always typical
+    #[arg(long, default_value_t = TypicalAcceptance::default().posterior_threshold)]
+    typical_acceptance_threshold: f32,
+
+    /// Scale of the entropy-dependent threshold `alpha * exp(-entropy)` of typical acceptance
+    #[arg(long, default_value_t = TypicalAcceptance::default().posterior_alpha)]
+    typical_acceptance_alpha: f32,
+
+    /// EAGLE draft head of the model, a local directory or a Huggingface repository with
+    /// `config.json` and `model.safetensors`. The head drafts from the hidden states of the model
+    /// with its embeddings and output layer
+    #[arg(long, conflicts_with_all = ["speculative_model", "medusa"])]
+    eagle: Option<String>,
+
+    /// Speculate the tokens which followed the last n-gram of a sequence where it occurred
+    /// earlier in its prompt or output, without a draft model
+    #[arg(long, conflicts_with_all = ["speculative_model", "medusa", "eagle"])]
+    prompt_lookup: bool,
+
+    /// Shortest n-gram matched by prompt lookup
+    #[arg(long, default_value_t = 1)]
+    prompt_lookup_min: usize,
+
+    /// Longest n-gram matched by prompt lookup, longer matches are tried first
+    #[arg(long, default_value_t = 3)]
+    prompt_lookup_max: usize,
+
+    /// Keep this many leading blocks of each sequence as attention sinks and evict the blocks
+    /// after them beyond `--attention-window-blocks`, for unbounded streaming generation
+    #[arg(long, requires = "attention_window_blocks")]
+    attention_sink_blocks: Option<usize>,
+
+    /// Number of most recent blocks kept after the attention sinks
+    #[arg(long, requires = "attention_sink_blocks")]
+    attention_window_blocks: Option<usize>,
+
+    /// Retain the KV cache of sessions for follow-up requests, swapping out sessions idle for this
+    /// many seconds and dropping them after twice as long
+    #[arg(long)]
+    session_ttl_secs: Option<u64>,
+
+    /// Store up to this many conversations on the server for the `/v1/conversations` API, which
+    /// is disabled if not set. The least recently updated conversation is dropped when full
+    #[arg(long)]
+    max_conversations: Option<usize>,
+
+    /// Number of data-parallel engine replicas
+    #[arg(long, default_value_t = 1)]
+    data_parallel_size: usize,
+
+    /// Number of CUDA devices the weights of each Llama model of the subcommand are sharded over,
+    /// starting from the first CUDA device, for models which do not fit a single device. The
+    /// devices compute one after another, so steps are not faster. Each data-parallel replica is
+    /// split over the same devices
+    #[arg(long, default_value_t = 1)]
+    tensor_parallel_size: usize,
+
+    /// Number of CUDA devices the layers of each Llama model of the subcommand are split over in
+    /// consecutive stages, starting from the first CUDA device. Suits devices with weak links
+    /// between them better than `--tensor-parallel-size`
+    #[arg(long, default_value_t = 1)]
+    pipeline_parallel_size: usize,
+
+    /// Number of micro-batches each step is split into with pipeline parallelism, the number of
+    /// stages by default
+    #[arg(long)]
+    num_micro_batches: Option<usize>,
+
+    /// Keep the weights of this many last layers of the Llama model of the subcommand in CPU
+    /// memory and upload them for each forward pass, for models slightly too big for the device.
+    /// The cost of the uploads is reported in the metrics
+    #[arg(long, default_value_t = 0)]
+    cpu_offload_layers: usize,
+
+    /// How requests are placed onto the data-parallel replicas
+    #[arg(long, value_enum, default_value_t = PlacementStrategy::RoundRobin)]
+    placement_strategy: PlacementStrategy,
+
+    /// Order in which waiting requests are admitted
+    #[arg(long, value_enum)]
+    scheduling_policy: Option<SchedulingPolicy>,
+
+    /// Append a JSON trace of the scheduling events of each finished request to this file
+    #[arg(long)]
+    export_trace: Option<String>,
+
+    /// Capture the scheduling, cache operation, prefill, decode and sampling ranges of the first
+    /// N engine steps and write them as a Chrome trace to `--profile-output`
+    #[arg(long)]
+    profile_steps: Option<usize>,
+
+    /// Chrome trace file of `--profile-steps`, viewable in chrome://tracing or Perfetto
+    #[arg(long, default_value = "profile.json")]
+    profile_output: String,
 }
 
-#[actix_web::main]
-async fn main() -> Result<(), APIError> {
-    let args = Args::parse();
-
-    let (loader, model_id) = get_model_loader(args.command);
-    let paths = loader.download_model(model_id, None, args.hf_token, args.hf_token_path)?;
-    let model = loader.load_model(paths, DType::F16, Device::Cpu)?;
-    let llm_engine = LLMEngine::new(
-        model.0,
-        SchedulerConfig {
-            max_num_seqs: args.max_num_seqs,
-        },
-        CacheConfig {
+/// The device of the draft model, see `--draft-device`.
+#[derive(Clone, Copy, Debug)]
+enum DraftDevice {
+    Cpu,
+    Cuda(usize),
+}
+
+impl FromStr for DraftDevice {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "cpu" => Ok(Self::Cpu),
+            ordinal => ordinal
+                .parse()
+                .map(Self::Cuda)
+                .map_err(|_| format!("expected `cpu` or a CUDA device ordinal, got `{ordinal}`")),
+        }
+    }
+}
+
+impl DraftDevice {
+    fn device(&self) -> Result<Device, APIError> {
+        match self {
+            Self::Cpu => Ok(Device::Cpu),
+            Self::Cuda(ordinal) => Device::new_cuda(*ordinal).map_err(APIError::from),
+        }
+    }
+}
+
+/// The scheduler configuration of the selected preset, with the explicitly given flags taking
+/// precedence.
+fn scheduler_config(args: &Args) -> SchedulerConfig {
+    let preset = args.preset.scheduler_config();
+    SchedulerConfig {
+        max_num_seqs: args.max_num_seqs.unwrap_or(preset.max_num_seqs),
+        policy: args.scheduling_policy.unwrap_or(preset.policy),
+        session_ttl: args.session_ttl_secs.map(Duration::from_secs),
+        max_num_batched_tokens: args
+            .max_num_batched_tokens
+            .unwrap_or(preset.max_num_batched_tokens),
+        max_prefill_fraction: args.max_prefill_fraction.or(preset.max_prefill_fraction),
+        max_num_prefill_tokens: args
+            .max_num_prefill_tokens
+            .or(preset.max_num_prefill_tokens),
+    }
+}
+
+/// The device of data-parallel replica `replica`: each replica takes the next
+/// `devices_per_replica` CUDA devices after `device`, for its tensor-parallel ranks or pipeline
+/// stages. Replicas on the CPU or Metal share the device.
+fn replica_device(
+    device: &Device,
+    replica: usize,
+    devices_per_replica: usize,
+) -> Result<Device, APIError> {
+    match device {
+        Device::Cuda(dev) if replica > 0 => {
+            Device::new_cuda(dev.ordinal() + replica * devices_per_replica).map_err(APIError::from)
+        }
+        _ => Ok(device.clone()),
+    }
+}
+
+/// Load `args.data_parallel_size` engines of the model `selected` at `revision` with the LoRA
+/// adapter `lora`, and the pipeline config of the model. The draft model and the Medusa and
+/// EAGLE heads belong to the model of the subcommand, so only its `primary` engines get them.
+fn load_engines(
+    args: &Args,
+    selected: ModelSelected,
+    revision: Option<String>,
+    lora: Option<&str>,
+    device: &Device,
+    primary: bool,
+) -> Result<(Vec<LLMEngine<'static>>, PipelineConfig), APIError> {
+    let (loader, model_id) = get_model_loader(selected);
+    let (tensor_parallel_size, pipeline_parallel_size) = if primary {
+        (args.tensor_parallel_size, args.pipeline_parallel_size)
+    } else {
+        (1, 1)
+    };
+    let devices_per_replica = tensor_parallel_size * pipeline_parallel_size;
+    let mut draft_models = Vec::new();
+    if let Some(draft_model_id) = args.speculative_model.as_ref().filter(|_| primary) {
+        let (draft_loader, draft_model_id) = get_model_loader(ModelSelected::Auto {
+            repeat_last_n: 64,
+            model: draft_model_id.clone(),
+        });
+        for replica in 0..args.data_parallel_size {
+            let device = match args.draft_device {
+                Some(draft_device) => draft_device.device()?,
+                None => replica_device(device, replica, devices_per_replica)?,
+            };
+            let paths = draft_loader.download_model(
+                draft_model_id.clone(),
+                None,
+                args.hf_token.clone(),
+                args.hf_token_path.clone(),
+            )?;
+            let dtype = args
+                .draft_dtype
+                .unwrap_or(args.dtype)
+                .resolve(paths.get_config_filename(), &device)?;
+            // The draft models are loaded without the LoRA adapter, which only adapts the model,
+            // and on a single device.
+            let options = LoadOptions {
+                in_situ_quantization: args.draft_quantize,
+                ..Default::default()
+            };
+            draft_models.push(draft_loader.load_model(paths, dtype, device, &options)?.0);
+        }
+        // The replicas pop their draft model from the back.
+        draft_models.reverse();
+    }
+    if lora.is_some() && tensor_parallel_size > 1 {
+        return Err(APIError::new_str(
+            "LoRA adapters are not supported with weight sharding across devices.",
+        ));
+    }
+    if lora.is_some() && pipeline_parallel_size > 1 {
+        return Err(APIError::new_str(
+            "LoRA adapters are not supported with pipeline parallelism.",
+        ));
+    }
+    let lora_adapter = match lora {
+        Some(lora) => Some((
+            Arc::new(LoraAdapter::load(
+                lora,
+                args.hf_token.clone(),
+                args.hf_token_path.clone(),
+            )?),
+            args.lora_mode,
+        )),
+        None => None,
+    };
+    let options = LoadOptions {
+        tensor_parallel_size,
+        pipeline_parallel_size,
+        num_offloaded_layers: if primary { args.cpu_offload_layers } else { 0 },
+        in_situ_quantization: args.quantize,
+        lora_adapter,
+    };
+    let mut engines = Vec::with_capacity(args.data_parallel_size);
+    let mut pipeline_config = None;
+    for replica in 0..args.data_parallel_size {
+        let device = &replica_device(device, replica, devices_per_replica)?;
+        let paths = loader.download_model(
+            model_id.clone(),
+            revision.clone(),
+            args.hf_token.clone(),
+            args.hf_token_path.clone(),
+        )?;
+        let dtype = args.dtype.resolve(paths.get_config_filename(), device)?;
+        let model = loader.load_model(paths, dtype, device.clone(), &options)?;
+        let mut cache_config = CacheConfig {
             block_size: args.block_size,
             num_gpu_blocks: None,
             num_cpu_blocks: None,
             fully_init: false,
-        },
+            swap_space_bytes: (args.swap_space_gb * GIB) as usize,
+            enable_prefix_caching: args.enable_prefix_caching,
+            prefix_cache_quota: args.prefix_cache_quota,
+            cache_dtype: args.kv_cache_dtype,
+            cache_layout: args.kv_cache_layout,
+            attention_sinks: args
+                .attention_sink_blocks
+                .zip(args.attention_window_blocks)
+                .map(|(num_sink_blocks, num_window_blocks)| AttentionSinks {
+                    num_sink_blocks,
+                    num_window_blocks,
+                }),
+        };
+        let autotune_result = if args.autotune {
+            let result = autotune(
+                &model_id,
+                &*model.0.get_model_config(),
+                &cache_config,
+                model.0.get_dtype(),
+                device,
+            )?;
+            result.apply(&mut cache_config);
+            Some(result)
+        } else {
+            None
+        };
+        let scheduler_config = scheduler_config(args);
+        let max_num_seqs = scheduler_config.max_num_seqs;
+        let max_num_batched_tokens = scheduler_config.max_num_batched_tokens;
+        let mut llm_engine = LLMEngine::new(model.0, scheduler_config, cache_config)?;
+        if let Some(result) = &autotune_result {
+            llm_engine.set_autotune_result(result);
+        }
+        llm_engine.set_attention_backend(args.attention_backend);
+        llm_engine.set_attention_compute(args.attention_compute_dtype);
+        if let Some(num_micro_batches) = args
+            .num_micro_batches
+            .filter(|_| pipeline_parallel_size > 1)
+        {
+            llm_engine.set_num_micro_batches(num_micro_batches);
+        }
+        if let Some(draft_model) = draft_models.pop() {
+            llm_engine.set_speculative_model(draft_model, args.num_speculative_tokens)?;
+        }
+        if let Some(medusa) = args.medusa.as_ref().filter(|_| primary) {
+            let pipeline = llm_engine.get_pipeline();
+            let heads = MedusaHeads::load(
+                medusa,
+                args.hf_token.clone(),
+                args.hf_token_path.clone(),
+                pipeline.get_dtype(),
+                pipeline.device(),
+            )?;
+            let typical_acceptance = args.typical_acceptance.then_some(TypicalAcceptance {
+                posterior_threshold: args.typical_acceptance_threshold,
+                posterior_alpha: args.typical_acceptance_alpha,
+            });
+            llm_engine.set_medusa_heads(heads, args.num_speculative_tokens, typical_acceptance)?;
+        }
+        if let Some(eagle) = args.eagle.as_ref().filter(|_| primary) {
+            let pipeline = llm_engine.get_pipeline();
+            let head = EagleHead::load(
+                eagle,
+                args.hf_token.clone(),
+                args.hf_token_path.clone(),
+                pipeline.get_dtype(),
+                pipeline.device(),
+            )?;
+            llm_engine.set_eagle_head(head, args.num_speculative_tokens)?;
+        }
+        if args.prompt_lookup {
+            llm_engine.set_prompt_lookup(PromptLookup::new(
+                args.num_speculative_tokens,
+                args.prompt_lookup_min,
+                args.prompt_lookup_max,
+            )?)?;
+        }
+        let (gpu_cache_bytes, cpu_cache_bytes) = llm_engine.get_kv_cache_memory_usage();
+        println!(
+            "KV cache: {:.2} GiB on the device, {:.2} GiB of swap space.",
+            gpu_cache_bytes as f64 / GIB,
+            cpu_cache_bytes as f64 / GIB
+        );
+        if args.warmup {
+            let mut batch_sizes = args
+                .warmup_batch_sizes
+                .iter()
+                .map(|batch_size| (*batch_size).min(max_num_seqs))
+                .filter(|batch_size| *batch_size > 0)
+                .collect::<Vec<_>>();
+            batch_sizes.sort_unstable();
+            batch_sizes.dedup();
+            let prompt_lens = args
+                .warmup_prompt_lens
+                .iter()
+                .copied()
+                .filter(|prompt_len| {
+                    *prompt_len > 0
+                        && prompt_len + WARMUP_MAX_TOKENS <= model.1.max_model_len
+                        && *prompt_len <= max_num_batched_tokens
+                })
+                .collect::<Vec<_>>();
+            let elapsed = llm_engine.warmup(&batch_sizes, &prompt_lens)?;
+            println!("Warmed up in {:.1} s.", elapsed.as_secs_f64());
+        }
+        if let Some(path) = &args.export_trace {
+            llm_engine.add_scheduler_observer(Box::new(TraceExporter::new(path)?));
+        }
+        engines.push(llm_engine);
+        pipeline_config = Some(model.1);
+    }
+    let pipeline_config = pipeline_config
+        .ok_or_else(|| APIError::new_str("The data-parallel size must be at least 1."))?;
+    Ok((engines, pipeline_config))
+}
+
+/// The server data of the engines of a model, the first engine serving as the primary engine
+/// and the others as its data-parallel replicas.
+fn serve_engines(
+    args: &Args,
+    engines: Vec<LLMEngine<'static>>,
+    pipeline_config: PipelineConfig,
+    device: Device,
+) -> OpenAIServerData<'static> {
+    let mut engines = engines.into_iter();
+    let llm_engine = engines.next().unwrap();
+    let replicas = engines
+        .map(|replica| Replica {
+            abort_handle: replica.get_abort_handle(),
+            metrics: replica.get_metrics_handle(),
+            counters: replica.get_replica_counters(),
+            model: Arc::new(Mutex::new(replica)),
+        })
+        .collect::<Vec<_>>();
+    OpenAIServerData {
+        pipeline_config: Arc::new(RwLock::new(pipeline_config)),
+        abort_handle: llm_engine.get_abort_handle(),
+        metrics: llm_engine.get_metrics_handle(),
+        counters: llm_engine.get_replica_counters(),
+        model: Arc::new(Mutex::new(llm_engine)),
+        device,
+        replicas,
+        placement: Arc::new(Mutex::new(ReplicaPlacer::new(args.placement_strategy))),
+        conversations: None,
+        reloader: None,
+        other_models: Vec::new(),
+    }
+}
+
+#[actix_web::main]
+async fn main() -> Result<(), APIError> {
+    let args = Arc::new(Args::parse());
+
+    let device = if args.cpu {
+        Device::Cpu
+    } else {
+        Device::new_cuda(0).map_err(APIError::from)?
+    };
+    let (engines, pipeline_config) = load_engines(
+        &args,
+        args.command.clone(),
+        args.revision.clone(),
+        args.lora.as_deref(),
+        &device,
+        true,
     )?;
+    if args.extra_model_devices.len() > args.extra_models.len() {
+        return Err(APIError::new(format!(
+            "Got {} `--extra-model-devices` for {} `--extra-models`.",
+            args.extra_model_devices.len(),
+            args.extra_models.len()
+        )));
+    }
+    // Requests select a model by name, so the names of the models must differ.
+    let mut names = vec![pipeline_config.name.clone()];
+    let mut other_models = Vec::with_capacity(args.extra_models.len());
+    for (i, model) in args.extra_models.iter().enumerate() {
+        let device = if args.cpu {
+            Device::Cpu
+        } else {
+            let ordinal = args.extra_model_devices.get(i).copied().unwrap_or(0);
+            Device::new_cuda(ordinal).map_err(APIError::from)?
+        };
+        let selected = ModelSelected::Auto {
+            repeat_last_n: 64,
+            model: model.clone(),
+        };
+        let (engines, pipeline_config) = load_engines(&args, selected, None, None, &device, false)?;
+        if names.contains(&pipeline_config.name) {
+            return Err(APIError::new(format!(
+                "Model `{}` is served more than once.",
+                pipeline_config.name
+            )));
+        }
+        names.push(pipeline_config.name.clone());
+        other_models.push(serve_engines(&args, engines, pipeline_config, device));
+    }
+    if let Some(num_steps) = args.profile_steps {
+        profiling::profile_steps(num_steps, &args.profile_output);
+    }
 
+    // A reload replaces the checkpoint, revision or adapter given in its request and keeps the
+    // others of the last load.
+    let current = Mutex::new((
+        args.command.clone(),
+        args.revision.clone(),
+        args.lora.clone(),
+    ));
+    let reload_args = args.clone();
+    let reload_device = device.clone();
+    let load: Arc<LoadEngines<'static>> = Arc::new(move |request: &ReloadRequest| {
+        let mut current = current.lock().unwrap();
+        let (selected, revision) = match &request.model {
+            Some(model) => (
+                ModelSelected::Auto {
+                    repeat_last_n: 64,
+                    model: model.clone(),
+                },
+                request.revision.clone(),
+            ),
+            None => (
+                current.0.clone(),
+                request.revision.clone().or(current.1.clone()),
+            ),
+        };
+        let lora = match &request.lora {
+            _ if request.disable_lora => None,
+            Some(lora) => Some(lora.clone()),
+            None => current.2.clone(),
+        };
+        let loaded = load_engines(
+            &reload_args,
+            selected.clone(),
+            revision.clone(),
+            lora.as_deref(),
+            &reload_device,
+            true,
+        )?;
+        *current = (selected, revision, lora);
+        Ok(loaded)
+    });
+
+    let mut allowed = args.reload_allowlist.clone();
+    allowed.extend(args.lora.clone());
     let server_data = OpenAIServerData {
-        pipeline_config: model.1,
-        model: Arc::new(Mutex::new(llm_engine)),
-        device: Device::Cpu,
+        conversations: args.max_conversations.map(ConversationStore::new),
+        reloader: args
+            .admin_key
+            .clone()
+            .map(|admin_key| ModelReloader::new(load, admin_key, allowed)),
+        other_models,
+        ..serve_engines(&args, engines, pipeline_config, device)
     };
 
+    // Abort the in-flight requests on Ctrl-C, so their clients are told why they ended.
+    let shutdown_data = server_data.clone();
+    actix_web::rt::spawn(async move {
+        if actix_web::rt::signal::ctrl_c().await.is_ok() {
+            shutdown_data.abort_all(CancellationReason::Shutdown);
+        }
+    });
+
     println!("Server started at http://127.0.0.1:{}.", args.port);
     if args.verbose {
         env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
@@ -79,6 +744,17 @@ async fn main() -> Result<(), APIError> {
             App::new()
                 .wrap(Logger::default())
                 .service(chat_completions)
+                .service(embeddings)
+                .service(rerank)
+                .service(cancel_request)
+                .service(get_metrics)
+                .service(list_models)
+                .service(create_conversation)
+                .service(get_conversation)
+                .service(append_conversation)
+                .service(delete_conversation)
+                .service(reload_model)
+                .service(playground)
                 .app_data(Data::new(server_data.clone()))
         })
         .bind(("127.0.0.1", args.port))
@@ -90,6 +766,17 @@ async fn main() -> Result<(), APIError> {
         HttpServer::new(move || {
             App::new()
                 .service(chat_completions)
+                .service(embeddings)
+                .service(rerank)
+                .service(cancel_request)
+                .service(get_metrics)
+                .service(list_models)
+                .service(create_conversation)
+                .service(get_conversation)
+                .service(append_conversation)
+                .service(delete_conversation)
+                .service(reload_model)
+                .service(playground)
                 .app_data(Data::new(server_data.clone()))
         })
         .bind(("127.0.0.1", args.port))
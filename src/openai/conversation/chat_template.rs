@@ -0,0 +1,141 @@
+//! Prompts rendered with the Jinja `chat_template` of `tokenizer_config.json`, like
+//! `apply_chat_template` of Transformers, instead of the prompt formats of `DefaultConversation`.
+
+use std::path::Path;
+
+use minijinja::{context, Environment, Error, ErrorKind};
+use serde::Deserialize;
+
+use crate::{openai::responses::APIError, try_api};
+
+const DEFAULT_TEMPLATE: &str = "default";
+/// The name of the template for requests with tools, if the model has a separate one.
+const TOOL_USE_TEMPLATE: &str = "tool_use";
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ChatTemplates {
+    Single(String),
+    Named(Vec<NamedChatTemplate>),
+}
+
+#[derive(Deserialize)]
+struct NamedChatTemplate {
+    name: String,
+    template: String,
+}
+
+/// A special token, either its content or a serialized `AddedToken`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SpecialToken {
+    Content(String),
+    Added { content: String },
+}
+
+impl SpecialToken {
+    fn content(self) -> String {
+        match self {
+            Self::Content(content) | Self::Added { content } => content,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenizerConfig {
+    #[serde(default)]
+    chat_template: Option<ChatTemplates>,
+    #[serde(default)]
+    bos_token: Option<SpecialToken>,
+    #[serde(default)]
+    eos_token: Option<SpecialToken>,
+}
+
+/// The chat template of a model with the special tokens it refers to.
+pub struct ChatTemplate {
+    env: Environment<'static>,
+    bos_token: String,
+    eos_token: String,
+}
+
+impl ChatTemplate {
+    /// The chat template of the `tokenizer_config.json` at `filename`, None if it has none.
+    pub fn from_tokenizer_config(filename: &Path) -> Result<Option<Self>, APIError> {
+        let config: TokenizerConfig =
+            try_api!(serde_json::from_slice(&try_api!(std::fs::read(filename))));
+        let templates = match config.chat_template {
+            None => return Ok(None),
+            Some(ChatTemplates::Single(template)) => vec![(DEFAULT_TEMPLATE.to_string(), template)],
+            Some(ChatTemplates::Named(templates)) => templates
+                .into_iter()
+                .map(|template| (template.name, template.template))
+                .collect(),
+        };
+
+        let mut env = Environment::new();
+        // Transformers renders the templates with these settings and Python string methods.
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+        env.add_function("raise_exception", raise_exception);
+        env.add_function("strftime_now", strftime_now);
+        for (name, template) in templates {
+            try_api!(env.add_template_owned(name, template));
+        }
+        if env.get_template(DEFAULT_TEMPLATE).is_err() {
+            return Err(APIError::new(format!(
+                "The tokenizer config {} has no `{DEFAULT_TEMPLATE}` chat template.",
+                filename.display()
+            )));
+        }
+        Ok(Some(Self {
+            env,
+            bos_token: config
+                .bos_token
+                .map(SpecialToken::content)
+                .unwrap_or_default(),
+            eos_token: config
+                .eos_token
+                .map(SpecialToken::content)
+                .unwrap_or_default(),
+        }))
+    }
+
+    /// The end-of-sequence token closing the turns of the template, if any.
+    pub fn eos_token(&self) -> Option<&str> {
+        Some(self.eos_token.as_str()).filter(|token| !token.is_empty())
+    }
+
+    /// Render `messages`, OpenAI chat messages with the `tool_calls` arguments as objects, with
+    /// the definitions of the `tools` the model may call. `add_generation_prompt` opens the turn
+    /// of the assistant.
+    pub fn render(
+        &self,
+        messages: &[serde_json::Value],
+        tools: Option<&[serde_json::Value]>,
+        add_generation_prompt: bool,
+    ) -> Result<String, APIError> {
+        let name = match tools {
+            Some(_) if self.env.get_template(TOOL_USE_TEMPLATE).is_ok() => TOOL_USE_TEMPLATE,
+            _ => DEFAULT_TEMPLATE,
+        };
+        let template = try_api!(self.env.get_template(name));
+        Ok(try_api!(template.render(context! {
+            messages,
+            tools,
+            add_generation_prompt,
+            bos_token => self.bos_token,
+            eos_token => self.eos_token,
+        })))
+    }
+}
+
+/// `raise_exception(message)` of the templates, which reject unsupported conversations with it.
+fn raise_exception(message: String) -> Result<String, Error> {
+    Err(Error::new(ErrorKind::InvalidOperation, message))
+}
+
+/// `strftime_now(format)` of the templates, e.g. for the date in the system prompt.
+fn strftime_now(format: String) -> String {
+    chrono::Local::now().format(&format).to_string()
+}
@@ -25,6 +25,7 @@ pub enum SeparatorStyle {
     Phoenix,
     Robin,
     FalconChat,
+    Gemma,
 }
 
 /// A struct for managing prompt templates and conversation history.
@@ -359,6 +360,24 @@ impl Conversation for DefaultConversation {
                 }
                 accum
             }
+
+            SeparatorStyle::Gemma => {
+                let mut accum = "".to_string();
+                for (i, message) in self.messages.iter().enumerate() {
+                    let Message((role, message)) = message;
+                    if let Some(message) = message {
+                        // There is no system turn, the system prompt leads the first user turn.
+                        if i == 0 && !system_prompt.is_empty() {
+                            accum += &format!("{role}\n{system_prompt}\n\n{message}{}\n", self.sep);
+                        } else {
+                            accum += &format!("{role}\n{message}{}\n", self.sep);
+                        }
+                    } else {
+                        accum += &format!("{role}\n");
+                    }
+                }
+                accum
+            }
         }
     }
 }
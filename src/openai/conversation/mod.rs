@@ -1,3 +1,4 @@
+pub mod chat_template;
 pub mod default_conversation;
 
 /// A trait for using conversation managers with a `ModulePipeline`.
@@ -0,0 +1,128 @@
+//! Server-side conversation history, so that clients only send the new messages of a turn. See
+//! the `/v1/conversations` endpoints. Each conversation belongs to the cache namespace of the API
+//! key which created it, and is not found with any other key.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use uuid::Uuid;
+
+use super::{responses::ConversationResponse, utils::get_created_time_secs};
+
+pub type Message = HashMap<String, String>;
+
+struct Conversation {
+    created: u64,
+    /// The cache namespace of the API key which created the conversation, if any.
+    cache_namespace: Option<String>,
+    messages: Vec<Message>,
+    /// Incremented on every change, used to evict the least recently updated conversation.
+    last_update: u64,
+}
+
+impl Conversation {
+    fn to_response(&self, id: &str) -> ConversationResponse {
+        ConversationResponse {
+            id: id.to_string(),
+            object: "conversation",
+            created: self.created,
+            messages: self.messages.clone(),
+        }
+    }
+}
+
+struct Conversations {
+    conversations: HashMap<String, Conversation>,
+    capacity: usize,
+    clock: u64,
+}
+
+/// An in-memory store of at most `capacity` conversations. Creating a conversation in a full
+/// store drops the least recently updated one.
+#[derive(Clone)]
+pub struct ConversationStore(Arc<Mutex<Conversations>>);
+
+impl ConversationStore {
+    pub fn new(capacity: usize) -> Self {
+        Self(Arc::new(Mutex::new(Conversations {
+            conversations: HashMap::new(),
+            capacity,
+            clock: 0,
+        })))
+    }
+
+    fn conversations(&self) -> MutexGuard<'_, Conversations> {
+        loop {
+            if let Ok(v) = self.0.try_lock() {
+                return v;
+            }
+        }
+    }
+
+    pub fn create(
+        &self,
+        cache_namespace: Option<&str>,
+        messages: Vec<Message>,
+    ) -> ConversationResponse {
+        let mut store = self.conversations();
+        if store.conversations.len() >= store.capacity {
+            let oldest = store
+                .conversations
+                .iter()
+                .min_by_key(|(_, conversation)| conversation.last_update)
+                .map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                store.conversations.remove(&oldest);
+            }
+        }
+        store.clock += 1;
+        let id = format!("conv-{}", Uuid::new_v4());
+        let conversation = Conversation {
+            created: get_created_time_secs(),
+            cache_namespace: cache_namespace.map(str::to_string),
+            messages,
+            last_update: store.clock,
+        };
+        let response = conversation.to_response(&id);
+        store.conversations.insert(id, conversation);
+        response
+    }
+
+    pub fn get(&self, cache_namespace: Option<&str>, id: &str) -> Option<ConversationResponse> {
+        self.conversations()
+            .conversations
+            .get(id)
+            .filter(|conversation| conversation.cache_namespace.as_deref() == cache_namespace)
+            .map(|conversation| conversation.to_response(id))
+    }
+
+    /// Append `messages` to the conversation, returning false if it does not exist in
+    /// `cache_namespace`.
+    pub fn append(&self, cache_namespace: Option<&str>, id: &str, messages: Vec<Message>) -> bool {
+        let mut store = self.conversations();
+        store.clock += 1;
+        let clock = store.clock;
+        match store.conversations.get_mut(id) {
+            Some(conversation) if conversation.cache_namespace.as_deref() == cache_namespace => {
+                conversation.messages.extend(messages);
+                conversation.last_update = clock;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Remove the conversation, returning false if it does not exist in `cache_namespace`.
+    pub fn remove(&self, cache_namespace: Option<&str>, id: &str) -> bool {
+        let mut store = self.conversations();
+        match store.conversations.get(id) {
+            Some(conversation) if conversation.cache_namespace.as_deref() == cache_namespace => {
+                store.conversations.remove(id);
+                true
+            }
+            _ => false,
+        }
+    }
+}
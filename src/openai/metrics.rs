@@ -0,0 +1,221 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    openai::{models::offload::OffloadStats, responses::ChatCompletionTimings, utils::GIB},
+    scheduler::sequence::SequenceTimings,
+};
+
+/// Number of latency samples retained for percentile computation.
+const LATENCY_WINDOW_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LatencyPercentiles {
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+}
+
+/// A sliding window of latency samples (in milliseconds).
+pub struct LatencyWindow {
+    samples: VecDeque<f64>,
+    capacity: usize,
+}
+
+impl LatencyWindow {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(latency.as_secs_f64() * 1000.);
+    }
+
+    pub fn percentiles(&self) -> Option<LatencyPercentiles> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let mut sorted = self.samples.iter().copied().collect::<Vec<_>>();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let at = |p: f64| sorted[((p / 100.) * (sorted.len() - 1) as f64).round() as usize];
+        Some(LatencyPercentiles {
+            p50: at(50.),
+            p90: at(90.),
+            p99: at(99.),
+        })
+    }
+}
+
+/// Aggregated engine-level latency statistics.
+pub struct EngineMetrics {
+    finished_sequences: usize,
+    prefill_tokens: usize,
+    decode_tokens: usize,
+    /// Tokens proposed by the draft model of speculative decoding, and those accepted.
+    draft_tokens: usize,
+    accepted_draft_tokens: usize,
+    ttft: LatencyWindow,
+    inter_token_latency: LatencyWindow,
+    /// The uploads of the layers offloaded to the CPU, with the upload time and the duration of
+    /// the generation steps since the first recorded step.
+    offload: Option<OffloadStats>,
+    offload_upload_time: Duration,
+    offload_step_time: Duration,
+}
+
+impl Default for EngineMetrics {
+    fn default() -> Self {
+        Self {
+            finished_sequences: 0,
+            prefill_tokens: 0,
+            decode_tokens: 0,
+            draft_tokens: 0,
+            accepted_draft_tokens: 0,
+            ttft: LatencyWindow::new(LATENCY_WINDOW_SIZE),
+            inter_token_latency: LatencyWindow::new(LATENCY_WINDOW_SIZE),
+            offload: None,
+            offload_upload_time: Duration::ZERO,
+            offload_step_time: Duration::ZERO,
+        }
+    }
+}
+
+impl EngineMetrics {
+    /// Record the timings of a finished sequence.
+    pub fn record_sequence(&mut self, timings: &SequenceTimings) {
+        self.finished_sequences += 1;
+        if let Some(ttft) = timings.time_to_first_token() {
+            self.ttft.record(ttft);
+        }
+        for latency in timings.inter_token_latencies() {
+            self.inter_token_latency.record(latency);
+        }
+    }
+
+    /// Record the tokens processed in one engine step.
+    pub fn record_step(&mut self, prefill_tokens: usize, decode_tokens: usize) {
+        self.prefill_tokens += prefill_tokens;
+        self.decode_tokens += decode_tokens;
+    }
+
+    /// Record the draft tokens of a speculative decoding step and how many were accepted.
+    pub fn record_speculation(&mut self, draft_tokens: usize, accepted_draft_tokens: usize) {
+        self.draft_tokens += draft_tokens;
+        self.accepted_draft_tokens += accepted_draft_tokens;
+    }
+
+    /// Record the cumulative upload statistics of the offloaded layers after a generation step
+    /// of `step_time`.
+    pub fn record_offload(&mut self, stats: OffloadStats, step_time: Duration) {
+        let previous = self
+            .offload
+            .map_or(Duration::ZERO, |offload| offload.upload_time);
+        self.offload_upload_time += stats.upload_time.saturating_sub(previous);
+        self.offload_step_time += step_time;
+        self.offload = Some(stats);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let total_tokens = self.prefill_tokens + self.decode_tokens;
+        MetricsSnapshot {
+            finished_sequences: self.finished_sequences,
+            prefill_tokens: self.prefill_tokens,
+            decode_tokens: self.decode_tokens,
+            prefill_token_fraction: (total_tokens > 0)
+                .then(|| self.prefill_tokens as f64 / total_tokens as f64),
+            draft_acceptance_rate: (self.draft_tokens > 0)
+                .then(|| self.accepted_draft_tokens as f64 / self.draft_tokens as f64),
+            ttft_ms: self.ttft.percentiles(),
+            inter_token_latency_ms: self.inter_token_latency.percentiles(),
+            offload: self.offload.map(|offload| OffloadMetrics {
+                num_offloaded_layers: offload.num_offloaded_layers,
+                offloaded_gib: offload.offloaded_bytes as f64 / GIB,
+                upload_gib_per_s: (!offload.upload_time.is_zero()).then(|| {
+                    offload.uploaded_bytes as f64 / GIB / offload.upload_time.as_secs_f64()
+                }),
+                upload_time_fraction: (!self.offload_step_time.is_zero()).then(|| {
+                    self.offload_upload_time.as_secs_f64() / self.offload_step_time.as_secs_f64()
+                }),
+            }),
+        }
+    }
+}
+
+/// Shared access to the metrics of an engine, which can be read without holding the lock on the
+/// `LLMEngine`, e.g. while it is generating.
+#[derive(Clone, Default)]
+pub struct MetricsHandle(Arc<Mutex<EngineMetrics>>);
+
+impl MetricsHandle {
+    pub(crate) fn metrics(&self) -> MutexGuard<'_, EngineMetrics> {
+        loop {
+            if let Ok(v) = self.0.try_lock() {
+                return v;
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        self.metrics().snapshot()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetricsSnapshot {
+    pub finished_sequences: usize,
+    pub prefill_tokens: usize,
+    pub decode_tokens: usize,
+    /// Achieved share of prefill tokens among all processed tokens.
+    pub prefill_token_fraction: Option<f64>,
+    /// Share of the draft tokens of speculative decoding accepted by the model.
+    pub draft_acceptance_rate: Option<f64>,
+    pub ttft_ms: Option<LatencyPercentiles>,
+    pub inter_token_latency_ms: Option<LatencyPercentiles>,
+    /// The cost of the layers offloaded to the CPU, None if no layer is offloaded.
+    pub offload: Option<OffloadMetrics>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OffloadMetrics {
+    pub num_offloaded_layers: usize,
+    /// Size of the weights of the offloaded layers.
+    pub offloaded_gib: f64,
+    /// Throughput of the uploads of the offloaded weights to the device.
+    pub upload_gib_per_s: Option<f64>,
+    /// Share of the duration of the generation steps spent uploading offloaded weights.
+    pub upload_time_fraction: Option<f64>,
+}
+
+/// Build the per-request timing summary returned in API responses.
+pub fn request_timings(timings: &SequenceTimings) -> ChatCompletionTimings {
+    let inter_token_latencies = timings.inter_token_latencies();
+    let mean_inter_token_latency_ms = if inter_token_latencies.is_empty() {
+        None
+    } else {
+        Some(
+            inter_token_latencies
+                .iter()
+                .map(|x| x.as_secs_f64() * 1000.)
+                .sum::<f64>()
+                / inter_token_latencies.len() as f64,
+        )
+    };
+    ChatCompletionTimings {
+        queue_time_ms: timings.queue_time().map(|x| x.as_secs_f64() * 1000.),
+        time_to_first_token_ms: timings
+            .time_to_first_token()
+            .map(|x| x.as_secs_f64() * 1000.),
+        mean_inter_token_latency_ms,
+    }
+}
@@ -1,10 +1,28 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
 
 use candle_core::Device;
-use tokenizers::{EncodeInput, Encoding, Tokenizer};
+use tokenizers::{DecoderWrapper, EncodeInput, Encoding, Tokenizer};
 
-use self::{pipelines::llm_engine::LLMEngine, responses::APIError};
+use self::{
+    conversation_store::ConversationStore,
+    metrics::{MetricsHandle, MetricsSnapshot},
+    pipelines::llm_engine::{AbortHandle, LLMEngine},
+    placement::{AdmittedRequest, ReplicaCounters, ReplicaPlacer},
+    requests::ReloadRequest,
+    responses::{APIError, ReloadResponse},
+    sampling_params::GenerationConfig,
+};
+use crate::{log_warning, scheduler::sequence::CancellationReason};
 
+pub mod metrics;
+pub mod placement;
 pub mod requests;
 pub mod responses;
 pub mod sampling_params;
@@ -15,7 +33,16 @@ where
     E: Into<EncodeInput<'s>>,
 {
     fn tokenize(&self, input: E) -> Result<Encoding, APIError>;
+    /// Tokenize with the special tokens of the tokenizer's post-processor, e.g. `[CLS]` and
+    /// `[SEP]` of BERT, which encoder-only models are trained with.
+    fn tokenize_with_special_tokens(&self, input: E) -> Result<Encoding, APIError>;
+    /// Tokenize a pair of texts as one input with special tokens, e.g. `[CLS] first [SEP] second
+    /// [SEP]`. The type ids of the encoding give the text of each token.
+    fn tokenize_pair(&self, first: &str, second: &str) -> Result<Encoding, APIError>;
     fn detokenize(&self, input: &[u32]) -> Result<String, APIError>;
+    /// Concatenate the raw bytes of the tokens. Unlike `detokenize`, partial UTF-8 sequences
+    /// are kept as-is.
+    fn detokenize_bytes(&self, input: &[u32]) -> Result<Vec<u8>, APIError>;
 }
 
 impl<'s, E> TokenizerWrapper<'s, E> for Tokenizer
@@ -26,25 +53,431 @@ where
         self.encode(input, false).map_err(APIError::from)
     }
 
+    fn tokenize_with_special_tokens(&self, input: E) -> Result<Encoding, APIError> {
+        self.encode(input, true).map_err(APIError::from)
+    }
+
+    fn tokenize_pair(&self, first: &str, second: &str) -> Result<Encoding, APIError> {
+        self.encode((first, second), true).map_err(APIError::from)
+    }
+
     fn detokenize(&self, input: &[u32]) -> Result<String, APIError> {
         self.decode(input, false).map_err(APIError::from)
     }
+
+    fn detokenize_bytes(&self, input: &[u32]) -> Result<Vec<u8>, APIError> {
+        let byte_level = matches!(self.get_decoder(), Some(DecoderWrapper::ByteLevel(_)));
+        let byte_table = byte_level.then(_byte_level_decoding_table);
+        let mut bytes = Vec::new();
+        for id in input {
+            let token = self
+                .id_to_token(*id)
+                .ok_or_else(|| APIError::new(format!("Unknown token id {id}.")))?;
+            if let Some(table) = &byte_table {
+                bytes.extend(token.chars().filter_map(|c| table.get(&c)));
+            } else if let Some(byte) = token
+                .strip_prefix("<0x")
+                .and_then(|x| x.strip_suffix('>'))
+                .and_then(|x| u8::from_str_radix(x, 16).ok())
+            {
+                // SentencePiece byte fallback token
+                bytes.push(byte);
+            } else {
+                bytes.extend(token.replace('\u{2581}', " ").into_bytes());
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+/// The inverse of the GPT-2 byte-to-unicode mapping used by byte-level BPE tokenizers.
+fn _byte_level_decoding_table() -> HashMap<char, u8> {
+    let mut table = HashMap::new();
+    let mut n = 0;
+    for byte in 0..=255u8 {
+        let printable = matches!(byte, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF);
+        let c = if printable {
+            char::from(byte)
+        } else {
+            n += 1;
+            char::from_u32(255 + n).unwrap()
+        };
+        table.insert(c, byte);
+    }
+    table
+}
+
+/// Number of tokens before the first generated token decoded with it, so that its text is
+/// decoded in context, e.g. with its leading space.
+const INITIAL_DETOKENIZATION_OFFSET: usize = 5;
+
+/// Decodes the tokens of a sequence as they are generated, decoding only the tokens since the
+/// last decoded text together with a few tokens before them for context, like vLLM. The text of
+/// the new tokens is the difference of the texts with and without them, so later tokens never
+/// change the text decoded before them, and the text is only appended to. A trailing incomplete
+/// character, which the tokenizer decodes to U+FFFD, is held back until the rest of its bytes is
+/// generated or the sequence finished.
+#[derive(Clone, Debug)]
+pub struct IncrementalDetokenizer {
+    /// Start of the tokens decoded as the context of the new tokens.
+    prefix_offset: usize,
+    /// End of the tokens whose text was decoded.
+    read_offset: usize,
+    text: String,
+}
+
+impl IncrementalDetokenizer {
+    /// A detokenizer of the tokens generated after a prompt of `prompt_len` tokens.
+    pub fn new(prompt_len: usize) -> Self {
+        Self {
+            prefix_offset: prompt_len.saturating_sub(INITIAL_DETOKENIZATION_OFFSET),
+            read_offset: prompt_len,
+            text: String::new(),
+        }
+    }
+
+    /// The offset of the first token `decode_next` needs.
+    pub fn prefix_offset(&self) -> usize {
+        self.prefix_offset
+    }
+
+    /// The text decoded so far.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Decode the new tokens of `token_ids`, the tokens of the sequence from `prefix_offset` on,
+    /// and return their text, which is also appended to `text`.
+    pub fn decode_next<'s>(
+        &mut self,
+        tokenizer: &dyn TokenizerWrapper<'s, String>,
+        token_ids: &[usize],
+        finished: bool,
+    ) -> Result<&str, APIError> {
+        let num_read = self.read_offset - self.prefix_offset;
+        if token_ids.len() <= num_read {
+            return Ok("");
+        }
+        let token_ids = token_ids.iter().map(|id| *id as u32).collect::<Vec<_>>();
+        let prefix_text = tokenizer.detokenize(&token_ids[..num_read])?;
+        let new_text = tokenizer.detokenize(&token_ids)?;
+        if new_text.len() <= prefix_text.len()
+            || (!finished && new_text.ends_with(char::REPLACEMENT_CHARACTER))
+        {
+            return Ok("");
+        }
+        // The prefix may decode differently in front of the new tokens, only its length counts.
+        let start = (0..=prefix_text.len())
+            .rev()
+            .find(|i| new_text.is_char_boundary(*i))
+            .unwrap_or(0);
+        let len = self.text.len();
+        self.text.push_str(&new_text[start..]);
+        self.prefix_offset = self.read_offset;
+        self.read_offset += token_ids.len() - num_read;
+        Ok(&self.text[len..])
+    }
 }
 
 #[derive(Clone)]
 pub struct PipelineConfig {
+    /// The name requests select the model by, see `ModulePipeline::name`.
+    pub name: String,
     pub max_model_len: usize,
+    pub generation_config: GenerationConfig,
+}
+
+/// Loads the engines of a reloaded model: the primary engine followed by one per data-parallel
+/// replica, with the pipeline config of the model.
+pub type LoadEngines<'s> =
+    dyn Fn(&ReloadRequest) -> Result<LoadedEngines<'s>, APIError> + Send + Sync + 's;
+
+/// The engines of a loaded model and its pipeline config.
+pub type LoadedEngines<'s> = (Vec<LLMEngine<'s>>, PipelineConfig);
+
+/// Hot reload of the served model, see `OpenAIServerData::reload`. Reloads are authorized by an
+/// admin key and may only load the checkpoints and adapters of an allowlist, so clients cannot
+/// make the server download arbitrary repositories.
+#[derive(Clone)]
+pub struct ModelReloader<'s> {
+    load: Arc<LoadEngines<'s>>,
+    in_progress: Arc<AtomicBool>,
+    admin_key: String,
+    /// The model ids and LoRA adapters a reload may load.
+    allowed: Vec<String>,
+}
+
+impl<'s> ModelReloader<'s> {
+    pub fn new(load: Arc<LoadEngines<'s>>, admin_key: String, allowed: Vec<String>) -> Self {
+        Self {
+            load,
+            in_progress: Arc::new(AtomicBool::new(false)),
+            admin_key,
+            allowed,
+        }
+    }
+
+    /// Whether `key`, the bearer token of a request, is the admin key. The comparison takes the
+    /// same time wherever the keys differ.
+    pub fn authorize(&self, key: Option<&str>) -> bool {
+        let Some(key) = key else {
+            return false;
+        };
+        key.len() == self.admin_key.len()
+            && key
+                .bytes()
+                .zip(self.admin_key.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    /// Reject a reload of a model or adapter which is not on the allowlist.
+    pub fn check_allowed(&self, request: &ReloadRequest) -> Result<(), APIError> {
+        for id in [&request.model, &request.lora].into_iter().flatten() {
+            if !self.allowed.contains(id) {
+                return Err(APIError::new(format!(
+                    "`{id}` is not allowed to be loaded, see `--reload-allowlist`."
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An additional engine serving the same model in data-parallel mode.
+#[derive(Clone)]
+pub struct Replica<'s> {
+    pub model: Arc<Mutex<LLMEngine<'s>>>,
+    pub abort_handle: AbortHandle,
+    pub metrics: MetricsHandle,
+    pub counters: Arc<ReplicaCounters>,
 }
 
 #[derive(Clone)]
 pub struct OpenAIServerData<'s> {
     pub model: Arc<Mutex<LLMEngine<'s>>>,
-    pub pipeline_config: PipelineConfig,
+    /// The pipeline config of the served model, replaced when the model is reloaded.
+    pub pipeline_config: Arc<RwLock<PipelineConfig>>,
     pub device: Device,
+    pub abort_handle: AbortHandle,
+    pub metrics: MetricsHandle,
+    /// The load counters of `model`, see `LLMEngine::get_replica_counters`.
+    pub counters: Arc<ReplicaCounters>,
+    /// Data-parallel replicas of `model`, empty when serving a single engine.
+    pub replicas: Vec<Replica<'s>>,
+    pub placement: Arc<Mutex<ReplicaPlacer>>,
+    /// Server-side conversation history, `None` if the conversations API is disabled.
+    pub conversations: Option<ConversationStore>,
+    /// Reloads the model for the admin endpoint, `None` if reloading is not supported.
+    pub reloader: Option<ModelReloader<'s>>,
+    /// Further models served by the same process, each with its own engines, schedulers and KV
+    /// caches. Requests are routed to a model by their `model` field, see `route`.
+    pub other_models: Vec<OpenAIServerData<'s>>,
+}
+
+impl<'s> OpenAIServerData<'s> {
+    /// This model followed by the other served models.
+    pub fn served_models(&self) -> impl Iterator<Item = &OpenAIServerData<'s>> {
+        std::iter::once(self).chain(&self.other_models)
+    }
+
+    /// The served model named `model_name`. Names are read from the pipeline configs, so this
+    /// does not wait for running generations.
+    pub fn route(&self, model_name: &str) -> Result<&OpenAIServerData<'s>, APIError> {
+        self.served_models()
+            .find(|model| model.pipeline_config.read().unwrap().name == model_name)
+            .ok_or_else(|| APIError::new(format!("Model name `{model_name}` is invalid.")))
+    }
+
+    /// The engine to run a request for `prompt` on, and the request counted in its load until
+    /// the returned guard is dropped. In data-parallel mode, this is the replica chosen by
+    /// `placement` from the load counters of the replicas. Replicas estimated to miss `ttft_slo`
+    /// are considered saturated. The prefix cache of a replica which is generating cannot be
+    /// inspected, so it counts as a miss.
+    pub fn select_engine(
+        &self,
+        prompt: &[usize],
+        cache_namespace: Option<&str>,
+        ttft_slo: Option<Duration>,
+    ) -> (Arc<Mutex<LLMEngine<'s>>>, AdmittedRequest) {
+        if self.replicas.is_empty() {
+            return (self.model.clone(), self.counters.admit(prompt.len()));
+        }
+        let engines = std::iter::once((&self.model, &self.counters))
+            .chain(
+                self.replicas
+                    .iter()
+                    .map(|replica| (&replica.model, &replica.counters)),
+            )
+            .collect::<Vec<_>>();
+        // Concurrent placements see the requests admitted before them.
+        let mut placement = self.placement.lock().unwrap();
+        let loads = engines
+            .iter()
+            .map(|(engine, counters)| {
+                let cached_prefix_blocks = engine.try_lock().map_or(0, |engine| {
+                    engine.get_num_cached_prefix_blocks(prompt, cache_namespace)
+                });
+                let mut load = counters.get_load(prompt.len(), cached_prefix_blocks);
+                if let (Some(slo), Some(wait)) = (ttft_slo, load.estimated_prefill_wait) {
+                    load.saturated |= wait > slo;
+                }
+                load
+            })
+            .collect::<Vec<_>>();
+        let (engine, counters) = engines[placement.place(&loads)];
+        (engine.clone(), counters.admit(prompt.len()))
+    }
+
+    /// Hand the parked session `session_id` of `cache_namespace` over to `engine`, the engine
+    /// locked for a follow-up request of the session, from the replica holding it, so that the
+    /// request reuses the KV cache of the session instead of recomputing it. Replicas which are
+    /// generating are skipped.
+    pub fn hand_over_session(
+        &self,
+        engine: &mut LLMEngine<'s>,
+        cache_namespace: Option<&str>,
+        session_id: &str,
+    ) {
+        let engines =
+            std::iter::once(&self.model).chain(self.replicas.iter().map(|replica| &replica.model));
+        for other in engines {
+            // The engine of the request is locked by the caller, so it is skipped as well.
+            let Ok(mut other) = other.try_lock() else {
+                continue;
+            };
+            match other.migrate_session(engine, cache_namespace, session_id) {
+                Ok(true) => return,
+                Ok(false) => {}
+                Err(err) => log_warning(&format!("Migrating session `{session_id}` failed: {err}")),
+            }
+        }
+    }
+
+    /// An engine which is not generating, falling back to `model`. Used for work which any
+    /// replica can do, such as tokenization.
+    pub fn idle_engine(&self) -> Arc<Mutex<LLMEngine<'s>>> {
+        self.replicas
+            .iter()
+            .map(|replica| &replica.model)
+            .find(|engine| engine.try_lock().is_ok())
+            .unwrap_or(&self.model)
+            .clone()
+    }
+
+    /// Abort `request_id` on whichever engine of the served models is running it.
+    pub fn abort(&self, request_id: String, reason: CancellationReason) {
+        for model in &self.other_models {
+            model.abort(request_id.clone(), reason);
+        }
+        for replica in &self.replicas {
+            replica.abort_handle.abort(request_id.clone(), reason);
+        }
+        self.abort_handle.abort(request_id, reason);
+    }
+
+    /// The metrics of `model` followed by those of the replicas, then those of the other served
+    /// models.
+    pub fn get_metrics(&self) -> Vec<MetricsSnapshot> {
+        std::iter::once(&self.metrics)
+            .chain(self.replicas.iter().map(|replica| &replica.metrics))
+            .map(MetricsHandle::snapshot)
+            .chain(
+                self.other_models
+                    .iter()
+                    .flat_map(OpenAIServerData::get_metrics),
+            )
+            .collect()
+    }
+
+    /// Abort every in-flight request on all engines of the served models.
+    pub fn abort_all(&self, reason: CancellationReason) {
+        for model in &self.other_models {
+            model.abort_all(reason);
+        }
+        for replica in &self.replicas {
+            replica.abort_handle.abort_all(reason);
+        }
+        self.abort_handle.abort_all(reason);
+    }
+
+    /// Load the model of `request` while the current model keeps serving, then switch every
+    /// engine to it at once. The switch waits for the requests running on the engines, and the
+    /// requests started after it run on the new model. Both models are in memory until the
+    /// switch, so the KV cache of the new model is sized from the memory left by the current
+    /// one. Blocks until the switch. The caller authorizes the request, see
+    /// `ModelReloader::authorize`.
+    pub fn reload(&self, request: &ReloadRequest) -> Result<ReloadResponse, APIError> {
+        let Some(reloader) = &self.reloader else {
+            return Err(APIError::new_str(
+                "Reloading the model is disabled, enable it with `--admin-key`.",
+            ));
+        };
+        reloader.check_allowed(request)?;
+        if reloader.in_progress.swap(true, Ordering::AcqRel) {
+            return Err(APIError::new_str("The model is already being reloaded."));
+        }
+        let result = self.reload_engines(reloader, request);
+        reloader.in_progress.store(false, Ordering::Release);
+        result
+    }
+
+    fn reload_engines(
+        &self,
+        reloader: &ModelReloader<'s>,
+        request: &ReloadRequest,
+    ) -> Result<ReloadResponse, APIError> {
+        let load_start = Instant::now();
+        let (engines, pipeline_config) = (reloader.load)(request)?;
+        if engines.len() != self.replicas.len() + 1 {
+            return Err(APIError::new(format!(
+                "Reloading loaded {} engines for {} running engines.",
+                engines.len(),
+                self.replicas.len() + 1
+            )));
+        }
+        if self
+            .other_models
+            .iter()
+            .any(|model| model.pipeline_config.read().unwrap().name == pipeline_config.name)
+        {
+            return Err(APIError::new(format!(
+                "Model `{}` is already served.",
+                pipeline_config.name
+            )));
+        }
+        let load_secs = load_start.elapsed().as_secs_f64();
+
+        // Every engine is locked before any is switched, so that no request runs on the new
+        // model while another one still runs on the current model.
+        let drain_start = Instant::now();
+        let mut guards = std::iter::once(&self.model)
+            .chain(self.replicas.iter().map(|replica| &replica.model))
+            .map(|engine| engine.lock().unwrap())
+            .collect::<Vec<_>>();
+        let drain_secs = drain_start.elapsed().as_secs_f64();
+        for (guard, mut engine) in guards.iter_mut().zip(engines) {
+            // The abort and metrics handles held by the server keep working.
+            engine.inherit_handles(guard);
+            **guard = engine;
+        }
+        *self.pipeline_config.write().unwrap() = pipeline_config;
+        let model = guards[0].get_pipeline().name().to_string();
+        drop(guards);
+        Ok(ReloadResponse {
+            object: "model.reload",
+            model,
+            load_secs,
+            drain_secs,
+        })
+    }
 }
 
 pub mod conversation;
+pub mod conversation_store;
 pub mod models;
+pub mod multimodal;
 pub mod openai_server;
 pub mod pipelines;
+pub mod playground;
 pub mod utils;
@@ -0,0 +1,447 @@
+/// Baichuan-2 7B and 13B LLMs, https://huggingface.co/baichuan-inc/Baichuan2-13B-Chat/blob/main/modeling_baichuan.py
+use candle_core::{DType, Device, Tensor, D};
+use candle_nn::{Embedding, Module, VarBuilder};
+use candle_transformers::models::with_tracing::Linear;
+use serde::Deserialize;
+use std::iter::zip;
+
+use crate::openai::responses::APIError;
+use crate::paged_attention::input_metadata::InputMetadata;
+use crate::paged_attention::PagedAttention;
+use crate::scheduler::kv_cache_manager::KVCacheManager;
+use crate::try_api;
+
+use super::quantization::{linear_no_bias as linear, QuantLinear, QuantizationConfig};
+use super::rotary_embedding::compute_cos_sin_cache;
+use super::{ConfigLike, LoadablePagedAttentionModel, PagedAttentionModel};
+
+pub const MAX_SEQ_LEN: usize = 4096;
+
+/// The hidden size of the 7B model, which uses RoPE. The 13B model uses ALiBi.
+const ROPE_HIDDEN_SIZE: usize = 4096;
+
+#[derive(Deserialize)]
+pub struct BaichuanConfig {
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub vocab_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub rms_norm_eps: f64,
+    #[serde(default = "default_rope")]
+    pub rope_theta: f32,
+    /// Given by the 7B config, the 13B config gives `model_max_length` instead.
+    #[serde(default)]
+    pub max_position_embeddings: Option<usize>,
+    #[serde(default)]
+    pub model_max_length: Option<usize>,
+    #[serde(default)]
+    pub quantization_config: Option<QuantizationConfig>,
+}
+
+fn default_rope() -> f32 {
+    10_000.0
+}
+
+impl BaichuanConfig {
+    pub fn into_config(self) -> Config {
+        Config {
+            hidden_size: self.hidden_size,
+            intermediate_size: self.intermediate_size,
+            vocab_size: self.vocab_size,
+            num_hidden_layers: self.num_hidden_layers,
+            num_attention_heads: self.num_attention_heads,
+            rms_norm_eps: self.rms_norm_eps,
+            rope_theta: self.rope_theta,
+            max_position_embeddings: self
+                .max_position_embeddings
+                .or(self.model_max_length)
+                .unwrap_or(MAX_SEQ_LEN),
+            use_alibi: self.hidden_size != ROPE_HIDDEN_SIZE,
+            quantization_config: self.quantization_config,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Config {
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub vocab_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub rms_norm_eps: f64,
+    pub rope_theta: f32,
+    pub max_position_embeddings: usize,
+    /// ALiBi attention biases instead of the rotary embedding (13B).
+    pub use_alibi: bool,
+    pub quantization_config: Option<QuantizationConfig>,
+}
+
+impl ConfigLike for Config {
+    fn get_num_kv_heads(&self) -> usize {
+        self.num_attention_heads
+    }
+    fn get_hidden_size(&self) -> usize {
+        self.hidden_size
+    }
+    fn get_num_hidden_layers(&self) -> usize {
+        self.num_hidden_layers
+    }
+    fn get_num_attention_heads(&self) -> usize {
+        self.num_attention_heads
+    }
+    fn get_vocab_size(&self) -> usize {
+        self.vocab_size
+    }
+    fn get_sliding_window(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// The ALiBi slope of each head, https://github.com/ofirpress/attention_with_linear_biases/blob/a35aaca144e0eb6b789dfcb46784c4b8e31b7983/fairseq/models/transformer.py#L742
+/// For a number of heads which is not a power of two, the slopes of the closest power of two
+/// are followed by every other slope of the next power of two.
+fn alibi_slopes(num_heads: usize) -> Vec<f64> {
+    let closest_power_of_2 = if num_heads.is_power_of_two() {
+        num_heads
+    } else {
+        num_heads.next_power_of_two() / 2
+    };
+    let slopes = |n: usize| -> Vec<f64> {
+        let base = 2f64.powf(-8. / n as f64);
+        (1..=n).map(|i| base.powi(i as i32)).collect()
+    };
+    let mut result = slopes(closest_power_of_2);
+    if closest_power_of_2 != num_heads {
+        result.extend(
+            slopes(2 * closest_power_of_2)
+                .into_iter()
+                .step_by(2)
+                .take(num_heads - closest_power_of_2),
+        );
+    }
+    result
+}
+
+fn embedding(cfg: &Config, vb: VarBuilder) -> candle_core::Result<Embedding> {
+    let embeddings = vb.get((cfg.vocab_size, cfg.hidden_size), "weight")?;
+    Ok(Embedding::new(embeddings, cfg.hidden_size))
+}
+
+/// The LM head of Baichuan-2 normalizes each row of its weight, which is done once at load.
+fn norm_head(cfg: &Config, vb: VarBuilder) -> candle_core::Result<QuantLinear> {
+    let weight = vb.get((cfg.vocab_size, cfg.hidden_size), "weight")?;
+    let dtype = weight.dtype();
+    let weight = weight.to_dtype(DType::F32)?;
+    let norm = weight
+        .sqr()?
+        .sum_keepdim(D::Minus1)?
+        .sqrt()?
+        .clamp(1e-12, f64::MAX)?;
+    let weight = weight.broadcast_div(&norm)?.to_dtype(dtype)?;
+    Ok(QuantLinear::Unquantized(Linear::from_weights(weight, None)))
+}
+
+struct RmsNorm {
+    inner: candle_nn::RmsNorm,
+    span: tracing::Span,
+}
+
+impl RmsNorm {
+    fn load(size: usize, eps: f64, vb: VarBuilder) -> candle_core::Result<Self> {
+        let span = tracing::span!(tracing::Level::TRACE, "rms-norm");
+        let inner = candle_nn::rms_norm(size, eps, vb)?;
+        Ok(Self { inner, span })
+    }
+
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let _enter = self.span.enter();
+        self.inner.forward(x)
+    }
+}
+
+struct Attention {
+    /// The fused query, key and value projection.
+    w_pack: QuantLinear,
+    o_proj: QuantLinear,
+    num_attention_heads: usize,
+    head_dim: usize,
+    attn: PagedAttention,
+    /// None with ALiBi.
+    cos_sin_cache: Option<Tensor>,
+}
+
+impl Attention {
+    fn forward(
+        &mut self,
+        x: &Tensor,
+        positions: &Tensor,
+        input_metadata: &mut InputMetadata,
+        cache: Option<(&Tensor, &Tensor)>,
+    ) -> Result<Tensor, APIError> {
+        let (b_sz, seq_len, hidden_size) = try_api!(x.dims3());
+        let qkv = try_api!(self.w_pack.forward(x));
+        let q = try_api!(qkv.narrow(D::Minus1, 0, hidden_size));
+        let k = try_api!(qkv.narrow(D::Minus1, hidden_size, hidden_size));
+        let v = try_api!(qkv.narrow(D::Minus1, 2 * hidden_size, hidden_size));
+
+        let dtype = q.dtype();
+        let device = q.device().clone();
+        let key_cache = cache.map(|(k, _)| k.clone());
+        let value_cache = cache.map(|(_, v)| v.clone());
+        let attn_output = match &self.cos_sin_cache {
+            Some(cos_sin_cache) => {
+                let shape = (b_sz, seq_len, self.num_attention_heads, self.head_dim);
+                let q = try_api!(try_api!(q.reshape(shape)).transpose(1, 2));
+                let k = try_api!(try_api!(k.reshape(shape)).transpose(1, 2));
+                let v = try_api!(try_api!(v.reshape(shape)).transpose(1, 2));
+                self.attn.forward_with_rotary_embedding(
+                    q,
+                    k,
+                    v,
+                    positions,
+                    cos_sin_cache,
+                    false,
+                    key_cache,
+                    value_cache,
+                    input_metadata,
+                    dtype,
+                    device,
+                )?
+            }
+            // The positions enter through the ALiBi biases of the attention.
+            None => self.attn.forward(
+                try_api!(q.contiguous()),
+                try_api!(k.contiguous()),
+                try_api!(v.contiguous()),
+                key_cache,
+                value_cache,
+                input_metadata,
+                dtype,
+                device,
+            )?,
+        };
+
+        self.o_proj.forward(&attn_output).map_err(APIError::from)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config, cos_sin_cache: Option<Tensor>) -> Result<Self, APIError> {
+        let head_dim = cfg.hidden_size / cfg.num_attention_heads;
+        let w_pack = try_api!(linear(
+            cfg.hidden_size,
+            3 * cfg.hidden_size,
+            vb.pp("W_pack")
+        ));
+        let o_proj = try_api!(linear(cfg.hidden_size, cfg.hidden_size, vb.pp("o_proj")));
+
+        Ok(Self {
+            w_pack,
+            o_proj,
+            num_attention_heads: cfg.num_attention_heads,
+            head_dim,
+            attn: PagedAttention::new(
+                cfg.num_attention_heads,
+                head_dim,
+                1. / ((head_dim as f32).sqrt()),
+                None,
+                None,
+                vb.device().clone(),
+                cfg.use_alibi.then(|| alibi_slopes(cfg.num_attention_heads)),
+            )?,
+            cos_sin_cache,
+        })
+    }
+}
+
+struct Mlp {
+    gate_proj: QuantLinear,
+    up_proj: QuantLinear,
+    down_proj: QuantLinear,
+    span: tracing::Span,
+}
+
+impl Mlp {
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let _enter = self.span.enter();
+        let x = (candle_nn::ops::silu(&self.gate_proj.forward(x)?)? * self.up_proj.forward(x)?)?;
+        self.down_proj.forward(&x)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config) -> candle_core::Result<Self> {
+        let span = tracing::span!(tracing::Level::TRACE, "mlp");
+        let (h_size, i_size) = (cfg.hidden_size, cfg.intermediate_size);
+        Ok(Self {
+            gate_proj: linear(h_size, i_size, vb.pp("gate_proj"))?,
+            up_proj: linear(h_size, i_size, vb.pp("up_proj"))?,
+            down_proj: linear(i_size, h_size, vb.pp("down_proj"))?,
+            span,
+        })
+    }
+}
+
+struct Block {
+    rms_1: RmsNorm,
+    attn: Attention,
+    rms_2: RmsNorm,
+    mlp: Mlp,
+    span: tracing::Span,
+}
+
+impl Block {
+    fn forward(
+        &mut self,
+        x: &Tensor,
+        positions: &Tensor,
+        cache: Option<(&Tensor, &Tensor)>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let _enter = self.span.enter();
+        let residual = x;
+        let x = try_api!(self.rms_1.forward(x));
+        let x = try_api!(
+            (try_api!(self.attn.forward(&x, positions, input_metadata, cache)) + residual)
+        );
+        let residual = &x;
+        let x =
+            try_api!((try_api!(self.mlp.forward(&try_api!(self.rms_2.forward(&x))))) + residual);
+        Ok(x)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config, cos_sin_cache: Option<Tensor>) -> Result<Self, APIError> {
+        let span = tracing::span!(tracing::Level::TRACE, "block");
+        let attn = Attention::load(vb.pp("self_attn"), cfg, cos_sin_cache)?;
+        let mlp = try_api!(Mlp::load(vb.pp("mlp"), cfg));
+        let rms_1 = try_api!(RmsNorm::load(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            vb.pp("input_layernorm")
+        ));
+        let rms_2 = try_api!(RmsNorm::load(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            vb.pp("post_attention_layernorm"),
+        ));
+        Ok(Self {
+            rms_1,
+            attn,
+            rms_2,
+            mlp,
+            span,
+        })
+    }
+}
+
+pub struct Baichuan {
+    wte: Embedding,
+    blocks: Vec<Block>,
+    ln_f: RmsNorm,
+    lm_head: QuantLinear,
+    cfg: Config,
+}
+
+impl Baichuan {
+    pub fn forward(
+        &mut self,
+        x: &Tensor,
+        positions: &Tensor,
+        kv_caches: Option<&KVCacheManager>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let (b_sz, seq_len) = try_api!(x.dims2());
+        let logits_indices = input_metadata.logits_indices(b_sz * seq_len, x.device())?;
+        let mut x = try_api!(self.wte.forward(x));
+        if let Some(kv_caches) = kv_caches {
+            for (layer, block) in zip(kv_caches.layers(), &mut self.blocks) {
+                x = block.forward(&x, positions, Some(layer.key_value()), input_metadata)?;
+            }
+        } else {
+            for block in &mut self.blocks {
+                x = block.forward(&x, positions, None, input_metadata)?;
+            }
+        }
+        let x = try_api!(self.ln_f.forward(&x));
+        let x = try_api!(x.flatten_to(1));
+        input_metadata.keep_hidden_states(&x);
+        let x = try_api!(x.index_select(&logits_indices, 0));
+        let logits = try_api!(self.lm_head.forward(&x));
+        logits.to_dtype(DType::F32).map_err(APIError::from)
+    }
+
+    pub fn load(
+        vb: VarBuilder,
+        cfg: &Config,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self, APIError> {
+        let wte = try_api!(embedding(cfg, vb.pp("model.embed_tokens")));
+        let lm_head = try_api!(norm_head(cfg, vb.pp("lm_head")));
+        let ln_f = try_api!(RmsNorm::load(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            vb.pp("model.norm")
+        ));
+        // The cos/sin cache is shared by all layers.
+        let cos_sin_cache = if cfg.use_alibi {
+            None
+        } else {
+            Some(compute_cos_sin_cache(
+                cfg.hidden_size / cfg.num_attention_heads,
+                cfg.rope_theta,
+                cfg.max_position_embeddings,
+                None,
+                dtype,
+                device,
+            )?)
+        };
+        let blocks = (0..cfg.num_hidden_layers)
+            .map(|i| {
+                Block::load(
+                    vb.pp(&format!("model.layers.{i}")),
+                    cfg,
+                    cos_sin_cache.clone(),
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            wte,
+            blocks,
+            ln_f,
+            lm_head,
+            cfg: cfg.clone(),
+        })
+    }
+
+    pub fn get_config(&self) -> &Config {
+        &self.cfg
+    }
+}
+
+impl PagedAttentionModel for Baichuan {
+    fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        positions: &Tensor,
+        kv_caches: Option<&KVCacheManager>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        Baichuan::forward(self, input_ids, positions, kv_caches, input_metadata)
+    }
+
+    fn get_config(&self) -> Box<dyn ConfigLike> {
+        Box::new(self.cfg.clone())
+    }
+}
+
+impl LoadablePagedAttentionModel for Baichuan {
+    fn load_from_config(
+        config: &[u8],
+        vb: VarBuilder,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self, APIError> {
+        let config: BaichuanConfig = try_api!(serde_json::from_slice(config));
+        Baichuan::load(vb, &config.into_config(), dtype, device)
+    }
+}
@@ -0,0 +1,443 @@
+/// BERT and XLM-RoBERTa encoders for embedding models such as BGE, E5 and GTE, and for
+/// cross-encoder rerankers with a sequence classification head,
+/// https://github.com/huggingface/transformers/blob/main/src/transformers/models/bert/modeling_bert.py
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, IndexOp, Module, Tensor};
+use candle_nn::{Embedding, LayerNorm, VarBuilder};
+use serde::Deserialize;
+
+use crate::openai::responses::APIError;
+use crate::paged_attention::input_metadata::InputMetadata;
+use crate::scheduler::kv_cache_manager::KVCacheManager;
+use crate::try_api;
+
+use super::quantization::{linear, QuantLinear, QuantizationConfig};
+use super::{ConfigLike, LoadablePagedAttentionModel, PagedAttentionModel};
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub enum BertActivation {
+    #[serde(rename = "gelu")]
+    Gelu,
+    #[serde(alias = "gelu_new", rename = "gelu_pytorch_tanh")]
+    GeluTanh,
+    #[serde(rename = "relu")]
+    Relu,
+}
+
+impl BertActivation {
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        match self {
+            Self::Gelu => x.gelu_erf(),
+            Self::GeluTanh => x.gelu(),
+            Self::Relu => x.relu(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BertConfig {
+    pub vocab_size: usize,
+    pub hidden_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub intermediate_size: usize,
+    #[serde(default = "default_hidden_act")]
+    pub hidden_act: BertActivation,
+    pub max_position_embeddings: usize,
+    #[serde(default = "default_type_vocab_size")]
+    pub type_vocab_size: usize,
+    #[serde(default = "default_layer_norm_eps")]
+    pub layer_norm_eps: f64,
+    #[serde(default)]
+    pub pad_token_id: usize,
+    /// "bert", or "roberta" / "xlm-roberta" whose positions start after the padding token.
+    #[serde(default = "default_model_type")]
+    pub model_type: String,
+    /// e.g. "BertForSequenceClassification" for a cross-encoder.
+    #[serde(default)]
+    pub architectures: Vec<String>,
+    /// The labels of a classification head, 2 if not given as in Transformers.
+    #[serde(default)]
+    pub id2label: Option<HashMap<String, String>>,
+    #[serde(default)]
+    pub quantization_config: Option<QuantizationConfig>,
+}
+
+fn default_hidden_act() -> BertActivation {
+    BertActivation::Gelu
+}
+
+fn default_type_vocab_size() -> usize {
+    2
+}
+
+fn default_layer_norm_eps() -> f64 {
+    1e-12
+}
+
+fn default_model_type() -> String {
+    "bert".to_string()
+}
+
+impl BertConfig {
+    pub fn into_config(self) -> Config {
+        let position_offset = match self.model_type.as_str() {
+            "roberta" | "xlm-roberta" | "camembert" => self.pad_token_id + 1,
+            _ => 0,
+        };
+        let num_labels = self
+            .architectures
+            .iter()
+            .any(|architecture| architecture.ends_with("ForSequenceClassification"))
+            .then(|| self.id2label.as_ref().map_or(2, HashMap::len));
+        Config {
+            vocab_size: self.vocab_size,
+            hidden_size: self.hidden_size,
+            num_hidden_layers: self.num_hidden_layers,
+            num_attention_heads: self.num_attention_heads,
+            intermediate_size: self.intermediate_size,
+            hidden_act: self.hidden_act,
+            max_position_embeddings: self.max_position_embeddings,
+            type_vocab_size: self.type_vocab_size,
+            layer_norm_eps: self.layer_norm_eps,
+            position_offset,
+            num_labels,
+            quantization_config: self.quantization_config,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Config {
+    pub vocab_size: usize,
+    pub hidden_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub intermediate_size: usize,
+    pub hidden_act: BertActivation,
+    pub max_position_embeddings: usize,
+    pub type_vocab_size: usize,
+    pub layer_norm_eps: f64,
+    /// The index of the position embedding of the first token.
+    pub position_offset: usize,
+    /// The outputs of the sequence classification head, None for embedding models.
+    pub num_labels: Option<usize>,
+    pub quantization_config: Option<QuantizationConfig>,
+}
+
+impl ConfigLike for Config {
+    fn get_num_kv_heads(&self) -> usize {
+        self.num_attention_heads
+    }
+    fn get_hidden_size(&self) -> usize {
+        self.hidden_size
+    }
+    fn get_num_hidden_layers(&self) -> usize {
+        self.num_hidden_layers
+    }
+    fn get_num_attention_heads(&self) -> usize {
+        self.num_attention_heads
+    }
+    fn get_vocab_size(&self) -> usize {
+        self.vocab_size
+    }
+    fn get_sliding_window(&self) -> Option<usize> {
+        None
+    }
+    /// The prompts are encoded in a single step, nothing is cached.
+    fn get_num_kv_cache_layers(&self) -> usize {
+        0
+    }
+    fn is_encoder_only(&self) -> bool {
+        true
+    }
+    fn get_num_labels(&self) -> Option<usize> {
+        self.num_labels
+    }
+}
+
+struct Embeddings {
+    word_embeddings: Embedding,
+    position_embeddings: Embedding,
+    token_type_embeddings: Embedding,
+    layer_norm: LayerNorm,
+}
+
+impl Embeddings {
+    /// token_type_ids: the segment of each token, all tokens are of type 0 if not given.
+    fn forward(
+        &self,
+        input_ids: &Tensor,
+        positions: &Tensor,
+        token_type_ids: Option<&Tensor>,
+    ) -> candle_core::Result<Tensor> {
+        let x = (self.word_embeddings.forward(input_ids)?
+            + self.position_embeddings.forward(positions)?)?;
+        let x = match token_type_ids {
+            Some(token_type_ids) => (x + self.token_type_embeddings.forward(token_type_ids)?)?,
+            None => x.broadcast_add(&self.token_type_embeddings.embeddings().get(0)?)?,
+        };
+        self.layer_norm.forward(&x)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config) -> candle_core::Result<Self> {
+        let size = cfg.hidden_size;
+        let word_embeddings = vb.get((cfg.vocab_size, size), "word_embeddings.weight")?;
+        // The rows before the offset are never used, the engine's positions start at 0.
+        let position_embeddings = vb
+            .get(
+                (cfg.max_position_embeddings, size),
+                "position_embeddings.weight",
+            )?
+            .narrow(
+                0,
+                cfg.position_offset,
+                cfg.max_position_embeddings - cfg.position_offset,
+            )?;
+        let token_type_embeddings =
+            vb.get((cfg.type_vocab_size, size), "token_type_embeddings.weight")?;
+        Ok(Self {
+            word_embeddings: Embedding::new(word_embeddings, size),
+            position_embeddings: Embedding::new(position_embeddings, size),
+            token_type_embeddings: Embedding::new(token_type_embeddings, size),
+            layer_norm: candle_nn::layer_norm(size, cfg.layer_norm_eps, vb.pp("LayerNorm"))?,
+        })
+    }
+}
+
+struct SelfAttention {
+    query: QuantLinear,
+    key: QuantLinear,
+    value: QuantLinear,
+    dense: QuantLinear,
+    layer_norm: LayerNorm,
+    num_heads: usize,
+    head_dim: usize,
+}
+
+impl SelfAttention {
+    /// x: shape = [num_prompts, max_prompt_len, hidden_size]
+    /// mask: the padding mask of the keys, shape = [num_prompts, 1, 1, max_prompt_len] (f32)
+    fn forward(&self, x: &Tensor, mask: &Tensor) -> candle_core::Result<Tensor> {
+        let (b_sz, seq_len, _) = x.dims3()?;
+        let split_heads = |x: Tensor| -> candle_core::Result<Tensor> {
+            x.reshape((b_sz, seq_len, self.num_heads, self.head_dim))?
+                .transpose(1, 2)?
+                .to_dtype(DType::F32)?
+                .contiguous()
+        };
+        let scale = (self.head_dim as f64).powf(-0.5);
+        let q = split_heads((self.query.forward(x)? * scale)?)?;
+        let k = split_heads(self.key.forward(x)?)?;
+        let v = split_heads(self.value.forward(x)?)?;
+        let scores = q.matmul(&k.t()?)?.broadcast_add(mask)?;
+        let attn = candle_nn::ops::softmax_last_dim(&scores)?;
+        let y = attn
+            .matmul(&v)?
+            .to_dtype(x.dtype())?
+            .transpose(1, 2)?
+            .reshape((b_sz, seq_len, ()))?;
+        self.layer_norm.forward(&(x + self.dense.forward(&y)?)?)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config) -> candle_core::Result<Self> {
+        let size = cfg.hidden_size;
+        let vb_s = vb.pp("self");
+        let vb_o = vb.pp("output");
+        Ok(Self {
+            query: linear(size, size, vb_s.pp("query"))?,
+            key: linear(size, size, vb_s.pp("key"))?,
+            value: linear(size, size, vb_s.pp("value"))?,
+            dense: linear(size, size, vb_o.pp("dense"))?,
+            layer_norm: candle_nn::layer_norm(size, cfg.layer_norm_eps, vb_o.pp("LayerNorm"))?,
+            num_heads: cfg.num_attention_heads,
+            head_dim: size / cfg.num_attention_heads,
+        })
+    }
+}
+
+/// A post-LN encoder layer: attention and feed-forward, each followed by the residual sum and
+/// the LayerNorm.
+struct EncoderLayer {
+    attention: SelfAttention,
+    intermediate: QuantLinear,
+    output: QuantLinear,
+    layer_norm: LayerNorm,
+    activation: BertActivation,
+    span: tracing::Span,
+}
+
+impl EncoderLayer {
+    fn forward(&self, x: &Tensor, mask: &Tensor) -> candle_core::Result<Tensor> {
+        let _enter = self.span.enter();
+        let x = self.attention.forward(x, mask)?;
+        let y = self
+            .output
+            .forward(&self.activation.forward(&self.intermediate.forward(&x)?)?)?;
+        self.layer_norm.forward(&(x + y)?)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config) -> candle_core::Result<Self> {
+        let span = tracing::span!(tracing::Level::TRACE, "bert-layer");
+        let (size, intermediate_size) = (cfg.hidden_size, cfg.intermediate_size);
+        Ok(Self {
+            attention: SelfAttention::load(vb.pp("attention"), cfg)?,
+            intermediate: linear(size, intermediate_size, vb.pp("intermediate.dense"))?,
+            output: linear(intermediate_size, size, vb.pp("output.dense"))?,
+            layer_norm: candle_nn::layer_norm(size, cfg.layer_norm_eps, vb.pp("output.LayerNorm"))?,
+            activation: cfg.hidden_act,
+            span,
+        })
+    }
+}
+
+/// The sequence classification head of a cross-encoder, applied to the hidden state of the
+/// first token: the pooler of BERT or the `classifier.dense` layer of RoBERTa, then the output
+/// projection.
+struct ClassificationHead {
+    dense: QuantLinear,
+    out_proj: QuantLinear,
+}
+
+impl ClassificationHead {
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        self.out_proj.forward(&self.dense.forward(x)?.tanh()?)
+    }
+
+    /// vb: the root of the checkpoint, which holds the head next to the encoder.
+    fn load(vb: VarBuilder, cfg: &Config, num_labels: usize) -> candle_core::Result<Self> {
+        let size = cfg.hidden_size;
+        if vb.contains_tensor("classifier.out_proj.weight") {
+            Ok(Self {
+                dense: linear(size, size, vb.pp("classifier.dense"))?,
+                out_proj: linear(size, num_labels, vb.pp("classifier.out_proj"))?,
+            })
+        } else {
+            Ok(Self {
+                dense: linear(size, size, vb.pp("bert.pooler.dense"))?,
+                out_proj: linear(size, num_labels, vb.pp("classifier"))?,
+            })
+        }
+    }
+}
+
+pub struct Bert {
+    embeddings: Embeddings,
+    layers: Vec<EncoderLayer>,
+    classifier: Option<ClassificationHead>,
+    cfg: Config,
+}
+
+impl Bert {
+    /// Encode the prompts of the step bidirectionally. Unlike the generative models, the hidden
+    /// states of every prompt token are returned instead of logits, packed as the prompt slice,
+    /// shape = [num_prompt_tokens, hidden_size] (f32). They are pooled by the pipeline.
+    /// Cross-encoders return the scores of their classification head instead, shape =
+    /// [num_prompts, num_labels] (f32).
+    pub fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        positions: &Tensor,
+        input_metadata: &InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let Some(prompt) = &input_metadata.prompt else {
+            return Err(APIError::new_str(
+                "Encoder-only models only take prompts, there is nothing to generate.",
+            ));
+        };
+        let device = input_ids.device();
+        let num_tokens = input_metadata.num_prompt_tokens();
+        let input_ids = try_api!(try_api!(input_ids.flatten_all()).narrow(0, 0, num_tokens));
+        let positions = try_api!(try_api!(positions.flatten_all()).narrow(0, 0, num_tokens));
+        // XLM-RoBERTa has a single token type, its tokenizer may still number the segments.
+        let token_type_ids = (self.cfg.type_vocab_size > 1)
+            .then_some(input_metadata.token_type_ids.as_ref())
+            .flatten();
+        let x = try_api!(self
+            .embeddings
+            .forward(&input_ids, &positions, token_type_ids));
+
+        // The prompts are padded to attend within their own tokens.
+        let (num_prompts, seq_len) = (prompt.prompt_lens.len(), prompt.max_prompt_len);
+        let x = try_api!(x.index_select(&prompt.pad_indices(device)?, 0));
+        let mut x = try_api!(x.reshape((num_prompts, seq_len, self.cfg.hidden_size)));
+        let mask = prompt
+            .prompt_lens
+            .iter()
+            .flat_map(|len| {
+                (0..seq_len).map(move |i| if i < *len { 0. } else { f32::NEG_INFINITY })
+            })
+            .collect::<Vec<_>>();
+        let mask = try_api!(Tensor::from_vec(mask, (num_prompts, 1, 1, seq_len), device));
+
+        for layer in &self.layers {
+            x = try_api!(layer.forward(&x, &mask));
+        }
+        if let Some(classifier) = &self.classifier {
+            let scores = try_api!(classifier.forward(&try_api!(x.i((.., 0)))));
+            return scores.to_dtype(DType::F32).map_err(APIError::from);
+        }
+        let x = try_api!(x.reshape((num_prompts * seq_len, self.cfg.hidden_size)));
+        let x = try_api!(x.index_select(&prompt.unpad_indices(device)?, 0));
+        x.to_dtype(DType::F32).map_err(APIError::from)
+    }
+
+    pub fn load(
+        vb: VarBuilder,
+        cfg: &Config,
+        _dtype: DType,
+        _device: &Device,
+    ) -> candle_core::Result<Self> {
+        let classifier = cfg
+            .num_labels
+            .map(|num_labels| ClassificationHead::load(vb.clone(), cfg, num_labels))
+            .transpose()?;
+        // Checkpoints of a model with a task head nest the encoder.
+        let vb = ["bert", "roberta"]
+            .into_iter()
+            .find(|prefix| {
+                vb.contains_tensor(&format!("{prefix}.embeddings.word_embeddings.weight"))
+            })
+            .map_or(vb.clone(), |prefix| vb.pp(prefix));
+        let embeddings = Embeddings::load(vb.pp("embeddings"), cfg)?;
+        let vb_l = vb.pp("encoder.layer");
+        let layers = (0..cfg.num_hidden_layers)
+            .map(|i| EncoderLayer::load(vb_l.pp(&i.to_string()), cfg))
+            .collect::<candle_core::Result<Vec<_>>>()?;
+        Ok(Self {
+            embeddings,
+            layers,
+            classifier,
+            cfg: cfg.clone(),
+        })
+    }
+}
+
+impl PagedAttentionModel for Bert {
+    fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        positions: &Tensor,
+        _kv_caches: Option<&KVCacheManager>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        Bert::forward(self, input_ids, positions, input_metadata)
+    }
+
+    fn get_config(&self) -> Box<dyn ConfigLike> {
+        Box::new(self.cfg.clone())
+    }
+}
+
+impl LoadablePagedAttentionModel for Bert {
+    fn load_from_config(
+        config: &[u8],
+        vb: VarBuilder,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self, APIError> {
+        let config: BertConfig = try_api!(serde_json::from_slice(config));
+        Bert::load(vb, &config.into_config(), dtype, device).map_err(APIError::from)
+    }
+}
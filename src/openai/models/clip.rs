@@ -0,0 +1,256 @@
+/// The vision tower of OpenAI CLIP, which encodes the images of vision-language models such as
+/// LLaVA, https://github.com/huggingface/transformers/blob/main/src/transformers/models/clip/modeling_clip.py
+use candle_core::{Module, Tensor};
+use candle_nn::{Conv2d, Conv2dConfig, LayerNorm, VarBuilder};
+use serde::Deserialize;
+
+use super::quantization::{linear, QuantLinear};
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClipActivation {
+    QuickGelu,
+    Gelu,
+}
+
+impl ClipActivation {
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        match self {
+            Self::QuickGelu => x * candle_nn::ops::sigmoid(&(x * 1.702)?)?,
+            Self::Gelu => x.gelu_erf(),
+        }
+    }
+}
+
+/// The `vision_config` of a CLIP model, with the defaults of Transformers.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ClipVisionConfig {
+    #[serde(default = "default_hidden_size")]
+    pub hidden_size: usize,
+    #[serde(default = "default_intermediate_size")]
+    pub intermediate_size: usize,
+    #[serde(default = "default_num_hidden_layers")]
+    pub num_hidden_layers: usize,
+    #[serde(default = "default_num_attention_heads")]
+    pub num_attention_heads: usize,
+    #[serde(default = "default_num_channels")]
+    pub num_channels: usize,
+    #[serde(default = "default_image_size")]
+    pub image_size: usize,
+    #[serde(default = "default_patch_size")]
+    pub patch_size: usize,
+    #[serde(default = "default_hidden_act")]
+    pub hidden_act: ClipActivation,
+    #[serde(default = "default_layer_norm_eps")]
+    pub layer_norm_eps: f64,
+}
+
+fn default_hidden_size() -> usize {
+    768
+}
+
+fn default_intermediate_size() -> usize {
+    3072
+}
+
+fn default_num_hidden_layers() -> usize {
+    12
+}
+
+fn default_num_attention_heads() -> usize {
+    12
+}
+
+fn default_num_channels() -> usize {
+    3
+}
+
+fn default_image_size() -> usize {
+    224
+}
+
+fn default_patch_size() -> usize {
+    32
+}
+
+fn default_hidden_act() -> ClipActivation {
+    ClipActivation::QuickGelu
+}
+
+fn default_layer_norm_eps() -> f64 {
+    1e-5
+}
+
+impl ClipVisionConfig {
+    /// Number of patch embeddings of an image, without the class embedding.
+    pub fn num_patches(&self) -> usize {
+        (self.image_size / self.patch_size).pow(2)
+    }
+}
+
+struct Attention {
+    q_proj: QuantLinear,
+    k_proj: QuantLinear,
+    v_proj: QuantLinear,
+    out_proj: QuantLinear,
+    num_heads: usize,
+    head_dim: usize,
+}
+
+impl Attention {
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let (b_sz, seq_len, _) = x.dims3()?;
+        let split_heads = |x: Tensor| -> candle_core::Result<Tensor> {
+            x.reshape((b_sz, seq_len, self.num_heads, self.head_dim))?
+                .transpose(1, 2)?
+                .contiguous()
+        };
+        let scale = (self.head_dim as f64).powf(-0.5);
+        let q = split_heads((self.q_proj.forward(x)? * scale)?)?;
+        let k = split_heads(self.k_proj.forward(x)?)?;
+        let v = split_heads(self.v_proj.forward(x)?)?;
+        let attn = candle_nn::ops::softmax_last_dim(&q.matmul(&k.t()?)?)?;
+        let y = attn
+            .matmul(&v)?
+            .transpose(1, 2)?
+            .reshape((b_sz, seq_len, ()))?;
+        self.out_proj.forward(&y)
+    }
+
+    fn load(vb: VarBuilder, cfg: &ClipVisionConfig) -> candle_core::Result<Self> {
+        let size = cfg.hidden_size;
+        Ok(Self {
+            q_proj: linear(size, size, vb.pp("q_proj"))?,
+            k_proj: linear(size, size, vb.pp("k_proj"))?,
+            v_proj: linear(size, size, vb.pp("v_proj"))?,
+            out_proj: linear(size, size, vb.pp("out_proj"))?,
+            num_heads: cfg.num_attention_heads,
+            head_dim: size / cfg.num_attention_heads,
+        })
+    }
+}
+
+struct Mlp {
+    fc1: QuantLinear,
+    fc2: QuantLinear,
+    activation: ClipActivation,
+}
+
+impl Mlp {
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        self.fc2
+            .forward(&self.activation.forward(&self.fc1.forward(x)?)?)
+    }
+
+    fn load(vb: VarBuilder, cfg: &ClipVisionConfig) -> candle_core::Result<Self> {
+        Ok(Self {
+            fc1: linear(cfg.hidden_size, cfg.intermediate_size, vb.pp("fc1"))?,
+            fc2: linear(cfg.intermediate_size, cfg.hidden_size, vb.pp("fc2"))?,
+            activation: cfg.hidden_act,
+        })
+    }
+}
+
+struct EncoderLayer {
+    layer_norm1: LayerNorm,
+    self_attn: Attention,
+    layer_norm2: LayerNorm,
+    mlp: Mlp,
+    span: tracing::Span,
+}
+
+impl EncoderLayer {
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let _enter = self.span.enter();
+        let x = (x + self.self_attn.forward(&self.layer_norm1.forward(x)?)?)?;
+        &x + self.mlp.forward(&self.layer_norm2.forward(&x)?)?
+    }
+
+    fn load(vb: VarBuilder, cfg: &ClipVisionConfig) -> candle_core::Result<Self> {
+        let span = tracing::span!(tracing::Level::TRACE, "clip-layer");
+        let (size, eps) = (cfg.hidden_size, cfg.layer_norm_eps);
+        Ok(Self {
+            layer_norm1: candle_nn::layer_norm(size, eps, vb.pp("layer_norm1"))?,
+            self_attn: Attention::load(vb.pp("self_attn"), cfg)?,
+            layer_norm2: candle_nn::layer_norm(size, eps, vb.pp("layer_norm2"))?,
+            mlp: Mlp::load(vb.pp("mlp"), cfg)?,
+            span,
+        })
+    }
+}
+
+/// The CLIP vision transformer up to a given layer. Vision-language models take the hidden
+/// states of an inner layer, so the final layers and the pooling head are not loaded.
+pub struct ClipVisionTower {
+    patch_embedding: Conv2d,
+    class_embedding: Tensor,
+    position_embedding: Tensor,
+    pre_layrnorm: LayerNorm,
+    layers: Vec<EncoderLayer>,
+}
+
+impl ClipVisionTower {
+    /// pixel_values: shape = [num_images, num_channels, image_size, image_size]
+    ///
+    /// Returns the hidden states after the loaded layers, shape = [num_images, num_patches + 1,
+    /// hidden_size] with the class embedding first.
+    pub fn forward(&self, pixel_values: &Tensor) -> candle_core::Result<Tensor> {
+        let pixel_values = pixel_values.to_dtype(self.class_embedding.dtype())?;
+        // [num_images, hidden_size, grid, grid] -> [num_images, num_patches, hidden_size]
+        let patches = self
+            .patch_embedding
+            .forward(&pixel_values)?
+            .flatten_from(2)?
+            .transpose(1, 2)?;
+        let (num_images, _, hidden_size) = patches.dims3()?;
+        let class_embedding = self
+            .class_embedding
+            .reshape((1, 1, hidden_size))?
+            .broadcast_as((num_images, 1, hidden_size))?;
+        let x = Tensor::cat(&[&class_embedding, &patches], 1)?
+            .broadcast_add(&self.position_embedding)?;
+        let mut x = self.pre_layrnorm.forward(&x)?;
+        for layer in &self.layers {
+            x = layer.forward(&x)?;
+        }
+        Ok(x)
+    }
+
+    /// Load the embeddings and the first `num_layers` layers of the tower in `vb` (the
+    /// `vision_model` of a CLIP checkpoint).
+    pub fn load(
+        vb: VarBuilder,
+        cfg: &ClipVisionConfig,
+        num_layers: usize,
+    ) -> candle_core::Result<Self> {
+        let vb_e = vb.pp("embeddings");
+        let conv_cfg = Conv2dConfig {
+            stride: cfg.patch_size,
+            ..Default::default()
+        };
+        let patch_embedding = candle_nn::conv2d_no_bias(
+            cfg.num_channels,
+            cfg.hidden_size,
+            cfg.patch_size,
+            conv_cfg,
+            vb_e.pp("patch_embedding"),
+        )?;
+        let class_embedding = vb_e.get(cfg.hidden_size, "class_embedding")?;
+        let position_embedding = vb_e.get(
+            (cfg.num_patches() + 1, cfg.hidden_size),
+            "position_embedding.weight",
+        )?;
+        let pre_layrnorm =
+            candle_nn::layer_norm(cfg.hidden_size, cfg.layer_norm_eps, vb.pp("pre_layrnorm"))?;
+        let layers = (0..num_layers)
+            .map(|i| EncoderLayer::load(vb.pp(&format!("encoder.layers.{i}")), cfg))
+            .collect::<candle_core::Result<Vec<_>>>()?;
+        Ok(Self {
+            patch_embedding,
+            class_embedding,
+            position_embedding,
+            pre_layrnorm,
+            layers,
+        })
+    }
+}
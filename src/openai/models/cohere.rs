@@ -0,0 +1,435 @@
+/// Cohere Command-R and Command-R+ LLMs, https://github.com/huggingface/transformers/blob/main/src/transformers/models/cohere/modeling_cohere.py
+use candle_core::{DType, Device, Tensor, D};
+use candle_nn::{Embedding, Module, VarBuilder};
+use candle_transformers::models::with_tracing::Linear;
+use serde::Deserialize;
+use std::iter::zip;
+
+use crate::openai::responses::APIError;
+use crate::paged_attention::input_metadata::InputMetadata;
+use crate::paged_attention::PagedAttention;
+use crate::scheduler::kv_cache_manager::KVCacheManager;
+use crate::try_api;
+
+use super::quantization::{linear_no_bias as linear, QuantLinear, QuantizationConfig};
+use super::rotary_embedding::compute_cos_sin_cache;
+use super::{ConfigLike, LoadablePagedAttentionModel, PagedAttentionModel};
+
+pub const MAX_SEQ_LEN: usize = 8192;
+
+#[derive(Deserialize)]
+pub struct CohereConfig {
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub vocab_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: Option<usize>,
+    pub layer_norm_eps: f64,
+    #[serde(default = "default_rope")]
+    pub rope_theta: f32,
+    #[serde(default)]
+    pub max_position_embeddings: Option<usize>,
+    #[serde(default = "default_logit_scale")]
+    pub logit_scale: f32,
+    #[serde(default)]
+    pub use_qk_norm: bool,
+    #[serde(default)]
+    pub quantization_config: Option<QuantizationConfig>,
+}
+
+fn default_rope() -> f32 {
+    10_000.0
+}
+
+fn default_logit_scale() -> f32 {
+    0.0625
+}
+
+impl CohereConfig {
+    pub fn into_config(self) -> Config {
+        Config {
+            hidden_size: self.hidden_size,
+            intermediate_size: self.intermediate_size,
+            vocab_size: self.vocab_size,
+            num_hidden_layers: self.num_hidden_layers,
+            num_attention_heads: self.num_attention_heads,
+            num_key_value_heads: self.num_key_value_heads.unwrap_or(self.num_attention_heads),
+            layer_norm_eps: self.layer_norm_eps,
+            rope_theta: self.rope_theta,
+            max_position_embeddings: self.max_position_embeddings.unwrap_or(MAX_SEQ_LEN),
+            logit_scale: self.logit_scale,
+            use_qk_norm: self.use_qk_norm,
+            quantization_config: self.quantization_config,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Config {
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub vocab_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: usize,
+    pub layer_norm_eps: f64,
+    pub rope_theta: f32,
+    pub max_position_embeddings: usize,
+    /// The factor the logits are scaled by.
+    pub logit_scale: f32,
+    /// Command-R+ normalizes the queries and keys of each head.
+    pub use_qk_norm: bool,
+    pub quantization_config: Option<QuantizationConfig>,
+}
+
+impl ConfigLike for Config {
+    fn get_num_kv_heads(&self) -> usize {
+        self.num_key_value_heads
+    }
+    fn get_hidden_size(&self) -> usize {
+        self.hidden_size
+    }
+    fn get_num_hidden_layers(&self) -> usize {
+        self.num_hidden_layers
+    }
+    fn get_num_attention_heads(&self) -> usize {
+        self.num_attention_heads
+    }
+    fn get_vocab_size(&self) -> usize {
+        self.vocab_size
+    }
+    fn get_sliding_window(&self) -> Option<usize> {
+        None
+    }
+}
+
+fn embedding(cfg: &Config, vb: VarBuilder) -> candle_core::Result<Embedding> {
+    let embeddings = vb.get((cfg.vocab_size, cfg.hidden_size), "weight")?;
+    Ok(Embedding::new(embeddings, cfg.hidden_size))
+}
+
+/// LayerNorm without bias over the last dimension, computed in f32. The weight covers the
+/// trailing dimensions of the input, [hidden_size] or [num_heads, head_dim] for the QK norms.
+struct LayerNorm {
+    weight: Tensor,
+    eps: f64,
+    span: tracing::Span,
+}
+
+impl LayerNorm {
+    fn load<S: Into<candle_core::Shape>>(
+        shape: S,
+        eps: f64,
+        vb: VarBuilder,
+    ) -> candle_core::Result<Self> {
+        let span = tracing::span!(tracing::Level::TRACE, "layer-norm");
+        let weight = vb.get(shape, "weight")?.to_dtype(DType::F32)?;
+        Ok(Self { weight, eps, span })
+    }
+
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let _enter = self.span.enter();
+        let dtype = x.dtype();
+        let x = x.to_dtype(DType::F32)?;
+        let x = x.broadcast_sub(&x.mean_keepdim(D::Minus1)?)?;
+        let norm = (x.sqr()?.mean_keepdim(D::Minus1)? + self.eps)?.sqrt()?;
+        x.broadcast_div(&norm)?
+            .broadcast_mul(&self.weight)?
+            .to_dtype(dtype)
+    }
+}
+
+struct Attention {
+    q_proj: QuantLinear,
+    k_proj: QuantLinear,
+    v_proj: QuantLinear,
+    o_proj: QuantLinear,
+    /// The per-head norms of the queries and keys of Command-R+.
+    qk_norm: Option<(LayerNorm, LayerNorm)>,
+    num_attention_heads: usize,
+    num_key_value_heads: usize,
+    head_dim: usize,
+    attn: PagedAttention,
+    cos_sin_cache: Tensor,
+}
+
+impl Attention {
+    fn forward(
+        &mut self,
+        x: &Tensor,
+        positions: &Tensor,
+        input_metadata: &mut InputMetadata,
+        cache: Option<(&Tensor, &Tensor)>,
+    ) -> Result<Tensor, APIError> {
+        let (b_sz, seq_len, _) = try_api!(x.dims3());
+        let q = try_api!(self.q_proj.forward(x));
+        let k = try_api!(self.k_proj.forward(x));
+        let v = try_api!(self.v_proj.forward(x));
+
+        let q = try_api!(q.reshape((b_sz, seq_len, self.num_attention_heads, self.head_dim)));
+        let k = try_api!(k.reshape((b_sz, seq_len, self.num_key_value_heads, self.head_dim)));
+        let (q, k) = match &self.qk_norm {
+            Some((q_norm, k_norm)) => (try_api!(q_norm.forward(&q)), try_api!(k_norm.forward(&k))),
+            None => (q, k),
+        };
+        let q = try_api!(q.transpose(1, 2));
+        let k = try_api!(k.transpose(1, 2));
+        let v =
+            try_api!(
+                try_api!(v.reshape((b_sz, seq_len, self.num_key_value_heads, self.head_dim)))
+                    .transpose(1, 2)
+            );
+
+        let dtype = q.dtype();
+        let device = q.device().clone();
+        let attn_output = self.attn.forward_with_rotary_embedding(
+            q,
+            k,
+            v,
+            positions,
+            &self.cos_sin_cache,
+            false,
+            cache.map(|(k, _)| k.clone()),
+            cache.map(|(_, v)| v.clone()),
+            input_metadata,
+            dtype,
+            device,
+        )?;
+
+        self.o_proj.forward(&attn_output).map_err(APIError::from)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config, cos_sin_cache: Tensor) -> Result<Self, APIError> {
+        let head_dim = cfg.hidden_size / cfg.num_attention_heads;
+        let size_q = head_dim * cfg.num_attention_heads;
+        let size_kv = head_dim * cfg.num_key_value_heads;
+        let q_proj = try_api!(linear(cfg.hidden_size, size_q, vb.pp("q_proj")));
+        let k_proj = try_api!(linear(cfg.hidden_size, size_kv, vb.pp("k_proj")));
+        let v_proj = try_api!(linear(cfg.hidden_size, size_kv, vb.pp("v_proj")));
+        let o_proj = try_api!(linear(size_q, cfg.hidden_size, vb.pp("o_proj")));
+        let qk_norm = if cfg.use_qk_norm {
+            Some((
+                try_api!(LayerNorm::load(
+                    (cfg.num_attention_heads, head_dim),
+                    cfg.layer_norm_eps,
+                    vb.pp("q_norm")
+                )),
+                try_api!(LayerNorm::load(
+                    (cfg.num_key_value_heads, head_dim),
+                    cfg.layer_norm_eps,
+                    vb.pp("k_norm")
+                )),
+            ))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            q_proj,
+            k_proj,
+            v_proj,
+            o_proj,
+            qk_norm,
+            num_attention_heads: cfg.num_attention_heads,
+            num_key_value_heads: cfg.num_key_value_heads,
+            head_dim,
+            attn: PagedAttention::new(
+                cfg.num_attention_heads,
+                head_dim,
+                1. / ((head_dim as f32).sqrt()),
+                Some(cfg.num_key_value_heads),
+                None,
+                vb.device().clone(),
+                None,
+            )?,
+            cos_sin_cache,
+        })
+    }
+}
+
+struct Mlp {
+    gate_proj: QuantLinear,
+    up_proj: QuantLinear,
+    down_proj: QuantLinear,
+    span: tracing::Span,
+}
+
+impl Mlp {
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let _enter = self.span.enter();
+        let x = (candle_nn::ops::silu(&self.gate_proj.forward(x)?)? * self.up_proj.forward(x)?)?;
+        self.down_proj.forward(&x)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config) -> candle_core::Result<Self> {
+        let span = tracing::span!(tracing::Level::TRACE, "mlp");
+        let (h_size, i_size) = (cfg.hidden_size, cfg.intermediate_size);
+        Ok(Self {
+            gate_proj: linear(h_size, i_size, vb.pp("gate_proj"))?,
+            up_proj: linear(h_size, i_size, vb.pp("up_proj"))?,
+            down_proj: linear(i_size, h_size, vb.pp("down_proj"))?,
+            span,
+        })
+    }
+}
+
+/// A parallel block: the attention and the MLP both read the same normalized input and their
+/// outputs are added to the residual together.
+struct Block {
+    ln: LayerNorm,
+    attn: Attention,
+    mlp: Mlp,
+    span: tracing::Span,
+}
+
+impl Block {
+    fn forward(
+        &mut self,
+        x: &Tensor,
+        positions: &Tensor,
+        cache: Option<(&Tensor, &Tensor)>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let _enter = self.span.enter();
+        let residual = x;
+        let x = try_api!(self.ln.forward(x));
+        let attn_output = self.attn.forward(&x, positions, input_metadata, cache)?;
+        let mlp_output = try_api!(self.mlp.forward(&x));
+        let x = try_api!(try_api!(attn_output + mlp_output) + residual);
+        Ok(x)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config, cos_sin_cache: Tensor) -> Result<Self, APIError> {
+        let span = tracing::span!(tracing::Level::TRACE, "block");
+        let attn = Attention::load(vb.pp("self_attn"), cfg, cos_sin_cache)?;
+        let mlp = try_api!(Mlp::load(vb.pp("mlp"), cfg));
+        let ln = try_api!(LayerNorm::load(
+            cfg.hidden_size,
+            cfg.layer_norm_eps,
+            vb.pp("input_layernorm")
+        ));
+        Ok(Self {
+            ln,
+            attn,
+            mlp,
+            span,
+        })
+    }
+}
+
+pub struct Cohere {
+    wte: Embedding,
+    blocks: Vec<Block>,
+    ln_f: LayerNorm,
+    lm_head: QuantLinear,
+    cfg: Config,
+}
+
+impl Cohere {
+    pub fn forward(
+        &mut self,
+        x: &Tensor,
+        positions: &Tensor,
+        kv_caches: Option<&KVCacheManager>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let (b_sz, seq_len) = try_api!(x.dims2());
+        let logits_indices = input_metadata.logits_indices(b_sz * seq_len, x.device())?;
+        let mut x = try_api!(self.wte.forward(x));
+        if let Some(kv_caches) = kv_caches {
+            for (layer, block) in zip(kv_caches.layers(), &mut self.blocks) {
+                x = block.forward(&x, positions, Some(layer.key_value()), input_metadata)?;
+            }
+        } else {
+            for block in &mut self.blocks {
+                x = block.forward(&x, positions, None, input_metadata)?;
+            }
+        }
+        let x = try_api!(self.ln_f.forward(&x));
+        let x = try_api!(x.flatten_to(1));
+        input_metadata.keep_hidden_states(&x);
+        let x = try_api!(x.index_select(&logits_indices, 0));
+        // Scaling the hidden states is equivalent to scaling the logits, but touches
+        // hidden_size instead of vocab_size (256k) values per row.
+        let x = try_api!(x * f64::from(self.cfg.logit_scale));
+        let logits = try_api!(self.lm_head.forward(&x));
+        logits.to_dtype(DType::F32).map_err(APIError::from)
+    }
+
+    pub fn load(
+        vb: VarBuilder,
+        cfg: &Config,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self, APIError> {
+        let wte = try_api!(embedding(cfg, vb.pp("model.embed_tokens")));
+        // The LM head is tied to the embedding, which is the largest tensor of the checkpoint
+        // with its 256k vocabulary, so both share the same storage instead of a copy.
+        let lm_head =
+            QuantLinear::Unquantized(Linear::from_weights(wte.embeddings().clone(), None));
+        let ln_f = try_api!(LayerNorm::load(
+            cfg.hidden_size,
+            cfg.layer_norm_eps,
+            vb.pp("model.norm")
+        ));
+        // The cos/sin cache is shared by all layers.
+        let cos_sin_cache = compute_cos_sin_cache(
+            cfg.hidden_size / cfg.num_attention_heads,
+            cfg.rope_theta,
+            cfg.max_position_embeddings,
+            None,
+            dtype,
+            device,
+        )?;
+        let blocks = (0..cfg.num_hidden_layers)
+            .map(|i| {
+                Block::load(
+                    vb.pp(&format!("model.layers.{i}")),
+                    cfg,
+                    cos_sin_cache.clone(),
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            wte,
+            blocks,
+            ln_f,
+            lm_head,
+            cfg: cfg.clone(),
+        })
+    }
+
+    pub fn get_config(&self) -> &Config {
+        &self.cfg
+    }
+}
+
+impl PagedAttentionModel for Cohere {
+    fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        positions: &Tensor,
+        kv_caches: Option<&KVCacheManager>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        Cohere::forward(self, input_ids, positions, kv_caches, input_metadata)
+    }
+
+    fn get_config(&self) -> Box<dyn ConfigLike> {
+        Box::new(self.cfg.clone())
+    }
+}
+
+impl LoadablePagedAttentionModel for Cohere {
+    fn load_from_config(
+        config: &[u8],
+        vb: VarBuilder,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self, APIError> {
+        let config: CohereConfig = try_api!(serde_json::from_slice(config));
+        Cohere::load(vb, &config.into_config(), dtype, device)
+    }
+}
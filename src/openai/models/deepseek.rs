@@ -0,0 +1,884 @@
+/// DeepSeek-V2/V3 mixture-of-experts LLM with multi-head latent attention, https://github.com/vllm-project/vllm/blob/main/vllm/model_executor/models/deepseek_v2.py
+use candle_core::{DType, Device, Tensor};
+use candle_nn::{Embedding, Module, VarBuilder};
+use candle_transformers::models::with_tracing::Linear;
+use serde::Deserialize;
+use std::iter::zip;
+
+use crate::backend::rotary_embedding;
+use crate::openai::responses::APIError;
+use crate::paged_attention::input_metadata::InputMetadata;
+use crate::paged_attention::mla::{MlaDims, MultiHeadLatentAttention};
+use crate::scheduler::kv_cache_manager::KVCacheManager;
+use crate::try_api;
+
+use super::mixtral::{fused_moe_forward, use_fused_moe, Routing};
+use super::quantization::{linear_no_bias as linear, QuantLinear, QuantizationConfig};
+use super::rotary_embedding::{compute_cos_sin_cache, yarn_get_mscale, RopeScaling};
+use super::{ConfigLike, LoadablePagedAttentionModel, PagedAttentionModel};
+
+pub const MAX_SEQ_LEN: usize = 4096;
+
+#[derive(Deserialize)]
+pub struct DeepSeekConfig {
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub vocab_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub rms_norm_eps: f64,
+    #[serde(default = "default_rope")]
+    pub rope_theta: f32,
+    #[serde(default)]
+    pub rope_scaling: Option<RopeScaling>,
+    #[serde(default)]
+    pub max_position_embeddings: Option<usize>,
+    #[serde(default)]
+    pub q_lora_rank: Option<usize>,
+    pub kv_lora_rank: usize,
+    pub qk_nope_head_dim: usize,
+    pub qk_rope_head_dim: usize,
+    pub v_head_dim: usize,
+    #[serde(default)]
+    pub n_routed_experts: Option<usize>,
+    #[serde(default)]
+    pub n_shared_experts: Option<usize>,
+    #[serde(default)]
+    pub num_experts_per_tok: Option<usize>,
+    #[serde(default)]
+    pub moe_intermediate_size: Option<usize>,
+    #[serde(default = "default_one")]
+    pub moe_layer_freq: usize,
+    #[serde(default)]
+    pub first_k_dense_replace: usize,
+    #[serde(default = "default_routed_scaling_factor")]
+    pub routed_scaling_factor: f32,
+    #[serde(default)]
+    pub topk_method: TopkMethod,
+    #[serde(default)]
+    pub n_group: Option<usize>,
+    #[serde(default)]
+    pub topk_group: Option<usize>,
+    #[serde(default)]
+    pub norm_topk_prob: bool,
+    #[serde(default)]
+    pub scoring_func: ScoringFunc,
+    /// Not part of the checkpoint config, set with `--expert-parallel-size`.
+    #[serde(default = "default_one")]
+    pub expert_parallel_size: usize,
+    #[serde(default)]
+    pub quantization_config: Option<QuantizationConfig>,
+}
+
+fn default_rope() -> f32 {
+    10_000.0
+}
+
+fn default_one() -> usize {
+    1
+}
+
+fn default_routed_scaling_factor() -> f32 {
+    1.0
+}
+
+/// How the experts of a token are selected from the router scores.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TopkMethod {
+    /// The `num_experts_per_tok` highest scores.
+    #[default]
+    Greedy,
+    /// The highest scores within the `topk_group` groups with the highest maximum (DeepSeek-V2).
+    GroupLimitedGreedy,
+    /// The highest scores plus the `e_score_correction_bias` of the gate, within the
+    /// `topk_group` groups with the highest sum of their top two biased scores (DeepSeek-V3).
+    NoauxTc,
+}
+
+/// The function turning the router logits into the scores.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScoringFunc {
+    #[default]
+    Softmax,
+    Sigmoid,
+}
+
+impl DeepSeekConfig {
+    pub fn into_config(self) -> Config {
+        let max_position_embeddings = self.max_position_embeddings.unwrap_or(MAX_SEQ_LEN);
+        let moe = match (
+            self.n_routed_experts,
+            self.num_experts_per_tok,
+            self.moe_intermediate_size,
+        ) {
+            (Some(n_routed_experts), Some(num_experts_per_tok), Some(moe_intermediate_size)) => {
+                Some(MoeConfig {
+                    n_routed_experts,
+                    n_shared_experts: self.n_shared_experts.unwrap_or(0),
+                    num_experts_per_tok,
+                    moe_intermediate_size,
+                    moe_layer_freq: self.moe_layer_freq.max(1),
+                    first_k_dense_replace: self.first_k_dense_replace,
+                    routed_scaling_factor: self.routed_scaling_factor,
+                    topk_method: self.topk_method,
+                    n_group: self.n_group.unwrap_or(1).max(1),
+                    topk_group: self.topk_group.unwrap_or(1).max(1),
+                    norm_topk_prob: self.norm_topk_prob,
+                    scoring_func: self.scoring_func,
+                    expert_parallel_size: self.expert_parallel_size.max(1),
+                })
+            }
+            _ => None,
+        };
+        Config {
+            hidden_size: self.hidden_size,
+            intermediate_size: self.intermediate_size,
+            vocab_size: self.vocab_size,
+            num_hidden_layers: self.num_hidden_layers,
+            num_attention_heads: self.num_attention_heads,
+            rms_norm_eps: self.rms_norm_eps,
+            rope_theta: self.rope_theta,
+            rope_scaling: self.rope_scaling,
+            max_position_embeddings,
+            q_lora_rank: self.q_lora_rank,
+            mla_dims: MlaDims {
+                kv_lora_rank: self.kv_lora_rank,
+                qk_nope_head_dim: self.qk_nope_head_dim,
+                qk_rope_head_dim: self.qk_rope_head_dim,
+                v_head_dim: self.v_head_dim,
+            },
+            moe,
+            quantization_config: self.quantization_config,
+        }
+    }
+}
+
+/// The mixture-of-experts layers of a config with routed experts.
+#[derive(Clone)]
+pub struct MoeConfig {
+    pub n_routed_experts: usize,
+    /// The number of shared experts every token runs through, fused into one MLP.
+    pub n_shared_experts: usize,
+    pub num_experts_per_tok: usize,
+    /// The hidden size of each expert.
+    pub moe_intermediate_size: usize,
+    pub moe_layer_freq: usize,
+    /// The number of leading layers with a dense MLP.
+    pub first_k_dense_replace: usize,
+    pub routed_scaling_factor: f32,
+    pub topk_method: TopkMethod,
+    pub n_group: usize,
+    pub topk_group: usize,
+    pub norm_topk_prob: bool,
+    pub scoring_func: ScoringFunc,
+    /// The number of CUDA devices the routed experts are split over, see `expert_devices`.
+    pub expert_parallel_size: usize,
+}
+
+#[derive(Clone)]
+pub struct Config {
+    pub hidden_size: usize,
+    /// The hidden size of the dense MLPs.
+    pub intermediate_size: usize,
+    pub vocab_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub rms_norm_eps: f64,
+    pub rope_theta: f32,
+    pub rope_scaling: Option<RopeScaling>,
+    pub max_position_embeddings: usize,
+    /// The rank of the low-rank query projection, None for a full query projection.
+    pub q_lora_rank: Option<usize>,
+    pub mla_dims: MlaDims,
+    pub moe: Option<MoeConfig>,
+    pub quantization_config: Option<QuantizationConfig>,
+}
+
+impl Config {
+    /// Whether layer `layer_idx` has a mixture of experts instead of a dense MLP.
+    fn is_moe_layer(&self, layer_idx: usize) -> bool {
+        self.moe.as_ref().is_some_and(|moe| {
+            layer_idx >= moe.first_k_dense_replace && layer_idx % moe.moe_layer_freq == 0
+        })
+    }
+}
+
+impl ConfigLike for Config {
+    fn get_num_kv_heads(&self) -> usize {
+        self.num_attention_heads
+    }
+    fn get_hidden_size(&self) -> usize {
+        self.hidden_size
+    }
+    fn get_num_hidden_layers(&self) -> usize {
+        self.num_hidden_layers
+    }
+    fn get_num_attention_heads(&self) -> usize {
+        self.num_attention_heads
+    }
+    fn get_vocab_size(&self) -> usize {
+        self.vocab_size
+    }
+    fn get_sliding_window(&self) -> Option<usize> {
+        None
+    }
+    fn get_head_size(&self) -> usize {
+        self.mla_dims.qk_nope_head_dim + self.mla_dims.qk_rope_head_dim
+    }
+    fn get_mla_dims(&self) -> Option<MlaDims> {
+        Some(self.mla_dims)
+    }
+}
+
+fn embedding(cfg: &Config, vb: VarBuilder) -> candle_core::Result<Embedding> {
+    let embeddings = vb.get((cfg.vocab_size, cfg.hidden_size), "weight")?;
+    Ok(Embedding::new(embeddings, cfg.hidden_size))
+}
+
+struct RmsNorm {
+    inner: candle_nn::RmsNorm,
+    span: tracing::Span,
+}
+
+impl RmsNorm {
+    fn load(size: usize, eps: f64, vb: VarBuilder) -> candle_core::Result<Self> {
+        let span = tracing::span!(tracing::Level::TRACE, "rms-norm");
+        let inner = candle_nn::rms_norm(size, eps, vb)?;
+        Ok(Self { inner, span })
+    }
+
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let _enter = self.span.enter();
+        self.inner.forward(x)
+    }
+}
+
+/// The query projection, either full or through a normalized low-rank latent.
+enum QueryProj {
+    Full(QuantLinear),
+    LowRank {
+        q_a_proj: QuantLinear,
+        q_a_layernorm: RmsNorm,
+        q_b_proj: QuantLinear,
+    },
+}
+
+impl QueryProj {
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        match self {
+            Self::Full(q_proj) => q_proj.forward(x),
+            Self::LowRank {
+                q_a_proj,
+                q_a_layernorm,
+                q_b_proj,
+            } => q_b_proj.forward(&q_a_layernorm.forward(&q_a_proj.forward(x)?)?),
+        }
+    }
+}
+
+struct Attention {
+    q_proj: QueryProj,
+    kv_a_proj_with_mqa: QuantLinear,
+    kv_a_layernorm: RmsNorm,
+    o_proj: QuantLinear,
+    num_attention_heads: usize,
+    dims: MlaDims,
+    attn: MultiHeadLatentAttention,
+    cos_sin_cache: Tensor,
+}
+
+impl Attention {
+    fn forward(
+        &mut self,
+        x: &Tensor,
+        positions: &Tensor,
+        input_metadata: &mut InputMetadata,
+        cache: Option<&Tensor>,
+    ) -> Result<Tensor, APIError> {
+        let (b_sz, seq_len, hidden_size) = try_api!(x.dims3());
+        let num_tokens = b_sz * seq_len;
+        let x = try_api!(x.reshape((num_tokens, hidden_size)));
+        let MlaDims {
+            kv_lora_rank,
+            qk_nope_head_dim,
+            qk_rope_head_dim,
+            ..
+        } = self.dims;
+
+        let q = try_api!(self.q_proj.forward(&x));
+        let q = try_api!(q.reshape((
+            num_tokens,
+            self.num_attention_heads,
+            qk_nope_head_dim + qk_rope_head_dim
+        )));
+        let q_nope = try_api!(q.narrow(2, 0, qk_nope_head_dim));
+        // The kernels take the heads of a token as one row.
+        let mut q_pe =
+            try_api!(try_api!(
+                try_api!(q.narrow(2, qk_nope_head_dim, qk_rope_head_dim)).contiguous()
+            )
+            .reshape((num_tokens, self.num_attention_heads * qk_rope_head_dim)));
+
+        let kv = try_api!(self.kv_a_proj_with_mqa.forward(&x));
+        let kv_latent = try_api!(try_api!(kv.narrow(1, 0, kv_lora_rank)).contiguous());
+        let kv_latent = try_api!(self.kv_a_layernorm.forward(&kv_latent));
+        let mut k_pe =
+            try_api!(try_api!(kv.narrow(1, kv_lora_rank, qk_rope_head_dim)).contiguous());
+
+        try_api!(unsafe {
+            rotary_embedding(
+                try_api!(positions.flatten_all()),
+                &mut q_pe,
+                &mut k_pe,
+                qk_rope_head_dim,
+                self.cos_sin_cache.clone(),
+                false,
+            )
+        });
+        let q_pe = try_api!(q_pe.reshape((num_tokens, self.num_attention_heads, qk_rope_head_dim)));
+
+        let attn_output = self.attn.forward(
+            &q_nope,
+            &q_pe,
+            &kv_latent,
+            &k_pe,
+            cache.cloned(),
+            input_metadata,
+        )?;
+        let attn_output = try_api!(self.o_proj.forward(&attn_output));
+        attn_output
+            .reshape((b_sz, seq_len, hidden_size))
+            .map_err(APIError::from)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config, cos_sin_cache: Tensor) -> Result<Self, APIError> {
+        let dims = cfg.mla_dims;
+        let num_heads = cfg.num_attention_heads;
+        let q_head_dim = dims.qk_nope_head_dim + dims.qk_rope_head_dim;
+        let q_proj = match cfg.q_lora_rank {
+            Some(q_lora_rank) => QueryProj::LowRank {
+                q_a_proj: try_api!(linear(cfg.hidden_size, q_lora_rank, vb.pp("q_a_proj"))),
+                q_a_layernorm: try_api!(RmsNorm::load(
+                    q_lora_rank,
+                    cfg.rms_norm_eps,
+                    vb.pp("q_a_layernorm")
+                )),
+                q_b_proj: try_api!(linear(
+                    q_lora_rank,
+                    num_heads * q_head_dim,
+                    vb.pp("q_b_proj")
+                )),
+            },
+            None => QueryProj::Full(try_api!(linear(
+                cfg.hidden_size,
+                num_heads * q_head_dim,
+                vb.pp("q_proj")
+            ))),
+        };
+        let kv_a_proj_with_mqa = try_api!(linear(
+            cfg.hidden_size,
+            dims.cache_entry_size(),
+            vb.pp("kv_a_proj_with_mqa")
+        ));
+        let kv_a_layernorm = try_api!(RmsNorm::load(
+            dims.kv_lora_rank,
+            cfg.rms_norm_eps,
+            vb.pp("kv_a_layernorm")
+        ));
+        let kv_b_proj = try_api!(vb.pp("kv_b_proj").get(
+            (
+                num_heads * (dims.qk_nope_head_dim + dims.v_head_dim),
+                dims.kv_lora_rank
+            ),
+            "weight"
+        ));
+        let o_proj = try_api!(linear(
+            num_heads * dims.v_head_dim,
+            cfg.hidden_size,
+            vb.pp("o_proj")
+        ));
+
+        // YaRN scales the attention of the extended context by `mscale_all_dim`.
+        let mut scale = 1. / (q_head_dim as f32).sqrt();
+        if let Some(RopeScaling::Yarn {
+            factor,
+            mscale_all_dim: Some(mscale_all_dim),
+            ..
+        }) = cfg.rope_scaling
+        {
+            scale *= yarn_get_mscale(factor, mscale_all_dim).powi(2);
+        }
+
+        Ok(Self {
+            q_proj,
+            kv_a_proj_with_mqa,
+            kv_a_layernorm,
+            o_proj,
+            num_attention_heads: num_heads,
+            dims,
+            attn: MultiHeadLatentAttention::new(num_heads, dims, scale, &kv_b_proj)?,
+            cos_sin_cache,
+        })
+    }
+}
+
+/// A SwiGLU MLP: the dense MLP of the leading layers, an expert or the fused shared experts.
+struct Mlp {
+    gate_proj: QuantLinear,
+    up_proj: QuantLinear,
+    down_proj: QuantLinear,
+    span: tracing::Span,
+}
+
+impl Mlp {
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let _enter = self.span.enter();
+        let x = (candle_nn::ops::silu(&self.gate_proj.forward(x)?)? * self.up_proj.forward(x)?)?;
+        self.down_proj.forward(&x)
+    }
+
+    fn load(
+        hidden_size: usize,
+        intermediate_size: usize,
+        vb: VarBuilder,
+    ) -> candle_core::Result<Self> {
+        let span = tracing::span!(tracing::Level::TRACE, "mlp");
+        Ok(Self {
+            gate_proj: linear(hidden_size, intermediate_size, vb.pp("gate_proj"))?,
+            up_proj: linear(hidden_size, intermediate_size, vb.pp("up_proj"))?,
+            down_proj: linear(intermediate_size, hidden_size, vb.pp("down_proj"))?,
+            span,
+        })
+    }
+
+    /// Load the MLP onto `device` instead of the device of `vb`, one weight at a time. Only
+    /// unquantized weights can be moved.
+    fn load_on(
+        hidden_size: usize,
+        intermediate_size: usize,
+        vb: VarBuilder,
+        device: &Device,
+    ) -> candle_core::Result<Self> {
+        if vb.contains_tensor("gate_proj.weight_scale") {
+            candle_core::bail!("Expert-parallel loading does not support quantized experts.");
+        }
+        let proj = |in_dim: usize, out_dim: usize, name: &str| -> candle_core::Result<_> {
+            let weight = vb
+                .pp(name)
+                .get((out_dim, in_dim), "weight")?
+                .to_device(device)?;
+            Ok(QuantLinear::Unquantized(Linear::from_weights(weight, None)))
+        };
+        Ok(Self {
+            gate_proj: proj(hidden_size, intermediate_size, "gate_proj")?,
+            up_proj: proj(hidden_size, intermediate_size, "up_proj")?,
+            down_proj: proj(intermediate_size, hidden_size, "down_proj")?,
+            span: tracing::span!(tracing::Level::TRACE, "mlp"),
+        })
+    }
+}
+
+enum Experts {
+    /// Each expert runs as separate GEMMs over the tokens routed to it, on the device it was
+    /// loaded onto.
+    Separate(Vec<(Mlp, Device)>),
+    /// The weights of all experts, run with the grouped GEMM kernel, see `fused_moe_forward`.
+    Fused { w13: Tensor, w2: Tensor },
+}
+
+/// The fine-grained MoE block: every token runs through its `num_experts_per_tok` highest-scoring
+/// routed experts, weighted by their (scaled) router scores, and through the shared experts.
+struct MoeBlock {
+    gate: QuantLinear,
+    /// The per-expert bias added to the scores for the selection only, see `TopkMethod::NoauxTc`.
+    e_score_correction_bias: Option<Vec<f32>>,
+    experts: Experts,
+    shared_experts: Option<Mlp>,
+    cfg: MoeConfig,
+    span: tracing::Span,
+}
+
+impl MoeBlock {
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let _enter = self.span.enter();
+        let (b_sz, seq_len, hidden_size) = x.dims3()?;
+        let x = x.reshape((b_sz * seq_len, hidden_size))?;
+        let routing = self.route(&x)?;
+        let mut out = match &self.experts {
+            Experts::Separate(experts) => Self::forward_separate(&x, experts, &routing)?,
+            Experts::Fused { w13, w2 } => fused_moe_forward(&x, w13, w2, &routing)?,
+        };
+        if let Some(shared_experts) = &self.shared_experts {
+            out = (out + shared_experts.forward(&x)?)?;
+        }
+        out.reshape((b_sz, seq_len, hidden_size))
+    }
+
+    /// Routing of the tokens of `x` as selected by `topk_method`. The router scores are small
+    /// ([num_tokens, n_routed_experts]), so the selection is done on the host.
+    fn route(&self, x: &Tensor) -> candle_core::Result<Routing> {
+        let cfg = &self.cfg;
+        let router_logits = self.gate.forward(x)?.to_dtype(DType::F32)?;
+        let scores = match cfg.scoring_func {
+            ScoringFunc::Softmax => candle_nn::ops::softmax_last_dim(&router_logits)?,
+            ScoringFunc::Sigmoid => candle_nn::ops::sigmoid(&router_logits)?,
+        }
+        .to_vec2::<f32>()?;
+        let num_experts = cfg.n_routed_experts;
+        let group_size = num_experts / cfg.n_group;
+        let mut routing = Routing {
+            tokens: vec![Vec::new(); num_experts],
+            weights: vec![Vec::new(); num_experts],
+        };
+        for (token, scores) in scores.iter().enumerate() {
+            let choice_scores: Vec<f32> = match &self.e_score_correction_bias {
+                Some(bias) => zip(scores, bias).map(|(s, b)| s + b).collect(),
+                None => scores.clone(),
+            };
+            let mut candidates = (0..num_experts).collect::<Vec<_>>();
+            if cfg.topk_method != TopkMethod::Greedy && cfg.n_group > 1 {
+                let group_scores = choice_scores
+                    .chunks(group_size)
+                    .map(|group| {
+                        let mut group = group.to_vec();
+                        group.sort_by(|a, b| b.total_cmp(a));
+                        match cfg.topk_method {
+                            TopkMethod::NoauxTc => group.iter().take(2).sum::<f32>(),
+                            _ => group[0],
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                let mut groups = (0..cfg.n_group).collect::<Vec<_>>();
+                groups.sort_by(|&a, &b| group_scores[b].total_cmp(&group_scores[a]));
+                let groups = &groups[..cfg.topk_group.min(cfg.n_group)];
+                candidates.retain(|expert| groups.contains(&(expert / group_size)));
+            }
+            candidates.sort_by(|&a, &b| choice_scores[b].total_cmp(&choice_scores[a]));
+            let experts = &candidates[..cfg.num_experts_per_tok.min(candidates.len())];
+            // The weights are the scores without the correction bias.
+            let norm = if cfg.norm_topk_prob && experts.len() > 1 {
+                experts.iter().map(|&e| scores[e]).sum::<f32>() + 1e-20
+            } else {
+                1.
+            };
+            for &expert in experts {
+                routing.tokens[expert].push(token as u32);
+                routing.weights[expert].push(scores[expert] / norm * cfg.routed_scaling_factor);
+            }
+        }
+        Ok(routing)
+    }
+
+    fn forward_separate(
+        x: &Tensor,
+        experts: &[(Mlp, Device)],
+        routing: &Routing,
+    ) -> candle_core::Result<Tensor> {
+        let mut out = x.zeros_like()?;
+        for ((expert, device), (tokens, weights)) in
+            zip(experts, zip(&routing.tokens, &routing.weights))
+        {
+            if tokens.is_empty() {
+                continue;
+            }
+            let tokens = Tensor::new(tokens.as_slice(), x.device())?;
+            let weights = Tensor::new(weights.as_slice(), x.device())?
+                .reshape(((), 1))?
+                .to_dtype(x.dtype())?;
+            let expert_in = x.index_select(&tokens, 0)?.to_device(device)?;
+            let expert_out = expert.forward(&expert_in)?.to_device(x.device())?;
+            out = out.index_add(&tokens, &expert_out.broadcast_mul(&weights)?, 0)?;
+        }
+        Ok(out)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config, moe: &MoeConfig) -> Result<Self, APIError> {
+        let span = tracing::span!(tracing::Level::TRACE, "moe");
+        let (hidden, intermediate) = (cfg.hidden_size, moe.moe_intermediate_size);
+        let gate_vb = vb.pp("gate");
+        let gate = try_api!(linear(hidden, moe.n_routed_experts, gate_vb.clone()));
+        let e_score_correction_bias = match moe.topk_method {
+            TopkMethod::NoauxTc => Some(try_api!(try_api!(try_api!(
+                gate_vb.get(moe.n_routed_experts, "e_score_correction_bias")
+            )
+            .to_dtype(DType::F32))
+            .to_vec1::<f32>())),
+            _ => None,
+        };
+
+        let experts_vb = vb.pp("experts");
+        let experts = if moe.expert_parallel_size == 1 && use_fused_moe(&experts_vb, "0.gate_proj")
+        {
+            let mut w13 = Vec::with_capacity(moe.n_routed_experts);
+            let mut w2 = Vec::with_capacity(moe.n_routed_experts);
+            for i in 0..moe.n_routed_experts {
+                let vb = experts_vb.pp(&i.to_string());
+                let w1 = try_api!(vb.pp("gate_proj").get((intermediate, hidden), "weight"));
+                let w3 = try_api!(vb.pp("up_proj").get((intermediate, hidden), "weight"));
+                w13.push(try_api!(Tensor::cat(&[w1, w3], 0)));
+                w2.push(try_api!(vb
+                    .pp("down_proj")
+                    .get((hidden, intermediate), "weight")));
+            }
+            Experts::Fused {
+                w13: try_api!(Tensor::stack(&w13, 0)),
+                w2: try_api!(Tensor::stack(&w2, 0)),
+            }
+        } else {
+            let devices = expert_devices(vb.device(), moe)?;
+            let experts = devices
+                .into_iter()
+                .enumerate()
+                .map(|(i, device)| {
+                    let vb = experts_vb.pp(&i.to_string());
+                    let expert = if device.same_device(vb.device()) {
+                        Mlp::load(hidden, intermediate, vb)
+                    } else {
+                        Mlp::load_on(hidden, intermediate, vb, &device)
+                    };
+                    Ok((try_api!(expert), device))
+                })
+                .collect::<Result<Vec<_>, APIError>>()?;
+            Experts::Separate(experts)
+        };
+
+        let shared_experts = match moe.n_shared_experts {
+            0 => None,
+            n => Some(try_api!(Mlp::load(
+                hidden,
+                intermediate * n,
+                vb.pp("shared_experts")
+            ))),
+        };
+        Ok(Self {
+            gate,
+            e_score_correction_bias,
+            experts,
+            shared_experts,
+            cfg: moe.clone(),
+            span,
+        })
+    }
+}
+
+/// The device of each routed expert. With `expert_parallel_size` N > 1, the experts are split
+/// into N contiguous ranges on the CUDA devices following the device of the model, so that the
+/// routed experts of the large variants do not need to fit onto one device.
+fn expert_devices(device: &Device, moe: &MoeConfig) -> Result<Vec<Device>, APIError> {
+    let num_experts = moe.n_routed_experts;
+    if moe.expert_parallel_size == 1 {
+        return Ok(vec![device.clone(); num_experts]);
+    }
+    let Device::Cuda(dev) = device else {
+        return Err(APIError::new_str(
+            "Expert-parallel loading requires CUDA devices.",
+        ));
+    };
+    let devices = (0..moe.expert_parallel_size)
+        .map(|rank| {
+            if rank == 0 {
+                Ok(device.clone())
+            } else {
+                Device::new_cuda(dev.ordinal() + rank).map_err(APIError::from)
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((0..num_experts)
+        .map(|expert| devices[expert * devices.len() / num_experts].clone())
+        .collect())
+}
+
+enum FeedForward {
+    Dense(Mlp),
+    Moe(MoeBlock),
+}
+
+impl FeedForward {
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        match self {
+            Self::Dense(mlp) => mlp.forward(x),
+            Self::Moe(moe) => moe.forward(x),
+        }
+    }
+}
+
+struct Block {
+    rms_1: RmsNorm,
+    attn: Attention,
+    rms_2: RmsNorm,
+    mlp: FeedForward,
+    span: tracing::Span,
+}
+
+impl Block {
+    fn forward(
+        &mut self,
+        x: &Tensor,
+        positions: &Tensor,
+        cache: Option<&Tensor>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let _enter = self.span.enter();
+        let residual = x;
+        let x = try_api!(self.rms_1.forward(x));
+        let x = try_api!(
+            (try_api!(self.attn.forward(&x, positions, input_metadata, cache)) + residual)
+        );
+        let residual = &x;
+        let x =
+            try_api!((try_api!(self.mlp.forward(&try_api!(self.rms_2.forward(&x))))) + residual);
+        Ok(x)
+    }
+
+    fn load(
+        vb: VarBuilder,
+        cfg: &Config,
+        layer_idx: usize,
+        cos_sin_cache: Tensor,
+    ) -> Result<Self, APIError> {
+        let span = tracing::span!(tracing::Level::TRACE, "block");
+        let attn = Attention::load(vb.pp("self_attn"), cfg, cos_sin_cache)?;
+        let mlp = match &cfg.moe {
+            Some(moe) if cfg.is_moe_layer(layer_idx) => {
+                FeedForward::Moe(MoeBlock::load(vb.pp("mlp"), cfg, moe)?)
+            }
+            _ => FeedForward::Dense(try_api!(Mlp::load(
+                cfg.hidden_size,
+                cfg.intermediate_size,
+                vb.pp("mlp")
+            ))),
+        };
+        let rms_1 = try_api!(RmsNorm::load(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            vb.pp("input_layernorm")
+        ));
+        let rms_2 = try_api!(RmsNorm::load(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            vb.pp("post_attention_layernorm"),
+        ));
+        Ok(Self {
+            rms_1,
+            attn,
+            rms_2,
+            mlp,
+            span,
+        })
+    }
+}
+
+pub struct DeepSeek {
+    wte: Embedding,
+    blocks: Vec<Block>,
+    ln_f: RmsNorm,
+    lm_head: QuantLinear,
+    cfg: Config,
+}
+
+impl DeepSeek {
+    pub fn forward(
+        &mut self,
+        x: &Tensor,
+        positions: &Tensor,
+        kv_caches: Option<&KVCacheManager>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let (b_sz, seq_len) = try_api!(x.dims2());
+        let logits_indices = input_metadata.logits_indices(b_sz * seq_len, x.device())?;
+        let mut x = try_api!(self.wte.forward(x));
+        if let Some(kv_caches) = kv_caches {
+            for (layer, block) in zip(kv_caches.layers(), &mut self.blocks) {
+                x = block.forward(&x, positions, layer.latent(), input_metadata)?;
+            }
+        } else {
+            for block in &mut self.blocks {
+                x = block.forward(&x, positions, None, input_metadata)?;
+            }
+        }
+        let x = try_api!(self.ln_f.forward(&x));
+        let x = try_api!(x.flatten_to(1));
+        input_metadata.keep_hidden_states(&x);
+        let x = try_api!(x.index_select(&logits_indices, 0));
+        let logits = try_api!(self.lm_head.forward(&x));
+        logits.to_dtype(DType::F32).map_err(APIError::from)
+    }
+
+    /// Load the model from `vb`, which may span the sharded safetensors of the checkpoint.
+    pub fn load(
+        vb: VarBuilder,
+        cfg: &Config,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self, APIError> {
+        let wte = try_api!(embedding(cfg, vb.pp("model.embed_tokens")));
+        let lm_head = try_api!(linear(cfg.hidden_size, cfg.vocab_size, vb.pp("lm_head")));
+        let ln_f = try_api!(RmsNorm::load(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            vb.pp("model.norm")
+        ));
+        // The rotary embedding only covers the rope part of the heads. The cos/sin cache is
+        // shared by all layers.
+        let cos_sin_cache = compute_cos_sin_cache(
+            cfg.mla_dims.qk_rope_head_dim,
+            cfg.rope_theta,
+            cfg.max_position_embeddings,
+            cfg.rope_scaling.as_ref(),
+            dtype,
+            device,
+        )?;
+        let blocks = (0..cfg.num_hidden_layers)
+            .map(|i| {
+                Block::load(
+                    vb.pp(&format!("model.layers.{i}")),
+                    cfg,
+                    i,
+                    cos_sin_cache.clone(),
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            wte,
+            blocks,
+            ln_f,
+            lm_head,
+            cfg: cfg.clone(),
+        })
+    }
+
+    pub fn get_config(&self) -> &Config {
+        &self.cfg
+    }
+}
+
+impl PagedAttentionModel for DeepSeek {
+    fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        positions: &Tensor,
+        kv_caches: Option<&KVCacheManager>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        DeepSeek::forward(self, input_ids, positions, kv_caches, input_metadata)
+    }
+
+    fn get_config(&self) -> Box<dyn ConfigLike> {
+        Box::new(self.cfg.clone())
+    }
+}
+
+impl LoadablePagedAttentionModel for DeepSeek {
+    fn load_from_config(
+        config: &[u8],
+        vb: VarBuilder,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self, APIError> {
+        let config: DeepSeekConfig = try_api!(serde_json::from_slice(config));
+        DeepSeek::load(vb, &config.into_config(), dtype, device)
+    }
+}
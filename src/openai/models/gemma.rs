@@ -0,0 +1,474 @@
+/// Gemma and Gemma-2 LLMs, https://github.com/huggingface/candle/blob/main/candle-transformers/src/models/gemma2.rs
+use candle_core::{DType, Device, Tensor};
+use candle_nn::{Embedding, Module, VarBuilder};
+use candle_transformers::models::with_tracing::Linear;
+use serde::Deserialize;
+use std::iter::zip;
+
+use crate::openai::responses::APIError;
+use crate::paged_attention::input_metadata::InputMetadata;
+use crate::paged_attention::PagedAttention;
+use crate::scheduler::kv_cache_manager::KVCacheManager;
+use crate::try_api;
+
+use super::quantization::{linear_no_bias as linear, QuantLinear, QuantizationConfig};
+use super::rotary_embedding::compute_cos_sin_cache;
+use super::{soft_cap_logits, ConfigLike, LoadablePagedAttentionModel, PagedAttentionModel};
+
+pub const MAX_SEQ_LEN: usize = 8192;
+
+#[derive(Deserialize)]
+pub struct GemmaConfig {
+    /// "gemma" or "gemma2".
+    pub model_type: String,
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub vocab_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: Option<usize>,
+    pub head_dim: usize,
+    pub rms_norm_eps: f64,
+    #[serde(default = "default_rope")]
+    pub rope_theta: f32,
+    #[serde(default)]
+    pub max_position_embeddings: Option<usize>,
+    /// The sliding window of every other layer of Gemma-2.
+    #[serde(default)]
+    pub sliding_window: Option<usize>,
+    /// Gemma-2 scales the queries by `query_pre_attn_scalar^-0.5` instead of `head_dim^-0.5`.
+    #[serde(default)]
+    pub query_pre_attn_scalar: Option<usize>,
+    #[serde(default)]
+    pub attn_logit_softcapping: Option<f32>,
+    #[serde(default)]
+    pub final_logit_softcapping: Option<f32>,
+    #[serde(default)]
+    pub quantization_config: Option<QuantizationConfig>,
+}
+
+fn default_rope() -> f32 {
+    10_000.0
+}
+
+impl GemmaConfig {
+    pub fn into_config(self) -> Config {
+        Config {
+            is_gemma2: self.model_type == "gemma2",
+            hidden_size: self.hidden_size,
+            intermediate_size: self.intermediate_size,
+            vocab_size: self.vocab_size,
+            num_hidden_layers: self.num_hidden_layers,
+            num_attention_heads: self.num_attention_heads,
+            num_key_value_heads: self.num_key_value_heads.unwrap_or(self.num_attention_heads),
+            head_dim: self.head_dim,
+            rms_norm_eps: self.rms_norm_eps,
+            rope_theta: self.rope_theta,
+            max_position_embeddings: self.max_position_embeddings.unwrap_or(MAX_SEQ_LEN),
+            sliding_window: self.sliding_window,
+            query_pre_attn_scalar: self.query_pre_attn_scalar.unwrap_or(self.head_dim),
+            attn_logit_softcapping: self.attn_logit_softcapping,
+            final_logit_softcapping: self.final_logit_softcapping,
+            quantization_config: self.quantization_config,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Config {
+    /// Gemma-2 adds norms after the attention and the MLP and alternates sliding window and
+    /// global attention layers.
+    pub is_gemma2: bool,
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub vocab_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: usize,
+    pub head_dim: usize,
+    pub rms_norm_eps: f64,
+    pub rope_theta: f32,
+    pub max_position_embeddings: usize,
+    pub sliding_window: Option<usize>,
+    pub query_pre_attn_scalar: usize,
+    pub attn_logit_softcapping: Option<f32>,
+    pub final_logit_softcapping: Option<f32>,
+    pub quantization_config: Option<QuantizationConfig>,
+}
+
+impl Config {
+    /// The even layers of Gemma-2 attend to a sliding window, the odd layers globally.
+    fn layer_sliding_window(&self, layer: usize) -> Option<usize> {
+        self.sliding_window
+            .filter(|_| self.is_gemma2 && layer % 2 == 0)
+    }
+}
+
+impl ConfigLike for Config {
+    fn get_num_kv_heads(&self) -> usize {
+        self.num_key_value_heads
+    }
+    fn get_hidden_size(&self) -> usize {
+        self.hidden_size
+    }
+    fn get_num_hidden_layers(&self) -> usize {
+        self.num_hidden_layers
+    }
+    fn get_num_attention_heads(&self) -> usize {
+        self.num_attention_heads
+    }
+    fn get_vocab_size(&self) -> usize {
+        self.vocab_size
+    }
+    fn get_sliding_window(&self) -> Option<usize> {
+        None
+    }
+    fn get_head_size(&self) -> usize {
+        self.head_dim
+    }
+}
+
+fn embedding(cfg: &Config, vb: VarBuilder) -> candle_core::Result<Embedding> {
+    let embeddings = vb.get((cfg.vocab_size, cfg.hidden_size), "weight")?;
+    Ok(Embedding::new(embeddings, cfg.hidden_size))
+}
+
+/// RMSNorm scaling by `1 + weight`, computed in f32.
+struct RmsNorm {
+    weight: Tensor,
+    eps: f64,
+    span: tracing::Span,
+}
+
+impl RmsNorm {
+    fn load(size: usize, eps: f64, vb: VarBuilder) -> candle_core::Result<Self> {
+        let span = tracing::span!(tracing::Level::TRACE, "rms-norm");
+        let weight = (vb.get(size, "weight")?.to_dtype(DType::F32)? + 1.)?;
+        Ok(Self { weight, eps, span })
+    }
+
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let _enter = self.span.enter();
+        let dtype = x.dtype();
+        let x = x.to_dtype(DType::F32)?;
+        let norm = (x.sqr()?.mean_keepdim(candle_core::D::Minus1)? + self.eps)?.sqrt()?;
+        x.broadcast_div(&norm)?
+            .broadcast_mul(&self.weight)?
+            .to_dtype(dtype)
+    }
+}
+
+struct Attention {
+    q_proj: QuantLinear,
+    k_proj: QuantLinear,
+    v_proj: QuantLinear,
+    o_proj: QuantLinear,
+    num_attention_heads: usize,
+    num_key_value_heads: usize,
+    head_dim: usize,
+    attn: PagedAttention,
+    cos_sin_cache: Tensor,
+}
+
+impl Attention {
+    fn forward(
+        &mut self,
+        x: &Tensor,
+        positions: &Tensor,
+        input_metadata: &mut InputMetadata,
+        cache: Option<(&Tensor, &Tensor)>,
+    ) -> Result<Tensor, APIError> {
+        let (b_sz, seq_len, _) = try_api!(x.dims3());
+        let q = try_api!(self.q_proj.forward(x));
+        let k = try_api!(self.k_proj.forward(x));
+        let v = try_api!(self.v_proj.forward(x));
+
+        let q =
+            try_api!(
+                try_api!(q.reshape((b_sz, seq_len, self.num_attention_heads, self.head_dim)))
+                    .transpose(1, 2)
+            );
+        let k =
+            try_api!(
+                try_api!(k.reshape((b_sz, seq_len, self.num_key_value_heads, self.head_dim)))
+                    .transpose(1, 2)
+            );
+        let v =
+            try_api!(
+                try_api!(v.reshape((b_sz, seq_len, self.num_key_value_heads, self.head_dim)))
+                    .transpose(1, 2)
+            );
+
+        let dtype = q.dtype();
+        let device = q.device().clone();
+        let attn_output = self.attn.forward_with_rotary_embedding(
+            q,
+            k,
+            v,
+            positions,
+            &self.cos_sin_cache,
+            false,
+            cache.map(|(k, _)| k.clone()),
+            cache.map(|(_, v)| v.clone()),
+            input_metadata,
+            dtype,
+            device,
+        )?;
+
+        self.o_proj.forward(&attn_output).map_err(APIError::from)
+    }
+
+    fn load(
+        vb: VarBuilder,
+        cfg: &Config,
+        sliding_window: Option<usize>,
+        cos_sin_cache: Tensor,
+    ) -> Result<Self, APIError> {
+        // The heads do not necessarily add up to the hidden size (e.g. Gemma-7B).
+        let head_dim = cfg.head_dim;
+        let size_q = head_dim * cfg.num_attention_heads;
+        let size_kv = head_dim * cfg.num_key_value_heads;
+        let q_proj = try_api!(linear(cfg.hidden_size, size_q, vb.pp("q_proj")));
+        let k_proj = try_api!(linear(cfg.hidden_size, size_kv, vb.pp("k_proj")));
+        let v_proj = try_api!(linear(cfg.hidden_size, size_kv, vb.pp("v_proj")));
+        let o_proj = try_api!(linear(size_q, cfg.hidden_size, vb.pp("o_proj")));
+
+        Ok(Self {
+            q_proj,
+            k_proj,
+            v_proj,
+            o_proj,
+            num_attention_heads: cfg.num_attention_heads,
+            num_key_value_heads: cfg.num_key_value_heads,
+            head_dim,
+            attn: PagedAttention::new(
+                cfg.num_attention_heads,
+                head_dim,
+                1. / ((cfg.query_pre_attn_scalar as f32).sqrt()),
+                Some(cfg.num_key_value_heads),
+                sliding_window,
+                vb.device().clone(),
+                None,
+            )?
+            .with_logits_soft_cap(cfg.attn_logit_softcapping),
+            cos_sin_cache,
+        })
+    }
+}
+
+/// The GeGLU MLP, with the tanh approximation of GELU.
+struct Mlp {
+    gate_proj: QuantLinear,
+    up_proj: QuantLinear,
+    down_proj: QuantLinear,
+    span: tracing::Span,
+}
+
+impl Mlp {
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let _enter = self.span.enter();
+        let x = (self.gate_proj.forward(x)?.gelu()? * self.up_proj.forward(x)?)?;
+        self.down_proj.forward(&x)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config) -> candle_core::Result<Self> {
+        let span = tracing::span!(tracing::Level::TRACE, "mlp");
+        let (h_size, i_size) = (cfg.hidden_size, cfg.intermediate_size);
+        Ok(Self {
+            gate_proj: linear(h_size, i_size, vb.pp("gate_proj"))?,
+            up_proj: linear(h_size, i_size, vb.pp("up_proj"))?,
+            down_proj: linear(i_size, h_size, vb.pp("down_proj"))?,
+            span,
+        })
+    }
+}
+
+/// The norms around the attention and the MLP of a layer. Gemma normalizes the inputs of both;
+/// Gemma-2 also normalizes their outputs.
+struct Norms {
+    input_layernorm: RmsNorm,
+    post_attention_layernorm: RmsNorm,
+    /// Gemma-2 only, the `post_attention_layernorm` then applies to the attention output.
+    pre_feedforward_layernorm: Option<RmsNorm>,
+    post_feedforward_layernorm: Option<RmsNorm>,
+}
+
+struct Block {
+    norms: Norms,
+    attn: Attention,
+    mlp: Mlp,
+    span: tracing::Span,
+}
+
+impl Block {
+    fn forward(
+        &mut self,
+        x: &Tensor,
+        positions: &Tensor,
+        cache: Option<(&Tensor, &Tensor)>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let _enter = self.span.enter();
+        let norms = &self.norms;
+        let residual = x;
+        let x = try_api!(norms.input_layernorm.forward(x));
+        let x = self.attn.forward(&x, positions, input_metadata, cache)?;
+        let (x, mlp_norm) = match &norms.pre_feedforward_layernorm {
+            Some(pre_feedforward_layernorm) => (
+                try_api!(norms.post_attention_layernorm.forward(&x)),
+                pre_feedforward_layernorm,
+            ),
+            None => (x, &norms.post_attention_layernorm),
+        };
+        let x = try_api!(x + residual);
+        let residual = &x;
+        let mlp_out = try_api!(self.mlp.forward(&try_api!(mlp_norm.forward(&x))));
+        let mlp_out = match &norms.post_feedforward_layernorm {
+            Some(post_feedforward_layernorm) => {
+                try_api!(post_feedforward_layernorm.forward(&mlp_out))
+            }
+            None => mlp_out,
+        };
+        Ok(try_api!(mlp_out + residual))
+    }
+
+    fn load(
+        vb: VarBuilder,
+        cfg: &Config,
+        sliding_window: Option<usize>,
+        cos_sin_cache: Tensor,
+    ) -> Result<Self, APIError> {
+        let span = tracing::span!(tracing::Level::TRACE, "block");
+        let attn = Attention::load(vb.pp("self_attn"), cfg, sliding_window, cos_sin_cache)?;
+        let mlp = try_api!(Mlp::load(vb.pp("mlp"), cfg));
+        let norm = |name: &str| RmsNorm::load(cfg.hidden_size, cfg.rms_norm_eps, vb.pp(name));
+        let gemma2_norm = |name: &str| cfg.is_gemma2.then(|| norm(name)).transpose();
+        let norms = Norms {
+            input_layernorm: try_api!(norm("input_layernorm")),
+            post_attention_layernorm: try_api!(norm("post_attention_layernorm")),
+            pre_feedforward_layernorm: try_api!(gemma2_norm("pre_feedforward_layernorm")),
+            post_feedforward_layernorm: try_api!(gemma2_norm("post_feedforward_layernorm")),
+        };
+        Ok(Self {
+            norms,
+            attn,
+            mlp,
+            span,
+        })
+    }
+}
+
+pub struct Gemma {
+    wte: Embedding,
+    blocks: Vec<Block>,
+    ln_f: RmsNorm,
+    lm_head: QuantLinear,
+    cfg: Config,
+}
+
+impl Gemma {
+    pub fn forward(
+        &mut self,
+        x: &Tensor,
+        positions: &Tensor,
+        kv_caches: Option<&KVCacheManager>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let (b_sz, seq_len) = try_api!(x.dims2());
+        let logits_indices = input_metadata.logits_indices(b_sz * seq_len, x.device())?;
+        // The embeddings are scaled by sqrt(hidden_size).
+        let mut x = try_api!(try_api!(self.wte.forward(x)) * (self.cfg.hidden_size as f64).sqrt());
+        if let Some(kv_caches) = kv_caches {
+            for (layer, block) in zip(kv_caches.layers(), &mut self.blocks) {
+                x = block.forward(&x, positions, Some(layer.key_value()), input_metadata)?;
+            }
+        } else {
+            for block in &mut self.blocks {
+                x = block.forward(&x, positions, None, input_metadata)?;
+            }
+        }
+        let x = try_api!(self.ln_f.forward(&x));
+        let x = try_api!(x.flatten_to(1));
+        input_metadata.keep_hidden_states(&x);
+        let x = try_api!(x.index_select(&logits_indices, 0));
+        let logits = try_api!(self.lm_head.forward(&x));
+        let logits = soft_cap_logits(logits, self.cfg.final_logit_softcapping)?;
+        logits.to_dtype(DType::F32).map_err(APIError::from)
+    }
+
+    pub fn load(
+        vb: VarBuilder,
+        cfg: &Config,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self, APIError> {
+        let wte = try_api!(embedding(cfg, vb.pp("model.embed_tokens")));
+        // The LM head is tied to the embedding.
+        let lm_head =
+            QuantLinear::Unquantized(Linear::from_weights(wte.embeddings().clone(), None));
+        let ln_f = try_api!(RmsNorm::load(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            vb.pp("model.norm")
+        ));
+        // The cos/sin cache is shared by all layers.
+        let cos_sin_cache = compute_cos_sin_cache(
+            cfg.head_dim,
+            cfg.rope_theta,
+            cfg.max_position_embeddings,
+            None,
+            dtype,
+            device,
+        )?;
+        let blocks = (0..cfg.num_hidden_layers)
+            .map(|i| {
+                Block::load(
+                    vb.pp(&format!("model.layers.{i}")),
+                    cfg,
+                    cfg.layer_sliding_window(i),
+                    cos_sin_cache.clone(),
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            wte,
+            blocks,
+            ln_f,
+            lm_head,
+            cfg: cfg.clone(),
+        })
+    }
+
+    pub fn get_config(&self) -> &Config {
+        &self.cfg
+    }
+}
+
+impl PagedAttentionModel for Gemma {
+    fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        positions: &Tensor,
+        kv_caches: Option<&KVCacheManager>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        Gemma::forward(self, input_ids, positions, kv_caches, input_metadata)
+    }
+
+    fn get_config(&self) -> Box<dyn ConfigLike> {
+        Box::new(self.cfg.clone())
+    }
+}
+
+impl LoadablePagedAttentionModel for Gemma {
+    fn load_from_config(
+        config: &[u8],
+        vb: VarBuilder,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self, APIError> {
+        let config: GemmaConfig = try_api!(serde_json::from_slice(config));
+        Gemma::load(vb, &config.into_config(), dtype, device)
+    }
+}
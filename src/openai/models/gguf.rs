@@ -0,0 +1,415 @@
+//! Loading of GGUF checkpoints of the llama.cpp ecosystem. The architecture and config are read
+//! from the metadata of the file and its tensors are renamed to the Transformers layout, so the
+//! existing model graphs load them. The weights of the linear layers stay quantized and are run
+//! with candle's quantized matmul kernels, all other tensors are dequantized to the model dtype.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use candle_core::{
+    quantized::{ggml_file::qtensor_from_ggml, gguf_file, GgmlDType, QMatMul},
+    DType, Device, Module, Tensor,
+};
+use candle_nn::VarBuilder;
+use serde_json::json;
+
+use crate::{
+    openai::{pipelines::LoadOptions, responses::APIError},
+    try_api,
+};
+
+use super::weights::apply_load_options;
+
+/// The quantization types of the tensors which are kept quantized, indexed by the code stored
+/// next to the raw weight, see `GGML_DTYPE_SUFFIX`.
+const GGML_DTYPES: [GgmlDType; 12] = [
+    GgmlDType::Q4_0,
+    GgmlDType::Q4_1,
+    GgmlDType::Q5_0,
+    GgmlDType::Q5_1,
+    GgmlDType::Q8_0,
+    GgmlDType::Q8_1,
+    GgmlDType::Q2K,
+    GgmlDType::Q3K,
+    GgmlDType::Q4K,
+    GgmlDType::Q5K,
+    GgmlDType::Q6K,
+    GgmlDType::Q8K,
+];
+
+/// A quantized weight `<name>` is stored as its raw blocks, shape = [out_dim, row_bytes] (U8),
+/// next to `<name>_ggml_dtype` holding the index of its type in `GGML_DTYPES`.
+pub(crate) const GGML_DTYPE_SUFFIX: &str = "_ggml_dtype";
+
+/// The layer tensors of the llama.cpp layout with their name in the Transformers layout.
+fn layer_tensor_name(architecture: &str, name: &str) -> Option<&'static str> {
+    Some(match (architecture, name) {
+        (_, "attn_norm") => "input_layernorm",
+        (_, "attn_q") => "self_attn.q_proj",
+        (_, "attn_k") => "self_attn.k_proj",
+        (_, "attn_v") => "self_attn.v_proj",
+        (_, "attn_qkv") => "self_attn.qkv_proj",
+        (_, "attn_output") => "self_attn.o_proj",
+        (_, "ffn_gate") => "mlp.gate_proj",
+        ("phi3", "ffn_up") => "mlp.gate_up_proj",
+        (_, "ffn_up") => "mlp.up_proj",
+        (_, "ffn_down") => "mlp.down_proj",
+        ("gemma2", "ffn_norm") => "pre_feedforward_layernorm",
+        (_, "ffn_norm") => "post_attention_layernorm",
+        ("gemma2", "post_attention_norm") => "post_attention_layernorm",
+        ("gemma2", "post_ffw_norm") => "post_feedforward_layernorm",
+        _ => return None,
+    })
+}
+
+fn is_linear(name: &str) -> bool {
+    [
+        "attn_q",
+        "attn_k",
+        "attn_v",
+        "attn_qkv",
+        "attn_output",
+        "ffn_gate",
+        "ffn_up",
+        "ffn_down",
+    ]
+    .contains(&name)
+}
+
+/// A numeric metadata value, GGUF writers differ in the integer types they use.
+fn to_f64(value: &gguf_file::Value) -> Option<f64> {
+    use gguf_file::Value;
+    match value {
+        Value::U8(x) => Some(f64::from(*x)),
+        Value::I8(x) => Some(f64::from(*x)),
+        Value::U16(x) => Some(f64::from(*x)),
+        Value::I16(x) => Some(f64::from(*x)),
+        Value::U32(x) => Some(f64::from(*x)),
+        Value::I32(x) => Some(f64::from(*x)),
+        Value::U64(x) => Some(*x as f64),
+        Value::I64(x) => Some(*x as f64),
+        Value::F32(x) => Some(f64::from(*x)),
+        Value::F64(x) => Some(*x),
+        _ => None,
+    }
+}
+
+pub struct GgufCheckpoint {
+    content: gguf_file::Content,
+    filename: PathBuf,
+    /// `general.architecture`, e.g. "llama" or "gemma2".
+    architecture: String,
+}
+
+impl GgufCheckpoint {
+    pub fn open(filename: &Path) -> Result<Self, APIError> {
+        let mut file = try_api!(File::open(filename));
+        let content = try_api!(gguf_file::Content::read(&mut file));
+        let architecture = match content.metadata.get("general.architecture") {
+            Some(gguf_file::Value::String(architecture)) => architecture.clone(),
+            _ => {
+                return Err(APIError::new(format!(
+                    "{} has no `general.architecture`.",
+                    filename.display()
+                )))
+            }
+        };
+        Ok(Self {
+            content,
+            filename: filename.to_path_buf(),
+            architecture,
+        })
+    }
+
+    fn get_f64(&self, key: &str) -> Option<f64> {
+        self.content
+            .metadata
+            .get(&format!("{}.{key}", self.architecture))
+            .or_else(|| self.content.metadata.get(key))
+            .and_then(to_f64)
+    }
+
+    fn get_usize(&self, key: &str) -> Option<usize> {
+        self.get_f64(key).map(|x| x as usize)
+    }
+
+    fn require_usize(&self, key: &str) -> Result<usize, APIError> {
+        self.get_usize(key).ok_or_else(|| {
+            APIError::new(format!(
+                "The GGUF metadata has no `{}.{key}`.",
+                self.architecture
+            ))
+        })
+    }
+
+    fn has_tensor(&self, name: &str) -> bool {
+        self.content.tensor_infos.contains_key(name)
+    }
+
+    /// The name of the model constructor of the checkpoint, see `registered_models`.
+    pub fn model_architecture(&self) -> Result<&'static str, APIError> {
+        match self.architecture.as_str() {
+            "llama" if self.get_usize("expert_count").unwrap_or(0) > 0 => Err(APIError::new_str(
+                "Mixture-of-experts GGUF checkpoints are not supported.",
+            )),
+            "llama" => Ok("llama"),
+            "qwen2" => Ok("qwen2"),
+            "gemma" | "gemma2" => Ok("gemma"),
+            "phi3" if self.has_tensor("rope_factors_long.weight") => Err(APIError::new_str(
+                "LongRoPE GGUF checkpoints of Phi-3 are not supported.",
+            )),
+            "phi3" => Ok("phi3"),
+            architecture => Err(APIError::new(format!(
+                "Unsupported GGUF architecture `{architecture}`."
+            ))),
+        }
+    }
+
+    /// The `config.json` of the checkpoint in the Transformers format, built from the metadata.
+    pub fn config(&self) -> Result<serde_json::Value, APIError> {
+        let hidden_size = self.require_usize("embedding_length")?;
+        let num_attention_heads = self.require_usize("attention.head_count")?;
+        let Some(embeddings) = self.content.tensor_infos.get("token_embd.weight") else {
+            return Err(APIError::new_str(
+                "The GGUF checkpoint has no `token_embd.weight`.",
+            ));
+        };
+        let mut config = json!({
+            "hidden_size": hidden_size,
+            "intermediate_size": self.require_usize("feed_forward_length")?,
+            "num_hidden_layers": self.require_usize("block_count")?,
+            "num_attention_heads": num_attention_heads,
+            "num_key_value_heads": self
+                .get_usize("attention.head_count_kv")
+                .unwrap_or(num_attention_heads),
+            "rms_norm_eps": self.get_f64("attention.layer_norm_rms_epsilon").unwrap_or(1e-5),
+            "rope_theta": self.get_f64("rope.freq_base").unwrap_or(10_000.),
+            "max_position_embeddings": self.get_usize("context_length"),
+            "vocab_size": try_api!(embeddings.shape.dims2()).0,
+            "bos_token_id": self.get_usize("tokenizer.ggml.bos_token_id"),
+            "eos_token_id": self.get_usize("tokenizer.ggml.eos_token_id"),
+            "sliding_window": self.get_usize("attention.sliding_window"),
+        });
+        match self.architecture.as_str() {
+            "gemma" | "gemma2" => {
+                config["model_type"] = json!(self.architecture);
+                config["head_dim"] = json!(self
+                    .get_usize("attention.key_length")
+                    .unwrap_or(hidden_size / num_attention_heads));
+                config["attn_logit_softcapping"] = json!(self.get_f64("attn_logit_softcapping"));
+                config["final_logit_softcapping"] = json!(self.get_f64("final_logit_softcapping"));
+            }
+            "qwen2" => {
+                config["tie_word_embeddings"] = json!(!self.has_tensor("output.weight"));
+            }
+            _ => (),
+        }
+        Ok(config)
+    }
+
+    /// Load the tensors of the checkpoint under their Transformers names. The weights of the
+    /// linear layers are kept as raw quantized blocks on the CPU until `linear_b` uploads them.
+    pub fn load(
+        &self,
+        dtype: DType,
+        device: &Device,
+        options: &LoadOptions,
+    ) -> Result<VarBuilder<'static>, APIError> {
+        let mut file = try_api!(File::open(&self.filename));
+        let mut tensors = HashMap::new();
+        let num_heads = self.require_usize("attention.head_count")?;
+        let num_kv_heads = self
+            .get_usize("attention.head_count_kv")
+            .unwrap_or(num_heads);
+        for (name, info) in &self.content.tensor_infos {
+            let (hf_name, linear) = match name.as_str() {
+                "token_embd.weight" => ("model.embed_tokens.weight".to_string(), false),
+                "output_norm.weight" => ("model.norm.weight".to_string(), false),
+                "output.weight" => ("lm_head.weight".to_string(), true),
+                _ => {
+                    let mut parts = name.splitn(3, '.');
+                    let (Some("blk"), Some(layer), Some(rest)) =
+                        (parts.next(), parts.next(), parts.next())
+                    else {
+                        continue;
+                    };
+                    let Some((tensor, suffix)) = rest.split_once('.') else {
+                        continue;
+                    };
+                    let Some(hf_tensor) = layer_tensor_name(&self.architecture, tensor) else {
+                        continue;
+                    };
+                    (
+                        format!("model.layers.{layer}.{hf_tensor}.{suffix}"),
+                        is_linear(tensor) && suffix == "weight",
+                    )
+                }
+            };
+            // llama.cpp interleaves the rotary halves of the query and key heads of Llama.
+            let num_rotary_heads = match (self.architecture.as_str(), name.rsplit('.').nth(1)) {
+                ("llama", Some("attn_q")) => Some(num_heads),
+                ("llama", Some("attn_k")) => Some(num_kv_heads),
+                _ => None,
+            };
+            if linear && GGML_DTYPES.contains(&info.ggml_dtype) {
+                let weight = self.read_raw(&mut file, info)?;
+                let weight = match num_rotary_heads {
+                    Some(num_heads) => unpermute_rotary_rows(&weight, num_heads)?,
+                    None => weight,
+                };
+                insert_raw(&mut tensors, hf_name, weight, info.ggml_dtype)?;
+                continue;
+            }
+            let tensor = try_api!(self.content.tensor(&mut file, name, &Device::Cpu));
+            let mut tensor = try_api!(tensor.dequantize(&Device::Cpu));
+            if let Some(num_heads) = num_rotary_heads {
+                tensor = unpermute_rotary_rows(&tensor, num_heads)?;
+            }
+            // llama.cpp stores the Gemma norms with the offset of 1 the model adds back.
+            if self.architecture.starts_with("gemma") && name.ends_with("norm.weight") {
+                tensor = try_api!(tensor - 1.);
+            }
+            let tensor = try_api!(try_api!(tensor.to_dtype(dtype)).to_device(device));
+            tensors.insert(hf_name, tensor);
+        }
+        // The LM head of checkpoints without an output layer is tied to the embedding.
+        let tied = matches!(self.architecture.as_str(), "llama" | "phi3");
+        if tied && !self.has_tensor("output.weight") {
+            let info = &self.content.tensor_infos["token_embd.weight"];
+            let name = "lm_head.weight".to_string();
+            if GGML_DTYPES.contains(&info.ggml_dtype) {
+                let weight = self.read_raw(&mut file, info)?;
+                insert_raw(&mut tensors, name, weight, info.ggml_dtype)?;
+            } else {
+                let embeddings = tensors["model.embed_tokens.weight"].clone();
+                tensors.insert(name, embeddings);
+            }
+        }
+        apply_load_options(&mut tensors, options)?;
+        Ok(VarBuilder::from_tensors(tensors, dtype, device))
+    }
+
+    /// The raw blocks of a quantized 2D tensor, shape = [rows, row_bytes] (U8).
+    fn read_raw(&self, file: &mut File, info: &gguf_file::TensorInfo) -> Result<Tensor, APIError> {
+        let (rows, cols) = try_api!(info.shape.dims2());
+        let dtype = info.ggml_dtype;
+        let row_bytes = cols / dtype.block_size() * dtype.type_size();
+        let mut data = vec![0u8; rows * row_bytes];
+        try_api!(file.seek(SeekFrom::Start(
+            self.content.tensor_data_offset + info.offset
+        )));
+        try_api!(file.read_exact(&mut data));
+        Ok(try_api!(Tensor::from_vec(
+            data,
+            (rows, row_bytes),
+            &Device::Cpu
+        )))
+    }
+}
+
+/// Store the raw blocks of a quantized weight with the code of its type.
+fn insert_raw(
+    tensors: &mut HashMap<String, Tensor>,
+    name: String,
+    weight: Tensor,
+    dtype: GgmlDType,
+) -> Result<(), APIError> {
+    let code = GGML_DTYPES.iter().position(|x| *x == dtype).unwrap_or(0) as u8;
+    let code = try_api!(Tensor::new(&[code], &Device::Cpu));
+    tensors.insert(format!("{name}{GGML_DTYPE_SUFFIX}"), code);
+    tensors.insert(name, weight);
+    Ok(())
+}
+
+/// Undo the interleaving of the rotary halves of each head by llama.cpp, which converts the
+/// rows of a head from the order (2, head_dim / 2) to (head_dim / 2, 2). Quantized blocks never
+/// span rows, so this applies to raw blocks as well.
+fn unpermute_rotary_rows(weight: &Tensor, num_heads: usize) -> Result<Tensor, APIError> {
+    let rows = try_api!(weight.dim(0));
+    let head_dim = rows / num_heads;
+    let half = head_dim / 2;
+    let indices = (0..rows)
+        .map(|row| {
+            let (head, i, j) = (row / head_dim, row % head_dim / half, row % half);
+            (head * head_dim + j * 2 + i) as u32
+        })
+        .collect::<Vec<_>>();
+    let indices = try_api!(Tensor::from_vec(indices, rows, weight.device()));
+    weight.index_select(&indices, 0).map_err(APIError::from)
+}
+
+/// Whether `vb` holds the quantized GGUF weight of a linear layer.
+pub fn is_gguf_linear(vb: &VarBuilder) -> bool {
+    vb.contains_tensor(&format!("weight{GGML_DTYPE_SUFFIX}"))
+}
+
+/// A linear layer holding quantized GGUF weights, computed with candle's quantized matmul.
+pub struct GgufLinear {
+    weight: QMatMul,
+    bias: Option<Tensor>,
+    span: tracing::Span,
+}
+
+impl GgufLinear {
+    pub fn new(weight: QMatMul, bias: Option<Tensor>) -> Self {
+        Self {
+            weight,
+            bias,
+            span: tracing::span!(tracing::Level::TRACE, "gguf-linear"),
+        }
+    }
+}
+
+impl Module for GgufLinear {
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let _enter = self.span.enter();
+        // The quantized kernels take f32 activations.
+        let out = self
+            .weight
+            .forward(&x.to_dtype(DType::F32)?)?
+            .to_dtype(x.dtype())?;
+        match &self.bias {
+            Some(bias) => out.broadcast_add(bias),
+            None => Ok(out),
+        }
+    }
+}
+
+/// Load the quantized GGUF weight of a linear layer, see `is_gguf_linear`.
+pub fn gguf_linear_b(
+    in_dim: usize,
+    out_dim: usize,
+    bias: bool,
+    vb: VarBuilder,
+) -> candle_core::Result<GgufLinear> {
+    let code = vb
+        .get_with_hints_dtype(
+            1,
+            &format!("weight{GGML_DTYPE_SUFFIX}"),
+            Default::default(),
+            DType::U8,
+        )?
+        .to_vec1::<u8>()?[0];
+    let dtype = GGML_DTYPES[code as usize];
+    let row_bytes = in_dim / dtype.block_size() * dtype.type_size();
+    let data = vb
+        .get_with_hints_dtype(
+            (out_dim, row_bytes),
+            "weight",
+            Default::default(),
+            DType::U8,
+        )?
+        .flatten_all()?
+        .to_vec1::<u8>()?;
+    let weight = qtensor_from_ggml(dtype, &data, vec![out_dim, in_dim], vb.device())?;
+    let bias = if bias {
+        Some(vb.get(out_dim, "bias")?)
+    } else {
+        None
+    };
+    Ok(GgufLinear::new(QMatMul::from_qtensor(weight)?, bias))
+}
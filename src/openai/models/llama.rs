@@ -1,17 +1,24 @@
 /// Llama LLM, https://github.com/huggingface/candle/blob/main/candle-transformers/src/models/llama.rs
-use candle_core::{DType, Device, IndexOp, Tensor, D};
+use std::ops::Range;
+
+use candle_core::{DType, Device, Tensor};
 use candle_nn::{Embedding, Module, VarBuilder};
-use candle_transformers::models::with_tracing::{linear_no_bias as linear, Linear};
 use serde::Deserialize;
-use std::iter::zip;
 
-use crate::backend::rotary_embedding;
 use crate::openai::responses::APIError;
 use crate::paged_attention::input_metadata::InputMetadata;
-use crate::paged_attention::PagedAttention;
+use crate::paged_attention::{sparse::SparseAttentionConfig, PagedAttention};
+use crate::scheduler::kv_cache_manager::KVCacheManager;
 use crate::try_api;
 
-use super::ConfigLike;
+use super::offload::{OffloadStats, OffloadedLayer};
+use super::pipeline_parallel::{stage_layers, wavefront, MicroBatch};
+use super::quantization::{linear_no_bias as linear, QuantLinear, QuantizationConfig};
+use super::rotary_embedding::{compute_cos_sin_cache, RopeScaling};
+use super::tensor_parallel::{rank_devices, sum_over_ranks};
+use super::{
+    soft_cap_logits, ConfigLike, LoadablePagedAttentionModel, ModelWeights, PagedAttentionModel,
+};
 
 pub const MAX_SEQ_LEN: usize = 4096;
 
@@ -26,6 +33,24 @@ pub struct LlamaConfig {
     pub rms_norm_eps: f64,
     #[serde(default = "default_rope")]
     pub rope_theta: f32,
+    #[serde(default)]
+    pub max_position_embeddings: Option<usize>,
+    #[serde(default)]
+    pub rope_scaling: Option<RopeScaling>,
+    #[serde(default)]
+    pub quantization_config: Option<QuantizationConfig>,
+    #[serde(default)]
+    pub sparse_attention: Option<SparseAttentionConfig>,
+    #[serde(default)]
+    pub attn_logit_softcapping: Option<f32>,
+    #[serde(default)]
+    pub final_logit_softcapping: Option<f32>,
+    /// Set by the loader, see `LoadOptions::tensor_parallel_size`.
+    #[serde(default = "default_parallel_size")]
+    pub tensor_parallel_size: usize,
+    /// Set by the loader, see `LoadOptions::pipeline_parallel_size`.
+    #[serde(default = "default_parallel_size")]
+    pub pipeline_parallel_size: usize,
 }
 
 impl ConfigLike for LlamaConfig {
@@ -53,6 +78,10 @@ fn default_rope() -> f32 {
     10_000.0
 }
 
+fn default_parallel_size() -> usize {
+    1
+}
+
 impl LlamaConfig {
     pub fn into_config(self) -> Config {
         Config {
@@ -64,6 +93,14 @@ impl LlamaConfig {
             num_key_value_heads: self.num_key_value_heads.unwrap_or(self.num_attention_heads),
             rms_norm_eps: self.rms_norm_eps,
             rope_theta: self.rope_theta,
+            max_position_embeddings: self.max_position_embeddings.unwrap_or(MAX_SEQ_LEN),
+            rope_scaling: self.rope_scaling,
+            quantization_config: self.quantization_config,
+            sparse_attention: self.sparse_attention,
+            attn_logit_softcapping: self.attn_logit_softcapping,
+            final_logit_softcapping: self.final_logit_softcapping,
+            tensor_parallel_size: self.tensor_parallel_size,
+            pipeline_parallel_size: self.pipeline_parallel_size,
         }
     }
 }
@@ -78,6 +115,23 @@ pub struct Config {
     pub num_key_value_heads: usize,
     pub rms_norm_eps: f64,
     pub rope_theta: f32,
+    /// The context length the model was trained for, extended by `rope_scaling`.
+    pub max_position_embeddings: usize,
+    /// Long-context scaling of the rotary embedding, see `RopeScaling`.
+    pub rope_scaling: Option<RopeScaling>,
+    pub quantization_config: Option<QuantizationConfig>,
+    /// Block-sparse decode attention for very long contexts, off by default.
+    pub sparse_attention: Option<SparseAttentionConfig>,
+    /// Soft-cap of the attention scores, e.g. 50 for Gemma-2.
+    pub attn_logit_softcapping: Option<f32>,
+    /// Soft-cap of the final logits, e.g. 30 for Gemma-2.
+    pub final_logit_softcapping: Option<f32>,
+    /// Number of devices the heads and the MLP of each layer are split over, see
+    /// `tensor_parallel`.
+    pub tensor_parallel_size: usize,
+    /// Number of devices the layers are split over in consecutive stages, see
+    /// `pipeline_parallel`.
+    pub pipeline_parallel_size: usize,
 }
 
 impl ConfigLike for Config {
@@ -99,6 +153,12 @@ impl ConfigLike for Config {
     fn get_sliding_window(&self) -> Option<usize> {
         None
     }
+    fn get_tensor_parallel_size(&self) -> usize {
+        self.tensor_parallel_size
+    }
+    fn get_pipeline_parallel_size(&self) -> usize {
+        self.pipeline_parallel_size
+    }
 }
 
 impl Config {
@@ -112,6 +172,14 @@ impl Config {
             num_key_value_heads: 32,
             rms_norm_eps: 1e-6,
             rope_theta: 10_000.0,
+            max_position_embeddings: MAX_SEQ_LEN,
+            rope_scaling: None,
+            quantization_config: None,
+            sparse_attention: None,
+            attn_logit_softcapping: None,
+            final_logit_softcapping: None,
+            tensor_parallel_size: 1,
+            pipeline_parallel_size: 1,
         }
     }
 
@@ -125,6 +193,14 @@ impl Config {
             num_key_value_heads: 32,
             rms_norm_eps: 1e-5,
             rope_theta: 10_000.0,
+            max_position_embeddings: MAX_SEQ_LEN,
+            rope_scaling: None,
+            quantization_config: None,
+            sparse_attention: None,
+            attn_logit_softcapping: None,
+            final_logit_softcapping: None,
+            tensor_parallel_size: 1,
+            pipeline_parallel_size: 1,
         }
     }
 }
@@ -153,10 +229,10 @@ impl RmsNorm {
 }
 
 struct CausalSelfAttention {
-    q_proj: Linear,
-    k_proj: Linear,
-    v_proj: Linear,
-    o_proj: Linear,
+    q_proj: QuantLinear,
+    k_proj: QuantLinear,
+    v_proj: QuantLinear,
+    o_proj: QuantLinear,
     num_attention_heads: usize,
     num_key_value_heads: usize,
     head_dim: usize,
@@ -165,51 +241,6 @@ struct CausalSelfAttention {
 }
 
 impl CausalSelfAttention {
-    fn compute_cos_sin_cache(
-        config: &Config,
-        device: &Device,
-        dtype: DType,
-    ) -> Result<Tensor, APIError> {
-        // precompute freqs_cis
-        let n_elem = config.hidden_size / config.num_attention_heads;
-        let theta: Vec<_> = (0..n_elem)
-            .step_by(2)
-            .map(|i| 1f32 / config.rope_theta.powf(i as f32 / n_elem as f32))
-            .collect();
-        let theta = try_api!(Tensor::new(theta.as_slice(), device));
-        let idx_theta =
-            try_api!(try_api!(try_api!(
-                try_api!(Tensor::arange(0, MAX_SEQ_LEN as u32, device)).to_dtype(DType::F32)
-            )
-            .reshape((MAX_SEQ_LEN, 1)))
-            .matmul(&try_api!(theta.reshape((1, theta.elem_count())))));
-        // This is different from the paper, see:
-        // https://github.com/huggingface/transformers/blob/6112b1c6442aaf7affd2b0676a1cd4eee30c45cf/src/transformers/models/llama/modeling_llama.py#L112
-        let idx_theta = try_api!(Tensor::cat(&[&idx_theta, &idx_theta], D::Minus1));
-        let cos = try_api!(try_api!(idx_theta.cos()).to_dtype(dtype));
-        let sin = try_api!(try_api!(idx_theta.sin()).to_dtype(dtype));
-        let last = cos.dims().len() - 1;
-        Tensor::cat(&[cos, sin], last).map_err(APIError::from)
-    }
-
-    fn apply_rotary_emb(
-        &mut self,
-        q: &mut Tensor,
-        k: &mut Tensor,
-        positions: Tensor,
-    ) -> Result<(), APIError> {
-        unsafe {
-            rotary_embedding(
-                positions,
-                q,
-                k,
-                self.head_dim,
-                self.cos_sin_cache.clone(),
-                false,
-            )
-        }
-    }
-
     fn forward(
         &mut self,
         x: &Tensor,
@@ -222,12 +253,12 @@ impl CausalSelfAttention {
         let k = try_api!(self.k_proj.forward(x));
         let v = try_api!(self.v_proj.forward(x));
 
-        let mut q =
+        let q =
             try_api!(
                 try_api!(q.reshape((b_sz, seq_len, self.num_attention_heads, self.head_dim)))
                     .transpose(1, 2)
             );
-        let mut k =
+        let k =
             try_api!(
                 try_api!(k.reshape((b_sz, seq_len, self.num_key_value_heads, self.head_dim)))
                     .transpose(1, 2)
@@ -238,14 +269,15 @@ impl CausalSelfAttention {
                     .transpose(1, 2)
             );
 
-        self.apply_rotary_emb(&mut q, &mut k, positions.clone())?;
-
         let dtype = q.dtype();
         let device = q.device().clone();
-        let attn_output = self.attn.forward(
+        let attn_output = self.attn.forward_with_rotary_embedding(
             q,
             k,
             v,
+            positions,
+            &self.cos_sin_cache,
+            false,
             cache.map(|(k, _)| k.clone()),
             cache.map(|(_, v)| v.clone()),
             input_metadata,
@@ -256,43 +288,83 @@ impl CausalSelfAttention {
         self.o_proj.forward(&attn_output).map_err(APIError::from)
     }
 
-    fn load(vb: VarBuilder, cfg: &Config, dtype: DType, device: &Device) -> Result<Self, APIError> {
-        let size_in = cfg.hidden_size;
-        let size_q = (cfg.hidden_size / cfg.num_attention_heads) * cfg.num_attention_heads;
-        let size_kv = (cfg.hidden_size / cfg.num_attention_heads) * cfg.num_key_value_heads;
-        let q_proj = try_api!(linear(size_in, size_q, vb.pp("q_proj")));
-        let k_proj = try_api!(linear(size_in, size_kv, vb.pp("k_proj")));
-        let v_proj = try_api!(linear(size_in, size_kv, vb.pp("v_proj")));
-        let o_proj = try_api!(linear(size_q, size_in, vb.pp("o_proj")));
-
+    /// Load the heads of a tensor-parallel rank from its weights `rank_vb` onto its device, `vb`
+    /// holding the weights of the first rank.
+    fn load(
+        vb: VarBuilder,
+        rank_vb: VarBuilder,
+        cfg: &Config,
+        cos_sin_cache: Tensor,
+    ) -> Result<Self, APIError> {
+        let num_attention_heads = cfg.num_attention_heads / cfg.tensor_parallel_size;
+        let num_key_value_heads = cfg.num_key_value_heads / cfg.tensor_parallel_size;
         let head_dim = cfg.hidden_size / cfg.num_attention_heads;
+        let size_in = cfg.hidden_size;
+        let size_q = head_dim * num_attention_heads;
+        let size_kv = head_dim * num_key_value_heads;
+        let device = rank_vb.device().clone();
+        let q_proj = try_api!(linear(size_in, size_q, rank_vb.pp("q_proj")));
+        let k_proj = try_api!(linear(size_in, size_kv, rank_vb.pp("k_proj")));
+        let v_proj = try_api!(linear(size_in, size_kv, rank_vb.pp("v_proj")));
+        let o_proj = try_api!(linear(size_q, size_in, rank_vb.pp("o_proj")));
+
+        let kv_cache_scales = match Self::load_kv_cache_scales(&vb, num_key_value_heads)? {
+            Some(scales) => Some(try_api!(scales.to_device(&device))),
+            None => None,
+        };
         Ok(Self {
             q_proj,
             k_proj,
             v_proj,
             o_proj,
-            num_attention_heads: cfg.num_attention_heads,
-            num_key_value_heads: cfg.num_key_value_heads,
+            num_attention_heads,
+            num_key_value_heads,
             head_dim,
             attn: PagedAttention::new(
-                cfg.num_attention_heads,
+                num_attention_heads,
                 head_dim,
                 1. / ((head_dim as f32).sqrt()),
-                Some(cfg.num_key_value_heads),
+                Some(num_key_value_heads),
                 None,
-                vb.device().clone(),
+                device.clone(),
                 None,
             )
-            .map_err(APIError::from)?,
-            cos_sin_cache: Self::compute_cos_sin_cache(cfg, device, dtype)?,
+            .map_err(APIError::from)?
+            .with_sparse_attention(cfg.sparse_attention.clone())
+            .with_kv_cache_scales(kv_cache_scales)
+            // Keys are re-rotated with the unscaled frequencies, so attention sinks are not
+            // supported with RoPE scaling.
+            .with_rope_theta(cfg.rope_scaling.is_none().then_some(cfg.rope_theta))
+            .with_logits_soft_cap(cfg.attn_logit_softcapping),
+            cos_sin_cache: try_api!(cos_sin_cache.to_device(&device)),
         })
     }
+
+    /// Load the scalar `k_scale` and `v_scale` of checkpoints calibrated for an FP8 KV cache,
+    /// broadcast to shape [2, num_kv_heads].
+    fn load_kv_cache_scales(
+        vb: &VarBuilder,
+        num_kv_heads: usize,
+    ) -> Result<Option<Tensor>, APIError> {
+        if !vb.contains_tensor("k_scale") || !vb.contains_tensor("v_scale") {
+            return Ok(None);
+        }
+        let scales = ["k_scale", "v_scale"]
+            .into_iter()
+            .map(|name| {
+                vb.get((), name)?
+                    .to_dtype(DType::F32)?
+                    .broadcast_as(num_kv_heads)
+            })
+            .collect::<candle_core::Result<Vec<_>>>();
+        Ok(Some(try_api!(Tensor::stack(&try_api!(scales), 0))))
+    }
 }
 
 struct Mlp {
-    c_fc1: Linear,
-    c_fc2: Linear,
-    c_proj: Linear,
+    c_fc1: QuantLinear,
+    c_fc2: QuantLinear,
+    c_proj: QuantLinear,
     span: tracing::Span,
 }
 
@@ -303,13 +375,14 @@ impl Mlp {
         self.c_proj.forward(&x)
     }
 
-    fn load(vb: VarBuilder, cfg: &Config) -> candle_core::Result<Self> {
+    /// Load the slice of the MLP of a tensor-parallel rank from its weights onto its device.
+    fn load(vb: VarBuilder, cfg: &Config) -> Result<Self, APIError> {
         let span = tracing::span!(tracing::Level::TRACE, "mlp");
         let h_size = cfg.hidden_size;
-        let i_size = cfg.intermediate_size;
-        let c_fc1 = linear(h_size, i_size, vb.pp("gate_proj"))?;
-        let c_fc2 = linear(h_size, i_size, vb.pp("up_proj"))?;
-        let c_proj = linear(i_size, h_size, vb.pp("down_proj"))?;
+        let i_size = cfg.intermediate_size / cfg.tensor_parallel_size;
+        let c_fc1 = try_api!(linear(h_size, i_size, vb.pp("gate_proj")));
+        let c_fc2 = try_api!(linear(h_size, i_size, vb.pp("up_proj")));
+        let c_proj = try_api!(linear(i_size, h_size, vb.pp("down_proj")));
         Ok(Self {
             c_fc1,
             c_fc2,
@@ -319,48 +392,107 @@ impl Mlp {
     }
 }
 
-struct Block {
-    rms_1: RmsNorm,
-    attn: CausalSelfAttention,
+pub(crate) struct Block {
+    /// None in the draft layer of EAGLE, whose input is not normalized, see `pipelines::eagle`.
+    rms_1: Option<RmsNorm>,
+    /// The heads of each tensor-parallel rank.
+    attn: Vec<CausalSelfAttention>,
     rms_2: RmsNorm,
-    mlp: Mlp,
+    /// The slice of the MLP of each tensor-parallel rank.
+    mlp: Vec<Mlp>,
     span: tracing::Span,
 }
 
 impl Block {
-    fn forward(
+    /// `cache` holds the KV caches of the model and the index of this layer in them.
+    pub(crate) fn forward(
         &mut self,
         x: &Tensor,
         positions: &Tensor,
-        cache: Option<(&Tensor, &Tensor)>,
+        cache: Option<(&KVCacheManager, usize)>,
         input_metadata: &mut InputMetadata,
     ) -> Result<Tensor, APIError> {
         let _enter = self.span.enter();
         let residual = x;
-        let x = try_api!(self.rms_1.forward(x));
-        let x = try_api!(
-            (try_api!(self.attn.forward(&x, positions, input_metadata, cache)) + residual)
-        );
+        let x = match &self.rms_1 {
+            Some(rms_1) => try_api!(rms_1.forward(x)),
+            None => x.clone(),
+        };
+        let attn_output = sum_over_ranks(
+            &x,
+            &mut self.attn,
+            input_metadata,
+            |rank, attn, x, metadata| {
+                let positions = try_api!(positions.to_device(x.device()));
+                let cache = cache.map(|(caches, layer)| caches.rank(rank).layer(layer).key_value());
+                attn.forward(x, &positions, metadata, cache)
+            },
+        )?;
+        let x = try_api!(attn_output + residual);
         let residual = &x;
-        let x =
-            try_api!((try_api!(self.mlp.forward(&try_api!(self.rms_2.forward(&x))))) + residual);
-        Ok(x)
+        let mlp_input = try_api!(self.rms_2.forward(&x));
+        let mlp_output =
+            sum_over_ranks(&mlp_input, &mut self.mlp, input_metadata, |_, mlp, x, _| {
+                mlp.forward(x).map_err(APIError::from)
+            })?;
+        Ok(try_api!(mlp_output + residual))
     }
 
-    fn load(vb: VarBuilder, cfg: &Config, dtype: DType, device: &Device) -> Result<Self, APIError> {
-        let span = tracing::span!(tracing::Level::TRACE, "block");
-        let attn = try_api!(CausalSelfAttention::load(
-            vb.pp("self_attn"),
-            cfg,
-            dtype,
-            device
-        ));
-        let mlp = try_api!(Mlp::load(vb.pp("mlp"), cfg));
+    pub(crate) fn load(
+        vb: VarBuilder,
+        cfg: &Config,
+        cos_sin_cache: Tensor,
+    ) -> Result<Self, APIError> {
+        Self::load_sharded(&[vb], cfg, cos_sin_cache)
+    }
+
+    /// A layer split over the tensor-parallel ranks, `ranks` holding the weights of the layer on
+    /// each rank, those of the first rank with the weights which are not split.
+    fn load_sharded(
+        ranks: &[VarBuilder],
+        cfg: &Config,
+        cos_sin_cache: Tensor,
+    ) -> Result<Self, APIError> {
         let rms_1 = try_api!(RmsNorm::load(
             cfg.hidden_size,
             cfg.rms_norm_eps,
-            vb.pp("input_layernorm")
+            ranks[0].pp("input_layernorm")
         ));
+        Self::load_with_input_norm(ranks, cfg, cos_sin_cache, Some(rms_1))
+    }
+
+    /// A layer without the input norm.
+    pub(crate) fn load_without_input_norm(
+        vb: VarBuilder,
+        cfg: &Config,
+        cos_sin_cache: Tensor,
+    ) -> Result<Self, APIError> {
+        Self::load_with_input_norm(&[vb], cfg, cos_sin_cache, None)
+    }
+
+    fn load_with_input_norm(
+        ranks: &[VarBuilder],
+        cfg: &Config,
+        cos_sin_cache: Tensor,
+        rms_1: Option<RmsNorm>,
+    ) -> Result<Self, APIError> {
+        let span = tracing::span!(tracing::Level::TRACE, "block");
+        let vb = &ranks[0];
+        let attn = ranks
+            .iter()
+            .map(|rank_vb| {
+                CausalSelfAttention::load(
+                    vb.pp("self_attn"),
+                    rank_vb.pp("self_attn"),
+                    cfg,
+                    cos_sin_cache.clone(),
+                )
+            })
+            .collect::<Result<Vec<_>, APIError>>()?;
+        let mlp = ranks
+            .iter()
+            .map(|rank_vb| Mlp::load(rank_vb.pp("mlp"), cfg))
+            .collect::<Result<Vec<_>, APIError>>()?;
         let rms_2 = try_api!(RmsNorm::load(
             cfg.hidden_size,
             cfg.rms_norm_eps,
@@ -376,12 +508,36 @@ impl Block {
     }
 }
 
+/// A decoder layer, on the device or offloaded to the CPU, see `offload`.
+enum DecoderLayer {
+    Resident(Block),
+    Offloaded {
+        weights: OffloadedLayer,
+        cos_sin_cache: Tensor,
+    },
+}
+
 pub struct Llama {
     wte: Embedding,
-    blocks: Vec<Block>,
+    blocks: Vec<DecoderLayer>,
     ln_f: RmsNorm,
-    lm_head: Linear,
+    lm_head: QuantLinear,
     cfg: Config,
+    /// The device of each tensor-parallel rank.
+    rank_devices: Vec<Device>,
+    /// The layers and the device of each pipeline stage.
+    stages: Vec<(Range<usize>, Device)>,
+    offload_stats: OffloadStats,
+}
+
+/// A micro-batch on its way through the pipeline stages.
+struct InFlightMicroBatch {
+    /// The hidden states after the last stage it went through.
+    x: Tensor,
+    logits_indices: Tensor,
+    micro_batch: MicroBatch,
+    /// The attention metadata on the devices of the other stages.
+    stage_metadata: Vec<InputMetadata>,
 }
 
 impl Llama {
@@ -389,38 +545,272 @@ impl Llama {
         &mut self,
         x: &Tensor,
         positions: &Tensor,
-        kv_caches: Option<&Vec<(Tensor, Tensor)>>,
+        kv_caches: Option<&KVCacheManager>,
         input_metadata: &mut InputMetadata,
     ) -> Result<Tensor, APIError> {
-        let (_b_sz, seq_len) = try_api!(x.dims2());
-        let mut x = try_api!(self.wte.forward(x));
-        if let Some(kv_caches) = kv_caches {
-            for ((k_cache, v_cache), block) in zip(kv_caches.iter(), &mut self.blocks) {
-                x = block.forward(&x, positions, Some((k_cache, v_cache)), input_metadata)?;
-            }
-        } else {
-            for block in &mut self.blocks {
-                x = block.forward(&x, positions, None, input_metadata)?;
+        let x = self.embed(x)?;
+        self.forward_embeds(x, positions, kv_caches, input_metadata)
+    }
+
+    /// The token embeddings, shape = [batch_size, seq_len, hidden_size].
+    pub fn embed(&self, x: &Tensor) -> Result<Tensor, APIError> {
+        self.wte.forward(x).map_err(APIError::from)
+    }
+
+    /// Run the model on input embeddings instead of tokens, e.g. with the image embeddings of a
+    /// vision-language model spliced in, see `Llava`.
+    pub fn forward_embeds(
+        &mut self,
+        x: Tensor,
+        positions: &Tensor,
+        kv_caches: Option<&KVCacheManager>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let (b_sz, seq_len, _) = try_api!(x.dims3());
+        let logits_indices = input_metadata.logits_indices(b_sz * seq_len, x.device())?;
+        input_metadata.ranks = self.rank_devices[1..]
+            .iter()
+            .map(|device| input_metadata.to_device(device))
+            .collect::<Result<Vec<_>, APIError>>()?;
+        let x = self.forward_stages(x, positions, kv_caches, input_metadata);
+        input_metadata.ranks.clear();
+        self.forward_head(x?, &logits_indices, input_metadata)
+    }
+
+    /// Run the micro-batches of a step through the pipeline stages in a wavefront, see
+    /// `pipeline_parallel`.
+    fn forward_micro_batches(
+        &mut self,
+        micro_batches: Vec<MicroBatch>,
+        kv_caches: Option<&KVCacheManager>,
+    ) -> Result<Vec<Tensor>, APIError> {
+        let num_micro_batches = micro_batches.len();
+        let mut in_flight = micro_batches
+            .into_iter()
+            .map(|micro_batch| {
+                let x = self.embed(&micro_batch.tokens)?;
+                let num_tokens = micro_batch.tokens.elem_count();
+                Ok(InFlightMicroBatch {
+                    logits_indices: micro_batch
+                        .metadata
+                        .logits_indices(num_tokens, x.device())?,
+                    stage_metadata: self.stage_metadata(&micro_batch.metadata)?,
+                    x,
+                    micro_batch,
+                })
+            })
+            .collect::<Result<Vec<_>, APIError>>()?;
+        for tick in wavefront(self.stages.len(), num_micro_batches) {
+            for (stage, i) in tick {
+                let state = &mut in_flight[i];
+                let metadata = match stage {
+                    0 => &mut state.micro_batch.metadata,
+                    stage => &mut state.stage_metadata[stage - 1],
+                };
+                state.x = self.forward_stage(
+                    stage,
+                    &state.x,
+                    &state.micro_batch.positions,
+                    kv_caches,
+                    metadata,
+                )?;
             }
         }
+        in_flight
+            .into_iter()
+            .map(|mut state| {
+                let x = try_api!(state.x.to_device(&self.stages[0].1));
+                self.forward_head(x, &state.logits_indices, &mut state.micro_batch.metadata)
+            })
+            .collect()
+    }
+
+    /// The attention metadata of a step on the devices of the stages after the first.
+    fn stage_metadata(
+        &self,
+        input_metadata: &InputMetadata,
+    ) -> Result<Vec<InputMetadata>, APIError> {
+        self.stages[1..]
+            .iter()
+            .map(|(_, device)| input_metadata.to_device(device))
+            .collect()
+    }
+
+    /// Run the decoder layers of all stages one after another, returning the hidden states on
+    /// the first device.
+    fn forward_stages(
+        &mut self,
+        mut x: Tensor,
+        positions: &Tensor,
+        kv_caches: Option<&KVCacheManager>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let mut stage_metadata = self.stage_metadata(input_metadata)?;
+        for stage in 0..self.stages.len() {
+            let metadata = match stage {
+                0 => &mut *input_metadata,
+                stage => &mut stage_metadata[stage - 1],
+            };
+            x = self.forward_stage(stage, &x, positions, kv_caches, metadata)?;
+        }
+        Ok(try_api!(x.to_device(&self.stages[0].1)))
+    }
+
+    /// Run the decoder layers of `stage` on its device, with `input_metadata` on that device.
+    fn forward_stage(
+        &mut self,
+        stage: usize,
+        x: &Tensor,
+        positions: &Tensor,
+        kv_caches: Option<&KVCacheManager>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let (layers, device) = &self.stages[stage];
+        let mut x = try_api!(x.to_device(device));
+        let positions = try_api!(positions.to_device(device));
+        let kv_caches = kv_caches.map(|caches| caches.stage(stage));
+        for (i, layer) in self.blocks[layers.clone()].iter_mut().enumerate() {
+            let cache = kv_caches.map(|cache| (cache, i));
+            let index = layers.start + i;
+            x = match layer {
+                DecoderLayer::Resident(block) => {
+                    block.forward(&x, &positions, cache, input_metadata)?
+                }
+                DecoderLayer::Offloaded {
+                    weights,
+                    cos_sin_cache,
+                } => {
+                    let mut block = weights.upload(&mut self.offload_stats, |vb| {
+                        Block::load(
+                            vb.pp(format!("model.layers.{index}")),
+                            &self.cfg,
+                            cos_sin_cache.clone(),
+                        )
+                    })?;
+                    block.forward(&x, &positions, cache, input_metadata)?
+                }
+            };
+        }
+        Ok(x)
+    }
+
+    /// The logits of the final hidden states `x` at `logits_indices`.
+    fn forward_head(
+        &self,
+        x: Tensor,
+        logits_indices: &Tensor,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
         let x = try_api!(self.ln_f.forward(&x));
-        let x = try_api!(x.i((.., seq_len - 1, ..)));
-        let logits = try_api!(self.lm_head.forward(&x));
+        let x = try_api!(x.flatten_to(1));
+        input_metadata.keep_hidden_states(&x);
+        let x = try_api!(x.index_select(logits_indices, 0));
+        self.compute_logits(&x)
+    }
+
+    /// The logits of final hidden states, shape = [num_tokens, vocab_size].
+    pub fn compute_logits(&self, x: &Tensor) -> Result<Tensor, APIError> {
+        let logits = try_api!(self.lm_head.forward(x));
+        let logits = soft_cap_logits(logits, self.cfg.final_logit_softcapping)?;
         logits.to_dtype(DType::F32).map_err(APIError::from)
     }
 
     pub fn load(
-        vb: VarBuilder,
+        weights: ModelWeights,
         cfg: &Config,
         dtype: DType,
         device: &Device,
     ) -> candle_core::Result<Self> {
+        let ModelWeights {
+            vb,
+            ranks,
+            stages: stage_weights,
+            mut offloaded_layers,
+        } = weights;
+        let tensor_parallel_size = cfg.tensor_parallel_size;
+        if cfg.num_attention_heads % tensor_parallel_size != 0
+            || cfg.num_key_value_heads % tensor_parallel_size != 0
+        {
+            candle_core::bail!(
+                "The attention and KV heads cannot be split evenly over {tensor_parallel_size} devices."
+            );
+        }
+        let pipeline_parallel_size = cfg.pipeline_parallel_size;
+        if tensor_parallel_size > 1 && pipeline_parallel_size > 1 {
+            candle_core::bail!("Tensor and pipeline parallelism cannot be combined.");
+        }
+        if cfg.num_hidden_layers < pipeline_parallel_size {
+            candle_core::bail!(
+                "{} layers cannot be split into {pipeline_parallel_size} pipeline stages.",
+                cfg.num_hidden_layers
+            );
+        }
+        if ranks.len() + 1 != tensor_parallel_size {
+            candle_core::bail!(
+                "The weights of {} of {tensor_parallel_size} tensor-parallel ranks were loaded.",
+                ranks.len() + 1
+            );
+        }
+        if stage_weights.len() + 1 != pipeline_parallel_size {
+            candle_core::bail!(
+                "The weights of {} of {pipeline_parallel_size} pipeline stages were loaded.",
+                stage_weights.len() + 1
+            );
+        }
+        let rank_devices = rank_devices(device, tensor_parallel_size)
+            .map_err(|e| candle_core::Error::Msg(e.to_string()))?;
+        let stage_devices = rank_devices(device, pipeline_parallel_size)
+            .map_err(|e| candle_core::Error::Msg(e.to_string()))?;
+        let stages = stage_layers(cfg.num_hidden_layers, pipeline_parallel_size)
+            .into_iter()
+            .zip(stage_devices)
+            .collect::<Vec<_>>();
         let wte = embedding(cfg, vb.pp("model.embed_tokens"))?;
         let lm_head = linear(cfg.hidden_size, cfg.vocab_size, vb.pp("lm_head"))?;
         let ln_f = RmsNorm::load(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("model.norm"))?;
-        let blocks: Vec<_> = (0..cfg.num_hidden_layers)
-            .map(|i| Block::load(vb.pp(&format!("model.layers.{i}")), cfg, dtype, device).unwrap())
-            .collect();
+        // The cos/sin cache is shared by all layers.
+        let cos_sin_cache = compute_cos_sin_cache(
+            cfg.hidden_size / cfg.num_attention_heads,
+            cfg.rope_theta,
+            cfg.max_position_embeddings,
+            cfg.rope_scaling.as_ref(),
+            dtype,
+            device,
+        )
+        .unwrap();
+        // Tensor and pipeline parallelism are not combined, so the first stage holds the ranks.
+        let first_stage = std::iter::once(vb.clone()).chain(ranks).collect::<Vec<_>>();
+        let stage_ranks = std::iter::once(first_stage)
+            .chain(stage_weights.into_iter().map(|vb| vec![vb]))
+            .collect::<Vec<_>>();
+        let mut blocks = Vec::with_capacity(cfg.num_hidden_layers);
+        for ((layers, _), ranks) in stages.iter().zip(&stage_ranks) {
+            for i in layers.clone() {
+                let layer = match offloaded_layers.remove(&i) {
+                    Some(weights) => DecoderLayer::Offloaded {
+                        weights,
+                        cos_sin_cache: cos_sin_cache.clone(),
+                    },
+                    None => {
+                        let ranks = ranks
+                            .iter()
+                            .map(|vb| vb.pp(format!("model.layers.{i}")))
+                            .collect::<Vec<_>>();
+                        let block = Block::load_sharded(&ranks, cfg, cos_sin_cache.clone())
+                            .map_err(|e| candle_core::Error::Msg(e.to_string()))?;
+                        DecoderLayer::Resident(block)
+                    }
+                };
+                blocks.push(layer);
+            }
+        }
+        let mut offload_stats = OffloadStats::default();
+        for layer in &blocks {
+            if let DecoderLayer::Offloaded { weights, .. } = layer {
+                offload_stats.num_offloaded_layers += 1;
+                offload_stats.offloaded_bytes += weights.size_in_bytes();
+            }
+        }
 
         Ok(Self {
             wte,
@@ -428,6 +818,9 @@ impl Llama {
             ln_f,
             lm_head,
             cfg: cfg.clone(),
+            rank_devices,
+            stages,
+            offload_stats,
         })
     }
 
@@ -435,3 +828,74 @@ impl Llama {
         &self.cfg
     }
 }
+
+impl PagedAttentionModel for Llama {
+    fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        positions: &Tensor,
+        kv_caches: Option<&KVCacheManager>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        Llama::forward(self, input_ids, positions, kv_caches, input_metadata)
+    }
+
+    fn forward_micro_batches(
+        &mut self,
+        mut micro_batches: Vec<MicroBatch>,
+        kv_caches: Option<&KVCacheManager>,
+    ) -> Result<Vec<Tensor>, APIError> {
+        if self.stages.len() > 1 {
+            return Llama::forward_micro_batches(self, micro_batches, kv_caches);
+        }
+        micro_batches
+            .iter_mut()
+            .map(|micro_batch| {
+                Llama::forward(
+                    self,
+                    &micro_batch.tokens,
+                    &micro_batch.positions,
+                    kv_caches,
+                    &mut micro_batch.metadata,
+                )
+            })
+            .collect()
+    }
+
+    fn embed_tokens(&self, input_ids: &Tensor) -> Result<Tensor, APIError> {
+        self.embed(input_ids)
+    }
+
+    fn compute_logits(&self, hidden_states: &Tensor) -> Result<Tensor, APIError> {
+        Llama::compute_logits(self, hidden_states)
+    }
+
+    fn get_config(&self) -> Box<dyn ConfigLike> {
+        Box::new(self.cfg.clone())
+    }
+
+    fn offload_stats(&self) -> Option<OffloadStats> {
+        (self.offload_stats.num_offloaded_layers > 0).then_some(self.offload_stats)
+    }
+}
+
+impl LoadablePagedAttentionModel for Llama {
+    fn load_from_config(
+        config: &[u8],
+        vb: VarBuilder,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self, APIError> {
+        Self::load_from_weights(config, vb.into(), dtype, device)
+    }
+
+    fn load_from_weights(
+        config: &[u8],
+        weights: ModelWeights,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self, APIError> {
+        let config: LlamaConfig = try_api!(serde_json::from_slice(config));
+        Llama::load(weights, &config.into_config(), dtype, device).map_err(APIError::from)
+    }
+}
@@ -0,0 +1,268 @@
+/// LLaVA, a Llama LLM reading the CLIP embeddings of images,
+/// https://github.com/huggingface/transformers/blob/main/src/transformers/models/llava/modeling_llava.py
+use candle_core::{DType, Device, Module, Tensor};
+use candle_nn::VarBuilder;
+use serde::Deserialize;
+
+use crate::openai::responses::APIError;
+use crate::paged_attention::input_metadata::InputMetadata;
+use crate::scheduler::kv_cache_manager::KVCacheManager;
+use crate::try_api;
+
+use super::clip::{ClipVisionConfig, ClipVisionTower};
+use super::llama::{self, Llama, LlamaConfig};
+use super::quantization::{linear, QuantLinear};
+use super::{
+    merge_image_embeddings, ConfigLike, ImageDims, LoadablePagedAttentionModel, PagedAttentionModel,
+};
+
+#[derive(Deserialize)]
+pub struct LlavaConfig {
+    /// The config of the language model, which only gives the values differing from the defaults
+    /// of `LlamaConfig` in Transformers.
+    #[serde(default)]
+    pub text_config: serde_json::Map<String, serde_json::Value>,
+    pub vision_config: ClipVisionConfig,
+    #[serde(default = "default_image_token_index")]
+    pub image_token_index: u32,
+    #[serde(default = "default_projector_hidden_act")]
+    pub projector_hidden_act: String,
+    /// The layer of the vision tower whose hidden states are projected, negative values count
+    /// from the end.
+    #[serde(default = "default_vision_feature_layer")]
+    pub vision_feature_layer: isize,
+    /// "default" drops the class embedding of the hidden states, "full" keeps it.
+    #[serde(default = "default_vision_feature_select_strategy")]
+    pub vision_feature_select_strategy: String,
+    #[serde(default)]
+    pub quantization_config: Option<serde_json::Value>,
+}
+
+fn default_image_token_index() -> u32 {
+    32000
+}
+
+fn default_projector_hidden_act() -> String {
+    "gelu".to_string()
+}
+
+fn default_vision_feature_layer() -> isize {
+    -2
+}
+
+fn default_vision_feature_select_strategy() -> String {
+    "default".to_string()
+}
+
+/// The defaults of `LlamaConfig` in Transformers, which the `text_config` of a LLaVA checkpoint
+/// is applied to.
+fn default_text_config() -> serde_json::Value {
+    serde_json::json!({
+        "vocab_size": 32000,
+        "hidden_size": 4096,
+        "intermediate_size": 11008,
+        "num_hidden_layers": 32,
+        "num_attention_heads": 32,
+        "rms_norm_eps": 1e-6,
+        "rope_theta": 10000.0,
+        "max_position_embeddings": 2048,
+    })
+}
+
+impl LlavaConfig {
+    pub fn into_config(self) -> Result<Config, APIError> {
+        let serde_json::Value::Object(mut text_config) = default_text_config() else {
+            unreachable!()
+        };
+        text_config.extend(self.text_config);
+        if let Some(quantization_config) = self.quantization_config {
+            text_config.insert("quantization_config".to_string(), quantization_config);
+        }
+        let text_config: LlamaConfig = try_api!(serde_json::from_value(serde_json::Value::Object(
+            text_config
+        )));
+        let num_layers = self.vision_config.num_hidden_layers;
+        let vision_layers = if self.vision_feature_layer < 0 {
+            (num_layers + 1).checked_add_signed(self.vision_feature_layer)
+        } else {
+            Some(self.vision_feature_layer as usize)
+        };
+        let Some(vision_layers) = vision_layers.filter(|layers| *layers <= num_layers) else {
+            return Err(APIError::new(format!(
+                "The vision tower has no layer {}.",
+                self.vision_feature_layer
+            )));
+        };
+        let keep_class_embedding = match self.vision_feature_select_strategy.as_str() {
+            "default" => false,
+            "full" => true,
+            strategy => {
+                return Err(APIError::new(format!(
+                    "Unsupported vision feature select strategy `{strategy}`."
+                )))
+            }
+        };
+        if self.projector_hidden_act != "gelu" {
+            return Err(APIError::new(format!(
+                "Unsupported projector activation `{}`.",
+                self.projector_hidden_act
+            )));
+        }
+        let num_image_tokens = self.vision_config.num_patches() + usize::from(keep_class_embedding);
+        Ok(Config {
+            image_dims: ImageDims {
+                image_size: self.vision_config.image_size,
+                num_image_tokens,
+            },
+            text: text_config.into_config(),
+            vision: self.vision_config,
+            image_token_id: self.image_token_index,
+            vision_layers,
+            keep_class_embedding,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct Config {
+    pub text: llama::Config,
+    pub vision: ClipVisionConfig,
+    pub image_dims: ImageDims,
+    pub image_token_id: u32,
+    /// Number of layers of the vision tower whose output is projected.
+    pub vision_layers: usize,
+    pub keep_class_embedding: bool,
+}
+
+impl ConfigLike for Config {
+    fn get_num_kv_heads(&self) -> usize {
+        self.text.get_num_kv_heads()
+    }
+    fn get_hidden_size(&self) -> usize {
+        self.text.get_hidden_size()
+    }
+    fn get_num_hidden_layers(&self) -> usize {
+        self.text.get_num_hidden_layers()
+    }
+    fn get_num_attention_heads(&self) -> usize {
+        self.text.get_num_attention_heads()
+    }
+    fn get_vocab_size(&self) -> usize {
+        self.text.get_vocab_size()
+    }
+    fn get_sliding_window(&self) -> Option<usize> {
+        self.text.get_sliding_window()
+    }
+    fn get_image_dims(&self) -> Option<ImageDims> {
+        Some(self.image_dims)
+    }
+}
+
+/// Projects the hidden states of the vision tower into the embedding space of the LLM.
+struct MultiModalProjector {
+    linear_1: QuantLinear,
+    linear_2: QuantLinear,
+}
+
+impl MultiModalProjector {
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        self.linear_2
+            .forward(&self.linear_1.forward(x)?.gelu_erf()?)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config) -> candle_core::Result<Self> {
+        let (vision_size, text_size) = (cfg.vision.hidden_size, cfg.text.hidden_size);
+        Ok(Self {
+            linear_1: linear(vision_size, text_size, vb.pp("linear_1"))?,
+            linear_2: linear(text_size, text_size, vb.pp("linear_2"))?,
+        })
+    }
+}
+
+pub struct Llava {
+    vision_tower: ClipVisionTower,
+    multi_modal_projector: MultiModalProjector,
+    language_model: Llama,
+    cfg: Config,
+    span: tracing::Span,
+}
+
+impl Llava {
+    /// The embeddings of the images of the step, one row per image token, shape =
+    /// [num_images * num_image_tokens, hidden_size].
+    fn encode_images(&self, input_metadata: &InputMetadata) -> Result<Option<Tensor>, APIError> {
+        let Some(images) = &input_metadata.images else {
+            return Ok(None);
+        };
+        let x = try_api!(self.vision_tower.forward(&images.pixel_values));
+        let x = if self.cfg.keep_class_embedding {
+            x
+        } else {
+            try_api!(x.narrow(1, 1, self.cfg.vision.num_patches()))
+        };
+        let x = try_api!(self.multi_modal_projector.forward(&x));
+        Ok(Some(try_api!(x.reshape(((), self.cfg.text.hidden_size)))))
+    }
+
+    pub fn load(
+        vb: VarBuilder,
+        cfg: &Config,
+        dtype: DType,
+        device: &Device,
+    ) -> candle_core::Result<Self> {
+        let span = tracing::span!(tracing::Level::TRACE, "llava");
+        let vision_tower = ClipVisionTower::load(
+            vb.pp("vision_tower.vision_model"),
+            &cfg.vision,
+            cfg.vision_layers,
+        )?;
+        let multi_modal_projector = MultiModalProjector::load(vb.pp("multi_modal_projector"), cfg)?;
+        let language_model = Llama::load(vb.pp("language_model").into(), &cfg.text, dtype, device)?;
+        Ok(Self {
+            vision_tower,
+            multi_modal_projector,
+            language_model,
+            cfg: cfg.clone(),
+            span,
+        })
+    }
+}
+
+impl PagedAttentionModel for Llava {
+    fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        positions: &Tensor,
+        kv_caches: Option<&KVCacheManager>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let _enter = self.span.enter();
+        let image_embeds = self.encode_images(input_metadata)?;
+        let embeds = self.language_model.embed(input_ids)?;
+        let embeds = merge_image_embeddings(
+            input_ids,
+            embeds,
+            image_embeds,
+            self.cfg.image_token_id,
+            input_metadata,
+        )?;
+        self.language_model
+            .forward_embeds(embeds, positions, kv_caches, input_metadata)
+    }
+
+    fn get_config(&self) -> Box<dyn ConfigLike> {
+        Box::new(self.cfg.clone())
+    }
+}
+
+impl LoadablePagedAttentionModel for Llava {
+    fn load_from_config(
+        config: &[u8],
+        vb: VarBuilder,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self, APIError> {
+        let config: LlavaConfig = try_api!(serde_json::from_slice(config));
+        Llava::load(vb, &config.into_config()?, dtype, device).map_err(APIError::from)
+    }
+}
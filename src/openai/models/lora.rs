@@ -0,0 +1,225 @@
+//! PEFT LoRA adapters. The low-rank update `scale * B @ A` of each adapted linear layer is either
+//! merged into its weight while loading, or applied at runtime next to the base layer, which also
+//! works for quantized base weights, see `LoraMode`.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use candle_core::{safetensors::MmapedSafetensors, DType, Device, Module, Tensor};
+use candle_nn::VarBuilder;
+use serde::Deserialize;
+
+use crate::{
+    openai::{
+        pipelines::{get_token, hub::HubRepo},
+        responses::APIError,
+    },
+    try_api,
+};
+
+use super::{gguf::GGML_DTYPE_SUFFIX, quantization::QuantLinear};
+
+/// How the adapter is applied.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LoraMode {
+    /// Add the update to the weights while loading, at no runtime cost. Layers with quantized
+    /// checkpoint weights fall back to `runtime`.
+    #[default]
+    Merge,
+    /// Compute the low-rank update in each forward pass next to the unchanged base layer.
+    Runtime,
+}
+
+/// `adapter_config.json` of a PEFT adapter.
+#[derive(Debug, Clone, Deserialize)]
+struct LoraConfig {
+    r: usize,
+    lora_alpha: f64,
+    /// Scale by `lora_alpha / sqrt(r)` instead of `lora_alpha / r`.
+    #[serde(default)]
+    use_rslora: bool,
+    /// Whether the adapted weights are stored as [in_dim, out_dim] (GPT-2 `Conv1D`).
+    #[serde(default)]
+    fan_in_fan_out: bool,
+}
+
+/// A loaded LoRA adapter, with the A and B matrices of each adapted linear layer by the name of
+/// the layer in the base model, e.g. "model.layers.0.self_attn.q_proj".
+pub struct LoraAdapter {
+    scale: f64,
+    /// (A, B): shape = [r, in_dim] and [out_dim, r], on the CPU in the dtype of the adapter.
+    weights: HashMap<String, (Tensor, Tensor)>,
+}
+
+impl LoraAdapter {
+    /// Load the adapter in the local directory `path`, or else the Hub repository `path`.
+    pub fn load(
+        path: &str,
+        hf_token: Option<String>,
+        hf_token_path: Option<String>,
+    ) -> Result<Self, APIError> {
+        let (config_filename, weights_filename) = if Path::new(path).is_dir() {
+            let dir = PathBuf::from(path);
+            (
+                dir.join("adapter_config.json"),
+                dir.join("adapter_model.safetensors"),
+            )
+        } else {
+            let repo = HubRepo::new(path, None, get_token(hf_token, hf_token_path)?)?;
+            (
+                repo.get("adapter_config.json")?,
+                repo.get("adapter_model.safetensors")?,
+            )
+        };
+        let config: LoraConfig = try_api!(serde_json::from_slice(&try_api!(std::fs::read(
+            config_filename
+        ))));
+        if config.fan_in_fan_out {
+            return Err(APIError::new_str(
+                "LoRA adapters of `fan_in_fan_out` (Conv1D) layers are not supported.",
+            ));
+        }
+        let scale = if config.use_rslora {
+            config.lora_alpha / (config.r as f64).sqrt()
+        } else {
+            config.lora_alpha / config.r as f64
+        };
+
+        let safetensors = try_api!(unsafe { MmapedSafetensors::new(weights_filename) });
+        let mut weights = HashMap::new();
+        for (name, _) in safetensors.tensors() {
+            let Some(layer) = name.strip_suffix(".lora_A.weight") else {
+                continue;
+            };
+            let b_name = format!("{layer}.lora_B.weight");
+            let a = try_api!(safetensors.load(&name, &Device::Cpu));
+            let b = try_api!(safetensors.load(&b_name, &Device::Cpu));
+            // PEFT prefixes the names of the base model with `base_model.model.`.
+            let layer = layer.strip_prefix("base_model.model.").unwrap_or(layer);
+            weights.insert(layer.to_string(), (a, b));
+        }
+        if weights.is_empty() {
+            return Err(APIError::new(format!(
+                "The LoRA adapter `{path}` adapts no linear layers."
+            )));
+        }
+        Ok(Self { scale, weights })
+    }
+}
+
+/// The rank of the update of an adapted linear layer whose update is applied at runtime, next to
+/// its `lora_A.weight`, `lora_B.weight` and `lora_scale`, see `apply_lora_adapter`.
+const LORA_RANK: &str = "lora_rank";
+
+const LORA_SCALE: &str = "lora_scale";
+
+/// Apply `adapter` to the loaded `tensors` of a model. The update of each adapted layer with an
+/// unquantized weight is added to the weight in `Merge` mode. Otherwise its A and B matrices, rank
+/// and scale are stored next to the weight, and the linear layer applies it at runtime, see
+/// `lora_weights`.
+pub(crate) fn apply_lora_adapter(
+    tensors: &mut HashMap<String, Tensor>,
+    adapter: &LoraAdapter,
+    mode: LoraMode,
+) -> Result<(), APIError> {
+    for (layer, (a, b)) in &adapter.weights {
+        let weight_name = format!("{layer}.weight");
+        let quantized = tensors.contains_key(&format!("{layer}.qweight"))
+            || tensors.contains_key(&format!("{layer}.weight_scale"))
+            || tensors.contains_key(&format!("{weight_name}{GGML_DTYPE_SUFFIX}"));
+        if !quantized && !tensors.contains_key(&weight_name) {
+            // The layer is not in these weights, e.g. in those of another pipeline stage.
+            continue;
+        }
+        if mode == LoraMode::Merge && !quantized {
+            let weight = &tensors[&weight_name];
+            let delta = LoraWeights {
+                a: try_api!(try_api!(a.to_dtype(weight.dtype())).to_device(weight.device())),
+                b: try_api!(try_api!(b.to_dtype(weight.dtype())).to_device(weight.device())),
+                scale: adapter.scale,
+            }
+            .delta();
+            let weight = try_api!(weight + try_api!(delta));
+            tensors.insert(weight_name, weight);
+            continue;
+        }
+        let rank = try_api!(a.dim(0)) as u32;
+        tensors.insert(format!("{layer}.lora_A.weight"), a.clone());
+        tensors.insert(format!("{layer}.lora_B.weight"), b.clone());
+        tensors.insert(
+            format!("{layer}.{LORA_RANK}"),
+            try_api!(Tensor::new(&[rank], &Device::Cpu)),
+        );
+        tensors.insert(
+            format!("{layer}.{LORA_SCALE}"),
+            try_api!(Tensor::new(&[adapter.scale], &Device::Cpu)),
+        );
+    }
+    Ok(())
+}
+
+/// The update of the linear layer of `vb` applied at runtime, if any: A, B and the scale, with A
+/// and B in the dtype and on the device of `vb`.
+pub(crate) fn lora_weights(
+    vb: &VarBuilder,
+    in_dim: usize,
+    out_dim: usize,
+) -> candle_core::Result<Option<LoraWeights>> {
+    if !vb.contains_tensor(LORA_RANK) {
+        return Ok(None);
+    }
+    let rank = vb
+        .get_with_hints_dtype(1, LORA_RANK, Default::default(), DType::U32)?
+        .to_vec1::<u32>()?[0] as usize;
+    let scale = vb
+        .get_with_hints_dtype(1, LORA_SCALE, Default::default(), DType::F64)?
+        .to_vec1::<f64>()?[0];
+    Ok(Some(LoraWeights {
+        a: vb.get((rank, in_dim), "lora_A.weight")?,
+        b: vb.get((out_dim, rank), "lora_B.weight")?,
+        scale,
+    }))
+}
+
+pub(crate) struct LoraWeights {
+    a: Tensor,
+    b: Tensor,
+    scale: f64,
+}
+
+impl LoraWeights {
+    /// The update of the weight, `scale * B @ A`, shape = [out_dim, in_dim].
+    fn delta(&self) -> candle_core::Result<Tensor> {
+        self.b.matmul(&self.a)? * self.scale
+    }
+}
+
+/// A linear layer with a LoRA update applied at runtime: `base(x) + scale * (x @ A^T) @ B^T`.
+pub struct LoraLinear {
+    base: QuantLinear,
+    lora: LoraWeights,
+    span: tracing::Span,
+}
+
+impl LoraLinear {
+    pub(crate) fn new(base: QuantLinear, lora: LoraWeights) -> Self {
+        Self {
+            base,
+            lora,
+            span: tracing::span!(tracing::Level::TRACE, "lora-linear"),
+        }
+    }
+}
+
+impl Module for LoraLinear {
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let _enter = self.span.enter();
+        let out = self.base.forward(x)?;
+        let update = x
+            .broadcast_matmul(&self.lora.a.t()?)?
+            .broadcast_matmul(&self.lora.b.t()?)?;
+        out + (update * self.lora.scale)?
+    }
+}
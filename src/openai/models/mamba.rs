@@ -0,0 +1,439 @@
+/// Mamba state-space LLM, https://github.com/huggingface/transformers/blob/main/src/transformers/models/mamba/modeling_mamba.py
+use candle_core::{DType, Device, Tensor};
+use candle_nn::{Embedding, Module, VarBuilder};
+use candle_transformers::models::with_tracing::Linear;
+use serde::Deserialize;
+
+use crate::backend::{causal_conv1d, selective_scan};
+use crate::openai::responses::APIError;
+use crate::paged_attention::input_metadata::InputMetadata;
+use crate::scheduler::kv_cache_manager::{KVCacheManager, LayerSsmState};
+use crate::scheduler::state_cache::SsmDims;
+use crate::try_api;
+
+use super::quantization::{linear_b, linear_no_bias as linear, QuantLinear, QuantizationConfig};
+use super::{ConfigLike, LoadablePagedAttentionModel, PagedAttentionModel};
+
+/// The rank of the time step projection, `"auto"` for `ceil(hidden_size / 16)`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum TimeStepRank {
+    Auto(String),
+    Rank(usize),
+}
+
+#[derive(Deserialize)]
+pub struct MambaConfig {
+    pub hidden_size: usize,
+    pub vocab_size: usize,
+    pub num_hidden_layers: usize,
+    #[serde(default = "default_state_size")]
+    pub state_size: usize,
+    #[serde(default = "default_expand")]
+    pub expand: usize,
+    #[serde(default)]
+    pub intermediate_size: Option<usize>,
+    #[serde(default = "default_conv_kernel")]
+    pub conv_kernel: usize,
+    #[serde(default)]
+    pub time_step_rank: Option<TimeStepRank>,
+    #[serde(default = "default_layer_norm_epsilon")]
+    pub layer_norm_epsilon: f64,
+    #[serde(default)]
+    pub use_bias: bool,
+    #[serde(default = "default_true")]
+    pub use_conv_bias: bool,
+    #[serde(default = "default_true")]
+    pub tie_word_embeddings: bool,
+    #[serde(default)]
+    pub quantization_config: Option<QuantizationConfig>,
+}
+
+fn default_state_size() -> usize {
+    16
+}
+
+fn default_expand() -> usize {
+    2
+}
+
+fn default_conv_kernel() -> usize {
+    4
+}
+
+fn default_layer_norm_epsilon() -> f64 {
+    1e-5
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl MambaConfig {
+    pub fn into_config(self) -> Config {
+        let time_step_rank = match self.time_step_rank {
+            Some(TimeStepRank::Rank(rank)) => rank,
+            Some(TimeStepRank::Auto(_)) | None => self.hidden_size.div_ceil(16),
+        };
+        Config {
+            hidden_size: self.hidden_size,
+            vocab_size: self.vocab_size,
+            num_hidden_layers: self.num_hidden_layers,
+            d_inner: self
+                .intermediate_size
+                .unwrap_or(self.expand * self.hidden_size),
+            d_state: self.state_size,
+            d_conv: self.conv_kernel,
+            time_step_rank,
+            layer_norm_epsilon: self.layer_norm_epsilon,
+            use_bias: self.use_bias,
+            use_conv_bias: self.use_conv_bias,
+            tie_word_embeddings: self.tie_word_embeddings,
+            quantization_config: self.quantization_config,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Config {
+    pub hidden_size: usize,
+    pub vocab_size: usize,
+    pub num_hidden_layers: usize,
+    pub d_inner: usize,
+    pub d_state: usize,
+    pub d_conv: usize,
+    pub time_step_rank: usize,
+    pub layer_norm_epsilon: f64,
+    pub use_bias: bool,
+    pub use_conv_bias: bool,
+    pub tie_word_embeddings: bool,
+    pub quantization_config: Option<QuantizationConfig>,
+}
+
+impl ConfigLike for Config {
+    fn get_num_kv_heads(&self) -> usize {
+        1
+    }
+    fn get_hidden_size(&self) -> usize {
+        self.hidden_size
+    }
+    fn get_num_hidden_layers(&self) -> usize {
+        self.num_hidden_layers
+    }
+    fn get_num_attention_heads(&self) -> usize {
+        1
+    }
+    fn get_vocab_size(&self) -> usize {
+        self.vocab_size
+    }
+    fn get_sliding_window(&self) -> Option<usize> {
+        None
+    }
+    fn get_ssm_dims(&self) -> Option<SsmDims> {
+        Some(SsmDims {
+            num_layers: self.num_hidden_layers,
+            d_inner: self.d_inner,
+            d_conv: self.d_conv,
+            d_state: self.d_state,
+        })
+    }
+    fn get_num_kv_cache_layers(&self) -> usize {
+        0
+    }
+}
+
+fn embedding(cfg: &Config, vb: VarBuilder) -> candle_core::Result<Embedding> {
+    let embeddings = vb.get((cfg.vocab_size, cfg.hidden_size), "weight")?;
+    Ok(Embedding::new(embeddings, cfg.hidden_size))
+}
+
+struct RmsNorm {
+    inner: candle_nn::RmsNorm,
+    span: tracing::Span,
+}
+
+impl RmsNorm {
+    fn load(size: usize, eps: f64, vb: VarBuilder) -> candle_core::Result<Self> {
+        let span = tracing::span!(tracing::Level::TRACE, "rms-norm");
+        let inner = candle_nn::rms_norm(size, eps, vb)?;
+        Ok(Self { inner, span })
+    }
+
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let _enter = self.span.enter();
+        self.inner.forward(x)
+    }
+}
+
+/// The selective state-space mixer, which takes the place of attention. Its convolution and SSM
+/// states are carried between steps in the state slot of each sequence.
+struct Mixer {
+    in_proj: QuantLinear,
+    /// The depthwise convolution, shape = [d_inner, d_conv].
+    conv_weight: Tensor,
+    conv_bias: Option<Tensor>,
+    x_proj: QuantLinear,
+    dt_proj: QuantLinear,
+    /// `-exp(A_log)`, shape = [d_inner, d_state] (f32).
+    a: Tensor,
+    /// shape = [d_inner] (f32).
+    d: Tensor,
+    out_proj: QuantLinear,
+    d_inner: usize,
+    d_state: usize,
+    d_conv: usize,
+    time_step_rank: usize,
+}
+
+impl Mixer {
+    fn forward(
+        &self,
+        x: &Tensor,
+        state: Option<&LayerSsmState>,
+        input_metadata: &InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let num_tokens = try_api!(x.dim(0));
+        let device = x.device();
+        let xz = try_api!(self.in_proj.forward(x));
+        let u = try_api!(xz.narrow(1, 0, self.d_inner));
+        let z = try_api!(xz.narrow(1, self.d_inner, self.d_inner));
+
+        // Without state slots (during profiling), the step is scanned as a single sequence from a
+        // zero state, which is not kept.
+        let (cu_seqlens, slots, num_prompts) = match (&input_metadata.state, state) {
+            (Some(step), Some(_)) => (
+                step.cu_seqlens.clone(),
+                step.slots.clone(),
+                step.num_prompts,
+            ),
+            _ => (
+                try_api!(Tensor::new(&[0u32, num_tokens as u32], device)),
+                try_api!(Tensor::new(&[-1i64], device)),
+                1,
+            ),
+        };
+        let (conv_state, ssm_state) = match state {
+            Some(state) => (state.conv_state.clone(), state.ssm_state.clone()),
+            None => (
+                try_api!(Tensor::zeros(
+                    (0, self.d_inner, self.d_conv - 1),
+                    x.dtype(),
+                    device
+                )),
+                try_api!(Tensor::zeros(
+                    (0, self.d_inner, self.d_state),
+                    DType::F32,
+                    device
+                )),
+            ),
+        };
+
+        let u = unsafe {
+            causal_conv1d(
+                &u,
+                &self.conv_weight,
+                self.conv_bias.as_ref(),
+                &conv_state,
+                &cu_seqlens,
+                &slots,
+                num_prompts,
+            )?
+        };
+        let x_dbl = try_api!(self.x_proj.forward(&u));
+        let dt = try_api!(x_dbl.narrow(1, 0, self.time_step_rank));
+        let b = try_api!(x_dbl.narrow(1, self.time_step_rank, self.d_state));
+        let c = try_api!(x_dbl.narrow(1, self.time_step_rank + self.d_state, self.d_state));
+        // The softplus of the time step is applied by the scan.
+        let delta = try_api!(self.dt_proj.forward(&dt));
+
+        let y = unsafe {
+            selective_scan(
+                &u,
+                &delta,
+                &self.a,
+                &b,
+                &c,
+                &self.d,
+                &z,
+                &ssm_state,
+                &cu_seqlens,
+                &slots,
+                num_prompts,
+            )?
+        };
+        self.out_proj.forward(&y).map_err(APIError::from)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config) -> Result<Self, APIError> {
+        let (d_inner, d_state, d_conv) = (cfg.d_inner, cfg.d_state, cfg.d_conv);
+        let conv_weight = try_api!(vb.get((d_inner, 1, d_conv), "conv1d.weight"));
+        let conv_bias = if cfg.use_conv_bias {
+            Some(try_api!(vb.get(d_inner, "conv1d.bias")))
+        } else {
+            None
+        };
+        let a_log = try_api!(vb.get((d_inner, d_state), "A_log"));
+        let a = try_api!(try_api!(try_api!(a_log.to_dtype(DType::F32)).exp()).neg());
+        let d = try_api!(try_api!(vb.get(d_inner, "D")).to_dtype(DType::F32));
+        Ok(Self {
+            in_proj: try_api!(linear_b(
+                cfg.hidden_size,
+                2 * d_inner,
+                cfg.use_bias,
+                vb.pp("in_proj")
+            )),
+            conv_weight: try_api!(conv_weight.reshape((d_inner, d_conv))),
+            conv_bias,
+            x_proj: try_api!(linear(
+                d_inner,
+                cfg.time_step_rank + 2 * d_state,
+                vb.pp("x_proj")
+            )),
+            dt_proj: try_api!(linear_b(
+                cfg.time_step_rank,
+                d_inner,
+                true,
+                vb.pp("dt_proj")
+            )),
+            a,
+            d,
+            out_proj: try_api!(linear_b(
+                d_inner,
+                cfg.hidden_size,
+                cfg.use_bias,
+                vb.pp("out_proj")
+            )),
+            d_inner,
+            d_state,
+            d_conv,
+            time_step_rank: cfg.time_step_rank,
+        })
+    }
+}
+
+struct Block {
+    norm: RmsNorm,
+    mixer: Mixer,
+    span: tracing::Span,
+}
+
+impl Block {
+    fn forward(
+        &self,
+        x: &Tensor,
+        state: Option<&LayerSsmState>,
+        input_metadata: &InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let _enter = self.span.enter();
+        let residual = x;
+        let x = try_api!(self.norm.forward(x));
+        let x = self.mixer.forward(&x, state, input_metadata)?;
+        Ok(try_api!(x + residual))
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config) -> Result<Self, APIError> {
+        let span = tracing::span!(tracing::Level::TRACE, "block");
+        Ok(Self {
+            norm: try_api!(RmsNorm::load(
+                cfg.hidden_size,
+                cfg.layer_norm_epsilon,
+                vb.pp("norm")
+            )),
+            mixer: Mixer::load(vb.pp("mixer"), cfg)?,
+            span,
+        })
+    }
+}
+
+pub struct Mamba {
+    wte: Embedding,
+    blocks: Vec<Block>,
+    ln_f: RmsNorm,
+    lm_head: QuantLinear,
+    cfg: Config,
+}
+
+impl Mamba {
+    pub fn forward(
+        &mut self,
+        x: &Tensor,
+        _positions: &Tensor,
+        kv_caches: Option<&KVCacheManager>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let (b_sz, seq_len) = try_api!(x.dims2());
+        let logits_indices = input_metadata.logits_indices(b_sz * seq_len, x.device())?;
+        // The mixers scan the tokens of the step as a single row.
+        let mut x = try_api!(try_api!(self.wte.forward(x)).flatten_to(1));
+        for (i, block) in self.blocks.iter().enumerate() {
+            let state = kv_caches.map(|kv_caches| kv_caches.ssm_state(i));
+            x = block.forward(&x, state, input_metadata)?;
+        }
+        let x = try_api!(self.ln_f.forward(&x));
+        let x = try_api!(x.index_select(&logits_indices, 0));
+        let logits = try_api!(self.lm_head.forward(&x));
+        logits.to_dtype(DType::F32).map_err(APIError::from)
+    }
+
+    pub fn load(
+        vb: VarBuilder,
+        cfg: &Config,
+        _dtype: DType,
+        _device: &Device,
+    ) -> Result<Self, APIError> {
+        let wte = try_api!(embedding(cfg, vb.pp("backbone.embeddings")));
+        let lm_head = if cfg.tie_word_embeddings {
+            QuantLinear::Unquantized(Linear::from_weights(wte.embeddings().clone(), None))
+        } else {
+            try_api!(linear(cfg.hidden_size, cfg.vocab_size, vb.pp("lm_head")))
+        };
+        let ln_f = try_api!(RmsNorm::load(
+            cfg.hidden_size,
+            cfg.layer_norm_epsilon,
+            vb.pp("backbone.norm_f")
+        ));
+        let blocks = (0..cfg.num_hidden_layers)
+            .map(|i| Block::load(vb.pp(&format!("backbone.layers.{i}")), cfg))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            wte,
+            blocks,
+            ln_f,
+            lm_head,
+            cfg: cfg.clone(),
+        })
+    }
+
+    pub fn get_config(&self) -> &Config {
+        &self.cfg
+    }
+}
+
+impl PagedAttentionModel for Mamba {
+    fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        positions: &Tensor,
+        kv_caches: Option<&KVCacheManager>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        Mamba::forward(self, input_ids, positions, kv_caches, input_metadata)
+    }
+
+    fn get_config(&self) -> Box<dyn ConfigLike> {
+        Box::new(self.cfg.clone())
+    }
+}
+
+impl LoadablePagedAttentionModel for Mamba {
+    fn load_from_config(
+        config: &[u8],
+        vb: VarBuilder,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self, APIError> {
+        let config: MambaConfig = try_api!(serde_json::from_slice(config));
+        Mamba::load(vb, &config.into_config(), dtype, device)
+    }
+}
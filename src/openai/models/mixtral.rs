@@ -0,0 +1,573 @@
+/// Mixtral sparse mixture-of-experts LLM, https://github.com/huggingface/candle/blob/main/candle-transformers/src/models/mixtral.rs
+use candle_core::{DType, Device, Tensor};
+use candle_nn::{Embedding, Module, VarBuilder};
+use serde::Deserialize;
+use std::iter::zip;
+
+use crate::backend::{moe_grouped_gemm, MOE_TILE_SIZE};
+use crate::openai::responses::APIError;
+use crate::paged_attention::input_metadata::InputMetadata;
+use crate::paged_attention::PagedAttention;
+use crate::scheduler::kv_cache_manager::KVCacheManager;
+use crate::try_api;
+
+use super::quantization::{linear_no_bias as linear, QuantLinear, QuantizationConfig};
+use super::rotary_embedding::compute_cos_sin_cache;
+use super::{ConfigLike, LoadablePagedAttentionModel, PagedAttentionModel};
+
+pub const MAX_SEQ_LEN: usize = 32768;
+
+#[derive(Deserialize)]
+pub struct MixtralConfig {
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub vocab_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: Option<usize>,
+    pub rms_norm_eps: f64,
+    #[serde(default = "default_rope")]
+    pub rope_theta: f32,
+    #[serde(default)]
+    pub max_position_embeddings: Option<usize>,
+    #[serde(default)]
+    pub sliding_window: Option<usize>,
+    pub num_experts_per_tok: usize,
+    pub num_local_experts: usize,
+    #[serde(default)]
+    pub quantization_config: Option<QuantizationConfig>,
+}
+
+fn default_rope() -> f32 {
+    1_000_000.0
+}
+
+impl MixtralConfig {
+    pub fn into_config(self) -> Config {
+        Config {
+            hidden_size: self.hidden_size,
+            intermediate_size: self.intermediate_size,
+            vocab_size: self.vocab_size,
+            num_hidden_layers: self.num_hidden_layers,
+            num_attention_heads: self.num_attention_heads,
+            num_key_value_heads: self.num_key_value_heads.unwrap_or(self.num_attention_heads),
+            rms_norm_eps: self.rms_norm_eps,
+            rope_theta: self.rope_theta,
+            max_position_embeddings: self.max_position_embeddings.unwrap_or(MAX_SEQ_LEN),
+            sliding_window: self.sliding_window,
+            num_experts_per_tok: self.num_experts_per_tok,
+            num_local_experts: self.num_local_experts,
+            quantization_config: self.quantization_config,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Config {
+    pub hidden_size: usize,
+    /// The hidden size of each expert.
+    pub intermediate_size: usize,
+    pub vocab_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: usize,
+    pub rms_norm_eps: f64,
+    pub rope_theta: f32,
+    pub max_position_embeddings: usize,
+    pub sliding_window: Option<usize>,
+    /// The number of experts each token is routed to (top-k), 2 for Mixtral-8x7B.
+    pub num_experts_per_tok: usize,
+    pub num_local_experts: usize,
+    pub quantization_config: Option<QuantizationConfig>,
+}
+
+impl ConfigLike for Config {
+    fn get_num_kv_heads(&self) -> usize {
+        self.num_key_value_heads
+    }
+    fn get_hidden_size(&self) -> usize {
+        self.hidden_size
+    }
+    fn get_num_hidden_layers(&self) -> usize {
+        self.num_hidden_layers
+    }
+    fn get_num_attention_heads(&self) -> usize {
+        self.num_attention_heads
+    }
+    fn get_vocab_size(&self) -> usize {
+        self.vocab_size
+    }
+    fn get_sliding_window(&self) -> Option<usize> {
+        self.sliding_window
+    }
+}
+
+fn embedding(cfg: &Config, vb: VarBuilder) -> candle_core::Result<Embedding> {
+    let embeddings = vb.get((cfg.vocab_size, cfg.hidden_size), "weight")?;
+    Ok(Embedding::new(embeddings, cfg.hidden_size))
+}
+
+struct RmsNorm {
+    inner: candle_nn::RmsNorm,
+    span: tracing::Span,
+}
+
+impl RmsNorm {
+    fn load(size: usize, eps: f64, vb: VarBuilder) -> candle_core::Result<Self> {
+        let span = tracing::span!(tracing::Level::TRACE, "rms-norm");
+        let inner = candle_nn::rms_norm(size, eps, vb)?;
+        Ok(Self { inner, span })
+    }
+
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let _enter = self.span.enter();
+        self.inner.forward(x)
+    }
+}
+
+struct Attention {
+    q_proj: QuantLinear,
+    k_proj: QuantLinear,
+    v_proj: QuantLinear,
+    o_proj: QuantLinear,
+    num_attention_heads: usize,
+    num_key_value_heads: usize,
+    head_dim: usize,
+    attn: PagedAttention,
+    cos_sin_cache: Tensor,
+}
+
+impl Attention {
+    fn forward(
+        &mut self,
+        x: &Tensor,
+        positions: &Tensor,
+        input_metadata: &mut InputMetadata,
+        cache: Option<(&Tensor, &Tensor)>,
+    ) -> Result<Tensor, APIError> {
+        let (b_sz, seq_len, _) = try_api!(x.dims3());
+        let q = try_api!(self.q_proj.forward(x));
+        let k = try_api!(self.k_proj.forward(x));
+        let v = try_api!(self.v_proj.forward(x));
+
+        let q =
+            try_api!(
+                try_api!(q.reshape((b_sz, seq_len, self.num_attention_heads, self.head_dim)))
+                    .transpose(1, 2)
+            );
+        let k =
+            try_api!(
+                try_api!(k.reshape((b_sz, seq_len, self.num_key_value_heads, self.head_dim)))
+                    .transpose(1, 2)
+            );
+        let v =
+            try_api!(
+                try_api!(v.reshape((b_sz, seq_len, self.num_key_value_heads, self.head_dim)))
+                    .transpose(1, 2)
+            );
+
+        let dtype = q.dtype();
+        let device = q.device().clone();
+        let attn_output = self.attn.forward_with_rotary_embedding(
+            q,
+            k,
+            v,
+            positions,
+            &self.cos_sin_cache,
+            false,
+            cache.map(|(k, _)| k.clone()),
+            cache.map(|(_, v)| v.clone()),
+            input_metadata,
+            dtype,
+            device,
+        )?;
+
+        self.o_proj.forward(&attn_output).map_err(APIError::from)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config, cos_sin_cache: Tensor) -> Result<Self, APIError> {
+        let head_dim = cfg.hidden_size / cfg.num_attention_heads;
+        let size_q = head_dim * cfg.num_attention_heads;
+        let size_kv = head_dim * cfg.num_key_value_heads;
+        let q_proj = try_api!(linear(cfg.hidden_size, size_q, vb.pp("q_proj")));
+        let k_proj = try_api!(linear(cfg.hidden_size, size_kv, vb.pp("k_proj")));
+        let v_proj = try_api!(linear(cfg.hidden_size, size_kv, vb.pp("v_proj")));
+        let o_proj = try_api!(linear(size_q, cfg.hidden_size, vb.pp("o_proj")));
+
+        Ok(Self {
+            q_proj,
+            k_proj,
+            v_proj,
+            o_proj,
+            num_attention_heads: cfg.num_attention_heads,
+            num_key_value_heads: cfg.num_key_value_heads,
+            head_dim,
+            attn: PagedAttention::new(
+                cfg.num_attention_heads,
+                head_dim,
+                1. / ((head_dim as f32).sqrt()),
+                Some(cfg.num_key_value_heads),
+                cfg.sliding_window,
+                vb.device().clone(),
+                None,
+            )?,
+            cos_sin_cache,
+        })
+    }
+}
+
+/// The SwiGLU MLP of a single expert.
+struct Expert {
+    w1: QuantLinear,
+    w2: QuantLinear,
+    w3: QuantLinear,
+}
+
+impl Expert {
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let x = (candle_nn::ops::silu(&self.w1.forward(x)?)? * self.w3.forward(x)?)?;
+        self.w2.forward(&x)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config) -> candle_core::Result<Self> {
+        Ok(Self {
+            w1: linear(cfg.hidden_size, cfg.intermediate_size, vb.pp("w1"))?,
+            w2: linear(cfg.intermediate_size, cfg.hidden_size, vb.pp("w2"))?,
+            w3: linear(cfg.hidden_size, cfg.intermediate_size, vb.pp("w3"))?,
+        })
+    }
+}
+
+enum Experts {
+    /// Each expert runs as separate GEMMs over the tokens routed to it.
+    Separate(Vec<Expert>),
+    /// The weights of all experts, run with the grouped GEMM kernel.
+    /// w13: the stacked w1 and w3 of each expert, shape = [num_experts, 2 * intermediate, hidden]
+    /// w2: shape = [num_experts, hidden, intermediate]
+    Fused { w13: Tensor, w2: Tensor },
+}
+
+/// The tokens routed to each expert with their routing weights.
+pub(crate) struct Routing {
+    pub tokens: Vec<Vec<u32>>,
+    pub weights: Vec<Vec<f32>>,
+}
+
+/// The sparse MoE feed-forward block: every token runs through its `num_experts_per_tok`
+/// highest-scoring experts, weighted by their renormalized router probabilities.
+struct SparseMoeBlock {
+    gate: QuantLinear,
+    experts: Experts,
+    num_experts_per_tok: usize,
+    span: tracing::Span,
+}
+
+impl SparseMoeBlock {
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let _enter = self.span.enter();
+        let (b_sz, seq_len, hidden_size) = x.dims3()?;
+        let x = x.reshape((b_sz * seq_len, hidden_size))?;
+        let routing = self.route(&x)?;
+        let out = match &self.experts {
+            Experts::Separate(experts) => Self::forward_separate(&x, experts, &routing)?,
+            Experts::Fused { w13, w2 } => fused_moe_forward(&x, w13, w2, &routing)?,
+        };
+        out.reshape((b_sz, seq_len, hidden_size))
+    }
+
+    /// Top-k routing of the tokens of `x`. The router probabilities are small ([num_tokens,
+    /// num_experts]), so the selection is done on the host.
+    fn route(&self, x: &Tensor) -> candle_core::Result<Routing> {
+        let router_logits = self.gate.forward(x)?.to_dtype(DType::F32)?;
+        let probs = candle_nn::ops::softmax_last_dim(&router_logits)?.to_vec2::<f32>()?;
+        let num_experts = probs.first().map_or(0, Vec::len);
+        let mut routing = Routing {
+            tokens: vec![Vec::new(); num_experts],
+            weights: vec![Vec::new(); num_experts],
+        };
+        for (token, probs) in probs.iter().enumerate() {
+            let mut experts = (0..num_experts).collect::<Vec<_>>();
+            experts.sort_by(|&a, &b| probs[b].total_cmp(&probs[a]));
+            let experts = &experts[..self.num_experts_per_tok.min(num_experts)];
+            let sum = experts.iter().map(|&e| probs[e]).sum::<f32>();
+            for &expert in experts {
+                routing.tokens[expert].push(token as u32);
+                routing.weights[expert].push(probs[expert] / sum);
+            }
+        }
+        Ok(routing)
+    }
+
+    fn forward_separate(
+        x: &Tensor,
+        experts: &[Expert],
+        routing: &Routing,
+    ) -> candle_core::Result<Tensor> {
+        let mut out = x.zeros_like()?;
+        for (expert, (tokens, weights)) in zip(experts, zip(&routing.tokens, &routing.weights)) {
+            if tokens.is_empty() {
+                continue;
+            }
+            let tokens = Tensor::new(tokens.as_slice(), x.device())?;
+            let weights = Tensor::new(weights.as_slice(), x.device())?
+                .reshape(((), 1))?
+                .to_dtype(x.dtype())?;
+            let expert_out = expert.forward(&x.index_select(&tokens, 0)?)?;
+            out = out.index_add(&tokens, &expert_out.broadcast_mul(&weights)?, 0)?;
+        }
+        Ok(out)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config) -> candle_core::Result<Self> {
+        let span = tracing::span!(tracing::Level::TRACE, "sparse-moe");
+        let gate = linear(cfg.hidden_size, cfg.num_local_experts, vb.pp("gate"))?;
+        let experts_vb = vb.pp("experts");
+        let experts = if use_fused_moe(&experts_vb, "0.w1") {
+            let mut w13 = Vec::with_capacity(cfg.num_local_experts);
+            let mut w2 = Vec::with_capacity(cfg.num_local_experts);
+            let (hidden, intermediate) = (cfg.hidden_size, cfg.intermediate_size);
+            for i in 0..cfg.num_local_experts {
+                let vb = experts_vb.pp(&i.to_string());
+                let w1 = vb.pp("w1").get((intermediate, hidden), "weight")?;
+                let w3 = vb.pp("w3").get((intermediate, hidden), "weight")?;
+                w13.push(Tensor::cat(&[w1, w3], 0)?);
+                w2.push(vb.pp("w2").get((hidden, intermediate), "weight")?);
+            }
+            Experts::Fused {
+                w13: Tensor::stack(&w13, 0)?,
+                w2: Tensor::stack(&w2, 0)?,
+            }
+        } else {
+            Experts::Separate(
+                (0..cfg.num_local_experts)
+                    .map(|i| Expert::load(experts_vb.pp(&i.to_string()), cfg))
+                    .collect::<candle_core::Result<Vec<_>>>()?,
+            )
+        };
+        Ok(Self {
+            gate,
+            experts,
+            num_experts_per_tok: cfg.num_experts_per_tok,
+            span,
+        })
+    }
+}
+
+/// The grouped GEMM expert kernel is used on CUDA for unquantized experts. `first_proj` is the
+/// path of a projection of the first expert, e.g. "0.w1".
+pub(crate) fn use_fused_moe(experts_vb: &VarBuilder, first_proj: &str) -> bool {
+    matches!(experts_vb.device(), Device::Cuda(_))
+        && matches!(experts_vb.dtype(), DType::F16 | DType::BF16 | DType::F32)
+        && !experts_vb.contains_tensor(&format!("{first_proj}.weight_scale"))
+}
+
+/// Run all experts with two grouped GEMMs. The routed rows are grouped by expert, each group
+/// padded to a multiple of `MOE_TILE_SIZE` rows, which have zero weight.
+pub(crate) fn fused_moe_forward(
+    x: &Tensor,
+    w13: &Tensor,
+    w2: &Tensor,
+    routing: &Routing,
+) -> candle_core::Result<Tensor> {
+    let mut row_x_idx = Vec::new();
+    let mut row_weights = Vec::new();
+    let mut row_tokens = Vec::new();
+    let mut tile_experts = Vec::new();
+    for (expert, (tokens, weights)) in zip(&routing.tokens, &routing.weights).enumerate() {
+        if tokens.is_empty() {
+            continue;
+        }
+        let num_tiles = tokens.len().div_ceil(MOE_TILE_SIZE);
+        let padding = num_tiles * MOE_TILE_SIZE - tokens.len();
+        row_x_idx.extend_from_slice(tokens);
+        row_x_idx.resize(row_x_idx.len() + padding, u32::MAX);
+        row_tokens.extend_from_slice(tokens);
+        row_tokens.resize(row_tokens.len() + padding, 0);
+        row_weights.extend_from_slice(weights);
+        row_weights.resize(row_weights.len() + padding, 0.);
+        tile_experts.extend((0..num_tiles).map(|_| expert as u32));
+    }
+    let num_rows = row_x_idx.len();
+    let device = x.device();
+    let row_x_idx = Tensor::from_vec(row_x_idx, num_rows, device)?;
+    let tile_experts = Tensor::from_vec(tile_experts, num_rows / MOE_TILE_SIZE, device)?;
+    let intermediate_size = w2.dim(2)?;
+
+    let to_candle = |e: APIError| candle_core::Error::Msg(e.to_string());
+    let gate_up =
+        unsafe { moe_grouped_gemm(x, w13, &row_x_idx, &tile_experts) }.map_err(to_candle)?;
+    let hidden = (candle_nn::ops::silu(&gate_up.narrow(1, 0, intermediate_size)?)?
+        * gate_up.narrow(1, intermediate_size, intermediate_size)?)?;
+    // The hidden rows are already grouped, so the second GEMM reads them in order.
+    let rows = Tensor::arange(0u32, num_rows as u32, device)?;
+    let expert_out =
+        unsafe { moe_grouped_gemm(&hidden, w2, &rows, &tile_experts) }.map_err(to_candle)?;
+
+    let row_weights = Tensor::from_vec(row_weights, (num_rows, 1), device)?.to_dtype(x.dtype())?;
+    let row_tokens = Tensor::from_vec(row_tokens, num_rows, device)?;
+    x.zeros_like()?
+        .index_add(&row_tokens, &expert_out.broadcast_mul(&row_weights)?, 0)
+}
+
+struct Block {
+    rms_1: RmsNorm,
+    attn: Attention,
+    rms_2: RmsNorm,
+    moe: SparseMoeBlock,
+    span: tracing::Span,
+}
+
+impl Block {
+    fn forward(
+        &mut self,
+        x: &Tensor,
+        positions: &Tensor,
+        cache: Option<(&Tensor, &Tensor)>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let _enter = self.span.enter();
+        let residual = x;
+        let x = try_api!(self.rms_1.forward(x));
+        let x = try_api!(
+            (try_api!(self.attn.forward(&x, positions, input_metadata, cache)) + residual)
+        );
+        let residual = &x;
+        let x =
+            try_api!((try_api!(self.moe.forward(&try_api!(self.rms_2.forward(&x))))) + residual);
+        Ok(x)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config, cos_sin_cache: Tensor) -> Result<Self, APIError> {
+        let span = tracing::span!(tracing::Level::TRACE, "block");
+        let attn = Attention::load(vb.pp("self_attn"), cfg, cos_sin_cache)?;
+        let moe = try_api!(SparseMoeBlock::load(vb.pp("block_sparse_moe"), cfg));
+        let rms_1 = try_api!(RmsNorm::load(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            vb.pp("input_layernorm")
+        ));
+        let rms_2 = try_api!(RmsNorm::load(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            vb.pp("post_attention_layernorm"),
+        ));
+        Ok(Self {
+            rms_1,
+            attn,
+            rms_2,
+            moe,
+            span,
+        })
+    }
+}
+
+pub struct Mixtral {
+    wte: Embedding,
+    blocks: Vec<Block>,
+    ln_f: RmsNorm,
+    lm_head: QuantLinear,
+    cfg: Config,
+}
+
+impl Mixtral {
+    pub fn forward(
+        &mut self,
+        x: &Tensor,
+        positions: &Tensor,
+        kv_caches: Option<&KVCacheManager>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let (b_sz, seq_len) = try_api!(x.dims2());
+        let logits_indices = input_metadata.logits_indices(b_sz * seq_len, x.device())?;
+        let mut x = try_api!(self.wte.forward(x));
+        if let Some(kv_caches) = kv_caches {
+            for (layer, block) in zip(kv_caches.layers(), &mut self.blocks) {
+                x = block.forward(&x, positions, Some(layer.key_value()), input_metadata)?;
+            }
+        } else {
+            for block in &mut self.blocks {
+                x = block.forward(&x, positions, None, input_metadata)?;
+            }
+        }
+        let x = try_api!(self.ln_f.forward(&x));
+        let x = try_api!(x.flatten_to(1));
+        input_metadata.keep_hidden_states(&x);
+        let x = try_api!(x.index_select(&logits_indices, 0));
+        let logits = try_api!(self.lm_head.forward(&x));
+        logits.to_dtype(DType::F32).map_err(APIError::from)
+    }
+
+    /// Load the model from `vb`, which may span the sharded safetensors of the checkpoint.
+    pub fn load(
+        vb: VarBuilder,
+        cfg: &Config,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self, APIError> {
+        let wte = try_api!(embedding(cfg, vb.pp("model.embed_tokens")));
+        let lm_head = try_api!(linear(cfg.hidden_size, cfg.vocab_size, vb.pp("lm_head")));
+        let ln_f = try_api!(RmsNorm::load(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            vb.pp("model.norm")
+        ));
+        // The cos/sin cache is shared by all layers.
+        let cos_sin_cache = compute_cos_sin_cache(
+            cfg.hidden_size / cfg.num_attention_heads,
+            cfg.rope_theta,
+            cfg.max_position_embeddings,
+            None,
+            dtype,
+            device,
+        )?;
+        let blocks = (0..cfg.num_hidden_layers)
+            .map(|i| {
+                Block::load(
+                    vb.pp(&format!("model.layers.{i}")),
+                    cfg,
+                    cos_sin_cache.clone(),
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            wte,
+            blocks,
+            ln_f,
+            lm_head,
+            cfg: cfg.clone(),
+        })
+    }
+
+    pub fn get_config(&self) -> &Config {
+        &self.cfg
+    }
+}
+
+impl PagedAttentionModel for Mixtral {
+    fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        positions: &Tensor,
+        kv_caches: Option<&KVCacheManager>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        Mixtral::forward(self, input_ids, positions, kv_caches, input_metadata)
+    }
+
+    fn get_config(&self) -> Box<dyn ConfigLike> {
+        Box::new(self.cfg.clone())
+    }
+}
+
+impl LoadablePagedAttentionModel for Mixtral {
+    fn load_from_config(
+        config: &[u8],
+        vb: VarBuilder,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self, APIError> {
+        let config: MixtralConfig = try_api!(serde_json::from_slice(config));
+        Mixtral::load(vb, &config.into_config(), dtype, device)
+    }
+}
@@ -1,4 +1,44 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+};
+
+use candle_core::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+
+use crate::{
+    paged_attention::{input_metadata::InputMetadata, mla::MlaDims},
+    scheduler::{kv_cache_manager::KVCacheManager, state_cache::SsmDims},
+};
+
+use self::offload::OffloadedLayer;
+use super::responses::APIError;
+use crate::try_api;
+
+pub mod baichuan;
+pub mod bert;
+pub mod clip;
+pub mod cohere;
+pub mod deepseek;
+pub mod gemma;
+pub mod gguf;
 pub mod llama;
+pub mod llava;
+pub mod lora;
+pub mod mamba;
+pub mod mixtral;
+pub mod offload;
+pub mod olmo;
+pub mod phi3;
+pub mod pipeline_parallel;
+pub mod quantization;
+pub mod qwen2;
+pub mod qwen_vl;
+pub mod rotary_embedding;
+pub mod starcoder2;
+pub mod t5;
+pub mod tensor_parallel;
+pub mod weights;
 
 pub trait ConfigLike {
     fn get_num_kv_heads(&self) -> usize;
@@ -10,4 +50,388 @@ pub trait ConfigLike {
     fn get_head_size(&self) -> usize {
         self.get_hidden_size() / self.get_num_attention_heads()
     }
+    /// The dimensions of multi-head latent attention for models which cache a compressed KV
+    /// latent instead of per-head keys and values (DeepSeek-V2/V3), see `paged_attention::mla`.
+    fn get_mla_dims(&self) -> Option<MlaDims> {
+        None
+    }
+    /// The recurrent state of the state-space layers of Mamba and hybrid models, which is kept in
+    /// a fixed-size slot per sequence instead of the KV cache, see `scheduler::state_cache`.
+    fn get_ssm_dims(&self) -> Option<SsmDims> {
+        None
+    }
+    /// Number of layers with a KV cache. Hybrid models only cache their attention layers, pure
+    /// state-space models none.
+    fn get_num_kv_cache_layers(&self) -> usize {
+        self.get_num_hidden_layers()
+    }
+    /// The first decoder token of encoder-decoder models (T5). Their requests are encoded and
+    /// attended with cross-attention, see `InputMetadata::cross`, while the decoder generates
+    /// from this token.
+    fn get_decoder_start_token_id(&self) -> Option<u32> {
+        None
+    }
+    /// The image inputs of vision-language models (LLaVA, Qwen-VL), see `multimodal`.
+    fn get_image_dims(&self) -> Option<ImageDims> {
+        None
+    }
+    /// Encoder-only models (BERT) return the hidden states of the prompt tokens instead of
+    /// logits, which are pooled into embeddings, see `pipelines::pooling`.
+    fn is_encoder_only(&self) -> bool {
+        false
+    }
+    /// Number of devices the attention heads and the MLP of each layer are split over, see
+    /// `tensor_parallel`. Each device caches the keys and values of its share of the KV heads.
+    fn get_tensor_parallel_size(&self) -> usize {
+        1
+    }
+    /// Number of devices the decoder layers are split over in consecutive stages, see
+    /// `pipeline_parallel`. Each device caches the keys and values of the layers of its stage.
+    fn get_pipeline_parallel_size(&self) -> usize {
+        1
+    }
+    /// The outputs of the sequence classification head of encoder-only cross-encoders, which
+    /// return one row of scores per prompt instead of hidden states.
+    fn get_num_labels(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// The images taken by a vision-language model.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageDims {
+    /// The input resolution of the vision tower, in pixels per side.
+    pub image_size: usize,
+    /// Number of embeddings each image is encoded to, i.e. its image tokens in the prompt.
+    pub num_image_tokens: usize,
+}
+
+/// A model architecture which can be served with paged attention. Implement this (and
+/// `LoadablePagedAttentionModel`) and register the type with `register_paged_attention_model!`
+/// to add an architecture from outside of this crate.
+pub trait PagedAttentionModel: Send + Sync {
+    /// input_ids: shape = [batch_size, seq_len], the engine passes the tokens of a step as a
+    ///     single row, see `InputMetadata`.
+    /// positions: shape = [batch_size, seq_len]
+    /// kv_caches: the cache of each layer, None during profiling.
+    ///
+    /// Returns the logits of the tokens given by `InputMetadata::logits_indices`, i.e. the last
+    /// token of each prompt and every generation token, shape = [num_seqs, vocab_size].
+    /// Encoder-only models return hidden states instead, see `ConfigLike::is_encoder_only`.
+    fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        positions: &Tensor,
+        kv_caches: Option<&KVCacheManager>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError>;
+
+    /// Run the micro-batches of a step, returning the logits of each like `forward`. Models split
+    /// into pipeline stages overlap the micro-batches on the stages, others run them one after
+    /// another.
+    fn forward_micro_batches(
+        &mut self,
+        micro_batches: Vec<pipeline_parallel::MicroBatch>,
+        kv_caches: Option<&KVCacheManager>,
+    ) -> Result<Vec<Tensor>, APIError> {
+        micro_batches
+            .into_iter()
+            .map(|mut micro_batch| {
+                self.forward(
+                    &micro_batch.tokens,
+                    &micro_batch.positions,
+                    kv_caches,
+                    &mut micro_batch.metadata,
+                )
+            })
+            .collect()
+    }
+
+    fn get_config(&self) -> Box<dyn ConfigLike>;
+
+    /// The uploads of the layers offloaded to the CPU so far, None if no layer is offloaded.
+    fn offload_stats(&self) -> Option<offload::OffloadStats> {
+        None
+    }
+
+    /// The input embeddings of `input_ids`, shape = [batch_size, seq_len, hidden_size], e.g. for
+    /// a draft head sharing the embeddings of the model.
+    fn embed_tokens(&self, _input_ids: &Tensor) -> Result<Tensor, APIError> {
+        Err(APIError::new_str(
+            "The architecture does not expose its embeddings.",
+        ))
+    }
+
+    /// The logits of final hidden states of the model (shape = [num_tokens, hidden_size]), e.g.
+    /// predicted by a draft head, shape = [num_tokens, vocab_size].
+    fn compute_logits(&self, _hidden_states: &Tensor) -> Result<Tensor, APIError> {
+        Err(APIError::new_str(
+            "The architecture does not expose its output layer.",
+        ))
+    }
+
+    fn num_kv_heads(&self) -> usize {
+        self.get_config().get_num_kv_heads()
+    }
+
+    fn head_dim(&self) -> usize {
+        self.get_config().get_head_size()
+    }
+
+    fn sliding_window(&self) -> Option<usize> {
+        self.get_config().get_sliding_window()
+    }
+}
+
+/// The weights of a model as loaded by the pipeline. Only the weights of models split over
+/// several devices (see `tensor_parallel` and `pipeline_parallel`) or partly offloaded to the CPU
+/// (see `offload`) go beyond `vb`.
+pub struct ModelWeights<'a> {
+    /// The weights on the device of the model: those of the first rank or stage and the weights
+    /// which are not split.
+    pub vb: VarBuilder<'a>,
+    /// The weights of the other tensor-parallel ranks, in order.
+    pub ranks: Vec<VarBuilder<'a>>,
+    /// The weights of the other pipeline stages, in order.
+    pub stages: Vec<VarBuilder<'a>>,
+    /// The weights of the offloaded decoder layers by layer index.
+    pub offloaded_layers: HashMap<usize, OffloadedLayer>,
+}
+
+impl<'a> From<VarBuilder<'a>> for ModelWeights<'a> {
+    fn from(vb: VarBuilder<'a>) -> Self {
+        Self {
+            vb,
+            ranks: Vec::new(),
+            stages: Vec::new(),
+            offloaded_layers: HashMap::new(),
+        }
+    }
+}
+
+/// Construction of a `PagedAttentionModel` from the contents of its `config.json`.
+pub trait LoadablePagedAttentionModel: PagedAttentionModel + Sized {
+    fn load_from_config(
+        config: &[u8],
+        vb: VarBuilder,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self, APIError>;
+
+    /// Construct the model from all of its weights. Models which cannot be split or offloaded
+    /// only take `weights.vb`.
+    fn load_from_weights(
+        config: &[u8],
+        weights: ModelWeights,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self, APIError> {
+        Self::load_from_config(config, weights.vb, dtype, device)
+    }
+}
+
+pub type ModelConstructor =
+    fn(&[u8], ModelWeights, DType, &Device) -> Result<Box<dyn PagedAttentionModel>, APIError>;
+
+/// The constructor of the model type `M`.
+fn constructor<M: LoadablePagedAttentionModel + 'static>() -> ModelConstructor {
+    |config, weights, dtype, device| {
+        Ok(
+            Box::new(M::load_from_weights(config, weights, dtype, device)?)
+                as Box<dyn PagedAttentionModel>,
+        )
+    }
+}
+
+fn model_registry() -> &'static Mutex<HashMap<String, ModelConstructor>> {
+    static MODEL_REGISTRY: OnceLock<Mutex<HashMap<String, ModelConstructor>>> = OnceLock::new();
+    MODEL_REGISTRY.get_or_init(|| {
+        let mut registry = HashMap::new();
+        registry.insert("llama".to_string(), constructor::<llama::Llama>());
+        registry.insert("baichuan".to_string(), constructor::<baichuan::Baichuan>());
+        registry.insert("bert".to_string(), constructor::<bert::Bert>());
+        registry.insert("cohere".to_string(), constructor::<cohere::Cohere>());
+        registry.insert("deepseek".to_string(), constructor::<deepseek::DeepSeek>());
+        registry.insert("gemma".to_string(), constructor::<gemma::Gemma>());
+        registry.insert("llava".to_string(), constructor::<llava::Llava>());
+        registry.insert("mamba".to_string(), constructor::<mamba::Mamba>());
+        registry.insert("mixtral".to_string(), constructor::<mixtral::Mixtral>());
+        registry.insert("olmo".to_string(), constructor::<olmo::Olmo>());
+        registry.insert("phi3".to_string(), constructor::<phi3::Phi3>());
+        registry.insert("qwen2".to_string(), constructor::<qwen2::Qwen2>());
+        registry.insert("qwen_vl".to_string(), constructor::<qwen_vl::QwenVl>());
+        registry.insert(
+            "starcoder2".to_string(),
+            constructor::<starcoder2::StarCoder2>(),
+        );
+        registry.insert("t5".to_string(), constructor::<t5::T5>());
+        Mutex::new(registry)
+    })
+}
+
+/// Register a model constructor under `name`, replacing any previous registration.
+/// Prefer the `register_paged_attention_model!` macro.
+pub fn register_model(name: &str, constructor: ModelConstructor) {
+    model_registry()
+        .lock()
+        .unwrap()
+        .insert(name.to_string(), constructor);
+}
+
+pub fn get_model_constructor(name: &str) -> Option<ModelConstructor> {
+    model_registry().lock().unwrap().get(name).copied()
+}
+
+pub fn registered_models() -> Vec<String> {
+    model_registry().lock().unwrap().keys().cloned().collect()
+}
+
+/// The Transformers model classes (`architectures`) and `model_type`s of `config.json` with the
+/// registered model serving them.
+const ARCHITECTURES: &[(&str, &str, &str)] = &[
+    ("LlamaForCausalLM", "llama", "llama"),
+    ("MistralForCausalLM", "mistral", "llama"),
+    ("MixtralForCausalLM", "mixtral", "mixtral"),
+    ("Phi3ForCausalLM", "phi3", "phi3"),
+    ("Qwen2ForCausalLM", "qwen2", "qwen2"),
+    ("GemmaForCausalLM", "gemma", "gemma"),
+    ("Gemma2ForCausalLM", "gemma2", "gemma"),
+    ("Starcoder2ForCausalLM", "starcoder2", "starcoder2"),
+    ("DeepseekV2ForCausalLM", "deepseek_v2", "deepseek"),
+    ("DeepseekV3ForCausalLM", "deepseek_v3", "deepseek"),
+    ("CohereForCausalLM", "cohere", "cohere"),
+    ("BaichuanForCausalLM", "baichuan", "baichuan"),
+    ("OlmoForCausalLM", "olmo", "olmo"),
+    ("MambaForCausalLM", "mamba", "mamba"),
+    ("T5ForConditionalGeneration", "t5", "t5"),
+    ("LlavaForConditionalGeneration", "llava", "llava"),
+    ("QWenLMHeadModel", "qwen", "qwen_vl"),
+    ("BertModel", "bert", "bert"),
+    ("BertForSequenceClassification", "bert", "bert"),
+    ("XLMRobertaModel", "xlm-roberta", "bert"),
+    ("XLMRobertaForSequenceClassification", "xlm-roberta", "bert"),
+];
+
+#[derive(serde::Deserialize)]
+struct ArchitectureConfig {
+    #[serde(default)]
+    architectures: Vec<String>,
+    #[serde(default)]
+    model_type: Option<String>,
+}
+
+/// The registered model serving the checkpoint with `config`, detected from the `architectures`
+/// of its `config.json`, else its `model_type`. Models registered under a model class or model
+/// type are found as well.
+pub fn detect_architecture(config: &[u8]) -> Result<String, APIError> {
+    let config: ArchitectureConfig = try_api!(serde_json::from_slice(config));
+    let model_type = config.model_type.as_deref();
+    let by_class = config.architectures.iter().find_map(|class| {
+        ARCHITECTURES
+            .iter()
+            .find(|(name, _, _)| name == class)
+            .map(|(_, _, registered)| registered.to_string())
+            .or_else(|| get_model_constructor(class).map(|_| class.clone()))
+    });
+    let by_model_type = || {
+        let model_type = model_type?;
+        ARCHITECTURES
+            .iter()
+            .find(|(_, name, _)| *name == model_type)
+            .map(|(_, _, registered)| registered.to_string())
+            .or_else(|| get_model_constructor(model_type).map(|_| model_type.to_string()))
+    };
+    if let Some(architecture) = by_class.or_else(by_model_type) {
+        return Ok(architecture);
+    }
+    let mut supported = ARCHITECTURES
+        .iter()
+        .map(|(class, _, _)| class.to_string())
+        .collect::<Vec<_>>();
+    supported.extend(registered_models());
+    supported.sort();
+    supported.dedup();
+    Err(APIError::new(format!(
+        "Unsupported architecture {:?} (model type {model_type:?}). Supported architectures: {}.",
+        config.architectures,
+        supported.join(", ")
+    )))
+}
+
+/// Register a type implementing `LoadablePagedAttentionModel` under a name:
+/// `register_paged_attention_model!("my-arch", MyModel);`
+#[macro_export]
+macro_rules! register_paged_attention_model {
+    ($name:expr, $model:ty) => {
+        $crate::openai::models::register_model($name, |config, weights, dtype, device| {
+            Ok(Box::new(
+                <$model as $crate::openai::models::LoadablePagedAttentionModel>::load_from_weights(
+                    config, weights, dtype, device,
+                )?,
+            )
+                as Box<dyn $crate::openai::models::PagedAttentionModel>)
+        })
+    };
+}
+
+/// Replace the embeddings of the image tokens (`image_token_id`) of the prompt slice with the
+/// image embeddings, in order. Generated tokens are never image tokens.
+/// input_ids: shape = [batch_size, seq_len]
+/// embeds: the token embeddings, shape = [batch_size, seq_len, hidden_size]
+/// image_embeds: shape = [num_image_tokens, hidden_size], one row per image token.
+pub fn merge_image_embeddings(
+    input_ids: &Tensor,
+    embeds: Tensor,
+    image_embeds: Option<Tensor>,
+    image_token_id: u32,
+    input_metadata: &InputMetadata,
+) -> Result<Tensor, APIError> {
+    let ids = try_api!(try_api!(input_ids.flatten_all()).to_vec1::<i64>());
+    let num_tokens = ids.len();
+    let num_prompt_tokens = input_metadata.num_prompt_tokens();
+    let num_image_embeds = match &image_embeds {
+        Some(image_embeds) => try_api!(image_embeds.dim(0)),
+        None => 0,
+    };
+    let mut num_image_tokens = 0;
+    let indices = ids
+        .iter()
+        .enumerate()
+        .map(|(i, id)| {
+            if i < num_prompt_tokens && *id == i64::from(image_token_id) {
+                num_image_tokens += 1;
+                (num_tokens + num_image_tokens - 1) as u32
+            } else {
+                i as u32
+            }
+        })
+        .collect::<Vec<_>>();
+    if num_image_tokens != num_image_embeds {
+        return Err(APIError::new(format!(
+            "The prompts hold {num_image_tokens} image tokens for {num_image_embeds} image embeddings."
+        )));
+    }
+    let Some(image_embeds) = image_embeds else {
+        return Ok(embeds);
+    };
+    let shape = embeds.shape().clone();
+    let image_embeds = try_api!(image_embeds.to_dtype(embeds.dtype()));
+    let rows = try_api!(Tensor::cat(
+        &[try_api!(embeds.flatten_to(1)), image_embeds],
+        0
+    ));
+    let indices = try_api!(Tensor::from_vec(indices, num_tokens, input_ids.device()));
+    try_api!(rows.index_select(&indices, 0))
+        .reshape(shape)
+        .map_err(APIError::from)
+}
+
+/// Soft-cap the final logits to (-cap, cap) with `cap * tanh(logits / cap)`, see
+/// `final_logit_softcapping` of Gemma-2.
+pub fn soft_cap_logits(logits: Tensor, cap: Option<f32>) -> Result<Tensor, APIError> {
+    let Some(cap) = cap else {
+        return Ok(logits);
+    };
+    let cap = f64::from(cap);
+    (try_api!(try_api!(logits / cap).tanh()) * cap).map_err(APIError::from)
 }
@@ -0,0 +1,143 @@
+//! Layer-wise CPU offloading, for models slightly too big for the memory of the device: the
+//! weights of the last decoder layers stay in CPU memory and are uploaded to the device just in
+//! time, for each forward pass of a layer, and dropped after it. The KV cache of the offloaded
+//! layers stays on the device. The uploads are synchronous and their cost grows with the number
+//! of offloaded layers, so it is reported in the metrics of the engines, see `OffloadStats`.
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use candle_core::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+
+use crate::{
+    openai::{pipelines::LoadOptions, responses::APIError, utils::GIB},
+    try_api,
+};
+
+use super::{
+    weights::{apply_load_options, load_shards},
+    ModelWeights,
+};
+
+/// Cumulative statistics of the offloaded layers of a model.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OffloadStats {
+    pub num_offloaded_layers: usize,
+    /// Size in bytes of the weights of the offloaded layers.
+    pub offloaded_bytes: usize,
+    /// Bytes uploaded to the device and the time spent uploading them.
+    pub uploaded_bytes: usize,
+    pub upload_time: Duration,
+}
+
+/// The weights of an offloaded decoder layer in CPU memory.
+#[derive(Clone)]
+pub struct OffloadedLayer {
+    /// The weights under their names in the checkpoint, uploaded to the device they are
+    /// loaded on.
+    weights: VarBuilder<'static>,
+    size_in_bytes: usize,
+}
+
+impl OffloadedLayer {
+    pub fn size_in_bytes(&self) -> usize {
+        self.size_in_bytes
+    }
+
+    /// Upload the weights with `load`, e.g. into a decoder layer which is dropped after its
+    /// forward pass, recording the upload in `stats`.
+    pub(crate) fn upload<T>(
+        &self,
+        stats: &mut OffloadStats,
+        load: impl FnOnce(VarBuilder<'static>) -> Result<T, APIError>,
+    ) -> Result<T, APIError> {
+        let start = Instant::now();
+        let loaded = load(self.weights.clone())?;
+        stats.uploaded_bytes += self.size_in_bytes;
+        stats.upload_time += start.elapsed();
+        Ok(loaded)
+    }
+}
+
+/// The decoder layer of a weight by its name, e.g. "model.layers.3.mlp.up_proj.weight", None for
+/// the weights outside of the decoder layers.
+fn layer_index(name: &str) -> Option<usize> {
+    name.strip_prefix("model.layers.")?
+        .split('.')
+        .next()?
+        .parse::<usize>()
+        .ok()
+}
+
+/// Load an unquantized checkpoint of `num_layers` decoder layers onto `device`, except for the
+/// last `num_offloaded_layers` layers of `options`, which stay in CPU memory. Returns the weights
+/// on the device, and the offloaded layers in `ModelWeights::offloaded_layers`.
+pub fn load_offloaded_safetensors(
+    filenames: &[PathBuf],
+    dtype: DType,
+    device: &Device,
+    num_layers: usize,
+    options: &LoadOptions,
+) -> Result<ModelWeights<'static>, APIError> {
+    let num_offloaded_layers = options.num_offloaded_layers;
+    if num_offloaded_layers > num_layers {
+        return Err(APIError::new(format!(
+            "Cannot offload {num_offloaded_layers} of the {num_layers} layers."
+        )));
+    }
+    let first_offloaded = num_layers - num_offloaded_layers;
+    let mut tensors = load_shards(filenames, |safetensors, name| {
+        let tensor = try_api!(try_api!(safetensors.load(name, &Device::Cpu)).to_dtype(dtype));
+        let tensor = match layer_index(name) {
+            Some(layer) if layer >= first_offloaded => tensor,
+            _ => try_api!(tensor.to_device(device)),
+        };
+        Ok(vec![(name.to_string(), tensor)])
+    })?;
+    apply_load_options(&mut tensors, options)?;
+    let mut resident = HashMap::new();
+    let mut offloaded: HashMap<usize, HashMap<String, Tensor>> = HashMap::new();
+    for (name, tensor) in tensors {
+        match layer_index(&name) {
+            Some(layer) if layer >= first_offloaded => {
+                offloaded.entry(layer).or_default().insert(name, tensor);
+            }
+            _ => {
+                resident.insert(name, tensor);
+            }
+        }
+    }
+    let offloaded = offloaded
+        .into_iter()
+        .map(|(layer, tensors)| {
+            let size_in_bytes = tensors
+                .values()
+                .map(|tensor| tensor.elem_count() * tensor.dtype().size_in_bytes())
+                .sum();
+            let weights = VarBuilder::from_tensors(tensors, dtype, device);
+            (
+                layer,
+                OffloadedLayer {
+                    weights,
+                    size_in_bytes,
+                },
+            )
+        })
+        .collect::<HashMap<_, _>>();
+    let offloaded_bytes = offloaded
+        .values()
+        .map(OffloadedLayer::size_in_bytes)
+        .sum::<usize>();
+    println!(
+        "Offloading {num_offloaded_layers} layers ({:.2} GiB) to the CPU, their weights are uploaded for each forward pass.",
+        offloaded_bytes as f64 / GIB
+    );
+    Ok(ModelWeights {
+        offloaded_layers: offloaded,
+        ..ModelWeights::from(VarBuilder::from_tensors(resident, dtype, device))
+    })
+}
@@ -0,0 +1,142 @@
+//! Pipeline parallelism within the process: the decoder layers are split into consecutive stages,
+//! one per CUDA device following the device of the model, and the hidden states of a token only
+//! cross to the next device once per stage. Unlike weight sharding, which sums partial outputs
+//! twice per layer, this suits devices with weak links between them. Each stage keeps the KV cache
+//! of its layers in a pool on its device, see `KVCacheManager::stage`. The embeddings, final norm
+//! and output layer stay on the first device.
+//!
+//! A step is split into micro-batches which flow through the stages in a wavefront: at each tick,
+//! stage `s` runs micro-batch `t - s`, so all stages are busy once the pipeline is filled. The
+//! kernels of a device run asynchronously to the others, and only the copy of the hidden states
+//! into the next stage waits for the previous one.
+
+use std::{collections::HashMap, ops::Range, path::PathBuf};
+
+use candle_core::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+
+use crate::{
+    openai::{pipelines::LoadOptions, responses::APIError},
+    paged_attention::input_metadata::InputMetadata,
+    try_api,
+};
+
+use super::{
+    weights::{apply_load_options, load_shards},
+    ModelWeights,
+};
+
+/// The inputs of one micro-batch of a step, with the tokens laid out as a single row like the
+/// inputs of `PagedAttentionModel::forward`.
+pub struct MicroBatch {
+    pub tokens: Tensor,
+    pub positions: Tensor,
+    pub metadata: InputMetadata,
+}
+
+/// The layers of each of `num_stages` stages, consecutive and as even as possible, the first
+/// stages taking one more layer if they cannot be even.
+pub fn stage_layers(num_layers: usize, num_stages: usize) -> Vec<Range<usize>> {
+    let mut start = 0;
+    (0..num_stages)
+        .map(|stage| {
+            let len = num_layers / num_stages + usize::from(stage < num_layers % num_stages);
+            start += len;
+            start - len..start
+        })
+        .collect()
+}
+
+/// The stage of the decoder layer of a weight by its name, e.g. "model.layers.3.mlp.up_proj.weight",
+/// None for the weights outside of the decoder layers.
+fn layer_stage(name: &str, stages: &[Range<usize>]) -> Option<usize> {
+    let layer = name
+        .strip_prefix("model.layers.")?
+        .split('.')
+        .next()?
+        .parse::<usize>()
+        .ok()?;
+    stages.iter().position(|layers| layers.contains(&layer))
+}
+
+/// Load an unquantized checkpoint of `num_layers` decoder layers split into a stage per device of
+/// `devices`, the weights of each layer on the device of its stage. Returns the weights of the
+/// first stage with the weights outside of the decoder layers, and those of the other stages in
+/// `ModelWeights::stages`.
+pub fn load_staged_safetensors(
+    filenames: &[PathBuf],
+    dtype: DType,
+    devices: &[Device],
+    num_layers: usize,
+    options: &LoadOptions,
+) -> Result<ModelWeights<'static>, APIError> {
+    let stages = stage_layers(num_layers, devices.len());
+    let tensors = load_shards(filenames, |safetensors, name| {
+        let stage = layer_stage(name, &stages).unwrap_or(0);
+        let tensor = try_api!(try_api!(safetensors.load(name, &Device::Cpu)).to_dtype(dtype));
+        Ok(vec![(
+            format!("{stage}.{name}"),
+            try_api!(tensor.to_device(&devices[stage])),
+        )])
+    })?;
+    let mut stage_tensors = vec![HashMap::new(); devices.len()];
+    for (name, tensor) in tensors {
+        let (stage, name) = name.split_once('.').unwrap();
+        stage_tensors[stage.parse::<usize>().unwrap()].insert(name.to_string(), tensor);
+    }
+    let mut stages = stage_tensors
+        .into_iter()
+        .zip(devices)
+        .map(|(mut tensors, device)| {
+            apply_load_options(&mut tensors, options)?;
+            Ok(VarBuilder::from_tensors(tensors, dtype, device))
+        })
+        .collect::<Result<Vec<_>, APIError>>()?;
+    let vb = stages.remove(0);
+    Ok(ModelWeights {
+        stages,
+        ..ModelWeights::from(vb)
+    })
+}
+
+/// Split the sequences of a step, with `num_tokens` tokens each, into at most `num_micro_batches`
+/// consecutive micro-batches of about the same number of tokens, as the slowest micro-batch holds
+/// up every stage after it. The prompts come first in a step, so every micro-batch is again a
+/// prompt slice followed by a decode slice.
+pub fn split_micro_batches(num_tokens: &[usize], num_micro_batches: usize) -> Vec<Range<usize>> {
+    let total = num_tokens.iter().sum::<usize>();
+    let num_micro_batches = num_micro_batches.clamp(1, num_tokens.len().max(1));
+    let mut micro_batches = Vec::with_capacity(num_micro_batches);
+    let mut start = 0;
+    let mut cumulative = 0;
+    for (i, tokens) in num_tokens.iter().enumerate() {
+        cumulative += tokens;
+        // Cut once the micro-batch reaches its share of the tokens, leaving at least one
+        // sequence for each of the remaining micro-batches.
+        let target = total * (micro_batches.len() + 1) / num_micro_batches;
+        let remaining_seqs = num_tokens.len() - i - 1;
+        let remaining_batches = num_micro_batches - micro_batches.len() - 1;
+        if remaining_batches > 0 && (cumulative >= target || remaining_seqs == remaining_batches) {
+            micro_batches.push(start..i + 1);
+            start = i + 1;
+        }
+    }
+    if start < num_tokens.len() {
+        micro_batches.push(start..num_tokens.len());
+    }
+    micro_batches
+}
+
+/// The (stage, micro-batch) pairs run at each tick of the wavefront over `num_stages` stages,
+/// the later stages first, as their inputs were computed at the previous tick.
+pub fn wavefront(num_stages: usize, num_micro_batches: usize) -> Vec<Vec<(usize, usize)>> {
+    (0..num_stages + num_micro_batches.saturating_sub(1))
+        .map(|tick| {
+            (0..num_stages)
+                .rev()
+                .filter(|stage| tick >= *stage && tick - stage < num_micro_batches)
+                .map(|stage| (stage, tick - stage))
+                .collect()
+        })
+        .collect()
+}
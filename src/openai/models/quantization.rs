@@ -0,0 +1,837 @@
+//! Loading of quantized checkpoints. Currently supports FP8 (e4m3) weights with per-tensor or
+//! per-channel scales, as written by `compressed-tensors` (`float-quantized` format) and by the
+//! `fp8` quantization method, optionally with FP8 activations (static or dynamic scales), 4-bit
+//! GPTQ and AWQ weights, and the quantized linear layers of GGUF checkpoints (see `gguf`). The
+//! linear layers of unquantized checkpoints can also be quantized while loading, see
+//! `InSituQuantization`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::OnceLock,
+};
+
+use candle_core::{
+    quantized::{GgmlDType, QMatMul, QTensor},
+    safetensors::MmapedSafetensors,
+    DType, Device, Module, Tensor,
+};
+use candle_nn::VarBuilder;
+use candle_transformers::models::with_tracing::Linear;
+use clap::ValueEnum;
+use safetensors::Dtype;
+use serde::Deserialize;
+
+use crate::{
+    backend::compute_capability,
+    openai::{pipelines::LoadOptions, responses::APIError},
+    try_api,
+};
+
+use super::{
+    gguf::{gguf_linear_b, is_gguf_linear, GgufLinear},
+    lora::{lora_weights, LoraLinear},
+    weights::{apply_load_options, load_shards},
+};
+
+/// The minimum compute capability (Ada) with native FP8 conversion, on which FP8 weights are
+/// kept in FP8 and dequantized inside the GEMM.
+const MIN_FP8_COMPUTE_CAPABILITY: (i32, i32) = (8, 9);
+
+/// The Marlin-style INT4 GEMM kernel relies on the F16/BF16 tensor cores of Ampere and newer.
+const MIN_MARLIN_COMPUTE_CAPABILITY: (i32, i32) = (8, 0);
+
+/// The number of 4-bit values packed into each 32-bit word of GPTQ and AWQ weights.
+const INT4_PACK_FACTOR: usize = 8;
+
+/// The nibble of each of 8 consecutive output columns in a 32-bit word of AWQ weights.
+const AWQ_PACK_ORDER: [usize; INT4_PACK_FACTOR] = [0, 4, 1, 5, 2, 6, 3, 7];
+
+/// The largest finite FP8 e4m3 value, which the absmax of dynamically quantized activations maps
+/// to.
+const FP8_E4M3_MAX: f64 = 448.;
+
+/// Marks the FP8 linear layers whose activations are quantized with per-token scales computed at
+/// runtime, inserted next to their `weight_scale` in place of a static `input_scale`.
+const DYNAMIC_INPUT_SCALE: &str = "input_scale_dynamic";
+
+/// Marks the unquantized weights which are quantized while loading, see
+/// `mark_in_situ_quantization`.
+const IN_SITU_QUANTIZATION_SUFFIX: &str = "_in_situ_quantization";
+
+/// The number of weights sharing an absmax scale in NF4 weights.
+const NF4_BLOCK_SIZE: usize = 64;
+
+/// The 16 values of the 4-bit NormalFloat data type (QLoRA), the quantiles of a normal
+/// distribution scaled to [-1, 1].
+const NF4_CODEBOOK: [f32; 16] = [
+    -1.0,
+    -0.696_192_8,
+    -0.525_073_05,
+    -0.394_917_5,
+    -0.284_441_38,
+    -0.184_773_43,
+    -0.091_050_036,
+    0.0,
+    0.079_580_3,
+    0.160_930_2,
+    0.246_112_3,
+    0.337_915_24,
+    0.440_709_83,
+    0.562_617,
+    0.722_956_84,
+    1.0,
+];
+
+/// A format the linear layers of an unquantized checkpoint are quantized to while loading.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum InSituQuantization {
+    /// 8-bit blocks of 32 weights, run with the quantized matmul (`GgufLinear`).
+    #[value(name = "q8_0")]
+    Q8_0,
+    /// 4-bit k-quant super-blocks of 256 weights, run with the quantized matmul (`GgufLinear`).
+    #[value(name = "q4_k")]
+    Q4K,
+    /// 4-bit NormalFloat with a scale per block of 64 weights, see `Nf4Linear`.
+    Nf4,
+}
+
+impl InSituQuantization {
+    /// The number of input columns quantized together, which must divide the input dimension.
+    fn block_size(&self) -> usize {
+        match self {
+            Self::Q8_0 => GgmlDType::Q8_0.block_size(),
+            Self::Q4K => GgmlDType::Q4K.block_size(),
+            Self::Nf4 => NF4_BLOCK_SIZE,
+        }
+    }
+}
+
+/// Mark the unquantized weights of `tensors` to be quantized to `quantization` when their linear
+/// layer is loaded: `<name>_in_situ_quantization` holds the index of the format in
+/// `InSituQuantization::value_variants`. The other weights are loaded as usual.
+pub(crate) fn mark_in_situ_quantization(
+    tensors: &mut HashMap<String, Tensor>,
+    quantization: InSituQuantization,
+) -> Result<(), APIError> {
+    let code = InSituQuantization::value_variants()
+        .iter()
+        .position(|x| *x == quantization)
+        .unwrap_or(0) as u8;
+    let names = tensors
+        .iter()
+        .filter(|(name, tensor)| {
+            name.ends_with(".weight") && tensor.rank() == 2 && tensor.dtype().is_float()
+        })
+        .map(|(name, _)| name.clone())
+        .collect::<Vec<_>>();
+    for name in names {
+        let marker = try_api!(Tensor::new(&[code], &Device::Cpu));
+        tensors.insert(format!("{name}{IN_SITU_QUANTIZATION_SUFFIX}"), marker);
+    }
+    Ok(())
+}
+
+/// The format the weight of the linear layer of `vb` is quantized to while loading, if any.
+fn in_situ_quantization(vb: &VarBuilder) -> candle_core::Result<Option<InSituQuantization>> {
+    let name = format!("weight{IN_SITU_QUANTIZATION_SUFFIX}");
+    if !vb.contains_tensor(&name) {
+        return Ok(None);
+    }
+    let code = vb
+        .get_with_hints_dtype(1, &name, Default::default(), DType::U8)?
+        .to_vec1::<u8>()?[0];
+    Ok(InSituQuantization::value_variants()
+        .get(code as usize)
+        .copied())
+}
+
+/// The `quantization_config` section of a model's `config.json`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct QuantizationConfig {
+    pub quant_method: String,
+    #[serde(default)]
+    pub format: Option<String>,
+    /// The bits per weight of GPTQ and AWQ checkpoints.
+    #[serde(default, alias = "w_bit")]
+    pub bits: Option<usize>,
+    /// The number of input rows sharing a scale and zero point, -1 for one group per column.
+    #[serde(default, alias = "q_group_size")]
+    pub group_size: Option<isize>,
+    /// The kernel layout of AWQ checkpoints, only "gemm" is supported.
+    #[serde(default)]
+    pub version: Option<String>,
+    /// Whether the `fp8` method quantizes activations with the static `input_scale` of each layer
+    /// or with scales computed at runtime ("dynamic", the default).
+    #[serde(default)]
+    pub activation_scheme: Option<String>,
+    /// The quantization schemes of `compressed-tensors` checkpoints.
+    #[serde(default)]
+    pub config_groups: HashMap<String, QuantizationScheme>,
+}
+
+/// A quantization scheme of a `compressed-tensors` checkpoint.
+#[derive(Debug, Clone, Deserialize)]
+pub struct QuantizationScheme {
+    /// How activations are quantized, weight-only schemes have none.
+    #[serde(default)]
+    pub input_activations: Option<ActivationQuantization>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ActivationQuantization {
+    /// Whether the scales are computed at runtime instead of stored as `input_scale`.
+    #[serde(default)]
+    pub dynamic: bool,
+}
+
+impl QuantizationConfig {
+    pub fn is_fp8(&self) -> bool {
+        match self.quant_method.as_str() {
+            "fp8" => true,
+            "compressed-tensors" => matches!(
+                self.format.as_deref(),
+                None | Some("float-quantized") | Some("naive-quantized")
+            ),
+            _ => false,
+        }
+    }
+
+    pub fn is_gptq(&self) -> bool {
+        self.quant_method == "gptq"
+    }
+
+    pub fn is_awq(&self) -> bool {
+        self.quant_method == "awq"
+    }
+
+    /// Whether the activations of FP8 layers without a static `input_scale` are quantized with
+    /// per-token scales computed at runtime. Otherwise they stay unquantized (weight-only FP8).
+    pub fn has_dynamic_activations(&self) -> bool {
+        match self.quant_method.as_str() {
+            "fp8" => self.activation_scheme.as_deref().unwrap_or("dynamic") == "dynamic",
+            "compressed-tensors" => self.config_groups.values().any(|scheme| {
+                scheme
+                    .input_activations
+                    .as_ref()
+                    .is_some_and(|activations| activations.dynamic)
+            }),
+            _ => false,
+        }
+    }
+
+    /// Whether the packed 4-bit weights of the checkpoint are supported, see `Int4Linear`.
+    pub fn is_int4(&self) -> bool {
+        (self.is_gptq() || self.is_awq() && self.version.as_deref().unwrap_or("gemm") == "gemm")
+            && self.bits == Some(4)
+    }
+}
+
+/// Load the weights of a checkpoint which may contain quantized tensors. FP8 tensors are kept as
+/// U8 tensors holding the raw e4m3 bytes, the 4-bit weights and zero points of GPTQ and AWQ are
+/// converted to the layout of `Int4Linear` and other I32 tensors (`g_idx`) are kept as U32
+/// tensors holding the same bits; scales are converted to F32 and all other tensors to `dtype`.
+/// FP8 layers with dynamic activation scales are marked with `DYNAMIC_INPUT_SCALE`. The shards
+/// are loaded in parallel, see `weights::load_shards`.
+pub fn load_quantized_safetensors(
+    filenames: &[PathBuf],
+    quantization_config: &QuantizationConfig,
+    dtype: DType,
+    device: &Device,
+    options: &LoadOptions,
+) -> Result<VarBuilder<'static>, APIError> {
+    let names = try_api!(unsafe { MmapedSafetensors::multi(filenames) })
+        .tensors()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect::<HashSet<_>>();
+    let awq = quantization_config.is_awq();
+    let mut tensors = load_shards(filenames, |safetensors, name| {
+        let view = try_api!(safetensors.get(name));
+        let tensor = match view.dtype() {
+            Dtype::I32 if name.ends_with(".qweight") => {
+                let prefix = name.trim_end_matches(".qweight");
+                let (rows, cols) = (view.shape()[0], view.shape()[1]);
+                let words = to_words(view.data());
+                // AWQ packs along the output dimension, GPTQ along the input dimension.
+                let (words, in_dim, out_dim) = if awq {
+                    let out_dim = cols * INT4_PACK_FACTOR;
+                    (repack_awq_qweight(&words, rows, out_dim), rows, out_dim)
+                } else {
+                    (words, rows * INT4_PACK_FACTOR, cols)
+                };
+                let qweight = try_api!(Tensor::from_vec(
+                    words,
+                    (in_dim / INT4_PACK_FACTOR, out_dim),
+                    device
+                ));
+                let mut tensors = vec![(name.to_string(), qweight)];
+                if !names.contains(&format!("{prefix}.g_idx")) {
+                    let group_size = match quantization_config.group_size {
+                        Some(group_size) if group_size > 0 => group_size as usize,
+                        _ => in_dim,
+                    };
+                    let g_idx = (0..in_dim)
+                        .map(|row| (row / group_size) as u32)
+                        .collect::<Vec<_>>();
+                    let g_idx = try_api!(Tensor::from_vec(g_idx, in_dim, device));
+                    tensors.push((format!("{prefix}.g_idx"), g_idx));
+                }
+                return Ok(tensors);
+            }
+            Dtype::I32 if name.ends_with(".qzeros") => {
+                let (num_groups, cols) = (view.shape()[0], view.shape()[1]);
+                let out_dim = cols * INT4_PACK_FACTOR;
+                let zeros = unpack_zeros(&to_words(view.data()), out_dim, awq);
+                let zeros = try_api!(Tensor::from_vec(zeros, (num_groups, out_dim), device));
+                return Ok(vec![(name.replace(".qzeros", ".zeros"), zeros)]);
+            }
+            Dtype::F8_E4M3 => try_api!(Tensor::from_raw_buffer(
+                view.data(),
+                DType::U8,
+                view.shape(),
+                device
+            )),
+            Dtype::I32 => try_api!(Tensor::from_raw_buffer(
+                view.data(),
+                DType::U32,
+                view.shape(),
+                device
+            )),
+            _ if name.ends_with(".scales") => {
+                try_api!(try_api!(safetensors.load(name, device)).to_dtype(DType::F32))
+            }
+            _ if name.ends_with("_scale") => {
+                let tensor = try_api!(safetensors.load(name, device));
+                try_api!(try_api!(tensor.flatten_all()).to_dtype(DType::F32))
+            }
+            _ => try_api!(try_api!(safetensors.load(name, device)).to_dtype(dtype)),
+        };
+        Ok(vec![(name.to_string(), tensor)])
+    })?;
+    if quantization_config.has_dynamic_activations() {
+        for prefix in names
+            .iter()
+            .filter_map(|name| name.strip_suffix(".weight_scale"))
+        {
+            if !names.contains(&format!("{prefix}.input_scale")) {
+                let marker = try_api!(Tensor::new(&[1u8], device));
+                tensors.insert(format!("{prefix}.{DYNAMIC_INPUT_SCALE}"), marker);
+            }
+        }
+    }
+    apply_load_options(&mut tensors, options)?;
+    Ok(VarBuilder::from_tensors(tensors, dtype, device))
+}
+
+/// Decode an FP8 e4m3 (`fn` variant: no infinities, a single NaN encoding) byte.
+fn fp8_e4m3_to_f32(x: u8) -> f32 {
+    let sign = if x & 0x80 != 0 { -1. } else { 1. };
+    let exponent = ((x >> 3) & 0xf) as i32;
+    let mantissa = (x & 0x7) as f32;
+    if exponent == 0xf && mantissa == 7. {
+        return f32::NAN;
+    }
+    if exponent == 0 {
+        sign * (mantissa / 8.) * 2f32.powi(-6)
+    } else {
+        sign * (1. + mantissa / 8.) * 2f32.powi(exponent - 7)
+    }
+}
+
+fn fp8_lookup_table() -> &'static [f32; 256] {
+    static TABLE: OnceLock<[f32; 256]> = OnceLock::new();
+    TABLE.get_or_init(|| std::array::from_fn(|i| fp8_e4m3_to_f32(i as u8)))
+}
+
+/// Dequantize a U8-stored FP8 weight of shape [out_dim, in_dim] with a per-tensor or per-channel
+/// scale.
+fn dequantize_fp8(
+    weight: &Tensor,
+    weight_scale: &Tensor,
+    dtype: DType,
+) -> candle_core::Result<Tensor> {
+    let table = fp8_lookup_table();
+    let (out_dim, _) = weight.dims2()?;
+    let values = weight
+        .flatten_all()?
+        .to_vec1::<u8>()?
+        .into_iter()
+        .map(|x| table[x as usize])
+        .collect::<Vec<_>>();
+    let weight = Tensor::from_vec(values, weight.shape(), weight.device())?;
+    let weight_scale = if weight_scale.elem_count() == out_dim {
+        weight_scale.reshape((out_dim, 1))?
+    } else {
+        weight_scale.clone()
+    };
+    weight.broadcast_mul(&weight_scale)?.to_dtype(dtype)
+}
+
+fn use_fused_fp8_gemm(device: &Device, dtype: DType) -> bool {
+    match device {
+        Device::Cuda(dev) => {
+            matches!(dtype, DType::F16 | DType::BF16 | DType::F32)
+                && compute_capability(dev.ordinal())
+                    .is_ok_and(|capability| capability >= MIN_FP8_COMPUTE_CAPABILITY)
+        }
+        _ => false,
+    }
+}
+
+/// How the activations of an FP8 linear layer are quantized.
+enum Fp8Activations {
+    /// Weight-only FP8, the activations stay in the model dtype.
+    Unquantized,
+    /// The per-tensor `input_scale` calibrated with the checkpoint.
+    Static(Tensor),
+    /// Per-token scales mapping the absmax of each row to `FP8_E4M3_MAX`.
+    Dynamic,
+}
+
+/// A linear layer holding FP8 weights, computed with the fused FP8 GEMM kernel, with FP8
+/// activations (W8A8) if the checkpoint quantizes them.
+pub struct Fp8Linear {
+    weight: Tensor,
+    weight_scale: Tensor,
+    activations: Fp8Activations,
+    bias: Option<Tensor>,
+    span: tracing::Span,
+}
+
+impl Module for Fp8Linear {
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let _enter = self.span.enter();
+        let (out_dim, in_dim) = self.weight.dims2()?;
+        let mut out_shape = x.dims().to_vec();
+        *out_shape.last_mut().unwrap() = out_dim;
+        let x = x.reshape(((), in_dim))?;
+        let x_scale = match &self.activations {
+            Fp8Activations::Unquantized => None,
+            Fp8Activations::Static(input_scale) => Some(input_scale.clone()),
+            Fp8Activations::Dynamic => Some(
+                (x.abs()?
+                    .max_keepdim(1)?
+                    .to_dtype(DType::F32)?
+                    .flatten_all()?
+                    / FP8_E4M3_MAX)?,
+            ),
+        };
+        let out = unsafe {
+            match &x_scale {
+                Some(x_scale) => {
+                    crate::backend::fp8_w8a8_gemm(&x, x_scale, &self.weight, &self.weight_scale)
+                }
+                None => crate::backend::fp8_gemm(&x, &self.weight, &self.weight_scale),
+            }
+        }
+        .map_err(|e| candle_core::Error::Msg(e.to_string()))?;
+        let out = match &self.bias {
+            Some(bias) => out.broadcast_add(bias)?,
+            None => out,
+        };
+        out.reshape(out_shape)
+    }
+}
+
+pub enum QuantLinear {
+    Unquantized(Linear),
+    Fp8(Fp8Linear),
+    Int4(Int4Linear),
+    Gguf(GgufLinear),
+    Nf4(Nf4Linear),
+    Lora(Box<LoraLinear>),
+}
+
+impl Module for QuantLinear {
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        match self {
+            Self::Unquantized(linear) => linear.forward(x),
+            Self::Fp8(linear) => linear.forward(x),
+            Self::Int4(linear) => linear.forward(x),
+            Self::Gguf(linear) => linear.forward(x),
+            Self::Nf4(linear) => linear.forward(x),
+            Self::Lora(linear) => linear.forward(x),
+        }
+    }
+}
+
+/// Load a linear layer without bias. FP8 weights (recognized by an accompanying `weight_scale`)
+/// are run with the fused FP8 GEMM on GPUs which support it, with FP8 activations if the layer
+/// has an `input_scale` or dynamic activation scales, and dequantized to the model dtype
+/// otherwise. GPTQ and AWQ weights (`qweight`) are likewise dequantized inside the GEMM on CUDA
+/// devices.
+/// Quantized GGUF weights are run with the quantized matmul, see `gguf`. Unquantized weights are
+/// quantized while loading if marked, see `mark_in_situ_quantization`. The layers adapted by a
+/// LoRA adapter whose update could not be merged get it at runtime, see `lora::apply_lora_adapter`.
+pub fn linear_no_bias(
+    in_dim: usize,
+    out_dim: usize,
+    vb: VarBuilder,
+) -> candle_core::Result<QuantLinear> {
+    linear_b(in_dim, out_dim, false, vb)
+}
+
+/// Load a linear layer with bias, see `linear_no_bias`.
+pub fn linear(in_dim: usize, out_dim: usize, vb: VarBuilder) -> candle_core::Result<QuantLinear> {
+    linear_b(in_dim, out_dim, true, vb)
+}
+
+/// Load a linear layer with or without bias, see `linear_no_bias`.
+pub fn linear_b(
+    in_dim: usize,
+    out_dim: usize,
+    bias: bool,
+    vb: VarBuilder,
+) -> candle_core::Result<QuantLinear> {
+    let Some(lora) = lora_weights(&vb, in_dim, out_dim)? else {
+        return base_linear_b(in_dim, out_dim, bias, vb);
+    };
+    let base = base_linear_b(in_dim, out_dim, bias, vb)?;
+    Ok(QuantLinear::Lora(Box::new(LoraLinear::new(base, lora))))
+}
+
+/// Load a linear layer of the checkpoint.
+fn base_linear_b(
+    in_dim: usize,
+    out_dim: usize,
+    bias: bool,
+    vb: VarBuilder,
+) -> candle_core::Result<QuantLinear> {
+    if is_gguf_linear(&vb) {
+        return Ok(QuantLinear::Gguf(gguf_linear_b(in_dim, out_dim, bias, vb)?));
+    }
+    if vb.contains_tensor("qweight") {
+        return int4_linear_b(in_dim, out_dim, bias, vb);
+    }
+    if !vb.contains_tensor("weight_scale") {
+        return unquantized_linear_b(in_dim, out_dim, bias, vb);
+    }
+    let bias = if bias {
+        Some(vb.get(out_dim, "bias")?)
+    } else {
+        None
+    };
+    let weight =
+        vb.get_with_hints_dtype((out_dim, in_dim), "weight", Default::default(), DType::U8)?;
+    let weight_scale = vb
+        .get_with_hints_dtype(out_dim, "weight_scale", Default::default(), DType::F32)
+        .or_else(|_| vb.get_with_hints_dtype(1, "weight_scale", Default::default(), DType::F32))?;
+    if use_fused_fp8_gemm(vb.device(), vb.dtype()) {
+        let activations = if vb.contains_tensor("input_scale") {
+            Fp8Activations::Static(vb.get_with_hints_dtype(
+                1,
+                "input_scale",
+                Default::default(),
+                DType::F32,
+            )?)
+        } else if vb.contains_tensor(DYNAMIC_INPUT_SCALE) {
+            Fp8Activations::Dynamic
+        } else {
+            Fp8Activations::Unquantized
+        };
+        Ok(QuantLinear::Fp8(Fp8Linear {
+            weight,
+            weight_scale,
+            activations,
+            bias,
+            span: tracing::span!(tracing::Level::TRACE, "fp8-linear"),
+        }))
+    } else {
+        let weight = dequantize_fp8(&weight, &weight_scale, vb.dtype())?;
+        Ok(QuantLinear::Unquantized(Linear::from_weights(weight, bias)))
+    }
+}
+
+/// Load an unquantized linear layer, and quantize it if marked.
+fn unquantized_linear_b(
+    in_dim: usize,
+    out_dim: usize,
+    bias: bool,
+    vb: VarBuilder,
+) -> candle_core::Result<QuantLinear> {
+    let bias = if bias {
+        Some(vb.get(out_dim, "bias")?)
+    } else {
+        None
+    };
+    let weight = vb.get((out_dim, in_dim), "weight")?;
+    match in_situ_quantization(&vb)? {
+        Some(quantization) if in_dim % quantization.block_size() == 0 => {
+            quantize_linear(quantization, &weight, bias)
+        }
+        _ => Ok(QuantLinear::Unquantized(Linear::from_weights(weight, bias))),
+    }
+}
+
+/// Quantize the weight of a linear layer, see `InSituQuantization`.
+fn quantize_linear(
+    quantization: InSituQuantization,
+    weight: &Tensor,
+    bias: Option<Tensor>,
+) -> candle_core::Result<QuantLinear> {
+    let weight = weight.to_dtype(DType::F32)?;
+    let ggml_dtype = match quantization {
+        InSituQuantization::Q8_0 => GgmlDType::Q8_0,
+        InSituQuantization::Q4K => GgmlDType::Q4K,
+        InSituQuantization::Nf4 => {
+            return Ok(QuantLinear::Nf4(Nf4Linear::quantize(&weight, bias)?))
+        }
+    };
+    let weight = QMatMul::from_qtensor(QTensor::quantize(&weight, ggml_dtype)?)?;
+    Ok(QuantLinear::Gguf(GgufLinear::new(weight, bias)))
+}
+
+/// A linear layer holding NF4 weights: the 4-bit codes of `NF4_CODEBOOK`, two per byte with the
+/// even column in the low nibble, and the absmax of each block of `NF4_BLOCK_SIZE` weights. The
+/// weight is dequantized to the dtype of the input in each forward, which trades compute for
+/// about 3.5x less weight memory than f16.
+pub struct Nf4Linear {
+    codes: Tensor,
+    absmax: Tensor,
+    codebook: Tensor,
+    bias: Option<Tensor>,
+    span: tracing::Span,
+}
+
+impl Nf4Linear {
+    /// Quantize a weight of shape [out_dim, in_dim], where `in_dim` is a multiple of
+    /// `NF4_BLOCK_SIZE`.
+    fn quantize(weight: &Tensor, bias: Option<Tensor>) -> candle_core::Result<Self> {
+        let (out_dim, in_dim) = weight.dims2()?;
+        let device = weight.device();
+        let values = weight.flatten_all()?.to_vec1::<f32>()?;
+        let absmax = values
+            .chunks_exact(NF4_BLOCK_SIZE)
+            .map(|block| block.iter().fold(0f32, |max, x| max.max(x.abs())))
+            .collect::<Vec<_>>();
+        let mut codes = vec![0u8; values.len() / 2];
+        for (i, x) in values.iter().enumerate() {
+            let scale = absmax[i / NF4_BLOCK_SIZE];
+            let x = if scale > 0. { x / scale } else { 0. };
+            let code = (0..NF4_CODEBOOK.len())
+                .min_by(|a, b| {
+                    (NF4_CODEBOOK[*a] - x)
+                        .abs()
+                        .total_cmp(&(NF4_CODEBOOK[*b] - x).abs())
+                })
+                .unwrap_or(0) as u8;
+            codes[i / 2] |= code << (i % 2 * 4);
+        }
+        Ok(Self {
+            codes: Tensor::from_vec(codes, (out_dim, in_dim / 2), device)?,
+            absmax: Tensor::from_vec(absmax, (out_dim, in_dim / NF4_BLOCK_SIZE, 1), device)?,
+            codebook: Tensor::new(&NF4_CODEBOOK, device)?,
+            bias,
+            span: tracing::span!(tracing::Level::TRACE, "nf4-linear"),
+        })
+    }
+
+    /// The dequantized weight, shape = [out_dim, in_dim].
+    fn dequantize(&self, dtype: DType) -> candle_core::Result<Tensor> {
+        let (out_dim, packed_dim) = self.codes.dims2()?;
+        let codes = self.codes.to_dtype(DType::F32)?;
+        let high = (&codes / 16.)?.floor()?;
+        let low = (codes - (&high * 16.)?)?;
+        let indices = Tensor::stack(&[low, high], 2)?
+            .flatten_all()?
+            .to_dtype(DType::U32)?;
+        self.codebook
+            .index_select(&indices, 0)?
+            .reshape((out_dim, (), NF4_BLOCK_SIZE))?
+            .broadcast_mul(&self.absmax)?
+            .reshape((out_dim, packed_dim * 2))?
+            .to_dtype(dtype)
+    }
+}
+
+impl Module for Nf4Linear {
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let _enter = self.span.enter();
+        Linear::from_weights(self.dequantize(x.dtype())?, self.bias.clone()).forward(x)
+    }
+}
+
+/// The little-endian 32-bit words of a packed tensor.
+fn to_words(data: &[u8]) -> Vec<u32> {
+    data.chunks_exact(4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect()
+}
+
+/// The 4-bit value of column `col` in its word of an AWQ tensor.
+fn unpack_awq(word: u32, col: usize) -> u32 {
+    (word >> (AWQ_PACK_ORDER[col % INT4_PACK_FACTOR] * 4)) & 0xf
+}
+
+/// Repack an AWQ weight of shape [in_dim, out_dim / 8], packed along the output dimension in
+/// the AWQ order, into the GPTQ layout of shape [in_dim / 8, out_dim].
+fn repack_awq_qweight(words: &[u32], in_dim: usize, out_dim: usize) -> Vec<u32> {
+    let mut packed = vec![0u32; in_dim / INT4_PACK_FACTOR * out_dim];
+    for row in 0..in_dim {
+        for col in 0..out_dim {
+            let q = unpack_awq(
+                words[row * (out_dim / INT4_PACK_FACTOR) + col / INT4_PACK_FACTOR],
+                col,
+            );
+            packed[row / INT4_PACK_FACTOR * out_dim + col] |= q << (row % INT4_PACK_FACTOR * 4);
+        }
+    }
+    packed
+}
+
+/// Unpack the zero points of shape [num_groups, out_dim / 8]. GPTQ stores them minus one in
+/// order, AWQ as they are in the AWQ order.
+fn unpack_zeros(words: &[u32], out_dim: usize, awq: bool) -> Vec<f32> {
+    let cols = out_dim / INT4_PACK_FACTOR;
+    (0..words.len() * INT4_PACK_FACTOR)
+        .map(|i| {
+            let (group, col) = (i / out_dim, i % out_dim);
+            let word = words[group * cols + col / INT4_PACK_FACTOR];
+            if awq {
+                unpack_awq(word, col) as f32
+            } else {
+                (((word >> (col % INT4_PACK_FACTOR * 4)) & 0xf) + 1) as f32
+            }
+        })
+        .collect()
+}
+
+/// A linear layer holding 4-bit GPTQ or AWQ weights, computed with the INT4 GEMM kernel, or with
+/// the Marlin-style tensor core kernel when the weight is repacked for it.
+pub struct Int4Linear {
+    qweight: Tensor,
+    marlin: bool,
+    zeros: Tensor,
+    scales: Tensor,
+    g_idx: Tensor,
+    bias: Option<Tensor>,
+    span: tracing::Span,
+}
+
+impl Module for Int4Linear {
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let _enter = self.span.enter();
+        let (in_dim, out_dim) = (self.g_idx.dim(0)?, self.scales.dim(1)?);
+        let mut out_shape = x.dims().to_vec();
+        *out_shape.last_mut().unwrap() = out_dim;
+        let x = x.reshape(((), in_dim))?;
+        let out = unsafe {
+            if self.marlin {
+                crate::backend::marlin_gemm(
+                    &x,
+                    &self.qweight,
+                    &self.zeros,
+                    &self.scales,
+                    &self.g_idx,
+                )
+            } else {
+                crate::backend::int4_gemm(&x, &self.qweight, &self.zeros, &self.scales, &self.g_idx)
+            }
+        }
+        .map_err(|e| candle_core::Error::Msg(e.to_string()))?;
+        let out = match &self.bias {
+            Some(bias) => out.broadcast_add(bias)?,
+            None => out,
+        };
+        out.reshape(out_shape)
+    }
+}
+
+/// Dequantize a 4-bit weight to shape [out_dim, in_dim], see `backend::int4_gemm` for the
+/// layout.
+fn dequantize_int4(
+    qweight: &Tensor,
+    zeros: &Tensor,
+    scales: &Tensor,
+    g_idx: &[u32],
+    dtype: DType,
+) -> candle_core::Result<Tensor> {
+    let (in_dim, out_dim) = (g_idx.len(), scales.dim(1)?);
+    let qweight = qweight.flatten_all()?.to_vec1::<u32>()?;
+    let zeros = zeros.flatten_all()?.to_vec1::<f32>()?;
+    let scales = scales.flatten_all()?.to_vec1::<f32>()?;
+    let mut weight = vec![0f32; out_dim * in_dim];
+    for (k, group) in g_idx.iter().map(|group| *group as usize).enumerate() {
+        for n in 0..out_dim {
+            let word = qweight[k / INT4_PACK_FACTOR * out_dim + n];
+            let q = ((word >> (k % INT4_PACK_FACTOR * 4)) & 0xf) as f32;
+            let i = group * out_dim + n;
+            weight[n * in_dim + k] = (q - zeros[i]) * scales[i];
+        }
+    }
+    Tensor::from_vec(weight, (out_dim, in_dim), &Device::Cpu)?.to_dtype(dtype)
+}
+
+fn use_marlin_gemm(device: &Device, dtype: DType, in_dim: usize, out_dim: usize) -> bool {
+    let (_, block_n, block_k) = crate::backend::MARLIN_TILE;
+    match device {
+        Device::Cuda(dev) => {
+            matches!(dtype, DType::F16 | DType::BF16)
+                && in_dim % block_k == 0
+                && out_dim % block_n == 0
+                && compute_capability(dev.ordinal())
+                    .is_ok_and(|capability| capability >= MIN_MARLIN_COMPUTE_CAPABILITY)
+        }
+        _ => false,
+    }
+}
+
+/// Load a linear layer of a 4-bit GPTQ or AWQ checkpoint, converted by
+/// `load_quantized_safetensors`: `qweight`, `zeros`, `scales` and the group of each input row in
+/// `g_idx`, whose groups may be reordered by activation order (`desc_act`).
+fn int4_linear_b(
+    in_dim: usize,
+    out_dim: usize,
+    bias: bool,
+    vb: VarBuilder,
+) -> candle_core::Result<QuantLinear> {
+    let g_idx = vb.get_with_hints_dtype(in_dim, "g_idx", Default::default(), DType::U32)?;
+    let groups = g_idx.to_vec1::<u32>()?;
+    let num_groups = groups.iter().max().map_or(0, |group| *group as usize + 1);
+    let qweight = vb.get_with_hints_dtype(
+        (in_dim / INT4_PACK_FACTOR, out_dim),
+        "qweight",
+        Default::default(),
+        DType::U32,
+    )?;
+    let zeros = vb.get_with_hints_dtype(
+        (num_groups, out_dim),
+        "zeros",
+        Default::default(),
+        DType::F32,
+    )?;
+    let scales = vb.get_with_hints_dtype(
+        (num_groups, out_dim),
+        "scales",
+        Default::default(),
+        DType::F32,
+    )?;
+    let bias = if bias {
+        Some(vb.get(out_dim, "bias")?)
+    } else {
+        None
+    };
+    if vb.device().is_cuda() {
+        let marlin = use_marlin_gemm(vb.device(), vb.dtype(), in_dim, out_dim);
+        let qweight = if marlin {
+            crate::backend::marlin_repack(&qweight)
+                .map_err(|e| candle_core::Error::Msg(e.to_string()))?
+        } else {
+            qweight
+        };
+        Ok(QuantLinear::Int4(Int4Linear {
+            qweight,
+            marlin,
+            zeros,
+            scales,
+            g_idx,
+            bias,
+            span: tracing::span!(tracing::Level::TRACE, "int4-linear"),
+        }))
+    } else {
+        let weight = dequantize_int4(&qweight, &zeros, &scales, &groups, vb.dtype())?;
+        let weight = weight.to_device(vb.device())?;
+        Ok(QuantLinear::Unquantized(Linear::from_weights(weight, bias)))
+    }
+}
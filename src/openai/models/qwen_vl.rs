@@ -0,0 +1,725 @@
+/// Qwen-VL, the first-generation Qwen LLM reading the embeddings of a ViT with a cross-attention
+/// resampler, https://huggingface.co/Qwen/Qwen-VL-Chat/blob/main/modeling_qwen.py and
+/// https://huggingface.co/Qwen/Qwen-VL-Chat/blob/main/visual.py
+use candle_core::{DType, Device, Tensor, D};
+use candle_nn::{Conv2d, Conv2dConfig, Embedding, LayerNorm, Module, VarBuilder};
+use candle_transformers::models::with_tracing::Linear;
+use serde::Deserialize;
+use std::iter::zip;
+
+use crate::openai::responses::APIError;
+use crate::paged_attention::input_metadata::InputMetadata;
+use crate::paged_attention::PagedAttention;
+use crate::scheduler::kv_cache_manager::KVCacheManager;
+use crate::try_api;
+
+use super::quantization::{linear, linear_no_bias, QuantLinear, QuantizationConfig};
+use super::rotary_embedding::compute_cos_sin_cache;
+use super::{
+    merge_image_embeddings, ConfigLike, ImageDims, LoadablePagedAttentionModel, PagedAttentionModel,
+};
+
+/// Number of queries of the resampler, i.e. the image tokens of each image.
+const NUM_IMAGE_QUERIES: usize = 256;
+/// Head size of the attention of the resampler.
+const RESAMPLER_HEAD_DIM: usize = 128;
+const VISUAL_LAYER_NORM_EPS: f64 = 1e-6;
+
+/// The `visual` section of the config of Qwen-VL.
+#[derive(Clone, Debug, Deserialize)]
+pub struct VisualConfig {
+    pub width: usize,
+    pub layers: usize,
+    pub heads: usize,
+    pub mlp_ratio: f64,
+    pub output_dim: usize,
+    pub image_size: usize,
+    pub patch_size: usize,
+    /// The id of `<img>`, followed by `</img>` and the image pad token `<imgpad>`.
+    pub image_start_id: u32,
+}
+
+impl VisualConfig {
+    fn grid_size(&self) -> usize {
+        self.image_size / self.patch_size
+    }
+
+    /// The image pad token, which stands for the image embeddings.
+    pub fn image_pad_id(&self) -> u32 {
+        self.image_start_id + 2
+    }
+}
+
+#[derive(Deserialize)]
+pub struct QwenVlConfig {
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub vocab_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    #[serde(default = "default_kv_channels")]
+    pub kv_channels: usize,
+    #[serde(default = "default_layer_norm_epsilon")]
+    pub layer_norm_epsilon: f64,
+    #[serde(default = "default_rotary_emb_base")]
+    pub rotary_emb_base: f32,
+    #[serde(default = "default_max_position_embeddings")]
+    pub max_position_embeddings: usize,
+    pub visual: VisualConfig,
+    #[serde(default)]
+    pub quantization_config: Option<QuantizationConfig>,
+}
+
+fn default_kv_channels() -> usize {
+    128
+}
+
+fn default_layer_norm_epsilon() -> f64 {
+    1e-6
+}
+
+fn default_rotary_emb_base() -> f32 {
+    10_000.0
+}
+
+fn default_max_position_embeddings() -> usize {
+    8192
+}
+
+impl QwenVlConfig {
+    pub fn into_config(self) -> Config {
+        Config {
+            hidden_size: self.hidden_size,
+            intermediate_size: self.intermediate_size,
+            vocab_size: self.vocab_size,
+            num_hidden_layers: self.num_hidden_layers,
+            num_attention_heads: self.num_attention_heads,
+            head_dim: self.kv_channels,
+            layer_norm_epsilon: self.layer_norm_epsilon,
+            rotary_emb_base: self.rotary_emb_base,
+            max_position_embeddings: self.max_position_embeddings,
+            visual: self.visual,
+            quantization_config: self.quantization_config,
+        }
+    }
+}
+
+/// The dynamic NTK scaling and the logn attention scaling of Qwen (v1), which only take effect
+/// beyond the 2048 tokens the model was trained on, are not applied.
+#[derive(Clone)]
+pub struct Config {
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub vocab_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub head_dim: usize,
+    pub layer_norm_epsilon: f64,
+    pub rotary_emb_base: f32,
+    pub max_position_embeddings: usize,
+    pub visual: VisualConfig,
+    pub quantization_config: Option<QuantizationConfig>,
+}
+
+impl ConfigLike for Config {
+    fn get_num_kv_heads(&self) -> usize {
+        self.num_attention_heads
+    }
+    fn get_hidden_size(&self) -> usize {
+        self.hidden_size
+    }
+    fn get_num_hidden_layers(&self) -> usize {
+        self.num_hidden_layers
+    }
+    fn get_num_attention_heads(&self) -> usize {
+        self.num_attention_heads
+    }
+    fn get_vocab_size(&self) -> usize {
+        self.vocab_size
+    }
+    fn get_sliding_window(&self) -> Option<usize> {
+        None
+    }
+    fn get_head_size(&self) -> usize {
+        self.head_dim
+    }
+    fn get_image_dims(&self) -> Option<ImageDims> {
+        Some(ImageDims {
+            image_size: self.visual.image_size,
+            num_image_tokens: NUM_IMAGE_QUERIES,
+        })
+    }
+}
+
+/// Unmasked scaled dot-product attention.
+/// q, k, v: shape = [batch_size, num_heads, seq_len, head_dim]
+fn full_attention(q: &Tensor, k: &Tensor, v: &Tensor) -> candle_core::Result<Tensor> {
+    let scale = (q.dim(D::Minus1)? as f64).powf(-0.5);
+    let attn = candle_nn::ops::softmax_last_dim(&(q.matmul(&k.t()?)? * scale)?)?;
+    attn.matmul(v)
+}
+
+/// [batch_size, seq_len, num_heads * head_dim] -> [batch_size, num_heads, seq_len, head_dim]
+fn split_heads(x: &Tensor, num_heads: usize) -> candle_core::Result<Tensor> {
+    let (b_sz, seq_len, size) = x.dims3()?;
+    x.reshape((b_sz, seq_len, num_heads, size / num_heads))?
+        .transpose(1, 2)?
+        .contiguous()
+}
+
+/// [batch_size, num_heads, seq_len, head_dim] -> [batch_size, seq_len, num_heads * head_dim]
+fn merge_heads(x: &Tensor) -> candle_core::Result<Tensor> {
+    let (b_sz, _, seq_len, _) = x.dims4()?;
+    x.transpose(1, 2)?.reshape((b_sz, seq_len, ()))
+}
+
+/// The weights of the bicubic interpolation of `src` samples to `dst` samples as done by
+/// PyTorch (`mode="bicubic", align_corners=False`), shape = [dst, src].
+fn bicubic_weights(src: usize, dst: usize) -> Vec<f32> {
+    const A: f64 = -0.75;
+    let cubic_1 = |x: f64| ((A + 2.) * x - (A + 3.)) * x * x + 1.;
+    let cubic_2 = |x: f64| ((A * x - 5. * A) * x + 8. * A) * x - 4. * A;
+    let scale = src as f64 / dst as f64;
+    let mut weights = vec![0f32; dst * src];
+    for i in 0..dst {
+        let x = (i as f64 + 0.5) * scale - 0.5;
+        let x_0 = x.floor();
+        let t = x - x_0;
+        let coeffs = [
+            cubic_2(t + 1.),
+            cubic_1(t),
+            cubic_1(1. - t),
+            cubic_2(2. - t),
+        ];
+        for (k, coeff) in coeffs.iter().enumerate() {
+            // Samples outside of the grid take the value of its edge.
+            let j = (x_0 as isize - 1 + k as isize).clamp(0, src as isize - 1) as usize;
+            weights[i * src + j] += *coeff as f32;
+        }
+    }
+    weights
+}
+
+/// Interpolate the positional embeddings of a square grid, shape = [grid * grid, dim], to a grid
+/// of `dst_grid` bicubically, see `get_abs_pos` of Qwen-VL.
+fn interpolate_pos_embed(pos_embed: &Tensor, dst_grid: usize) -> candle_core::Result<Tensor> {
+    let (num_pos, dim) = pos_embed.dims2()?;
+    let src_grid = (num_pos as f64).sqrt() as usize;
+    if src_grid == dst_grid {
+        return Ok(pos_embed.clone());
+    }
+    let weights = Tensor::from_vec(
+        bicubic_weights(src_grid, dst_grid),
+        (dst_grid, src_grid),
+        pos_embed.device(),
+    )?;
+    // Interpolate the rows, then the columns: [row, column, dim] -> [dst row, column, dim] ->
+    // [column, dst row, dim] -> [dst column, dst row, dim].
+    let x = pos_embed
+        .to_dtype(DType::F32)?
+        .reshape((src_grid, src_grid * dim))?;
+    let x = weights.matmul(&x)?.reshape((dst_grid, src_grid, dim))?;
+    let x = x
+        .transpose(0, 1)?
+        .contiguous()?
+        .reshape((src_grid, dst_grid * dim))?;
+    let x = weights.matmul(&x)?.reshape((dst_grid, dst_grid, dim))?;
+    x.transpose(0, 1)?
+        .contiguous()?
+        .reshape((dst_grid * dst_grid, dim))?
+        .to_dtype(pos_embed.dtype())
+}
+
+struct VisualAttention {
+    in_proj: QuantLinear,
+    out_proj: QuantLinear,
+    num_heads: usize,
+    head_dim: usize,
+}
+
+impl VisualAttention {
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let (b_sz, seq_len, _) = x.dims3()?;
+        // The projections of each head are interleaved: (q, k, v) of head 0, of head 1, ...
+        let qkv =
+            self.in_proj
+                .forward(x)?
+                .reshape((b_sz, seq_len, self.num_heads, 3, self.head_dim))?;
+        let split = |i: usize| -> candle_core::Result<Tensor> {
+            qkv.narrow(3, i, 1)?
+                .squeeze(3)?
+                .transpose(1, 2)?
+                .contiguous()
+        };
+        let y = full_attention(&split(0)?, &split(1)?, &split(2)?)?;
+        self.out_proj.forward(&merge_heads(&y)?)
+    }
+
+    fn load(vb: VarBuilder, cfg: &VisualConfig) -> candle_core::Result<Self> {
+        Ok(Self {
+            in_proj: linear(cfg.width, 3 * cfg.width, vb.pp("in_proj"))?,
+            out_proj: linear(cfg.width, cfg.width, vb.pp("out_proj"))?,
+            num_heads: cfg.heads,
+            head_dim: cfg.width / cfg.heads,
+        })
+    }
+}
+
+struct VisualBlock {
+    ln_1: LayerNorm,
+    attn: VisualAttention,
+    ln_2: LayerNorm,
+    c_fc: QuantLinear,
+    c_proj: QuantLinear,
+    span: tracing::Span,
+}
+
+impl VisualBlock {
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let _enter = self.span.enter();
+        let x = (x + self.attn.forward(&self.ln_1.forward(x)?)?)?;
+        let y = self.c_fc.forward(&self.ln_2.forward(&x)?)?.gelu_erf()?;
+        &x + self.c_proj.forward(&y)?
+    }
+
+    fn load(vb: VarBuilder, cfg: &VisualConfig) -> candle_core::Result<Self> {
+        let span = tracing::span!(tracing::Level::TRACE, "visual-block");
+        let mlp_width = (cfg.width as f64 * cfg.mlp_ratio) as usize;
+        Ok(Self {
+            ln_1: candle_nn::layer_norm(cfg.width, VISUAL_LAYER_NORM_EPS, vb.pp("ln_1"))?,
+            attn: VisualAttention::load(vb.pp("attn"), cfg)?,
+            ln_2: candle_nn::layer_norm(cfg.width, VISUAL_LAYER_NORM_EPS, vb.pp("ln_2"))?,
+            c_fc: linear(cfg.width, mlp_width, vb.pp("mlp.c_fc"))?,
+            c_proj: linear(mlp_width, cfg.width, vb.pp("mlp.c_proj"))?,
+            span,
+        })
+    }
+}
+
+/// Compresses the patch embeddings of an image to `NUM_IMAGE_QUERIES` embeddings with a single
+/// cross-attention layer of learned queries.
+struct Resampler {
+    query: Tensor,
+    pos_embed: Tensor,
+    /// `pos_embed` interpolated to the patch grid, added to the keys.
+    kv_pos_embed: Tensor,
+    kv_proj: Option<QuantLinear>,
+    ln_q: LayerNorm,
+    ln_kv: LayerNorm,
+    q_proj: QuantLinear,
+    k_proj: QuantLinear,
+    v_proj: QuantLinear,
+    out_proj: QuantLinear,
+    num_heads: usize,
+}
+
+impl Resampler {
+    /// x: shape = [num_images, num_patches, width]
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let x = match &self.kv_proj {
+            Some(kv_proj) => kv_proj.forward(x)?,
+            None => x.clone(),
+        };
+        let x = self.ln_kv.forward(&x)?;
+        let num_images = x.dim(0)?;
+        let q = (self.ln_q.forward(&self.query)? + &self.pos_embed)?;
+        let q = self.q_proj.forward(&q)?.unsqueeze(0)?;
+        let (_, num_queries, size) = q.dims3()?;
+        let q = q
+            .broadcast_as((num_images, num_queries, size))?
+            .contiguous()?;
+        let k = self.k_proj.forward(&x.broadcast_add(&self.kv_pos_embed)?)?;
+        let v = self.v_proj.forward(&x)?;
+        let y = full_attention(
+            &split_heads(&q, self.num_heads)?,
+            &split_heads(&k, self.num_heads)?,
+            &split_heads(&v, self.num_heads)?,
+        )?;
+        self.out_proj.forward(&merge_heads(&y)?)
+    }
+
+    fn load(vb: VarBuilder, cfg: &VisualConfig) -> candle_core::Result<Self> {
+        let (size, kv_size) = (cfg.output_dim, cfg.width);
+        let pos_embed = vb.get((NUM_IMAGE_QUERIES, size), "pos_embed")?;
+        let kv_pos_embed = interpolate_pos_embed(&pos_embed, cfg.grid_size())?;
+        let kv_proj = if kv_size != size {
+            Some(linear_no_bias(kv_size, size, vb.pp("kv_proj"))?)
+        } else {
+            None
+        };
+        // The query, key and value projections of `nn.MultiheadAttention` are stacked.
+        let in_proj_weight = vb.get((3 * size, size), "attn.in_proj_weight")?;
+        let in_proj_bias = vb.get(3 * size, "attn.in_proj_bias")?;
+        let in_proj = |i: usize| -> candle_core::Result<QuantLinear> {
+            Ok(QuantLinear::Unquantized(Linear::from_weights(
+                in_proj_weight.narrow(0, i * size, size)?,
+                Some(in_proj_bias.narrow(0, i * size, size)?),
+            )))
+        };
+        Ok(Self {
+            query: vb.get((NUM_IMAGE_QUERIES, size), "query")?,
+            pos_embed,
+            kv_pos_embed,
+            kv_proj,
+            ln_q: candle_nn::layer_norm(size, VISUAL_LAYER_NORM_EPS, vb.pp("ln_q"))?,
+            ln_kv: candle_nn::layer_norm(size, VISUAL_LAYER_NORM_EPS, vb.pp("ln_kv"))?,
+            q_proj: in_proj(0)?,
+            k_proj: in_proj(1)?,
+            v_proj: in_proj(2)?,
+            out_proj: linear(size, size, vb.pp("attn.out_proj"))?,
+            num_heads: size / RESAMPLER_HEAD_DIM,
+        })
+    }
+}
+
+/// The vision encoder of Qwen-VL: a ViT followed by the resampler.
+struct VisionTransformer {
+    conv1: Conv2d,
+    positional_embedding: Tensor,
+    ln_pre: LayerNorm,
+    blocks: Vec<VisualBlock>,
+    attn_pool: Resampler,
+    ln_post: LayerNorm,
+    proj: Tensor,
+}
+
+impl VisionTransformer {
+    /// pixel_values: shape = [num_images, 3, image_size, image_size]
+    ///
+    /// Returns shape = [num_images, NUM_IMAGE_QUERIES, output_dim].
+    fn forward(&self, pixel_values: &Tensor) -> candle_core::Result<Tensor> {
+        let pixel_values = pixel_values.to_dtype(self.proj.dtype())?;
+        let x = self
+            .conv1
+            .forward(&pixel_values)?
+            .flatten_from(2)?
+            .transpose(1, 2)?
+            .broadcast_add(&self.positional_embedding)?;
+        let mut x = self.ln_pre.forward(&x)?;
+        for block in &self.blocks {
+            x = block.forward(&x)?;
+        }
+        let x = self.ln_post.forward(&self.attn_pool.forward(&x)?)?;
+        x.broadcast_matmul(&self.proj)
+    }
+
+    fn load(vb: VarBuilder, cfg: &VisualConfig) -> candle_core::Result<Self> {
+        let conv_cfg = Conv2dConfig {
+            stride: cfg.patch_size,
+            ..Default::default()
+        };
+        let conv1 =
+            candle_nn::conv2d_no_bias(3, cfg.width, cfg.patch_size, conv_cfg, vb.pp("conv1"))?;
+        let positional_embedding = interpolate_pos_embed(
+            &vb.get((NUM_IMAGE_QUERIES, cfg.width), "positional_embedding")?,
+            cfg.grid_size(),
+        )?;
+        let blocks = (0..cfg.layers)
+            .map(|i| VisualBlock::load(vb.pp(&format!("transformer.resblocks.{i}")), cfg))
+            .collect::<candle_core::Result<Vec<_>>>()?;
+        let size = cfg.output_dim;
+        Ok(Self {
+            conv1,
+            positional_embedding,
+            ln_pre: candle_nn::layer_norm(cfg.width, VISUAL_LAYER_NORM_EPS, vb.pp("ln_pre"))?,
+            blocks,
+            attn_pool: Resampler::load(vb.pp("attn_pool"), cfg)?,
+            ln_post: candle_nn::layer_norm(size, VISUAL_LAYER_NORM_EPS, vb.pp("ln_post"))?,
+            proj: vb.get((size, size), "proj")?,
+        })
+    }
+}
+
+fn embedding(cfg: &Config, vb: VarBuilder) -> candle_core::Result<Embedding> {
+    let embeddings = vb.get((cfg.vocab_size, cfg.hidden_size), "weight")?;
+    Ok(Embedding::new(embeddings, cfg.hidden_size))
+}
+
+struct RmsNorm {
+    inner: candle_nn::RmsNorm,
+    span: tracing::Span,
+}
+
+impl RmsNorm {
+    fn load(size: usize, eps: f64, vb: VarBuilder) -> candle_core::Result<Self> {
+        let span = tracing::span!(tracing::Level::TRACE, "rms-norm");
+        let inner = candle_nn::rms_norm(size, eps, vb)?;
+        Ok(Self { inner, span })
+    }
+
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let _enter = self.span.enter();
+        self.inner.forward(x)
+    }
+}
+
+struct Attention {
+    c_attn: QuantLinear,
+    c_proj: QuantLinear,
+    num_attention_heads: usize,
+    head_dim: usize,
+    attn: PagedAttention,
+    cos_sin_cache: Tensor,
+}
+
+impl Attention {
+    fn forward(
+        &mut self,
+        x: &Tensor,
+        positions: &Tensor,
+        input_metadata: &mut InputMetadata,
+        cache: Option<(&Tensor, &Tensor)>,
+    ) -> Result<Tensor, APIError> {
+        let (b_sz, seq_len, _) = try_api!(x.dims3());
+        let qkv = try_api!(self.c_attn.forward(x));
+        let size = self.num_attention_heads * self.head_dim;
+        let split = |i: usize| -> Result<Tensor, APIError> {
+            let x = try_api!(qkv.narrow(D::Minus1, i * size, size));
+            let x = try_api!(x.reshape((b_sz, seq_len, self.num_attention_heads, self.head_dim)));
+            x.transpose(1, 2).map_err(APIError::from)
+        };
+        let (q, k, v) = (split(0)?, split(1)?, split(2)?);
+
+        let dtype = q.dtype();
+        let device = q.device().clone();
+        let attn_output = self.attn.forward_with_rotary_embedding(
+            q,
+            k,
+            v,
+            positions,
+            &self.cos_sin_cache,
+            false,
+            cache.map(|(k, _)| k.clone()),
+            cache.map(|(_, v)| v.clone()),
+            input_metadata,
+            dtype,
+            device,
+        )?;
+
+        self.c_proj.forward(&attn_output).map_err(APIError::from)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config, cos_sin_cache: Tensor) -> Result<Self, APIError> {
+        let size = cfg.head_dim * cfg.num_attention_heads;
+        // Only the fused QKV projection carries a bias.
+        let c_attn = try_api!(linear(cfg.hidden_size, 3 * size, vb.pp("c_attn")));
+        let c_proj = try_api!(linear_no_bias(size, cfg.hidden_size, vb.pp("c_proj")));
+
+        Ok(Self {
+            c_attn,
+            c_proj,
+            num_attention_heads: cfg.num_attention_heads,
+            head_dim: cfg.head_dim,
+            attn: PagedAttention::new(
+                cfg.num_attention_heads,
+                cfg.head_dim,
+                1. / ((cfg.head_dim as f32).sqrt()),
+                Some(cfg.num_attention_heads),
+                None,
+                vb.device().clone(),
+                None,
+            )?,
+            cos_sin_cache,
+        })
+    }
+}
+
+struct Mlp {
+    w1: QuantLinear,
+    w2: QuantLinear,
+    c_proj: QuantLinear,
+    span: tracing::Span,
+}
+
+impl Mlp {
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let _enter = self.span.enter();
+        let x = (self.w1.forward(x)? * candle_nn::ops::silu(&self.w2.forward(x)?)?)?;
+        self.c_proj.forward(&x)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config) -> candle_core::Result<Self> {
+        let span = tracing::span!(tracing::Level::TRACE, "mlp");
+        // `intermediate_size` counts both halves of the gated projection.
+        let (h_size, i_size) = (cfg.hidden_size, cfg.intermediate_size / 2);
+        Ok(Self {
+            w1: linear_no_bias(h_size, i_size, vb.pp("w1"))?,
+            w2: linear_no_bias(h_size, i_size, vb.pp("w2"))?,
+            c_proj: linear_no_bias(i_size, h_size, vb.pp("c_proj"))?,
+            span,
+        })
+    }
+}
+
+struct Block {
+    ln_1: RmsNorm,
+    attn: Attention,
+    ln_2: RmsNorm,
+    mlp: Mlp,
+    span: tracing::Span,
+}
+
+impl Block {
+    fn forward(
+        &mut self,
+        x: &Tensor,
+        positions: &Tensor,
+        cache: Option<(&Tensor, &Tensor)>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let _enter = self.span.enter();
+        let residual = x;
+        let x = try_api!(self.ln_1.forward(x));
+        let x = try_api!(
+            (try_api!(self.attn.forward(&x, positions, input_metadata, cache)) + residual)
+        );
+        let residual = &x;
+        let x = try_api!((try_api!(self.mlp.forward(&try_api!(self.ln_2.forward(&x))))) + residual);
+        Ok(x)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config, cos_sin_cache: Tensor) -> Result<Self, APIError> {
+        let span = tracing::span!(tracing::Level::TRACE, "block");
+        let attn = Attention::load(vb.pp("attn"), cfg, cos_sin_cache)?;
+        let mlp = try_api!(Mlp::load(vb.pp("mlp"), cfg));
+        let ln_1 = try_api!(RmsNorm::load(
+            cfg.hidden_size,
+            cfg.layer_norm_epsilon,
+            vb.pp("ln_1")
+        ));
+        let ln_2 = try_api!(RmsNorm::load(
+            cfg.hidden_size,
+            cfg.layer_norm_epsilon,
+            vb.pp("ln_2")
+        ));
+        Ok(Self {
+            ln_1,
+            attn,
+            ln_2,
+            mlp,
+            span,
+        })
+    }
+}
+
+pub struct QwenVl {
+    wte: Embedding,
+    blocks: Vec<Block>,
+    ln_f: RmsNorm,
+    lm_head: QuantLinear,
+    visual: VisionTransformer,
+    cfg: Config,
+}
+
+impl QwenVl {
+    pub fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        positions: &Tensor,
+        kv_caches: Option<&KVCacheManager>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let (b_sz, seq_len) = try_api!(input_ids.dims2());
+        let logits_indices = input_metadata.logits_indices(b_sz * seq_len, input_ids.device())?;
+        let image_embeds = match &input_metadata.images {
+            Some(images) => {
+                let x = try_api!(self.visual.forward(&images.pixel_values));
+                Some(try_api!(x.reshape(((), self.cfg.hidden_size))))
+            }
+            None => None,
+        };
+        let x = try_api!(self.wte.forward(input_ids));
+        let mut x = merge_image_embeddings(
+            input_ids,
+            x,
+            image_embeds,
+            self.cfg.visual.image_pad_id(),
+            input_metadata,
+        )?;
+        if let Some(kv_caches) = kv_caches {
+            for (layer, block) in zip(kv_caches.layers(), &mut self.blocks) {
+                x = block.forward(&x, positions, Some(layer.key_value()), input_metadata)?;
+            }
+        } else {
+            for block in &mut self.blocks {
+                x = block.forward(&x, positions, None, input_metadata)?;
+            }
+        }
+        let x = try_api!(self.ln_f.forward(&x));
+        let x = try_api!(x.flatten_to(1));
+        input_metadata.keep_hidden_states(&x);
+        let x = try_api!(x.index_select(&logits_indices, 0));
+        let logits = try_api!(self.lm_head.forward(&x));
+        logits.to_dtype(DType::F32).map_err(APIError::from)
+    }
+
+    pub fn load(
+        vb: VarBuilder,
+        cfg: &Config,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self, APIError> {
+        let vb_t = vb.pp("transformer");
+        let wte = try_api!(embedding(cfg, vb_t.pp("wte")));
+        let lm_head = try_api!(linear_no_bias(
+            cfg.hidden_size,
+            cfg.vocab_size,
+            vb.pp("lm_head")
+        ));
+        let ln_f = try_api!(RmsNorm::load(
+            cfg.hidden_size,
+            cfg.layer_norm_epsilon,
+            vb_t.pp("ln_f")
+        ));
+        // The cos/sin cache is shared by all layers.
+        let cos_sin_cache = compute_cos_sin_cache(
+            cfg.head_dim,
+            cfg.rotary_emb_base,
+            cfg.max_position_embeddings,
+            None,
+            dtype,
+            device,
+        )?;
+        let blocks = (0..cfg.num_hidden_layers)
+            .map(|i| Block::load(vb_t.pp(&format!("h.{i}")), cfg, cos_sin_cache.clone()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let visual = try_api!(VisionTransformer::load(vb_t.pp("visual"), &cfg.visual));
+
+        Ok(Self {
+            wte,
+            blocks,
+            ln_f,
+            lm_head,
+            visual,
+            cfg: cfg.clone(),
+        })
+    }
+}
+
+impl PagedAttentionModel for QwenVl {
+    fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        positions: &Tensor,
+        kv_caches: Option<&KVCacheManager>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        QwenVl::forward(self, input_ids, positions, kv_caches, input_metadata)
+    }
+
+    fn get_config(&self) -> Box<dyn ConfigLike> {
+        Box::new(self.cfg.clone())
+    }
+}
+
+impl LoadablePagedAttentionModel for QwenVl {
+    fn load_from_config(
+        config: &[u8],
+        vb: VarBuilder,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self, APIError> {
+        let config: QwenVlConfig = try_api!(serde_json::from_slice(config));
+        QwenVl::load(vb, &config.into_config(), dtype, device)
+    }
+}
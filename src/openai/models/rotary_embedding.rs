@@ -0,0 +1,355 @@
+//! The cos/sin cache of the rotary embedding, with the RoPE scaling strategies which long-context
+//! fine-tunes declare in the `rope_scaling` entry of their `config.json`.
+
+use std::{f32::consts::PI, iter::zip};
+
+use candle_core::{DType, Device, Tensor, D};
+use serde::Deserialize;
+
+use crate::{openai::responses::APIError, try_api};
+
+/// A `rope_scaling` entry of a model config. The strategy is given by `type` or `rope_type`.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "RawRopeScaling")]
+pub enum RopeScaling {
+    /// Position interpolation: positions are divided by `factor`.
+    Linear { factor: f32 },
+    /// NTK-aware scaling: the base is raised so that the lowest frequency covers `factor` times
+    /// the original context. It is computed for the longest context, so that the cached keys stay
+    /// valid as a sequence grows.
+    DynamicNtk {
+        factor: f32,
+        original_max_position_embeddings: Option<usize>,
+    },
+    /// YaRN: interpolates the low frequencies, keeps the high frequencies and scales the
+    /// attention by `attention_factor`. `mscale` and `mscale_all_dim` are the DeepSeek-V2/V3 form
+    /// of the attention factor.
+    Yarn {
+        factor: f32,
+        original_max_position_embeddings: usize,
+        beta_fast: f32,
+        beta_slow: f32,
+        mscale: Option<f32>,
+        mscale_all_dim: Option<f32>,
+        attention_factor: Option<f32>,
+    },
+    /// LongRoPE (Phi-3): per-frequency rescale factors, `short_factor` within the original
+    /// context and `long_factor` beyond it.
+    LongRope {
+        short_factor: Vec<f32>,
+        long_factor: Vec<f32>,
+        original_max_position_embeddings: Option<usize>,
+        short_mscale: Option<f32>,
+        long_mscale: Option<f32>,
+        attention_factor: Option<f32>,
+    },
+    /// Llama 3.1: the low frequencies are divided by `factor`, with a smooth transition to the
+    /// unscaled high frequencies.
+    Llama3 {
+        factor: f32,
+        low_freq_factor: f32,
+        high_freq_factor: f32,
+        original_max_position_embeddings: usize,
+    },
+}
+
+#[derive(Deserialize)]
+struct RawRopeScaling {
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    rope_type: Option<String>,
+    factor: Option<f32>,
+    original_max_position_embeddings: Option<usize>,
+    beta_fast: Option<f32>,
+    beta_slow: Option<f32>,
+    mscale: Option<f32>,
+    mscale_all_dim: Option<f32>,
+    attention_factor: Option<f32>,
+    short_factor: Option<Vec<f32>>,
+    long_factor: Option<Vec<f32>>,
+    short_mscale: Option<f32>,
+    long_mscale: Option<f32>,
+    low_freq_factor: Option<f32>,
+    high_freq_factor: Option<f32>,
+}
+
+impl TryFrom<RawRopeScaling> for RopeScaling {
+    type Error = String;
+
+    fn try_from(raw: RawRopeScaling) -> Result<Self, Self::Error> {
+        let kind = raw
+            .rope_type
+            .or(raw.kind)
+            .ok_or("`rope_scaling` has no `type` or `rope_type`.")?;
+        let factor = raw.factor.unwrap_or(1.);
+        let original_max_position_embeddings = |kind: &str| {
+            raw.original_max_position_embeddings.ok_or(format!(
+                "`{kind}` RoPE scaling requires `original_max_position_embeddings`."
+            ))
+        };
+        Ok(match kind.as_str() {
+            "default" => Self::Linear { factor: 1. },
+            "linear" => Self::Linear { factor },
+            "dynamic" => Self::DynamicNtk {
+                factor,
+                original_max_position_embeddings: raw.original_max_position_embeddings,
+            },
+            "yarn" => Self::Yarn {
+                factor,
+                original_max_position_embeddings: original_max_position_embeddings(&kind)?,
+                beta_fast: raw.beta_fast.unwrap_or(32.),
+                beta_slow: raw.beta_slow.unwrap_or(1.),
+                mscale: raw.mscale,
+                mscale_all_dim: raw.mscale_all_dim,
+                attention_factor: raw.attention_factor,
+            },
+            "longrope" | "su" => Self::LongRope {
+                short_factor: raw
+                    .short_factor
+                    .ok_or("LongRoPE scaling requires `short_factor`.")?,
+                long_factor: raw
+                    .long_factor
+                    .ok_or("LongRoPE scaling requires `long_factor`.")?,
+                original_max_position_embeddings: raw.original_max_position_embeddings,
+                short_mscale: raw.short_mscale,
+                long_mscale: raw.long_mscale,
+                attention_factor: raw.attention_factor,
+            },
+            "llama3" => Self::Llama3 {
+                factor,
+                low_freq_factor: raw.low_freq_factor.unwrap_or(1.),
+                high_freq_factor: raw.high_freq_factor.unwrap_or(4.),
+                original_max_position_embeddings: original_max_position_embeddings(&kind)?,
+            },
+            other => return Err(format!("Unsupported RoPE scaling type `{other}`.")),
+        })
+    }
+}
+
+/// The YaRN attention factor for a context extended by `scale`.
+pub fn yarn_get_mscale(scale: f32, mscale: f32) -> f32 {
+    if scale <= 1. {
+        1.
+    } else {
+        0.1 * mscale * scale.ln() + 1.
+    }
+}
+
+/// The dimension (of `rot_dim`) whose frequency completes `num_rotations` rotations over
+/// `max_position_embeddings` positions.
+fn yarn_find_correction_dim(
+    num_rotations: f32,
+    rot_dim: usize,
+    base: f32,
+    max_position_embeddings: usize,
+) -> f32 {
+    (rot_dim as f32 * (max_position_embeddings as f32 / (num_rotations * 2. * PI)).ln())
+        / (2. * base.ln())
+}
+
+/// The inverse frequencies `1 / base^(2i / rot_dim)` of the unscaled rotary embedding.
+fn base_inv_freq(rot_dim: usize, base: f32) -> Vec<f32> {
+    (0..rot_dim)
+        .step_by(2)
+        .map(|i| 1. / base.powf(i as f32 / rot_dim as f32))
+        .collect()
+}
+
+impl RopeScaling {
+    /// The number of positions the scaled embedding covers, given the `max_position_embeddings`
+    /// of the config. Linear and NTK scaling extend the configured context, the other strategies
+    /// are configured with their extended context.
+    pub fn max_position_embeddings(&self, max_position_embeddings: usize) -> usize {
+        match self {
+            Self::Linear { factor } | Self::DynamicNtk { factor, .. } => {
+                (max_position_embeddings as f32 * factor) as usize
+            }
+            Self::Yarn {
+                factor,
+                original_max_position_embeddings,
+                ..
+            } => max_position_embeddings
+                .max((*original_max_position_embeddings as f32 * factor) as usize),
+            Self::LongRope { .. } | Self::Llama3 { .. } => max_position_embeddings,
+        }
+    }
+}
+
+/// The cos/sin cache of the rotary embedding for `max_position_embeddings` positions and heads of
+/// `rot_dim` dimensions, as used by `rotary_embedding`: shape = [max_position, 2 * rot_dim], the
+/// cosines followed by the sines, each duplicated across both halves of the head. With
+/// `rope_scaling`, the cache covers the extended context, see
+/// `RopeScaling::max_position_embeddings`.
+pub fn compute_cos_sin_cache(
+    rot_dim: usize,
+    rope_theta: f32,
+    max_position_embeddings: usize,
+    rope_scaling: Option<&RopeScaling>,
+    dtype: DType,
+    device: &Device,
+) -> Result<Tensor, APIError> {
+    let max_position = rope_scaling.map_or(max_position_embeddings, |scaling| {
+        scaling.max_position_embeddings(max_position_embeddings)
+    });
+    // The inverse frequencies of a range of positions, with the factor the cosines and sines
+    // are scaled by.
+    let mut ranges: Vec<(usize, usize, Vec<f32>, f32)> = Vec::new();
+    match rope_scaling {
+        None => ranges.push((0, max_position, base_inv_freq(rot_dim, rope_theta), 1.)),
+        Some(RopeScaling::Linear { factor }) => {
+            let inv_freq = base_inv_freq(rot_dim, rope_theta)
+                .into_iter()
+                .map(|freq| freq / factor)
+                .collect();
+            ranges.push((0, max_position, inv_freq, 1.));
+        }
+        Some(RopeScaling::DynamicNtk {
+            factor,
+            original_max_position_embeddings,
+        }) => {
+            let original =
+                original_max_position_embeddings.unwrap_or(max_position_embeddings) as f32;
+            let base = rope_theta
+                * ((factor * max_position as f32 / original) - (factor - 1.))
+                    .powf(rot_dim as f32 / (rot_dim as f32 - 2.));
+            ranges.push((0, max_position, base_inv_freq(rot_dim, base), 1.));
+        }
+        Some(RopeScaling::Yarn {
+            factor,
+            original_max_position_embeddings,
+            beta_fast,
+            beta_slow,
+            mscale,
+            mscale_all_dim,
+            attention_factor,
+        }) => {
+            let low = yarn_find_correction_dim(
+                *beta_fast,
+                rot_dim,
+                rope_theta,
+                *original_max_position_embeddings,
+            )
+            .floor()
+            .max(0.);
+            let high = yarn_find_correction_dim(
+                *beta_slow,
+                rot_dim,
+                rope_theta,
+                *original_max_position_embeddings,
+            )
+            .ceil()
+            .min(rot_dim as f32 - 1.);
+            let high = if low == high { high + 0.001 } else { high };
+            let inv_freq = base_inv_freq(rot_dim, rope_theta)
+                .into_iter()
+                .enumerate()
+                .map(|(i, freq)| {
+                    // 1 for the high frequencies, which are extrapolated, 0 for the low
+                    // frequencies, which are interpolated.
+                    let extrapolation = 1. - ((i as f32 - low) / (high - low)).clamp(0., 1.);
+                    freq / factor * (1. - extrapolation) + freq * extrapolation
+                })
+                .collect();
+            let attention_factor = attention_factor.unwrap_or(match (mscale, mscale_all_dim) {
+                (Some(mscale), Some(mscale_all_dim)) => {
+                    yarn_get_mscale(*factor, *mscale) / yarn_get_mscale(*factor, *mscale_all_dim)
+                }
+                _ => yarn_get_mscale(*factor, 1.),
+            });
+            ranges.push((0, max_position, inv_freq, attention_factor));
+        }
+        Some(RopeScaling::LongRope {
+            short_factor,
+            long_factor,
+            original_max_position_embeddings,
+            short_mscale,
+            long_mscale,
+            attention_factor,
+        }) => {
+            if short_factor.len() != rot_dim / 2 || long_factor.len() != rot_dim / 2 {
+                return Err(APIError::new(format!(
+                    "LongRoPE scaling needs {} factors, got {} short and {} long factors.",
+                    rot_dim / 2,
+                    short_factor.len(),
+                    long_factor.len()
+                )));
+            }
+            let original = original_max_position_embeddings.unwrap_or(max_position_embeddings);
+            let scale = max_position as f32 / original as f32;
+            let attention_factor = attention_factor.unwrap_or(if scale <= 1. {
+                1.
+            } else {
+                (1. + scale.ln() / (original as f32).ln()).sqrt()
+            });
+            let inv_freq = base_inv_freq(rot_dim, rope_theta);
+            let rescale = |factors: &[f32]| -> Vec<f32> {
+                zip(&inv_freq, factors)
+                    .map(|(freq, factor)| freq / factor)
+                    .collect()
+            };
+            // Each position uses the factors of its own range, rather than switching the whole
+            // sequence to the long factors, so that cached keys never have to be rotated again.
+            let short_end = original.min(max_position);
+            ranges.push((
+                0,
+                short_end,
+                rescale(short_factor),
+                short_mscale.unwrap_or(attention_factor),
+            ));
+            if max_position > short_end {
+                ranges.push((
+                    short_end,
+                    max_position,
+                    rescale(long_factor),
+                    long_mscale.unwrap_or(attention_factor),
+                ));
+            }
+        }
+        Some(RopeScaling::Llama3 {
+            factor,
+            low_freq_factor,
+            high_freq_factor,
+            original_max_position_embeddings,
+        }) => {
+            let original = *original_max_position_embeddings as f32;
+            let low_freq_wavelen = original / low_freq_factor;
+            let high_freq_wavelen = original / high_freq_factor;
+            let inv_freq = base_inv_freq(rot_dim, rope_theta)
+                .into_iter()
+                .map(|freq| {
+                    let wavelen = 2. * PI / freq;
+                    if wavelen < high_freq_wavelen {
+                        freq
+                    } else if wavelen > low_freq_wavelen {
+                        freq / factor
+                    } else {
+                        let smooth = (original / wavelen - low_freq_factor)
+                            / (high_freq_factor - low_freq_factor);
+                        (1. - smooth) * freq / factor + smooth * freq
+                    }
+                })
+                .collect();
+            ranges.push((0, max_position, inv_freq, 1.));
+        }
+    }
+
+    let mut caches = Vec::with_capacity(ranges.len());
+    for (start, end, inv_freq, scale) in ranges {
+        let inv_freq = try_api!(Tensor::from_vec(inv_freq, (1, rot_dim / 2), device));
+        let positions =
+            try_api!(try_api!(
+                try_api!(Tensor::arange(start as u32, end as u32, device)).to_dtype(DType::F32)
+            )
+            .reshape((end - start, 1)));
+        let idx_theta = try_api!(positions.matmul(&inv_freq));
+        // This is different from the paper, see:
+        // https://github.com/huggingface/transformers/blob/6112b1c6442aaf7affd2b0676a1cd4eee30c45cf/src/transformers/models/llama/modeling_llama.py#L112
+        let idx_theta = try_api!(Tensor::cat(&[&idx_theta, &idx_theta], D::Minus1));
+        let cos = try_api!(try_api!(idx_theta.cos()) * f64::from(scale));
+        let sin = try_api!(try_api!(idx_theta.sin()) * f64::from(scale));
+        caches.push(try_api!(Tensor::cat(&[cos, sin], D::Minus1)));
+    }
+    try_api!(Tensor::cat(&caches, 0))
+        .to_dtype(dtype)
+        .map_err(APIError::from)
+}
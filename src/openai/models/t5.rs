@@ -0,0 +1,843 @@
+/// Google T5 and FLAN-T5 encoder-decoder LLMs, https://github.com/huggingface/transformers/blob/main/src/transformers/models/t5/modeling_t5.py
+use candle_core::{DType, Device, Tensor};
+use candle_nn::{Embedding, Module, VarBuilder};
+use candle_transformers::models::with_tracing::Linear;
+use serde::Deserialize;
+use std::iter::zip;
+
+use crate::backend::{gather_cached_kv, key_cache_block_dims, reshape_and_cache};
+use crate::openai::responses::APIError;
+use crate::paged_attention::input_metadata::InputMetadata;
+use crate::paged_attention::PagedAttention;
+use crate::scheduler::kv_cache_manager::KVCacheManager;
+use crate::try_api;
+
+use super::quantization::{linear_no_bias as linear, QuantLinear, QuantizationConfig};
+use super::{ConfigLike, LoadablePagedAttentionModel, PagedAttentionModel};
+
+/// The hidden states of T5 overflow in f16, they are clamped to slightly below its maximum.
+const F16_CLAMP_VALUE: f64 = 65504. - 1000.;
+
+#[derive(Deserialize)]
+pub struct T5Config {
+    pub vocab_size: usize,
+    pub d_model: usize,
+    pub d_kv: usize,
+    pub d_ff: usize,
+    pub num_layers: usize,
+    #[serde(default)]
+    pub num_decoder_layers: Option<usize>,
+    pub num_heads: usize,
+    #[serde(default = "default_relative_attention_num_buckets")]
+    pub relative_attention_num_buckets: usize,
+    #[serde(default = "default_relative_attention_max_distance")]
+    pub relative_attention_max_distance: usize,
+    #[serde(default = "default_layer_norm_epsilon")]
+    pub layer_norm_epsilon: f64,
+    /// The feed-forward activation, e.g. "relu" (T5) or "gated-gelu" (T5 v1.1, FLAN-T5).
+    #[serde(default = "default_feed_forward_proj")]
+    pub feed_forward_proj: String,
+    #[serde(default = "default_true")]
+    pub tie_word_embeddings: bool,
+    #[serde(default)]
+    pub pad_token_id: Option<u32>,
+    #[serde(default)]
+    pub decoder_start_token_id: Option<u32>,
+    #[serde(default)]
+    pub quantization_config: Option<QuantizationConfig>,
+}
+
+fn default_relative_attention_num_buckets() -> usize {
+    32
+}
+
+fn default_relative_attention_max_distance() -> usize {
+    128
+}
+
+fn default_layer_norm_epsilon() -> f64 {
+    1e-6
+}
+
+fn default_feed_forward_proj() -> String {
+    "relu".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl T5Config {
+    pub fn into_config(self) -> Result<Config, APIError> {
+        let (gated_feed_forward, activation) = match self.feed_forward_proj.split_once('-') {
+            Some(("gated", activation)) => (true, activation),
+            _ => (false, self.feed_forward_proj.as_str()),
+        };
+        let activation = match activation {
+            "relu" => Activation::Relu,
+            // "gated-gelu" uses the tanh approximation, see `T5Config` of Transformers.
+            "gelu" if gated_feed_forward => Activation::GeluNew,
+            "gelu" => Activation::Gelu,
+            "gelu_new" => Activation::GeluNew,
+            "silu" => Activation::Silu,
+            _ => {
+                return Err(APIError::new(format!(
+                    "Unsupported feed-forward projection `{}`.",
+                    self.feed_forward_proj
+                )))
+            }
+        };
+        let pad_token_id = self.pad_token_id.unwrap_or(0);
+        Ok(Config {
+            hidden_size: self.d_model,
+            d_kv: self.d_kv,
+            d_ff: self.d_ff,
+            vocab_size: self.vocab_size,
+            num_encoder_layers: self.num_layers,
+            num_decoder_layers: self.num_decoder_layers.unwrap_or(self.num_layers),
+            num_heads: self.num_heads,
+            relative_attention_num_buckets: self.relative_attention_num_buckets,
+            relative_attention_max_distance: self.relative_attention_max_distance,
+            layer_norm_epsilon: self.layer_norm_epsilon,
+            gated_feed_forward,
+            activation,
+            tie_word_embeddings: self.tie_word_embeddings,
+            decoder_start_token_id: self.decoder_start_token_id.unwrap_or(pad_token_id),
+            quantization_config: self.quantization_config,
+        })
+    }
+}
+
+/// The activation of the feed-forward layers.
+#[derive(Clone, Copy)]
+pub enum Activation {
+    Relu,
+    Gelu,
+    /// GELU with the tanh approximation.
+    GeluNew,
+    Silu,
+}
+
+impl Activation {
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        match self {
+            Self::Relu => x.relu(),
+            Self::Gelu => x.gelu_erf(),
+            Self::GeluNew => x.gelu(),
+            Self::Silu => candle_nn::ops::silu(x),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Config {
+    pub hidden_size: usize,
+    pub d_kv: usize,
+    pub d_ff: usize,
+    pub vocab_size: usize,
+    pub num_encoder_layers: usize,
+    pub num_decoder_layers: usize,
+    pub num_heads: usize,
+    pub relative_attention_num_buckets: usize,
+    pub relative_attention_max_distance: usize,
+    pub layer_norm_epsilon: f64,
+    pub gated_feed_forward: bool,
+    pub activation: Activation,
+    pub tie_word_embeddings: bool,
+    pub decoder_start_token_id: u32,
+    pub quantization_config: Option<QuantizationConfig>,
+}
+
+impl ConfigLike for Config {
+    fn get_num_kv_heads(&self) -> usize {
+        self.num_heads
+    }
+    fn get_hidden_size(&self) -> usize {
+        self.hidden_size
+    }
+    /// The decoder layers, which hold the KV cache. The encoder runs once per prompt.
+    fn get_num_hidden_layers(&self) -> usize {
+        self.num_decoder_layers
+    }
+    fn get_num_attention_heads(&self) -> usize {
+        self.num_heads
+    }
+    fn get_vocab_size(&self) -> usize {
+        self.vocab_size
+    }
+    fn get_sliding_window(&self) -> Option<usize> {
+        None
+    }
+    fn get_head_size(&self) -> usize {
+        self.d_kv
+    }
+    fn get_decoder_start_token_id(&self) -> Option<u32> {
+        Some(self.decoder_start_token_id)
+    }
+}
+
+fn embedding(cfg: &Config, vb: VarBuilder) -> candle_core::Result<Embedding> {
+    let embeddings = vb.get((cfg.vocab_size, cfg.hidden_size), "weight")?;
+    Ok(Embedding::new(embeddings, cfg.hidden_size))
+}
+
+fn clamp_f16(x: Tensor) -> candle_core::Result<Tensor> {
+    if x.dtype() == DType::F16 {
+        x.clamp(-F16_CLAMP_VALUE, F16_CLAMP_VALUE)
+    } else {
+        Ok(x)
+    }
+}
+
+/// Softmax attention with an additive bias, computed in f32. T5 does not scale the scores.
+/// query: shape = [batch_size, num_heads, q_len, d_kv]
+/// key, value: shape = [batch_size, num_heads, k_len, d_kv]
+/// bias: shape = [batch_size or 1, num_heads, q_len, k_len] (f32)
+fn attention(
+    query: &Tensor,
+    key: &Tensor,
+    value: &Tensor,
+    bias: &Tensor,
+) -> candle_core::Result<Tensor> {
+    let dtype = query.dtype();
+    let query = query.to_dtype(DType::F32)?.contiguous()?;
+    let key = key.to_dtype(DType::F32)?.t()?.contiguous()?;
+    let value = value.to_dtype(DType::F32)?.contiguous()?;
+    let scores = query.matmul(&key)?.broadcast_add(bias)?;
+    let probs = candle_nn::ops::softmax_last_dim(&scores)?;
+    probs.matmul(&value)?.to_dtype(dtype)
+}
+
+/// The T5 LayerNorm, which only rescales.
+struct RmsNorm {
+    inner: candle_nn::RmsNorm,
+    span: tracing::Span,
+}
+
+impl RmsNorm {
+    fn load(size: usize, eps: f64, vb: VarBuilder) -> candle_core::Result<Self> {
+        let span = tracing::span!(tracing::Level::TRACE, "rms-norm");
+        let inner = candle_nn::rms_norm(size, eps, vb)?;
+        Ok(Self { inner, span })
+    }
+
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let _enter = self.span.enter();
+        self.inner.forward(x)
+    }
+}
+
+/// The learned relative position bias of T5. It is held by the first layer of a stack and added
+/// to the scores of all of its self-attention layers.
+struct RelativePositionBias {
+    embedding: Embedding,
+    num_buckets: usize,
+    max_distance: usize,
+    bidirectional: bool,
+}
+
+impl RelativePositionBias {
+    fn load(vb: VarBuilder, cfg: &Config, bidirectional: bool) -> candle_core::Result<Self> {
+        let num_buckets = cfg.relative_attention_num_buckets;
+        let embeddings = vb.get((num_buckets, cfg.num_heads), "weight")?;
+        Ok(Self {
+            embedding: Embedding::new(embeddings, cfg.num_heads),
+            num_buckets,
+            max_distance: cfg.relative_attention_max_distance,
+            bidirectional,
+        })
+    }
+
+    /// The bucket of a key at `relative_position` (key position - query position): exact for
+    /// small distances, logarithmic up to `max_distance`.
+    fn bucket(&self, relative_position: i64) -> u32 {
+        let mut num_buckets = self.num_buckets as i64;
+        let mut bucket = 0;
+        let distance = if self.bidirectional {
+            num_buckets /= 2;
+            if relative_position > 0 {
+                bucket += num_buckets;
+            }
+            relative_position.abs()
+        } else {
+            (-relative_position).max(0)
+        };
+        let max_exact = num_buckets / 2;
+        bucket += if distance < max_exact {
+            distance
+        } else {
+            let log_ratio = (distance as f64 / max_exact as f64).ln()
+                / (self.max_distance as f64 / max_exact as f64).ln();
+            let large = max_exact + (log_ratio * (num_buckets - max_exact) as f64) as i64;
+            large.min(num_buckets - 1)
+        };
+        bucket as u32
+    }
+
+    /// The bias of the queries at `query_positions` over the keys at positions 0..key_len,
+    /// shape = [1, num_heads, num_queries, key_len] (f32). Unless the bias is bidirectional, the
+    /// keys after a query are masked out.
+    fn forward(
+        &self,
+        query_positions: &[i64],
+        key_len: usize,
+        device: &Device,
+    ) -> candle_core::Result<Tensor> {
+        let mut buckets = Vec::with_capacity(query_positions.len() * key_len);
+        let mut mask = Vec::with_capacity(query_positions.len() * key_len);
+        for query_position in query_positions {
+            for key_position in 0..key_len as i64 {
+                buckets.push(self.bucket(key_position - query_position));
+                let masked = !self.bidirectional && key_position > *query_position;
+                mask.push(if masked { f32::NEG_INFINITY } else { 0. });
+            }
+        }
+        let shape = (query_positions.len(), key_len);
+        let buckets = Tensor::from_vec(buckets, shape, device)?;
+        let mask = Tensor::from_vec(mask, shape, device)?;
+        // [num_queries, key_len, num_heads] -> [num_heads, num_queries, key_len]
+        let bias = self
+            .embedding
+            .forward(&buckets)?
+            .permute((2, 0, 1))?
+            .to_dtype(DType::F32)?;
+        bias.broadcast_add(&mask)?.unsqueeze(0)
+    }
+}
+
+/// The keys attended by a segment of the decoder self-attention.
+enum SegmentKeys {
+    /// The new keys of the step at rows [start, start + len), i.e. the tokens of a prompt.
+    Step { start: usize, len: usize },
+    /// The cached keys of a generating sequence.
+    Cache {
+        block_table: Vec<usize>,
+        context_len: usize,
+    },
+}
+
+/// A sequence of the step as seen by the decoder self-attention: its query rows, its keys and
+/// the position bias between them, shape = [1, num_heads, query_len, key_len] (f32). The
+/// segments are shared by all decoder layers.
+struct SelfAttentionSegment {
+    query_start: usize,
+    query_len: usize,
+    keys: SegmentKeys,
+    bias: Tensor,
+}
+
+struct Attention {
+    q: QuantLinear,
+    k: QuantLinear,
+    v: QuantLinear,
+    o: QuantLinear,
+    num_heads: usize,
+    d_kv: usize,
+}
+
+impl Attention {
+    /// Bidirectional self-attention of the encoder over the padded prompts.
+    /// x: shape = [num_prompts, max_encoder_len, hidden_size]
+    /// bias: the position bias and padding mask, shape = [num_prompts, num_heads,
+    ///     max_encoder_len, max_encoder_len]
+    fn forward_encoder(&self, x: &Tensor, bias: &Tensor) -> candle_core::Result<Tensor> {
+        let (b_sz, seq_len, _) = x.dims3()?;
+        let split_heads = |x: Tensor| -> candle_core::Result<Tensor> {
+            x.reshape((b_sz, seq_len, self.num_heads, self.d_kv))?
+                .transpose(1, 2)
+        };
+        let q = split_heads(self.q.forward(x)?)?;
+        let k = split_heads(self.k.forward(x)?)?;
+        let v = split_heads(self.v.forward(x)?)?;
+        let y = attention(&q, &k, &v, bias)?;
+        let y = y.transpose(1, 2)?.reshape((b_sz, seq_len, ()))?;
+        self.o.forward(&y)
+    }
+
+    /// Causal self-attention of the decoder. The new keys and values of the step are written
+    /// into the paged cache, then each segment attends its keys with its position bias, which
+    /// the paged attention kernels cannot add.
+    /// x: shape = [batch_size, seq_len, hidden_size], the tokens of the step.
+    fn forward_decoder(
+        &self,
+        x: &Tensor,
+        segments: &[SelfAttentionSegment],
+        (key_cache, value_cache): (&Tensor, &Tensor),
+        input_metadata: &InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let (b_sz, seq_len, _) = try_api!(x.dims3());
+        let shape = ((), self.num_heads, self.d_kv);
+        let q = try_api!(try_api!(self.q.forward(x)).reshape(shape));
+        let k = try_api!(try_api!(self.k.forward(x)).reshape(shape));
+        let v = try_api!(try_api!(self.v.forward(x)).reshape(shape));
+
+        let slot_mapping = try_api!(input_metadata.slot_mapping.flatten_all());
+        let (mut key_cache, mut value_cache) = (key_cache.clone(), value_cache.clone());
+        unsafe {
+            reshape_and_cache(
+                k.clone(),
+                v.clone(),
+                &mut key_cache,
+                &mut value_cache,
+                slot_mapping,
+            )?
+        };
+
+        // [len, num_heads, d_kv] -> [1, num_heads, len, d_kv]
+        let heads_first =
+            |x: &Tensor| -> candle_core::Result<Tensor> { x.transpose(0, 1)?.unsqueeze(0) };
+        let mut outputs = Vec::with_capacity(segments.len());
+        for segment in segments {
+            let (key, value) = match &segment.keys {
+                SegmentKeys::Step { start, len } => (
+                    try_api!(k.narrow(0, *start, *len)),
+                    try_api!(v.narrow(0, *start, *len)),
+                ),
+                SegmentKeys::Cache {
+                    block_table,
+                    context_len,
+                } => gather_cached_kv(&key_cache, &value_cache, block_table, *context_len)?,
+            };
+            let query = try_api!(q.narrow(0, segment.query_start, segment.query_len));
+            let y = try_api!(attention(
+                &try_api!(heads_first(&query)),
+                &try_api!(heads_first(&key)),
+                &try_api!(heads_first(&value)),
+                &segment.bias,
+            ));
+            let y = try_api!(try_api!(y.squeeze(0)).transpose(0, 1));
+            outputs.push(try_api!(y.reshape((segment.query_len, ()))));
+        }
+        let y = try_api!(try_api!(Tensor::cat(&outputs, 0)).reshape((b_sz, seq_len, ())));
+        self.o.forward(&y).map_err(APIError::from)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config) -> candle_core::Result<Self> {
+        let inner_dim = cfg.num_heads * cfg.d_kv;
+        Ok(Self {
+            q: linear(cfg.hidden_size, inner_dim, vb.pp("q"))?,
+            k: linear(cfg.hidden_size, inner_dim, vb.pp("k"))?,
+            v: linear(cfg.hidden_size, inner_dim, vb.pp("v"))?,
+            o: linear(inner_dim, cfg.hidden_size, vb.pp("o"))?,
+            num_heads: cfg.num_heads,
+            d_kv: cfg.d_kv,
+        })
+    }
+}
+
+/// The attention of the decoder over the encoder outputs, which are kept in the cross blocks of
+/// the paged cache, see `PagedAttention::forward_cross_attention`. It has no position bias.
+struct CrossAttention {
+    q: QuantLinear,
+    k: QuantLinear,
+    v: QuantLinear,
+    o: QuantLinear,
+    attn: PagedAttention,
+}
+
+impl CrossAttention {
+    /// encoder_hidden: the encoder outputs of the prompts of the step, if any.
+    fn forward(
+        &mut self,
+        x: &Tensor,
+        encoder_hidden: Option<&Tensor>,
+        (key_cache, value_cache): (&Tensor, &Tensor),
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let q = try_api!(self.q.forward(x));
+        let (k, v) = match encoder_hidden {
+            Some(encoder_hidden) => (
+                Some(try_api!(self.k.forward(encoder_hidden))),
+                Some(try_api!(self.v.forward(encoder_hidden))),
+            ),
+            None => (None, None),
+        };
+        let y = self.attn.forward_cross_attention(
+            q,
+            k,
+            v,
+            key_cache.clone(),
+            value_cache.clone(),
+            input_metadata,
+        )?;
+        self.o.forward(&y).map_err(APIError::from)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config) -> Result<Self, APIError> {
+        let inner_dim = cfg.num_heads * cfg.d_kv;
+        Ok(Self {
+            q: try_api!(linear(cfg.hidden_size, inner_dim, vb.pp("q"))),
+            k: try_api!(linear(cfg.hidden_size, inner_dim, vb.pp("k"))),
+            v: try_api!(linear(cfg.hidden_size, inner_dim, vb.pp("v"))),
+            o: try_api!(linear(inner_dim, cfg.hidden_size, vb.pp("o"))),
+            attn: PagedAttention::new(
+                cfg.num_heads,
+                cfg.d_kv,
+                1.,
+                Some(cfg.num_heads),
+                None,
+                vb.device().clone(),
+                None,
+            )?,
+        })
+    }
+}
+
+struct FeedForward {
+    /// `wi`, or `wi_0` of a gated layer.
+    wi: QuantLinear,
+    /// The linear gate of a gated layer.
+    wi_1: Option<QuantLinear>,
+    wo: QuantLinear,
+    activation: Activation,
+    span: tracing::Span,
+}
+
+impl FeedForward {
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let _enter = self.span.enter();
+        let h = self.activation.forward(&self.wi.forward(x)?)?;
+        let h = match &self.wi_1 {
+            Some(wi_1) => (h * wi_1.forward(x)?)?,
+            None => h,
+        };
+        self.wo.forward(&h)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config) -> candle_core::Result<Self> {
+        let span = tracing::span!(tracing::Level::TRACE, "feed-forward");
+        let (h_size, ff_size) = (cfg.hidden_size, cfg.d_ff);
+        let (wi, wi_1) = if cfg.gated_feed_forward {
+            (
+                linear(h_size, ff_size, vb.pp("wi_0"))?,
+                Some(linear(h_size, ff_size, vb.pp("wi_1"))?),
+            )
+        } else {
+            (linear(h_size, ff_size, vb.pp("wi"))?, None)
+        };
+        Ok(Self {
+            wi,
+            wi_1,
+            wo: linear(ff_size, h_size, vb.pp("wo"))?,
+            activation: cfg.activation,
+            span,
+        })
+    }
+}
+
+struct EncoderBlock {
+    ln_1: RmsNorm,
+    attn: Attention,
+    ln_2: RmsNorm,
+    ff: FeedForward,
+    span: tracing::Span,
+}
+
+impl EncoderBlock {
+    fn forward(&self, x: &Tensor, bias: &Tensor) -> candle_core::Result<Tensor> {
+        let _enter = self.span.enter();
+        let x = clamp_f16((x + self.attn.forward_encoder(&self.ln_1.forward(x)?, bias)?)?)?;
+        clamp_f16((&x + self.ff.forward(&self.ln_2.forward(&x)?)?)?)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config) -> candle_core::Result<Self> {
+        let span = tracing::span!(tracing::Level::TRACE, "encoder-block");
+        let eps = cfg.layer_norm_epsilon;
+        Ok(Self {
+            ln_1: RmsNorm::load(cfg.hidden_size, eps, vb.pp("layer.0.layer_norm"))?,
+            attn: Attention::load(vb.pp("layer.0.SelfAttention"), cfg)?,
+            ln_2: RmsNorm::load(cfg.hidden_size, eps, vb.pp("layer.1.layer_norm"))?,
+            ff: FeedForward::load(vb.pp("layer.1.DenseReluDense"), cfg)?,
+            span,
+        })
+    }
+}
+
+struct DecoderBlock {
+    ln_1: RmsNorm,
+    self_attn: Attention,
+    ln_2: RmsNorm,
+    cross_attn: CrossAttention,
+    ln_3: RmsNorm,
+    ff: FeedForward,
+    span: tracing::Span,
+}
+
+impl DecoderBlock {
+    fn forward(
+        &mut self,
+        x: &Tensor,
+        encoder_hidden: Option<&Tensor>,
+        segments: &[SelfAttentionSegment],
+        cache: (&Tensor, &Tensor),
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let _enter = self.span.enter();
+        let h = try_api!(self.ln_1.forward(x));
+        let h = self
+            .self_attn
+            .forward_decoder(&h, segments, cache, input_metadata)?;
+        let x = try_api!(clamp_f16(try_api!(x + h)));
+        let h = try_api!(self.ln_2.forward(&x));
+        let h = self
+            .cross_attn
+            .forward(&h, encoder_hidden, cache, input_metadata)?;
+        let x = try_api!(clamp_f16(try_api!(x + h)));
+        let h = try_api!(self.ff.forward(&try_api!(self.ln_3.forward(&x))));
+        clamp_f16(try_api!(x + h)).map_err(APIError::from)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config) -> Result<Self, APIError> {
+        let span = tracing::span!(tracing::Level::TRACE, "decoder-block");
+        let (size, eps) = (cfg.hidden_size, cfg.layer_norm_epsilon);
+        Ok(Self {
+            ln_1: try_api!(RmsNorm::load(size, eps, vb.pp("layer.0.layer_norm"))),
+            self_attn: try_api!(Attention::load(vb.pp("layer.0.SelfAttention"), cfg)),
+            ln_2: try_api!(RmsNorm::load(size, eps, vb.pp("layer.1.layer_norm"))),
+            cross_attn: CrossAttention::load(vb.pp("layer.1.EncDecAttention"), cfg)?,
+            ln_3: try_api!(RmsNorm::load(size, eps, vb.pp("layer.2.layer_norm"))),
+            ff: try_api!(FeedForward::load(vb.pp("layer.2.DenseReluDense"), cfg)),
+            span,
+        })
+    }
+}
+
+pub struct T5 {
+    shared: Embedding,
+    encoder: Vec<EncoderBlock>,
+    encoder_bias: RelativePositionBias,
+    encoder_ln_f: RmsNorm,
+    decoder: Vec<DecoderBlock>,
+    decoder_bias: RelativePositionBias,
+    decoder_ln_f: RmsNorm,
+    lm_head: QuantLinear,
+    cfg: Config,
+}
+
+impl T5 {
+    /// Encode the prompts of the step, whose encoder prompts are given by `InputMetadata::cross`.
+    /// Returns their encoder outputs, shape = [num_prompts, max_encoder_len, hidden_size], or
+    /// None if the step has no prompts.
+    fn encode(&self, input_metadata: &InputMetadata) -> Result<Option<Tensor>, APIError> {
+        let Some(cross) = &input_metadata.cross else {
+            return Ok(None);
+        };
+        let Some(input_ids) = &cross.input_ids else {
+            return Ok(None);
+        };
+        let seq_len = cross.max_encoder_len;
+        let num_prompts = try_api!(input_ids.dim(0)) / seq_len;
+        let device = input_ids.device();
+        let input_ids = try_api!(input_ids.reshape((num_prompts, seq_len)));
+
+        let positions = (0..seq_len as i64).collect::<Vec<_>>();
+        let bias = try_api!(self.encoder_bias.forward(&positions, seq_len, device));
+        // Mask out the padding keys of each prompt.
+        let mask = cross.encoder_lens[..num_prompts]
+            .iter()
+            .flat_map(|len| {
+                (0..seq_len).map(move |i| if i < *len { 0. } else { f32::NEG_INFINITY })
+            })
+            .collect::<Vec<_>>();
+        let mask = try_api!(Tensor::from_vec(mask, (num_prompts, 1, 1, seq_len), device));
+        let bias = try_api!(bias.broadcast_add(&mask));
+
+        let mut x = try_api!(self.shared.forward(&input_ids));
+        for block in &self.encoder {
+            x = try_api!(block.forward(&x, &bias));
+        }
+        Ok(Some(try_api!(self.encoder_ln_f.forward(&x))))
+    }
+
+    /// The segments of the decoder self-attention: each prompt attends its own tokens, each
+    /// generating sequence its cached context.
+    fn self_attention_segments(
+        &self,
+        positions: &Tensor,
+        key_cache: &Tensor,
+        input_metadata: &InputMetadata,
+    ) -> Result<Vec<SelfAttentionSegment>, APIError> {
+        let device = positions.device();
+        let positions = try_api!(try_api!(positions.flatten_all()).to_vec1::<i64>());
+        let mut segments = Vec::new();
+        let mut offset = 0;
+        if let Some(prompt) = &input_metadata.prompt {
+            if prompt.cached_context.is_some() {
+                return Err(APIError::new_str(
+                    "T5 does not support prompts with cached tokens.",
+                ));
+            }
+            for prompt_len in &prompt.prompt_lens {
+                let query_positions = &positions[offset..offset + prompt_len];
+                segments.push(SelfAttentionSegment {
+                    query_start: offset,
+                    query_len: *prompt_len,
+                    keys: SegmentKeys::Step {
+                        start: offset,
+                        len: *prompt_len,
+                    },
+                    bias: try_api!(self
+                        .decoder_bias
+                        .forward(query_positions, *prompt_len, device)),
+                });
+                offset += prompt_len;
+            }
+        }
+        if let Some(decode) = &input_metadata.decode {
+            let (block_size, _) = key_cache_block_dims(key_cache);
+            let block_tables = try_api!(decode.block_tables.to_vec2::<i64>());
+            let context_lens = try_api!(decode.context_lens.to_vec1::<i64>());
+            let num_query_tokens = (positions.len() - offset) / context_lens.len().max(1);
+            for (block_table, context_len) in zip(block_tables, context_lens) {
+                let context_len = context_len as usize;
+                let block_table = block_table[..context_len.div_ceil(block_size)]
+                    .iter()
+                    .map(|block| *block as usize)
+                    .collect();
+                let query_positions = &positions[offset..offset + num_query_tokens];
+                segments.push(SelfAttentionSegment {
+                    query_start: offset,
+                    query_len: num_query_tokens,
+                    keys: SegmentKeys::Cache {
+                        block_table,
+                        context_len,
+                    },
+                    bias: try_api!(self
+                        .decoder_bias
+                        .forward(query_positions, context_len, device)),
+                });
+                offset += num_query_tokens;
+            }
+        }
+        Ok(segments)
+    }
+
+    pub fn forward(
+        &mut self,
+        x: &Tensor,
+        positions: &Tensor,
+        kv_caches: Option<&KVCacheManager>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let (b_sz, seq_len) = try_api!(x.dims2());
+        let logits_indices = input_metadata.logits_indices(b_sz * seq_len, x.device())?;
+        // The encoder outputs are only attended from the cross blocks of the cache.
+        let Some(kv_caches) = kv_caches else {
+            return Err(APIError::new_str("T5 requires the KV cache."));
+        };
+        if input_metadata.kv_cache_dtype != "auto" {
+            return Err(APIError::new_str(
+                "T5 requires an unquantized KV cache, as its self-attention reads the cache.",
+            ));
+        }
+        let encoder_hidden = self.encode(input_metadata)?;
+        let segments = self.self_attention_segments(
+            positions,
+            kv_caches.layer(0).key_value().0,
+            input_metadata,
+        )?;
+
+        let mut x = try_api!(self.shared.forward(x));
+        for (layer, block) in zip(kv_caches.layers(), &mut self.decoder) {
+            x = block.forward(
+                &x,
+                encoder_hidden.as_ref(),
+                &segments,
+                layer.key_value(),
+                input_metadata,
+            )?;
+        }
+        let x = try_api!(self.decoder_ln_f.forward(&x));
+        let x = try_api!(try_api!(x.flatten_to(1)).index_select(&logits_indices, 0));
+        // The decoder outputs are rescaled for the LM head tied to the embedding.
+        let x = if self.cfg.tie_word_embeddings {
+            try_api!(x * (self.cfg.hidden_size as f64).powf(-0.5))
+        } else {
+            x
+        };
+        let logits = try_api!(self.lm_head.forward(&x));
+        logits.to_dtype(DType::F32).map_err(APIError::from)
+    }
+
+    pub fn load(
+        vb: VarBuilder,
+        cfg: &Config,
+        _dtype: DType,
+        _device: &Device,
+    ) -> Result<Self, APIError> {
+        let shared = try_api!(embedding(cfg, vb.pp("shared")));
+        let lm_head = if cfg.tie_word_embeddings {
+            QuantLinear::Unquantized(Linear::from_weights(shared.embeddings().clone(), None))
+        } else {
+            try_api!(linear(cfg.hidden_size, cfg.vocab_size, vb.pp("lm_head")))
+        };
+        let bias_prefix = "block.0.layer.0.SelfAttention.relative_attention_bias";
+
+        let vb_e = vb.pp("encoder");
+        let encoder_bias = try_api!(RelativePositionBias::load(vb_e.pp(bias_prefix), cfg, true));
+        let encoder = (0..cfg.num_encoder_layers)
+            .map(|i| EncoderBlock::load(vb_e.pp(&format!("block.{i}")), cfg))
+            .collect::<candle_core::Result<Vec<_>>>();
+        let encoder = try_api!(encoder);
+        let encoder_ln_f = try_api!(RmsNorm::load(
+            cfg.hidden_size,
+            cfg.layer_norm_epsilon,
+            vb_e.pp("final_layer_norm")
+        ));
+
+        let vb_d = vb.pp("decoder");
+        let decoder_bias = try_api!(RelativePositionBias::load(vb_d.pp(bias_prefix), cfg, false));
+        let decoder = (0..cfg.num_decoder_layers)
+            .map(|i| DecoderBlock::load(vb_d.pp(&format!("block.{i}")), cfg))
+            .collect::<Result<Vec<_>, _>>()?;
+        let decoder_ln_f = try_api!(RmsNorm::load(
+            cfg.hidden_size,
+            cfg.layer_norm_epsilon,
+            vb_d.pp("final_layer_norm")
+        ));
+
+        Ok(Self {
+            shared,
+            encoder,
+            encoder_bias,
+            encoder_ln_f,
+            decoder,
+            decoder_bias,
+            decoder_ln_f,
+            lm_head,
+            cfg: cfg.clone(),
+        })
+    }
+
+    pub fn get_config(&self) -> &Config {
+        &self.cfg
+    }
+}
+
+impl PagedAttentionModel for T5 {
+    fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        positions: &Tensor,
+        kv_caches: Option<&KVCacheManager>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        T5::forward(self, input_ids, positions, kv_caches, input_metadata)
+    }
+
+    fn get_config(&self) -> Box<dyn ConfigLike> {
+        Box::new(self.cfg.clone())
+    }
+}
+
+impl LoadablePagedAttentionModel for T5 {
+    fn load_from_config(
+        config: &[u8],
+        vb: VarBuilder,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self, APIError> {
+        let config: T5Config = try_api!(serde_json::from_slice(config));
+        T5::load(vb, &config.into_config()?, dtype, device)
+    }
+}
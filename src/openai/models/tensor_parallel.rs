@@ -0,0 +1,155 @@
+//! Weight sharding across devices within the process: the attention heads and the MLP of each
+//! layer are split over the CUDA devices following the device of the model, one rank per device,
+//! with the Megatron layout of tensor parallelism. The query, key, value, gate and up projections
+//! are column-parallel (split by output), the output and down projections row-parallel (split by
+//! input), so each rank computes its heads and its slice of the MLP on its own and the partial
+//! outputs of the ranks are summed on the first device. Each rank keeps the KV cache of its heads
+//! in a pool on its device, see `KVCacheManager::rank`. The embeddings, norms and output layer
+//! stay on the first device.
+//!
+//! This is not tensor parallelism for speed: the ranks run one after another, and their partial
+//! outputs are copied to the first device like any device to device copy, without a collective
+//! such as an NCCL all-reduce. It serves models whose weights and KV cache do not fit a single
+//! device, a step taking about as long as on one device plus the copies.
+
+use std::{collections::HashMap, path::PathBuf};
+
+use candle_core::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+
+use crate::{
+    openai::{pipelines::LoadOptions, responses::APIError},
+    paged_attention::input_metadata::InputMetadata,
+    try_api,
+};
+
+use super::{
+    weights::{apply_load_options, load_shards},
+    ModelWeights,
+};
+
+/// The devices of `num_devices` tensor-parallel ranks or pipeline stages: `device` followed by
+/// the next CUDA devices. On the CPU, e.g. for the swap space, every rank is on the CPU.
+pub fn rank_devices(device: &Device, num_devices: usize) -> Result<Vec<Device>, APIError> {
+    match device {
+        _ if num_devices == 1 => Ok(vec![device.clone()]),
+        Device::Cuda(dev) => (0..num_devices)
+            .map(|rank| {
+                if rank == 0 {
+                    Ok(device.clone())
+                } else {
+                    Device::new_cuda(dev.ordinal() + rank).map_err(APIError::from)
+                }
+            })
+            .collect(),
+        Device::Cpu => Ok(vec![Device::Cpu; num_devices]),
+        _ => Err(APIError::new_str(
+            "Splitting a model over several devices requires CUDA devices.",
+        )),
+    }
+}
+
+/// The dimension a weight is split along, by its name: the output of the column-parallel
+/// projections and the input of the row-parallel ones. None for the weights every rank needs,
+/// which stay on the first device.
+fn shard_dim(name: &str) -> Option<usize> {
+    const COLUMN_PARALLEL: [&str; 5] = ["q_proj", "k_proj", "v_proj", "gate_proj", "up_proj"];
+    const ROW_PARALLEL: [&str; 2] = ["o_proj", "down_proj"];
+    let layer = name.strip_suffix(".weight")?.rsplit('.').next()?;
+    if COLUMN_PARALLEL.contains(&layer) {
+        Some(0)
+    } else if ROW_PARALLEL.contains(&layer) {
+        Some(1)
+    } else {
+        None
+    }
+}
+
+/// Load an unquantized checkpoint split over `devices`, one rank per device. Returns the
+/// weights of the first rank with the weights which are not split, and those of the other ranks
+/// in `ModelWeights::ranks`.
+pub fn load_sharded_safetensors(
+    filenames: &[PathBuf],
+    dtype: DType,
+    devices: &[Device],
+    options: &LoadOptions,
+) -> Result<ModelWeights<'static>, APIError> {
+    let num_ranks = devices.len();
+    let tensors = load_shards(filenames, |safetensors, name| {
+        let tensor = try_api!(try_api!(safetensors.load(name, &Device::Cpu)).to_dtype(dtype));
+        let Some(dim) = shard_dim(name) else {
+            return Ok(vec![(
+                format!("0.{name}"),
+                try_api!(tensor.to_device(&devices[0])),
+            )]);
+        };
+        let size = try_api!(tensor.dim(dim));
+        if size % num_ranks != 0 {
+            return Err(APIError::new(format!(
+                "`{name}` cannot be split evenly over {num_ranks} devices."
+            )));
+        }
+        let shard_size = size / num_ranks;
+        devices
+            .iter()
+            .enumerate()
+            .map(|(rank, device)| {
+                let shard = try_api!(tensor.narrow(dim, rank * shard_size, shard_size));
+                Ok((format!("{rank}.{name}"), try_api!(shard.to_device(device))))
+            })
+            .collect()
+    })?;
+    let mut ranks = vec![HashMap::new(); num_ranks];
+    for (name, tensor) in tensors {
+        let (rank, name) = name.split_once('.').unwrap();
+        ranks[rank.parse::<usize>().unwrap()].insert(name.to_string(), tensor);
+    }
+    let mut ranks = ranks
+        .into_iter()
+        .zip(devices)
+        .map(|(mut tensors, device)| {
+            apply_load_options(&mut tensors, options)?;
+            Ok(VarBuilder::from_tensors(tensors, dtype, device))
+        })
+        .collect::<Result<Vec<_>, APIError>>()?;
+    let vb = ranks.remove(0);
+    Ok(ModelWeights {
+        ranks,
+        ..ModelWeights::from(vb)
+    })
+}
+
+/// Run the row-parallel `forward` of each rank on `x` and the metadata of the step on the
+/// device of the rank, and sum the partial outputs on the device of `x`. The ranks run one after
+/// another, as copying `x` to the device of a rank waits for the work queued before.
+pub(crate) fn sum_over_ranks<T>(
+    x: &Tensor,
+    ranks: &mut [T],
+    input_metadata: &mut InputMetadata,
+    mut forward: impl FnMut(usize, &mut T, &Tensor, &mut InputMetadata) -> Result<Tensor, APIError>,
+) -> Result<Tensor, APIError> {
+    let Some((first, others)) = ranks.split_first_mut() else {
+        return Err(APIError::new_str("A layer needs at least one rank."));
+    };
+    if others.is_empty() {
+        return forward(0, first, x, input_metadata);
+    }
+    let mut rank_metadata = std::mem::take(&mut input_metadata.ranks);
+    let result = (|| {
+        if rank_metadata.len() != others.len() {
+            return Err(APIError::new_str(
+                "The metadata of the tensor-parallel ranks is missing.",
+            ));
+        }
+        let mut sum = forward(0, first, x, input_metadata)?;
+        for (rank, (layer, metadata)) in others.iter_mut().zip(&mut rank_metadata).enumerate() {
+            let x = try_api!(x.to_device(metadata.slot_mapping.device()));
+            let partial = forward(rank + 1, layer, &x, metadata)?;
+            let partial = try_api!(partial.to_device(sum.device()));
+            sum = try_api!(sum + partial);
+        }
+        Ok(sum)
+    })();
+    input_metadata.ranks = rank_metadata;
+    result
+}
@@ -0,0 +1,98 @@
+//! Parallel loading of sharded safetensors checkpoints. All shards are memory-mapped up front and
+//! their tensors are read, converted and uploaded to the device by a pool of threads, reporting
+//! the progress per shard.
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+use candle_core::{safetensors::MmapedSafetensors, DType, Device, Tensor};
+use candle_nn::VarBuilder;
+use rayon::prelude::*;
+
+use crate::{
+    openai::{pipelines::LoadOptions, responses::APIError},
+    try_api,
+};
+
+use super::{lora::apply_lora_adapter, quantization::mark_in_situ_quantization};
+
+/// Load the tensors of the shards `filenames` in parallel: `load` is called once for each tensor
+/// name with the shard holding it, and returns the tensors to store for it under their names.
+pub fn load_shards<F>(filenames: &[PathBuf], load: F) -> Result<HashMap<String, Tensor>, APIError>
+where
+    F: Fn(&MmapedSafetensors, &str) -> Result<Vec<(String, Tensor)>, APIError> + Sync,
+{
+    let start = Instant::now();
+    let shards = filenames
+        .iter()
+        .map(|filename| Ok(try_api!(unsafe { MmapedSafetensors::new(filename) })))
+        .collect::<Result<Vec<_>, APIError>>()?;
+    let names = shards
+        .iter()
+        .enumerate()
+        .flat_map(|(shard, safetensors)| {
+            safetensors
+                .tensors()
+                .into_iter()
+                .map(move |(name, _)| (shard, name))
+        })
+        .collect::<Vec<_>>();
+    let remaining = shards
+        .iter()
+        .map(|safetensors| AtomicUsize::new(safetensors.tensors().len()))
+        .collect::<Vec<_>>();
+    let num_loaded_shards = AtomicUsize::new(0);
+    let tensors = names
+        .par_iter()
+        .map(|(shard, name)| {
+            let tensors = load(&shards[*shard], name)?;
+            if remaining[*shard].fetch_sub(1, Ordering::SeqCst) == 1 {
+                let num_loaded = num_loaded_shards.fetch_add(1, Ordering::SeqCst) + 1;
+                println!(
+                    "Loaded shard {num_loaded}/{} ({}) after {:.1}s.",
+                    shards.len(),
+                    filenames[*shard].display(),
+                    start.elapsed().as_secs_f64()
+                );
+            }
+            Ok(tensors)
+        })
+        .collect::<Result<Vec<_>, APIError>>()?;
+    Ok(tensors.into_iter().flatten().collect())
+}
+
+/// Load an unquantized checkpoint, converting every tensor to `dtype` on the CPU before uploading
+/// it to `device`.
+pub fn load_safetensors(
+    filenames: &[PathBuf],
+    dtype: DType,
+    device: &Device,
+    options: &LoadOptions,
+) -> Result<VarBuilder<'static>, APIError> {
+    let mut tensors = load_shards(filenames, |safetensors, name| {
+        let tensor = try_api!(safetensors.load(name, &Device::Cpu));
+        let tensor = try_api!(try_api!(tensor.to_dtype(dtype)).to_device(device));
+        Ok(vec![(name.to_string(), tensor)])
+    })?;
+    apply_load_options(&mut tensors, options)?;
+    Ok(VarBuilder::from_tensors(tensors, dtype, device))
+}
+
+/// Apply the in-situ quantization and the LoRA adapter of `options` to the loaded `tensors` of a
+/// model, which the linear layers pick up while loading, see `quantization::linear_b`.
+pub fn apply_load_options(
+    tensors: &mut HashMap<String, Tensor>,
+    options: &LoadOptions,
+) -> Result<(), APIError> {
+    if let Some(quantization) = options.in_situ_quantization {
+        mark_in_situ_quantization(tensors, quantization)?;
+    }
+    if let Some((adapter, mode)) = &options.lora_adapter {
+        apply_lora_adapter(tensors, adapter, *mode)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,257 @@
+//! Image inputs of vision-language models (LLaVA, Qwen-VL). Each image of a request is added to
+//! the prompt as a placeholder token, which is expanded to one image token per image embedding,
+//! so that the image takes its share of the KV cache like any other prompt tokens. The pixels are
+//! preprocessed on the host and passed along with the request, see `InputMetadata::images`.
+
+use std::{collections::HashMap, io::Read, iter::zip, time::Duration};
+
+use base64::Engine;
+use candle_core::{DType, Device, Tensor};
+use image::{imageops::FilterType, DynamicImage};
+use serde::Deserialize;
+use tokenizers::Encoding;
+
+use super::responses::APIError;
+use crate::try_api;
+
+/// The normalization of the OpenAI CLIP vision towers, used unless the preprocessor config of a
+/// model gives its own.
+pub const OPENAI_CLIP_MEAN: [f32; 3] = [0.481_454_66, 0.457_827_5, 0.408_210_73];
+pub const OPENAI_CLIP_STD: [f32; 3] = [0.268_629_54, 0.261_302_58, 0.275_777_11];
+
+/// Images fetched from a URL may not be larger than this.
+const MAX_IMAGE_BYTES: u64 = 20 << 20;
+const IMAGE_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How an image is resized before it is cropped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageResize {
+    /// Scale the shortest edge to this size, keeping the aspect ratio.
+    ShortestEdge(u32),
+    /// Scale to exactly this size, ignoring the aspect ratio.
+    Exact { width: u32, height: u32 },
+}
+
+/// The preprocessing of the images of a vision tower: resize, center crop, rescale to [0, 1] and
+/// normalize each channel.
+#[derive(Clone, Debug)]
+pub struct ImageProcessor {
+    pub resize: ImageResize,
+    /// (width, height) of the center crop, if any.
+    pub crop: Option<(u32, u32)>,
+    pub mean: [f32; 3],
+    pub std: [f32; 3],
+}
+
+/// A size in `preprocessor_config.json`, given as a number or by its edges.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawSize {
+    Edge(u32),
+    Edges {
+        shortest_edge: Option<u32>,
+        height: Option<u32>,
+        width: Option<u32>,
+    },
+}
+
+/// The parts of the `preprocessor_config.json` of a CLIP image processor which are used.
+#[derive(Deserialize)]
+struct RawImageProcessor {
+    #[serde(default)]
+    size: Option<RawSize>,
+    #[serde(default)]
+    crop_size: Option<RawSize>,
+    #[serde(default = "default_true")]
+    do_center_crop: bool,
+    #[serde(default)]
+    image_mean: Option<[f32; 3]>,
+    #[serde(default)]
+    image_std: Option<[f32; 3]>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl ImageProcessor {
+    /// Squash the images to `image_size` x `image_size` without cropping, e.g. for Qwen-VL.
+    pub fn square(image_size: u32) -> Self {
+        Self {
+            resize: ImageResize::Exact {
+                width: image_size,
+                height: image_size,
+            },
+            crop: None,
+            mean: OPENAI_CLIP_MEAN,
+            std: OPENAI_CLIP_STD,
+        }
+    }
+
+    /// The processor described by the `preprocessor_config.json` of a model.
+    pub fn from_preprocessor_config(config: &[u8]) -> Result<Self, APIError> {
+        let raw: RawImageProcessor = try_api!(serde_json::from_slice(config));
+        let resize = match raw.size {
+            Some(RawSize::Edge(edge))
+            | Some(RawSize::Edges {
+                shortest_edge: Some(edge),
+                ..
+            }) => ImageResize::ShortestEdge(edge),
+            Some(RawSize::Edges {
+                height: Some(height),
+                width: Some(width),
+                ..
+            }) => ImageResize::Exact { width, height },
+            _ => {
+                return Err(APIError::new_str(
+                    "The preprocessor config gives no image size.",
+                ))
+            }
+        };
+        let crop = match (raw.do_center_crop, raw.crop_size) {
+            (true, Some(RawSize::Edge(edge))) => Some((edge, edge)),
+            (
+                true,
+                Some(RawSize::Edges {
+                    height: Some(height),
+                    width: Some(width),
+                    ..
+                }),
+            ) => Some((width, height)),
+            _ => None,
+        };
+        Ok(Self {
+            resize,
+            crop,
+            mean: raw.image_mean.unwrap_or(OPENAI_CLIP_MEAN),
+            std: raw.image_std.unwrap_or(OPENAI_CLIP_STD),
+        })
+    }
+
+    /// The normalized pixels of `image`, shape = [3, height, width] (f32, on the CPU).
+    pub fn preprocess(&self, image: &DynamicImage) -> Result<Tensor, APIError> {
+        let image = match self.resize {
+            ImageResize::ShortestEdge(edge) => {
+                let scale = edge as f32 / image.width().min(image.height()) as f32;
+                let width = ((image.width() as f32 * scale).round() as u32).max(edge);
+                let height = ((image.height() as f32 * scale).round() as u32).max(edge);
+                image.resize_exact(width, height, FilterType::CatmullRom)
+            }
+            ImageResize::Exact { width, height } => {
+                image.resize_exact(width, height, FilterType::CatmullRom)
+            }
+        };
+        let image = match self.crop {
+            Some((width, height)) if image.width() >= width && image.height() >= height => {
+                let x = (image.width() - width) / 2;
+                let y = (image.height() - height) / 2;
+                image.crop_imm(x, y, width, height)
+            }
+            Some((width, height)) => image.resize_exact(width, height, FilterType::CatmullRom),
+            None => image,
+        };
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let pixels = image.to_rgb8().into_raw();
+        let pixels = try_api!(Tensor::from_vec(pixels, (height, width, 3), &Device::Cpu));
+        let pixels = try_api!(try_api!(pixels.permute((2, 0, 1))).to_dtype(DType::F32));
+        let mean = try_api!(try_api!(Tensor::new(&self.mean, &Device::Cpu)).reshape((3, 1, 1)));
+        let std = try_api!(try_api!(Tensor::new(&self.std, &Device::Cpu)).reshape((3, 1, 1)));
+        let pixels = try_api!(try_api!(pixels / 255.).broadcast_sub(&mean));
+        pixels.broadcast_div(&std).map_err(APIError::from)
+    }
+}
+
+/// Load the image of an `image_url` content part: a base64 `data:` URL or an HTTP(S) URL.
+pub fn load_image(url: &str) -> Result<DynamicImage, APIError> {
+    let bytes = if let Some(data) = url.strip_prefix("data:") {
+        let Some((_, data)) = data.split_once(";base64,") else {
+            return Err(APIError::new_str("Image data URLs must be base64-encoded."));
+        };
+        try_api!(base64::engine::general_purpose::STANDARD.decode(data))
+    } else if url.starts_with("http://") || url.starts_with("https://") {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(IMAGE_FETCH_TIMEOUT)
+            .build();
+        let response = try_api!(agent.get(url).call());
+        let mut bytes = Vec::new();
+        try_api!(response
+            .into_reader()
+            .take(MAX_IMAGE_BYTES)
+            .read_to_end(&mut bytes));
+        bytes
+    } else {
+        return Err(APIError::new_str(
+            "Image URLs must be `data:` or HTTP(S) URLs.",
+        ));
+    };
+    image::load_from_memory(&bytes).map_err(APIError::from)
+}
+
+/// The image inputs of a vision-language pipeline.
+#[derive(Clone)]
+pub struct ImageInputs {
+    /// The text added to the prompt for each image, `{n}` is replaced with the number of the image
+    /// in the request, starting at 1.
+    pub template: &'static str,
+    /// The token of `template` which stands for the image embeddings.
+    pub placeholder: &'static str,
+    pub placeholder_id: u32,
+    /// Number of embeddings each image is encoded to. The placeholder of an image is expanded to
+    /// this many image tokens.
+    pub num_tokens: usize,
+    pub processor: ImageProcessor,
+}
+
+impl ImageInputs {
+    /// The prompt text of the `index`-th image of a request.
+    pub fn prompt(&self, index: usize) -> String {
+        self.template.replace("{n}", &(index + 1).to_string())
+    }
+
+    /// Expand each placeholder of the tokenized prompt of a request with `num_images` images
+    /// to `num_tokens` image tokens.
+    pub fn expand_placeholders(
+        &self,
+        encoding: &Encoding,
+        num_images: usize,
+    ) -> Result<Encoding, APIError> {
+        let repeats = encoding
+            .get_ids()
+            .iter()
+            .map(|id| {
+                if *id == self.placeholder_id {
+                    self.num_tokens
+                } else {
+                    1
+                }
+            })
+            .collect::<Vec<_>>();
+        let num_placeholders = encoding
+            .get_ids()
+            .iter()
+            .filter(|id| **id == self.placeholder_id)
+            .count();
+        if num_placeholders != num_images {
+            return Err(APIError::new(format!(
+                "The prompt holds {num_placeholders} image placeholders `{}` for {num_images} images, the placeholder may not be used in text.",
+                self.placeholder
+            )));
+        }
+        fn expand<T: Clone>(x: &[T], repeats: &[usize]) -> Vec<T> {
+            zip(x, repeats)
+                .flat_map(|(x, n)| std::iter::repeat(x.clone()).take(*n))
+                .collect()
+        }
+        Ok(Encoding::new(
+            expand(encoding.get_ids(), &repeats),
+            expand(encoding.get_type_ids(), &repeats),
+            expand(encoding.get_tokens(), &repeats),
+            expand(encoding.get_word_ids(), &repeats),
+            expand(encoding.get_offsets(), &repeats),
+            expand(encoding.get_special_tokens_mask(), &repeats),
+            expand(encoding.get_attention_mask(), &repeats),
+            Vec::new(),
+            HashMap::new(),
+        ))
+    }
+}
@@ -1,114 +1,232 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 use std::thread;
+use std::time::Duration;
 
-use super::requests::ChatCompletionRequest;
-use super::requests::Messages;
-use super::responses::{APIError, ChatCompletionResponse, ChatCompletionUsageResponse};
-use super::sampling_params::{EarlyStoppingCondition, SamplingParams};
+use super::conversation_store::ConversationStore;
+use super::multimodal::load_image;
+use super::pipelines::llm_engine::{EncoderInput, GroupResponse, RequestOptions};
+use super::requests::{
+    CancelRequestQuery, ContentPart, CreateConversationRequest, EmbeddingEncodingFormat,
+    EmbeddingInput, EmbeddingRequest, MessageContent, Messages, ReloadRequest, RerankRequest,
+};
+use super::requests::{ChatCompletionRequest, DetokenizationMode};
+use super::responses::{
+    APIError, ChatCompletionCancellation, ChatCompletionResponse, ChatCompletionUsageResponse,
+    ConversationResponse, EmbeddingData, EmbeddingResponse, EmbeddingUsageResponse,
+    EmbeddingVector, MetricsResponse, ModelCard, ModelsResponse, ReloadResponse, RerankBilledUnits,
+    RerankMeta, RerankResponse, RerankResult, RerankResultDocument,
+    StreamingChatCompletionResponse, StreamingChoice, StreamingChoiceData,
+};
+use super::sampling_params::SamplingOptions;
 use super::streaming::new_streaming_conn;
 use super::utils::get_created_time_secs;
 use super::OpenAIServerData;
+use crate::scheduler::sequence::CancellationReason;
 use actix_web::web::Bytes;
-use actix_web::{post, web, Either, HttpResponse};
+use actix_web::{delete, get, post, web, Either, HttpRequest, HttpResponse};
+use base64::Engine;
+use candle_core::Tensor;
 use tokenizers::Encoding;
 use uuid::Uuid;
 
-fn verify_model(data: &OpenAIServerData<'_>, model_name: &String) -> Result<(), APIError> {
-    let current_name = {
-        let model = data.model.lock().unwrap();
-        model.get_pipeline().name().to_string()
-    };
-    if &current_name != model_name {
-        Err(APIError::new(format!(
-            "Model name `{model_name}` is invalid."
-        )))
-    } else {
-        Ok(())
-    }
-}
+const DISCONNECT_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
-// Get prompt, roles
+// Get prompt, roles and the URLs of the images of the prompt
 async fn get_gen_prompt(
     data: &OpenAIServerData<'_>,
-    request: &web::Json<ChatCompletionRequest>,
-) -> Result<String, APIError> {
-    let mut model = data.model.lock().unwrap();
-    let conversation = model.get_mut_pipeline().get_conversation();
+    request: &ChatCompletionRequest,
+) -> Result<(String, Vec<String>), APIError> {
+    let engine = data.idle_engine();
+    let mut model = engine.lock().unwrap();
 
-    match &request.messages {
+    let mut image_urls = Vec::new();
+    let messages = match &request.messages {
         Messages::Literal(msg) => {
-            return Ok(msg.clone());
+            let prompt = match &request.suffix {
+                Some(suffix) => {
+                    let fim_tokens = model.get_pipeline().fim_tokens().ok_or(APIError::new_str(
+                        "The model does not support fill-in-the-middle (`suffix`).",
+                    ))?;
+                    fim_tokens.prompt(msg, suffix)
+                }
+                None => msg.clone(),
+            };
+            return Ok((prompt, image_urls));
+        }
+        Messages::Map(_) | Messages::Parts(_) if request.suffix.is_some() => {
+            return Err(APIError::new_str(
+                "`suffix` requires a literal prompt in `messages`.",
+            ));
         }
-        Messages::Map(messages) => {
+        Messages::Map(messages) => messages
+            .iter()
+            .map(|message| serde_json::json!(message))
+            .collect::<Vec<_>>(),
+        Messages::Parts(messages) => {
+            let image_inputs = model.get_pipeline().image_inputs();
+            let mut messages_json = Vec::new();
             for message in messages {
-                let role = message
-                    .get("role")
-                    .ok_or(APIError::new("Message key `role` not found.".to_string()))?;
-                let content = message
-                    .get("content")
-                    .ok_or(APIError::new(
-                        "Message key `content` not found.".to_string(),
-                    ))?
-                    .clone();
-
-                if role == "system" {
-                    conversation.set_system_message(content);
-                } else if role == "user" {
-                    conversation.append_message(conversation.get_roles().0.clone(), content)
-                } else if role == "assistant" {
-                    conversation.append_message(conversation.get_roles().1.clone(), content)
-                } else {
-                    return Err(APIError::new(format!("Unknown role: {role}")));
+                let content = match &message.content {
+                    None => None,
+                    Some(MessageContent::Text(text)) => Some(text.clone()),
+                    Some(MessageContent::Parts(parts)) => {
+                        let mut content = String::new();
+                        for part in parts {
+                            match part {
+                                ContentPart::Text { text } => content += text,
+                                ContentPart::ImageUrl { image_url } => {
+                                    let image_inputs = image_inputs.ok_or(APIError::new_str(
+                                        "The model does not accept images.",
+                                    ))?;
+                                    content += &image_inputs.prompt(image_urls.len());
+                                    image_urls.push(image_url.url.clone());
+                                }
+                            }
+                        }
+                        Some(content)
+                    }
+                };
+                let mut message_json = serde_json::to_value(message).map_err(APIError::from)?;
+                message_json["content"] = content.into();
+                // The templates expect the arguments of the tool calls as objects, OpenAI sends
+                // them as JSON strings.
+                if let Some(tool_calls) = message_json["tool_calls"].as_array_mut() {
+                    for arguments in tool_calls
+                        .iter_mut()
+                        .filter_map(|tool_call| tool_call.pointer_mut("/function/arguments"))
+                    {
+                        if let Some(parsed) = arguments
+                            .as_str()
+                            .and_then(|text| serde_json::from_str(text).ok())
+                        {
+                            *arguments = parsed;
+                        }
+                    }
                 }
+                messages_json.push(message_json);
             }
+            messages_json
         }
+    };
+    let add_generation_prompt = request.add_generation_prompt.unwrap_or(true);
+
+    if let Some(chat_template) = model.get_pipeline().chat_template() {
+        let prompt =
+            chat_template.render(&messages, request.tools.as_deref(), add_generation_prompt)?;
+        return Ok((prompt, image_urls));
+    }
+    if request.tools.is_some() {
+        return Err(APIError::new_str(
+            "`tools` require a model with a chat template.",
+        ));
     }
 
-    conversation.append_none_message(conversation.get_roles().1.clone());
+    let conversation = model.get_mut_pipeline().get_conversation();
+    for message in messages {
+        let role = message["role"]
+            .as_str()
+            .ok_or(APIError::new("Message key `role` not found.".to_string()))?
+            .to_string();
+        let content = message["content"]
+            .as_str()
+            .ok_or(APIError::new(
+                "Message key `content` not found.".to_string(),
+            ))?
+            .to_string();
+        if role == "system" {
+            conversation.set_system_message(content);
+        } else if role == "user" {
+            conversation.append_message(conversation.get_roles().0.clone(), content)
+        } else if role == "assistant" {
+            conversation.append_message(conversation.get_roles().1.clone(), content)
+        } else {
+            return Err(APIError::new(format!("Unknown role: {role}")));
+        }
+    }
 
-    Ok(conversation.get_prompt())
+    if add_generation_prompt {
+        conversation.append_none_message(conversation.get_roles().1.clone());
+    }
+
+    Ok((conversation.get_prompt(), image_urls))
+}
+
+fn tokenize_prompt(prompt: String, data: &OpenAIServerData<'_>) -> Result<Encoding, APIError> {
+    let engine = data.idle_engine();
+    let model = engine.lock().unwrap();
+    model.get_pipeline().tokenizer().tokenize(prompt)
 }
 
-fn check_length(
-    request: &web::Json<ChatCompletionRequest>,
-    prompt: String,
+/// Load and preprocess the images at `image_urls` and expand their placeholders in the tokenized
+/// prompt to the image tokens. Returns the expanded prompt and the pixels of each image.
+fn prepare_images(
+    token_ids: Encoding,
+    image_urls: &[String],
     data: &OpenAIServerData<'_>,
-) -> Result<Encoding, APIError> {
-    let token_ids = {
-        let model = data.model.lock().unwrap();
-        model.get_pipeline().tokenizer().tokenize(prompt)?
+) -> Result<(Encoding, Vec<Tensor>), APIError> {
+    if image_urls.is_empty() {
+        return Ok((token_ids, Vec::new()));
+    }
+    let image_inputs = {
+        let engine = data.idle_engine();
+        let model = engine.lock().unwrap();
+        model
+            .get_pipeline()
+            .image_inputs()
+            .cloned()
+            .ok_or(APIError::new_str("The model does not accept images."))?
     };
+    let images = image_urls
+        .iter()
+        .map(|url| image_inputs.processor.preprocess(&load_image(url)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    let token_ids = image_inputs.expand_placeholders(&token_ids, images.len())?;
+    Ok((token_ids, images))
+}
 
-    let max_tokens = if let Some(max_toks) = request.max_tokens {
-        max_toks
-    } else {
-        data.pipeline_config.max_model_len - token_ids.len()
-    };
+/// The key in the `Authorization` header of a request, with or without the `Bearer` scheme.
+fn get_bearer_token(req: &HttpRequest) -> Option<&str> {
+    let auth = req.headers().get("Authorization")?.to_str().ok()?;
+    Some(auth.strip_prefix("Bearer ").unwrap_or(auth))
+}
 
-    if token_ids.len() + max_tokens > data.pipeline_config.max_model_len {
-        Err(APIError::new(format!(
-            "This model's maximum context length is {} tokens. \
-            However, you requested {} tokens ({} in the messages, \
-            {} in the completion). Please reduce the length of the \
-            messages or completion.",
-            data.pipeline_config.max_model_len,
-            max_tokens + token_ids.len(),
-            token_ids.len(),
-            max_tokens
-        )))
-    } else {
-        Ok(token_ids)
-    }
+/// Derive the prefix cache namespace from the API key so tenants never share cached blocks.
+/// Only a hash of the key is kept.
+fn get_cache_namespace(req: &HttpRequest) -> Option<String> {
+    let key = get_bearer_token(req)?;
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    Some(format!("{:016x}", hasher.finish()))
 }
 
 #[post("/v1/chat/completions")]
 async fn chat_completions(
     data: web::Data<OpenAIServerData<'static>>,
     request: web::Json<ChatCompletionRequest>,
+    req: HttpRequest,
+) -> Either<Result<web::Json<ChatCompletionResponse>, APIError>, HttpResponse> {
+    create_chat_completion(&data, request.into_inner(), &req).await
+}
+
+pub(crate) async fn create_chat_completion(
+    data: &web::Data<OpenAIServerData<'static>>,
+    request: ChatCompletionRequest,
+    req: &HttpRequest,
 ) -> Either<Result<web::Json<ChatCompletionResponse>, APIError>, HttpResponse> {
-    let model_name = &request.model;
-    let res = verify_model(&data, model_name);
-    if res.is_err() {
-        return Either::Left(Err(res.err().unwrap()));
+    let data = match data.route(&request.model) {
+        Ok(data) => data,
+        Err(err) => return Either::Left(Err(err)),
+    };
+
+    if request
+        .stream_max_tokens_per_sec
+        .is_some_and(|x| !x.is_finite() || x <= 0.)
+    {
+        return Either::Left(Err(APIError::new_str(
+            "`stream_max_tokens_per_sec` must be a positive number.",
+        )));
     }
 
     if request.logit_bias.as_ref().is_some()
@@ -119,93 +237,224 @@ async fn chat_completions(
         )));
     }
 
-    let prompt = get_gen_prompt(&data, &request).await;
+    let prompt = get_gen_prompt(data, &request).await;
     if prompt.is_err() {
         return Either::Left(Err(prompt.err().unwrap()));
     }
-    let prompt = prompt.unwrap();
+    let (prompt, image_urls) = prompt.unwrap();
 
-    let token_ids = check_length(&request, prompt, &data);
+    let token_ids = tokenize_prompt(prompt, data);
     if token_ids.is_err() {
         return Either::Left(Err(token_ids.err().unwrap()));
     }
     let token_ids = token_ids.unwrap();
 
+    let images = prepare_images(token_ids, &image_urls, data);
+    if images.is_err() {
+        return Either::Left(Err(images.err().unwrap()));
+    }
+    let (token_ids, images) = images.unwrap();
+
     let request_id = format!("cmpl-{}", Uuid::new_v4());
 
-    let sampling_params = SamplingParams::new(
-        request.n.unwrap_or(1),
-        request.best_of,
-        request.presence_penalty.unwrap_or(0.0),
-        request.frequency_penalty.unwrap_or(0.0),
-        1.0,
-        request.temperature.unwrap_or(0.7),
-        request.top_p.unwrap_or(1.0),
-        request.top_k.unwrap_or(-1),
-        request.use_beam_search.unwrap_or(false),
-        1.0,
-        EarlyStoppingCondition::UnlikelyBetterCandidates,
-        request.stop.clone(),
-        request.stop_token_ids.clone().unwrap_or_default(),
-        request.ignore_eos.unwrap_or(false),
-        request.max_tokens.unwrap_or(16),
-        None,
-        None,
-        request.skip_special_tokens.unwrap_or(true),
+    let pipeline_config = data.pipeline_config.read().unwrap().clone();
+    let sampling_params = SamplingOptions::from(&request).normalize(
+        &pipeline_config.generation_config,
+        token_ids.len(),
+        pipeline_config.max_model_len,
     );
     if sampling_params.is_err() {
-        return Either::Left(Err(sampling_params.err().unwrap()));
+        return Either::Left(Err(sampling_params.err().unwrap().into()));
     }
     let sampling_params = sampling_params.unwrap();
 
     let created = get_created_time_secs();
+    let cache_namespace = get_cache_namespace(req);
+    let ttft_slo = request.ttft_slo_ms.map(Duration::from_millis);
+    let (engine, admitted) = data.select_engine(
+        &token_ids
+            .get_ids()
+            .iter()
+            .map(|x| *x as usize)
+            .collect::<Vec<_>>(),
+        cache_namespace.as_deref(),
+        ttft_slo,
+    );
+    let options = RequestOptions {
+        cache_namespace,
+        session_id: request.session_id.clone(),
+        ttft_slo,
+        detokenize: request.detokenize.unwrap_or_default(),
+        disable_prefix_cache: request.disable_prefix_cache.unwrap_or(false),
+        disable_speculation: request.disable_speculation.unwrap_or(false),
+        images,
+    };
 
     if request.stream.is_some_and(|x| x) {
-        let (sender, receiver) = new_streaming_conn();
+        let (sender, receiver) = new_streaming_conn(request.stream_max_tokens_per_sec);
+
+        // Abort the request once the client disconnects, which closes the receiving end.
+        let disconnected = sender.downgrade();
+        let abort_data = data.clone();
+        let abort_request_id = request_id.clone();
+        let _ = thread::spawn(move || loop {
+            thread::sleep(DISCONNECT_POLL_INTERVAL);
+            match disconnected.upgrade() {
+                Some(sender) if sender.is_closed() => {
+                    abort_data.abort(abort_request_id, CancellationReason::ClientRequest);
+                    break;
+                }
+                Some(_) => {}
+                // Generation finished
+                None => break,
+            }
+        });
+
+        let response_request_id = request_id.clone();
+        let model_name = request.model.clone();
+        let session_data = data.clone();
         let _ = thread::spawn(move || {
-            let mut model = data.model.lock().unwrap();
-            let model_res = model.generate(token_ids, request_id, created, sampling_params);
-            if model_res.is_err() {
-                let runtime = tokio::runtime::Builder::new_current_thread()
-                    .enable_all()
-                    .build()
-                    .unwrap();
-
-                // Ignore sending errors
-                let _ = runtime.block_on(sender.send(Ok(Bytes::from(
-                    serde_json::to_vec(&model_res.err().unwrap()).unwrap(),
-                ))));
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            // Ignore sending errors. The pacing counts the `num_tokens` of each event.
+            let send = |event: String, num_tokens: usize| {
+                let _ = runtime.block_on(
+                    sender.send((Ok(Bytes::from(format!("data: {event}\n\n"))), num_tokens)),
+                );
+            };
+
+            let mut model = engine.lock().unwrap();
+            if let Some(session_id) = &options.session_id {
+                session_data.hand_over_session(
+                    &mut model,
+                    options.cache_namespace.as_deref(),
+                    session_id,
+                );
+            }
+            // The text of a single sequence is streamed as it is generated. The ranks of several
+            // sequences change while they are sampled, so those are sent once they finished.
+            let stream_deltas =
+                options.detokenize == DetokenizationMode::Text && sampling_params.best_of == 1;
+            let role = model
+                .get_mut_pipeline()
+                .get_conversation()
+                .get_roles()
+                .0
+                .clone();
+            // The output text is only appended to, so the streamed text is a prefix of it.
+            let mut streamed_len = 0;
+            let mut streamed_tokens = 0;
+            let model_res = if stream_deltas {
+                model.generate_request_streaming(
+                    token_ids,
+                    request_id.clone(),
+                    created,
+                    sampling_params,
+                    options,
+                    &mut |output| {
+                        let Some(completion) = output.outputs.first() else {
+                            return;
+                        };
+                        let delta = &completion.text[streamed_len..];
+                        if delta.is_empty() {
+                            return;
+                        }
+                        let chunk = StreamingChatCompletionResponse {
+                            id: request_id.clone(),
+                            choices: vec![StreamingChoice {
+                                delta: StreamingChoiceData {
+                                    content: Some(delta.to_string()),
+                                    role: role.clone(),
+                                },
+                                finish_reason: None,
+                                index: completion.index,
+                            }],
+                            created,
+                            model: model_name.clone(),
+                            object: "chat.completion.chunk",
+                        };
+                        send(
+                            serde_json::to_string(&chunk).unwrap(),
+                            completion.token_ids.len() - streamed_tokens,
+                        );
+                        streamed_len = completion.text.len();
+                        streamed_tokens = completion.token_ids.len();
+                    },
+                )
+            } else {
+                model.generate_request(
+                    token_ids,
+                    request_id.clone(),
+                    created,
+                    sampling_params,
+                    options,
+                )
+            };
+            drop(admitted);
+
+            match model_res {
+                Err(err) => send(serde_json::to_string(&err).unwrap(), 0),
+                Ok(result) => {
+                    let chunk = get_streaming_chunk(
+                        &request_id,
+                        created,
+                        model_name,
+                        &result,
+                        stream_deltas,
+                    );
+                    // Without deltas, the final chunk holds every token.
+                    let num_tokens = if stream_deltas {
+                        0
+                    } else {
+                        get_total_usage(&result).completion_tokens
+                    };
+                    send(serde_json::to_string(&chunk).unwrap(), num_tokens);
+                    if let Some(cancellation) = get_cancellation(request_id, &result) {
+                        send(serde_json::to_string(&cancellation).unwrap(), 0);
+                    }
+                }
             }
         });
 
         return Either::Right(
             HttpResponse::Ok()
                 .append_header(("content-type", "text/event-stream"))
+                .append_header(("x-request-id", response_request_id))
                 //.no_chunking(asdf)
                 .streaming(receiver),
         );
     }
 
     let result = {
-        let mut model = data.model.lock().unwrap();
-        let model_res = model.generate(token_ids, request_id.clone(), created, sampling_params);
+        let mut model = engine.lock().unwrap();
+        if let Some(session_id) = &options.session_id {
+            data.hand_over_session(&mut model, options.cache_namespace.as_deref(), session_id);
+        }
+        let model_res = model.generate_request(
+            token_ids,
+            request_id.clone(),
+            created,
+            sampling_params,
+            options,
+        );
         if model_res.is_err() {
             return Either::Left(Err(model_res.err().unwrap()));
         }
         model_res.unwrap()
     };
+    drop(admitted);
 
     let choices = result
         .iter()
-        .flat_map(|(choices, _)| choices.clone())
+        .flat_map(|(choices, _, _)| choices.clone())
         .collect::<Vec<_>>();
-    let usage = ChatCompletionUsageResponse {
-        completion_tokens: result
-            .iter()
-            .map(|(_, usage)| usage.completion_tokens)
-            .sum(),
-        prompt_tokens: result.iter().map(|(_, usage)| usage.prompt_tokens).sum(),
-        total_tokens: result.iter().map(|(_, usage)| usage.total_tokens).sum(),
+    let usage = get_total_usage(&result);
+    let timings = if request.return_timings.unwrap_or(false) {
+        result.first().map(|(_, _, timings)| timings.clone())
+    } else {
+        None
     };
 
     Either::Left(Ok(web::Json(ChatCompletionResponse {
@@ -215,5 +464,401 @@ async fn chat_completions(
         model: request.model.clone(),
         object: "chat.completion",
         usage,
+        timings,
     })))
 }
+
+fn get_total_usage(result: &[GroupResponse]) -> ChatCompletionUsageResponse {
+    ChatCompletionUsageResponse {
+        completion_tokens: result
+            .iter()
+            .map(|(_, usage, _)| usage.completion_tokens)
+            .sum(),
+        prompt_tokens: result.iter().map(|(_, usage, _)| usage.prompt_tokens).sum(),
+        total_tokens: result.iter().map(|(_, usage, _)| usage.total_tokens).sum(),
+    }
+}
+
+/// The final chunk of a streaming response, with the finish reasons of the choices, and their
+/// text unless it was already `streamed` in deltas.
+fn get_streaming_chunk(
+    request_id: &str,
+    created: u64,
+    model: String,
+    result: &[GroupResponse],
+    streamed: bool,
+) -> StreamingChatCompletionResponse {
+    let choices = result
+        .iter()
+        .flat_map(|(choices, _, _)| choices)
+        .map(|choice| StreamingChoice {
+            delta: StreamingChoiceData {
+                content: choice.message.content.clone().filter(|_| !streamed),
+                role: choice.message.role.clone(),
+            },
+            finish_reason: choice.finish_reason.clone(),
+            index: choice.index,
+        })
+        .collect();
+    StreamingChatCompletionResponse {
+        id: request_id.to_string(),
+        choices,
+        created,
+        model,
+        object: "chat.completion.chunk",
+    }
+}
+
+/// The final chunk sent to a streaming client if the engine aborted the request.
+fn get_cancellation(
+    request_id: String,
+    result: &[GroupResponse],
+) -> Option<ChatCompletionCancellation> {
+    let reason = result
+        .iter()
+        .flat_map(|(choices, _, _)| choices)
+        .find_map(|choice| choice.cancellation_reason)?;
+    Some(ChatCompletionCancellation {
+        id: request_id,
+        object: "chat.completion.cancellation",
+        reason,
+        usage: get_total_usage(result),
+    })
+}
+
+/// The token ids of each input of an embeddings request. Texts are tokenized with the special
+/// tokens the encoder was trained with, token ids are taken as-is.
+fn tokenize_embedding_input(
+    input: &EmbeddingInput,
+    data: &OpenAIServerData<'_>,
+) -> Result<Vec<Vec<usize>>, APIError> {
+    let engine = data.idle_engine();
+    let model = engine.lock().unwrap();
+    let to_ids = |tokens: &[u32]| tokens.iter().map(|x| *x as usize).collect::<Vec<_>>();
+    let inputs = match input {
+        EmbeddingInput::Single(text) => vec![text.clone()],
+        EmbeddingInput::Multi(texts) => texts.clone(),
+        EmbeddingInput::Tokens(tokens) => return Ok(vec![to_ids(tokens)]),
+        EmbeddingInput::MultiTokens(inputs) => {
+            return Ok(inputs.iter().map(|tokens| to_ids(tokens)).collect())
+        }
+    };
+    let tokenizer = model.get_pipeline().tokenizer();
+    inputs
+        .into_iter()
+        .map(|text| {
+            Ok(to_ids(
+                tokenizer.tokenize_with_special_tokens(text)?.get_ids(),
+            ))
+        })
+        .collect()
+}
+
+/// Embed texts or token ids with an encoder-only model, e.g. BGE. Each input is pooled into an
+/// L2-normalized embedding, see `PoolingMode`.
+#[post("/v1/embeddings")]
+async fn embeddings(
+    data: web::Data<OpenAIServerData<'static>>,
+    request: web::Json<EmbeddingRequest>,
+) -> Result<web::Json<EmbeddingResponse>, APIError> {
+    let data = data.route(&request.model)?;
+    let inputs = tokenize_embedding_input(&request.input, data)?;
+    if inputs.is_empty() {
+        return Err(APIError::new_str("`input` must not be empty."));
+    }
+    let max_model_len = data.pipeline_config.read().unwrap().max_model_len;
+    if let Some(input) = inputs
+        .iter()
+        .find(|input| input.is_empty() || input.len() > max_model_len)
+    {
+        return Err(APIError::new(format!(
+            "Each input must have between 1 and {max_model_len} tokens, got {}.",
+            input.len()
+        )));
+    }
+    let prompt_tokens = inputs.iter().map(Vec::len).sum();
+
+    let request_id = format!("embd-{}", Uuid::new_v4());
+    let (engine, _admitted) = data.select_engine(&inputs[0], None, None);
+    let embeddings = {
+        let mut model = engine.lock().unwrap();
+        model.embed(inputs, request_id, get_created_time_secs())?
+    };
+
+    let encoding_format = request.encoding_format.unwrap_or_default();
+    let embeddings = embeddings
+        .into_iter()
+        .enumerate()
+        .map(|(index, embedding)| EmbeddingData {
+            object: "embedding",
+            embedding: match encoding_format {
+                EmbeddingEncodingFormat::Float => EmbeddingVector::Float(embedding),
+                EmbeddingEncodingFormat::Base64 => {
+                    let bytes = embedding
+                        .iter()
+                        .flat_map(|x| x.to_le_bytes())
+                        .collect::<Vec<_>>();
+                    EmbeddingVector::Base64(base64::engine::general_purpose::STANDARD.encode(bytes))
+                }
+            },
+            index,
+        })
+        .collect();
+    Ok(web::Json(EmbeddingResponse {
+        object: "list",
+        data: embeddings,
+        model: request.model.clone(),
+        usage: EmbeddingUsageResponse {
+            prompt_tokens,
+            total_tokens: prompt_tokens,
+        },
+    }))
+}
+
+/// Score how relevant each document is to the query with a cross-encoder, e.g. BGE-reranker.
+/// The query and document of each pair are encoded together and scored in one batch, the
+/// results are sorted by decreasing relevance (Cohere-compatible).
+#[post("/v1/rerank")]
+async fn rerank(
+    data: web::Data<OpenAIServerData<'static>>,
+    request: web::Json<RerankRequest>,
+) -> Result<web::Json<RerankResponse>, APIError> {
+    let data = data.route(&request.model)?;
+    if request.documents.is_empty() {
+        return Err(APIError::new_str("`documents` must not be empty."));
+    }
+    let inputs = {
+        let engine = data.idle_engine();
+        let model = engine.lock().unwrap();
+        let tokenizer = model.get_pipeline().tokenizer();
+        request
+            .documents
+            .iter()
+            .map(|document| {
+                let encoding = tokenizer.tokenize_pair(&request.query, document.text())?;
+                Ok(EncoderInput {
+                    tokens: encoding.get_ids().iter().map(|x| *x as usize).collect(),
+                    token_type_ids: Some(encoding.get_type_ids().to_vec()),
+                })
+            })
+            .collect::<Result<Vec<_>, APIError>>()?
+    };
+    let max_model_len = data.pipeline_config.read().unwrap().max_model_len;
+    if let Some((index, input)) = inputs
+        .iter()
+        .enumerate()
+        .find(|(_, input)| input.tokens.len() > max_model_len)
+    {
+        return Err(APIError::new(format!(
+            "The query and document {index} must have at most {max_model_len} tokens, got {}.",
+            input.tokens.len()
+        )));
+    }
+    let input_tokens = inputs.iter().map(|input| input.tokens.len()).sum();
+
+    let request_id = format!("rerank-{}", Uuid::new_v4());
+    let (engine, _admitted) = data.select_engine(&inputs[0].tokens, None, None);
+    let scores = {
+        let mut model = engine.lock().unwrap();
+        model.score(inputs, request_id.clone(), get_created_time_secs())?
+    };
+
+    let return_documents = request.return_documents.unwrap_or(false);
+    let mut results = scores
+        .into_iter()
+        .enumerate()
+        .map(|(index, relevance_score)| RerankResult {
+            index,
+            relevance_score,
+            document: return_documents.then(|| RerankResultDocument {
+                text: request.documents[index].text().to_string(),
+            }),
+        })
+        .collect::<Vec<_>>();
+    results.sort_by(|a, b| b.relevance_score.total_cmp(&a.relevance_score));
+    results.truncate(request.top_n.unwrap_or(results.len()));
+    Ok(web::Json(RerankResponse {
+        id: request_id,
+        results,
+        meta: RerankMeta {
+            billed_units: RerankBilledUnits { input_tokens },
+        },
+    }))
+}
+
+/// Abort an in-flight request. Its sequences finish with the `abort` finish reason and the
+/// `reason` given in the query, `client_request` by default.
+#[post("/v1/requests/{request_id}/cancel")]
+async fn cancel_request(
+    data: web::Data<OpenAIServerData<'static>>,
+    request_id: web::Path<String>,
+    query: web::Query<CancelRequestQuery>,
+) -> HttpResponse {
+    data.abort(
+        request_id.into_inner(),
+        query.reason.unwrap_or(CancellationReason::ClientRequest),
+    );
+    HttpResponse::Ok().finish()
+}
+
+/// Latency and throughput metrics of each engine. They are tracked outside of the engines, so
+/// this does not wait for running generations.
+#[get("/v1/metrics")]
+async fn get_metrics(data: web::Data<OpenAIServerData<'static>>) -> web::Json<MetricsResponse> {
+    web::Json(MetricsResponse {
+        object: "metrics",
+        engines: data.get_metrics(),
+    })
+}
+
+/// The served models with their context lengths.
+#[get("/v1/models")]
+async fn list_models(data: web::Data<OpenAIServerData<'static>>) -> web::Json<ModelsResponse> {
+    let created = get_created_time_secs();
+    web::Json(ModelsResponse {
+        object: "list",
+        data: data
+            .served_models()
+            .map(|model| {
+                let pipeline_config = model.pipeline_config.read().unwrap();
+                ModelCard {
+                    id: pipeline_config.name.clone(),
+                    object: "model",
+                    created,
+                    owned_by: "candle-vllm",
+                    max_model_len: pipeline_config.max_model_len,
+                }
+            })
+            .collect(),
+    })
+}
+
+/// Load a new checkpoint or adapter while the current model keeps serving, then switch to it
+/// once the running requests are done, see `OpenAIServerData::reload`. Requires the admin key as
+/// bearer token.
+#[post("/admin/models/reload")]
+async fn reload_model(
+    data: web::Data<OpenAIServerData<'static>>,
+    request: web::Json<ReloadRequest>,
+    req: HttpRequest,
+) -> Either<Result<web::Json<ReloadResponse>, APIError>, HttpResponse> {
+    if let Some(reloader) = &data.reloader {
+        if !reloader.authorize(get_bearer_token(&req)) {
+            return Either::Right(HttpResponse::Unauthorized().body("Invalid admin key."));
+        }
+    }
+    let request = request.into_inner();
+    let result = web::block(move || data.reload(&request))
+        .await
+        .map_err(|err| APIError::new(err.to_string()));
+    Either::Left(result.and_then(|result| result.map(web::Json)))
+}
+
+fn get_conversation_store(data: &OpenAIServerData<'_>) -> Result<ConversationStore, APIError> {
+    data.conversations.clone().ok_or_else(|| {
+        APIError::new_str(
+            "The conversations API is disabled, enable it with `--max-conversations`.",
+        )
+    })
+}
+
+fn conversation_not_found(conversation_id: &str) -> APIError {
+    APIError::new(format!("Conversation `{conversation_id}` not found."))
+}
+
+#[post("/v1/conversations")]
+async fn create_conversation(
+    data: web::Data<OpenAIServerData<'static>>,
+    request: web::Json<CreateConversationRequest>,
+    req: HttpRequest,
+) -> Result<web::Json<ConversationResponse>, APIError> {
+    let store = get_conversation_store(&data)?;
+    Ok(web::Json(store.create(
+        get_cache_namespace(&req).as_deref(),
+        request.into_inner().messages,
+    )))
+}
+
+#[get("/v1/conversations/{conversation_id}")]
+async fn get_conversation(
+    data: web::Data<OpenAIServerData<'static>>,
+    conversation_id: web::Path<String>,
+    req: HttpRequest,
+) -> Result<web::Json<ConversationResponse>, APIError> {
+    let store = get_conversation_store(&data)?;
+    store
+        .get(get_cache_namespace(&req).as_deref(), &conversation_id)
+        .map(web::Json)
+        .ok_or_else(|| conversation_not_found(&conversation_id))
+}
+
+#[delete("/v1/conversations/{conversation_id}")]
+async fn delete_conversation(
+    data: web::Data<OpenAIServerData<'static>>,
+    conversation_id: web::Path<String>,
+    req: HttpRequest,
+) -> Result<HttpResponse, APIError> {
+    let store = get_conversation_store(&data)?;
+    if !store.remove(get_cache_namespace(&req).as_deref(), &conversation_id) {
+        return Err(conversation_not_found(&conversation_id));
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Continue a stored conversation. `messages` only holds the new messages of the turn, the
+/// completion is generated for the whole history and its first choice is appended to it. The
+/// request runs in the session of the conversation unless `session_id` is set, so with
+/// `--session-ttl-secs` the KV cache of the history is reused.
+#[post("/v1/conversations/{conversation_id}/messages")]
+async fn append_conversation(
+    data: web::Data<OpenAIServerData<'static>>,
+    conversation_id: web::Path<String>,
+    request: web::Json<ChatCompletionRequest>,
+    req: HttpRequest,
+) -> Either<Result<web::Json<ChatCompletionResponse>, APIError>, HttpResponse> {
+    let store = match get_conversation_store(&data) {
+        Ok(store) => store,
+        Err(err) => return Either::Left(Err(err)),
+    };
+    let mut request = request.into_inner();
+    if request.stream.is_some_and(|x| x) {
+        return Either::Left(Err(APIError::new_str(
+            "Streaming is not supported for conversations.",
+        )));
+    }
+    let Messages::Map(new_messages) = &request.messages else {
+        return Either::Left(Err(APIError::new_str(
+            "Conversation messages must be a list of text messages.",
+        )));
+    };
+    let new_messages = new_messages.clone();
+    let cache_namespace = get_cache_namespace(&req);
+    let Some(conversation) = store.get(cache_namespace.as_deref(), &conversation_id) else {
+        return Either::Left(Err(conversation_not_found(&conversation_id)));
+    };
+
+    let mut messages = conversation.messages;
+    messages.extend(new_messages.clone());
+    request.messages = Messages::Map(messages);
+    request
+        .session_id
+        .get_or_insert_with(|| conversation_id.clone());
+
+    let response = create_chat_completion(&data, request, &req).await;
+    if let Either::Left(Ok(completion)) = &response {
+        let mut turn = new_messages;
+        if let Some(content) = completion
+            .choices
+            .first()
+            .and_then(|choice| choice.message.content.clone())
+        {
+            turn.push(HashMap::from([
+                ("role".to_string(), "assistant".to_string()),
+                ("content".to_string(), content),
+            ]));
+        }
+        // The conversation may have been deleted in the meantime.
+        store.append(cache_namespace.as_deref(), &conversation_id, turn);
+    }
+    response
+}
@@ -0,0 +1,92 @@
+//! An asynchronous front of an `LLMEngine` for Rust applications embedding the engine. Each
+//! request is generated on a thread of its own, which holds the engine until the request finished
+//! like a request of the server, and its outputs are streamed as they are generated.
+
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    thread,
+};
+
+use futures::Stream;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+use crate::{
+    openai::{responses::APIError, sampling_params::SamplingParams},
+    scheduler::sequence::CancellationReason,
+};
+
+use super::llm_engine::{AbortHandle, LLMEngine, RequestOutput};
+
+/// The outputs of a request of an `AsyncLLMEngine` as they are generated, each holding everything
+/// generated so far. Dropping the stream aborts the request.
+pub struct RequestOutputStream {
+    receiver: UnboundedReceiver<Result<RequestOutput, APIError>>,
+}
+
+impl Stream for RequestOutputStream {
+    type Item = Result<RequestOutput, APIError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+#[derive(Clone)]
+pub struct AsyncLLMEngine {
+    engine: Arc<Mutex<LLMEngine<'static>>>,
+    abort_handle: AbortHandle,
+}
+
+impl AsyncLLMEngine {
+    pub fn new(engine: LLMEngine<'static>) -> Self {
+        Self {
+            abort_handle: engine.get_abort_handle(),
+            engine: Arc::new(Mutex::new(engine)),
+        }
+    }
+
+    /// The engine, e.g. to read its metrics or to generate a batch of prompts synchronously.
+    pub fn engine(&self) -> Arc<Mutex<LLMEngine<'static>>> {
+        self.engine.clone()
+    }
+
+    /// Generate the completions of a raw text `prompt`, see `LLMEngine::generate`, returning the
+    /// stream of its outputs. An error of the engine is the last item of the stream.
+    pub fn generate(
+        &self,
+        prompt: String,
+        request_id: String,
+        sampling_params: SamplingParams,
+    ) -> RequestOutputStream {
+        let (sender, receiver) = unbounded_channel();
+        let engine = self.engine.clone();
+        let abort_handle = self.abort_handle.clone();
+        let _ = thread::spawn(move || {
+            let mut engine = engine.lock().unwrap();
+            let result = engine.generate_streaming(
+                prompt,
+                request_id.clone(),
+                sampling_params,
+                &mut |output| {
+                    // The receiving end is closed once the stream is dropped.
+                    let finished = output.finished;
+                    if sender.send(Ok(output)).is_err() && !finished {
+                        abort_handle.abort(request_id.clone(), CancellationReason::ClientRequest);
+                    }
+                },
+            );
+            if let Err(err) = result {
+                let _ = sender.send(Err(err));
+            }
+        });
+        RequestOutputStream { receiver }
+    }
+
+    /// Abort the request `request_id`, its stream ends with the aborted output.
+    pub fn abort(&self, request_id: String) {
+        self.abort_handle
+            .abort(request_id, CancellationReason::ClientRequest);
+    }
+}
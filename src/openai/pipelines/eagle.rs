@@ -0,0 +1,277 @@
+//! EAGLE speculative decoding: a draft head of a single decoder layer predicts the next final
+//! hidden state ("feature") of the model from the feature of a token and the embedding of the
+//! token after it, and the output layer of the model turns the predicted features into draft
+//! tokens. The head shares the embeddings and the output layer of the model. Its layer keeps its
+//! own KV cache with the block size and number of blocks of the model's cache, addressed by the
+//! block tables of the `BlockEngine` like the cache of a draft model.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use candle_core::{DType, Device, Module, Tensor, D};
+
+use crate::{
+    openai::{
+        models::{
+            llama::{Block, Config, LlamaConfig},
+            quantization::{linear, QuantLinear},
+            rotary_embedding::compute_cos_sin_cache,
+            weights::load_safetensors,
+            ConfigLike,
+        },
+        responses::APIError,
+    },
+    paged_attention::input_metadata::InputMetadata,
+    scheduler::{
+        cache_engine::{CacheConfig, CacheEngine},
+        kv_cache_manager::KVCacheManager,
+        SchedulerOutput,
+    },
+    try_api,
+};
+
+use super::{get_token, hub::HubRepo, LoadOptions, ModulePipeline};
+
+/// The draft head of an EAGLE checkpoint (Llama architecture).
+pub struct EagleHead {
+    /// Projects the concatenated embedding and feature to the hidden size.
+    fc: QuantLinear,
+    layers: Vec<Block>,
+    cfg: Config,
+    dtype: DType,
+    device: Device,
+}
+
+impl EagleHead {
+    /// Load the head in the local directory `path`, or else the Hub repository `path`, in `dtype`
+    /// on `device`. The head is read from `config.json` and `model.safetensors`, checkpoints
+    /// saved as `pytorch_model.bin` have to be converted first.
+    pub fn load(
+        path: &str,
+        hf_token: Option<String>,
+        hf_token_path: Option<String>,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self, APIError> {
+        let (config_filename, weights_filename) = if Path::new(path).is_dir() {
+            let dir = PathBuf::from(path);
+            (dir.join("config.json"), dir.join("model.safetensors"))
+        } else {
+            let repo = HubRepo::new(path, None, get_token(hf_token, hf_token_path)?)?;
+            (repo.get("config.json")?, repo.get("model.safetensors")?)
+        };
+        let config: LlamaConfig = try_api!(serde_json::from_slice(&try_api!(std::fs::read(
+            config_filename
+        ))));
+        let cfg = config.into_config();
+        let vb = load_safetensors(&[weights_filename], dtype, device, &LoadOptions::default())?;
+
+        let fc = try_api!(linear(2 * cfg.hidden_size, cfg.hidden_size, vb.pp("fc")));
+        let cos_sin_cache = compute_cos_sin_cache(
+            cfg.hidden_size / cfg.num_attention_heads,
+            cfg.rope_theta,
+            cfg.max_position_embeddings,
+            cfg.rope_scaling.as_ref(),
+            dtype,
+            device,
+        )?;
+        // The input of the first layer is the projection of the normalized feature, which is
+        // not normalized again.
+        let layers = (0..cfg.num_hidden_layers)
+            .map(|i| {
+                let vb = vb.pp(format!("layers.{i}"));
+                if i == 0 {
+                    Block::load_without_input_norm(vb, &cfg, cos_sin_cache.clone())
+                } else {
+                    Block::load(vb, &cfg, cos_sin_cache.clone())
+                }
+            })
+            .collect::<Result<Vec<_>, APIError>>()?;
+        Ok(Self {
+            fc,
+            layers,
+            cfg,
+            dtype,
+            device: device.clone(),
+        })
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    /// The predicted feature of the position after each token: `embeddings` ([1, num_tokens,
+    /// hidden_size]) are the embeddings of the next token of each position and `features`
+    /// ([num_tokens, hidden_size]) the final hidden states of the model at the positions.
+    /// Returns shape = [num_tokens, hidden_size].
+    fn forward(
+        &mut self,
+        embeddings: &Tensor,
+        features: &Tensor,
+        positions: &Tensor,
+        kv_caches: &KVCacheManager,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let features = try_api!(features.unsqueeze(0));
+        let x = try_api!(Tensor::cat(&[embeddings, &features], D::Minus1));
+        let mut x = try_api!(self.fc.forward(&x));
+        for (i, block) in self.layers.iter_mut().enumerate() {
+            x = block.forward(&x, positions, Some((kv_caches, i)), input_metadata)?;
+        }
+        Ok(try_api!(x.flatten_to(1)))
+    }
+}
+
+/// An EAGLE head with its KV cache and the draft state of the sequences of the last step.
+pub(crate) struct EagleDraft {
+    head: EagleHead,
+    cache_engine: CacheEngine,
+    num_speculative_tokens: usize,
+    /// The predicted feature ([hidden_size]) of the last token of each sequence, whose draft
+    /// token follows the last token, by sequence id.
+    next: HashMap<usize, (Tensor, usize)>,
+}
+
+impl EagleDraft {
+    /// A head proposing `num_speculative_tokens` tokens per step, with a KV cache laid out by
+    /// `cache_config`. The head must match the hidden size and vocabulary of the model
+    /// `pipeline`.
+    pub(crate) fn new(
+        head: EagleHead,
+        pipeline: &dyn ModulePipeline<'_>,
+        cache_config: CacheConfig,
+        num_speculative_tokens: usize,
+    ) -> Result<Self, APIError> {
+        if num_speculative_tokens == 0 {
+            return Err(APIError::new_str(
+                "The number of speculative tokens must be at least 1.",
+            ));
+        }
+        let config = pipeline.get_model_config();
+        if head.cfg.get_hidden_size() != config.get_hidden_size()
+            || head.cfg.get_vocab_size() != config.get_vocab_size()
+        {
+            return Err(APIError::new(format!(
+                "The EAGLE head does not match the hidden size and vocabulary of `{}`.",
+                pipeline.name()
+            )));
+        }
+        let cache_engine = CacheEngine::new_on_device(
+            Box::new(head.cfg.clone()),
+            cache_config,
+            head.dtype,
+            head.device.clone(),
+        )?;
+        Ok(Self {
+            head,
+            cache_engine,
+            num_speculative_tokens,
+            next: HashMap::new(),
+        })
+    }
+
+    pub(crate) fn num_speculative_tokens(&self) -> usize {
+        self.num_speculative_tokens
+    }
+
+    /// Size in bytes of the KV cache of the head on the GPU and of its CPU swap space.
+    pub(crate) fn get_kv_cache_memory_usage(&self) -> (usize, usize) {
+        self.cache_engine.memory_usage()
+    }
+
+    /// Apply the swaps and copies of the blocks of a step to the cache of the head.
+    pub(crate) fn execute_scheduler_ops(
+        &mut self,
+        scheduler_output: &SchedulerOutput,
+    ) -> Result<(), APIError> {
+        self.cache_engine
+            .swap_in(scheduler_output.blocks_to_swap_in.clone())?;
+        self.cache_engine
+            .swap_out(scheduler_output.blocks_to_swap_out.clone())?;
+        self.cache_engine
+            .copy(scheduler_output.blocks_to_copy.clone())
+    }
+
+    /// Copy the blocks of a migrated session into the cache of the head of the replica `dst`,
+    /// see `LLMEngine::migrate_session`.
+    pub(crate) fn migrate_to(
+        &self,
+        dst: &EagleDraft,
+        src_to_dst: HashMap<usize, usize>,
+    ) -> Result<(), APIError> {
+        self.cache_engine.migrate_to(&dst.cache_engine, src_to_dst)
+    }
+
+    /// The predicted feature of the last token of a sequence of the last step and its draft
+    /// token.
+    pub(crate) fn next(&self, seq_id: usize) -> Option<&(Tensor, usize)> {
+        self.next.get(&seq_id)
+    }
+
+    /// Run the head on `tokens` ([1, num_tokens]), the token after each position of the step,
+    /// with the `features` of the model at the positions, caching the keys and values of the
+    /// positions. Returns the predicted feature of the position after each token.
+    pub(crate) fn forward(
+        &mut self,
+        pipeline: &dyn ModulePipeline<'_>,
+        tokens: &Tensor,
+        features: &Tensor,
+        positions: &Tensor,
+        mut metadata: InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let embeddings = pipeline.embed_tokens(tokens)?;
+        self.head.forward(
+            &embeddings,
+            features,
+            positions,
+            &self.cache_engine.get_kv_cache(),
+            &mut metadata,
+        )
+    }
+
+    /// The draft tokens of predicted `features` ([num_rows, hidden_size]): the greedy tokens of
+    /// the output layer of the model.
+    pub(crate) fn draft_tokens(
+        &self,
+        pipeline: &dyn ModulePipeline<'_>,
+        features: &Tensor,
+    ) -> Result<Vec<usize>, APIError> {
+        let logits = pipeline.compute_logits(features)?;
+        let tokens = try_api!(try_api!(logits.argmax(D::Minus1)).to_vec1::<u32>());
+        Ok(tokens.into_iter().map(|token| token as usize).collect())
+    }
+
+    /// Replace the draft state with the predicted features (one row per sequence of `seq_ids`)
+    /// and their draft tokens.
+    pub(crate) fn set_next(
+        &mut self,
+        pipeline: &dyn ModulePipeline<'_>,
+        seq_ids: &[usize],
+        features: &Tensor,
+    ) -> Result<(), APIError> {
+        self.next.clear();
+        self.extend_next(pipeline, seq_ids, features)
+    }
+
+    /// Add the predicted features of more sequences to the draft state, see `set_next`.
+    pub(crate) fn extend_next(
+        &mut self,
+        pipeline: &dyn ModulePipeline<'_>,
+        seq_ids: &[usize],
+        features: &Tensor,
+    ) -> Result<(), APIError> {
+        let tokens = self.draft_tokens(pipeline, features)?;
+        for (i, (seq_id, token)) in seq_ids.iter().zip(tokens).enumerate() {
+            self.next
+                .insert(*seq_id, (try_api!(features.get(i)), token));
+        }
+        Ok(())
+    }
+
+    /// Forget the draft state, e.g. before catching up with the tokens of a step.
+    pub(crate) fn clear_next(&mut self) {
+        self.next.clear();
+    }
+}
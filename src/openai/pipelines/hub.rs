@@ -0,0 +1,209 @@
+//! Downloads from the Huggingface Hub into the cache shared with the `huggingface_hub` Python
+//! library (`HF_HUB_CACHE`, `$HF_HOME/hub` or `~/.cache/huggingface/hub`): the files of a
+//! revision are stored in `models--<org>--<name>/snapshots/<commit>/` and the commit of a branch
+//! or tag in `refs/<revision>`. Interrupted downloads are resumed from their partial file, and
+//! cached revisions load without network access.
+
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{openai::responses::APIError, try_api};
+
+const DEFAULT_ENDPOINT: &str = "https://huggingface.co";
+
+/// The suffix of partially downloaded files, which are resumed by the next download.
+const INCOMPLETE_SUFFIX: &str = ".incomplete";
+
+#[derive(Deserialize)]
+struct RepoInfo {
+    sha: String,
+    siblings: Vec<Sibling>,
+}
+
+#[derive(Deserialize)]
+struct Sibling {
+    rfilename: String,
+}
+
+/// The cache directory shared with the `huggingface_hub` Python library.
+pub fn hub_cache_dir() -> PathBuf {
+    if let Ok(dir) = env::var("HF_HUB_CACHE") {
+        return PathBuf::from(dir);
+    }
+    let home = env::var("HF_HOME").map(PathBuf::from).unwrap_or_else(|_| {
+        dirs::home_dir()
+            .unwrap_or_else(env::temp_dir)
+            .join(".cache")
+            .join("huggingface")
+    });
+    home.join("hub")
+}
+
+/// Whether `revision` is a full commit hash rather than a branch or tag.
+fn is_commit_hash(revision: &str) -> bool {
+    revision.len() == 40 && revision.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// A model repository at a revision resolved to its commit.
+pub struct HubRepo {
+    endpoint: String,
+    model_id: String,
+    token: Option<String>,
+    /// `models--<org>--<name>` in the cache directory.
+    dir: PathBuf,
+    commit: String,
+    files: Vec<String>,
+}
+
+impl HubRepo {
+    /// Resolve `revision` (a branch, tag or commit, "main" by default) of the model repository
+    /// `model_id`. Gated repositories require a `token`. Without network access, a revision
+    /// which was downloaded before is read from the cache.
+    pub fn new(
+        model_id: &str,
+        revision: Option<&str>,
+        token: Option<String>,
+    ) -> Result<Self, APIError> {
+        let revision = revision.unwrap_or("main");
+        let endpoint = env::var("HF_ENDPOINT").unwrap_or_else(|_| DEFAULT_ENDPOINT.to_string());
+        let dir = hub_cache_dir().join(format!("models--{}", model_id.replace('/', "--")));
+        let ref_path = dir.join("refs").join(revision);
+        let mut repo = Self {
+            endpoint,
+            model_id: model_id.to_string(),
+            token,
+            dir,
+            commit: String::new(),
+            files: Vec::new(),
+        };
+        match repo.fetch_info(revision) {
+            Ok(info) => {
+                if !is_commit_hash(revision) {
+                    try_api!(fs::create_dir_all(ref_path.parent().unwrap()));
+                    try_api!(fs::write(&ref_path, &info.sha));
+                }
+                repo.commit = info.sha;
+                repo.files = info
+                    .siblings
+                    .into_iter()
+                    .map(|sibling| sibling.rfilename)
+                    .collect();
+            }
+            Err(err) => {
+                repo.commit = if is_commit_hash(revision) {
+                    revision.to_string()
+                } else {
+                    fs::read_to_string(&ref_path)
+                        .map_err(|_| err)?
+                        .trim()
+                        .to_string()
+                };
+                let snapshot = repo.snapshot_dir();
+                repo.files = list_files(&snapshot, &snapshot);
+            }
+        }
+        Ok(repo)
+    }
+
+    fn fetch_info(&self, revision: &str) -> Result<RepoInfo, APIError> {
+        let url = format!(
+            "{}/api/models/{}/revision/{revision}",
+            self.endpoint, self.model_id
+        );
+        let response = try_api!(self.request(&url).call());
+        Ok(try_api!(serde_json::from_reader(response.into_reader())))
+    }
+
+    fn request(&self, url: &str) -> ureq::Request {
+        let request = ureq::get(url);
+        match &self.token {
+            Some(token) => request.set("Authorization", &format!("Bearer {token}")),
+            None => request,
+        }
+    }
+
+    fn snapshot_dir(&self) -> PathBuf {
+        self.dir.join("snapshots").join(&self.commit)
+    }
+
+    /// The files of the repository at the revision.
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    /// The path of `filename` in the cache, downloading it if it is not cached yet.
+    pub fn get(&self, filename: &str) -> Result<PathBuf, APIError> {
+        let path = self.snapshot_dir().join(filename);
+        if path.exists() {
+            return Ok(path);
+        }
+        if !self.files.iter().any(|file| file == filename) {
+            return Err(APIError::new(format!(
+                "`{}` has no file `{filename}` at revision {}.",
+                self.model_id, self.commit
+            )));
+        }
+        try_api!(fs::create_dir_all(path.parent().unwrap()));
+        let partial = PathBuf::from(format!("{}{INCOMPLETE_SUFFIX}", path.display()));
+        let offset = fs::metadata(&partial).map_or(0, |metadata| metadata.len());
+        let url = format!(
+            "{}/{}/resolve/{}/{filename}",
+            self.endpoint, self.model_id, self.commit
+        );
+        if offset > 0 {
+            println!(
+                "Resuming the download of {filename} from {} at {:.1} MiB.",
+                self.model_id,
+                offset as f64 / (1 << 20) as f64
+            );
+        } else {
+            println!("Downloading {filename} from {}.", self.model_id);
+        }
+        let response = match self
+            .request(&url)
+            .set("Range", &format!("bytes={offset}-"))
+            .call()
+        {
+            Ok(response) => Some(response),
+            // The partial file is already complete.
+            Err(ureq::Error::Status(416, _)) if offset > 0 => None,
+            Err(err) => return Err(APIError::new(err.to_string())),
+        };
+        if let Some(response) = response {
+            // Servers which ignore the range send the whole file.
+            let mut file = if response.status() == 206 {
+                try_api!(OpenOptions::new().create(true).append(true).open(&partial))
+            } else {
+                try_api!(File::create(&partial))
+            };
+            try_api!(io::copy(&mut response.into_reader(), &mut file));
+        }
+        try_api!(fs::rename(&partial, &path));
+        Ok(path)
+    }
+}
+
+/// The files below `dir`, relative to `root`, without partial downloads.
+fn list_files(root: &Path, dir: &Path) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut files = Vec::new();
+    for path in entries.flatten().map(|entry| entry.path()) {
+        if path.is_dir() {
+            files.extend(list_files(root, &path));
+        } else if let Ok(relative) = path.strip_prefix(root) {
+            let relative = relative.to_string_lossy();
+            if !relative.ends_with(INCOMPLETE_SUFFIX) {
+                files.push(relative.into_owned());
+            }
+        }
+    }
+    files
+}
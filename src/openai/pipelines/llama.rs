@@ -3,34 +3,49 @@ use std::{iter::zip, path::PathBuf, sync::Arc};
 use crate::{
     openai::{
         conversation::{
+            chat_template::ChatTemplate,
             default_conversation::{
                 DefaultConversation, DefaultConversationSeparators, SeparatorStyle,
             },
             Conversation,
         },
         models::{
-            llama::{Llama, LlamaConfig},
-            ConfigLike,
+            detect_architecture, get_model_constructor,
+            offload::{load_offloaded_safetensors, OffloadStats},
+            pipeline_parallel::{load_staged_safetensors, MicroBatch},
+            quantization::{load_quantized_safetensors, QuantizationConfig},
+            tensor_parallel::{load_sharded_safetensors, rank_devices},
+            weights::load_safetensors,
+            ConfigLike, PagedAttentionModel,
         },
+        multimodal::{ImageInputs, ImageProcessor},
         requests::StopTokens,
         responses::APIError,
-        sampling_params::SamplingParams,
+        sampling_params::{GenerationConfig, SamplingParams},
         PipelineConfig, TokenizerWrapper,
     },
     paged_attention::input_metadata::InputMetadata,
-    scheduler::sequence::Sequence,
+    scheduler::{kv_cache_manager::KVCacheManager, sequence::Sequence},
     try_api,
 };
 use candle_core::{DType, Device, IndexOp, Tensor};
-use candle_lora_transformers::varbuilder_utils::from_mmaped_safetensors;
 use either::Either::{Left, Right};
-use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
+use serde::Deserialize;
 use tokenizers::Tokenizer;
 
-use super::{get_token, ModelLoader, ModelPaths, ModulePipeline, TokenOrFinishReason};
+use super::{
+    get_token,
+    hub::HubRepo,
+    pooling::PoolingMode,
+    thinking::{force_token, ThinkingTags},
+    tokenizer::{get_tokenizer_filename, load_tokenizer},
+    FimTokens, LoadOptions, ModelLoader, ModelPaths, ModulePipeline, TokenOrFinishReason,
+};
 
-const EOS_TOKEN: &str = "</s>";
 const SAMPLING_SEED: u64 = 299792458;
+/// The context length of models whose config does not give `max_position_embeddings`, see
+/// https://huggingface.co/docs/transformers/model_doc/llama2#transformers.LlamaConfig.max_position_embeddings
+const DEFAULT_MAX_MODEL_LEN: usize = 4096;
 
 #[derive(Debug, Clone)]
 pub struct LlamaSpecificConfig {
@@ -45,21 +60,62 @@ impl LlamaSpecificConfig {
 
 /// top-p, multinomial, and argmax sampling are implemented. Beam search is not implemented.
 pub struct LlamaPipeline {
-    llama: Llama,
+    model: Box<dyn PagedAttentionModel>,
     args: LlamaSpecificConfig,
     tokenizer: Tokenizer,
     conversation: DefaultConversation,
+    chat_template: Option<ChatTemplate>,
+    eos_tokens: &'static [&'static str],
+    fim_tokens: Option<FimTokens>,
+    image_inputs: Option<ImageInputs>,
+    pooling: Option<PoolingMode>,
     name: String,
+    dtype: DType,
+    device: Device,
 }
 
 pub struct LlamaLoader {
     config: LlamaSpecificConfig,
     name: String,
+    architecture: Option<String>,
+    config_overrides: Vec<(String, serde_json::Value)>,
+    pooling: PoolingMode,
+    gguf: Option<GgufFiles>,
+}
+
+/// A GGUF checkpoint, whose repository usually holds neither the tokenizer nor the config.
+struct GgufFiles {
+    /// The GGUF file in the model repository, e.g. "llama-2-7b-chat.Q4_K_M.gguf".
+    filename: String,
+    /// The repository of the original model holding `tokenizer.json`.
+    tokenizer_model_id: String,
+}
+
+/// The parts of `config.json` the loader needs before the model is constructed.
+#[derive(Deserialize)]
+struct LoaderConfig {
+    #[serde(default)]
+    quantization_config: Option<QuantizationConfig>,
+    #[serde(default)]
+    max_position_embeddings: Option<usize>,
+    #[serde(default)]
+    num_hidden_layers: Option<usize>,
+}
+
+impl LoaderConfig {
+    fn num_layers(&self) -> Result<usize, APIError> {
+        self.num_hidden_layers
+            .ok_or_else(|| APIError::new_str("The config does not give the number of layers."))
+    }
 }
 
 pub struct LlamaModelPaths<P> {
     tokenizer_filename: P,
     config_filename: P,
+    tokenizer_config_filename: Option<P>,
+    generation_config_filename: Option<P>,
+    preprocessor_config_filename: Option<P>,
+    pooling_config_filename: Option<P>,
     filenames: Vec<P>,
 }
 
@@ -73,11 +129,62 @@ impl ModelPaths for LlamaModelPaths<PathBuf> {
     fn get_weight_filenames(&self) -> &Vec<PathBuf> {
         &self.filenames
     }
+    fn get_tokenizer_config_filename(&self) -> Option<&PathBuf> {
+        self.tokenizer_config_filename.as_ref()
+    }
+    fn get_generation_config_filename(&self) -> Option<&PathBuf> {
+        self.generation_config_filename.as_ref()
+    }
+    fn get_preprocessor_config_filename(&self) -> Option<&PathBuf> {
+        self.preprocessor_config_filename.as_ref()
+    }
+    fn get_pooling_config_filename(&self) -> Option<&PathBuf> {
+        self.pooling_config_filename.as_ref()
+    }
 }
 
 impl LlamaLoader {
     pub fn new(config: LlamaSpecificConfig, name: String) -> Self {
-        Self { config, name }
+        Self {
+            config,
+            name,
+            architecture: None,
+            config_overrides: Vec::new(),
+            pooling: PoolingMode::default(),
+            gguf: None,
+        }
+    }
+
+    /// Load the model with the constructor registered under `architecture`, e.g. "mixtral" or
+    /// "qwen2" (see `registered_models`), which also selects the prompt format, instead of the
+    /// one detected from the config of the checkpoint (see `detect_architecture`).
+    pub fn with_architecture(mut self, architecture: &str) -> Self {
+        self.architecture = Some(architecture.to_string());
+        self
+    }
+
+    /// Set `key` of the model's `config.json` to `value` before the model is constructed, for
+    /// model options given on the command line, e.g. "expert_parallel_size".
+    pub fn with_config_override(mut self, key: &str, value: serde_json::Value) -> Self {
+        self.config_overrides.push((key.to_string(), value));
+        self
+    }
+
+    /// Pool the embeddings of an encoder-only model with `pooling` if the checkpoint has no
+    /// sentence-transformers pooling config.
+    pub fn with_pooling(mut self, pooling: PoolingMode) -> Self {
+        self.pooling = pooling;
+        self
+    }
+
+    /// Load `filename` of the model repository as a GGUF checkpoint, whose architecture and
+    /// config are read from its metadata, with the tokenizer of `tokenizer_model_id`.
+    pub fn with_gguf(mut self, filename: &str, tokenizer_model_id: &str) -> Self {
+        self.gguf = Some(GgufFiles {
+            filename: filename.to_string(),
+            tokenizer_model_id: tokenizer_model_id.to_string(),
+        });
+        self
     }
 }
 
@@ -89,31 +196,57 @@ impl<'a> ModelLoader<'a> for LlamaLoader {
         hf_token: Option<String>,
         hf_token_path: Option<String>,
     ) -> Result<Box<dyn ModelPaths>, APIError> {
-        let api = try_api!(ApiBuilder::new()
-            .with_progress(true)
-            .with_token(Some(get_token(hf_token, hf_token_path)?))
-            .build());
-        let revision = revision.unwrap_or("main".to_string());
-        let api = api.repo(Repo::with_revision(model_id, RepoType::Model, revision));
+        let token = get_token(hf_token, hf_token_path)?;
+        let repo = HubRepo::new(&model_id, revision.as_deref(), token.clone())?;
+        if let Some(gguf) = &self.gguf {
+            let filename = repo.get(&gguf.filename)?;
+            let tokenizer_repo = HubRepo::new(&gguf.tokenizer_model_id, None, token)?;
+            // The config is derived from the metadata, the models read it from `config.json`.
+            let config = GgufCheckpoint::open(&filename)?.config()?;
+            let config_filename = dirs::cache_dir()
+                .unwrap_or_else(std::env::temp_dir)
+                .join("candle-vllm")
+                .join("gguf")
+                .join(format!("{}.config.json", gguf.filename));
+            try_api!(std::fs::create_dir_all(config_filename.parent().unwrap()));
+            try_api!(std::fs::write(
+                &config_filename,
+                try_api!(serde_json::to_vec_pretty(&config))
+            ));
+            return Ok(Box::new(LlamaModelPaths {
+                tokenizer_filename: get_tokenizer_filename(&tokenizer_repo)?,
+                config_filename,
+                tokenizer_config_filename: tokenizer_repo.get("tokenizer_config.json").ok(),
+                generation_config_filename: tokenizer_repo.get("generation_config.json").ok(),
+                preprocessor_config_filename: None,
+                pooling_config_filename: None,
+                filenames: vec![filename],
+            }));
+        }
+        let tokenizer_filename = get_tokenizer_filename(&repo)?;
+
+        let config_filename = repo.get("config.json")?;
+
+        let tokenizer_config_filename = repo.get("tokenizer_config.json").ok();
+
+        let generation_config_filename = repo.get("generation_config.json").ok();
 
-        let tokenizer_filename = try_api!(api.get("tokenizer.json"));
+        let preprocessor_config_filename = repo.get("preprocessor_config.json").ok();
 
-        let config_filename = try_api!(api.get("config.json"));
+        let pooling_config_filename = repo.get("1_Pooling/config.json").ok();
 
         let mut filenames = vec![];
-        for rfilename in try_api!(api.info())
-            .siblings
-            .iter()
-            .map(|x| x.rfilename.clone())
-            .filter(|x| x.ends_with(".safetensors"))
-        {
-            let filename = try_api!(api.get(&rfilename));
-            filenames.push(filename);
+        for rfilename in repo.files().iter().filter(|x| x.ends_with(".safetensors")) {
+            filenames.push(repo.get(rfilename)?);
         }
 
         Ok(Box::new(LlamaModelPaths {
             tokenizer_filename,
             config_filename,
+            tokenizer_config_filename,
+            generation_config_filename,
+            preprocessor_config_filename,
+            pooling_config_filename,
             filenames,
         }))
     }
@@ -123,72 +256,500 @@ impl<'a> ModelLoader<'a> for LlamaLoader {
         paths: Box<dyn ModelPaths>,
         dtype: DType,
         device: Device,
+        options: &LoadOptions,
     ) -> Result<(Box<dyn ModulePipeline<'a>>, PipelineConfig), APIError> {
         let args = self.config.clone();
 
-        let config: LlamaConfig = try_api!(serde_json::from_slice(&try_api!(std::fs::read(
-            paths.get_config_filename()
-        )),));
-        let config = config.into_config();
+        let tensor_parallel_size = options.tensor_parallel_size.max(1);
+        let pipeline_parallel_size = options.pipeline_parallel_size.max(1);
+        let num_offloaded_layers = options.num_offloaded_layers;
+        let mut config_overrides = self.config_overrides.clone();
+        if tensor_parallel_size > 1 {
+            config_overrides.push((
+                "tensor_parallel_size".to_string(),
+                serde_json::Value::from(tensor_parallel_size),
+            ));
+        }
+        if pipeline_parallel_size > 1 {
+            config_overrides.push((
+                "pipeline_parallel_size".to_string(),
+                serde_json::Value::from(pipeline_parallel_size),
+            ));
+        }
+        let mut config_bytes = try_api!(std::fs::read(paths.get_config_filename()));
+        if !config_overrides.is_empty() {
+            let mut config: serde_json::Map<String, serde_json::Value> =
+                try_api!(serde_json::from_slice(&config_bytes));
+            config.extend(config_overrides);
+            config_bytes = try_api!(serde_json::to_vec(&config));
+        }
+        let config: LoaderConfig = try_api!(serde_json::from_slice(&config_bytes));
+        let gguf = match &self.gguf {
+            Some(_) => Some(GgufCheckpoint::open(&paths.get_weight_filenames()[0])?),
+            None => None,
+        };
+        let architecture = match (&gguf, &self.architecture) {
+            (Some(gguf), _) => gguf.model_architecture()?.to_string(),
+            (None, Some(architecture)) => architecture.clone(),
+            (None, None) => detect_architecture(&config_bytes)?,
+        };
+        let Some(constructor) = get_model_constructor(&architecture) else {
+            return Err(APIError::new(format!(
+                "Unknown model architecture `{architecture}`."
+            )));
+        };
+
+        if tensor_parallel_size > 1
+            && (architecture != "llama" || gguf.is_some() || config.quantization_config.is_some())
+        {
+            return Err(APIError::new(format!(
+                "Weight sharding across devices only supports unquantized safetensors checkpoints of the Llama architecture, got `{architecture}`."
+            )));
+        }
+        if pipeline_parallel_size > 1
+            && (architecture != "llama" || gguf.is_some() || config.quantization_config.is_some())
+        {
+            return Err(APIError::new(format!(
+                "Pipeline parallelism only supports unquantized safetensors checkpoints of the Llama architecture, got `{architecture}`."
+            )));
+        }
+        if num_offloaded_layers > 0
+            && (architecture != "llama" || gguf.is_some() || config.quantization_config.is_some())
+        {
+            return Err(APIError::new(format!(
+                "CPU offloading only supports unquantized safetensors checkpoints of the Llama architecture, got `{architecture}`."
+            )));
+        }
+        if num_offloaded_layers > 0 && (tensor_parallel_size > 1 || pipeline_parallel_size > 1) {
+            return Err(APIError::new_str(
+                "CPU offloading cannot be combined with tensor or pipeline parallelism.",
+            ));
+        }
+        if options.lora_adapter.is_some() && tensor_parallel_size > 1 {
+            return Err(APIError::new_str(
+                "LoRA adapters are not supported with weight sharding across devices.",
+            ));
+        }
 
         println!("Loading {} model.", self.name);
 
-        let vb = try_api!(from_mmaped_safetensors(
-            paths.get_weight_filenames(),
-            dtype,
-            &device,
-            false
-        ));
+        let weights = if let Some(gguf) = &gguf {
+            gguf.load(dtype, &device, options)?.into()
+        } else {
+            match &config.quantization_config {
+                Some(quantization_config)
+                    if quantization_config.is_fp8() || quantization_config.is_int4() =>
+                {
+                    load_quantized_safetensors(
+                        paths.get_weight_filenames(),
+                        quantization_config,
+                        dtype,
+                        &device,
+                        options,
+                    )?
+                    .into()
+                }
+                Some(quantization_config)
+                    if quantization_config.is_gptq() || quantization_config.is_awq() =>
+                {
+                    return Err(APIError::new(format!(
+                        "Only 4-bit GPTQ and AWQ (GEMM version) checkpoints are supported, got {:?} bits.",
+                        quantization_config.bits
+                    )));
+                }
+                Some(quantization_config) => {
+                    return Err(APIError::new(format!(
+                        "Unsupported quantization method `{}`.",
+                        quantization_config.quant_method
+                    )));
+                }
+                None if tensor_parallel_size > 1 => load_sharded_safetensors(
+                    paths.get_weight_filenames(),
+                    dtype,
+                    &rank_devices(&device, tensor_parallel_size)?,
+                    options,
+                )?,
+                None if pipeline_parallel_size > 1 => load_staged_safetensors(
+                    paths.get_weight_filenames(),
+                    dtype,
+                    &rank_devices(&device, pipeline_parallel_size)?,
+                    config.num_layers()?,
+                    options,
+                )?,
+                None if num_offloaded_layers > 0 => load_offloaded_safetensors(
+                    paths.get_weight_filenames(),
+                    dtype,
+                    &device,
+                    config.num_layers()?,
+                    options,
+                )?,
+                None => {
+                    load_safetensors(paths.get_weight_filenames(), dtype, &device, options)?.into()
+                }
+            }
+        };
+
+        let model = constructor(&config_bytes, weights, dtype, &device)?;
 
-        let llama = try_api!(Llama::load(vb, &config, dtype, &device));
+        let tokenizer = load_tokenizer(paths.get_tokenizer_filename())?;
 
-        let tokenizer = Tokenizer::from_file(paths.get_tokenizer_filename())
-            .map_err(|x| APIError::new(x.to_string()))?;
+        let image_inputs = load_image_inputs(
+            &architecture,
+            model.get_config().as_ref(),
+            &tokenizer,
+            paths.get_preprocessor_config_filename(),
+        )?;
+
+        // Cross-encoders score the prompts with their classification head instead.
+        let model_config = model.get_config();
+        let pooling = if model_config.is_encoder_only() && model_config.get_num_labels().is_none() {
+            let pooling = match paths.get_pooling_config_filename() {
+                Some(filename) => {
+                    PoolingMode::from_pooling_config(&try_api!(std::fs::read(filename)))?
+                }
+                None => None,
+            };
+            Some(pooling.unwrap_or(self.pooling))
+        } else {
+            None
+        };
 
         println!("Done loading.");
 
-        //max is https://huggingface.co/docs/transformers/model_doc/llama2#transformers.LlamaConfig.max_position_embeddings
+        let generation_config = match paths.get_generation_config_filename() {
+            Some(filename) => try_api!(serde_json::from_slice(&try_api!(std::fs::read(filename)))),
+            None => GenerationConfig::default(),
+        };
+
         let pipeline_config = PipelineConfig {
-            max_model_len: 4096,
+            name: self.name.clone(),
+            max_model_len: config
+                .max_position_embeddings
+                .unwrap_or(DEFAULT_MAX_MODEL_LEN),
+            generation_config,
         };
 
-        //reference: https://huggingface.co/blog/codellama#conversational-instructions,
-        //reference: https://github.com/facebookresearch/llama/blob/1a240688810f8036049e8da36b073f63d2ac552c/llama/generation.py#L212
+        let chat_template = match paths.get_tokenizer_config_filename() {
+            Some(filename) => ChatTemplate::from_tokenizer_config(filename)?,
+            None => None,
+        };
+        let (conversation, eos_tokens) = chat_format(&architecture);
         Ok((
             Box::new(LlamaPipeline {
-                llama,
+                model,
                 args,
                 tokenizer,
-                conversation: DefaultConversation::new(
-                    "llama-2".to_string(),
-                    "[INST] <<SYS>>\n{}\n<</SYS>>\n\n".to_string(),
-                    Vec::default(),
-                    0,
-                    SeparatorStyle::Llama2,
-                    "".to_string(),
-                    Vec::default(),
-                    ("[INST]".to_string(), "[/INST]".to_string()),
-                    DefaultConversationSeparators {
-                        sep: " ".to_string(),
-                        sep2: Some(" </s></s>".to_string()),
-                    },
-                ),
+                conversation,
+                chat_template,
+                eos_tokens,
+                fim_tokens: fim_tokens(&architecture),
+                image_inputs,
+                pooling,
                 name: self.name.clone(),
+                dtype,
+                device,
             }),
             pipeline_config,
         ))
     }
 }
 
+/// The prompt format and the end-of-sequence tokens of the chat models of `architecture`.
+fn chat_format(architecture: &str) -> (DefaultConversation, &'static [&'static str]) {
+    match architecture {
+        // reference: https://huggingface.co/baichuan-inc/Baichuan2-13B-Chat/blob/main/generation_utils.py
+        "baichuan" => (
+            DefaultConversation::new(
+                "baichuan2".to_string(),
+                "{}".to_string(),
+                Vec::default(),
+                0,
+                SeparatorStyle::NoColonSingle,
+                "".to_string(),
+                Vec::default(),
+                ("<reserved_106>".to_string(), "<reserved_107>".to_string()),
+                DefaultConversationSeparators {
+                    sep: "".to_string(),
+                    sep2: None,
+                },
+            ),
+            &["</s>"],
+        ),
+        // reference: https://huggingface.co/CohereForAI/c4ai-command-r-v01#tool-use--rag-capabilities
+        "cohere" => (
+            DefaultConversation::new(
+                "command-r".to_string(),
+                "<|START_OF_TURN_TOKEN|><|SYSTEM_TOKEN|>{}<|END_OF_TURN_TOKEN|>".to_string(),
+                Vec::default(),
+                0,
+                SeparatorStyle::NoColonSingle,
+                "".to_string(),
+                Vec::default(),
+                (
+                    "<|START_OF_TURN_TOKEN|><|USER_TOKEN|>".to_string(),
+                    "<|START_OF_TURN_TOKEN|><|CHATBOT_TOKEN|>".to_string(),
+                ),
+                DefaultConversationSeparators {
+                    sep: "<|END_OF_TURN_TOKEN|>".to_string(),
+                    sep2: None,
+                },
+            ),
+            &["<|END_OF_TURN_TOKEN|>", "<EOS_TOKEN>"],
+        ),
+        // reference: https://huggingface.co/deepseek-ai/DeepSeek-V2.5#chat-template
+        "deepseek" => (
+            DefaultConversation::new(
+                "deepseek".to_string(),
+                "{}".to_string(),
+                Vec::default(),
+                0,
+                SeparatorStyle::NoColonTwo,
+                "".to_string(),
+                Vec::default(),
+                ("<｜User｜>".to_string(), "<｜Assistant｜>".to_string()),
+                DefaultConversationSeparators {
+                    sep: "".to_string(),
+                    sep2: Some("<｜end▁of▁sentence｜>".to_string()),
+                },
+            ),
+            &["<｜end▁of▁sentence｜>"],
+        ),
+        // reference: https://huggingface.co/microsoft/Phi-3-mini-4k-instruct#chat-format
+        "phi3" => (
+            DefaultConversation::new(
+                "phi-3".to_string(),
+                "<|system|>\n{}".to_string(),
+                Vec::default(),
+                0,
+                SeparatorStyle::ChatML,
+                "".to_string(),
+                Vec::default(),
+                ("<|user|>".to_string(), "<|assistant|>".to_string()),
+                DefaultConversationSeparators {
+                    sep: "<|end|>".to_string(),
+                    sep2: None,
+                },
+            ),
+            &["<|end|>", "<|endoftext|>"],
+        ),
+        // reference: https://ai.google.dev/gemma/docs/formatting
+        "gemma" => (
+            DefaultConversation::new(
+                "gemma".to_string(),
+                "{}".to_string(),
+                Vec::default(),
+                0,
+                SeparatorStyle::Gemma,
+                "".to_string(),
+                Vec::default(),
+                (
+                    "<start_of_turn>user".to_string(),
+                    "<start_of_turn>model".to_string(),
+                ),
+                DefaultConversationSeparators {
+                    sep: "<end_of_turn>".to_string(),
+                    sep2: None,
+                },
+            ),
+            &["<end_of_turn>", "<eos>"],
+        ),
+        "starcoder2" => (
+            DefaultConversation::new(
+                "starcoder2".to_string(),
+                "{}".to_string(),
+                Vec::default(),
+                0,
+                SeparatorStyle::AddNewLineSingle,
+                "".to_string(),
+                Vec::default(),
+                ("### Instruction".to_string(), "### Response".to_string()),
+                DefaultConversationSeparators {
+                    sep: "\n\n".to_string(),
+                    sep2: None,
+                },
+            ),
+            &["<|endoftext|>"],
+        ),
+        // reference: https://huggingface.co/allenai/OLMo-7B-Instruct-hf
+        "olmo" => (
+            DefaultConversation::new(
+                "olmo".to_string(),
+                "<|system|>\n{}".to_string(),
+                Vec::default(),
+                0,
+                SeparatorStyle::AddNewLineSingle,
+                "".to_string(),
+                Vec::default(),
+                ("<|user|>".to_string(), "<|assistant|>".to_string()),
+                DefaultConversationSeparators {
+                    sep: "\n".to_string(),
+                    sep2: None,
+                },
+            ),
+            &["<|endoftext|>"],
+        ),
+        "qwen2" => (
+            DefaultConversation::new(
+                "qwen2".to_string(),
+                "<|im_start|>system\n{}".to_string(),
+                Vec::default(),
+                0,
+                SeparatorStyle::ChatML,
+                "".to_string(),
+                Vec::default(),
+                (
+                    "<|im_start|>user".to_string(),
+                    "<|im_start|>assistant".to_string(),
+                ),
+                DefaultConversationSeparators {
+                    sep: "<|im_end|>".to_string(),
+                    sep2: None,
+                },
+            ),
+            &["<|im_end|>", "<|endoftext|>"],
+        ),
+        // reference: https://huggingface.co/llava-hf/llava-1.5-7b-hf#how-to-use-the-model
+        "llava" => (
+            DefaultConversation::new(
+                "llava".to_string(),
+                "{}".to_string(),
+                Vec::default(),
+                0,
+                SeparatorStyle::AddColonTwo,
+                "".to_string(),
+                Vec::default(),
+                ("USER".to_string(), "ASSISTANT".to_string()),
+                DefaultConversationSeparators {
+                    sep: " ".to_string(),
+                    sep2: Some("</s>".to_string()),
+                },
+            ),
+            &["</s>"],
+        ),
+        "qwen_vl" => (
+            DefaultConversation::new(
+                "qwen-vl".to_string(),
+                "<|im_start|>system\n{}".to_string(),
+                Vec::default(),
+                0,
+                SeparatorStyle::ChatML,
+                "".to_string(),
+                Vec::default(),
+                (
+                    "<|im_start|>user".to_string(),
+                    "<|im_start|>assistant".to_string(),
+                ),
+                DefaultConversationSeparators {
+                    sep: "<|im_end|>".to_string(),
+                    sep2: None,
+                },
+            ),
+            &["<|im_end|>", "<|endoftext|>"],
+        ),
+        // FLAN-T5 is instruction-tuned without a chat template, the messages are concatenated.
+        "t5" => (
+            DefaultConversation::new(
+                "t5".to_string(),
+                "{}".to_string(),
+                Vec::default(),
+                0,
+                SeparatorStyle::NoColonSingle,
+                "".to_string(),
+                Vec::default(),
+                ("".to_string(), "".to_string()),
+                DefaultConversationSeparators {
+                    sep: "\n".to_string(),
+                    sep2: None,
+                },
+            ),
+            &["</s>"],
+        ),
+        //reference: https://huggingface.co/blog/codellama#conversational-instructions,
+        //reference: https://github.com/facebookresearch/llama/blob/1a240688810f8036049e8da36b073f63d2ac552c/llama/generation.py#L212
+        _ => (
+            DefaultConversation::new(
+                "llama-2".to_string(),
+                "[INST] <<SYS>>\n{}\n<</SYS>>\n\n".to_string(),
+                Vec::default(),
+                0,
+                SeparatorStyle::Llama2,
+                "".to_string(),
+                Vec::default(),
+                ("[INST]".to_string(), "[/INST]".to_string()),
+                DefaultConversationSeparators {
+                    sep: " ".to_string(),
+                    sep2: Some(" </s></s>".to_string()),
+                },
+            ),
+            &["</s>"],
+        ),
+    }
+}
+
+/// The prompt text of each image and its placeholder token, for the vision-language models of
+/// `architecture`.
+fn image_prompt(architecture: &str) -> Option<(&'static str, &'static str)> {
+    match architecture {
+        "llava" => Some(("<image>\n", "<image>")),
+        // reference: https://huggingface.co/Qwen/Qwen-VL-Chat/blob/main/tokenization_qwen.py
+        "qwen_vl" => Some(("Picture {n}: <img><imgpad></img>\n", "<imgpad>")),
+        _ => None,
+    }
+}
+
+/// The image inputs of the model, if it is a vision-language model.
+fn load_image_inputs(
+    architecture: &str,
+    config: &dyn ConfigLike,
+    tokenizer: &Tokenizer,
+    preprocessor_config_filename: Option<&PathBuf>,
+) -> Result<Option<ImageInputs>, APIError> {
+    let (Some((template, placeholder)), Some(dims)) =
+        (image_prompt(architecture), config.get_image_dims())
+    else {
+        return Ok(None);
+    };
+    let Some(placeholder_id) = tokenizer.token_to_id(placeholder) else {
+        return Err(APIError::new(format!(
+            "The tokenizer lacks the image placeholder `{placeholder}`."
+        )));
+    };
+    let processor = match preprocessor_config_filename {
+        Some(filename) => {
+            ImageProcessor::from_preprocessor_config(&try_api!(std::fs::read(filename)))?
+        }
+        None => ImageProcessor::square(dims.image_size as u32),
+    };
+    Ok(Some(ImageInputs {
+        template,
+        placeholder,
+        placeholder_id,
+        num_tokens: dims.num_image_tokens,
+        processor,
+    }))
+}
+
+/// The fill-in-the-middle tokens of the code models of `architecture`.
+fn fim_tokens(architecture: &str) -> Option<FimTokens> {
+    match architecture {
+        "starcoder2" => Some(FimTokens {
+            prefix: "<fim_prefix>",
+            suffix: "<fim_suffix>",
+            middle: "<fim_middle>",
+        }),
+        _ => None,
+    }
+}
+
 impl<'s> ModulePipeline<'s> for LlamaPipeline {
     fn forward(
         &mut self,
         input_tokens: Tensor,
         input_positions: Tensor,
-        kv_cache: Option<&Vec<(Tensor, Tensor)>>,
+        kv_cache: Option<&KVCacheManager>,
         mut input_metadata: InputMetadata,
     ) -> Result<Tensor, APIError> {
-        self.llama.forward(
+        self.model.forward(
             &input_tokens,
             &input_positions,
             kv_cache,
@@ -196,13 +757,66 @@ impl<'s> ModulePipeline<'s> for LlamaPipeline {
         )
     }
 
+    fn forward_micro_batches(
+        &mut self,
+        micro_batches: Vec<MicroBatch>,
+        kv_cache: Option<&KVCacheManager>,
+    ) -> Result<Vec<Tensor>, APIError> {
+        self.model.forward_micro_batches(micro_batches, kv_cache)
+    }
+
+    fn offload_stats(&self) -> Option<OffloadStats> {
+        self.model.offload_stats()
+    }
+
+    fn forward_with_hidden_states(
+        &mut self,
+        input_tokens: Tensor,
+        input_positions: Tensor,
+        kv_cache: Option<&KVCacheManager>,
+        input_metadata: InputMetadata,
+    ) -> Result<(Tensor, Tensor), APIError> {
+        let mut input_metadata = input_metadata.with_output_hidden_states(true);
+        let logits = self.model.forward(
+            &input_tokens,
+            &input_positions,
+            kv_cache,
+            &mut input_metadata,
+        )?;
+        match input_metadata.hidden_states.take() {
+            Some(hidden_states) => Ok((logits, hidden_states)),
+            None => Err(APIError::new(format!(
+                "The architecture of `{}` does not expose its hidden states.",
+                self.name
+            ))),
+        }
+    }
+
+    fn embed_tokens(&self, input_ids: &Tensor) -> Result<Tensor, APIError> {
+        self.model.embed_tokens(input_ids)
+    }
+
+    fn compute_logits(&self, hidden_states: &Tensor) -> Result<Tensor, APIError> {
+        self.model.compute_logits(hidden_states)
+    }
+
     fn sample(
         &mut self,
         logits: Tensor,
         sampling_params: &SamplingParams,
         seqs: &[(&usize, &Arc<Sequence>)],
     ) -> Result<Vec<TokenOrFinishReason>, APIError> {
-        let eos_token_id = self.tokenizer.token_to_id(EOS_TOKEN);
+        let eos_token_ids = self
+            .eos_tokens
+            .iter()
+            .copied()
+            .chain(
+                self.chat_template
+                    .as_ref()
+                    .and_then(ChatTemplate::eos_token),
+            )
+            .filter_map(|token| self.tokenizer.token_to_id(token))
+            .collect::<Vec<_>>();
 
         let mut logits_processor = sampling_params.get_logits_processor(
             SAMPLING_SEED,
@@ -218,6 +832,11 @@ impl<'s> ModulePipeline<'s> for LlamaPipeline {
             None => vec![],
         };
 
+        let thinking_tags = match sampling_params.max_thinking_tokens {
+            Some(_) => Some(ThinkingTags::new(&self.tokenizer)?),
+            None => None,
+        };
+
         let n_seqs = logits.dims()[0];
 
         let mut result = Vec::new();
@@ -243,6 +862,18 @@ impl<'s> ModulePipeline<'s> for LlamaPipeline {
                 ))
             };
 
+            let forced_token = thinking_tags.as_ref().and_then(|tags| {
+                tags.forced_token(
+                    &tokens,
+                    seq.deref_mut().get_prompt_len(),
+                    sampling_params.max_thinking_tokens.unwrap(),
+                )
+            });
+            let logits = match forced_token {
+                Some(token) => force_token(&logits, token)?,
+                None => logits,
+            };
+
             let next_token = try_api!(logits_processor.sample(&logits));
             if let Some(text) = self.tokenizer.id_to_token(next_token.token as u32) {
                 let text = text.replace('▁', " ").replace("<0x0A>", "\n");
@@ -252,7 +883,7 @@ impl<'s> ModulePipeline<'s> for LlamaPipeline {
                 }
             }
 
-            if Some(next_token.token) == eos_token_id.map(|x| x as usize) {
+            if eos_token_ids.contains(&(next_token.token as u32)) {
                 result.push(Right("stop".to_string()));
                 continue;
             }
@@ -278,12 +909,32 @@ impl<'s> ModulePipeline<'s> for LlamaPipeline {
         &mut self.conversation
     }
 
+    fn chat_template(&self) -> Option<&ChatTemplate> {
+        self.chat_template.as_ref()
+    }
+
+    fn fim_tokens(&self) -> Option<&FimTokens> {
+        self.fim_tokens.as_ref()
+    }
+
+    fn image_inputs(&self) -> Option<&ImageInputs> {
+        self.image_inputs.as_ref()
+    }
+
+    fn pooling(&self) -> Option<PoolingMode> {
+        self.pooling
+    }
+
     fn get_model_config(&self) -> Box<dyn ConfigLike> {
-        Box::new(self.llama.get_config().clone())
+        self.model.get_config()
     }
 
     fn get_dtype(&self) -> DType {
-        todo!()
+        self.dtype
+    }
+
+    fn device(&self) -> &Device {
+        &self.device
     }
 }
 
@@ -1,24 +1,43 @@
 use std::{
     collections::{HashMap, VecDeque},
     iter::zip,
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
 };
 
 use either::Either;
 use tokenizers::Encoding;
+use uuid::Uuid;
 
 use crate::{
+    backend::AttentionComputeDtype,
+    log_warning,
     openai::{
+        metrics::{request_timings, EngineMetrics, MetricsHandle, MetricsSnapshot},
+        models::pipeline_parallel::{split_micro_batches, MicroBatch},
+        placement::ReplicaCounters,
+        requests::DetokenizationMode,
         responses::{
-            APIError, ChatChoice, ChatChoiceData, ChatCompletionUsageResponse, WrapperLogprobs,
+            APIError, ChatChoice, ChatChoiceData, ChatCompletionTimings,
+            ChatCompletionUsageResponse, WrapperLogprobs,
         },
-        sampling_params::SamplingParams,
+        sampling_params::{GenerationConfig, SamplingOptions, SamplingParams},
         utils::get_created_time_secs,
     },
-    paged_attention::input_metadata::InputMetadata,
+    paged_attention::{
+        attention_backend::AttentionBackendKind,
+        input_metadata::{
+            CrossAttentionMetadata, DecodeMetadata, ImageMetadata, InputMetadata, PromptMetadata,
+            StateMetadata,
+        },
+    },
+    profiling,
     scheduler::{
+        autotune::AutotuneResult,
         cache_engine::{CacheConfig, CacheEngine},
-        sequence::{Sequence, SequenceGroup, _Sequence},
+        observer::SchedulerObserver,
+        sequence::{_Sequence, CancellationReason, Sequence, SequenceGroup},
         SchedulerConfig, SchedulerOutput,
     },
     try_api,
@@ -26,7 +45,15 @@ use crate::{
 
 use crate::scheduler::Scheduler;
 
-use super::{ModulePipeline, _make_tensor_with_pad};
+use super::{
+    _make_tensor_with_pad,
+    eagle::{EagleDraft, EagleHead},
+    medusa::{Medusa, MedusaHeads, TypicalAcceptance},
+    pooling::PoolingMode,
+    prompt_lookup::PromptLookup,
+    speculative::{accept_draft_tokens, DraftModel},
+    ModulePipeline, TokenOrFinishReason,
+};
 
 use candle_core::{Device, Tensor};
 
@@ -37,7 +64,189 @@ struct PreparedInputs {
     metadata: InputMetadata,
 }
 
+/// The tokens of a step, laid out as a single row: the prompt slice followed by the decode slice.
+#[derive(Default)]
+struct StepTokens {
+    tokens: Vec<i64>,
+    positions: Vec<i64>,
+    slot_mapping: Vec<i64>,
+}
+
+impl StepTokens {
+    fn into_inputs(
+        self,
+        prompt: Option<PromptMetadata>,
+        decode: Option<DecodeMetadata>,
+        kv_cache_dtype: String,
+        sliding_window: Option<usize>,
+        device: &Device,
+    ) -> Result<PreparedInputs, APIError> {
+        let num_tokens = self.tokens.len();
+        let row = |x: Vec<i64>| Tensor::from_vec(x, (1, num_tokens), device);
+        Ok(PreparedInputs {
+            tokens: try_api!(row(self.tokens)),
+            positions: try_api!(row(self.positions)),
+            metadata: InputMetadata::new(
+                prompt,
+                decode,
+                try_api!(row(self.slot_mapping)),
+                kv_cache_dtype,
+                sliding_window,
+            ),
+        })
+    }
+}
+
+/// The outputs of an engine step.
+enum StepOutput {
+    /// The next token or the finish reason of each sequence.
+    Sampled(Vec<TokenOrFinishReason>),
+    /// The logits of the model for the draft tokens of each sequence, see
+    /// `accept_draft_tokens`, and its hidden states if the tokens were drafted by EAGLE. The
+    /// sequences which opted out of speculation have no draft tokens.
+    Verified {
+        logits: Tensor,
+        draft_tokens: Vec<Vec<usize>>,
+        hidden_states: Option<Tensor>,
+    },
+    /// The logits and hidden states of the model for the candidate tree of each sequence and
+    /// whether it may accept candidates, see `Medusa::accept`.
+    TreeVerified {
+        logits: Tensor,
+        hidden_states: Tensor,
+        node_tokens: Vec<Vec<usize>>,
+        speculative: Vec<bool>,
+    },
+}
+
 const _PAD_SLOT_ID: i64 = -1;
+/// Number of consecutive failed engine steps after which the error is returned.
+const MAX_STEP_ATTEMPTS: usize = 3;
+/// Number of tokens generated for each dummy request of the warmup.
+pub const WARMUP_MAX_TOKENS: usize = 3;
+
+/// An input of an encoder-only model.
+#[derive(Clone, Debug, Default)]
+pub struct EncoderInput {
+    pub tokens: Vec<usize>,
+    /// The segment of each token, e.g. of the query and the document of a cross-encoder pair.
+    pub token_type_ids: Option<Vec<u32>>,
+}
+
+/// Per-request options which are not sampling parameters.
+#[derive(Clone, Debug, Default)]
+pub struct RequestOptions {
+    /// Prefix cache namespace (tenant) of the request.
+    pub cache_namespace: Option<String>,
+    /// The session of a stateful conversation. With a session TTL configured, the KV cache of the
+    /// request is retained for a follow-up request in the same session.
+    pub session_id: Option<String>,
+    /// Time to first token objective. The request fails with an SLO-miss error if the scheduler
+    /// estimates that it cannot be met.
+    pub ttft_slo: Option<Duration>,
+    /// How the generated tokens are returned.
+    pub detokenize: DetokenizationMode,
+    /// Neither reuse cached blocks for the request nor share its blocks with other requests,
+    /// e.g. for privacy-sensitive prompts.
+    pub disable_prefix_cache: bool,
+    /// Decode the request one token at a time even if speculative decoding is enabled, e.g. for
+    /// strict determinism. The steps it is batched in are not speculative either.
+    pub disable_speculation: bool,
+    /// The pixels of the images of the prompt of a vision-language model in prompt order, see
+    /// `ImageProcessor::preprocess`.
+    pub images: Vec<Tensor>,
+}
+
+/// A sequence generated for a `RequestOutput`.
+#[derive(Clone, Debug)]
+pub struct CompletionOutput {
+    /// The rank of the sequence among the sequences of the request, by cumulative logprob.
+    pub index: usize,
+    /// The text of the generated tokens, decoded incrementally as they are generated, see
+    /// `IncrementalDetokenizer`. A trailing incomplete character is left out until the sequence
+    /// finished.
+    pub text: String,
+    pub token_ids: Vec<usize>,
+    pub cumulative_logprob: f32,
+    /// None while the sequence is generating.
+    pub finish_reason: Option<String>,
+    pub cancellation_reason: Option<CancellationReason>,
+}
+
+/// The generated sequences of a request of the library API, see `LLMEngine::generate` and
+/// `AsyncLLMEngine::generate`. A streamed output holds everything generated so far.
+#[derive(Clone, Debug)]
+pub struct RequestOutput {
+    pub request_id: String,
+    pub prompt_token_ids: Vec<usize>,
+    /// The `n` best sequences of the request.
+    pub outputs: Vec<CompletionOutput>,
+    pub finished: bool,
+}
+
+/// The choices, usage and timings of a finished (or aborted) group.
+pub type GroupResponse = (
+    Vec<ChatChoice>,
+    ChatCompletionUsageResponse,
+    ChatCompletionTimings,
+);
+
+/// A handle to request the abortion of in-flight requests. It can be used without holding the
+/// lock on the `LLMEngine`, aborts are applied at the start of the next engine step.
+#[derive(Clone, Default)]
+pub struct AbortHandle(Arc<Mutex<AbortRequests>>);
+
+#[derive(Default)]
+struct AbortRequests {
+    requests: HashMap<String, CancellationReason>,
+    /// Abort every unfinished request, e.g. on shutdown.
+    all: Option<CancellationReason>,
+}
+
+impl AbortHandle {
+    fn requests(&self) -> MutexGuard<'_, AbortRequests> {
+        loop {
+            if let Ok(v) = self.0.try_lock() {
+                return v;
+            }
+        }
+    }
+
+    pub fn abort(&self, request_id: String, reason: CancellationReason) {
+        self.requests().requests.insert(request_id, reason);
+    }
+
+    pub fn abort_all(&self, reason: CancellationReason) {
+        self.requests().all = Some(reason);
+    }
+
+    fn take(&self) -> AbortRequests {
+        std::mem::take(&mut *self.requests())
+    }
+}
+
+/// Where `LLMEngine::run_until_finished` passes the outputs of the library API and of streamed
+/// requests of the server.
+enum OutputSink<'o> {
+    /// The requests of the server are answered with the group responses only.
+    None,
+    /// The output of each group once it finished.
+    Finished(&'o mut dyn FnMut(RequestOutput)),
+    /// The output of each group after each of its steps.
+    Streamed(&'o mut dyn FnMut(RequestOutput)),
+}
+
+/// The token ids of a tokenized prompt.
+fn encoding_ids(prompt: &Encoding) -> Vec<usize> {
+    prompt.get_ids().iter().map(|x| *x as usize).collect()
+}
+
+/// Whether `err` is the device running out of memory, after which the engine can recover by
+/// dropping the batch.
+fn is_out_of_memory(err: &APIError) -> bool {
+    let err = err.to_string().to_lowercase();
+    err.contains("out of memory") || err.contains("out_of_memory")
+}
 
 pub struct LLMEngine<'a> {
     pipeline: Box<dyn ModulePipeline<'a>>,
@@ -47,166 +256,1920 @@ pub struct LLMEngine<'a> {
     group_id: usize,
     cache_engine: CacheEngine,
     sliding_window: Option<usize>,
+    /// The first decoder token of an encoder-decoder model, whose requests are encoded.
+    decoder_start_token_id: Option<usize>,
+    v2_min_context_len: Option<usize>,
+    attention_backend: AttentionBackendKind,
+    attention_compute: AttentionComputeDtype,
+    metrics: MetricsHandle,
+    abort_handle: AbortHandle,
+    /// The draft model of speculative decoding, if any.
+    draft: Option<DraftModel<'a>>,
+    /// The Medusa heads proposing candidate trees, if any.
+    medusa: Option<Medusa>,
+    /// The EAGLE head drafting from the hidden states of the model, if any.
+    eagle: Option<EagleDraft>,
+    /// The n-gram matching of prompt-lookup speculation, if enabled.
+    prompt_lookup: Option<PromptLookup>,
+    /// Number of micro-batches a step is split into, see `models::pipeline_parallel`.
+    num_micro_batches: usize,
+    /// The load of this engine as a data-parallel replica, published after each step.
+    replica_counters: Arc<ReplicaCounters>,
 }
 
 impl<'a> LLMEngine<'a> {
     pub fn new(
         pipeline: Box<dyn ModulePipeline<'a>>,
         scheduler_config: SchedulerConfig,
-        cache_config: CacheConfig,
+        mut cache_config: CacheConfig,
     ) -> Result<Self, APIError> {
-        let cache_engine = CacheEngine::new(
+        let ssm_dims = pipeline.get_model_config().get_ssm_dims();
+        // A prefix cache hit skips the prompt tokens, whose recurrent state is not cached.
+        if ssm_dims.is_some() && cache_config.enable_prefix_caching {
+            log_warning("Prefix caching is disabled for state-space models.");
+            cache_config.enable_prefix_caching = false;
+        }
+        // The hidden states of every prompt token are pooled, none can be skipped.
+        let encoder_only = pipeline.get_model_config().is_encoder_only();
+        if encoder_only && cache_config.enable_prefix_caching {
+            log_warning("Prefix caching is disabled for encoder-only models.");
+            cache_config.enable_prefix_caching = false;
+        }
+        if cache_config.num_cpu_blocks.is_none() {
+            let num_cpu_blocks = CacheEngine::get_num_cpu_blocks(
+                &*pipeline.get_model_config(),
+                &cache_config,
+                pipeline.get_dtype(),
+            )?;
+            cache_config.set_num_cpu_blocks(num_cpu_blocks);
+        }
+        let cache_engine = CacheEngine::new_on_device(
             pipeline.get_model_config(),
             cache_config.clone(),
             pipeline.get_dtype(),
+            pipeline.device().clone(),
+        )?;
+        let sliding_window = pipeline.get_model_config().get_sliding_window();
+        let decoder_start_token_id = pipeline
+            .get_model_config()
+            .get_decoder_start_token_id()
+            .map(|token| token as usize);
+        let max_num_seqs = scheduler_config.max_num_seqs;
+        let mut scheduler = Scheduler::new(scheduler_config, &cache_config);
+        if let Some(ssm_dims) = ssm_dims {
+            // Every running sequence holds one state slot.
+            cache_engine.allocate_state_cache(ssm_dims, max_num_seqs, pipeline.get_dtype())?;
+            let uses_kv_blocks = pipeline.get_model_config().get_num_kv_cache_layers() > 0;
+            scheduler = scheduler.with_state_cache(max_num_seqs, uses_kv_blocks);
+        }
+        if encoder_only {
+            scheduler = scheduler.without_kv_blocks();
+        }
+        // One micro-batch per stage keeps every stage busy once the pipeline is filled.
+        let num_micro_batches = pipeline.get_model_config().get_pipeline_parallel_size();
+        let engine = Self {
+            pipeline,
+            scheduler,
+            seq_id: 0,
+            cache_config,
+            group_id: 0,
+            cache_engine,
+            sliding_window,
+            decoder_start_token_id,
+            v2_min_context_len: None,
+            attention_backend: AttentionBackendKind::default(),
+            attention_compute: AttentionComputeDtype::default(),
+            metrics: MetricsHandle::default(),
+            abort_handle: AbortHandle::default(),
+            draft: None,
+            medusa: None,
+            eagle: None,
+            prompt_lookup: None,
+            num_micro_batches,
+            replica_counters: Arc::default(),
+        };
+        engine.publish_load();
+        Ok(engine)
+    }
+
+    pub fn get_pipeline(&self) -> &dyn ModulePipeline<'a> {
+        &*self.pipeline
+    }
+
+    pub fn get_mut_pipeline(&mut self) -> &mut dyn ModulePipeline<'a> {
+        &mut *self.pipeline
+    }
+
+    pub fn get_metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    pub fn get_metrics_handle(&self) -> MetricsHandle {
+        self.metrics.clone()
+    }
+
+    /// Take over the abort and metrics handles of `previous`, which this engine replaces.
+    pub fn inherit_handles(&mut self, previous: &LLMEngine<'a>) {
+        self.abort_handle = previous.abort_handle.clone();
+        self.metrics = previous.metrics.clone();
+        self.replica_counters = previous.replica_counters.clone();
+        self.publish_load();
+    }
+
+    /// Observe the scheduling decisions of this engine, e.g. with a `TraceExporter`.
+    pub fn add_scheduler_observer(&mut self, observer: Box<dyn SchedulerObserver>) {
+        self.scheduler.add_observer(observer);
+    }
+
+    /// Pick the decode attention kernel by the crossover measured by `scheduler::autotune`.
+    pub fn set_autotune_result(&mut self, result: &AutotuneResult) {
+        self.v2_min_context_len = Some(result.v2_min_context_len);
+    }
+
+    /// Prefer `attention_backend` over the automatic choice wherever it is supported.
+    pub fn set_attention_backend(&mut self, attention_backend: AttentionBackendKind) {
+        self.attention_backend = attention_backend;
+    }
+
+    /// Compute the decode attention scores in `attention_compute` instead of full precision.
+    pub fn set_attention_compute(&mut self, attention_compute: AttentionComputeDtype) {
+        self.attention_compute = attention_compute;
+    }
+
+    /// Split the steps into up to `num_micro_batches` micro-batches, by default the number of
+    /// pipeline stages of the model. More micro-batches shrink the bubble at the start and end of
+    /// a step, at the cost of smaller batches per stage.
+    pub fn set_num_micro_batches(&mut self, num_micro_batches: usize) {
+        self.num_micro_batches = num_micro_batches.max(1);
+    }
+
+    /// Speculate `num_speculative_tokens` tokens per decode step with the `draft` model, which
+    /// must share the tokenizer of the model. It may run on another device, where its KV cache
+    /// has as many blocks as the cache of the model.
+    pub fn set_speculative_model(
+        &mut self,
+        draft: Box<dyn ModulePipeline<'a>>,
+        num_speculative_tokens: usize,
+    ) -> Result<(), APIError> {
+        self.check_speculation_support()?;
+        let draft = DraftModel::new(draft, self.cache_config.clone(), num_speculative_tokens)?;
+        self.scheduler
+            .set_num_lookahead_slots(num_speculative_tokens);
+        self.draft = Some(draft);
+        Ok(())
+    }
+
+    /// Verify a tree of `num_candidates` candidates of the Medusa `heads` in each decode step,
+    /// accepted with `typical_acceptance` if given, see `medusa::TypicalAcceptance`. The heads
+    /// run on the last hidden state of each sequence, so the architecture of the model must
+    /// expose its hidden states.
+    pub fn set_medusa_heads(
+        &mut self,
+        heads: MedusaHeads,
+        num_candidates: usize,
+        typical_acceptance: Option<TypicalAcceptance>,
+    ) -> Result<(), APIError> {
+        self.check_speculation_support()?;
+        // The keys and values of the accepted candidates are moved to their positions.
+        if self.cache_config.cache_dtype.kernel_name() != "auto"
+            || self.pipeline.get_model_config().get_mla_dims().is_some()
+        {
+            return Err(APIError::new_str(
+                "Medusa decoding requires an unquantized KV cache with separate keys and values.",
+            ));
+        }
+        if self.pipeline.device().is_metal() {
+            return Err(APIError::new_str(
+                "Tree attention is not supported by the Metal kernels.",
+            ));
+        }
+        let medusa = Medusa::new(heads, num_candidates, typical_acceptance)?;
+        self.scheduler
+            .set_num_lookahead_slots(medusa.tree().len() - 1);
+        self.medusa = Some(medusa);
+        Ok(())
+    }
+
+    /// Speculate `num_speculative_tokens` tokens per decode step with the EAGLE `head`, which
+    /// shares the embeddings and the output layer of the model and must run on its device. Its KV
+    /// cache has as many blocks as the cache of the model.
+    pub fn set_eagle_head(
+        &mut self,
+        head: EagleHead,
+        num_speculative_tokens: usize,
+    ) -> Result<(), APIError> {
+        self.check_speculation_support()?;
+        if !head.device().same_device(self.pipeline.device()) {
+            return Err(APIError::new_str(
+                "The EAGLE head must run on the device of the model.",
+            ));
+        }
+        let eagle = EagleDraft::new(
+            head,
+            &*self.pipeline,
+            self.cache_config.clone(),
+            num_speculative_tokens,
+        )?;
+        self.scheduler
+            .set_num_lookahead_slots(num_speculative_tokens);
+        self.eagle = Some(eagle);
+        Ok(())
+    }
+
+    /// Speculate the tokens proposed by `prompt_lookup` in each decode step, see
+    /// `prompt_lookup::PromptLookup`.
+    pub fn set_prompt_lookup(&mut self, prompt_lookup: PromptLookup) -> Result<(), APIError> {
+        self.check_speculation_support()?;
+        self.scheduler
+            .set_num_lookahead_slots(prompt_lookup.num_speculative_tokens());
+        self.prompt_lookup = Some(prompt_lookup);
+        Ok(())
+    }
+
+    /// Whether the model supports decoding several tokens per step, with a draft model, Medusa
+    /// heads, an EAGLE head or prompt lookup. Only one of them can be used.
+    fn check_speculation_support(&self) -> Result<(), APIError> {
+        let config = self.pipeline.get_model_config();
+        if config.get_ssm_dims().is_some()
+            || config.is_encoder_only()
+            || self.decoder_start_token_id.is_some()
+            || self.pipeline.image_inputs().is_some()
+        {
+            return Err(APIError::new(format!(
+                "Speculative decoding is not supported for `{}`.",
+                self.pipeline.name()
+            )));
+        }
+        // The speculated tokens are positioned after the last token of the block table.
+        if self.cache_config.attention_sinks.is_some() {
+            return Err(APIError::new_str(
+                "Speculative decoding is not supported with attention sinks.",
+            ));
+        }
+        if self.draft.is_some()
+            || self.medusa.is_some()
+            || self.eagle.is_some()
+            || self.prompt_lookup.is_some()
+        {
+            return Err(APIError::new_str(
+                "Speculative decoding is already enabled for the engine.",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Size in bytes of the KV cache on the GPU and of the CPU swap space, including the cache of
+    /// the draft model or the EAGLE head. The cache of a draft model on another device is left
+    /// out, as it does not take memory of the device of the model.
+    pub fn get_kv_cache_memory_usage(&self) -> (usize, usize) {
+        let (gpu_bytes, cpu_bytes) = self.cache_engine.memory_usage();
+        let (draft_gpu_bytes, draft_cpu_bytes) = match (&self.draft, &self.eagle) {
+            (Some(draft), _) if draft.is_on_other_device(self.pipeline.device()) => (0, 0),
+            (Some(draft), _) => draft.get_kv_cache_memory_usage(),
+            (_, Some(eagle)) => eagle.get_kv_cache_memory_usage(),
+            _ => (0, 0),
+        };
+        (gpu_bytes + draft_gpu_bytes, cpu_bytes + draft_cpu_bytes)
+    }
+
+    pub fn get_abort_handle(&self) -> AbortHandle {
+        self.abort_handle.clone()
+    }
+
+    /// The load counters of this engine as a data-parallel replica, which can be read without
+    /// holding the lock on the engine.
+    pub fn get_replica_counters(&self) -> Arc<ReplicaCounters> {
+        self.replica_counters.clone()
+    }
+
+    /// Number of leading blocks of `prompt` in the prefix cache of `cache_namespace`, used to
+    /// place requests in data-parallel mode.
+    pub fn get_num_cached_prefix_blocks(
+        &self,
+        prompt: &[usize],
+        cache_namespace: Option<&str>,
+    ) -> usize {
+        let seq = _Sequence::new(prompt.to_vec(), 0, self.cache_config.block_size);
+        self.scheduler
+            .block_engine
+            .get_num_cached_blocks(&seq, cache_namespace)
+    }
+
+    /// Publish the free GPU blocks and the prefill throughput to the replica counters.
+    fn publish_load(&self) {
+        self.replica_counters.publish(
+            self.scheduler.block_engine.get_num_free_gpu_blocks(),
+            self.cache_config.block_size,
+            self.scheduler.get_prefill_throughput(),
+        );
+    }
+
+    /// Move the parked session `session_id` of `cache_namespace` with its KV cache, and those of
+    /// the draft model or EAGLE head, from this engine to `dst`, a replica of the same model, see
+    /// `Scheduler::migrate_session`. Returns whether the session was migrated.
+    pub fn migrate_session(
+        &mut self,
+        dst: &mut LLMEngine<'a>,
+        cache_namespace: Option<&str>,
+        session_id: &str,
+    ) -> Result<bool, APIError> {
+        let (cache_engine, draft, eagle) = (&self.cache_engine, &self.draft, &self.eagle);
+        let (dst_cache_engine, dst_draft, dst_eagle) = (&dst.cache_engine, &dst.draft, &dst.eagle);
+        self.scheduler.migrate_session(
+            &mut dst.scheduler,
+            cache_namespace,
+            session_id,
+            |src_to_dst| {
+                if let (Some(draft), Some(dst_draft)) = (draft, dst_draft) {
+                    draft.migrate_to(dst_draft, src_to_dst.clone())?;
+                }
+                if let (Some(eagle), Some(dst_eagle)) = (eagle, dst_eagle) {
+                    eagle.migrate_to(dst_eagle, src_to_dst.clone())?;
+                }
+                cache_engine.migrate_to(dst_cache_engine, src_to_dst)
+            },
+        )
+    }
+
+    /// Generate the completions of a request of the server, returning the response of each of
+    /// its groups once all of them finished.
+    pub fn generate_request(
+        &mut self,
+        prompt: Encoding,
+        request_id: String,
+        created: u64,
+        sampling_params: SamplingParams,
+        options: RequestOptions,
+    ) -> Result<Vec<GroupResponse>, APIError> {
+        self.run_request(
+            prompt,
+            request_id,
+            created,
+            sampling_params,
+            options,
+            OutputSink::None,
+        )
+    }
+
+    /// Generate the completions of a request of the server like `generate_request`, passing the
+    /// output of its groups so far to `on_output` after each step, e.g. to stream the tokens.
+    pub fn generate_request_streaming(
+        &mut self,
+        prompt: Encoding,
+        request_id: String,
+        created: u64,
+        sampling_params: SamplingParams,
+        options: RequestOptions,
+        on_output: &mut dyn FnMut(RequestOutput),
+    ) -> Result<Vec<GroupResponse>, APIError> {
+        self.run_request(
+            prompt,
+            request_id,
+            created,
+            sampling_params,
+            options,
+            OutputSink::Streamed(on_output),
+        )
+    }
+
+    fn run_request(
+        &mut self,
+        prompt: Encoding,
+        request_id: String,
+        created: u64,
+        sampling_params: SamplingParams,
+        options: RequestOptions,
+        sink: OutputSink,
+    ) -> Result<Vec<GroupResponse>, APIError> {
+        self.check_generation_support(&sampling_params)?;
+        if let Some(session_id) = &options.session_id {
+            self.scheduler
+                .resume_session(options.cache_namespace.as_deref(), session_id);
+        }
+        let detokenize = options.detokenize;
+        self.add_request(encoding_ids(&prompt), request_id.clone(), created, options);
+        let responses = self.run_until_finished(&sampling_params, detokenize, sink)?;
+        let deadline_missed = responses.values().any(|(choices, _, _)| {
+            choices.iter().any(|choice| {
+                choice.cancellation_reason == Some(CancellationReason::DeadlineMissed)
+            })
+        });
+        if deadline_missed {
+            return Err(APIError::new(format!(
+                "Request `{request_id}` cannot meet its time to first token objective."
+            )));
+        }
+        Ok(responses.into_values().collect())
+    }
+
+    /// Generate the completions of raw text `prompts`, tokenized with the special tokens of the
+    /// tokenizer (e.g. BOS) but without chat template, with the same sampling parameters, which
+    /// must fit the context of the model (see `SamplingOptions::normalize`). The prompts are
+    /// batched by the scheduler like concurrent requests. Returns the output of each prompt, in
+    /// prompt order.
+    pub fn generate(
+        &mut self,
+        prompts: Vec<String>,
+        sampling_params: SamplingParams,
+    ) -> Result<Vec<RequestOutput>, APIError> {
+        self.check_generation_support(&sampling_params)?;
+        let prompts = prompts
+            .into_iter()
+            .map(|prompt| {
+                self.pipeline
+                    .tokenizer()
+                    .tokenize_with_special_tokens(prompt)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let created = get_created_time_secs();
+        let request_ids = prompts
+            .iter()
+            .map(|prompt| {
+                let request_id = format!("cmpl-{}", Uuid::new_v4());
+                self.add_request(
+                    encoding_ids(prompt),
+                    request_id.clone(),
+                    created,
+                    RequestOptions::default(),
+                );
+                request_id
+            })
+            .collect::<Vec<_>>();
+
+        let mut outputs = HashMap::new();
+        self.run_until_finished(
+            &sampling_params,
+            DetokenizationMode::TokenIds,
+            OutputSink::Finished(&mut |output| {
+                outputs.insert(output.request_id.clone(), output);
+            }),
+        )?;
+        request_ids
+            .iter()
+            .map(|request_id| {
+                outputs
+                    .remove(request_id)
+                    .ok_or_else(|| APIError::new(format!("Request `{request_id}` did not finish.")))
+            })
+            .collect()
+    }
+
+    /// Generate the completions of a raw text `prompt` like `generate`, passing the output of the
+    /// request so far to `on_output` after each of its steps, up to the finished output.
+    pub fn generate_streaming(
+        &mut self,
+        prompt: String,
+        request_id: String,
+        sampling_params: SamplingParams,
+        on_output: &mut dyn FnMut(RequestOutput),
+    ) -> Result<(), APIError> {
+        self.check_generation_support(&sampling_params)?;
+        let prompt = self
+            .pipeline
+            .tokenizer()
+            .tokenize_with_special_tokens(prompt)?;
+        self.add_request(
+            encoding_ids(&prompt),
+            request_id,
+            get_created_time_secs(),
+            RequestOptions::default(),
+        );
+        self.run_until_finished(
+            &sampling_params,
+            DetokenizationMode::TokenIds,
+            OutputSink::Streamed(on_output),
+        )?;
+        Ok(())
+    }
+
+    fn check_generation_support(&self, sampling_params: &SamplingParams) -> Result<(), APIError> {
+        if self.pipeline.get_model_config().is_encoder_only() {
+            return Err(APIError::new(format!(
+                "The model `{}` is an encoder-only model, see `/v1/embeddings` and `/v1/rerank`.",
+                self.pipeline.name()
+            )));
+        }
+        if sampling_params.use_beam_search {
+            return Err(APIError::new_str("Beam search is not supported."));
+        }
+        // A fork only computes the last prompt token, see `fork_prompt_seqs`. The recurrent state
+        // and the encoder and image inputs of the prompt cannot be shared that way.
+        if sampling_params.best_of > 1
+            && (self.scheduler.state_cache.is_some()
+                || self.decoder_start_token_id.is_some()
+                || self.pipeline.image_inputs().is_some())
+        {
+            return Err(APIError::new(format!(
+                "`best_of` > 1 is not supported for `{}`.",
+                self.pipeline.name()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Fork the sequence of each group scheduled for its prefill into `best_of` sequences, which
+    /// share the blocks of the prompt and sample their tokens independently from the first one.
+    /// Groups which already hold several sequences or have generated tokens are left as is.
+    fn fork_prompt_seqs(&mut self, scheduler_outputs: &SchedulerOutput, best_of: usize) {
+        if best_of < 2 {
+            return;
+        }
+        for group in scheduler_outputs.scheduled.iter() {
+            let seqs = group.get_seqs();
+            let Some((parent_id, parent)) = seqs.iter().next().filter(|_| seqs.len() == 1) else {
+                continue;
+            };
+            if !parent.deref_mut().is_prompt() {
+                continue;
+            }
+            for _ in 1..best_of {
+                if self
+                    .scheduler
+                    .fork_seq(group, *parent_id, self.seq_id)
+                    .is_none()
+                {
+                    break;
+                }
+                self.seq_id += 1;
+            }
+        }
+    }
+
+    /// Run the scheduler until every queued request finished, returning the response of each
+    /// group by its id. The outputs of the groups are passed to `sink` after each step and
+    /// once a group is aborted.
+    fn run_until_finished(
+        &mut self,
+        sampling_params: &SamplingParams,
+        detokenize: DetokenizationMode,
+        mut sink: OutputSink,
+    ) -> Result<HashMap<usize, GroupResponse>, APIError> {
+        let mut responses = HashMap::new();
+        let mut num_failed_steps = 0;
+        while self.scheduler.has_unfinished_sequences() {
+            let aborts = self.abort_handle.take();
+            let aborted = match aborts.all {
+                Some(reason) => self.scheduler.abort_all(reason),
+                None => aborts
+                    .requests
+                    .into_iter()
+                    .filter_map(|(request_id, reason)| {
+                        self.scheduler.abort_request(&request_id, reason)
+                    })
+                    .collect(),
+            };
+            for group in aborted {
+                let response = self.get_group_response(&group, sampling_params.n, detokenize)?;
+                responses.insert(*group.get_id(), response);
+                self.send_output(&mut sink, &group, sampling_params.n)?;
+            }
+            if !self.scheduler.has_unfinished_sequences() {
+                break;
+            }
+
+            let scheduler_outputs = {
+                let _range = profiling::range("schedule");
+                self.scheduler.schedule()
+            };
+            // The shed groups and the groups whose prompt can never fit in the KV cache
+            // (`FinishedIgnored`) are finished like aborted ones, the other groups keep running.
+            let ignored = scheduler_outputs.ignored_seq_groups.iter().cloned();
+            for group in self.scheduler.take_slo_missed().into_iter().chain(ignored) {
+                let response = self.get_group_response(&group, sampling_params.n, detokenize)?;
+                responses.insert(*group.get_id(), response);
+                self.send_output(&mut sink, &group, sampling_params.n)?;
+            }
+            if scheduler_outputs.scheduled.is_empty() {
+                self.scheduler.commit();
+                continue;
+            }
+            self.fork_prompt_seqs(&scheduler_outputs, sampling_params.best_of);
+
+            let scheduled = &*scheduler_outputs.scheduled;
+
+            // The prompts come first in the batch, see `InputMetadata`.
+            let mut seqs = scheduled
+                .iter()
+                .flat_map(|group| group.get_seqs())
+                .collect::<Vec<_>>();
+            seqs.sort_by_key(|(_, seq)| !seq.deref_mut().is_prompt());
+            let seq_refs = seqs.iter().map(|(id, seq)| (id, seq)).collect::<Vec<_>>();
+
+            let step_start = Instant::now();
+            let step_result = {
+                let _range = profiling::range("step");
+                self.execute_scheduled_step(&scheduler_outputs, sampling_params, &seq_refs)
+            };
+            profiling::end_step();
+            let result = match step_result {
+                Ok(result) => result,
+                Err(err) => {
+                    // Nothing of the step was committed, requeue its groups as they were.
+                    self.scheduler.rollback(scheduled);
+                    if is_out_of_memory(&err) {
+                        // Drop the batch and keep serving the remaining requests.
+                        for group in scheduled.iter() {
+                            if let Some(group) = self.scheduler.abort_request(
+                                group.get_request_id(),
+                                CancellationReason::OutOfMemory,
+                            ) {
+                                let response =
+                                    self.get_group_response(&group, sampling_params.n, detokenize)?;
+                                responses.insert(*group.get_id(), response);
+                                self.send_output(&mut sink, &group, sampling_params.n)?;
+                            }
+                        }
+                        continue;
+                    }
+                    num_failed_steps += 1;
+                    if num_failed_steps < MAX_STEP_ATTEMPTS {
+                        log_warning(&format!("Engine step failed, retrying: {err}"));
+                        continue;
+                    }
+                    return Err(err);
+                }
+            };
+            num_failed_steps = 0;
+            self.scheduler.commit();
+            self.metrics.metrics().record_step(
+                scheduler_outputs.num_prefill_tokens,
+                scheduler_outputs.num_decode_tokens,
+            );
+            if let Some(offload) = self.pipeline.offload_stats() {
+                self.metrics
+                    .metrics()
+                    .record_offload(offload, step_start.elapsed());
+            }
+            self.scheduler.record_step_latency(
+                scheduled,
+                step_start.elapsed(),
+                scheduler_outputs.num_prefill_tokens > 0,
+            );
+
+            self.apply_step_output(result, &seq_refs, sampling_params)?;
+
+            self.scheduler.free_finished_sequence_groups();
+            self.publish_load();
+
+            for group in scheduler_outputs.scheduled.iter() {
+                if group.is_finished() && !responses.contains_key(group.get_id()) {
+                    let response = self.get_group_response(group, sampling_params.n, detokenize)?;
+                    responses.insert(*group.get_id(), response);
+                }
+                self.send_output(&mut sink, group, sampling_params.n)?;
+            }
+        }
+        self.publish_load();
+
+        Ok(responses)
+    }
+
+    /// Execute a scheduled step of a generation request, which verifies several tokens of each
+    /// speculative sequence if speculation is enabled and every sequence decodes a single token.
+    fn execute_scheduled_step(
+        &mut self,
+        scheduler_outputs: &SchedulerOutput,
+        sampling_params: &SamplingParams,
+        seq_refs: &[(&usize, &Arc<Sequence>)],
+    ) -> Result<StepOutput, APIError> {
+        // The sequences of groups which opted out of speculation are verified without proposals,
+        // so that they gain a single token while the others speculate.
+        let seq_ids = seq_refs.iter().map(|(id, _)| **id).collect::<Vec<_>>();
+        let speculative = scheduler_outputs.speculation_mask(&seq_ids);
+        let multi_token = speculative.is_some();
+        let speculate = self.draft.is_some() && multi_token;
+        // The head drafts from the features of the sequences of the previous step only.
+        let speculate_eagle = multi_token
+            && self.eagle.as_ref().is_some_and(|eagle| {
+                seq_refs
+                    .iter()
+                    .all(|(_, seq)| eagle.next(seq.deref_mut().get_id()).is_some())
+            });
+        // The heads proposed candidates for the sequences of the previous step only.
+        let verify_tree = multi_token
+            && self.medusa.as_ref().is_some_and(|medusa| {
+                seq_refs
+                    .iter()
+                    .all(|(_, seq)| medusa.candidates(seq.deref_mut().get_id()).is_some())
+            });
+        let lookup_tokens = match (&self.prompt_lookup, &speculative) {
+            (Some(prompt_lookup), Some(speculative)) => {
+                let token_ids = zip(seq_refs, speculative)
+                    .filter(|(_, speculative)| **speculative)
+                    .map(|((_, seq), _)| seq.deref_mut().get_token_ids())
+                    .collect::<Vec<_>>();
+                prompt_lookup.propose_batch(&token_ids).map(|proposals| {
+                    let mut proposals = proposals.into_iter();
+                    speculative
+                        .iter()
+                        .map(|speculative| {
+                            if *speculative {
+                                proposals.next().unwrap()
+                            } else {
+                                Vec::new()
+                            }
+                        })
+                        .collect::<Vec<_>>()
+                })
+            }
+            _ => None,
+        };
+        let speculative = speculative.unwrap_or_default();
+
+        if speculate {
+            self.execute_speculative_step(
+                scheduler_outputs,
+                sampling_params,
+                seq_refs,
+                &speculative,
+            )
+        } else if speculate_eagle {
+            self.execute_eagle_step(scheduler_outputs, sampling_params, seq_refs, &speculative)
+        } else if let Some(lookup_tokens) = lookup_tokens {
+            self.execute_lookup_step(scheduler_outputs, sampling_params, seq_refs, lookup_tokens)
+        } else if verify_tree {
+            self.execute_tree_step(scheduler_outputs, sampling_params, seq_refs, speculative)
+        } else if self.num_micro_batches > 1
+            && self.draft.is_none()
+            && self.medusa.is_none()
+            && self.eagle.is_none()
+        {
+            self.execute_micro_batched_step(scheduler_outputs, sampling_params, seq_refs)
+                .map(StepOutput::Sampled)
+        } else {
+            self.execute_step(scheduler_outputs, sampling_params, seq_refs)
+                .map(StepOutput::Sampled)
+        }
+    }
+
+    /// Add the tokens of a committed step to its sequences, or finish them.
+    fn apply_step_output(
+        &mut self,
+        result: StepOutput,
+        seq_refs: &[(&usize, &Arc<Sequence>)],
+        sampling_params: &SamplingParams,
+    ) -> Result<(), APIError> {
+        match result {
+            StepOutput::Sampled(result) => {
+                for (result, (_, seq)) in zip(result, seq_refs) {
+                    match result {
+                        Either::Left(logprobs) => {
+                            seq.deref_mut().add_token(logprobs);
+                        }
+                        Either::Right(finish_reason) => {
+                            seq.deref_mut().set_finish_reason(finish_reason)
+                        }
+                    }
+                }
+            }
+            StepOutput::Verified {
+                logits,
+                draft_tokens,
+                hidden_states,
+            } => {
+                let _range = profiling::range("accept");
+                let starts = seq_refs
+                    .iter()
+                    .map(|(_, seq)| seq.deref_mut().get_len() - 1)
+                    .collect::<Vec<_>>();
+                let num_accepted = accept_draft_tokens(
+                    &mut *self.pipeline,
+                    &logits,
+                    &draft_tokens,
+                    sampling_params,
+                    seq_refs,
+                )?;
+                if let Some(hidden_states) = hidden_states {
+                    self.catch_up_eagle(seq_refs, &starts, &hidden_states, sampling_params)?;
+                }
+                let num_draft_tokens = draft_tokens.iter().map(Vec::len).sum();
+                self.metrics
+                    .metrics()
+                    .record_speculation(num_draft_tokens, num_accepted);
+            }
+            StepOutput::TreeVerified {
+                logits,
+                hidden_states,
+                node_tokens,
+                speculative,
+            } => {
+                let _range = profiling::range("accept");
+                let starts = seq_refs
+                    .iter()
+                    .map(|(_, seq)| seq.deref_mut().get_len() - 1)
+                    .collect::<Vec<_>>();
+                let medusa = self.medusa.as_mut().unwrap();
+                let accepted = medusa.accept(
+                    &mut *self.pipeline,
+                    &logits,
+                    &node_tokens,
+                    &speculative,
+                    sampling_params,
+                    seq_refs,
+                )?;
+                // The heads continue from the last node of each sequence whose row was sampled.
+                let tree_len = medusa.tree().len();
+                let rows = accepted
+                    .iter()
+                    .enumerate()
+                    .map(|(seq_n, nodes)| (seq_n * tree_len + nodes.last().unwrap()) as u32)
+                    .collect::<Vec<_>>();
+                let rows = try_api!(Tensor::from_vec(rows, seq_refs.len(), logits.device()));
+                let seq_ids = seq_refs
+                    .iter()
+                    .map(|(_, seq)| seq.deref_mut().get_id())
+                    .collect::<Vec<_>>();
+                medusa.propose(&try_api!(hidden_states.index_select(&rows, 0)), &seq_ids)?;
+                let num_proposed = medusa.tree().max_depth() * seq_refs.len();
+                let num_accepted = accepted.iter().map(|nodes| nodes.len() - 1).sum();
+                self.compact_accepted_candidates(seq_refs, &starts, &accepted)?;
+                self.metrics
+                    .metrics()
+                    .record_speculation(num_proposed, num_accepted);
+            }
+        }
+        Ok(())
+    }
+
+    /// Run the model on dummy requests for every combination of `batch_sizes` and `prompt_lens`,
+    /// prefilling their prompts and decoding a few tokens (or encoding them with an encoder-only
+    /// model), so that the kernels are loaded and the allocator holds buffers of the step shapes
+    /// before the first request. The requests bypass the prefix cache and are left out of the
+    /// metrics. Returns the duration of the warmup.
+    pub fn warmup(
+        &mut self,
+        batch_sizes: &[usize],
+        prompt_lens: &[usize],
+    ) -> Result<Duration, APIError> {
+        if self.scheduler.has_unfinished_sequences() {
+            return Err(APIError::new_str(
+                "The engine can only be warmed up before serving requests.",
+            ));
+        }
+        let start = Instant::now();
+        for &prompt_len in prompt_lens {
+            for &batch_size in batch_sizes {
+                let _range = profiling::range("warmup");
+                if self.pipeline.get_model_config().is_encoder_only() {
+                    let inputs = vec![
+                        EncoderInput {
+                            tokens: vec![0; prompt_len],
+                            token_type_ids: None,
+                        };
+                        batch_size
+                    ];
+                    let pooling = self.pipeline.pooling();
+                    self.encode(inputs, pooling, "warmup".to_string(), 0)?;
+                    continue;
+                }
+                let sampling_params = SamplingOptions {
+                    temperature: Some(0.),
+                    ignore_eos: Some(true),
+                    max_tokens: Some(WARMUP_MAX_TOKENS),
+                    ..Default::default()
+                }
+                .normalize(&GenerationConfig::default(), prompt_len, usize::MAX)?;
+                for _ in 0..batch_size {
+                    let options = RequestOptions {
+                        disable_prefix_cache: true,
+                        ..Default::default()
+                    };
+                    self.add_request(vec![0; prompt_len], "warmup".to_string(), 0, options);
+                }
+                if let Err(err) = self.run_warmup_requests(&sampling_params) {
+                    self.scheduler.abort_all(CancellationReason::ClientRequest);
+                    self.scheduler.free_finished_sequence_groups();
+                    return Err(err);
+                }
+            }
+        }
+        self.scheduler.reset_step_history();
+        self.publish_load();
+        *self.metrics.metrics() = EngineMetrics::default();
+        Ok(start.elapsed())
+    }
+
+    /// Run the queued warmup requests to completion. Prompts which do not fit in the KV cache are
+    /// skipped.
+    fn run_warmup_requests(&mut self, sampling_params: &SamplingParams) -> Result<(), APIError> {
+        while self.scheduler.has_unfinished_sequences() {
+            let scheduler_outputs = self.scheduler.schedule();
+            if scheduler_outputs.scheduled.is_empty() {
+                self.scheduler.commit();
+                continue;
+            }
+            let scheduled = &*scheduler_outputs.scheduled;
+            let mut seqs = scheduled
+                .iter()
+                .flat_map(|group| group.get_seqs())
+                .collect::<Vec<_>>();
+            seqs.sort_by_key(|(_, seq)| !seq.deref_mut().is_prompt());
+            let seq_refs = seqs.iter().map(|(id, seq)| (id, seq)).collect::<Vec<_>>();
+            let result =
+                match self.execute_scheduled_step(&scheduler_outputs, sampling_params, &seq_refs) {
+                    Ok(result) => result,
+                    Err(err) => {
+                        self.scheduler.rollback(scheduled);
+                        return Err(err);
+                    }
+                };
+            self.scheduler.commit();
+            self.apply_step_output(result, &seq_refs, sampling_params)?;
+            self.scheduler.free_finished_sequence_groups();
+        }
+        Ok(())
+    }
+
+    /// Encode `inputs` with an encoder-only model into their pooled embeddings, in input order.
+    pub fn embed(
+        &mut self,
+        inputs: Vec<Vec<usize>>,
+        request_id: String,
+        created: u64,
+    ) -> Result<Vec<Vec<f32>>, APIError> {
+        let Some(pooling) = self.pipeline.pooling() else {
+            return Err(APIError::new(format!(
+                "The model `{}` is not an embedding model.",
+                self.pipeline.name()
+            )));
+        };
+        let inputs = inputs
+            .into_iter()
+            .map(|tokens| EncoderInput {
+                tokens,
+                token_type_ids: None,
+            })
+            .collect();
+        self.encode(inputs, Some(pooling), request_id, created)
+    }
+
+    /// Score the relevance of each (query, document) pair of `inputs` with a cross-encoder, in
+    /// input order. The inputs are the pairs encoded together, with their token types.
+    pub fn score(
+        &mut self,
+        inputs: Vec<EncoderInput>,
+        request_id: String,
+        created: u64,
+    ) -> Result<Vec<f32>, APIError> {
+        if self.pipeline.get_model_config().get_num_labels() != Some(1) {
+            return Err(APIError::new(format!(
+                "The model `{}` is not a cross-encoder with a single relevance score.",
+                self.pipeline.name()
+            )));
+        }
+        let scores = self.encode(inputs, None, request_id, created)?;
+        // The relevance logits are mapped to [0, 1] like the scores of Cohere.
+        Ok(scores
+            .into_iter()
+            .map(|score| 1. / (1. + (-score[0]).exp()))
+            .collect())
+    }
+
+    /// Run `inputs` through an encoder-only model, one row of outputs per input in input order:
+    /// the embeddings pooled with `pooling`, or the scores of a classification head. Each input
+    /// is queued as a sequence group of its own, so the inputs are batched by the scheduler like
+    /// prompts, but nothing is cached or generated.
+    fn encode(
+        &mut self,
+        inputs: Vec<EncoderInput>,
+        pooling: Option<PoolingMode>,
+        request_id: String,
+        created: u64,
+    ) -> Result<Vec<Vec<f32>>, APIError> {
+        let mut token_type_ids = HashMap::new();
+        let mut seq_ids = Vec::with_capacity(inputs.len());
+        for input in inputs {
+            let seq_id = self.add_request(
+                input.tokens,
+                request_id.clone(),
+                created,
+                RequestOptions::default(),
+            );
+            if let Some(ids) = input.token_type_ids {
+                token_type_ids.insert(seq_id, ids);
+            }
+            seq_ids.push(seq_id);
+        }
+
+        let mut outputs = HashMap::new();
+        let mut num_failed_steps = 0;
+        while self.scheduler.has_unfinished_sequences() {
+            // The outputs are returned together, an abort drops all inputs.
+            let mut aborts = self.abort_handle.take();
+            if let Some(reason) = aborts.all.or(aborts.requests.remove(&request_id)) {
+                self.scheduler.abort_all(reason);
+                return Err(APIError::new(format!(
+                    "Request `{request_id}` was aborted: {reason:?}."
+                )));
+            }
+
+            let scheduler_outputs = {
+                let _range = profiling::range("schedule");
+                self.scheduler.schedule()
+            };
+            if scheduler_outputs.scheduled.is_empty() {
+                self.scheduler.commit();
+                continue;
+            }
+            let scheduled = &*scheduler_outputs.scheduled;
+            let seqs = scheduled
+                .iter()
+                .flat_map(|group| group.get_seqs())
+                .collect::<Vec<_>>();
+            let seq_refs = seqs.iter().map(|(id, seq)| (id, seq)).collect::<Vec<_>>();
+
+            let step_start = Instant::now();
+            let step_result = {
+                let _range = profiling::range("step");
+                self.execute_encoder_step(&scheduler_outputs, pooling, &token_type_ids, &seq_refs)
+            };
+            profiling::end_step();
+            let result = match step_result {
+                Ok(result) => result,
+                Err(err) => {
+                    self.scheduler.rollback(scheduled);
+                    num_failed_steps += 1;
+                    if is_out_of_memory(&err) {
+                        self.scheduler.abort_all(CancellationReason::OutOfMemory);
+                        return Err(err);
+                    }
+                    if num_failed_steps < MAX_STEP_ATTEMPTS {
+                        log_warning(&format!("Engine step failed, retrying: {err}"));
+                        continue;
+                    }
+                    // The error is returned to the client in place of the outputs.
+                    self.scheduler.abort_all(CancellationReason::ClientRequest);
+                    return Err(err);
+                }
+            };
+            num_failed_steps = 0;
+            self.scheduler.commit();
+            self.metrics
+                .metrics()
+                .record_step(scheduler_outputs.num_prefill_tokens, 0);
+            self.scheduler
+                .record_step_latency(scheduled, step_start.elapsed(), true);
+
+            for (output, (seq_id, seq)) in zip(result, seqs) {
+                outputs.insert(seq_id, output);
+                seq.deref_mut().set_finish_reason("stop".to_string());
+            }
+            self.scheduler.free_finished_sequence_groups();
+        }
+
+        seq_ids
+            .iter()
+            .map(|seq_id| {
+                outputs
+                    .remove(seq_id)
+                    .ok_or_else(|| APIError::new(format!("Input {seq_id} was not encoded.")))
+            })
+            .collect()
+    }
+}
+
+impl<'a> LLMEngine<'a> {
+    /// Build the choices, usage and timings of a finished (or aborted) group.
+    fn get_group_response(
+        &mut self,
+        group: &SequenceGroup,
+        n: usize,
+        detokenize: DetokenizationMode,
+    ) -> Result<GroupResponse, APIError> {
+        // Create choices from the group
+        let group_seqs = group.get_seqs();
+        let mut seqs = group_seqs.values().collect::<Vec<_>>();
+        seqs.sort_by(|seq_a, seq_b| {
+            seq_b
+                .deref_mut()
+                .get_cumulative_logprob()
+                .total_cmp(&seq_a.deref_mut().get_cumulative_logprob())
+        });
+        // A group shed or aborted before its first step holds a single sequence.
+        let top_n = &seqs[..n.min(seqs.len())];
+
+        let mut choices = Vec::new();
+        for (index, seq) in top_n.iter().enumerate() {
+            let outputs = seq.deref_mut().get_output_tokens();
+            let data = outputs
+                .iter()
+                .map(|x| x.token.try_into().unwrap())
+                .collect::<Vec<_>>();
+            let (content, bytes, token_ids) = match detokenize {
+                DetokenizationMode::Text => (
+                    Some(self.pipeline.tokenizer().detokenize(&data)?),
+                    None,
+                    None,
+                ),
+                DetokenizationMode::Bytes => (
+                    None,
+                    Some(self.pipeline.tokenizer().detokenize_bytes(&data)?),
+                    None,
+                ),
+                DetokenizationMode::TokenIds => (None, None, Some(data)),
+            };
+            let choice = ChatChoice {
+                message: ChatChoiceData {
+                    role: self.pipeline.get_conversation().get_roles().0.clone(),
+                    content,
+                    bytes,
+                    token_ids,
+                },
+                finish_reason: Some(seq.deref_mut().get_finish_reason().clone()),
+                cancellation_reason: seq.deref_mut().get_cancellation_reason(),
+                index,
+                logprobs: Some(WrapperLogprobs { content: outputs }),
+            };
+            choices.push(choice);
+        }
+
+        // The encoder prompt of an encoder-decoder model counts towards the prompt.
+        let prompt_tokens =
+            top_n.first().unwrap().deref_mut().get_prompt_len() + group.get_num_encoder_tokens();
+        let usage = ChatCompletionUsageResponse {
+            completion_tokens: top_n
+                .iter()
+                .map(|seq| seq.deref_mut().get_len() - seq.deref_mut().get_prompt_len())
+                .sum(),
+            prompt_tokens,
+            total_tokens: top_n
+                .iter()
+                .map(|seq| seq.deref_mut().get_len() - seq.deref_mut().get_prompt_len())
+                .sum::<usize>()
+                + prompt_tokens,
+        };
+
+        for seq in group.get_seqs().values() {
+            self.metrics
+                .metrics()
+                .record_sequence(seq.deref_mut().get_timings());
+        }
+        let timings = request_timings(top_n.first().unwrap().deref_mut().get_timings());
+        Ok((choices, usage, timings))
+    }
+
+    /// Pass the output of `group` to `sink` if it takes it.
+    fn send_output(
+        &self,
+        sink: &mut OutputSink,
+        group: &SequenceGroup,
+        n: usize,
+    ) -> Result<(), APIError> {
+        match sink {
+            OutputSink::Streamed(on_output) => on_output(self.get_request_output(group, n)?),
+            OutputSink::Finished(on_output) if group.is_finished() => {
+                on_output(self.get_request_output(group, n)?)
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Build the output of a group of the library API from the `n` best sequences so far.
+    fn get_request_output(
+        &self,
+        group: &SequenceGroup,
+        n: usize,
+    ) -> Result<RequestOutput, APIError> {
+        let group_seqs = group.get_seqs();
+        let mut seqs = group_seqs.values().collect::<Vec<_>>();
+        seqs.sort_by(|seq_a, seq_b| {
+            seq_b
+                .deref_mut()
+                .get_cumulative_logprob()
+                .total_cmp(&seq_a.deref_mut().get_cumulative_logprob())
+        });
+        let mut prompt_token_ids = group.get_encoder_prompt().to_vec();
+        let mut outputs = Vec::new();
+        for (index, seq) in seqs.into_iter().take(n).enumerate() {
+            let mut seq = seq.deref_mut();
+            // Only the new tokens are decoded, so streaming a long output stays linear.
+            seq.detokenize_new_tokens(self.pipeline.tokenizer())?;
+            if prompt_token_ids.is_empty() {
+                prompt_token_ids = seq.get_prompt_token_ids();
+            }
+            outputs.push(CompletionOutput {
+                index,
+                text: seq.get_output_text().to_string(),
+                token_ids: seq.get_output_token_ids(),
+                cumulative_logprob: seq.get_cumulative_logprob(),
+                finish_reason: seq.is_finished().then(|| seq.get_finish_reason()),
+                cancellation_reason: seq.get_cancellation_reason(),
+            });
+        }
+        Ok(RequestOutput {
+            request_id: group.get_request_id().clone(),
+            prompt_token_ids,
+            outputs,
+            finished: group.is_finished(),
+        })
+    }
+
+    /// Execute the cache operations and the model for a scheduled step and sample the next
+    /// tokens. Only the cache contents are changed, so the step can be rolled back on failure.
+    /// The sequences in prompt phase must come before the generating ones in `seq_refs`.
+    fn execute_step(
+        &mut self,
+        scheduler_outputs: &SchedulerOutput,
+        sampling_params: &SamplingParams,
+        seq_refs: &[(&usize, &Arc<Sequence>)],
+    ) -> Result<Vec<TokenOrFinishReason>, APIError> {
+        self.execute_scheduler_ops(scheduler_outputs)?;
+
+        let prepare_range = profiling::range("prepare_inputs");
+        let num_prompt_seqs = seq_refs
+            .iter()
+            .take_while(|(_, seq)| seq.deref_mut().is_prompt())
+            .count();
+        let (prompt_seqs, decode_seqs) = seq_refs.split_at(num_prompt_seqs);
+        let PreparedInputs {
+            tokens,
+            positions,
+            metadata,
+        } = self.prepare_inputs(prompt_seqs, decode_seqs)?;
+        let cross = self.prepare_cross(&scheduler_outputs.scheduled, prompt_seqs, decode_seqs)?;
+        let state = self.prepare_state(prompt_seqs, decode_seqs, metadata.prompt.as_ref())?;
+        let images = self.prepare_images(&scheduler_outputs.scheduled, prompt_seqs)?;
+        let metadata = self.with_attention_settings(
+            metadata
+                .with_cross_attention(cross)
+                .with_state(state)
+                .with_images(images),
+            sampling_params,
+        );
+        drop(prepare_range);
+
+        // The draft model caches the tokens of every step, so that it can speculate from the
+        // current token in the following decode steps. On another device, it runs concurrently
+        // with the model.
+        let draft_inputs = if self.draft.is_some() {
+            let inputs = self.prepare_inputs(prompt_seqs, decode_seqs)?;
+            Some(PreparedInputs {
+                metadata: self.with_attention_settings(inputs.metadata, sampling_params),
+                ..inputs
+            })
+        } else {
+            None
+        };
+        let draft_inputs = match (self.draft.as_mut(), draft_inputs) {
+            (Some(draft), Some(inputs)) if !draft.is_on_other_device(self.pipeline.device()) => {
+                let _range = profiling::range("draft");
+                draft.cache(inputs.tokens, inputs.positions, inputs.metadata)?;
+                None
+            }
+            (_, inputs) => inputs,
+        };
+        // The EAGLE head runs on the positions of the step once the next tokens are sampled.
+        let eagle_inputs = if self.eagle.is_some() {
+            let inputs = self.prepare_inputs(prompt_seqs, decode_seqs)?;
+            Some(PreparedInputs {
+                metadata: self.with_attention_settings(inputs.metadata, sampling_params),
+                ..inputs
+            })
+        } else {
+            None
+        };
+
+        let forward_range = profiling::range(match (&metadata.prompt, &metadata.decode) {
+            (Some(_), Some(_)) => "prefill+decode",
+            (Some(_), None) => "prefill",
+            _ => "decode",
+        });
+        let step_start = Instant::now();
+        // The Medusa heads propose the candidates of the next step from the hidden state of the
+        // last token of each sequence, the rows of its logits.
+        let logits_indices = if self.medusa.is_some() {
+            Some(metadata.logits_indices(tokens.elem_count(), self.pipeline.device())?)
+        } else {
+            None
+        };
+        let (logits, hidden_states) = Self::forward_with_draft(
+            &mut *self.pipeline,
+            &self.cache_engine,
+            self.draft.as_mut().zip(draft_inputs),
+            PreparedInputs {
+                tokens,
+                positions,
+                metadata,
+            },
+            self.medusa.is_some() || self.eagle.is_some(),
         )?;
-        let sliding_window = pipeline.get_model_config().get_sliding_window();
-        Ok(Self {
-            pipeline,
-            scheduler: Scheduler::new(scheduler_config, &cache_config),
-            seq_id: 0,
-            cache_config,
-            group_id: 0,
-            cache_engine,
-            sliding_window,
+        if let (Some(medusa), Some(hidden_states), Some(logits_indices)) =
+            (&mut self.medusa, &hidden_states, logits_indices)
+        {
+            let seq_ids = seq_refs
+                .iter()
+                .map(|(_, seq)| seq.deref_mut().get_id())
+                .collect::<Vec<_>>();
+            medusa.propose(
+                &try_api!(hidden_states.index_select(&logits_indices, 0)),
+                &seq_ids,
+            )?;
+        }
+        self.scheduler
+            .record_prefill_throughput(scheduler_outputs.num_prefill_tokens, step_start.elapsed());
+        drop(forward_range);
+
+        let sample_range = profiling::range("sample");
+        let results = self.pipeline.sample(logits, sampling_params, seq_refs)?;
+        drop(sample_range);
+
+        if let (Some(inputs), Some(hidden_states)) = (eagle_inputs, hidden_states) {
+            let _range = profiling::range("draft");
+            self.execute_eagle_head(inputs, &hidden_states, seq_refs, &results)?;
+        }
+        Ok(results)
+    }
+
+    /// Run the model on the `inputs` of a step, also returning its hidden states if
+    /// `output_hidden_states` is set. The draft model on another device caches its inputs in a
+    /// thread of its own meanwhile.
+    fn forward_with_draft(
+        pipeline: &mut dyn ModulePipeline<'a>,
+        cache_engine: &CacheEngine,
+        draft: Option<(&mut DraftModel<'a>, PreparedInputs)>,
+        inputs: PreparedInputs,
+        output_hidden_states: bool,
+    ) -> Result<(Tensor, Option<Tensor>), APIError> {
+        let PreparedInputs {
+            tokens,
+            positions,
+            metadata,
+        } = inputs;
+        thread::scope(|scope| {
+            let draft = draft.map(|(draft, inputs)| {
+                scope.spawn(move || {
+                    let _range = profiling::range("draft");
+                    draft.cache(inputs.tokens, inputs.positions, inputs.metadata)
+                })
+            });
+            let kv_cache = cache_engine.get_kv_cache();
+            let output = if output_hidden_states {
+                pipeline
+                    .forward_with_hidden_states(tokens, positions, Some(&*kv_cache), metadata)
+                    .map(|(logits, hidden_states)| (logits, Some(hidden_states)))
+            } else {
+                pipeline
+                    .forward(tokens, positions, Some(&*kv_cache), metadata)
+                    .map(|logits| (logits, None))
+            };
+            if let Some(draft) = draft {
+                draft
+                    .join()
+                    .map_err(|_| APIError::new_str("The draft model panicked."))??;
+            }
+            output
         })
     }
 
-    pub fn get_pipeline(&self) -> &dyn ModulePipeline<'a> {
-        &*self.pipeline
+    /// Execute a step split into micro-batches of about the same number of tokens, which the
+    /// stages of a pipeline-parallel model work on at the same time. The draft models and heads
+    /// of speculative decoding need the whole step, see `execute_step`.
+    fn execute_micro_batched_step(
+        &mut self,
+        scheduler_outputs: &SchedulerOutput,
+        sampling_params: &SamplingParams,
+        seq_refs: &[(&usize, &Arc<Sequence>)],
+    ) -> Result<Vec<TokenOrFinishReason>, APIError> {
+        self.execute_scheduler_ops(scheduler_outputs)?;
+
+        let prepare_range = profiling::range("prepare_inputs");
+        let num_tokens = seq_refs
+            .iter()
+            .map(|(_, seq)| {
+                let seq = seq.deref_mut();
+                if seq.is_prompt() {
+                    seq.get_len()
+                        - self
+                            .scheduler
+                            .block_engine
+                            .get_num_cached_prompt_tokens(seq.get_id())
+                } else {
+                    1
+                }
+            })
+            .collect::<Vec<_>>();
+        let micro_batches = split_micro_batches(&num_tokens, self.num_micro_batches)
+            .into_iter()
+            .map(|range| {
+                let seqs = &seq_refs[range];
+                let num_prompt_seqs = seqs
+                    .iter()
+                    .take_while(|(_, seq)| seq.deref_mut().is_prompt())
+                    .count();
+                let (prompt_seqs, decode_seqs) = seqs.split_at(num_prompt_seqs);
+                let inputs = self.prepare_inputs(prompt_seqs, decode_seqs)?;
+                Ok(MicroBatch {
+                    tokens: inputs.tokens,
+                    positions: inputs.positions,
+                    metadata: self.with_attention_settings(inputs.metadata, sampling_params),
+                })
+            })
+            .collect::<Result<Vec<_>, APIError>>()?;
+        drop(prepare_range);
+
+        let forward_range = profiling::range("micro-batches");
+        let step_start = Instant::now();
+        let logits = self
+            .pipeline
+            .forward_micro_batches(micro_batches, Some(&*self.cache_engine.get_kv_cache()))?;
+        let logits = try_api!(Tensor::cat(&logits, 0));
+        self.scheduler
+            .record_prefill_throughput(scheduler_outputs.num_prefill_tokens, step_start.elapsed());
+        drop(forward_range);
+
+        let _range = profiling::range("sample");
+        self.pipeline.sample(logits, sampling_params, seq_refs)
     }
 
-    pub fn get_mut_pipeline(&mut self) -> &mut dyn ModulePipeline<'a> {
-        &mut *self.pipeline
+    /// Run the EAGLE head on the positions of a step with the features of the model and the
+    /// tokens after them, the last one being the sampled token of each sequence, and keep the
+    /// predicted feature of the last position of each sequence for the next step.
+    fn execute_eagle_head(
+        &mut self,
+        inputs: PreparedInputs,
+        hidden_states: &Tensor,
+        seq_refs: &[(&usize, &Arc<Sequence>)],
+        results: &[TokenOrFinishReason],
+    ) -> Result<(), APIError> {
+        let PreparedInputs {
+            tokens,
+            positions,
+            metadata,
+        } = inputs;
+        let num_tokens = tokens.elem_count();
+        let device = self.pipeline.device();
+        let logits_indices = metadata.logits_indices(num_tokens, device)?;
+        let prompt_lens = metadata
+            .prompt
+            .as_ref()
+            .map_or_else(Vec::new, |prompt| prompt.prompt_lens.clone());
+        let mut next_tokens = Vec::with_capacity(num_tokens);
+        for (i, ((_, seq), result)) in zip(seq_refs, results).enumerate() {
+            let seq = seq.deref_mut();
+            if let Some(prompt_len) = prompt_lens.get(i) {
+                let token_ids = seq.get_token_ids();
+                next_tokens.extend(
+                    token_ids[token_ids.len() + 1 - prompt_len..]
+                        .iter()
+                        .copied(),
+                );
+            }
+            // A finished sequence is not drafted for, any token fills its position.
+            next_tokens.push(match result {
+                Either::Left(logprobs) => logprobs.token,
+                Either::Right(_) => seq.get_last_token_id(),
+            });
+        }
+        let next_tokens = next_tokens.into_iter().map(|x| x as i64).collect();
+        let next_tokens = try_api!(Tensor::from_vec(next_tokens, (1, num_tokens), device));
+        let seq_ids = seq_refs
+            .iter()
+            .map(|(_, seq)| seq.deref_mut().get_id())
+            .collect::<Vec<_>>();
+        let eagle = self.eagle.as_mut().unwrap();
+        let features = eagle.forward(
+            &*self.pipeline,
+            &next_tokens,
+            hidden_states,
+            &positions,
+            metadata,
+        )?;
+        eagle.set_next(
+            &*self.pipeline,
+            &seq_ids,
+            &try_api!(features.index_select(&logits_indices, 0)),
+        )
     }
 
-    pub fn generate(
+    /// Execute a decode step with speculative decoding: the draft model proposes
+    /// `num_speculative_tokens` tokens for each sequence, one per forward pass, and the model
+    /// computes the logits of the last token and all draft tokens of each sequence in a single
+    /// pass. The draft tokens of the sequences which are not `speculative` are dropped. Only the
+    /// cache contents are changed, the tokens are accepted after the step is committed.
+    fn execute_speculative_step(
         &mut self,
-        prompt: Encoding,
-        request_id: String,
-        created: u64,
-        sampling_params: SamplingParams,
-    ) -> Result<Vec<(Vec<ChatChoice>, ChatCompletionUsageResponse)>, APIError> {
-        self.add_request(prompt, request_id, created);
+        scheduler_outputs: &SchedulerOutput,
+        sampling_params: &SamplingParams,
+        seq_refs: &[(&usize, &Arc<Sequence>)],
+        speculative: &[bool],
+    ) -> Result<StepOutput, APIError> {
+        self.execute_scheduler_ops(scheduler_outputs)?;
+        let num_speculative_tokens = self.draft.as_ref().unwrap().num_speculative_tokens();
 
-        let mut responses = HashMap::new();
-        while self.scheduler.has_unfinished_sequences() {
-            let scheduler_outputs = self.scheduler.schedule();
-            if !scheduler_outputs.ignored_seq_groups.is_empty() {
-                todo!();
+        // A draft model on another device caches its last draft token while the model verifies
+        // the draft tokens. Otherwise, the first pass also recomputes the token before the last
+        // one, which the draft model has not cached if all draft tokens of the previous step
+        // were accepted.
+        let overlap = self
+            .draft
+            .as_ref()
+            .unwrap()
+            .is_on_other_device(self.pipeline.device());
+        let draft_range = profiling::range("draft");
+        let mut queries = seq_refs
+            .iter()
+            .map(|(_, seq)| {
+                let token_ids = seq.deref_mut().get_token_ids();
+                let start = token_ids.len() - if overlap { 1 } else { 2 };
+                (start, token_ids[start..].to_vec())
+            })
+            .collect::<Vec<_>>();
+        let mut draft_tokens = vec![Vec::with_capacity(num_speculative_tokens); seq_refs.len()];
+        for _ in 0..num_speculative_tokens {
+            let PreparedInputs {
+                tokens,
+                positions,
+                metadata,
+            } = self.prepare_queries(seq_refs, &queries)?;
+            let metadata = self.with_attention_settings(metadata, sampling_params);
+            let next_tokens = self
+                .draft
+                .as_mut()
+                .unwrap()
+                .forward(tokens, positions, metadata)?;
+            // The logits of the last query token of a sequence give its next draft token.
+            let num_queries = queries[0].1.len();
+            for (i, (start, query)) in queries.iter_mut().enumerate() {
+                let token = next_tokens[(i + 1) * num_queries - 1];
+                draft_tokens[i].push(token);
+                *start += query.len();
+                *query = vec![token];
+            }
+        }
+        drop(draft_range);
+        // The draft model still runs on every sequence to keep its cache in step with the model.
+        for (draft_tokens, speculative) in zip(&mut draft_tokens, speculative) {
+            if !speculative {
+                draft_tokens.clear();
             }
+        }
 
-            try_api!(self.execute_scheduler_ops(&scheduler_outputs));
+        let draft_inputs = if overlap {
+            let inputs = self.prepare_queries(seq_refs, &queries)?;
+            Some(PreparedInputs {
+                metadata: self.with_attention_settings(inputs.metadata, sampling_params),
+                ..inputs
+            })
+        } else {
+            None
+        };
+        self.verify_draft_tokens(seq_refs, draft_tokens, draft_inputs, sampling_params, false)
+    }
 
-            let scheduled = &*scheduler_outputs.scheduled;
+    /// Execute a decode step verifying the `lookup_tokens` of each sequence proposed by prompt
+    /// lookup. Only the cache contents are changed, the tokens are accepted after the step is
+    /// committed.
+    fn execute_lookup_step(
+        &mut self,
+        scheduler_outputs: &SchedulerOutput,
+        sampling_params: &SamplingParams,
+        seq_refs: &[(&usize, &Arc<Sequence>)],
+        lookup_tokens: Vec<Vec<usize>>,
+    ) -> Result<StepOutput, APIError> {
+        self.execute_scheduler_ops(scheduler_outputs)?;
+        self.verify_draft_tokens(seq_refs, lookup_tokens, None, sampling_params, false)
+    }
 
-            let seqs = scheduled
-                .iter()
-                .flat_map(|group| group.get_seqs())
-                .collect::<Vec<_>>();
+    /// Compute the logits of the model for the last token and the `draft_tokens` of each
+    /// sequence in a single pass, and also its hidden states if `output_hidden_states` is set.
+    /// Sequences with fewer draft tokens, e.g. without any as they opted out of speculation, are
+    /// padded with their last token to the same number of query tokens. The draft model caches
+    /// the `draft_inputs`, if any, concurrently.
+    fn verify_draft_tokens(
+        &mut self,
+        seq_refs: &[(&usize, &Arc<Sequence>)],
+        draft_tokens: Vec<Vec<usize>>,
+        draft_inputs: Option<PreparedInputs>,
+        sampling_params: &SamplingParams,
+        output_hidden_states: bool,
+    ) -> Result<StepOutput, APIError> {
+        let _range = profiling::range("verify");
+        let num_queries = draft_tokens.iter().map(Vec::len).max().unwrap_or(0) + 1;
+        let queries = zip(seq_refs, &draft_tokens)
+            .map(|((_, seq), draft_tokens)| {
+                let start = seq.deref_mut().get_len() - 1;
+                let last_token_id = seq.deref_mut().get_last_token_id();
+                let mut query = [last_token_id]
+                    .into_iter()
+                    .chain(draft_tokens.iter().copied())
+                    .collect::<Vec<_>>();
+                query.resize(num_queries, *query.last().unwrap());
+                (start, query)
+            })
+            .collect::<Vec<_>>();
+        let inputs = self.prepare_queries(seq_refs, &queries)?;
+        let inputs = PreparedInputs {
+            metadata: self.with_attention_settings(inputs.metadata, sampling_params),
+            ..inputs
+        };
+        let (logits, hidden_states) = Self::forward_with_draft(
+            &mut *self.pipeline,
+            &self.cache_engine,
+            self.draft.as_mut().zip(draft_inputs),
+            inputs,
+            output_hidden_states,
+        )?;
+        Ok(StepOutput::Verified {
+            logits,
+            draft_tokens,
+            hidden_states,
+        })
+    }
+
+    /// Execute a decode step with EAGLE: the head drafts `num_speculative_tokens` tokens for each
+    /// sequence from the predicted features of the previous step, one per forward pass, and the
+    /// model verifies them like the tokens of a draft model, also returning its hidden states.
+    /// The draft tokens of the sequences which are not `speculative` are dropped. Only the cache
+    /// contents are changed, the tokens are accepted after the step is committed.
+    fn execute_eagle_step(
+        &mut self,
+        scheduler_outputs: &SchedulerOutput,
+        sampling_params: &SamplingParams,
+        seq_refs: &[(&usize, &Arc<Sequence>)],
+        speculative: &[bool],
+    ) -> Result<StepOutput, APIError> {
+        self.execute_scheduler_ops(scheduler_outputs)?;
+        let eagle = self.eagle.as_ref().unwrap();
+        let num_speculative_tokens = eagle.num_speculative_tokens();
 
+        let draft_range = profiling::range("draft");
+        let mut features = Vec::with_capacity(seq_refs.len());
+        let mut draft_tokens = Vec::with_capacity(seq_refs.len());
+        for (_, seq) in seq_refs {
+            let (feature, token) = eagle.next(seq.deref_mut().get_id()).unwrap();
+            features.push(feature.clone());
+            draft_tokens.push(vec![*token]);
+        }
+        let mut features = try_api!(Tensor::stack(&features, 0));
+        // Each pass feeds the predicted feature of a position with the draft token after it.
+        for i in 0..num_speculative_tokens - 1 {
+            let queries = zip(seq_refs, &draft_tokens)
+                .map(|((_, seq), draft_tokens)| {
+                    (seq.deref_mut().get_len() - 1 + i, vec![draft_tokens[i]])
+                })
+                .collect::<Vec<_>>();
             let PreparedInputs {
                 tokens,
                 positions,
                 metadata,
-            } = if scheduled
-                .front()
-                .unwrap()
-                .get_seqs()
-                .values()
-                .nth(0)
-                .unwrap()
-                .deref_mut()
-                .is_prompt()
-            {
-                self.prepare_prompt(scheduled)
-            } else {
-                // Because of the KV cache, we only need to take
-                // the last token.
-                self.prepare_decode(scheduled)
-            }?;
+            } = self.prepare_queries(seq_refs, &queries)?;
+            let metadata = self.with_attention_settings(metadata, sampling_params);
+            let eagle = self.eagle.as_mut().unwrap();
+            features = eagle.forward(&*self.pipeline, &tokens, &features, &positions, metadata)?;
+            let next_tokens = eagle.draft_tokens(&*self.pipeline, &features)?;
+            for (draft_tokens, token) in zip(&mut draft_tokens, next_tokens) {
+                draft_tokens.push(token);
+            }
+        }
+        drop(draft_range);
+        for (draft_tokens, speculative) in zip(&mut draft_tokens, speculative) {
+            if !speculative {
+                draft_tokens.clear();
+            }
+        }
 
-            let logits = self.pipeline.forward(
+        self.verify_draft_tokens(seq_refs, draft_tokens, None, sampling_params, true)
+    }
+
+    /// Run the EAGLE head on the positions of a verified step whose tokens were accepted, with the
+    /// features of the model in `hidden_states` (one row per query token of each sequence), and
+    /// keep the predicted feature of the new last position of each unfinished sequence. `starts`
+    /// are the positions of the last tokens of the sequences before the step.
+    fn catch_up_eagle(
+        &mut self,
+        seq_refs: &[(&usize, &Arc<Sequence>)],
+        starts: &[usize],
+        hidden_states: &Tensor,
+        sampling_params: &SamplingParams,
+    ) -> Result<(), APIError> {
+        let _range = profiling::range("draft");
+        let num_rows = try_api!(hidden_states.dim(0)) / seq_refs.len().max(1);
+        // The sequences are grouped by their number of new tokens, which is the number of query
+        // tokens of each of them.
+        let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+        for (seq_n, ((_, seq), start)) in zip(seq_refs, starts).enumerate() {
+            let seq = seq.deref_mut();
+            if !seq.is_finished() {
+                groups
+                    .entry(seq.get_len() - 1 - start)
+                    .or_default()
+                    .push(seq_n);
+            }
+        }
+        self.eagle.as_mut().unwrap().clear_next();
+        for (num_added, seq_ns) in groups {
+            let group_refs = seq_ns
+                .iter()
+                .map(|seq_n| seq_refs[*seq_n])
+                .collect::<Vec<_>>();
+            let queries = seq_ns
+                .iter()
+                .map(|seq_n| {
+                    let start = starts[*seq_n];
+                    let token_ids = seq_refs[*seq_n].1.deref_mut().get_token_ids();
+                    (start, token_ids[start + 1..=start + num_added].to_vec())
+                })
+                .collect::<Vec<_>>();
+            let rows = seq_ns
+                .iter()
+                .flat_map(|seq_n| (0..num_added).map(move |i| (seq_n * num_rows + i) as u32))
+                .collect::<Vec<_>>();
+            let num_tokens = rows.len();
+            let device = self.pipeline.device();
+            let rows = try_api!(Tensor::from_vec(rows, num_tokens, device));
+            let last_rows = (1..=seq_ns.len())
+                .map(|n| (n * num_added - 1) as u32)
+                .collect::<Vec<_>>();
+            let last_rows = try_api!(Tensor::from_vec(last_rows, seq_ns.len(), device));
+            let PreparedInputs {
                 tokens,
                 positions,
-                Some(&*self.cache_engine.get_kv_cache()),
                 metadata,
+            } = self.prepare_queries(&group_refs, &queries)?;
+            let metadata = self.with_attention_settings(metadata, sampling_params);
+            let features = try_api!(hidden_states.index_select(&rows, 0));
+            let seq_ids = group_refs
+                .iter()
+                .map(|(_, seq)| seq.deref_mut().get_id())
+                .collect::<Vec<_>>();
+            let eagle = self.eagle.as_mut().unwrap();
+            let features =
+                eagle.forward(&*self.pipeline, &tokens, &features, &positions, metadata)?;
+            eagle.extend_next(
+                &*self.pipeline,
+                &seq_ids,
+                &try_api!(features.index_select(&last_rows, 0)),
             )?;
-            let result = self.pipeline.sample(logits, &sampling_params, &seqs)?;
+        }
+        Ok(())
+    }
 
-            for (result, (_, seq)) in zip(result, seqs) {
-                match result {
-                    Either::Left(logprobs) => {
-                        seq.deref_mut().add_token(logprobs);
-                    }
-                    Either::Right(finish_reason) => {
-                        seq.deref_mut().set_finish_reason(finish_reason)
-                    }
+    /// Execute a decode step verifying the candidate trees of the Medusa heads: the model
+    /// computes the logits of the last token of each sequence and of every node of its tree in a
+    /// single pass, each node attending to its ancestors. The nodes are cached in tree order in
+    /// the slots after the last token and positioned by their depth. The sequences which are not
+    /// `speculative` only accept the token sampled at the root. Only the cache contents are
+    /// changed, the tokens are accepted after the step is committed.
+    fn execute_tree_step(
+        &mut self,
+        scheduler_outputs: &SchedulerOutput,
+        sampling_params: &SamplingParams,
+        seq_refs: &[(&usize, &Arc<Sequence>)],
+        speculative: Vec<bool>,
+    ) -> Result<StepOutput, APIError> {
+        self.execute_scheduler_ops(scheduler_outputs)?;
+
+        let _range = profiling::range("verify");
+        let medusa = self.medusa.as_ref().unwrap();
+        let tree = medusa.tree();
+        let mut queries = Vec::with_capacity(seq_refs.len());
+        let mut positions = Vec::with_capacity(seq_refs.len() * tree.len());
+        for (_, seq) in seq_refs {
+            let seq = seq.deref_mut();
+            let start = seq.get_len() - 1;
+            let candidates = medusa.candidates(seq.get_id()).unwrap();
+            queries.push((start, tree.tokens(seq.get_last_token_id(), candidates)));
+            positions.extend((0..tree.len()).map(|node| (start + tree.depth(node)) as i64));
+        }
+        let PreparedInputs {
+            tokens,
+            positions: _,
+            mut metadata,
+        } = self.prepare_queries(seq_refs, &queries)?;
+        let num_tokens = positions.len();
+        let device = self.pipeline.device();
+        let positions = try_api!(Tensor::from_vec(positions, (1, num_tokens), device));
+        if let Some(decode) = &mut metadata.decode {
+            let masks = tree.masks().to_vec();
+            decode.tree_mask = Some(try_api!(Tensor::from_vec(masks, tree.len(), device)));
+        }
+        let metadata = self.with_attention_settings(metadata, sampling_params);
+        let (logits, hidden_states) = self.pipeline.forward_with_hidden_states(
+            tokens,
+            positions,
+            Some(&*self.cache_engine.get_kv_cache()),
+            metadata,
+        )?;
+        Ok(StepOutput::TreeVerified {
+            logits,
+            hidden_states,
+            node_tokens: queries.into_iter().map(|(_, tokens)| tokens).collect(),
+            speculative,
+        })
+    }
+
+    /// Move the keys and values of the accepted candidates of each sequence, cached in tree order
+    /// after the position `starts` of its root, to the positions of their depths. `accepted` are
+    /// the nodes of each sequence from the root, see `Medusa::accept`.
+    fn compact_accepted_candidates(
+        &self,
+        seq_refs: &[(&usize, &Arc<Sequence>)],
+        starts: &[usize],
+        accepted: &[Vec<usize>],
+    ) -> Result<(), APIError> {
+        let block_size = self.cache_config.block_size;
+        let mut src_to_dst = Vec::new();
+        for (((_, seq), start), nodes) in zip(zip(seq_refs, starts), accepted) {
+            let seq_id = seq.deref_mut().get_id();
+            let Some(table) = self.scheduler.block_engine.block_tables.get(&seq_id) else {
+                continue;
+            };
+            let slot = |position: usize| {
+                table[position / block_size].deref_mut().block_id * block_size
+                    + position % block_size
+            };
+            for (depth, node) in nodes.iter().enumerate().skip(1) {
+                if *node != depth {
+                    src_to_dst.push((slot(start + node), slot(start + depth)));
                 }
             }
+        }
+        self.cache_engine.copy_slots(&src_to_dst)
+    }
 
-            self.scheduler.free_finished_sequence_groups();
+    /// Execute an encoder-only model for a scheduled step, whose sequences are all prompts, and
+    /// return one row of outputs per sequence of `seq_refs`: its embedding pooled with `pooling`,
+    /// or the scores of the classification head.
+    fn execute_encoder_step(
+        &mut self,
+        scheduler_outputs: &SchedulerOutput,
+        pooling: Option<PoolingMode>,
+        token_type_ids: &HashMap<usize, Vec<u32>>,
+        seq_refs: &[(&usize, &Arc<Sequence>)],
+    ) -> Result<Vec<Vec<f32>>, APIError> {
+        let prepare_range = profiling::range("prepare_inputs");
+        let mut step_tokens = StepTokens::default();
+        let prompt = self.prepare_prompt(seq_refs, &mut step_tokens)?;
+        let prompt_lens = prompt.prompt_lens.clone();
+        let token_type_ids = self.prepare_token_type_ids(token_type_ids, seq_refs)?;
+        let PreparedInputs {
+            tokens,
+            positions,
+            metadata,
+        } = step_tokens.into_inputs(
+            Some(prompt),
+            None,
+            self.cache_config.cache_dtype.kernel_name().to_string(),
+            None,
+            self.pipeline.device(),
+        )?;
+        let metadata = metadata.with_token_type_ids(token_type_ids);
+        drop(prepare_range);
 
-            for group in scheduler_outputs.scheduled.iter() {
-                if group.is_finished() && !responses.contains_key(group.get_id()) {
-                    // Create choices from the group
-                    let mut seqs = group.get_seqs().values().collect::<Vec<_>>();
-                    seqs.sort_by(|seq_a, seq_b| {
-                        seq_b
-                            .deref_mut()
-                            .get_cumulative_logprob()
-                            .partial_cmp(&seq_a.deref_mut().get_cumulative_logprob())
-                            .unwrap()
-                    });
-                    let top_n = seqs.get(0..sampling_params.n).unwrap();
-
-                    let mut choices = Vec::new();
-                    for (index, seq) in top_n.iter().enumerate() {
-                        let outputs = seq.deref_mut().get_output_tokens();
-                        let data = outputs
-                            .iter()
-                            .map(|x| x.token.try_into().unwrap())
-                            .collect::<Vec<_>>();
-                        let data = self.pipeline.tokenizer().detokenize(&data)?;
-                        let choice = ChatChoice {
-                            message: ChatChoiceData {
-                                role: self.pipeline.get_conversation().get_roles().0.clone(),
-                                content: Some(data),
-                            },
-                            finish_reason: Some(seq.deref_mut().get_finish_reason().clone()),
-                            index,
-                            logprobs: Some(WrapperLogprobs { content: outputs }),
-                        };
-                        choices.push(choice);
-                    }
+        let forward_range = profiling::range("encode");
+        let step_start = Instant::now();
+        let outputs = self.pipeline.forward(tokens, positions, None, metadata)?;
+        self.scheduler
+            .record_prefill_throughput(scheduler_outputs.num_prefill_tokens, step_start.elapsed());
+        drop(forward_range);
 
-                    let usage = ChatCompletionUsageResponse {
-                        completion_tokens: top_n
-                            .iter()
-                            .map(|seq| seq.deref_mut().get_len() - seq.deref_mut().get_prompt_len())
-                            .sum(),
-                        prompt_tokens: top_n.first().unwrap().deref_mut().get_prompt_len(),
-                        total_tokens: top_n
-                            .iter()
-                            .map(|seq| seq.deref_mut().get_len() - seq.deref_mut().get_prompt_len())
-                            .sum::<usize>()
-                            + top_n.first().unwrap().deref_mut().get_prompt_len(),
-                    };
+        let outputs = match pooling {
+            Some(pooling) => {
+                let _range = profiling::range("pool");
+                pooling.pool(&outputs, &prompt_lens)?
+            }
+            None => outputs,
+        };
+        Ok(try_api!(outputs.to_vec2()))
+    }
 
-                    responses.insert(*group.get_id(), (choices, usage));
-                }
+    /// The token types of the prompt slice, `None` if no sequence of the step has any. The
+    /// prompts are encoded in full, as encoder-only models do not use the prefix cache.
+    fn prepare_token_type_ids(
+        &self,
+        token_type_ids: &HashMap<usize, Vec<u32>>,
+        seq_refs: &[(&usize, &Arc<Sequence>)],
+    ) -> Result<Option<Tensor>, APIError> {
+        if token_type_ids.is_empty() {
+            return Ok(None);
+        }
+        let mut ids = Vec::new();
+        for (seq_id, seq) in seq_refs {
+            match token_type_ids.get(*seq_id) {
+                Some(types) => ids.extend(types),
+                None => ids.extend(std::iter::repeat(0).take(seq.deref_mut().get_len())),
             }
         }
-
-        Ok(responses.into_values().collect::<Vec<_>>())
+        let num_tokens = ids.len();
+        Ok(Some(try_api!(Tensor::from_vec(
+            ids,
+            num_tokens,
+            self.pipeline.device()
+        ))))
     }
-}
 
-impl<'a> LLMEngine<'a> {
     fn execute_scheduler_ops(
         &mut self,
         scheduler_output: &SchedulerOutput,
     ) -> Result<(), APIError> {
+        // The draft caches are addressed by the same block tables.
+        if let Some(draft) = &mut self.draft {
+            draft.execute_scheduler_ops(scheduler_output)?;
+        }
+        if let Some(eagle) = &mut self.eagle {
+            eagle.execute_scheduler_ops(scheduler_output)?;
+        }
         try_api!(self
             .cache_engine
             .swap_in(scheduler_output.blocks_to_swap_in.clone()));
@@ -216,177 +2179,278 @@ impl<'a> LLMEngine<'a> {
         try_api!(self
             .cache_engine
             .copy(scheduler_output.blocks_to_copy.clone()));
+        try_api!(self
+            .cache_engine
+            .copy_states(&scheduler_output.states_to_copy));
         Ok(())
     }
 
-    fn prepare_prompt(
+    /// The tokens and the attention metadata of the prompt and decode slices of a step.
+    fn prepare_inputs(
         &self,
-        groups: &VecDeque<Arc<SequenceGroup>>,
+        prompt_seqs: &[(&usize, &Arc<Sequence>)],
+        decode_seqs: &[(&usize, &Arc<Sequence>)],
     ) -> Result<PreparedInputs, APIError> {
-        let mut prompt_lens = Vec::new();
-        let mut input_tokens = Vec::new();
-        let mut input_positions = Vec::new();
-        let mut slot_mappings = Vec::new();
-        for group in groups {
-            for seq in group.get_seqs().values() {
-                let prompt_ids = seq.deref_mut().get_token_ids();
-
-                let prompt_len = prompt_ids.len();
-                prompt_lens.push(prompt_len);
-
-                input_tokens.push(prompt_ids);
-                input_positions.push((0..prompt_len).collect::<Vec<_>>());
-                let table = self
-                    .scheduler
+        let mut step_tokens = StepTokens::default();
+        let prompt = if prompt_seqs.is_empty() {
+            None
+        } else {
+            Some(self.prepare_prompt(prompt_seqs, &mut step_tokens)?)
+        };
+        // Because of the KV cache, we only need to take the last token of the generating
+        // sequences.
+        let decode = if decode_seqs.is_empty() {
+            None
+        } else {
+            Some(self.prepare_decode(decode_seqs, &mut step_tokens)?)
+        };
+        step_tokens.into_inputs(
+            prompt,
+            decode,
+            self.cache_config.cache_dtype.kernel_name().to_string(),
+            self.sliding_window,
+            self.pipeline.device(),
+        )
+    }
+
+    /// The inputs of a decode step with the query tokens of each sequence in `queries`, given as
+    /// the position of the first query token and the tokens, e.g. its last token followed by
+    /// draft tokens. Every sequence has the same number of query tokens.
+    fn prepare_queries(
+        &self,
+        seqs: &[(&usize, &Arc<Sequence>)],
+        queries: &[(usize, Vec<usize>)],
+    ) -> Result<PreparedInputs, APIError> {
+        let mut step_tokens = StepTokens::default();
+        let decode = self.prepare_decode_queries(seqs, queries, &mut step_tokens)?;
+        step_tokens.into_inputs(
+            None,
+            Some(decode),
+            self.cache_config.cache_dtype.kernel_name().to_string(),
+            self.sliding_window,
+            self.pipeline.device(),
+        )
+    }
+
+    /// Apply the attention settings of the engine and the attention scale of `sampling_params`.
+    fn with_attention_settings(
+        &self,
+        metadata: InputMetadata,
+        sampling_params: &SamplingParams,
+    ) -> InputMetadata {
+        metadata
+            .with_v2_min_context_len(self.v2_min_context_len)
+            .with_attention_backend(self.attention_backend)
+            .with_attention_compute(self.attention_compute)
+            .with_attention_scale(
+                sampling_params.attention_scale,
+                sampling_params.attention_temperature,
+            )
+    }
+
+    /// Append the prompt slice of a step, the prompts packed back to back. The leading tokens of
+    /// a prompt which are already in the prefix cache are left out.
+    fn prepare_prompt(
+        &self,
+        seqs: &[(&usize, &Arc<Sequence>)],
+        step_tokens: &mut StepTokens,
+    ) -> Result<PromptMetadata, APIError> {
+        let context_lens = seqs
+            .iter()
+            .map(|(_, seq)| {
+                self.scheduler
                     .block_engine
-                    .block_tables
-                    .get(&seq.deref_mut().get_id());
-                if table.is_none() {
-                    // Will be None during profiling.
-                    slot_mappings.push([_PAD_SLOT_ID].repeat(prompt_len));
-                    continue;
-                }
-                let table = table
-                    .unwrap()
-                    .iter()
-                    .map(|block| block.deref_mut().block_id)
-                    .collect::<Vec<_>>();
+                    .get_num_cached_prompt_tokens(seq.deref_mut().get_id())
+            })
+            .collect::<Vec<_>>();
+        let prompt_lens = zip(seqs, &context_lens)
+            .map(|((_, seq), context_len)| seq.deref_mut().get_len() - context_len)
+            .collect::<Vec<_>>();
+        let max_prompt_len = *prompt_lens.iter().max().unwrap();
+        let mut tables = Vec::with_capacity(seqs.len());
+        for ((_, seq), context_len) in zip(seqs, &context_lens) {
+            let prompt_ids = seq.deref_mut().get_token_ids();
+            let prompt_len = prompt_ids.len();
 
-                let start_idx = if let Some(sliding_window) = self.sliding_window {
-                    0.min(prompt_len - sliding_window)
-                } else {
-                    0
-                };
+            step_tokens
+                .tokens
+                .extend(prompt_ids[*context_len..].iter().map(|x| *x as i64));
+            step_tokens
+                .positions
+                .extend(*context_len as i64..prompt_len as i64);
 
-                let mut slot_mapping = Vec::new();
-                for i in 0..prompt_len {
-                    if i < start_idx {
-                        // Pad [0,start_idx) with _PAD_TOKEN_ID
-                        slot_mapping.push(_PAD_SLOT_ID);
-                    }
+            let table = self
+                .scheduler
+                .block_engine
+                .block_tables
+                .get(&seq.deref_mut().get_id());
+            let Some(table) = table else {
+                // Will be None during profiling.
+                step_tokens
+                    .slot_mapping
+                    .extend([_PAD_SLOT_ID].repeat(prompt_len));
+                tables.push(Vec::new());
+                continue;
+            };
+            let table = table
+                .iter()
+                .map(|block| block.deref_mut().block_id)
+                .collect::<Vec<_>>();
 
-                    let block_number = table.get(i / self.cache_config.block_size).unwrap();
-                    let block_offset = i % self.cache_config.block_size;
-                    let slot = block_number * self.cache_config.block_size + block_offset;
-                    slot_mapping.push(slot.try_into().unwrap());
-                }
-                slot_mappings.push(slot_mapping);
+            // All new tokens are cached, the attention masks tokens outside of a sliding window.
+            for i in *context_len..prompt_len {
+                let block_number = table.get(i / self.cache_config.block_size).unwrap();
+                let block_offset = i % self.cache_config.block_size;
+                let slot = block_number * self.cache_config.block_size + block_offset;
+                step_tokens.slot_mapping.push(slot.try_into().unwrap());
             }
+            tables.push(table);
         }
 
-        let max_prompt_len = prompt_lens.iter().max().unwrap();
-        let input_tokens = _make_tensor_with_pad(
-            input_tokens
-                .iter()
-                .map(|x| x.iter().map(|x| *x as i64).collect::<Vec<_>>())
-                .collect::<Vec<_>>(),
-            *max_prompt_len,
-            0,
-        )?;
-        let input_positions = _make_tensor_with_pad(
-            input_positions
-                .iter()
-                .map(|x| x.iter().map(|x| *x as i64).collect::<Vec<_>>())
-                .collect::<Vec<_>>(),
-            *max_prompt_len,
-            0,
-        )?;
-        let slot_mapping = _make_tensor_with_pad(slot_mappings, *max_prompt_len, _PAD_SLOT_ID)?;
+        let cached_context = if context_lens.iter().any(|context_len| *context_len > 0) {
+            Some(self.prepare_cached_context(&tables, &prompt_lens, &context_lens)?)
+        } else {
+            None
+        };
+        let cu_seqlens = [0]
+            .into_iter()
+            .chain(prompt_lens.iter().scan(0, |offset, prompt_len| {
+                *offset += *prompt_len as u32;
+                Some(*offset)
+            }))
+            .collect::<Vec<_>>();
+        let num_offsets = cu_seqlens.len();
+        Ok(PromptMetadata {
+            prompt_lens,
+            context_lens,
+            cached_context,
+            max_prompt_len,
+            cu_seqlens: try_api!(Tensor::from_vec(
+                cu_seqlens,
+                num_offsets,
+                self.pipeline.device()
+            )),
+            attn_bias: None,
+        })
+    }
 
-        Ok(PreparedInputs {
-            tokens: input_tokens,
-            positions: input_positions,
-            metadata: InputMetadata {
-                prompt_lens,
-                slot_mapping,
-                max_context_len: None,
-                context_lens: None,
-                block_tables: None,
-                attn_bias: None,
-                is_prompt: true,
-                kv_cache_dtype: "auto".to_string(), // TODO(EricLBuehler): specialize for models
-            },
+    /// The attention context of each new prompt token, see `PromptMetadata::cached_context`.
+    fn prepare_cached_context(
+        &self,
+        tables: &[Vec<usize>],
+        prompt_lens: &[usize],
+        context_lens: &[usize],
+    ) -> Result<DecodeMetadata, APIError> {
+        let mut token_tables = Vec::new();
+        let mut token_context_lens = Vec::new();
+        for ((table, prompt_len), context_len) in zip(zip(tables, prompt_lens), context_lens) {
+            for position in *context_len..context_len + prompt_len {
+                token_tables.push(table.iter().map(|x| *x as i64).collect::<Vec<_>>());
+                token_context_lens.push(position as i64 + 1);
+            }
+        }
+        let max_context_len = *token_context_lens.iter().max().unwrap() as usize;
+        let max_block_table_len = tables.iter().map(|x| x.len()).max().unwrap();
+        let num_tokens = token_context_lens.len();
+        Ok(DecodeMetadata {
+            max_context_len,
+            block_tables: _make_tensor_with_pad(
+                token_tables,
+                max_block_table_len,
+                0,
+                self.pipeline.device(),
+            )?,
+            context_lens: try_api!(Tensor::from_vec(
+                token_context_lens,
+                (num_tokens,),
+                self.pipeline.device(),
+            )),
+            block_position_shifts: None,
+            tree_mask: None,
         })
     }
 
+    /// Append the decode slice of a step, the last token of each sequence.
     fn prepare_decode(
         &self,
-        groups: &VecDeque<Arc<SequenceGroup>>,
-    ) -> Result<PreparedInputs, APIError> {
-        let mut input_tokens = Vec::new();
-        let mut input_positions = Vec::new();
+        seqs: &[(&usize, &Arc<Sequence>)],
+        step_tokens: &mut StepTokens,
+    ) -> Result<DecodeMetadata, APIError> {
+        let queries = seqs
+            .iter()
+            .map(|(_, seq)| {
+                let start = seq.deref_mut().get_len() - 1;
+                (start, vec![seq.deref_mut().get_last_token_id()])
+            })
+            .collect::<Vec<_>>();
+        self.prepare_decode_queries(seqs, &queries, step_tokens)
+    }
+
+    /// Append a decode slice with the query tokens of each sequence, see `prepare_queries`.
+    fn prepare_decode_queries(
+        &self,
+        seqs: &[(&usize, &Arc<Sequence>)],
+        queries: &[(usize, Vec<usize>)],
+        step_tokens: &mut StepTokens,
+    ) -> Result<DecodeMetadata, APIError> {
         let mut context_lens = Vec::new();
-        let mut slot_mappings = Vec::new();
         let mut block_tables = Vec::new();
-        for group in groups {
-            for seq in group.get_seqs().values() {
-                let last_token_id = seq.deref_mut().get_last_token_id();
-                input_tokens.push(vec![last_token_id]);
+        let mut block_position_shifts = Vec::new();
+        for ((_, seq), (start, query)) in zip(seqs, queries) {
+            let seq_id = seq.deref_mut().get_id();
+            step_tokens
+                .tokens
+                .extend(query.iter().map(|token| *token as i64));
 
-                let position = seq.deref_mut().get_len() - 1;
-                input_positions.push(vec![position]);
+            // With attention sinks, tokens are positioned within the block table, which no
+            // longer holds the evicted tokens.
+            let num_evicted_tokens = self.scheduler.block_engine.get_num_evicted_tokens(seq_id);
+            let start = start - num_evicted_tokens;
+            let positions = start..start + query.len();
+            step_tokens
+                .positions
+                .extend(positions.clone().map(|position| position as i64));
 
-                let context_len = if let Some(sliding_window) = self.sliding_window {
-                    seq.deref_mut().get_len().min(sliding_window)
-                } else {
-                    seq.deref_mut().get_len()
-                };
-                context_lens.push(context_len);
+            context_lens.push(positions.end);
 
-                let table = self
-                    .scheduler
-                    .block_engine
-                    .block_tables
-                    .get(&seq.deref_mut().get_id())
-                    .unwrap();
-                let table = table
-                    .iter()
-                    .map(|block| block.deref_mut().block_id)
-                    .collect::<Vec<_>>();
+            if let Some(shifts) = self
+                .scheduler
+                .block_engine
+                .get_block_position_shifts(seq_id)
+            {
+                block_position_shifts.push(shifts);
+            }
+
+            let Some(table) = self.scheduler.block_engine.block_tables.get(&seq_id) else {
+                // Pure state-space models hold no blocks.
+                step_tokens
+                    .slot_mapping
+                    .extend([_PAD_SLOT_ID].repeat(query.len()));
+                block_tables.push(Vec::new());
+                continue;
+            };
+            let table = table
+                .iter()
+                .map(|block| block.deref_mut().block_id)
+                .collect::<Vec<_>>();
 
+            for position in positions {
                 let block_number = table.get(position / self.cache_config.block_size).unwrap();
                 let block_offset = position % self.cache_config.block_size;
                 let slot = block_number * self.cache_config.block_size + block_offset;
-                let slot = slot.try_into().unwrap();
-                slot_mappings.push(vec![slot]);
-
-                if let Some(sliding_window) = self.sliding_window {
-                    let sliding_window_blocks = sliding_window / self.cache_config.block_size;
-                    block_tables.push(
-                        table
-                            .get(table.len() - sliding_window_blocks..)
-                            .unwrap()
-                            .to_vec(),
-                    );
-                } else {
-                    block_tables.push(table);
-                }
+                step_tokens.slot_mapping.push(slot.try_into().unwrap());
             }
-        }
 
-        let input_tokens = _make_tensor_with_pad(
-            input_tokens
-                .iter()
-                .map(|x| x.iter().map(|x| *x as i64).collect::<Vec<_>>())
-                .collect::<Vec<_>>(),
-            1,
-            0,
-        )?;
-        let input_positions = _make_tensor_with_pad(
-            input_positions
-                .iter()
-                .map(|x| x.iter().map(|x| *x as i64).collect::<Vec<_>>())
-                .collect::<Vec<_>>(),
-            1,
-            0,
-        )?;
-        let slot_mapping = _make_tensor_with_pad(slot_mappings, 1, _PAD_SLOT_ID)?;
+            // The full block table is kept so that the positions of the tokens line up with
+            // the context length, the kernel skips the blocks before a sliding window.
+            block_tables.push(table);
+        }
 
-        let max_context_len = context_lens.iter().max().unwrap();
+        let max_context_len = *context_lens.iter().max().unwrap();
         let context_lens = try_api!(Tensor::from_vec(
             context_lens.iter().map(|x| *x as i64).collect::<Vec<_>>(),
             (context_lens.len(),),
-            &try_api!(Device::new_cuda(0)),
+            self.pipeline.device(),
         ));
 
         let max_block_table_len = block_tables.iter().map(|x| x.len()).max().unwrap();
@@ -397,33 +2461,223 @@ impl<'a> LLMEngine<'a> {
                 .collect::<Vec<_>>(),
             max_block_table_len,
             0,
+            self.pipeline.device(),
         )?;
+        let block_position_shifts = if block_position_shifts.is_empty() {
+            None
+        } else {
+            Some(_make_tensor_with_pad(
+                block_position_shifts
+                    .iter()
+                    .map(|x| x.iter().map(|x| *x as i64).collect::<Vec<_>>())
+                    .collect::<Vec<_>>(),
+                max_block_table_len,
+                0,
+                self.pipeline.device(),
+            )?)
+        };
 
-        Ok(PreparedInputs {
-            tokens: input_tokens,
-            positions: input_positions,
-            metadata: InputMetadata {
-                prompt_lens: vec![],
-                slot_mapping,
-                max_context_len: Some(*max_context_len),
-                context_lens: Some(context_lens),
-                block_tables: Some(block_tables),
-                attn_bias: None,
-                is_prompt: false,
-                kv_cache_dtype: "auto".to_string(), // TODO(EricLBuehler): specialize for models
-            },
+        Ok(DecodeMetadata {
+            max_context_len,
+            block_tables,
+            context_lens,
+            block_position_shifts,
+            tree_mask: None,
         })
     }
 
-    fn add_request(&mut self, prompt: Encoding, request_id: String, created: u64) {
-        let seq = Arc::new(Sequence(Mutex::new(_Sequence::new(
-            prompt
-                .get_ids()
-                .to_vec()
+    /// The encoder outputs of the sequences of an encoder-decoder model, `None` if no sequence
+    /// has cross-attention blocks. The encoder outputs of the prompts are written with the step,
+    /// their encoder prompts are taken from their groups in `scheduled`.
+    fn prepare_cross(
+        &self,
+        scheduled: &VecDeque<Arc<SequenceGroup>>,
+        prompt_seqs: &[(&usize, &Arc<Sequence>)],
+        decode_seqs: &[(&usize, &Arc<Sequence>)],
+    ) -> Result<Option<CrossAttentionMetadata>, APIError> {
+        let cross_tables = prompt_seqs
+            .iter()
+            .chain(decode_seqs)
+            .map(|(_, seq)| {
+                self.scheduler
+                    .block_engine
+                    .cross_block_tables
+                    .get(&seq.deref_mut().get_id())
+            })
+            .collect::<Vec<_>>();
+        if cross_tables.iter().all(Option::is_none) {
+            return Ok(None);
+        }
+        let encoder_lens = cross_tables
+            .iter()
+            .map(|table| table.map_or(0, |table| table.num_encoder_tokens))
+            .collect::<Vec<_>>();
+        let max_encoder_len = *encoder_lens.iter().max().unwrap();
+        let block_tables = cross_tables
+            .iter()
+            .map(|table| {
+                table.map_or(Vec::new(), |table| {
+                    table
+                        .blocks
+                        .iter()
+                        .map(|block| block.deref_mut().block_id)
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let (slot_mapping, input_ids) = if prompt_seqs.is_empty() {
+            (None, None)
+        } else {
+            let mut slot_mapping = Vec::new();
+            let mut input_ids = Vec::new();
+            for ((_, seq), (table, encoder_len)) in
+                zip(prompt_seqs, zip(&block_tables, &encoder_lens))
+            {
+                for i in 0..*encoder_len {
+                    let block_number = table[i / self.cache_config.block_size];
+                    let block_offset = i % self.cache_config.block_size;
+                    let slot = block_number * self.cache_config.block_size + block_offset;
+                    slot_mapping.push(slot as i64);
+                }
+                slot_mapping.extend([_PAD_SLOT_ID].repeat(max_encoder_len - encoder_len));
+
+                let seq_id = seq.deref_mut().get_id();
+                let group = scheduled
+                    .iter()
+                    .find(|group| group.get_seqs().contains_key(&seq_id));
+                let encoder_prompt = group.map_or(&[][..], |group| group.get_encoder_prompt());
+                input_ids.extend(encoder_prompt.iter().map(|x| *x as i64));
+                input_ids.extend([0].repeat(max_encoder_len - encoder_prompt.len()));
+            }
+            let num_slots = slot_mapping.len();
+            (
+                Some(try_api!(Tensor::from_vec(
+                    slot_mapping,
+                    num_slots,
+                    self.pipeline.device(),
+                ))),
+                Some(try_api!(Tensor::from_vec(
+                    input_ids,
+                    num_slots,
+                    self.pipeline.device(),
+                ))),
+            )
+        };
+
+        let max_block_table_len = block_tables.iter().map(|x| x.len()).max().unwrap();
+        let block_tables = _make_tensor_with_pad(
+            block_tables
                 .iter()
-                .map(|x| *x as usize)
+                .map(|x| x.iter().map(|x| *x as i64).collect::<Vec<_>>())
                 .collect::<Vec<_>>(),
-            self.seq_id,
+            max_block_table_len,
+            0,
+            self.pipeline.device(),
+        )?;
+        let context_lens = try_api!(Tensor::from_vec(
+            encoder_lens.iter().map(|x| *x as i64).collect::<Vec<_>>(),
+            (encoder_lens.len(),),
+            self.pipeline.device(),
+        ));
+
+        Ok(Some(CrossAttentionMetadata {
+            encoder_lens,
+            max_encoder_len,
+            block_tables,
+            context_lens,
+            slot_mapping,
+            input_ids,
+        }))
+    }
+
+    /// The state slots of the sequences of a state-space model, `None` for other models. Each
+    /// prompt is scanned over its prompt tokens, each generating sequence over its last token.
+    fn prepare_state(
+        &self,
+        prompt_seqs: &[(&usize, &Arc<Sequence>)],
+        decode_seqs: &[(&usize, &Arc<Sequence>)],
+        prompt: Option<&PromptMetadata>,
+    ) -> Result<Option<StateMetadata>, APIError> {
+        let Some(state_cache) = &self.scheduler.state_cache else {
+            return Ok(None);
+        };
+        let slots = prompt_seqs
+            .iter()
+            .chain(decode_seqs)
+            .map(|(_, seq)| {
+                state_cache
+                    .get_slot(seq.deref_mut().get_id())
+                    .map_or(_PAD_SLOT_ID, |slot| slot as i64)
+            })
+            .collect::<Vec<_>>();
+        let seq_lens = prompt
+            .map_or(&[][..], |prompt| &prompt.prompt_lens[..])
+            .iter()
+            .copied()
+            .chain(std::iter::repeat(1).take(decode_seqs.len()));
+        let cu_seqlens = [0]
+            .into_iter()
+            .chain(seq_lens.scan(0, |offset, seq_len| {
+                *offset += seq_len as u32;
+                Some(*offset)
+            }))
+            .collect::<Vec<_>>();
+        let num_seqs = slots.len();
+        Ok(Some(StateMetadata {
+            cu_seqlens: try_api!(Tensor::from_vec(
+                cu_seqlens,
+                num_seqs + 1,
+                self.pipeline.device()
+            )),
+            slots: try_api!(Tensor::from_vec(slots, num_seqs, self.pipeline.device())),
+            num_prompts: prompt_seqs.len(),
+        }))
+    }
+
+    /// The images of the prompts of a vision-language model, `None` if they have none. Prompts
+    /// with images are prefilled in full, as their groups do not use the prefix cache.
+    fn prepare_images(
+        &self,
+        scheduled: &VecDeque<Arc<SequenceGroup>>,
+        prompt_seqs: &[(&usize, &Arc<Sequence>)],
+    ) -> Result<Option<ImageMetadata>, APIError> {
+        let mut images = Vec::new();
+        for (_, seq) in prompt_seqs {
+            let seq_id = seq.deref_mut().get_id();
+            if let Some(group) = scheduled
+                .iter()
+                .find(|group| group.get_seqs().contains_key(&seq_id))
+            {
+                images.extend(group.get_images().iter().cloned());
+            }
+        }
+        if images.is_empty() {
+            return Ok(None);
+        }
+        let pixel_values = try_api!(Tensor::stack(&images, 0));
+        Ok(Some(ImageMetadata {
+            pixel_values: try_api!(pixel_values.to_device(self.pipeline.device())),
+        }))
+    }
+
+    /// Queue a sequence group for `prompt`, returning the id of its sequence.
+    fn add_request(
+        &mut self,
+        prompt: Vec<usize>,
+        request_id: String,
+        created: u64,
+        options: RequestOptions,
+    ) -> usize {
+        // The prompt of an encoder-decoder model is encoded, its decoder starts from scratch.
+        let (decoder_prompt, encoder_prompt) = match self.decoder_start_token_id {
+            Some(decoder_start_token_id) => (vec![decoder_start_token_id], prompt),
+            None => (prompt, Vec::new()),
+        };
+        let seq_id = self.seq_id;
+        let seq = Arc::new(Sequence(Mutex::new(_Sequence::new(
+            decoder_prompt,
+            seq_id,
             self.cache_config.block_size,
         ))));
         self.seq_id += 1;
@@ -433,9 +2687,17 @@ impl<'a> LLMEngine<'a> {
             self.group_id,
             request_id,
             created,
-        );
+            options.cache_namespace,
+            options.session_id,
+        )
+        .with_ttft_deadline(options.ttft_slo.map(|slo| Instant::now() + slo))
+        .with_prefix_caching(!options.disable_prefix_cache)
+        .with_speculation(!options.disable_speculation)
+        .with_encoder_prompt(encoder_prompt)
+        .with_images(options.images);
         self.group_id += 1;
 
         self.scheduler.add_sequence(seq_group);
+        seq_id
     }
 }
@@ -0,0 +1,503 @@
+//! Medusa decoding heads: extra heads on the last hidden state of the model predict the tokens
+//! after the next one, head `i` the token `i + 2` positions ahead. The top candidates of the
+//! heads form a tree of continuations of the last token, which the model verifies in a single
+//! forward pass with tree attention: every node attends to the cached context and its ancestors,
+//! see `DecodeMetadata::tree_mask`.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use candle_core::{safetensors::MmapedSafetensors, DType, Device, IndexOp, Module, Tensor, D};
+use candle_nn::Linear;
+use either::Either;
+use serde::Deserialize;
+
+use crate::{
+    backend::MAX_TREE_QUERY_TOKENS,
+    openai::{responses::APIError, sampling_params::SamplingParams},
+    scheduler::sequence::Sequence,
+    try_api,
+};
+
+use super::{get_token, hub::HubRepo, thinking::force_token, ModulePipeline};
+
+/// `config.json` of a Medusa checkpoint.
+#[derive(Debug, Clone, Deserialize)]
+struct MedusaConfig {
+    medusa_num_heads: usize,
+    /// Number of residual blocks before the output layer of each head.
+    #[serde(default = "default_num_layers")]
+    medusa_num_layers: usize,
+}
+
+fn default_num_layers() -> usize {
+    1
+}
+
+/// A residual block of a head: `x + silu(linear(x))`.
+struct ResBlock {
+    linear: Linear,
+}
+
+impl Module for ResBlock {
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        x + candle_nn::ops::silu(&self.linear.forward(x)?)?
+    }
+}
+
+struct MedusaHead {
+    blocks: Vec<ResBlock>,
+    lm_head: Linear,
+}
+
+impl Module for MedusaHead {
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let mut x = x.clone();
+        for block in &self.blocks {
+            x = block.forward(&x)?;
+        }
+        self.lm_head.forward(&x)
+    }
+}
+
+/// The decoding heads of a Medusa checkpoint.
+pub struct MedusaHeads {
+    heads: Vec<MedusaHead>,
+}
+
+impl MedusaHeads {
+    /// Load the heads in the local directory `path`, or else the Hub repository `path`, in
+    /// `dtype` on `device`. The heads are read from `medusa_lm_head.safetensors`.
+    pub fn load(
+        path: &str,
+        hf_token: Option<String>,
+        hf_token_path: Option<String>,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self, APIError> {
+        let (config_filename, weights_filename) = if Path::new(path).is_dir() {
+            let dir = PathBuf::from(path);
+            (
+                dir.join("config.json"),
+                dir.join("medusa_lm_head.safetensors"),
+            )
+        } else {
+            let repo = HubRepo::new(path, None, get_token(hf_token, hf_token_path)?)?;
+            (
+                repo.get("config.json")?,
+                repo.get("medusa_lm_head.safetensors")?,
+            )
+        };
+        let config: MedusaConfig = try_api!(serde_json::from_slice(&try_api!(std::fs::read(
+            config_filename
+        ))));
+        if config.medusa_num_heads == 0 {
+            return Err(APIError::new(format!(
+                "The Medusa checkpoint `{path}` has no heads."
+            )));
+        }
+
+        let safetensors = try_api!(unsafe { MmapedSafetensors::new(weights_filename) });
+        let load = |name: &str| -> Result<Tensor, APIError> {
+            Ok(try_api!(
+                try_api!(safetensors.load(name, device)).to_dtype(dtype)
+            ))
+        };
+        let mut heads = Vec::with_capacity(config.medusa_num_heads);
+        for i in 0..config.medusa_num_heads {
+            let blocks = (0..config.medusa_num_layers)
+                .map(|j| {
+                    let weight = load(&format!("{i}.{j}.linear.weight"))?;
+                    let bias = load(&format!("{i}.{j}.linear.bias"))?;
+                    Ok(ResBlock {
+                        linear: Linear::new(weight, Some(bias)),
+                    })
+                })
+                .collect::<Result<Vec<_>, APIError>>()?;
+            let lm_head = Linear::new(
+                load(&format!("{i}.{}.weight", config.medusa_num_layers))?,
+                None,
+            );
+            heads.push(MedusaHead { blocks, lm_head });
+        }
+        Ok(Self { heads })
+    }
+
+    pub fn num_heads(&self) -> usize {
+        self.heads.len()
+    }
+
+    /// The `k` most likely tokens of each head for each row of `hidden_states` ([num_rows,
+    /// hidden_size]), indexed by row, head and rank.
+    fn top_candidates(
+        &self,
+        hidden_states: &Tensor,
+        k: usize,
+    ) -> Result<Vec<Vec<Vec<usize>>>, APIError> {
+        let logits = self
+            .heads
+            .iter()
+            .map(|head| head.forward(hidden_states))
+            .collect::<candle_core::Result<Vec<_>>>();
+        // [num_rows, num_heads, vocab_size]
+        let logits = try_api!(Tensor::stack(&try_api!(logits), 1));
+        let mut logits = try_api!(logits.to_dtype(DType::F32));
+        let vocab_size = try_api!(logits.dim(D::Minus1));
+        let device = logits.device().clone();
+        let token_ids = try_api!(Tensor::arange(0u32, vocab_size as u32, &device));
+        let neg_inf = try_api!(
+            try_api!(Tensor::new(f32::NEG_INFINITY, &device)).broadcast_as(logits.shape())
+        );
+        // The ranks are taken one at a time, which is cheap for the few candidates per head.
+        let mut top = Vec::with_capacity(k);
+        for _ in 0..k {
+            let best = try_api!(logits.argmax_keepdim(D::Minus1));
+            let taken = try_api!(token_ids.broadcast_eq(&best));
+            logits = try_api!(taken.where_cond(&neg_inf, &logits));
+            top.push(best);
+        }
+        let top = try_api!(try_api!(Tensor::cat(&top, D::Minus1)).to_vec3::<u32>());
+        Ok(top
+            .into_iter()
+            .map(|heads| {
+                heads
+                    .into_iter()
+                    .map(|ranks| ranks.into_iter().map(|token| token as usize).collect())
+                    .collect()
+            })
+            .collect())
+    }
+}
+
+/// A tree of candidate continuations of the last token of a sequence. Node 0 is the last token
+/// and every other node is the candidate of a head: a node at depth `d` holds a candidate of head
+/// `d - 1`, by its rank. The parent of a node comes before it.
+pub struct CandidateTree {
+    /// The ranks of the candidates on the path from the root to each node.
+    paths: Vec<Vec<usize>>,
+    parents: Vec<usize>,
+    /// The nodes each node attends to as a bit mask: itself and its ancestors.
+    masks: Vec<i64>,
+}
+
+impl CandidateTree {
+    /// A tree of the `num_candidates` most likely paths of `num_heads` heads, built best-first
+    /// with a Zipf prior on the ranks: the likelihood of a path is the product of `1 / (rank + 1)`
+    /// over its candidates. The top candidates of all heads form the first path, so that
+    /// candidates accepted along it are already cached at their positions.
+    pub fn new(num_heads: usize, num_candidates: usize) -> Self {
+        let num_nodes = (num_candidates + 1).min(MAX_TREE_QUERY_TOKENS);
+        let mut paths = vec![Vec::new()];
+        let mut parents = vec![0];
+        let mut masks = vec![1i64];
+        let mut frontier = vec![(1f64, vec![0])];
+        while paths.len() < num_nodes && !frontier.is_empty() {
+            let best = (0..frontier.len()).fold(0, |best, i| {
+                if frontier[i].0 > frontier[best].0 {
+                    i
+                } else {
+                    best
+                }
+            });
+            let (likelihood, path) = frontier.remove(best);
+            let rank = *path.last().unwrap();
+            if path.len() < num_heads {
+                frontier.push((likelihood, [path.as_slice(), &[0]].concat()));
+            }
+            let mut sibling = path.clone();
+            *sibling.last_mut().unwrap() += 1;
+            frontier.push((likelihood * (rank + 1) as f64 / (rank + 2) as f64, sibling));
+
+            let parent = paths
+                .iter()
+                .position(|other| *other == path[..path.len() - 1])
+                .unwrap();
+            masks.push(masks[parent] | (1u64 << paths.len()) as i64);
+            parents.push(parent);
+            paths.push(path);
+        }
+        Self {
+            paths,
+            parents,
+            masks,
+        }
+    }
+
+    /// Number of nodes, including the root.
+    pub(crate) fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub fn depth(&self, node: usize) -> usize {
+        self.paths[node].len()
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.paths.iter().map(Vec::len).max().unwrap_or(0)
+    }
+
+    /// The attention mask of the nodes, see `DecodeMetadata::tree_mask`.
+    pub fn masks(&self) -> &[i64] {
+        &self.masks
+    }
+
+    /// Number of candidates needed of each head.
+    fn num_ranks(&self) -> usize {
+        self.paths.iter().flatten().max().map_or(1, |rank| rank + 1)
+    }
+
+    fn children(&self, node: usize) -> impl Iterator<Item = usize> + '_ {
+        (node + 1..self.len()).filter(move |child| self.parents[*child] == node)
+    }
+
+    /// The token of each node, given the last token and the candidates of the heads by rank.
+    pub fn tokens(&self, last_token: usize, candidates: &[Vec<usize>]) -> Vec<usize> {
+        self.paths
+            .iter()
+            .map(|path| match path.last() {
+                Some(rank) => candidates[path.len() - 1][*rank],
+                None => last_token,
+            })
+            .collect()
+    }
+}
+
+/// Typical acceptance of the candidates (Medusa): a candidate is accepted if the model gives it a
+/// probability above `min(posterior_threshold, posterior_alpha * exp(-entropy))` at its parent,
+/// instead of only if the model samples it. This accepts more candidates at high temperatures,
+/// at the cost of no longer following the sampling distribution exactly. Greedy sampling always
+/// accepts exactly.
+#[derive(Clone, Copy, Debug)]
+pub struct TypicalAcceptance {
+    pub posterior_threshold: f32,
+    pub posterior_alpha: f32,
+}
+
+impl Default for TypicalAcceptance {
+    fn default() -> Self {
+        Self {
+            posterior_threshold: 0.09,
+            posterior_alpha: 0.3,
+        }
+    }
+}
+
+/// The Medusa heads of an engine with the candidates they proposed for the sequences of the last
+/// step.
+pub(crate) struct Medusa {
+    heads: MedusaHeads,
+    tree: CandidateTree,
+    typical_acceptance: Option<TypicalAcceptance>,
+    /// The candidates of each head by rank, by sequence id.
+    candidates: HashMap<usize, Vec<Vec<usize>>>,
+}
+
+impl Medusa {
+    pub(crate) fn new(
+        heads: MedusaHeads,
+        num_candidates: usize,
+        typical_acceptance: Option<TypicalAcceptance>,
+    ) -> Result<Self, APIError> {
+        if num_candidates == 0 {
+            return Err(APIError::new_str(
+                "The number of speculative tokens must be at least 1.",
+            ));
+        }
+        let tree = CandidateTree::new(heads.num_heads(), num_candidates);
+        Ok(Self {
+            heads,
+            tree,
+            typical_acceptance,
+            candidates: HashMap::new(),
+        })
+    }
+
+    pub(crate) fn tree(&self) -> &CandidateTree {
+        &self.tree
+    }
+
+    pub(crate) fn candidates(&self, seq_id: usize) -> Option<&[Vec<usize>]> {
+        self.candidates.get(&seq_id).map(Vec::as_slice)
+    }
+
+    /// Replace the candidates with those of `hidden_states`, one row per sequence of `seq_ids`.
+    pub(crate) fn propose(
+        &mut self,
+        hidden_states: &Tensor,
+        seq_ids: &[usize],
+    ) -> Result<(), APIError> {
+        let top = self
+            .heads
+            .top_candidates(hidden_states, self.tree.num_ranks())?;
+        self.candidates = seq_ids.iter().copied().zip(top).collect();
+        Ok(())
+    }
+
+    /// Accept the candidates of the tree of each sequence, see `accept_tree_candidates`, with
+    /// typical acceptance if it is enabled and the sampling is not greedy. Thinking budgets
+    /// force tokens themselves, so their requests are accepted exactly.
+    pub(crate) fn accept(
+        &self,
+        pipeline: &mut dyn ModulePipeline<'_>,
+        logits: &Tensor,
+        node_tokens: &[Vec<usize>],
+        speculative: &[bool],
+        sampling_params: &SamplingParams,
+        seqs: &[(&usize, &Arc<Sequence>)],
+    ) -> Result<Vec<Vec<usize>>, APIError> {
+        let typical_acceptance = self
+            .typical_acceptance
+            .filter(|_| sampling_params.temperature > 0.)
+            .filter(|_| sampling_params.max_thinking_tokens.is_none());
+        accept_tree_candidates(
+            pipeline,
+            logits,
+            &self.tree,
+            node_tokens,
+            speculative,
+            typical_acceptance,
+            sampling_params,
+            seqs,
+        )
+    }
+}
+
+/// Accept a path of candidates down the tree of each sequence. `logits` holds the logits of the
+/// model for every node of the tree of each sequence, `tree.len()` rows per sequence, and
+/// `node_tokens` the token of each node. Starting at the root, the token of a node is sampled
+/// from its row and added to the sequence, and the sequence continues with the child holding the
+/// token. With `typical_acceptance`, the path of the typically accepted candidates is taken
+/// instead and their tokens are forced. Every sequence gains at least one token, and a sequence
+/// which is not `speculative` exactly the token sampled at its root. Returns the nodes whose rows
+/// were sampled for each sequence, the root first.
+fn accept_tree_candidates(
+    pipeline: &mut dyn ModulePipeline<'_>,
+    logits: &Tensor,
+    tree: &CandidateTree,
+    node_tokens: &[Vec<usize>],
+    speculative: &[bool],
+    typical_acceptance: Option<TypicalAcceptance>,
+    sampling_params: &SamplingParams,
+    seqs: &[(&usize, &Arc<Sequence>)],
+) -> Result<Vec<Vec<usize>>, APIError> {
+    let typical_paths = match typical_acceptance {
+        Some(acceptance) => Some(typical_paths(
+            logits,
+            tree,
+            node_tokens,
+            acceptance,
+            sampling_params.temperature,
+        )?),
+        None => None,
+    };
+    let mut visited = vec![vec![0]; seqs.len()];
+    let mut active = (0..seqs.len()).collect::<Vec<_>>();
+    while !active.is_empty() {
+        let mut rows = Vec::with_capacity(active.len());
+        for seq_n in &active {
+            let node = *visited[*seq_n].last().unwrap();
+            let row = try_api!(logits.i(seq_n * tree.len() + node));
+            let next = visited[*seq_n].len();
+            let forced = typical_paths
+                .as_ref()
+                .filter(|_| speculative[*seq_n])
+                .and_then(|paths| paths[*seq_n].get(next).copied());
+            rows.push(match forced {
+                Some(child) => force_token(&row, node_tokens[*seq_n][child] as u32)?,
+                None => row,
+            });
+        }
+        let active_seqs = active.iter().map(|seq_n| seqs[*seq_n]).collect::<Vec<_>>();
+        let results = pipeline.sample(
+            try_api!(Tensor::stack(&rows, 0)),
+            sampling_params,
+            &active_seqs,
+        )?;
+
+        let mut still_active = Vec::new();
+        for (seq_n, result) in active.iter().zip(results) {
+            let (_, seq) = seqs[*seq_n];
+            match result {
+                Either::Left(logprobs) => {
+                    let token = logprobs.token;
+                    seq.deref_mut().add_token(logprobs);
+                    let node = *visited[*seq_n].last().unwrap();
+                    if let Some(child) = tree
+                        .children(node)
+                        .filter(|_| speculative[*seq_n])
+                        .find(|child| node_tokens[*seq_n][*child] == token)
+                    {
+                        visited[*seq_n].push(child);
+                        still_active.push(*seq_n);
+                    }
+                }
+                Either::Right(finish_reason) => seq.deref_mut().set_finish_reason(finish_reason),
+            }
+        }
+        active = still_active;
+    }
+    Ok(visited)
+}
+
+/// The longest path of typically accepted candidates of each sequence, the root first.
+fn typical_paths(
+    logits: &Tensor,
+    tree: &CandidateTree,
+    node_tokens: &[Vec<usize>],
+    acceptance: TypicalAcceptance,
+    temperature: f32,
+) -> Result<Vec<Vec<usize>>, APIError> {
+    let vocab_size = try_api!(logits.dim(D::Minus1));
+    let log_probs = try_api!(candle_nn::ops::log_softmax(
+        &try_api!(logits / f64::from(temperature)),
+        D::Minus1
+    ));
+    let probs = try_api!(log_probs.exp());
+    let entropies = try_api!((&probs * &log_probs)
+        .and_then(|x| x.sum(D::Minus1))
+        .and_then(|x| x.neg())
+        .and_then(|x| x.to_vec1::<f32>()));
+    // The probability of each candidate at its parent.
+    let indices = (0..node_tokens.len())
+        .flat_map(|seq_n| {
+            (1..tree.len()).map(move |node| {
+                let row = seq_n * tree.len() + tree.parents[node];
+                (row * vocab_size + node_tokens[seq_n][node]) as u32
+            })
+        })
+        .collect::<Vec<_>>();
+    let num_indices = indices.len();
+    let indices = try_api!(Tensor::from_vec(indices, num_indices, logits.device()));
+    let candidate_probs = try_api!(probs
+        .flatten_all()
+        .and_then(|x| x.index_select(&indices, 0))
+        .and_then(|x| x.to_vec1::<f32>()));
+
+    let mut paths = Vec::with_capacity(node_tokens.len());
+    for seq_n in 0..node_tokens.len() {
+        let mut accepted = vec![true; tree.len()];
+        let mut deepest = 0;
+        for node in 1..tree.len() {
+            let parent = tree.parents[node];
+            let entropy = entropies[seq_n * tree.len() + parent];
+            let threshold = acceptance
+                .posterior_threshold
+                .min(acceptance.posterior_alpha * (-entropy).exp());
+            let prob = candidate_probs[seq_n * (tree.len() - 1) + node - 1];
+            accepted[node] = accepted[parent] && prob > threshold;
+            if accepted[node] && tree.depth(node) > tree.depth(deepest) {
+                deepest = node;
+            }
+        }
+        let mut path = vec![deepest];
+        while let Some(&node) = path.last().filter(|node| **node != 0) {
+            path.push(tree.parents[node]);
+        }
+        path.reverse();
+        paths.push(path);
+    }
+    Ok(paths)
+}
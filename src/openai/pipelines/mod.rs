@@ -2,33 +2,133 @@ use candle_core::{DType, Device, Tensor, WithDType};
 use candle_sampling::logits_processor::Logprobs;
 use dirs;
 use either::Either;
-use std::{env, fs, path::PathBuf, sync::Arc};
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::PathBuf,
+    sync::{Arc, Mutex, OnceLock},
+};
 
 use crate::{
-    paged_attention::input_metadata::InputMetadata, scheduler::sequence::Sequence, try_api,
+    backend::compute_capability,
+    log_warning,
+    paged_attention::input_metadata::InputMetadata,
+    scheduler::{kv_cache_manager::KVCacheManager, sequence::Sequence},
+    try_api,
 };
 
+use self::pooling::PoolingMode;
 use super::{
-    conversation::Conversation, models::ConfigLike, responses::APIError,
-    sampling_params::SamplingParams, PipelineConfig, TokenizerWrapper,
+    conversation::{chat_template::ChatTemplate, Conversation},
+    models::{
+        lora::{LoraAdapter, LoraMode},
+        offload::OffloadStats,
+        pipeline_parallel::MicroBatch,
+        quantization::InSituQuantization,
+        ConfigLike,
+    },
+    multimodal::ImageInputs,
+    responses::APIError,
+    sampling_params::SamplingParams,
+    PipelineConfig, TokenizerWrapper,
 };
 
+pub mod async_llm_engine;
+pub mod eagle;
+pub mod hub;
 pub mod llama;
 /// The LLMEngine is effectively a wrapper around a ModulePipeline. It contains a Scheduler and a CacheEngine
 /// which are used to scheduler and manage the cache during generation requests, respectively.
 pub mod llm_engine;
+pub mod medusa;
+pub mod pooling;
+pub mod prompt_lookup;
+pub mod speculative;
+mod thinking;
+pub mod tokenizer;
+
+/// A sampled token, or the reason the sequence finished.
+pub type TokenOrFinishReason = Either<Logprobs, String>;
+
+/// The special tokens of fill-in-the-middle prompts of code models.
+pub struct FimTokens {
+    pub prefix: &'static str,
+    pub suffix: &'static str,
+    pub middle: &'static str,
+}
 
-type TokenOrFinishReason = Either<Logprobs, String>;
+impl FimTokens {
+    /// The prompt generating the code between `prefix` and `suffix` (PSM order).
+    pub fn prompt(&self, prefix: &str, suffix: &str) -> String {
+        format!(
+            "{}{prefix}{}{suffix}{}",
+            self.prefix, self.suffix, self.middle
+        )
+    }
+}
 
 pub trait ModulePipeline<'s>: Send + Sync {
     fn forward(
         &mut self,
         input_tokens: Tensor,
         input_positions: Tensor,
-        kv_cache: Option<&Vec<(Tensor, Tensor)>>,
+        kv_cache: Option<&KVCacheManager>,
         input_metadata: InputMetadata,
     ) -> Result<Tensor, APIError>;
 
+    /// Run the micro-batches of a step, returning the logits of each like `forward`, see
+    /// `PagedAttentionModel::forward_micro_batches`.
+    fn forward_micro_batches(
+        &mut self,
+        micro_batches: Vec<MicroBatch>,
+        kv_cache: Option<&KVCacheManager>,
+    ) -> Result<Vec<Tensor>, APIError> {
+        micro_batches
+            .into_iter()
+            .map(|micro_batch| {
+                self.forward(
+                    micro_batch.tokens,
+                    micro_batch.positions,
+                    kv_cache,
+                    micro_batch.metadata,
+                )
+            })
+            .collect()
+    }
+
+    /// Like `forward`, but also returns the final hidden states of all tokens of the step, shape
+    /// = [num_tokens, hidden_size], e.g. for the heads of Medusa or the draft head of EAGLE.
+    fn forward_with_hidden_states(
+        &mut self,
+        _input_tokens: Tensor,
+        _input_positions: Tensor,
+        _kv_cache: Option<&KVCacheManager>,
+        _input_metadata: InputMetadata,
+    ) -> Result<(Tensor, Tensor), APIError> {
+        Err(APIError::new(format!(
+            "`{}` does not expose its hidden states.",
+            self.name()
+        )))
+    }
+
+    /// The input embeddings of the tokens `input_ids` ([batch_size, seq_len]), shared with the
+    /// draft head of EAGLE.
+    fn embed_tokens(&self, _input_ids: &Tensor) -> Result<Tensor, APIError> {
+        Err(APIError::new(format!(
+            "`{}` does not expose its embeddings.",
+            self.name()
+        )))
+    }
+
+    /// The logits of final hidden states ([num_tokens, hidden_size]), e.g. of those predicted by
+    /// the draft head of EAGLE.
+    fn compute_logits(&self, _hidden_states: &Tensor) -> Result<Tensor, APIError> {
+        Err(APIError::new(format!(
+            "`{}` does not expose its output layer.",
+            self.name()
+        )))
+    }
+
     fn sample(
         &mut self,
         logits: Tensor,
@@ -42,9 +142,40 @@ pub trait ModulePipeline<'s>: Send + Sync {
 
     fn get_conversation(&mut self) -> &mut dyn Conversation;
 
+    /// The Jinja chat template of the model, which takes precedence over `get_conversation`, None
+    /// if the model has none.
+    fn chat_template(&self) -> Option<&ChatTemplate> {
+        None
+    }
+
+    /// The fill-in-the-middle tokens of code models, None if the model does not support it.
+    fn fim_tokens(&self) -> Option<&FimTokens> {
+        None
+    }
+
+    /// The image inputs of vision-language models, None if the model does not accept images.
+    fn image_inputs(&self) -> Option<&ImageInputs> {
+        None
+    }
+
+    /// How the hidden states returned by `forward` are pooled into embeddings, None if the model
+    /// is not an embedding model.
+    fn pooling(&self) -> Option<PoolingMode> {
+        None
+    }
+
     fn get_model_config(&self) -> Box<dyn ConfigLike>;
 
+    /// The uploads of the layers of the model offloaded to the CPU so far, see
+    /// `models::offload`.
+    fn offload_stats(&self) -> Option<OffloadStats> {
+        None
+    }
+
     fn get_dtype(&self) -> DType;
+
+    /// The device the model runs on, which also holds the KV cache and the model inputs.
+    fn device(&self) -> &Device;
 }
 
 // TODO(EricLBuehler): Ensure the padding token matches tokenizer
@@ -52,17 +183,14 @@ fn _make_tensor_with_pad<D: WithDType>(
     x: Vec<Vec<D>>,
     max_len: usize,
     pad: D,
+    device: &Device,
 ) -> Result<Tensor, APIError> {
     let mut padded_x = Vec::new();
     for mut x_i in x {
         assert!(x_i.len() <= max_len);
         x_i.extend([pad].repeat(max_len - x_i.len()));
         let shape = (x_i.len(),);
-        padded_x.push(try_api!(Tensor::from_vec(
-            x_i,
-            shape,
-            &try_api!(Device::new_cuda(0))
-        )));
+        padded_x.push(try_api!(Tensor::from_vec(x_i, shape, device)));
     }
     Tensor::cat(&padded_x[..], 0).map_err(APIError::from)
 }
@@ -70,28 +198,143 @@ fn _make_tensor_with_pad<D: WithDType>(
 pub(crate) fn get_token(
     hf_token: Option<String>,
     hf_token_path: Option<String>,
-) -> Result<String, APIError> {
-    Ok(match (hf_token, hf_token_path) {
+) -> Result<Option<String>, APIError> {
+    let token = match (hf_token, hf_token_path) {
         (Some(envvar), None) => try_api!(env::var(envvar)),
         (None, Some(path)) => try_api!(fs::read_to_string(path)),
-        (None, None) => try_api!(fs::read_to_string(format!(
-            "{}/.cache/huggingface/token",
-            dirs::home_dir()
-                .ok_or(APIError::new_str("No home directory"))?
-                .display()
-        ))),
+        // Public repositories need no token.
+        (None, None) => match env::var("HF_TOKEN") {
+            Ok(token) => token,
+            Err(_) => {
+                let home = match env::var("HF_HOME") {
+                    Ok(home) => PathBuf::from(home),
+                    Err(_) => dirs::home_dir()
+                        .ok_or(APIError::new_str("No home directory"))?
+                        .join(".cache")
+                        .join("huggingface"),
+                };
+                match fs::read_to_string(home.join("token")) {
+                    Ok(token) => token,
+                    Err(_) => return Ok(None),
+                }
+            }
+        },
         _ => {
             return Err(APIError::new_str(
                 "Do not specify `hf_token` and `hf_token_path` at the same time.",
             ))
         }
-    })
+    };
+    Ok(Some(token.trim().to_string()))
 }
 
 pub trait ModelPaths {
     fn get_weight_filenames(&self) -> &Vec<PathBuf>;
     fn get_config_filename(&self) -> &PathBuf;
     fn get_tokenizer_filename(&self) -> &PathBuf;
+    /// `tokenizer_config.json`, which holds the chat template, if the model provides one.
+    fn get_tokenizer_config_filename(&self) -> Option<&PathBuf> {
+        None
+    }
+    /// `generation_config.json`, if the model provides one.
+    fn get_generation_config_filename(&self) -> Option<&PathBuf> {
+        None
+    }
+    /// `preprocessor_config.json` of the image processor of vision-language models, if any.
+    fn get_preprocessor_config_filename(&self) -> Option<&PathBuf> {
+        None
+    }
+    /// `1_Pooling/config.json` of sentence-transformers embedding models, if any.
+    fn get_pooling_config_filename(&self) -> Option<&PathBuf> {
+        None
+    }
+}
+
+/// BF16 kernels and matmuls require Ampere or newer on CUDA devices.
+const MIN_BF16_COMPUTE_CAPABILITY: (i32, i32) = (8, 0);
+
+/// The data type to load the weights in and run the model with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ModelDtype {
+    /// The `torch_dtype` of the checkpoint. BF16 checkpoints fall back to f16 on devices
+    /// without BF16 support.
+    #[default]
+    Auto,
+    F16,
+    Bf16,
+    F32,
+}
+
+#[derive(serde::Deserialize)]
+struct TorchDtypeConfig {
+    torch_dtype: Option<String>,
+}
+
+impl ModelDtype {
+    /// The dtype for the model with the `config.json` at `config_filename`, running on `device`.
+    pub fn resolve(&self, config_filename: &PathBuf, device: &Device) -> Result<DType, APIError> {
+        match self {
+            Self::F16 => Ok(DType::F16),
+            Self::F32 => Ok(DType::F32),
+            Self::Bf16 if supports_bf16(device) => Ok(DType::BF16),
+            Self::Bf16 => Err(APIError::new(format!(
+                "BF16 is not supported on {device:?}, which requires a CPU or a CUDA device of compute capability 8.0 or newer."
+            ))),
+            Self::Auto => {
+                let config: TorchDtypeConfig =
+                    try_api!(serde_json::from_slice(&try_api!(fs::read(config_filename))));
+                Ok(match config.torch_dtype.as_deref() {
+                    Some("float32") => DType::F32,
+                    Some("bfloat16") if supports_bf16(device) => DType::BF16,
+                    Some("bfloat16") => {
+                        log_warning(&format!(
+                            "BF16 is not supported on {device:?}, converting the BF16 checkpoint to f16."
+                        ));
+                        DType::F16
+                    }
+                    _ => DType::F16,
+                })
+            }
+        }
+    }
+}
+
+fn supports_bf16(device: &Device) -> bool {
+    match device {
+        Device::Cpu => true,
+        Device::Cuda(dev) => compute_capability(dev.ordinal())
+            .is_ok_and(|capability| capability >= MIN_BF16_COMPUTE_CAPABILITY),
+        _ => false,
+    }
+}
+
+/// How `ModelLoader::load_model` loads the weights of a model. Splitting and offloading only
+/// support unquantized safetensors checkpoints of the Llama architecture.
+#[derive(Clone)]
+pub struct LoadOptions {
+    /// Split the model over this many devices, see `models::tensor_parallel`.
+    pub tensor_parallel_size: usize,
+    /// Split the decoder layers into this many stages, see `models::pipeline_parallel`.
+    pub pipeline_parallel_size: usize,
+    /// Keep the weights of this many last decoder layers in CPU memory, see `models::offload`.
+    pub num_offloaded_layers: usize,
+    /// Quantize the linear layers of unquantized checkpoints while loading. Linear layers whose
+    /// input dimension is not a multiple of the block size of the format stay unquantized.
+    pub in_situ_quantization: Option<InSituQuantization>,
+    /// The LoRA adapter applied to the model.
+    pub lora_adapter: Option<(Arc<LoraAdapter>, LoraMode)>,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        Self {
+            tensor_parallel_size: 1,
+            pipeline_parallel_size: 1,
+            num_offloaded_layers: 0,
+            in_situ_quantization: None,
+            lora_adapter: None,
+        }
+    }
 }
 
 pub trait ModelLoader<'a> {
@@ -108,5 +351,45 @@ pub trait ModelLoader<'a> {
         paths: Box<dyn ModelPaths>,
         dtype: DType,
         device: Device,
+        options: &LoadOptions,
     ) -> Result<(Box<dyn ModulePipeline<'a>>, PipelineConfig), APIError>;
 }
+
+/// Creates the loader of a pipeline implemented outside of this crate, see `register_pipeline`.
+pub trait PipelineFactory: Send + Sync {
+    /// The loader of the pipeline, serving the model under `name`.
+    fn loader<'a>(&self, name: &str) -> Box<dyn ModelLoader<'a>>;
+}
+
+fn pipeline_registry() -> &'static Mutex<HashMap<String, Arc<dyn PipelineFactory>>> {
+    static PIPELINE_REGISTRY: OnceLock<Mutex<HashMap<String, Arc<dyn PipelineFactory>>>> =
+        OnceLock::new();
+    PIPELINE_REGISTRY.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Register a pipeline under `name`, replacing any previous registration, to serve an
+/// architecture which does not fit `LlamaPipeline` without modifying this crate. The
+/// `ModelLoader` downloads and loads the model into a `ModulePipeline`: the engine sizes the KV
+/// cache from its `get_model_config`, runs `forward` on the scheduled batches with the
+/// `KVCacheManager`, and decodes with `sample` and `tokenizer`. Serve it with
+/// `ModelSelected::Plugin`. Architectures which only need a new model are simpler to add with
+/// `register_paged_attention_model!`.
+pub fn register_pipeline(name: &str, factory: impl PipelineFactory + 'static) {
+    pipeline_registry()
+        .lock()
+        .unwrap()
+        .insert(name.to_string(), Arc::new(factory));
+}
+
+pub fn get_pipeline_factory(name: &str) -> Option<Arc<dyn PipelineFactory>> {
+    pipeline_registry().lock().unwrap().get(name).cloned()
+}
+
+pub fn registered_pipelines() -> Vec<String> {
+    pipeline_registry()
+        .lock()
+        .unwrap()
+        .keys()
+        .cloned()
+        .collect()
+}
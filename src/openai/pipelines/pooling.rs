@@ -0,0 +1,64 @@
+use candle_core::{Tensor, D};
+use serde::Deserialize;
+
+use crate::{openai::responses::APIError, try_api};
+
+/// How the hidden states of the tokens of a prompt are pooled into its embedding.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum PoolingMode {
+    /// The hidden state of the first token, e.g. `[CLS]` (BGE).
+    #[default]
+    Cls,
+    /// The mean of the hidden states of all tokens (E5, GTE).
+    Mean,
+}
+
+/// `1_Pooling/config.json` of a sentence-transformers model.
+#[derive(Deserialize)]
+struct PoolingConfig {
+    #[serde(default)]
+    pooling_mode_cls_token: bool,
+    #[serde(default)]
+    pooling_mode_mean_tokens: bool,
+}
+
+impl PoolingMode {
+    /// The pooling mode of a sentence-transformers pooling config, None if it is neither CLS nor
+    /// mean pooling.
+    pub fn from_pooling_config(config: &[u8]) -> Result<Option<Self>, APIError> {
+        let config: PoolingConfig = try_api!(serde_json::from_slice(config));
+        Ok(
+            match (
+                config.pooling_mode_cls_token,
+                config.pooling_mode_mean_tokens,
+            ) {
+                (true, false) => Some(Self::Cls),
+                (false, true) => Some(Self::Mean),
+                _ => None,
+            },
+        )
+    }
+
+    /// Pool the hidden states of the prompt slice into one L2-normalized embedding per prompt.
+    /// hidden_states: shape = [num_prompt_tokens, hidden_size], the prompts packed back to back.
+    /// prompt_lens: the number of tokens of each prompt.
+    ///
+    /// Returns shape = [num_prompts, hidden_size].
+    pub fn pool(&self, hidden_states: &Tensor, prompt_lens: &[usize]) -> Result<Tensor, APIError> {
+        let mut offset = 0;
+        let mut embeddings = Vec::with_capacity(prompt_lens.len());
+        for prompt_len in prompt_lens {
+            let embedding = match self {
+                Self::Cls => try_api!(hidden_states.get(offset)),
+                Self::Mean => {
+                    try_api!(try_api!(hidden_states.narrow(0, offset, *prompt_len)).mean(0))
+                }
+            };
+            embeddings.push(embedding);
+            offset += prompt_len;
+        }
+        let embeddings = try_api!(Tensor::stack(&embeddings, 0));
+        let norm = try_api!(try_api!(try_api!(embeddings.sqr()).sum_keepdim(D::Minus1)).sqrt());
+        embeddings.broadcast_div(&norm).map_err(APIError::from)
+    }
+}
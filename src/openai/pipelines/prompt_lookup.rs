@@ -0,0 +1,81 @@
+//! Prompt-lookup speculation: the tokens which followed the latest earlier occurrence of the last
+//! n-gram of a sequence, in its prompt or its output, are proposed as its next tokens and
+//! verified like the tokens of a draft model. No model runs to propose them, which pays off when
+//! the output copies from the prompt, e.g. in summarization or retrieval-augmented generation.
+
+use crate::openai::responses::APIError;
+
+/// Proposes up to `num_speculative_tokens` tokens by matching n-grams of `min_ngram` to
+/// `max_ngram` tokens, the longest match first.
+#[derive(Debug, Clone)]
+pub struct PromptLookup {
+    num_speculative_tokens: usize,
+    min_ngram: usize,
+    max_ngram: usize,
+}
+
+impl PromptLookup {
+    pub fn new(
+        num_speculative_tokens: usize,
+        min_ngram: usize,
+        max_ngram: usize,
+    ) -> Result<Self, APIError> {
+        if num_speculative_tokens == 0 {
+            return Err(APIError::new_str(
+                "The number of speculative tokens must be at least 1.",
+            ));
+        }
+        if min_ngram == 0 || min_ngram > max_ngram {
+            return Err(APIError::new(format!(
+                "Invalid prompt lookup n-gram sizes {min_ngram} to {max_ngram}."
+            )));
+        }
+        Ok(Self {
+            num_speculative_tokens,
+            min_ngram,
+            max_ngram,
+        })
+    }
+
+    pub fn num_speculative_tokens(&self) -> usize {
+        self.num_speculative_tokens
+    }
+
+    /// The tokens after the latest earlier occurrence of the longest matching suffix of
+    /// `token_ids`, empty if no suffix of at least `min_ngram` tokens occurs earlier.
+    pub fn propose(&self, token_ids: &[usize]) -> Vec<usize> {
+        let len = token_ids.len();
+        for n in (self.min_ngram..=self.max_ngram.min(len.saturating_sub(1))).rev() {
+            let suffix = &token_ids[len - n..];
+            // The match ends before the last token, so that at least one token follows it.
+            if let Some(start) = (0..len - n)
+                .rev()
+                .find(|i| &token_ids[*i..*i + n] == suffix)
+            {
+                let end = (start + n + self.num_speculative_tokens).min(len);
+                return token_ids[start + n..end].to_vec();
+            }
+        }
+        Vec::new()
+    }
+
+    /// The proposals of a batch of sequences, all padded to the longest proposal by repeating
+    /// their last token, as the model verifies the same number of tokens of every sequence. Any
+    /// padding token the model samples is accepted like a proposed one. None if no sequence has
+    /// a proposal.
+    pub fn propose_batch(&self, token_ids: &[Vec<usize>]) -> Option<Vec<Vec<usize>>> {
+        let mut proposals = token_ids
+            .iter()
+            .map(|token_ids| self.propose(token_ids))
+            .collect::<Vec<_>>();
+        let num_tokens = proposals.iter().map(Vec::len).max()?;
+        if num_tokens == 0 {
+            return None;
+        }
+        for (proposal, token_ids) in proposals.iter_mut().zip(token_ids) {
+            let padding = *proposal.last().or(token_ids.last())?;
+            proposal.resize(num_tokens, padding);
+        }
+        Some(proposals)
+    }
+}
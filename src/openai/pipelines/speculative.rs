@@ -0,0 +1,223 @@
+//! Speculative decoding with a draft model: a small model sharing the tokenizer of the model
+//! proposes `num_speculative_tokens` tokens per decode step, which the model verifies in a single
+//! forward pass with the multi-query decode attention. The draft model keeps its own KV cache with
+//! the block size and number of blocks of the model's cache, so that both are addressed by the
+//! block tables of the `BlockEngine`.
+//!
+//! The draft model may use another dtype or quantization than the model and run on another
+//! device, e.g. a second small GPU or the CPU. The inputs of each step are then copied to its
+//! device, and it caches the tokens of the steps concurrently with the forward pass of the model,
+//! see `LLMEngine::execute_step`.
+
+use std::{collections::HashMap, sync::Arc};
+
+use candle_core::{Device, Tensor, D};
+use either::Either;
+
+use crate::{
+    openai::{responses::APIError, sampling_params::SamplingParams},
+    paged_attention::input_metadata::InputMetadata,
+    scheduler::{
+        cache_engine::{CacheConfig, CacheEngine},
+        sequence::Sequence,
+        SchedulerOutput,
+    },
+    try_api,
+};
+
+use super::ModulePipeline;
+
+pub struct DraftModel<'a> {
+    pipeline: Box<dyn ModulePipeline<'a>>,
+    cache_engine: CacheEngine,
+    num_speculative_tokens: usize,
+}
+
+impl<'a> DraftModel<'a> {
+    /// A draft model proposing `num_speculative_tokens` tokens per step, with a KV cache laid out
+    /// by `cache_config` on the device of `pipeline`.
+    pub fn new(
+        pipeline: Box<dyn ModulePipeline<'a>>,
+        cache_config: CacheConfig,
+        num_speculative_tokens: usize,
+    ) -> Result<Self, APIError> {
+        if num_speculative_tokens == 0 {
+            return Err(APIError::new_str(
+                "The number of speculative tokens must be at least 1.",
+            ));
+        }
+        let config = pipeline.get_model_config();
+        if config.get_ssm_dims().is_some()
+            || config.is_encoder_only()
+            || config.get_decoder_start_token_id().is_some()
+        {
+            return Err(APIError::new(format!(
+                "`{}` cannot be used as a draft model, only decoder-only attention models can.",
+                pipeline.name()
+            )));
+        }
+        let cache_engine = CacheEngine::new_on_device(
+            config,
+            cache_config,
+            pipeline.get_dtype(),
+            pipeline.device().clone(),
+        )?;
+        Ok(Self {
+            pipeline,
+            cache_engine,
+            num_speculative_tokens,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        self.pipeline.name()
+    }
+
+    pub fn device(&self) -> &Device {
+        self.pipeline.device()
+    }
+
+    /// Whether the draft model runs on another device than the model on `device`.
+    pub fn is_on_other_device(&self, device: &Device) -> bool {
+        !self.device().same_device(device)
+    }
+
+    pub fn num_speculative_tokens(&self) -> usize {
+        self.num_speculative_tokens
+    }
+
+    /// Size in bytes of the draft KV cache on the GPU and of its CPU swap space.
+    pub fn get_kv_cache_memory_usage(&self) -> (usize, usize) {
+        self.cache_engine.memory_usage()
+    }
+
+    /// Apply the swaps and copies of the blocks of a step to the draft cache.
+    pub(crate) fn execute_scheduler_ops(
+        &mut self,
+        scheduler_output: &SchedulerOutput,
+    ) -> Result<(), APIError> {
+        self.cache_engine
+            .swap_in(scheduler_output.blocks_to_swap_in.clone())?;
+        self.cache_engine
+            .swap_out(scheduler_output.blocks_to_swap_out.clone())?;
+        self.cache_engine
+            .copy(scheduler_output.blocks_to_copy.clone())
+    }
+
+    /// Copy the blocks of a migrated session into the draft cache of the replica `dst`, see
+    /// `LLMEngine::migrate_session`.
+    pub(crate) fn migrate_to(
+        &self,
+        dst: &DraftModel<'_>,
+        src_to_dst: HashMap<usize, usize>,
+    ) -> Result<(), APIError> {
+        self.cache_engine.migrate_to(&dst.cache_engine, src_to_dst)
+    }
+
+    /// Run the draft model on the tokens of a step, caching their keys and values, and return the
+    /// greedy next token of each token with logits: the last token of each prompt, followed by
+    /// every generation token.
+    pub(crate) fn forward(
+        &mut self,
+        tokens: Tensor,
+        positions: Tensor,
+        metadata: InputMetadata,
+    ) -> Result<Vec<usize>, APIError> {
+        let logits = self.forward_logits(tokens, positions, metadata)?;
+        let next_tokens = try_api!(try_api!(logits.argmax(D::Minus1)).flatten_all());
+        Ok(try_api!(next_tokens.to_vec1::<u32>())
+            .into_iter()
+            .map(|token| token as usize)
+            .collect())
+    }
+
+    /// Run the draft model on the tokens of a step only to cache their keys and values.
+    pub(crate) fn cache(
+        &mut self,
+        tokens: Tensor,
+        positions: Tensor,
+        metadata: InputMetadata,
+    ) -> Result<(), APIError> {
+        self.forward_logits(tokens, positions, metadata)?;
+        Ok(())
+    }
+
+    /// The logits of the draft model for inputs prepared on the device of the model, which are
+    /// copied to the device of the draft model first.
+    fn forward_logits(
+        &mut self,
+        tokens: Tensor,
+        positions: Tensor,
+        metadata: InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let device = self.pipeline.device().clone();
+        let (tokens, positions, metadata) = if tokens.device().same_device(&device) {
+            (tokens, positions, metadata)
+        } else {
+            (
+                try_api!(tokens.to_device(&device)),
+                try_api!(positions.to_device(&device)),
+                metadata.to_device(&device)?,
+            )
+        };
+        self.pipeline.forward(
+            tokens,
+            positions,
+            Some(&*self.cache_engine.get_kv_cache()),
+            metadata,
+        )
+    }
+}
+
+/// Accept the longest prefix of the `draft_tokens` of each sequence which the model samples
+/// itself. `logits` holds the logits of the model for the last token of each sequence and its
+/// draft tokens, the same number of rows per sequence, one more than its longest draft. The token
+/// of each position is sampled from its row and added to the sequence, and the sequence continues
+/// with the next row only if the token equals the draft token, so the tokens follow the sampling
+/// distribution of the model. Every sequence gains at least one token, and a sequence without
+/// draft tokens exactly one. Returns the number of accepted draft tokens.
+pub(crate) fn accept_draft_tokens(
+    pipeline: &mut dyn ModulePipeline<'_>,
+    logits: &Tensor,
+    draft_tokens: &[Vec<usize>],
+    sampling_params: &SamplingParams,
+    seqs: &[(&usize, &Arc<Sequence>)],
+) -> Result<usize, APIError> {
+    let num_rows = try_api!(logits.dim(0)) / seqs.len().max(1);
+    let mut active = (0..seqs.len()).collect::<Vec<_>>();
+    let mut num_accepted = 0;
+    for i in 0..num_rows {
+        if active.is_empty() {
+            break;
+        }
+        let rows = active
+            .iter()
+            .map(|seq_n| (seq_n * num_rows + i) as u32)
+            .collect::<Vec<_>>();
+        let rows = try_api!(Tensor::from_vec(rows, active.len(), logits.device()));
+        let active_seqs = active.iter().map(|seq_n| seqs[*seq_n]).collect::<Vec<_>>();
+        let results = pipeline.sample(
+            try_api!(logits.index_select(&rows, 0)),
+            sampling_params,
+            &active_seqs,
+        )?;
+
+        let mut still_active = Vec::new();
+        for (seq_n, result) in active.iter().zip(results) {
+            let (_, seq) = seqs[*seq_n];
+            match result {
+                Either::Left(logprobs) => {
+                    let token = logprobs.token;
+                    seq.deref_mut().add_token(logprobs);
+                    if draft_tokens[*seq_n].get(i) == Some(&token) {
+                        num_accepted += 1;
+                        still_active.push(*seq_n);
+                    }
+                }
+                Either::Right(finish_reason) => seq.deref_mut().set_finish_reason(finish_reason),
+            }
+        }
+        active = still_active;
+    }
+    Ok(num_accepted)
+}
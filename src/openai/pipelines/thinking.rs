@@ -0,0 +1,82 @@
+//! Budget for the thinking span of reasoning models, see `SamplingParams::max_thinking_tokens`.
+
+use candle_core::{Device, Tensor};
+use tokenizers::Tokenizer;
+
+use crate::{openai::responses::APIError, try_api};
+
+/// The tags delimiting the thinking span.
+const THINK_START: &str = "<think>";
+const THINK_END: &str = "</think>";
+
+/// The thinking tags as token ids of a model.
+pub(crate) struct ThinkingTags {
+    start: Vec<u32>,
+    end: Vec<u32>,
+}
+
+impl ThinkingTags {
+    pub(crate) fn new(tokenizer: &Tokenizer) -> Result<Self, APIError> {
+        let encode = |tag: &str| -> Result<Vec<u32>, APIError> {
+            Ok(try_api!(tokenizer.encode(tag, false)).get_ids().to_vec())
+        };
+        Ok(Self {
+            start: encode(THINK_START)?,
+            end: encode(THINK_END)?,
+        })
+    }
+
+    /// The next token of the closing tag if the thinking span has used up `budget` generated
+    /// tokens and is still open. The span may also be opened by the prompt (e.g. by the chat
+    /// template), only generated tokens count towards the budget.
+    pub(crate) fn forced_token(
+        &self,
+        tokens: &[u32],
+        prompt_len: usize,
+        budget: usize,
+    ) -> Option<u32> {
+        if self.start.is_empty() || self.end.is_empty() {
+            return None;
+        }
+        let span_start = find_last(tokens, &self.start)? + self.start.len();
+        if find_last(&tokens[span_start..], &self.end).is_some() {
+            return None;
+        }
+        let thinking_tokens = tokens.len() - span_start.max(prompt_len);
+        if thinking_tokens < budget {
+            return None;
+        }
+        // Continue a closing tag which was already partially forced.
+        let forced = (1..self.end.len())
+            .rev()
+            .find(|n| tokens.ends_with(&self.end[..*n]))
+            .unwrap_or(0);
+        Some(self.end[forced])
+    }
+}
+
+fn find_last(haystack: &[u32], needle: &[u32]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .rposition(|window| window == needle)
+}
+
+/// Mask `logits` ([vocab_size]) so that only `token` can be sampled.
+pub(crate) fn force_token(logits: &Tensor, token: u32) -> Result<Tensor, APIError> {
+    let vocab_size = try_api!(logits.dim(0));
+    let mask = (0..vocab_size)
+        .map(|i| {
+            if i == token as usize {
+                0.
+            } else {
+                f32::NEG_INFINITY
+            }
+        })
+        .collect::<Vec<_>>();
+    let mask = try_api!(
+        try_api!(Tensor::from_vec(mask, vocab_size, &Device::Cpu)).to_device(logits.device())
+    );
+    logits
+        .broadcast_add(&try_api!(mask.to_dtype(logits.dtype())))
+        .map_err(APIError::from)
+}
@@ -0,0 +1,399 @@
+//! Tokenizers of models which ship no `tokenizer.json`: a SentencePiece `tokenizer.model` (older
+//! Llama releases) or a tiktoken vocabulary (Qwen), converted to a `tokenizers::Tokenizer` like the
+//! slow-to-fast converters of Transformers do.
+
+use std::{collections::HashMap, fs, path::Path, path::PathBuf};
+
+use base64::Engine;
+use tokenizers::{
+    decoders::{
+        byte_fallback::ByteFallback, fuse::Fuse, sequence::Sequence as DecoderSequence,
+        strip::Strip,
+    },
+    models::{bpe::BPE, unigram::Unigram},
+    normalizers::{Prepend, Replace, Sequence as NormalizerSequence},
+    pre_tokenizers::{
+        byte_level::ByteLevel,
+        sequence::Sequence as PreTokenizerSequence,
+        split::{Split, SplitPattern},
+    },
+    AddedToken, DecoderWrapper, ModelWrapper, NormalizerWrapper, PreTokenizerWrapper,
+    SplitDelimiterBehavior, Tokenizer,
+};
+
+use crate::{openai::responses::APIError, try_api};
+
+use super::hub::HubRepo;
+
+/// The whitespace marker of SentencePiece.
+const SPIECE_UNDERLINE: &str = "\u{2581}";
+
+/// The pre-tokenization pattern of the Qwen tiktoken vocabulary.
+const TIKTOKEN_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+/// The special tokens following the ranks of a tiktoken vocabulary, as in Qwen.
+const TIKTOKEN_SPECIAL_TOKENS: [&str; 3] = ["<|endoftext|>", "<|im_start|>", "<|im_end|>"];
+const TIKTOKEN_NUM_EXTRA_TOKENS: usize = 205;
+
+/// The tokenizer of `repo`: `tokenizer.json`, else a SentencePiece `tokenizer.model` or a
+/// tiktoken vocabulary.
+pub fn get_tokenizer_filename(repo: &HubRepo) -> Result<PathBuf, APIError> {
+    let files = repo.files();
+    let filename = ["tokenizer.json", "tokenizer.model"]
+        .into_iter()
+        .find(|name| files.iter().any(|file| file == name))
+        .map(str::to_string)
+        .or_else(|| {
+            files
+                .iter()
+                .find(|file| file.ends_with(".tiktoken"))
+                .cloned()
+        });
+    match filename {
+        Some(filename) => repo.get(&filename),
+        None => Err(APIError::new_str(
+            "The model has no `tokenizer.json`, SentencePiece `tokenizer.model` or tiktoken vocabulary.",
+        )),
+    }
+}
+
+/// Load the tokenizer at `filename`, in any format of `get_tokenizer_filename`. The format of a
+/// `.model` file is detected from its content, since tiktoken vocabularies use the name as well.
+pub fn load_tokenizer(filename: &Path) -> Result<Tokenizer, APIError> {
+    if filename
+        .extension()
+        .is_some_and(|extension| extension == "json")
+    {
+        return Tokenizer::from_file(filename).map_err(|x| APIError::new(x.to_string()));
+    }
+    let bytes = try_api!(fs::read(filename));
+    match parse_tiktoken(&bytes) {
+        Some(ranks) => tiktoken_tokenizer(ranks),
+        None => sentencepiece_tokenizer(&bytes),
+    }
+}
+
+/// The ranks of a tiktoken vocabulary, lines of a base64 token and its rank, None if `bytes` is
+/// not one.
+fn parse_tiktoken(bytes: &[u8]) -> Option<Vec<(Vec<u8>, u32)>> {
+    let text = std::str::from_utf8(bytes).ok()?;
+    text.lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let (token, rank) = line.split_once(' ')?;
+            let token = base64::engine::general_purpose::STANDARD
+                .decode(token)
+                .ok()?;
+            Some((token, rank.parse().ok()?))
+        })
+        .collect()
+}
+
+/// A byte-level BPE tokenizer with the merges recovered from the ranks: each token of several
+/// bytes is the merge of its two parts of lower ranks.
+fn tiktoken_tokenizer(ranks: Vec<(Vec<u8>, u32)>) -> Result<Tokenizer, APIError> {
+    let byte_encoder = super::super::_byte_level_decoding_table()
+        .into_iter()
+        .map(|(c, byte)| (byte, c))
+        .collect::<HashMap<_, _>>();
+    let encode = |bytes: &[u8]| bytes.iter().map(|b| byte_encoder[b]).collect::<String>();
+    let rank_of = ranks
+        .iter()
+        .map(|(token, rank)| (token.as_slice(), *rank))
+        .collect::<HashMap<_, _>>();
+
+    let mut merges = Vec::new();
+    for (token, rank) in &ranks {
+        for split in 1..token.len() {
+            let (left, right) = token.split_at(split);
+            if let (Some(left_rank), Some(right_rank)) = (rank_of.get(left), rank_of.get(right)) {
+                merges.push((*rank, *left_rank, *right_rank, encode(left), encode(right)));
+            }
+        }
+    }
+    merges.sort();
+    let vocab = ranks
+        .iter()
+        .map(|(token, rank)| (encode(token), *rank))
+        .collect::<HashMap<_, _>>();
+    let merges = merges
+        .into_iter()
+        .map(|(_, _, _, left, right)| (left, right))
+        .collect();
+    let bpe = try_api!(BPE::builder().vocab_and_merges(vocab, merges).build());
+
+    let mut tokenizer = Tokenizer::new(bpe);
+    let split = try_api!(Split::new(
+        SplitPattern::Regex(TIKTOKEN_PATTERN.to_string()),
+        SplitDelimiterBehavior::Isolated,
+        false
+    ));
+    tokenizer.with_pre_tokenizer(PreTokenizerWrapper::Sequence(PreTokenizerSequence::new(
+        vec![
+            PreTokenizerWrapper::Split(split),
+            PreTokenizerWrapper::ByteLevel(ByteLevel::new(false, false, false)),
+        ],
+    )));
+    tokenizer.with_decoder(DecoderWrapper::ByteLevel(ByteLevel::default()));
+    // The special tokens take the ids after the vocabulary.
+    let special_tokens = TIKTOKEN_SPECIAL_TOKENS
+        .into_iter()
+        .map(str::to_string)
+        .chain((0..TIKTOKEN_NUM_EXTRA_TOKENS).map(|i| format!("<|extra_{i}|>")))
+        .map(|token| AddedToken::from(token, true))
+        .collect::<Vec<_>>();
+    tokenizer.add_special_tokens(&special_tokens);
+    Ok(tokenizer)
+}
+
+/// The type of a piece of a SentencePiece model.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PieceType {
+    Normal,
+    Unknown,
+    Control,
+    UserDefined,
+    Unused,
+    Byte,
+}
+
+struct Piece {
+    piece: String,
+    score: f32,
+    kind: PieceType,
+}
+
+/// The fields of a serialized `sentencepiece.ModelProto` needed for the conversion.
+struct SentencePieceModel {
+    pieces: Vec<Piece>,
+    /// BPE, else Unigram.
+    bpe: bool,
+    byte_fallback: bool,
+    add_dummy_prefix: bool,
+}
+
+/// A minimal reader of the protobuf wire format.
+struct ProtoReader<'a> {
+    bytes: &'a [u8],
+}
+
+enum ProtoValue<'a> {
+    Varint(u64),
+    Fixed32(u32),
+    Fixed64,
+    Bytes(&'a [u8]),
+}
+
+impl<'a> ProtoReader<'a> {
+    fn varint(&mut self) -> Option<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (byte, rest) = self.bytes.split_first()?;
+            self.bytes = rest;
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    /// The next field number and value, None at the end.
+    fn next_field(&mut self) -> Option<Result<(u64, ProtoValue<'a>), APIError>> {
+        if self.bytes.is_empty() {
+            return None;
+        }
+        let invalid = || APIError::new_str("Invalid SentencePiece model.");
+        let field = (|| {
+            let key = self.varint()?;
+            let value = match key & 7 {
+                0 => ProtoValue::Varint(self.varint()?),
+                1 => {
+                    self.take(8)?;
+                    ProtoValue::Fixed64
+                }
+                2 => {
+                    let len = self.varint()? as usize;
+                    ProtoValue::Bytes(self.take(len)?)
+                }
+                5 => ProtoValue::Fixed32(u32::from_le_bytes(self.take(4)?.try_into().ok()?)),
+                _ => return None,
+            };
+            Some((key >> 3, value))
+        })();
+        Some(field.ok_or_else(invalid))
+    }
+}
+
+impl SentencePieceModel {
+    fn parse(bytes: &[u8]) -> Result<Self, APIError> {
+        let mut model = Self {
+            pieces: Vec::new(),
+            bpe: false,
+            byte_fallback: false,
+            add_dummy_prefix: true,
+        };
+        let mut reader = ProtoReader { bytes };
+        while let Some(field) = reader.next_field() {
+            match field? {
+                (1, ProtoValue::Bytes(piece)) => model.pieces.push(Self::parse_piece(piece)?),
+                // TrainerSpec: model_type and byte_fallback.
+                (2, ProtoValue::Bytes(spec)) => {
+                    let mut reader = ProtoReader { bytes: spec };
+                    while let Some(field) = reader.next_field() {
+                        match field? {
+                            (3, ProtoValue::Varint(model_type)) => model.bpe = model_type == 2,
+                            (35, ProtoValue::Varint(value)) => model.byte_fallback = value != 0,
+                            _ => {}
+                        }
+                    }
+                }
+                // NormalizerSpec: add_dummy_prefix.
+                (3, ProtoValue::Bytes(spec)) => {
+                    let mut reader = ProtoReader { bytes: spec };
+                    while let Some(field) = reader.next_field() {
+                        if let (3, ProtoValue::Varint(value)) = field? {
+                            model.add_dummy_prefix = value != 0;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        if model.pieces.is_empty() {
+            return Err(APIError::new_str("The SentencePiece model has no pieces."));
+        }
+        Ok(model)
+    }
+
+    fn parse_piece(bytes: &[u8]) -> Result<Piece, APIError> {
+        let mut piece = Piece {
+            piece: String::new(),
+            score: 0.,
+            kind: PieceType::Normal,
+        };
+        let mut reader = ProtoReader { bytes };
+        while let Some(field) = reader.next_field() {
+            match field? {
+                (1, ProtoValue::Bytes(text)) => {
+                    piece.piece = String::from_utf8_lossy(text).into_owned()
+                }
+                (2, ProtoValue::Fixed32(score)) => piece.score = f32::from_bits(score),
+                (3, ProtoValue::Varint(kind)) => {
+                    piece.kind = match kind {
+                        2 => PieceType::Unknown,
+                        3 => PieceType::Control,
+                        4 => PieceType::UserDefined,
+                        5 => PieceType::Unused,
+                        6 => PieceType::Byte,
+                        _ => PieceType::Normal,
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(piece)
+    }
+}
+
+/// A BPE (Llama) or Unigram tokenizer of a SentencePiece model. The BPE merges are recovered
+/// from the scores: each piece is the merge of its two parts, ordered by the score of the piece.
+fn sentencepiece_tokenizer(bytes: &[u8]) -> Result<Tokenizer, APIError> {
+    let model = SentencePieceModel::parse(bytes)?;
+    let unk_id = model
+        .pieces
+        .iter()
+        .position(|piece| piece.kind == PieceType::Unknown);
+
+    let model_wrapper: ModelWrapper = if model.bpe {
+        let vocab = model
+            .pieces
+            .iter()
+            .enumerate()
+            .map(|(id, piece)| (piece.piece.clone(), id as u32))
+            .collect::<HashMap<_, _>>();
+        let mut merges = Vec::new();
+        for (id, piece) in model.pieces.iter().enumerate() {
+            if piece.kind != PieceType::Normal && piece.kind != PieceType::UserDefined {
+                continue;
+            }
+            for (split, _) in piece.piece.char_indices().skip(1) {
+                let (left, right) = piece.piece.split_at(split);
+                if let (Some(left_id), Some(right_id)) = (vocab.get(left), vocab.get(right)) {
+                    merges.push((-piece.score, id, *left_id, *right_id, left, right));
+                }
+            }
+        }
+        merges.sort_by(|a, b| {
+            a.0.total_cmp(&b.0)
+                .then((a.1, a.2, a.3).cmp(&(b.1, b.2, b.3)))
+        });
+        let merges = merges
+            .into_iter()
+            .map(|(_, _, _, _, left, right)| (left.to_string(), right.to_string()))
+            .collect();
+        let mut builder = BPE::builder()
+            .vocab_and_merges(vocab, merges)
+            .byte_fallback(model.byte_fallback)
+            .fuse_unk(true);
+        if let Some(unk_id) = unk_id {
+            builder = builder.unk_token(model.pieces[unk_id].piece.clone());
+        }
+        try_api!(builder.build()).into()
+    } else {
+        let vocab = model
+            .pieces
+            .iter()
+            .map(|piece| (piece.piece.clone(), piece.score as f64))
+            .collect();
+        try_api!(Unigram::from(vocab, unk_id, model.byte_fallback)).into()
+    };
+
+    let mut tokenizer = Tokenizer::new(model_wrapper);
+    let mut normalizers = Vec::new();
+    if model.add_dummy_prefix {
+        normalizers.push(NormalizerWrapper::Prepend(Prepend::new(
+            SPIECE_UNDERLINE.to_string(),
+        )));
+    }
+    normalizers.push(NormalizerWrapper::Replace(try_api!(Replace::new(
+        " ",
+        SPIECE_UNDERLINE
+    ))));
+    tokenizer.with_normalizer(NormalizerWrapper::Sequence(NormalizerSequence::new(
+        normalizers,
+    )));
+    let mut decoders = vec![
+        DecoderWrapper::Replace(try_api!(Replace::new(SPIECE_UNDERLINE, " "))),
+        DecoderWrapper::ByteFallback(ByteFallback::new()),
+        DecoderWrapper::Fuse(Fuse::new()),
+    ];
+    if model.add_dummy_prefix {
+        decoders.push(DecoderWrapper::Strip(Strip::new(' ', 1, 0)));
+    }
+    tokenizer.with_decoder(DecoderWrapper::Sequence(DecoderSequence::new(decoders)));
+
+    let special_tokens = model
+        .pieces
+        .iter()
+        .filter(|piece| matches!(piece.kind, PieceType::Control | PieceType::Unknown))
+        .map(|piece| AddedToken::from(piece.piece.clone(), true))
+        .collect::<Vec<_>>();
+    tokenizer.add_special_tokens(&special_tokens);
+    let user_defined = model
+        .pieces
+        .iter()
+        .filter(|piece| piece.kind == PieceType::UserDefined)
+        .map(|piece| AddedToken::from(piece.piece.clone(), false))
+        .collect::<Vec<_>>();
+    tokenizer.add_tokens(&user_defined);
+    Ok(tokenizer)
+}
@@ -0,0 +1,200 @@
+//! Placement of incoming requests onto the engine replicas when serving in data-parallel mode.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// How requests are distributed over the data-parallel replicas.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum PlacementStrategy {
+    /// Cycle through the replicas.
+    RoundRobin,
+    /// Place on the replica with the fewest tokens queued or in flight.
+    LeastOutstandingTokens,
+    /// Place on the replica which has most of the prompt's prefix cached, then by load.
+    PrefixAffinity,
+    /// Smooth weighted round-robin, weighted by the free GPU blocks of each replica.
+    WeightedFreeBlocks,
+}
+
+/// A snapshot of the load of one replica, see `ReplicaCounters::get_load`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplicaLoad {
+    pub outstanding_tokens: usize,
+    pub free_gpu_blocks: usize,
+    pub cached_prefix_blocks: usize,
+    /// The replica cannot currently fit the prompt (or meet its TTFT objective).
+    pub saturated: bool,
+    /// Estimated time until the prompt would be prefilled on this replica, if known.
+    pub estimated_prefill_wait: Option<Duration>,
+}
+
+/// The load of one replica, kept up to date without locking its engine, which is held for the
+/// whole of each request. The server counts the prompt tokens of the requests it placed on the
+/// replica until they finish, see `admit`, and the engine publishes its free GPU blocks and
+/// prefill throughput after each step.
+#[derive(Debug, Default)]
+pub struct ReplicaCounters {
+    outstanding_tokens: AtomicUsize,
+    free_gpu_blocks: AtomicUsize,
+    block_size: AtomicUsize,
+    /// Prefill throughput in tokens per second as `f64` bits, 0 until it was measured.
+    prefill_tokens_per_sec: AtomicU64,
+}
+
+impl ReplicaCounters {
+    /// Count the `num_tokens` prompt tokens of a request placed on this replica as outstanding
+    /// until the returned guard is dropped, once the request finished.
+    pub fn admit(self: &Arc<Self>, num_tokens: usize) -> AdmittedRequest {
+        self.outstanding_tokens
+            .fetch_add(num_tokens, Ordering::AcqRel);
+        AdmittedRequest {
+            counters: self.clone(),
+            num_tokens,
+        }
+    }
+
+    /// Publish the state of the engine of the replica, see `LLMEngine::publish_load`.
+    pub fn publish(
+        &self,
+        free_gpu_blocks: usize,
+        block_size: usize,
+        prefill_tokens_per_sec: Option<f64>,
+    ) {
+        self.free_gpu_blocks
+            .store(free_gpu_blocks, Ordering::Release);
+        self.block_size.store(block_size, Ordering::Release);
+        self.prefill_tokens_per_sec.store(
+            prefill_tokens_per_sec.unwrap_or(0.).to_bits(),
+            Ordering::Release,
+        );
+    }
+
+    pub fn get_outstanding_tokens(&self) -> usize {
+        self.outstanding_tokens.load(Ordering::Acquire)
+    }
+
+    /// The load of the replica for a new request of `prompt_len` tokens, whose leading
+    /// `cached_prefix_blocks` blocks are in the prefix cache of the replica. The prompt is
+    /// estimated to be prefilled after the outstanding tokens.
+    pub fn get_load(&self, prompt_len: usize, cached_prefix_blocks: usize) -> ReplicaLoad {
+        let outstanding_tokens = self.get_outstanding_tokens();
+        let free_gpu_blocks = self.free_gpu_blocks.load(Ordering::Acquire);
+        let block_size = self.block_size.load(Ordering::Acquire).max(1);
+        let throughput = f64::from_bits(self.prefill_tokens_per_sec.load(Ordering::Acquire));
+        ReplicaLoad {
+            outstanding_tokens,
+            free_gpu_blocks,
+            cached_prefix_blocks,
+            saturated: free_gpu_blocks < prompt_len.div_ceil(block_size),
+            estimated_prefill_wait: (throughput > 0.).then(|| {
+                Duration::from_secs_f64((outstanding_tokens + prompt_len) as f64 / throughput)
+            }),
+        }
+    }
+}
+
+/// A request counted in the outstanding tokens of a replica, see `ReplicaCounters::admit`.
+#[derive(Debug)]
+pub struct AdmittedRequest {
+    counters: Arc<ReplicaCounters>,
+    num_tokens: usize,
+}
+
+impl Drop for AdmittedRequest {
+    fn drop(&mut self) {
+        self.counters
+            .outstanding_tokens
+            .fetch_sub(self.num_tokens, Ordering::AcqRel);
+    }
+}
+
+pub struct ReplicaPlacer {
+    strategy: PlacementStrategy,
+    next: usize,
+    current_weights: Vec<i64>,
+}
+
+impl ReplicaPlacer {
+    pub fn new(strategy: PlacementStrategy) -> Self {
+        Self {
+            strategy,
+            next: 0,
+            current_weights: Vec::new(),
+        }
+    }
+
+    /// Pick the replica for a request from the `loads` of the replicas. Saturated replicas are
+    /// only chosen if every replica is saturated, in which case the least loaded one is used.
+    pub fn place(&mut self, loads: &[ReplicaLoad]) -> usize {
+        assert!(!loads.is_empty());
+        let available = loads
+            .iter()
+            .enumerate()
+            .filter(|(_, load)| !load.saturated)
+            .collect::<Vec<_>>();
+        if available.is_empty() {
+            return Self::place_saturated(loads);
+        }
+
+        match self.strategy {
+            PlacementStrategy::RoundRobin => {
+                let idx = (0..loads.len())
+                    .map(|offset| (self.next + offset) % loads.len())
+                    .find(|i| available.iter().any(|(j, _)| i == j))
+                    .unwrap();
+                self.next = idx + 1;
+                idx
+            }
+            PlacementStrategy::LeastOutstandingTokens => {
+                available
+                    .iter()
+                    .min_by_key(|(_, load)| load.outstanding_tokens)
+                    .unwrap()
+                    .0
+            }
+            PlacementStrategy::PrefixAffinity => {
+                available
+                    .iter()
+                    .min_by_key(|(_, load)| {
+                        (
+                            std::cmp::Reverse(load.cached_prefix_blocks),
+                            load.outstanding_tokens,
+                        )
+                    })
+                    .unwrap()
+                    .0
+            }
+            PlacementStrategy::WeightedFreeBlocks => {
+                self.current_weights.resize(loads.len(), 0);
+                let total: i64 = available
+                    .iter()
+                    .map(|(_, load)| load.free_gpu_blocks as i64)
+                    .sum();
+                for (i, load) in &available {
+                    self.current_weights[*i] += load.free_gpu_blocks as i64;
+                }
+                let idx = available
+                    .iter()
+                    .max_by_key(|(i, _)| (self.current_weights[*i], std::cmp::Reverse(*i)))
+                    .unwrap()
+                    .0;
+                self.current_weights[idx] -= total;
+                idx
+            }
+        }
+    }
+
+    fn place_saturated(loads: &[ReplicaLoad]) -> usize {
+        loads
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, load)| load.outstanding_tokens)
+            .unwrap()
+            .0
+    }
+}
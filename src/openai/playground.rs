@@ -0,0 +1,23 @@
+//! A minimal chat UI served at `/playground`, to smoke-test a deployment from the browser. The
+//! page is embedded in the binary and only uses the public API endpoints.
+
+use actix_web::{get, http::header::ContentType, web, HttpResponse};
+
+use super::OpenAIServerData;
+
+const PLAYGROUND_HTML: &str = include_str!("../../res/playground.html");
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[get("/playground")]
+async fn playground(data: web::Data<OpenAIServerData<'static>>) -> HttpResponse {
+    let model_name = data.pipeline_config.read().unwrap().name.clone();
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(PLAYGROUND_HTML.replace("{{MODEL_NAME}}", &escape_html(&model_name)))
+}
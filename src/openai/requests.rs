@@ -2,19 +2,82 @@ use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
 
+use crate::scheduler::sequence::CancellationReason;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Messages {
     Map(Vec<HashMap<String, String>>),
+    /// Messages whose content is a list of content parts, e.g. with images.
+    Parts(Vec<ChatMessage>),
     Literal(String),
 }
 
+/// A message with OpenAI content parts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    /// None for assistant messages with only tool calls.
+    #[serde(default)]
+    pub content: Option<MessageContent>,
+    /// The tool calls of an assistant message, for the chat template of the model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<serde_json::Value>>,
+    /// The tool call a `tool` message answers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    /// The name of the tool whose result a `tool` message holds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text {
+        text: String,
+    },
+    /// An image for vision-language models, see `multimodal::load_image`.
+    ImageUrl {
+        image_url: ImageUrl,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageUrl {
+    /// A base64 `data:` URL or an HTTP(S) URL.
+    pub url: String,
+    /// The OpenAI detail level, ignored: images are encoded at the resolution of the model.
+    #[serde(default)]
+    pub detail: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum StopTokens {
     Multi(Vec<String>),
     Single(String),
 }
 
+/// How the generated tokens are returned to the client.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DetokenizationMode {
+    /// Decode the tokens to text, returned in `content`.
+    #[default]
+    Text,
+    /// Return the raw bytes of the tokens in `bytes`, without UTF-8 validation.
+    Bytes,
+    /// Skip detokenization and return only the token ids in `token_ids`.
+    TokenIds,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatCompletionRequest {
     pub model: String,
@@ -52,4 +115,134 @@ pub struct ChatCompletionRequest {
     pub skip_special_tokens: Option<bool>, //false
     #[serde(default)]
     pub stop_token_ids: Option<Vec<usize>>, //[]
+    #[serde(default)]
+    pub return_timings: Option<bool>, //false
+    #[serde(default)]
+    pub session_id: Option<String>, //None
+    #[serde(default)]
+    pub ttft_slo_ms: Option<u64>, //None
+    #[serde(default)]
+    pub detokenize: Option<DetokenizationMode>, //text
+    #[serde(default)]
+    pub disable_prefix_cache: Option<bool>, //false
+    #[serde(default)]
+    pub disable_speculation: Option<bool>, //false
+    /// Deliver at most this many tokens per second to a streaming client, independent of the
+    /// generation speed. A chunk of several tokens, e.g. of a speculative step, is followed by a
+    /// pause of as many tokens.
+    #[serde(default)]
+    pub stream_max_tokens_per_sec: Option<f64>, //None
+    /// For reasoning models, close the `<think>` span after this many tokens and continue with
+    /// the final answer.
+    #[serde(default)]
+    pub max_thinking_tokens: Option<usize>, //None
+    /// Softmax scale of the attention layers instead of the model's, e.g. for long-context
+    /// entropy control experiments.
+    #[serde(default)]
+    pub attention_scale: Option<f32>, //None
+    /// Divide the attention scores by this temperature before the softmax.
+    #[serde(default)]
+    pub attention_temperature: Option<f32>, //None
+    /// Fill-in-the-middle for code models: generate the code between the literal prompt and
+    /// this suffix.
+    #[serde(default)]
+    pub suffix: Option<String>, //None
+    /// The definitions of the tools the model may call, for the chat template of the model.
+    #[serde(default)]
+    pub tools: Option<Vec<serde_json::Value>>, //None
+    /// Open the turn of the assistant after the messages.
+    #[serde(default)]
+    pub add_generation_prompt: Option<bool>, //true
+}
+
+/// Body of the conversation creation endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateConversationRequest {
+    /// Initial history, e.g. a system message.
+    #[serde(default)]
+    pub messages: Vec<HashMap<String, String>>,
+}
+
+/// Query parameters of the cancel endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CancelRequestQuery {
+    /// Reported to the client, e.g. `moderation` when cancelled by a moderation service.
+    #[serde(default)]
+    pub reason: Option<CancellationReason>,
+}
+
+/// The body of the model reload endpoint. An empty body reloads the current checkpoint with the
+/// current adapter.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReloadRequest {
+    /// Huggingface model id of the new checkpoint, whose architecture is detected from its
+    /// config.
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub revision: Option<String>,
+    /// LoRA adapter replacing the current one, a local directory or a Huggingface repository.
+    #[serde(default)]
+    pub lora: Option<String>,
+    /// Load the model without a LoRA adapter.
+    #[serde(default)]
+    pub disable_lora: bool,
+}
+
+/// The input of the embeddings endpoint: a text, token ids, or a batch of either.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    Single(String),
+    Multi(Vec<String>),
+    Tokens(Vec<u32>),
+    MultiTokens(Vec<Vec<u32>>),
+}
+
+/// How the embeddings are returned.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmbeddingEncodingFormat {
+    #[default]
+    Float,
+    /// The little-endian bytes of the f32 values, base64-encoded.
+    Base64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddingRequest {
+    pub model: String,
+    pub input: EmbeddingInput,
+    #[serde(default)]
+    pub encoding_format: Option<EmbeddingEncodingFormat>, //float
+    #[serde(default)]
+    pub user: Option<String>, //None
+}
+
+/// A document to rerank, a text or an object with a `text` field.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RerankDocument {
+    Text(String),
+    Object { text: String },
+}
+
+impl RerankDocument {
+    pub fn text(&self) -> &str {
+        match self {
+            Self::Text(text) | Self::Object { text } => text,
+        }
+    }
+}
+
+/// Body of the Cohere-compatible rerank endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RerankRequest {
+    pub model: String,
+    pub query: String,
+    pub documents: Vec<RerankDocument>,
+    #[serde(default)]
+    pub top_n: Option<usize>, //all documents
+    #[serde(default)]
+    pub return_documents: Option<bool>, //false
 }
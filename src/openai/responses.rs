@@ -1,9 +1,13 @@
+use std::collections::HashMap;
+
 use actix_web::error;
 use candle_sampling::logits_processor::Logprobs;
 use derive_more::{Display, Error};
 
 use serde::{Deserialize, Serialize};
 
+use crate::{openai::metrics::MetricsSnapshot, scheduler::sequence::CancellationReason};
+
 #[derive(Debug, Display, Error, Serialize)]
 #[display(fmt = "Error: {}", data)]
 pub struct APIError {
@@ -48,11 +52,124 @@ pub struct ChatCompletionUsageResponse {
     pub total_tokens: usize,
 }
 
+/// A conversation stored on the server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConversationResponse {
+    pub id: String,
+    pub object: &'static str,
+    pub created: u64,
+    pub messages: Vec<HashMap<String, String>>,
+}
+
+/// The metrics of the primary engine followed by those of the data-parallel replicas, then those
+/// of the engines of the other served models.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetricsResponse {
+    pub object: &'static str,
+    pub engines: Vec<MetricsSnapshot>,
+}
+
+/// A served model, see `ModelsResponse`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelCard {
+    pub id: String,
+    pub object: &'static str,
+    pub created: u64,
+    pub owned_by: &'static str,
+    /// The context length of the model in tokens.
+    pub max_model_len: usize,
+}
+
+/// The models served by this server, in the order they were loaded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelsResponse {
+    pub object: &'static str,
+    pub data: Vec<ModelCard>,
+}
+
+/// The model served after a reload, and how long loading it and draining the requests on the
+/// previous model took.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReloadResponse {
+    pub object: &'static str,
+    pub model: String,
+    pub load_secs: f64,
+    pub drain_secs: f64,
+}
+
+/// An embedding as a list of floats, or base64-encoded with the `base64` encoding format.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EmbeddingVector {
+    Float(Vec<f32>),
+    Base64(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddingData {
+    pub object: &'static str,
+    pub embedding: EmbeddingVector,
+    pub index: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddingUsageResponse {
+    pub prompt_tokens: usize,
+    pub total_tokens: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddingResponse {
+    pub object: &'static str,
+    pub data: Vec<EmbeddingData>,
+    pub model: String,
+    pub usage: EmbeddingUsageResponse,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RerankResultDocument {
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RerankResult {
+    /// The index of the document in the request.
+    pub index: usize,
+    pub relevance_score: f32,
+    /// The document, returned with `return_documents`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub document: Option<RerankResultDocument>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RerankBilledUnits {
+    pub input_tokens: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RerankMeta {
+    pub billed_units: RerankBilledUnits,
+}
+
+/// The documents of a rerank request by decreasing relevance.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RerankResponse {
+    pub id: String,
+    pub results: Vec<RerankResult>,
+    pub meta: RerankMeta,
+}
+
 // tool_calls, function_call not supported!
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatChoiceData {
     pub content: Option<String>,
     pub role: String,
+    /// Raw token bytes, returned with the `bytes` detokenization mode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<Vec<u8>>,
+    /// Generated token ids, returned with the `token_ids` detokenization mode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_ids: Option<Vec<u32>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -64,10 +181,21 @@ pub struct WrapperLogprobs {
 pub struct ChatChoice {
     pub message: ChatChoiceData,
     pub finish_reason: Option<String>,
+    /// Why the engine aborted the choice, set with the `abort` finish reason.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cancellation_reason: Option<CancellationReason>,
     pub index: usize,
     pub logprobs: Option<WrapperLogprobs>,
 }
 
+/// Per-request latency information, returned when `return_timings` is set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatCompletionTimings {
+    pub queue_time_ms: Option<f64>,
+    pub time_to_first_token_ms: Option<f64>,
+    pub mean_inter_token_latency_ms: Option<f64>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatCompletionResponse {
     pub id: String,
@@ -76,6 +204,17 @@ pub struct ChatCompletionResponse {
     pub model: String,
     pub object: &'static str,
     pub usage: ChatCompletionUsageResponse,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timings: Option<ChatCompletionTimings>,
+}
+
+/// The final chunk of a stream which the engine aborted, with the usage up to that point.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatCompletionCancellation {
+    pub id: String,
+    pub object: &'static str,
+    pub reason: CancellationReason,
+    pub usage: ChatCompletionUsageResponse,
 }
 
 // tool_calls, function_call not supported!
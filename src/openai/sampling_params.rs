@@ -1,11 +1,255 @@
-use std::ops::Range;
+use std::{fmt, ops::Range};
 
 use candle_sampling::logits_processor::{LogitsProcessor, SamplingMethod};
+use serde::Deserialize;
 use tokenizers::Tokenizer;
 
-use super::{requests::StopTokens, responses::APIError};
+use super::{
+    requests::{ChatCompletionRequest, StopTokens},
+    responses::APIError,
+};
 
 const SAMPLING_EPS: f32 = 1e-5;
+const DEFAULT_TEMPERATURE: f32 = 0.7;
+
+/// Why a set of sampling parameters was rejected.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SamplingParamsError {
+    InvalidN(usize),
+    BestOfLessThanN {
+        n: usize,
+        best_of: usize,
+    },
+    OutOfRange {
+        name: &'static str,
+        value: f32,
+        range: &'static str,
+    },
+    InvalidTopK(isize),
+    TopKWithTopP {
+        top_k: isize,
+        top_p: f32,
+    },
+    InvalidMaxTokens(usize),
+    ContextLengthExceeded {
+        max_model_len: usize,
+        prompt_len: usize,
+        max_tokens: usize,
+    },
+    /// A parameter which is incompatible with the selected sampling type.
+    Incompatible(String),
+}
+
+impl fmt::Display for SamplingParamsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidN(n) => write!(f, "n must be at least 1, got {n}."),
+            Self::BestOfLessThanN { n, best_of } => write!(
+                f,
+                "best_of must be greater than or equal to n, got n={n} and best_of={best_of}"
+            ),
+            Self::OutOfRange { name, value, range } => {
+                write!(f, "{name} must be in {range}, got {value}")
+            }
+            Self::InvalidTopK(top_k) => {
+                write!(f, "top_k must be -1 (disable) or at least 1, got {top_k}")
+            }
+            Self::TopKWithTopP { top_k, top_p } => write!(
+                f,
+                "top_k and top_p cannot be combined, got top_k={top_k} and top_p={top_p}"
+            ),
+            Self::InvalidMaxTokens(max_tokens) => {
+                write!(f, "max_tokens must be at least 1, got {max_tokens}")
+            }
+            Self::ContextLengthExceeded {
+                max_model_len,
+                prompt_len,
+                max_tokens,
+            } => write!(
+                f,
+                "This model's maximum context length is {} tokens. \
+                However, you requested {} tokens ({} in the messages, \
+                {} in the completion). Please reduce the length of the \
+                messages or completion.",
+                max_model_len,
+                prompt_len + max_tokens,
+                prompt_len,
+                max_tokens
+            ),
+            Self::Incompatible(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+impl std::error::Error for SamplingParamsError {}
+
+impl From<SamplingParamsError> for APIError {
+    fn from(value: SamplingParamsError) -> Self {
+        APIError::new(value.to_string())
+    }
+}
+
+/// Model-provided defaults (`generation_config.json`), used for any option a request leaves unset.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct GenerationConfig {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub top_k: Option<isize>,
+    pub repetition_penalty: Option<f32>,
+    pub max_new_tokens: Option<usize>,
+}
+
+/// Sampling options as given by a request, before defaults are resolved. Use `normalize` to obtain
+/// the canonical `SamplingParams`.
+#[derive(Clone, Debug, Default)]
+pub struct SamplingOptions {
+    pub n: Option<usize>,
+    pub best_of: Option<usize>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub repetition_penalty: Option<f32>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub top_k: Option<isize>,
+    pub use_beam_search: Option<bool>,
+    pub length_penalty: Option<f32>,
+    pub early_stopping: Option<EarlyStoppingCondition>,
+    pub stop: Option<StopTokens>,
+    pub stop_token_ids: Option<Vec<usize>>,
+    pub ignore_eos: Option<bool>,
+    pub max_tokens: Option<usize>,
+    pub logprobs: Option<usize>,
+    pub prompt_logprobs: Option<usize>,
+    pub skip_special_tokens: Option<bool>,
+    pub max_thinking_tokens: Option<usize>,
+    pub attention_scale: Option<f32>,
+    pub attention_temperature: Option<f32>,
+}
+
+impl From<&ChatCompletionRequest> for SamplingOptions {
+    fn from(request: &ChatCompletionRequest) -> Self {
+        Self {
+            n: request.n,
+            best_of: request.best_of,
+            presence_penalty: request.presence_penalty,
+            frequency_penalty: request.frequency_penalty,
+            temperature: request.temperature,
+            top_p: request.top_p,
+            top_k: request.top_k,
+            use_beam_search: request.use_beam_search,
+            stop: request.stop.clone(),
+            stop_token_ids: request.stop_token_ids.clone(),
+            ignore_eos: request.ignore_eos,
+            max_tokens: request.max_tokens,
+            skip_special_tokens: request.skip_special_tokens,
+            max_thinking_tokens: request.max_thinking_tokens,
+            attention_scale: request.attention_scale,
+            attention_temperature: request.attention_temperature,
+            ..Default::default()
+        }
+    }
+}
+
+impl SamplingOptions {
+    /// Resolve defaults (request, then `generation_config`, then built-in), normalize equivalent
+    /// settings and validate the result.
+    ///
+    /// - `max_tokens` defaults to the remaining context (capped by `max_new_tokens`), an explicit
+    ///   value which does not fit into the context is rejected.
+    /// - Greedy sampling (temperature 0) ignores `top_p`/`top_k`, so they are reset.
+    /// - `top_k == 0` is treated as disabled.
+    /// - A `top_k` or `top_p` default of `generation_config` which conflicts with the other one
+    ///   is dropped.
+    pub fn normalize(
+        self,
+        generation_config: &GenerationConfig,
+        prompt_len: usize,
+        max_model_len: usize,
+    ) -> Result<SamplingParams, SamplingParamsError> {
+        let remaining = max_model_len.saturating_sub(prompt_len);
+        let max_tokens = match self.max_tokens {
+            Some(max_tokens) if prompt_len + max_tokens > max_model_len => {
+                return Err(SamplingParamsError::ContextLengthExceeded {
+                    max_model_len,
+                    prompt_len,
+                    max_tokens,
+                })
+            }
+            Some(max_tokens) => max_tokens,
+            None => generation_config
+                .max_new_tokens
+                .map_or(remaining, |max_new_tokens| max_new_tokens.min(remaining)),
+        };
+
+        let use_beam_search = self.use_beam_search.unwrap_or(false);
+        // Beam search is deterministic, so the model's sampling defaults do not apply.
+        let (mut temperature, mut top_p, mut top_k) = if use_beam_search {
+            (
+                self.temperature.unwrap_or(0.),
+                self.top_p.unwrap_or(1.),
+                self.top_k.unwrap_or(-1),
+            )
+        } else {
+            (
+                self.temperature
+                    .or(generation_config.temperature)
+                    .unwrap_or(DEFAULT_TEMPERATURE),
+                self.top_p.or(generation_config.top_p).unwrap_or(1.),
+                self.top_k.or(generation_config.top_k).unwrap_or(-1),
+            )
+        };
+        if top_k == 0 {
+            top_k = -1;
+        }
+        // Only one of top_k and top_p can be active, see `get_logits_processor`. A value of the
+        // request overrides the model's default for the other one, and of two defaults (e.g.
+        // Qwen2 sets both) top_p is kept. Two values of the request are rejected by `verify`.
+        if top_k > 0 && top_p < 1. {
+            if self.top_k.is_none() {
+                top_k = -1;
+            } else if self.top_p.is_none() {
+                top_p = 1.;
+            }
+        }
+        if !use_beam_search && temperature < SAMPLING_EPS {
+            temperature = 0.;
+            top_p = 1.;
+            top_k = -1;
+        }
+
+        let n = self.n.unwrap_or(1);
+        let this = SamplingParams {
+            n,
+            best_of: self.best_of.unwrap_or(n),
+            presence_penalty: self.presence_penalty.unwrap_or(0.),
+            frequency_penalty: self.frequency_penalty.unwrap_or(0.),
+            repetition_penalty: self
+                .repetition_penalty
+                .or(generation_config.repetition_penalty)
+                .unwrap_or(1.),
+            temperature,
+            top_p,
+            top_k,
+            use_beam_search,
+            length_penalty: self.length_penalty.unwrap_or(1.),
+            early_stopping: self
+                .early_stopping
+                .unwrap_or(EarlyStoppingCondition::UnlikelyBetterCandidates),
+            stop: self.stop,
+            stop_token_ids: self.stop_token_ids.unwrap_or_default(),
+            ignore_eos: self.ignore_eos.unwrap_or(false),
+            max_tokens,
+            logprobs: self.logprobs,
+            prompt_logprobs: self.prompt_logprobs,
+            skip_special_tokens: self.skip_special_tokens.unwrap_or(true),
+            max_thinking_tokens: self.max_thinking_tokens,
+            attention_scale: self.attention_scale,
+            attention_temperature: self.attention_temperature,
+        };
+        this.verify()?;
+        Ok(this)
+    }
+}
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum EarlyStoppingCondition {
@@ -76,6 +320,14 @@ pub struct SamplingParams {
     /// Skip special toks in output.
     /// rec. default = true
     pub skip_special_tokens: bool,
+    /// Max number of toks to gen inside the `<think>` span of reasoning models. Once exhausted,
+    /// the closing tag is forced and generation continues with the final answer.
+    pub max_thinking_tokens: Option<usize>,
+    /// Softmax scale of the attention layers instead of the model's.
+    pub attention_scale: Option<f32>,
+    /// Divides the attention scores before the softmax, > 1 flattens and < 1 sharpens the
+    /// attention distribution.
+    pub attention_temperature: Option<f32>,
 }
 
 impl SamplingParams {
@@ -99,6 +351,9 @@ impl SamplingParams {
         logprobs: Option<usize>,
         prompt_logprobs: Option<usize>,
         skip_special_tokens: bool,
+        max_thinking_tokens: Option<usize>,
+        attention_scale: Option<f32>,
+        attention_temperature: Option<f32>,
     ) -> Result<Self, APIError> {
         let this = Self {
             n,
@@ -119,18 +374,12 @@ impl SamplingParams {
             logprobs,
             prompt_logprobs,
             skip_special_tokens,
+            max_thinking_tokens,
+            attention_scale,
+            attention_temperature,
         };
 
-        this.verify_args()?;
-        if this.use_beam_search {
-            this.verify_beam_search()?;
-        } else {
-            this.verify_non_beam_search()?;
-            if this.temperature < SAMPLING_EPS {
-                this.verify_greedy_sampling()?;
-            }
-        }
-
+        this.verify()?;
         Ok(this)
     }
 
@@ -172,30 +421,42 @@ impl SamplingParams {
         }
     }
 
-    fn verify_args(&self) -> Result<(), APIError> {
+    fn verify(&self) -> Result<(), SamplingParamsError> {
+        self.verify_args()?;
+        if self.use_beam_search {
+            self.verify_beam_search()
+        } else {
+            self.verify_non_beam_search()?;
+            if self.temperature < SAMPLING_EPS {
+                self.verify_greedy_sampling()?;
+            }
+            Ok(())
+        }
+    }
+
+    fn verify_args(&self) -> Result<(), SamplingParamsError> {
         if self.n < 1 {
-            return Err(APIError::new(format!(
-                "n must be at leas 1, got {}.",
-                self.n
-            )));
+            return Err(SamplingParamsError::InvalidN(self.n));
         }
         if self.best_of < self.n {
-            return Err(APIError::new(format!(
-                "best_of must be greater than or equal to n, got n={} and best_of={}",
-                self.n, self.best_of
-            )));
+            return Err(SamplingParamsError::BestOfLessThanN {
+                n: self.n,
+                best_of: self.best_of,
+            });
         }
         if !(-2.0..=2.0).contains(&self.presence_penalty) {
-            return Err(APIError::new(format!(
-                "presence_penalty must be in [-2, 2], got {}",
-                self.presence_penalty
-            )));
+            return Err(SamplingParamsError::OutOfRange {
+                name: "presence_penalty",
+                value: self.presence_penalty,
+                range: "[-2, 2]",
+            });
         }
         if !(-2.0..=2.0).contains(&self.frequency_penalty) {
-            return Err(APIError::new(format!(
-                "frequency_penalty must be in [-2, 2], got {}",
-                self.frequency_penalty
-            )));
+            return Err(SamplingParamsError::OutOfRange {
+                name: "frequency_penalty",
+                value: self.frequency_penalty,
+                range: "[-2, 2]",
+            });
         }
         if !(Range {
             start: 0.0,
@@ -204,72 +465,109 @@ impl SamplingParams {
         .contains(&self.repetition_penalty)
             || self.repetition_penalty == 0.0
         {
-            return Err(APIError::new(format!(
-                "repetition_penalty must be in (0, 2], got {}",
-                self.repetition_penalty
-            )));
+            return Err(SamplingParamsError::OutOfRange {
+                name: "repetition_penalty",
+                value: self.repetition_penalty,
+                range: "(0, 2]",
+            });
         }
         if self.temperature < 0.0 {
-            return Err(APIError::new(format!(
-                "temperature must be non-negative, got {}",
-                self.temperature
-            )));
+            return Err(SamplingParamsError::OutOfRange {
+                name: "temperature",
+                value: self.temperature,
+                range: "[0, inf)",
+            });
+        }
+        if self.top_p <= 0.0 || self.top_p > 1.0 {
+            return Err(SamplingParamsError::OutOfRange {
+                name: "top_p",
+                value: self.top_p,
+                range: "(0, 1]",
+            });
+        }
+        if let Some(attention_scale) = self.attention_scale.filter(|scale| *scale <= 0.0) {
+            return Err(SamplingParamsError::OutOfRange {
+                name: "attention_scale",
+                value: attention_scale,
+                range: "(0, inf)",
+            });
+        }
+        if let Some(attention_temperature) = self
+            .attention_temperature
+            .filter(|temperature| *temperature <= 0.0)
+        {
+            return Err(SamplingParamsError::OutOfRange {
+                name: "attention_temperature",
+                value: attention_temperature,
+                range: "(0, inf)",
+            });
+        }
+        if self.top_k < -1 || self.top_k == 0 {
+            return Err(SamplingParamsError::InvalidTopK(self.top_k));
+        }
+        if self.top_k > 0 && self.top_p < 1.0 {
+            // See `get_logits_processor`, only one of the two may be active.
+            return Err(SamplingParamsError::TopKWithTopP {
+                top_k: self.top_k,
+                top_p: self.top_p,
+            });
         }
         if self.max_tokens < 1 {
-            return Err(APIError::new(format!(
-                "max_tokens must be at least 1, got {}",
-                self.max_tokens
-            )));
+            return Err(SamplingParamsError::InvalidMaxTokens(self.max_tokens));
         }
         Ok(())
     }
 
-    fn verify_beam_search(&self) -> Result<(), APIError> {
+    fn verify_beam_search(&self) -> Result<(), SamplingParamsError> {
         if self.best_of <= 1 {
-            return Err(APIError::new(format!(
+            return Err(SamplingParamsError::Incompatible(format!(
                 "best_of must be greater than 1 when using beam search. Got {}",
                 self.best_of
             )));
         }
         if self.temperature > SAMPLING_EPS {
-            return Err(APIError::new_str(
-                "temperature must be 0 when using beam search",
+            return Err(SamplingParamsError::Incompatible(
+                "temperature must be 0 when using beam search".to_string(),
             ));
         }
         if self.top_p < 1.0 - SAMPLING_EPS {
-            return Err(APIError::new_str("top_p must be 1 when using beam search"));
+            return Err(SamplingParamsError::Incompatible(
+                "top_p must be 1 when using beam search".to_string(),
+            ));
         }
         if self.top_k != -1 {
-            return Err(APIError::new_str("top_k must be -1 when using beam search"));
+            return Err(SamplingParamsError::Incompatible(
+                "top_k must be -1 when using beam search".to_string(),
+            ));
         }
         Ok(())
     }
 
-    fn verify_non_beam_search(&self) -> Result<(), APIError> {
+    fn verify_non_beam_search(&self) -> Result<(), SamplingParamsError> {
         if self.early_stopping != EarlyStoppingCondition::UnlikelyBetterCandidates {
-            return Err(APIError::new_str("early_stopping is not effective and must be UnlikelyBetterCandidates when not using beam search."));
+            return Err(SamplingParamsError::Incompatible("early_stopping is not effective and must be UnlikelyBetterCandidates when not using beam search.".to_string()));
         }
         if self.length_penalty < 1.0 - SAMPLING_EPS || self.length_penalty > 1.0 + SAMPLING_EPS {
-            return Err(APIError::new_str("length_penalty is not effective and must be the default value of 1.0 when not using beam search."));
+            return Err(SamplingParamsError::Incompatible("length_penalty is not effective and must be the default value of 1.0 when not using beam search.".to_string()));
         }
         Ok(())
     }
 
-    fn verify_greedy_sampling(&self) -> Result<(), APIError> {
+    fn verify_greedy_sampling(&self) -> Result<(), SamplingParamsError> {
         if self.best_of > 1 {
-            return Err(APIError::new(format!(
+            return Err(SamplingParamsError::Incompatible(format!(
                 "best_of must be 1 when using greedy sampling. Got {}.",
                 self.best_of
             )));
         }
         if self.top_p < 1.0 - SAMPLING_EPS {
-            return Err(APIError::new_str(
-                "top_p must be 1 when using greedy sampling.",
+            return Err(SamplingParamsError::Incompatible(
+                "top_p must be 1 when using greedy sampling.".to_string(),
             ));
         }
-        if self.top_p != -1.0 {
-            return Err(APIError::new_str(
-                "top_k must be -1 when using greedy sampling.",
+        if self.top_k != -1 {
+            return Err(SamplingParamsError::Incompatible(
+                "top_k must be -1 when using greedy sampling.".to_string(),
             ));
         }
         Ok(())
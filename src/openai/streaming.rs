@@ -1,17 +1,44 @@
-use std::{error::Error, sync::Arc};
+use std::{collections::VecDeque, error::Error, pin::Pin, sync::Arc, task::Poll, time::Duration};
 
 use actix_web::web::Bytes;
-use futures::Stream;
-use tokio::sync::mpsc::{channel, Receiver, Sender};
+use futures::{Future, Stream};
+use tokio::{
+    sync::mpsc::{channel, Receiver, Sender},
+    time::{sleep_until, Instant, Sleep},
+};
 
 pub(crate) type SenderError = Arc<dyn Error + Send + Sync>;
 
-pub(crate) fn new_streaming_conn() -> (Sender<Result<Bytes, SenderError>>, Client) {
+/// A chunk of a streaming response with the number of tokens it holds, which the pacing counts.
+pub(crate) type StreamChunk = (Result<Bytes, SenderError>, usize);
+
+/// Create a streaming connection. If `max_tokens_per_sec` is set, tokens are delivered to the
+/// client at most at this rate and buffered in the meantime, so generation is not slowed down.
+pub(crate) fn new_streaming_conn(max_tokens_per_sec: Option<f64>) -> (Sender<StreamChunk>, Client) {
     let (tx, rx) = channel(128);
-    (tx, Client(rx))
+    let pacer = max_tokens_per_sec.map(|rate| Pacer {
+        interval: Duration::from_secs_f64(1. / rate),
+        buffer: VecDeque::new(),
+        delay: None,
+        closed: false,
+    });
+    (tx, Client { rx, pacer })
+}
+
+pub(crate) struct Client {
+    rx: Receiver<StreamChunk>,
+    pacer: Option<Pacer>,
 }
 
-pub(crate) struct Client(Receiver<Result<Bytes, SenderError>>);
+/// Releases buffered chunks no faster than one token per `interval`: a chunk of `n` tokens is
+/// followed by a pause of `n` intervals.
+struct Pacer {
+    interval: Duration,
+    buffer: VecDeque<StreamChunk>,
+    /// Earliest time the next chunk may be released.
+    delay: Option<Pin<Box<Sleep>>>,
+    closed: bool,
+}
 
 impl Stream for Client {
     type Item = Result<Bytes, SenderError>;
@@ -20,6 +47,36 @@ impl Stream for Client {
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        self.0.poll_recv(cx)
+        let Client { rx, pacer } = &mut *self;
+        let Some(pacer) = pacer else {
+            return rx.poll_recv(cx).map(|chunk| chunk.map(|(chunk, _)| chunk));
+        };
+
+        // Drain the channel eagerly so the sender never waits on the pacing.
+        while !pacer.closed {
+            match rx.poll_recv(cx) {
+                Poll::Ready(Some(chunk)) => pacer.buffer.push_back(chunk),
+                Poll::Ready(None) => pacer.closed = true,
+                Poll::Pending => break,
+            }
+        }
+
+        if pacer.buffer.is_empty() {
+            return if pacer.closed {
+                Poll::Ready(None)
+            } else {
+                Poll::Pending
+            };
+        }
+        if let Some(delay) = &mut pacer.delay {
+            if delay.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+        let (chunk, num_tokens) = pacer.buffer.pop_front().unwrap();
+        pacer.delay = Some(Box::pin(sleep_until(
+            Instant::now() + pacer.interval * num_tokens as u32,
+        )));
+        Poll::Ready(Some(chunk))
     }
 }
@@ -6,3 +6,14 @@ pub(crate) fn get_created_time_secs() -> u64 {
         .expect("Time travel has occurred...")
         .as_secs()
 }
+
+pub const GIB: f64 = (1 << 30) as f64;
+
+/// Total host memory in bytes, read from `/proc/meminfo`. Returns `None` where that is unavailable.
+pub fn get_total_host_memory() -> Option<usize> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|line| line.starts_with("MemTotal:"))?;
+    // The value is given in kB
+    let kb = line.split_whitespace().nth(1)?.parse::<usize>().ok()?;
+    Some(kb * 1024)
+}
@@ -0,0 +1,495 @@
+//! The implementations of the prompt and decode attention behind a common interface, so that new
+//! kernels can be added without touching the models. A backend is picked for each phase by what
+//! the device and the layer support, `--attention-backend` overrides the choice.
+
+use candle_core::{DType, Tensor};
+
+use crate::{
+    backend::{
+        cpu::paged_attention_cpu, key_cache_block_dims, num_kv_splits, paged_attention_v1,
+        paged_attention_v2, AttentionComputeDtype, PARTITION_SIZE,
+    },
+    openai::responses::APIError,
+    try_api,
+};
+
+use super::{
+    attn_bias::AttentionBias,
+    blockwise_attention::BLOCKWISE_CHUNK_SIZE,
+    input_metadata::{DecodeMetadata, InputMetadata, PromptMetadata},
+    PagedAttention,
+};
+
+/// The attention backend requested with `--attention-backend`. A backend which does not support
+/// a phase, the device or the layer (e.g. FlashAttention-2 for decoding) is replaced by the
+/// automatic choice for that phase.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum AttentionBackendKind {
+    /// The fastest supported backend of each phase.
+    #[default]
+    Auto,
+    /// Masked attention over the padded prompts, on any device.
+    Naive,
+    /// FlashAttention-2 over the packed prompts, on Ampere or newer GPUs.
+    Flash,
+    /// Chunked prompt attention with a running softmax, on any device.
+    Blockwise,
+    /// Single-pass paged decode attention.
+    PagedV1,
+    /// Split-K paged decode attention, for long contexts.
+    PagedV2,
+    /// The reference paged decode attention on the CPU.
+    Cpu,
+}
+
+impl AttentionBackendKind {
+    /// The requested backend, None for the automatic choice.
+    fn backend(&self) -> Option<&'static dyn AttentionBackend> {
+        match self {
+            Self::Auto => None,
+            Self::Naive => Some(&NaiveBackend),
+            Self::Flash => Some(&FlashBackend),
+            Self::Blockwise => Some(&BlockwiseBackend),
+            Self::PagedV1 => Some(&PagedV1Backend),
+            Self::PagedV2 => Some(&PagedV2Backend),
+            Self::Cpu => Some(&CpuBackend),
+        }
+    }
+}
+
+/// An implementation of the prompt attention, within the packed prompts of a step, or of the
+/// decode attention, over the paged KV cache. A backend implements the phases it supports.
+pub trait AttentionBackend: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Whether the backend can attend the prompts of `query` for the layer `attn`.
+    fn supports_prompt(
+        &self,
+        _attn: &PagedAttention,
+        _query: &Tensor,
+        _prompt: &PromptMetadata,
+    ) -> bool {
+        false
+    }
+
+    /// Whether the backend can attend the cache for the generation tokens of `query` for the
+    /// layer `attn`.
+    fn supports_decode(&self, _attn: &PagedAttention, _query: &Tensor) -> bool {
+        false
+    }
+
+    /// query: shape = [num_prompt_tokens, num_heads, head_size]
+    /// key: shape = [num_prompt_tokens, num_kv_heads, head_size]
+    /// value: shape = [num_prompt_tokens, num_kv_heads, head_size]
+    ///
+    /// Returns the output, shape = [num_prompt_tokens, num_heads, head_size].
+    fn prompt_attention(
+        &self,
+        _attn: &PagedAttention,
+        _query: Tensor,
+        _key: Tensor,
+        _value: Tensor,
+        _input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        Err(APIError::new(format!(
+            "The {} attention backend does not support prompts.",
+            self.name()
+        )))
+    }
+
+    /// query: shape = [num_generation_tokens, num_heads, head_size], see
+    ///     `PagedAttention::_paged_attention`.
+    ///
+    /// Returns the output, shape = [num_generation_tokens, num_heads, head_size].
+    #[allow(clippy::too_many_arguments)]
+    fn decode_attention(
+        &self,
+        _attn: &PagedAttention,
+        _query: Tensor,
+        _key_cache: Tensor,
+        _value_cache: Tensor,
+        _decode: &DecodeMetadata,
+        _kv_cache_dtype: &str,
+        _sliding_window: Option<usize>,
+        _alibi_slopes: Option<Tensor>,
+        _attention_compute: AttentionComputeDtype,
+    ) -> Result<Tensor, APIError> {
+        Err(APIError::new(format!(
+            "The {} attention backend does not support decoding.",
+            self.name()
+        )))
+    }
+}
+
+/// Prompts from this length on are attended blockwise if FlashAttention-2 is unavailable, as the
+/// padded score matrix of the naive attention grows quadratically with the prompt length.
+const BLOCKWISE_MIN_PROMPT_LEN: usize = 16 * BLOCKWISE_CHUNK_SIZE;
+
+/// The backend of the prompt attention: the requested one if it supports the prompts, otherwise
+/// FlashAttention-2 where supported, the blockwise attention for long prompts and the naive
+/// attention elsewhere.
+pub(crate) fn select_prompt_backend(
+    kind: AttentionBackendKind,
+    attn: &PagedAttention,
+    query: &Tensor,
+    prompt: &PromptMetadata,
+) -> &'static dyn AttentionBackend {
+    if let Some(backend) = kind
+        .backend()
+        .filter(|backend| backend.supports_prompt(attn, query, prompt))
+    {
+        return backend;
+    }
+    if FlashBackend.supports_prompt(attn, query, prompt) {
+        &FlashBackend
+    } else if prompt.max_prompt_len >= BLOCKWISE_MIN_PROMPT_LEN
+        && BlockwiseBackend.supports_prompt(attn, query, prompt)
+    {
+        &BlockwiseBackend
+    } else {
+        &NaiveBackend
+    }
+}
+
+/// The backend of the decode attention: the requested one if it supports the generation tokens,
+/// otherwise the reference attention on the CPU and V1 or V2 elsewhere. V1 avoids the reduction
+/// pass, which pays off when the context fits in one partition or there are already enough
+/// (sequence, head) pairs to occupy the GPU. Longer contexts are split across the SMs by V2
+/// (split-K). An autotuned crossover (`v2_min_context_len`) replaces the heuristic.
+pub(crate) fn select_decode_backend(
+    kind: AttentionBackendKind,
+    attn: &PagedAttention,
+    query: &Tensor,
+    max_context_len: usize,
+    v2_min_context_len: Option<usize>,
+) -> Result<&'static dyn AttentionBackend, APIError> {
+    if let Some(backend) = kind
+        .backend()
+        .filter(|backend| backend.supports_decode(attn, query))
+    {
+        return Ok(backend);
+    }
+    if CpuBackend.supports_decode(attn, query) {
+        return Ok(&CpuBackend);
+    }
+    let (num_queries, num_heads, _head_size) = try_api!(query.dims3());
+    let max_num_partitions = max_context_len.div_ceil(PARTITION_SIZE);
+    let use_v1 = match v2_min_context_len {
+        Some(v2_min_context_len) => max_context_len < v2_min_context_len,
+        None => {
+            max_context_len <= 8192 && (max_num_partitions == 1 || num_queries * num_heads > 512)
+        }
+    };
+    Ok(if use_v1 {
+        &PagedV1Backend
+    } else {
+        &PagedV2Backend
+    })
+}
+
+/// Masked attention over the prompts padded to the longest one, see `_memory_efficient_attention`.
+struct NaiveBackend;
+
+impl AttentionBackend for NaiveBackend {
+    fn name(&self) -> &'static str {
+        "naive"
+    }
+
+    fn supports_prompt(
+        &self,
+        _attn: &PagedAttention,
+        _query: &Tensor,
+        _prompt: &PromptMetadata,
+    ) -> bool {
+        true
+    }
+
+    fn prompt_attention(
+        &self,
+        attn: &PagedAttention,
+        query: Tensor,
+        key: Tensor,
+        value: Tensor,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let device = query.device().clone();
+        let dtype = query.dtype();
+        let prompt = input_metadata.prompt.as_ref().unwrap();
+        let num_prompts = prompt.prompt_lens.len();
+        let max_prompt_len = prompt.max_prompt_len;
+        if prompt.prompt_lens.iter().all(|len| *len == max_prompt_len) {
+            return attn._normal_attention(
+                query,
+                key,
+                value,
+                input_metadata,
+                max_prompt_len,
+                num_prompts,
+                &device,
+                dtype,
+            );
+        }
+        let pad_indices = prompt.pad_indices(&device)?;
+        let unpad_indices = prompt.unpad_indices(&device)?;
+        let output = attn._normal_attention(
+            try_api!(query.index_select(&pad_indices, 0)),
+            try_api!(key.index_select(&pad_indices, 0)),
+            try_api!(value.index_select(&pad_indices, 0)),
+            input_metadata,
+            max_prompt_len,
+            num_prompts,
+            &device,
+            dtype,
+        )?;
+        output
+            .index_select(&unpad_indices, 0)
+            .map_err(APIError::from)
+    }
+}
+
+/// FlashAttention-2 over the packed prompts, for causal masks only, see `_flash_attention`.
+struct FlashBackend;
+
+impl AttentionBackend for FlashBackend {
+    fn name(&self) -> &'static str {
+        "flash"
+    }
+
+    #[cfg(feature = "cuda")]
+    fn supports_prompt(
+        &self,
+        attn: &PagedAttention,
+        query: &Tensor,
+        prompt: &PromptMetadata,
+    ) -> bool {
+        attn.can_use_flash_attention(query)
+            && prompt
+                .attn_bias
+                .as_ref()
+                .map_or(true, AttentionBias::is_causal)
+    }
+
+    #[cfg(feature = "cuda")]
+    fn prompt_attention(
+        &self,
+        attn: &PagedAttention,
+        query: Tensor,
+        key: Tensor,
+        value: Tensor,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let prompt = input_metadata.prompt.as_ref().unwrap();
+        attn._flash_attention(
+            &query,
+            &key,
+            &value,
+            &prompt.cu_seqlens,
+            prompt.max_prompt_len,
+            attn.get_sliding_window(input_metadata),
+        )
+    }
+}
+
+/// Chunked attention over the packed prompts with a running softmax, for causal masks without
+/// ALiBi, see `_blockwise_attention`.
+struct BlockwiseBackend;
+
+impl AttentionBackend for BlockwiseBackend {
+    fn name(&self) -> &'static str {
+        "blockwise"
+    }
+
+    fn supports_prompt(
+        &self,
+        attn: &PagedAttention,
+        _query: &Tensor,
+        prompt: &PromptMetadata,
+    ) -> bool {
+        attn.alibi_slopes.is_none()
+            && prompt
+                .attn_bias
+                .as_ref()
+                .map_or(true, AttentionBias::is_causal)
+    }
+
+    fn prompt_attention(
+        &self,
+        attn: &PagedAttention,
+        query: Tensor,
+        key: Tensor,
+        value: Tensor,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let prompt = input_metadata.prompt.as_ref().unwrap();
+        attn._blockwise_attention(
+            &query,
+            &key,
+            &value,
+            &prompt.prompt_lens,
+            attn.get_sliding_window(input_metadata),
+        )
+    }
+}
+
+/// Single-pass paged decode attention, see `paged_attention_v1`.
+struct PagedV1Backend;
+
+impl AttentionBackend for PagedV1Backend {
+    fn name(&self) -> &'static str {
+        "paged-v1"
+    }
+
+    fn supports_decode(&self, _attn: &PagedAttention, query: &Tensor) -> bool {
+        !query.device().is_cpu()
+    }
+
+    fn decode_attention(
+        &self,
+        attn: &PagedAttention,
+        query: Tensor,
+        key_cache: Tensor,
+        value_cache: Tensor,
+        decode: &DecodeMetadata,
+        kv_cache_dtype: &str,
+        sliding_window: Option<usize>,
+        alibi_slopes: Option<Tensor>,
+        attention_compute: AttentionComputeDtype,
+    ) -> Result<Tensor, APIError> {
+        let (block_size, _) = key_cache_block_dims(&key_cache);
+        paged_attention_v1(
+            query,
+            key_cache,
+            value_cache,
+            attn.num_key_value_heads.try_into().unwrap(),
+            attn.scale,
+            decode.block_tables.clone(),
+            decode.context_lens.clone(),
+            block_size,
+            decode.max_context_len,
+            alibi_slopes,
+            attn.kv_cache_scales.clone(),
+            decode.block_position_shifts.clone(),
+            decode.tree_mask.clone(),
+            attn.rope_theta,
+            sliding_window,
+            attn.logits_soft_cap,
+            kv_cache_dtype,
+            attention_compute,
+        )
+    }
+}
+
+/// Split-K paged decode attention, see `paged_attention_v2`.
+struct PagedV2Backend;
+
+impl AttentionBackend for PagedV2Backend {
+    fn name(&self) -> &'static str {
+        "paged-v2"
+    }
+
+    fn supports_decode(&self, _attn: &PagedAttention, query: &Tensor) -> bool {
+        !query.device().is_cpu()
+    }
+
+    fn decode_attention(
+        &self,
+        attn: &PagedAttention,
+        query: Tensor,
+        key_cache: Tensor,
+        value_cache: Tensor,
+        decode: &DecodeMetadata,
+        kv_cache_dtype: &str,
+        sliding_window: Option<usize>,
+        alibi_slopes: Option<Tensor>,
+        attention_compute: AttentionComputeDtype,
+    ) -> Result<Tensor, APIError> {
+        let (block_size, _) = key_cache_block_dims(&key_cache);
+        let (num_queries, num_heads, _head_size) = try_api!(query.dims3());
+        let num_splits = num_kv_splits(
+            num_queries,
+            num_heads,
+            decode.max_context_len,
+            query.device(),
+        )?;
+        let exp_sums = try_api!(Tensor::zeros(
+            (num_queries, num_heads, num_splits),
+            DType::F32,
+            query.device(),
+        ));
+        let max_logits = try_api!(exp_sums.zeros_like());
+        paged_attention_v2(
+            exp_sums,
+            max_logits,
+            query,
+            key_cache,
+            value_cache,
+            attn.num_key_value_heads.try_into().unwrap(),
+            attn.scale,
+            decode.block_tables.clone(),
+            decode.context_lens.clone(),
+            block_size,
+            decode.max_context_len,
+            alibi_slopes,
+            attn.kv_cache_scales.clone(),
+            decode.block_position_shifts.clone(),
+            decode.tree_mask.clone(),
+            attn.rope_theta,
+            sliding_window,
+            attn.logits_soft_cap,
+            kv_cache_dtype,
+            attention_compute,
+        )
+    }
+}
+
+/// The reference paged decode attention, see `paged_attention_cpu`.
+struct CpuBackend;
+
+impl AttentionBackend for CpuBackend {
+    fn name(&self) -> &'static str {
+        "cpu"
+    }
+
+    fn supports_decode(&self, _attn: &PagedAttention, query: &Tensor) -> bool {
+        query.device().is_cpu()
+    }
+
+    fn decode_attention(
+        &self,
+        attn: &PagedAttention,
+        query: Tensor,
+        key_cache: Tensor,
+        value_cache: Tensor,
+        decode: &DecodeMetadata,
+        kv_cache_dtype: &str,
+        sliding_window: Option<usize>,
+        alibi_slopes: Option<Tensor>,
+        attention_compute: AttentionComputeDtype,
+    ) -> Result<Tensor, APIError> {
+        if kv_cache_dtype != "auto" {
+            return Err(APIError::new(format!(
+                "The KV cache data type {kv_cache_dtype} is not supported by the CPU kernels."
+            )));
+        }
+        if attention_compute != AttentionComputeDtype::Full {
+            return Err(APIError::new(format!(
+                "{attention_compute:?} attention compute is not supported by the CPU kernels."
+            )));
+        }
+        paged_attention_cpu(
+            &query,
+            &key_cache,
+            &value_cache,
+            attn.num_key_value_heads,
+            attn.scale,
+            &decode.block_tables,
+            &decode.context_lens,
+            alibi_slopes.as_ref(),
+            decode.block_position_shifts.as_ref(),
+            decode.tree_mask.as_ref(),
+            attn.rope_theta,
+            sliding_window,
+            attn.logits_soft_cap,
+        )
+    }
+}
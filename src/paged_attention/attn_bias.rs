@@ -9,6 +9,113 @@ use crate::openai::responses::APIError;
 use crate::paged_attention::utils;
 use crate::try_api;
 
+/// The mask of the prompt attention. It is materialized lazily, for the shape of each batch,
+/// as an additive bias (0 to attend, -inf to mask out).
+pub enum AttentionBias {
+    /// Each token attends to itself and the tokens before it.
+    Causal,
+    /// Prefix-LM: bidirectional attention over the first `prefix_lens[i]` tokens of prompt `i`
+    /// (e.g. the encoded prompt), causal attention after them.
+    PrefixLm { prefix_lens: Vec<usize> },
+    /// An arbitrary mask, shape = [seq_len, seq_len] or [batch_size, seq_len, seq_len]. It is
+    /// used as-is, without a causal or sliding window mask.
+    Dense(Tensor),
+    /// A block-diagonal or tensor bias, see `AttentionBiasBlockDiagonal`.
+    BlockDiagonal(Box<dyn AttentionBiasBlockDiagonal>),
+}
+
+impl AttentionBias {
+    /// Whether the bias is a plain causal mask, which the attention kernels apply on their own.
+    pub fn is_causal(&self) -> bool {
+        matches!(self, Self::Causal)
+    }
+
+    /// The bias on `device`. Block-diagonal biases cannot be moved.
+    pub fn to_device(&self, device: &Device) -> Result<Self, APIError> {
+        Ok(match self {
+            Self::Causal => Self::Causal,
+            Self::PrefixLm { prefix_lens } => Self::PrefixLm {
+                prefix_lens: prefix_lens.clone(),
+            },
+            Self::Dense(mask) => Self::Dense(try_api!(mask.to_device(device))),
+            Self::BlockDiagonal(_) => {
+                return Err(APIError::new_str(
+                    "Block-diagonal attention biases cannot be moved to another device.",
+                ))
+            }
+        })
+    }
+
+    /// The bias of a batch of `batch_size` prompts padded to `seq_len` tokens, shape =
+    /// [batch_size, seq_len, seq_len], or [1, seq_len, seq_len] if it is the same for all prompts.
+    /// Block-diagonal biases are materialized for the shape [seq_len, seq_len].
+    pub fn materialize(
+        &self,
+        batch_size: usize,
+        seq_len: usize,
+        dtype: DType,
+        device: &Device,
+        sliding_window: Option<usize>,
+    ) -> Result<Tensor, APIError> {
+        let shape = Shape::from_dims(&[seq_len, seq_len]);
+        match self {
+            Self::Causal => try_api!(utils::materialize_causal_mask(
+                &shape,
+                dtype,
+                device,
+                sliding_window,
+                false
+            ))
+            .unsqueeze(0)
+            .map_err(APIError::from),
+            Self::PrefixLm { prefix_lens } => {
+                if prefix_lens.len() != batch_size {
+                    return Err(APIError::new(format!(
+                        "Got {} prefix lengths for a batch of {batch_size} prompts.",
+                        prefix_lens.len()
+                    )));
+                }
+                let causal =
+                    utils::materialize_causal_mask(&shape, dtype, device, sliding_window, false)?;
+                let masks = prefix_lens
+                    .iter()
+                    .map(|prefix_len| {
+                        let prefix_len = (*prefix_len).min(seq_len);
+                        if prefix_len == 0 {
+                            return Ok(causal.clone());
+                        }
+                        let prefix =
+                            try_api!(Tensor::zeros((prefix_len, prefix_len), dtype, device));
+                        causal
+                            .slice_assign(&[0..prefix_len, 0..prefix_len], &prefix)
+                            .map_err(APIError::from)
+                    })
+                    .collect::<Result<Vec<_>, APIError>>()?;
+                Tensor::stack(&masks, 0).map_err(APIError::from)
+            }
+            Self::Dense(mask) => {
+                let mask = match mask.rank() {
+                    2 => try_api!(mask.unsqueeze(0)),
+                    _ => mask.clone(),
+                };
+                let (mask_batch_size, q_len, k_len) = try_api!(mask.dims3());
+                if (q_len, k_len) != (seq_len, seq_len)
+                    || (mask_batch_size != 1 && mask_batch_size != batch_size)
+                {
+                    return Err(APIError::new(format!(
+                        "The attention mask has shape {:?}, expected [{batch_size}, {seq_len}, {seq_len}].",
+                        mask.dims()
+                    )));
+                }
+                try_api!(mask.to_device(device))
+                    .to_dtype(dtype)
+                    .map_err(APIError::from)
+            }
+            Self::BlockDiagonal(bias) => bias.materialize(&shape, dtype, device),
+        }
+    }
+}
+
 pub trait AttentionBiasBlockDiagonal {
     /// Queries and Keys are each divided into the same number of blocks.
     /// A query Q in block i cannot attend to a key which is not in block i,
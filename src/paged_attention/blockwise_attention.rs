@@ -0,0 +1,202 @@
+//! Blockwise prompt attention for very long prompts without FlashAttention-2. Each prompt is
+//! streamed through the attention in chunks of queries and keys, keeping running softmax
+//! statistics per query, so the scores of only one pair of chunks exist at a time instead of the
+//! full [num_heads, prompt_len, prompt_len] matrix.
+
+use candle_core::{DType, Device, Tensor, D};
+
+use crate::{openai::responses::APIError, try_api};
+
+use super::PagedAttention;
+
+/// Number of queries and keys per chunk. The f32 scores of a pair of chunks take
+/// num_heads * BLOCKWISE_CHUNK_SIZE^2 * 4 bytes.
+pub(crate) const BLOCKWISE_CHUNK_SIZE: usize = 512;
+
+impl PagedAttention {
+    /// query: shape = [num_prompt_tokens, num_heads, head_size]
+    /// key: shape = [num_prompt_tokens, num_kv_heads, head_size]
+    /// value: shape = [num_prompt_tokens, num_kv_heads, head_size]
+    /// prompt_lens: the lengths of the packed prompts.
+    ///
+    /// Returns the causal attention within each prompt, shape = [num_prompt_tokens, num_heads,
+    /// head_size].
+    pub(crate) fn _blockwise_attention(
+        &self,
+        query: &Tensor,
+        key: &Tensor,
+        value: &Tensor,
+        prompt_lens: &[usize],
+        sliding_window: Option<usize>,
+    ) -> Result<Tensor, APIError> {
+        let mut outputs = Vec::with_capacity(prompt_lens.len());
+        let mut offset = 0;
+        for prompt_len in prompt_lens {
+            // [num_kv_heads, num_queries_per_kv, prompt_len, head_size]
+            let q = try_api!(try_api!(query.narrow(0, offset, *prompt_len)).reshape((
+                *prompt_len,
+                self.num_key_value_heads,
+                self.num_queries_per_kv,
+                self.head_dim
+            )));
+            let q = try_api!(
+                try_api!(try_api!(q.permute((1, 2, 0, 3))).contiguous()).to_dtype(DType::F32)
+            );
+            // [num_kv_heads, prompt_len, head_size]
+            let k = try_api!(
+                try_api!(try_api!(key.narrow(0, offset, *prompt_len)).transpose(0, 1))
+                    .to_dtype(DType::F32)
+            );
+            let v = try_api!(try_api!(
+                try_api!(value.narrow(0, offset, *prompt_len)).transpose(0, 1)
+            )
+            .to_dtype(DType::F32));
+
+            for q_start in (0..*prompt_len).step_by(BLOCKWISE_CHUNK_SIZE) {
+                let q_len = BLOCKWISE_CHUNK_SIZE.min(prompt_len - q_start);
+                let output = self.attend_query_chunk(&q, &k, &v, q_start, q_len, sliding_window)?;
+                // [q_len, num_heads, head_size]
+                let output = try_api!(
+                    try_api!(try_api!(output.permute((2, 0, 1, 3))).contiguous()).reshape((
+                        q_len,
+                        self.num_attention_heads,
+                        self.head_dim
+                    ))
+                );
+                outputs.push(try_api!(output.to_dtype(query.dtype())));
+            }
+            offset += prompt_len;
+        }
+        Tensor::cat(&outputs, 0).map_err(APIError::from)
+    }
+
+    /// The attention of the queries [q_start, q_start + q_len) of a prompt over the keys up to
+    /// them, shape = [num_kv_heads, num_queries_per_kv, q_len, head_size]. The key chunks are
+    /// visited from the diagonal backwards, so every query has seen a key (itself) before any of
+    /// its rows can be fully masked.
+    fn attend_query_chunk(
+        &self,
+        q: &Tensor,
+        k: &Tensor,
+        v: &Tensor,
+        q_start: usize,
+        q_len: usize,
+        sliding_window: Option<usize>,
+    ) -> Result<Tensor, APIError> {
+        // The query heads of a KV head are folded into the rows: [num_kv_heads,
+        // num_queries_per_kv * q_len, head_size]
+        let q = try_api!(
+            try_api!(try_api!(q.narrow(2, q_start, q_len)) * f64::from(self.scale)).reshape((
+                self.num_key_value_heads,
+                self.num_queries_per_kv * q_len,
+                self.head_dim
+            ))
+        );
+        let q_end = q_start + q_len;
+        // The first key any query of the chunk attends to.
+        let first_key = sliding_window.map_or(0, |window| (q_start + 1).saturating_sub(window));
+
+        let mut max_scores: Option<Tensor> = None;
+        let mut sums: Option<Tensor> = None;
+        let mut acc: Option<Tensor> = None;
+        let mut k_end = q_end;
+        while k_end > first_key {
+            let k_start = k_end.saturating_sub(BLOCKWISE_CHUNK_SIZE).max(first_key);
+            let k_len = k_end - k_start;
+            let k_chunk = try_api!(try_api!(k.narrow(1, k_start, k_len)).contiguous());
+            let v_chunk = try_api!(try_api!(v.narrow(1, k_start, k_len)).contiguous());
+
+            // [num_kv_heads, num_queries_per_kv, q_len, k_len]
+            let mut scores = try_api!(try_api!(q.matmul(&try_api!(k_chunk.t()))).reshape((
+                self.num_key_value_heads,
+                self.num_queries_per_kv,
+                q_len,
+                k_len
+            )));
+            if let Some(cap) = self.logits_soft_cap {
+                let cap = f64::from(cap);
+                scores = try_api!(try_api!(try_api!(scores / cap).tanh()) * cap);
+            }
+            if let Some(mask) =
+                chunk_mask(q_start, q_len, k_start, k_len, sliding_window, q.device())?
+            {
+                scores = try_api!(scores.broadcast_add(&mask));
+            }
+
+            let chunk_max = try_api!(scores.max_keepdim(D::Minus1));
+            let new_max = match &max_scores {
+                Some(max_scores) => try_api!(max_scores.maximum(&chunk_max)),
+                None => chunk_max,
+            };
+            let probs = try_api!(try_api!(scores.broadcast_sub(&new_max)).exp());
+            let chunk_sum = try_api!(probs.sum_keepdim(D::Minus1));
+            let chunk_acc = try_api!(try_api!(try_api!(probs.reshape((
+                self.num_key_value_heads,
+                self.num_queries_per_kv * q_len,
+                k_len
+            )))
+            .matmul(&v_chunk))
+            .reshape((
+                self.num_key_value_heads,
+                self.num_queries_per_kv,
+                q_len,
+                self.head_dim
+            )));
+            (sums, acc) = match (max_scores, sums, acc) {
+                (Some(max_scores), Some(sums), Some(acc)) => {
+                    // Rescale the statistics of the previous chunks to the new maximum.
+                    let correction = try_api!(try_api!(max_scores - &new_max).exp());
+                    (
+                        Some(try_api!(try_api!(sums * &correction) + chunk_sum)),
+                        Some(try_api!(
+                            try_api!(acc.broadcast_mul(&correction)) + chunk_acc
+                        )),
+                    )
+                }
+                _ => (Some(chunk_sum), Some(chunk_acc)),
+            };
+            max_scores = Some(new_max);
+            k_end = k_start;
+        }
+        try_api!(acc.unwrap().broadcast_div(&sums.unwrap()))
+            .contiguous()
+            .map_err(APIError::from)
+    }
+}
+
+/// The additive mask of the keys [k_start, k_start + k_len) for the queries [q_start, q_start +
+/// q_len), shape = [q_len, k_len], masking future keys and keys outside of the sliding window.
+/// None if no key of the chunk is masked.
+fn chunk_mask(
+    q_start: usize,
+    q_len: usize,
+    k_start: usize,
+    k_len: usize,
+    sliding_window: Option<usize>,
+    device: &Device,
+) -> Result<Option<Tensor>, APIError> {
+    let is_masked = |q: usize, k: usize| k > q || sliding_window.is_some_and(|w| q - k >= w);
+    let q_end = q_start + q_len - 1;
+    let k_end = k_start + k_len - 1;
+    // The extreme pairs decide whether any pair is masked: the last key for the first query
+    // (causality) and the first key for the last query (the window).
+    if !is_masked(q_start, k_end) && !is_masked(q_end, k_start) {
+        return Ok(None);
+    }
+    let mask = (q_start..=q_end)
+        .flat_map(|q| {
+            (k_start..=k_end).map(move |k| {
+                if is_masked(q, k) {
+                    f32::NEG_INFINITY
+                } else {
+                    0.
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    Ok(Some(try_api!(Tensor::from_vec(
+        mask,
+        (q_len, k_len),
+        device
+    ))))
+}
@@ -0,0 +1,65 @@
+//! FlashAttention-2 for the prompt phase. Attends causally within each packed prompt without
+//! materializing the attention mask or the attention weights, so memory grows linearly with the
+//! prompt length instead of quadratically.
+
+use candle_core::{DType, Device, Tensor};
+
+use crate::{backend::compute_capability, openai::responses::APIError, try_api};
+
+use super::PagedAttention;
+
+/// FlashAttention-2 requires Ampere or newer.
+const MIN_FLASH_ATTN_COMPUTE_CAPABILITY: (i32, i32) = (8, 0);
+
+impl PagedAttention {
+    /// Whether the prompt phase can run with FlashAttention-2: on a supported CUDA device, in
+    /// half precision and without ALiBi or soft-capping.
+    pub(crate) fn can_use_flash_attention(&self, query: &Tensor) -> bool {
+        let Device::Cuda(dev) = query.device() else {
+            return false;
+        };
+        matches!(query.dtype(), DType::F16 | DType::BF16)
+            && self.alibi_slopes.is_none()
+            && self.logits_soft_cap.is_none()
+            && compute_capability(dev.ordinal())
+                .is_ok_and(|capability| capability >= MIN_FLASH_ATTN_COMPUTE_CAPABILITY)
+    }
+
+    /// query: shape = [num_prompt_tokens, num_heads, head_size]
+    /// key: shape = [num_prompt_tokens, num_kv_heads, head_size]
+    /// value: shape = [num_prompt_tokens, num_kv_heads, head_size]
+    /// cu_seqlens: the offsets of the packed prompts, see `PromptMetadata::cu_seqlens`.
+    ///
+    /// Returns the output, shape = [num_prompt_tokens, num_heads, head_size].
+    pub(crate) fn _flash_attention(
+        &self,
+        query: &Tensor,
+        key: &Tensor,
+        value: &Tensor,
+        cu_seqlens: &Tensor,
+        max_seqlen: usize,
+        sliding_window: Option<usize>,
+    ) -> Result<Tensor, APIError> {
+        // The varlen kernels attend within each packed prompt, so no FLOPs are spent on padding.
+        // Grouped-query attention is handled by the kernel, no need to repeat the kv heads.
+        let output = match sliding_window {
+            Some(sliding_window) => try_api!(candle_flash_attn::flash_attn_varlen_windowed(
+                query,
+                key,
+                value,
+                cu_seqlens,
+                cu_seqlens,
+                max_seqlen,
+                max_seqlen,
+                self.scale,
+                // The window includes the current token.
+                Some(sliding_window - 1),
+                Some(0),
+            )),
+            None => try_api!(candle_flash_attn::flash_attn_varlen(
+                query, key, value, cu_seqlens, cu_seqlens, max_seqlen, max_seqlen, self.scale, true
+            )),
+        };
+        Ok(output)
+    }
+}
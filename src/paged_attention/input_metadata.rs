@@ -1,43 +1,380 @@
-use candle_core::Tensor;
+use candle_core::{Device, Tensor};
 
-use super::attn_bias::AttentionBiasBlockDiagonal;
+use super::{attention_backend::AttentionBackendKind, attn_bias::AttentionBias};
+use crate::{backend::AttentionComputeDtype, openai::responses::APIError, try_api};
 
-pub struct InputMetadata {
+/// The prompt slice of a step: the prompts packed back to back, without padding.
+pub struct PromptMetadata {
+    /// The number of new tokens of each prompt in the slice.
     pub prompt_lens: Vec<usize>,
-    pub max_context_len: Option<usize>,
-    pub block_tables: Option<Tensor>,
-    pub context_lens: Option<Tensor>,
+    /// The number of leading tokens of each prompt which are already cached (prefix cache hits)
+    /// and left out of the slice. The new tokens of a prompt start at this position.
+    pub context_lens: Vec<usize>,
+    /// The attention context of each new token if a prompt has cached tokens: the block table of
+    /// its prompt and its position + 1 as the context length. The new tokens are then attended
+    /// from the cache, each as a sequence of its own, as the slice lacks the cached tokens.
+    pub cached_context: Option<DecodeMetadata>,
+    /// The length of the longest prompt.
+    pub max_prompt_len: usize,
+    /// The offset of each prompt in the slice followed by the number of prompt tokens, shape =
+    /// [num_prompts + 1] (u32).
+    pub cu_seqlens: Tensor,
+    /// The mask of the prompt attention, causal if not given. Masks other than the causal one are
+    /// applied to the prompts padded to `max_prompt_len`.
+    pub attn_bias: Option<AttentionBias>,
+}
+
+/// The decode slice of a step: the generation tokens, the same number for each sequence.
+pub struct DecodeMetadata {
+    /// The maximum context length.
+    pub max_context_len: usize,
+    /// The block tables. (Seq id -> list of physical block)
+    pub block_tables: Tensor,
+    /// The length of attention context for each sequence, including all of its generation
+    /// tokens.
+    pub context_lens: Tensor,
+    /// Number of positions the keys of each block (shape = [num_seqs, max_num_blocks_per_seq])
+    /// were cached ahead of their current position, e.g. behind attention sinks. The attention
+    /// kernels rotate them back before use.
+    pub block_position_shifts: Option<Tensor>,
+    /// The attention mask between the generation tokens of each sequence, when they are a tree
+    /// of candidates rather than consecutive tokens, shape = [num_generation_tokens / num_seqs]
+    /// (i64), shared by all sequences, see `backend::paged_attention_v1`. Causal if not given.
+    pub tree_mask: Option<Tensor>,
+}
+
+/// The encoder outputs attended by the cross-attention layers of an encoder-decoder model (e.g.
+/// Whisper or T5). They are cached in static blocks of the same pool as the self-attention cache,
+/// written once with the prompt and never appended to. The sequences are in step order: the
+/// prompts followed by the generating sequences.
+pub struct CrossAttentionMetadata {
+    pub encoder_lens: Vec<usize>,
+    pub max_encoder_len: usize,
+    /// The cross block tables. (Seq id -> list of physical block)
+    pub block_tables: Tensor,
+    /// The encoder length of each sequence.
+    pub context_lens: Tensor,
+    /// The address to write the encoder KV to, shape = [num_prompts * max_encoder_len] with -1
+    /// for padding. Only given if the step has a prompt slice, whose encoder outputs are new.
+    pub slot_mapping: Option<Tensor>,
+    /// The encoder prompts of the prompts, padded with token 0 to `max_encoder_len`, shape =
+    /// [num_prompts * max_encoder_len]. Given along with `slot_mapping`.
+    pub input_ids: Option<Tensor>,
+}
+
+/// The recurrent states of the sequences of a state-space model, which are scanned over the
+/// tokens of each sequence in step order: the prompts followed by the generating sequences.
+pub struct StateMetadata {
+    /// The offset of each sequence in the token row followed by the number of tokens, shape =
+    /// [num_seqs + 1] (u32).
+    pub cu_seqlens: Tensor,
+    /// The state slot of each sequence, shape = [num_seqs] (i64). Sequences with slot -1 (e.g.
+    /// during profiling) start from a zero state, which is not kept.
+    pub slots: Tensor,
+    /// Number of leading sequences which are prompts. Their states start from zero instead of
+    /// the stale contents of their slot.
+    pub num_prompts: usize,
+}
+
+/// The images of the prompts of a vision-language model. Their embeddings replace the image
+/// tokens of the prompt slice in order, see `models::merge_image_embeddings`.
+pub struct ImageMetadata {
+    /// The preprocessed images, shape = [num_images, 3, height, width] (f32).
+    pub pixel_values: Tensor,
+}
+
+/// The metadata of an engine step. The tokens of a step are laid out as a single row: the packed
+/// prompt slice followed by the decode slice. Either slice may be missing, each one is attended
+/// with its own metadata, so prompts and generation tokens can be batched in the same step.
+pub struct InputMetadata {
+    pub prompt: Option<PromptMetadata>,
+    pub decode: Option<DecodeMetadata>,
+    /// The encoder outputs of an encoder-decoder model, if any.
+    pub cross: Option<CrossAttentionMetadata>,
+    /// The recurrent states of a state-space model, if any.
+    pub state: Option<StateMetadata>,
+    /// The images of the prompt slice of a vision-language model, if any.
+    pub images: Option<ImageMetadata>,
+    /// The segment of each token of the prompt slice of an encoder-only model, e.g. 0 for the
+    /// query and 1 for the document of a cross-encoder pair. All 0 if not given.
+    pub token_type_ids: Option<Tensor>,
+    /// The address to write the new KV to of each token, -1 for padding.
     pub slot_mapping: Tensor,
-    pub attn_bias: Option<Box<dyn AttentionBiasBlockDiagonal>>,
-    pub is_prompt: bool,
     pub kv_cache_dtype: String,
+    /// Model-wide attention window. The block tables hold the full context, tokens outside of
+    /// the window are masked by the attention kernels.
+    pub sliding_window: Option<usize>,
+    /// Decode with the partitioned (V2) attention kernel from this context length on, as
+    /// measured by `scheduler::autotune`. A heuristic picks the kernel if not given.
+    pub v2_min_context_len: Option<usize>,
+    /// The attention backend requested for this step, see `attention_backend`.
+    pub attention_backend: AttentionBackendKind,
+    /// Precision of the attention scores of the paged attention kernels.
+    pub attention_compute: AttentionComputeDtype,
+    /// Softmax scale of all attention layers, replacing the scale of each layer.
+    pub attention_scale: Option<f32>,
+    /// Divides the attention scores of all attention layers before the softmax.
+    pub attention_temperature: Option<f32>,
+    /// Keep the final hidden states of the tokens in `hidden_states`.
+    pub output_hidden_states: bool,
+    /// The final hidden states of all tokens of the step, not only those given by
+    /// `logits_indices`, shape = [num_tokens, hidden_size], set by the model if
+    /// `output_hidden_states` is set.
+    pub hidden_states: Option<Tensor>,
+    /// The attention metadata of the step on the devices of the other tensor-parallel ranks,
+    /// set by the model for the duration of its forward pass, see `models::tensor_parallel`.
+    pub ranks: Vec<InputMetadata>,
+}
+
+impl PromptMetadata {
+    /// The packed row of each token of the prompts padded to `max_prompt_len`, shape =
+    /// [num_prompts * max_prompt_len]. Padding tokens take the first row, their outputs are
+    /// dropped again with `unpad_indices`.
+    pub fn pad_indices(&self, device: &Device) -> Result<Tensor, APIError> {
+        let mut indices = Vec::with_capacity(self.prompt_lens.len() * self.max_prompt_len);
+        let mut offset = 0;
+        for prompt_len in &self.prompt_lens {
+            indices.extend((offset..offset + prompt_len).map(|i| i as u32));
+            indices.extend(std::iter::repeat(0).take(self.max_prompt_len - prompt_len));
+            offset += prompt_len;
+        }
+        let num_indices = indices.len();
+        Ok(try_api!(Tensor::from_vec(indices, num_indices, device)))
+    }
+
+    /// The padded row of each packed token, the inverse of `pad_indices`.
+    pub fn unpad_indices(&self, device: &Device) -> Result<Tensor, APIError> {
+        let indices = self
+            .prompt_lens
+            .iter()
+            .enumerate()
+            .flat_map(|(i, prompt_len)| {
+                (0..*prompt_len).map(move |j| (i * self.max_prompt_len + j) as u32)
+            })
+            .collect::<Vec<_>>();
+        let num_indices = indices.len();
+        Ok(try_api!(Tensor::from_vec(indices, num_indices, device)))
+    }
 }
 
 impl InputMetadata {
-    /// prompt_lens: Lengths of prompts.
+    /// prompt: The prompt slice, if any.
+    /// decode: The decode slice, if any.
     /// slot_mapping: The address to write the new KV to of each token.
-    /// context_lens: the length of attention context for each generation token.
-    /// max_context_len: The maximum context length.
-    /// block_tables: The block tables. (Seq id -> list of physical block)
-    /// kv_cache_dtype: KV cache datatype (auto or fp8_e5m2)
+    /// kv_cache_dtype: KV cache datatype (auto, fp8_e5m2, fp8_e4m3 or int8)
+    /// sliding_window: The attention window of the model, if any.
     pub fn new(
-        prompt_lens: Vec<usize>,
-        max_context_len: Option<usize>,
-        block_tables: Option<Tensor>,
-        context_lens: Option<Tensor>,
+        prompt: Option<PromptMetadata>,
+        decode: Option<DecodeMetadata>,
         slot_mapping: Tensor,
         kv_cache_dtype: String,
+        sliding_window: Option<usize>,
     ) -> Self {
-        let is_prompt = !prompt_lens.is_empty();
         Self {
-            prompt_lens,
-            max_context_len,
-            block_tables,
-            context_lens,
+            prompt,
+            decode,
+            cross: None,
+            state: None,
+            images: None,
+            token_type_ids: None,
             slot_mapping,
-            attn_bias: None,
-            is_prompt,
             kv_cache_dtype,
+            sliding_window,
+            v2_min_context_len: None,
+            attention_backend: AttentionBackendKind::default(),
+            attention_compute: AttentionComputeDtype::default(),
+            attention_scale: None,
+            attention_temperature: None,
+            output_hidden_states: false,
+            hidden_states: None,
+            ranks: Vec::new(),
+        }
+    }
+
+    /// The attention metadata of the step on `device`, for the layers of a tensor-parallel rank
+    /// or a pipeline stage. The images, encoder outputs, recurrent states and token types are left
+    /// out, as the ranks and stages only run the self-attention and MLP of decoder layers.
+    pub fn to_device(&self, device: &Device) -> Result<Self, APIError> {
+        let decode_to_device = |decode: &DecodeMetadata| -> Result<DecodeMetadata, APIError> {
+            Ok(DecodeMetadata {
+                max_context_len: decode.max_context_len,
+                block_tables: try_api!(decode.block_tables.to_device(device)),
+                context_lens: try_api!(decode.context_lens.to_device(device)),
+                block_position_shifts: match &decode.block_position_shifts {
+                    Some(shifts) => Some(try_api!(shifts.to_device(device))),
+                    None => None,
+                },
+                tree_mask: match &decode.tree_mask {
+                    Some(mask) => Some(try_api!(mask.to_device(device))),
+                    None => None,
+                },
+            })
+        };
+        let prompt = match &self.prompt {
+            Some(prompt) => Some(PromptMetadata {
+                prompt_lens: prompt.prompt_lens.clone(),
+                context_lens: prompt.context_lens.clone(),
+                cached_context: prompt
+                    .cached_context
+                    .as_ref()
+                    .map(decode_to_device)
+                    .transpose()?,
+                max_prompt_len: prompt.max_prompt_len,
+                cu_seqlens: try_api!(prompt.cu_seqlens.to_device(device)),
+                attn_bias: prompt
+                    .attn_bias
+                    .as_ref()
+                    .map(|bias| bias.to_device(device))
+                    .transpose()?,
+            }),
+            None => None,
+        };
+        let mut metadata = Self::new(
+            prompt,
+            self.decode.as_ref().map(decode_to_device).transpose()?,
+            try_api!(self.slot_mapping.to_device(device)),
+            self.kv_cache_dtype.clone(),
+            self.sliding_window,
+        );
+        metadata.v2_min_context_len = self.v2_min_context_len;
+        metadata.attention_backend = self.attention_backend;
+        metadata.attention_compute = self.attention_compute;
+        metadata.attention_scale = self.attention_scale;
+        metadata.attention_temperature = self.attention_temperature;
+        Ok(metadata)
+    }
+
+    /// Number of tokens of the prompt slice.
+    pub fn num_prompt_tokens(&self) -> usize {
+        self.prompt
+            .as_ref()
+            .map_or(0, |prompt| prompt.prompt_lens.iter().sum())
+    }
+
+    /// Attend the encoder outputs in `cross` with the cross-attention layers.
+    pub fn with_cross_attention(mut self, cross: Option<CrossAttentionMetadata>) -> Self {
+        self.cross = cross;
+        self
+    }
+
+    /// Scan the state-space layers with the recurrent states in `state`.
+    pub fn with_state(mut self, state: Option<StateMetadata>) -> Self {
+        self.state = state;
+        self
+    }
+
+    /// Encode the images in `images` and splice their embeddings into the prompts.
+    pub fn with_images(mut self, images: Option<ImageMetadata>) -> Self {
+        self.images = images;
+        self
+    }
+
+    /// Embed the prompt tokens with the segments in `token_type_ids`, shape = [num_prompt_tokens].
+    pub fn with_token_type_ids(mut self, token_type_ids: Option<Tensor>) -> Self {
+        self.token_type_ids = token_type_ids;
+        self
+    }
+
+    /// Pick the decode attention kernel by the autotuned `v2_min_context_len`.
+    pub fn with_v2_min_context_len(mut self, v2_min_context_len: Option<usize>) -> Self {
+        self.v2_min_context_len = v2_min_context_len;
+        self
+    }
+
+    /// Request an attention backend, see `select_prompt_backend` and `select_decode_backend`.
+    pub fn with_attention_backend(mut self, attention_backend: AttentionBackendKind) -> Self {
+        self.attention_backend = attention_backend;
+        self
+    }
+
+    /// Compute the attention scores of the paged attention kernels in `attention_compute`.
+    pub fn with_attention_compute(mut self, attention_compute: AttentionComputeDtype) -> Self {
+        self.attention_compute = attention_compute;
+        self
+    }
+
+    /// Override the softmax scale and attention temperature of the step, see
+    /// `SamplingParams::attention_scale`.
+    pub fn with_attention_scale(
+        mut self,
+        attention_scale: Option<f32>,
+        attention_temperature: Option<f32>,
+    ) -> Self {
+        self.attention_scale = attention_scale;
+        self.attention_temperature = attention_temperature;
+        self
+    }
+
+    /// Keep the final hidden states of the step, e.g. for the heads of Medusa or EAGLE.
+    pub fn with_output_hidden_states(mut self, output_hidden_states: bool) -> Self {
+        self.output_hidden_states = output_hidden_states;
+        self
+    }
+
+    /// Called by the models with the final hidden states of all tokens (after the final norm),
+    /// which are kept if requested.
+    pub fn keep_hidden_states(&mut self, hidden_states: &Tensor) {
+        if self.output_hidden_states {
+            self.hidden_states = Some(hidden_states.clone());
+        }
+    }
+
+    /// The factor to scale the query of a layer with softmax scale `layer_scale` by, so that the
+    /// attention kernels, which multiply the scores by `layer_scale`, apply the requested scale
+    /// and temperature instead. None without overrides.
+    pub fn query_scale_factor(&self, layer_scale: f32) -> Option<f64> {
+        if self.attention_scale.is_none() && self.attention_temperature.is_none() {
+            return None;
+        }
+        let scale = self.attention_scale.unwrap_or(layer_scale);
+        let temperature = self.attention_temperature.unwrap_or(1.);
+        Some(f64::from(scale) / f64::from(layer_scale) / f64::from(temperature))
+    }
+
+    /// The indices of the tokens whose logits are sampled, out of `num_tokens`: the last token
+    /// of each prompt, followed by every generation token.
+    pub fn logits_indices(&self, num_tokens: usize, device: &Device) -> Result<Tensor, APIError> {
+        let mut indices = Vec::new();
+        if let Some(prompt) = &self.prompt {
+            let mut offset = 0;
+            for prompt_len in &prompt.prompt_lens {
+                offset += prompt_len;
+                indices.push((offset - 1) as u32);
+            }
+        }
+        indices.extend((self.num_prompt_tokens()..num_tokens).map(|i| i as u32));
+        let num_indices = indices.len();
+        Ok(try_api!(Tensor::from_vec(indices, num_indices, device)))
+    }
+
+    /// The sequence of each of the `num_tokens` tokens, as an index into the sequences of the
+    /// step: each prompt, followed by the generation tokens of every generating sequence.
+    pub fn token_seq_indices(
+        &self,
+        num_tokens: usize,
+        device: &Device,
+    ) -> Result<Tensor, APIError> {
+        let mut indices = Vec::with_capacity(num_tokens);
+        let mut num_prompts = 0;
+        if let Some(prompt) = &self.prompt {
+            num_prompts = prompt.prompt_lens.len();
+            for (i, prompt_len) in prompt.prompt_lens.iter().enumerate() {
+                indices.extend(std::iter::repeat(i as u32).take(*prompt_len));
+            }
+        }
+        if let Some(decode) = &self.decode {
+            let num_seqs = try_api!(decode.context_lens.dim(0));
+            let num_decode_tokens = num_tokens - self.num_prompt_tokens();
+            if num_seqs == 0 || num_decode_tokens % num_seqs != 0 {
+                return Err(APIError::new(format!(
+                    "{num_decode_tokens} generation tokens cannot be split evenly over {num_seqs} sequences."
+                )));
+            }
+            let num_query_tokens = num_decode_tokens / num_seqs;
+            indices.extend(
+                (0..num_decode_tokens).map(|i| (num_prompts + i / num_query_tokens) as u32),
+            );
         }
+        Ok(try_api!(Tensor::from_vec(indices, num_tokens, device)))
     }
 }
@@ -2,7 +2,7 @@ use candle_core::{DType, Device, IndexOp, Shape, Tensor, D};
 
 use crate::{
     openai::responses::APIError,
-    paged_attention::attn_bias::{BlockDiagonalCausalMask, LowerTriangularMaskWithTensorBias},
+    paged_attention::attn_bias::{AttentionBias, LowerTriangularMaskWithTensorBias},
     try_api,
 };
 
@@ -20,185 +20,199 @@ pub fn _memory_efficient_attention(
     device: &Device,
     dtype: DType,
 ) -> Result<Tensor, APIError> {
-    let (query, key, value) = if this.num_key_value_heads != this.num_attention_heads {
-        let query = try_api!(query.reshape((
-            *query.shape().dims().first().unwrap(),
-            this.num_key_value_heads,
-            this.num_queries_per_kv,
-            *query.shape().dims().last().unwrap(),
-        )));
-
-        let key = try_api!(
-            try_api!(try_api!(key.i((.., .., .., ..))).unsqueeze(2)).expand((
-                *key.shape().dims().first().unwrap(),
-                this.num_key_value_heads,
-                this.num_queries_per_kv,
-                *key.shape().dims().last().unwrap(),
-            ))
+    let sliding_window = this.get_sliding_window(input_metadata);
+    let prompt_bias = &mut input_metadata.prompt.as_mut().unwrap().attn_bias;
+    let Some(alibi_slopes) = &this.alibi_slopes else {
+        return _grouped_query_attention(
+            this,
+            &query,
+            &key,
+            &value,
+            seq_len,
+            batch_size,
+            prompt_bias.as_ref(),
+            sliding_window,
+            device,
         );
-
-        let value = try_api!(
-            try_api!(try_api!(value.i((.., .., .., ..))).unsqueeze(2)).expand((
-                *value.shape().dims().first().unwrap(),
-                this.num_key_value_heads,
-                this.num_queries_per_kv,
-                *value.shape().dims().last().unwrap(),
-            ))
-        );
-
-        (query, key, value)
-    } else {
-        (query, key, value)
     };
+    if this.num_key_value_heads != this.num_attention_heads {
+        return Err(APIError::new_str(
+            "ALiBi is not supported with grouped-query attention.",
+        ));
+    }
 
-    if input_metadata.attn_bias.is_none() {
-        if let Some(alibi_slopes) = &this.alibi_slopes {
-            //make alibi bias
-            let bias = try_api!(try_api!(Tensor::arange(
-                0f64,
-                TryInto::<i32>::try_into(seq_len).unwrap().into(),
-                device,
-            ))
-            .to_dtype(dtype));
-            let bias = try_api!(try_api!(
-                (try_api!(bias.unsqueeze(0)) - try_api!(bias.unsqueeze(1)))
-            )
-            .to_device(alibi_slopes.device()));
-
-            let padded_len = ((seq_len + 7) / 8) * 8;
-            let mut bias_new = try_api!(try_api!(Tensor::zeros(
-                (
-                    batch_size,
-                    alibi_slopes.shape().dims()[0],
-                    seq_len,
-                    padded_len,
-                ),
-                dtype,
-                device,
-            ))
-            .i((.., .., .., ..seq_len)));
-
-            bias_new = try_api!(bias_new.slice_assign(&[.., .., .., ..], &bias));
-
-            bias_new = try_api!(bias_new.mul(&try_api!(try_api!(
-                try_api!(alibi_slopes.i(..)).unsqueeze(1)
-            )
-            .unsqueeze(2)),));
-            let attn_bias = LowerTriangularMaskWithTensorBias::new(bias_new);
-            input_metadata.attn_bias = Some(Box::new(attn_bias));
-        } else {
-            let mut attn_bias = try_api!(BlockDiagonalCausalMask::from_seqlens(
-                [seq_len.try_into().unwrap()].repeat(batch_size),
-                None,
+    if !matches!(prompt_bias, Some(AttentionBias::BlockDiagonal(_))) {
+        if !prompt_bias.as_ref().map_or(true, AttentionBias::is_causal) {
+            return Err(APIError::new_str(
+                "Only causal attention is supported with ALiBi.",
             ));
-            if let Some(sliding_window) = this.sliding_window {
-                attn_bias = try_api!(attn_bias.make_local_attention(sliding_window));
-            }
-            input_metadata.attn_bias = Some(attn_bias);
         }
-    }
+        //make alibi bias
+        let bias = try_api!(try_api!(Tensor::arange(
+            0f64,
+            TryInto::<i32>::try_into(seq_len).unwrap().into(),
+            device,
+        ))
+        .to_dtype(dtype));
+        let bias = try_api!(
+            try_api!((try_api!(bias.unsqueeze(0)) - try_api!(bias.unsqueeze(1))))
+                .to_device(alibi_slopes.device())
+        );
 
-    let (query, key, value) = if this.alibi_slopes.is_none() {
-        (
-            try_api!(query.unsqueeze(0)),
-            try_api!(key.unsqueeze(0)),
-            try_api!(value.unsqueeze(0)),
-        )
-    } else {
-        assert_eq!(query.shape().dims().len(), key.shape().dims().len());
-        assert_eq!(value.shape().dims().len(), key.shape().dims().len());
-        assert!(query.shape().dims().len() == 3 || query.shape().dims().len() == 4);
-        if query.shape().dims().len() == 3 {
-            (
-                try_api!(query.reshape((
-                    batch_size,
-                    seq_len,
-                    query.shape().dims()[1],
-                    query.shape().dims()[2],
-                ))),
-                try_api!(key.reshape((
-                    batch_size,
-                    seq_len,
-                    key.shape().dims()[1],
-                    key.shape().dims()[2],
-                ))),
-                try_api!(value.reshape((
-                    batch_size,
-                    seq_len,
-                    value.shape().dims()[1],
-                    value.shape().dims()[2],
-                ))),
-            )
-        } else {
+        let padded_len = ((seq_len + 7) / 8) * 8;
+        let mut bias_new = try_api!(try_api!(Tensor::zeros(
             (
-                try_api!(query.reshape((
-                    batch_size,
-                    seq_len,
-                    query.shape().dims()[1],
-                    query.shape().dims()[2],
-                    query.shape().dims()[3],
-                ))),
-                try_api!(key.reshape((
-                    batch_size,
-                    seq_len,
-                    key.shape().dims()[1],
-                    key.shape().dims()[2],
-                    key.shape().dims()[3],
-                ))),
-                try_api!(value.reshape((
-                    batch_size,
-                    seq_len,
-                    value.shape().dims()[1],
-                    value.shape().dims()[2],
-                    value.shape().dims()[3],
-                ))),
-            )
-        }
-    };
+                batch_size,
+                alibi_slopes.shape().dims()[0],
+                seq_len,
+                padded_len,
+            ),
+            dtype,
+            device,
+        ))
+        .i((.., .., .., ..seq_len)));
+
+        bias_new = try_api!(bias_new.slice_assign(&[.., .., .., ..], &bias));
+
+        bias_new = try_api!(bias_new.mul(&try_api!(try_api!(
+            try_api!(alibi_slopes.i(..)).unsqueeze(1)
+        )
+        .unsqueeze(2)),));
+        let attn_bias = LowerTriangularMaskWithTensorBias::new(bias_new);
+        *prompt_bias = Some(AttentionBias::BlockDiagonal(Box::new(attn_bias)));
+    }
+
+    assert_eq!(query.shape().dims().len(), key.shape().dims().len());
+    assert_eq!(value.shape().dims().len(), key.shape().dims().len());
+    let (query, key, value) = (
+        try_api!(query.reshape((
+            batch_size,
+            seq_len,
+            query.shape().dims()[1],
+            query.shape().dims()[2],
+        ))),
+        try_api!(key.reshape((
+            batch_size,
+            seq_len,
+            key.shape().dims()[1],
+            key.shape().dims()[2],
+        ))),
+        try_api!(value.reshape((
+            batch_size,
+            seq_len,
+            value.shape().dims()[1],
+            value.shape().dims()[2],
+        ))),
+    );
 
     let l = try_api!(query.dim(D::Minus2));
     let s = try_api!(key.dim(D::Minus2));
+    let Some(AttentionBias::BlockDiagonal(attn_bias)) = prompt_bias else {
+        unreachable!("the ALiBi bias is set above");
+    };
 
     scaled_dot_product_attention(
         &query,
         &key,
         &value,
-        &try_api!(input_metadata.attn_bias.as_ref().unwrap().materialize(
-            &Shape::from_dims(&[l, s]),
-            query.dtype(),
-            device
-        )),
+        &try_api!(attn_bias.materialize(&Shape::from_dims(&[l, s]), query.dtype(), device)),
         None,
         this.scale,
+        this.logits_soft_cap,
     )
 }
 
-#[cfg(feature = "cuda")]
-/// Flash-attention v2 layer.
-///
-/// This implements scaled dot-product attention, `softmax(Q @ K^T . softmax_scale) @ V`.
-/// Multi-query and grouped-query attention are supported by using tensors k and v with fewer heads
-/// than q, the number of heads in k and v has to be divisible by the number of heads in q.
-///
-/// # Arguments
+/// Attention within each prompt, causal unless `attn_bias` says otherwise, where every KV head is
+/// shared by `num_queries_per_kv` query heads. The query heads of a group are folded into the
+/// sequence dimension so the KV heads are used as-is, without being repeated in memory.
 ///
-/// * `q` - Query tensor with shape `(batch, seq_len_q, num_heads_q, head_size)`.
-/// * `k` - Key tensor with shape `(batch, seq_len_kv, num_heads_kv, head_size)`.
-/// * `v` - Value tensor with shape `(batch, seq_len_kv, num_heads_kv, head_size)`.
+/// - query: [batch_size * seq_len, num_heads, head_size]
+/// - key: [batch_size * seq_len, num_kv_heads, head_size]
+/// - value: [batch_size * seq_len, num_kv_heads, head_size]
 ///
-/// The resulting tensor has dimensions `(batch, seq_len_q, num_heads_q, head_size)`.
-pub fn scaled_dot_product_attention(
+/// Returns the output, shape = [batch_size * seq_len, num_heads, head_size].
+#[allow(clippy::too_many_arguments)]
+fn _grouped_query_attention(
+    this: &PagedAttention,
     query: &Tensor,
     key: &Tensor,
     value: &Tensor,
-    _attn_bias: &Tensor,
-    _dropout_p: Option<f32>,
-    scale_factor: f32,
+    seq_len: usize,
+    batch_size: usize,
+    attn_bias: Option<&AttentionBias>,
+    sliding_window: Option<usize>,
+    device: &Device,
 ) -> Result<Tensor, APIError> {
-    candle_flash_attn::flash_attn(query, key, value, scale_factor, false).map_err(APIError::from)
+    let num_kv_heads = this.num_key_value_heads;
+    let num_queries_per_kv = this.num_queries_per_kv;
+    let head_dim = this.head_dim;
+
+    // [b, s, kv, q_per_kv, d] -> [b, kv, q_per_kv * s, d]
+    let query = try_api!(try_api!(try_api!(query.reshape((
+        batch_size,
+        seq_len,
+        num_kv_heads,
+        num_queries_per_kv,
+        head_dim,
+    )))
+    .permute((0, 2, 3, 1, 4)))
+    .reshape((
+        batch_size,
+        num_kv_heads,
+        num_queries_per_kv * seq_len,
+        head_dim
+    )));
+    // [b, s, kv, d] -> [b, kv, s, d]
+    let key =
+        try_api!(try_api!(
+            try_api!(key.reshape((batch_size, seq_len, num_kv_heads, head_dim))).transpose(1, 2)
+        )
+        .contiguous());
+    let value =
+        try_api!(try_api!(
+            try_api!(value.reshape((batch_size, seq_len, num_kv_heads, head_dim))).transpose(1, 2)
+        )
+        .contiguous());
+
+    // The mask is shared by all query heads of a group: [b or 1, 1, q_per_kv * s, s]
+    let mask = attn_bias.unwrap_or(&AttentionBias::Causal).materialize(
+        batch_size,
+        seq_len,
+        query.dtype(),
+        device,
+        sliding_window,
+    )?;
+    let mask_batch_size = try_api!(mask.dim(0));
+    let mask = try_api!(try_api!(try_api!(mask.unsqueeze(1)).broadcast_as((
+        mask_batch_size,
+        num_queries_per_kv,
+        seq_len,
+        seq_len
+    )))
+    .reshape((mask_batch_size, 1, num_queries_per_kv * seq_len, seq_len)));
+
+    let output = scaled_dot_product_attention(
+        &query,
+        &key,
+        &value,
+        &mask,
+        None,
+        this.scale,
+        this.logits_soft_cap,
+    )?;
+    // [b, kv, q_per_kv * s, d] -> [b * s, num_heads, d]
+    try_api!(try_api!(output.reshape((
+        batch_size,
+        num_kv_heads,
+        num_queries_per_kv,
+        seq_len,
+        head_dim,
+    )))
+    .permute((0, 3, 1, 2, 4)))
+    .reshape(((), this.num_attention_heads, head_dim))
+    .map_err(APIError::from)
 }
 
-#[cfg(not(feature = "cuda"))]
 // https://github.com/mokeyish/candle-ext/blob/main/src/scaled_dot_product_attention.rs
 
 /// Computes scaled dot product attention on query, key and value tensors,
@@ -209,6 +223,7 @@ pub fn scaled_dot_product_attention(
 /// - query   - Query tensor; shape (N, ..., L, E)
 /// - key     - Key tensor; shape (N, ..., S, E)
 /// - value   - Value tensor; shape (N, ..., S, E)
+/// - logits_soft_cap - Soft-cap the scaled scores to (-cap, cap) before the mask is added
 ///
 /// https://pytorch.org/docs/stable/generated/torch.nn.functional.scaled_dot_product_attention.html
 /// # Errors
@@ -221,12 +236,17 @@ pub fn scaled_dot_product_attention(
     attn_bias: &Tensor,
     dropout_p: Option<f32>,
     scale_factor: f32,
+    logits_soft_cap: Option<f32>,
 ) -> Result<Tensor, APIError> {
     let mut attn_weights = try_api!(
         try_api!(query.matmul(&try_api!(
             try_api!(key.transpose(D::Minus2, D::Minus1)).contiguous()
         ),)) * f64::from(scale_factor)
     );
+    if let Some(cap) = logits_soft_cap {
+        let cap = f64::from(cap);
+        attn_weights = try_api!(try_api!(try_api!(attn_weights / cap).tanh()) * cap);
+    }
 
     attn_weights = try_api!(&attn_weights + try_api!(attn_bias.broadcast_as(attn_weights.shape())));
     attn_weights = try_api!(candle_nn::ops::softmax_last_dim(&attn_weights));
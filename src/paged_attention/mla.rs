@@ -0,0 +1,270 @@
+//! Multi-head latent attention (MLA) of DeepSeek-V2/V3. The keys and values of all heads are
+//! compressed into one low-rank latent per token, which is cached together with the rotated rope
+//! key shared by the heads. Instead of expanding the cached latents into per-head keys and
+//! values, the up-projection of the keys is absorbed into the query and the up-projection of the
+//! values is applied to the attention output, so attention runs on the latents directly.
+
+use candle_core::{Tensor, D};
+
+use crate::{
+    backend::{mla_decode_attention, reshape_and_cache_mla},
+    openai::responses::APIError,
+    try_api,
+};
+
+use super::{
+    attn_bias::AttentionBias,
+    input_metadata::{DecodeMetadata, InputMetadata},
+    slice_rows,
+};
+
+/// The dimensions of multi-head latent attention, see `ConfigLike::get_mla_dims`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MlaDims {
+    /// Rank of the compressed KV latent.
+    pub kv_lora_rank: usize,
+    /// Per-head query and key dimensions without the rotary embedding.
+    pub qk_nope_head_dim: usize,
+    /// Per-head query dimensions with the rotary embedding, the key part is shared by all heads.
+    pub qk_rope_head_dim: usize,
+    pub v_head_dim: usize,
+}
+
+impl MlaDims {
+    /// Number of elements cached per token and layer: the latent followed by the rope key.
+    pub fn cache_entry_size(&self) -> usize {
+        self.kv_lora_rank + self.qk_rope_head_dim
+    }
+}
+
+pub struct MultiHeadLatentAttention {
+    num_heads: usize,
+    dims: MlaDims,
+    scale: f32,
+    /// The key up-projection absorbed into the query, shape =
+    /// [num_heads, qk_nope_head_dim, kv_lora_rank].
+    w_uk: Tensor,
+    /// The value up-projection applied to the output, shape =
+    /// [num_heads, kv_lora_rank, v_head_dim].
+    w_uv: Tensor,
+}
+
+impl MultiHeadLatentAttention {
+    /// kv_b_proj: the weight of the up-projection of the latent into the keys (without rope) and
+    ///     values of all heads, shape = [num_heads * (qk_nope_head_dim + v_head_dim),
+    ///     kv_lora_rank].
+    ///
+    /// scale: the softmax scale, usually `1 / sqrt(qk_nope_head_dim + qk_rope_head_dim)`.
+    pub fn new(
+        num_heads: usize,
+        dims: MlaDims,
+        scale: f32,
+        kv_b_proj: &Tensor,
+    ) -> Result<Self, APIError> {
+        let kv_b_proj = try_api!(kv_b_proj.reshape((
+            num_heads,
+            dims.qk_nope_head_dim + dims.v_head_dim,
+            dims.kv_lora_rank
+        )));
+        let w_uk = try_api!(try_api!(kv_b_proj.narrow(1, 0, dims.qk_nope_head_dim)).contiguous());
+        let w_uv = try_api!(try_api!(try_api!(kv_b_proj.narrow(
+            1,
+            dims.qk_nope_head_dim,
+            dims.v_head_dim
+        ))
+        .transpose(1, 2))
+        .contiguous());
+        Ok(Self {
+            num_heads,
+            dims,
+            scale,
+            w_uk,
+            w_uv,
+        })
+    }
+
+    /// q_nope: shape = [num_tokens, num_heads, qk_nope_head_dim]
+    /// q_pe: shape = [num_tokens, num_heads, qk_rope_head_dim], rotated
+    /// kv_latent: shape = [num_tokens, kv_lora_rank], normalized
+    /// k_pe: shape = [num_tokens, qk_rope_head_dim], rotated
+    /// kv_cache: shape = [num_blocks, block_size, 1, kv_lora_rank + qk_rope_head_dim], None
+    ///     during profiling, see `LayerKVCache::Latent`.
+    /// input_metadata: metadata for paged attention, the tokens are the prompt slice followed by
+    ///     the decode slice, see `InputMetadata`.
+    ///
+    /// Returns the output, shape = [num_tokens, num_heads * v_head_dim].
+    pub fn forward(
+        &self,
+        q_nope: &Tensor,
+        q_pe: &Tensor,
+        kv_latent: &Tensor,
+        k_pe: &Tensor,
+        mut kv_cache: Option<Tensor>,
+        input_metadata: &InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let num_tokens = try_api!(q_nope.dim(0));
+        // Absorb the key up-projection: q_nope @ w_uk, per head.
+        let q_latent = try_api!(try_api!(q_nope.transpose(0, 1)).contiguous());
+        let q_latent = try_api!(try_api!(q_latent.matmul(&self.w_uk)).transpose(0, 1));
+        let query = try_api!(try_api!(Tensor::cat(&[&q_latent, q_pe], D::Minus1)).contiguous());
+        let query = match input_metadata.query_scale_factor(self.scale) {
+            Some(factor) => try_api!(query * factor),
+            None => query,
+        };
+
+        if let Some(kv_cache) = kv_cache.as_mut() {
+            let slot_mapping = try_api!(input_metadata
+                .slot_mapping
+                .flatten(0, input_metadata.slot_mapping.dims().len()));
+            try_api!(unsafe {
+                reshape_and_cache_mla(kv_latent.clone(), k_pe.clone(), kv_cache, slot_mapping)
+            });
+        }
+
+        let num_prompt_tokens = input_metadata.num_prompt_tokens();
+        let mut outputs = Vec::with_capacity(2);
+        let cached_context = input_metadata
+            .prompt
+            .as_ref()
+            .and_then(|prompt| prompt.cached_context.as_ref());
+        if let Some(cached_context) = cached_context {
+            // The new prompt tokens attend to their prompts in the cache, which holds the cached
+            // tokens missing from the slice, see `PromptMetadata::cached_context`.
+            outputs.push(self.cache_attention(
+                slice_rows(&query, 0, num_prompt_tokens)?,
+                kv_cache.as_ref(),
+                cached_context,
+            )?);
+        } else if input_metadata.prompt.is_some() {
+            outputs.push(self.prompt_attention(
+                slice_rows(&query, 0, num_prompt_tokens)?,
+                slice_rows(kv_latent, 0, num_prompt_tokens)?,
+                slice_rows(k_pe, 0, num_prompt_tokens)?,
+                input_metadata,
+            )?);
+        }
+        if let Some(decode) = &input_metadata.decode {
+            outputs.push(self.cache_attention(
+                slice_rows(&query, num_prompt_tokens, num_tokens - num_prompt_tokens)?,
+                kv_cache.as_ref(),
+                decode,
+            )?);
+        }
+        let output = match outputs.len() {
+            1 => outputs.pop().unwrap(),
+            _ => try_api!(Tensor::cat(&outputs, 0)),
+        };
+
+        // Expand the latent output into the value heads: output @ w_uv, per head.
+        let output = try_api!(try_api!(output.transpose(0, 1)).contiguous());
+        let output = try_api!(try_api!(output.matmul(&self.w_uv)).transpose(0, 1));
+        output
+            .reshape((num_tokens, self.num_heads * self.dims.v_head_dim))
+            .map_err(APIError::from)
+    }
+
+    /// Attention of each query over the latent cache described by `decode`.
+    fn cache_attention(
+        &self,
+        query: Tensor,
+        kv_cache: Option<&Tensor>,
+        decode: &DecodeMetadata,
+    ) -> Result<Tensor, APIError> {
+        let Some(kv_cache) = kv_cache else {
+            return Err(APIError::new_str(
+                "Attending the cache with multi-head latent attention requires the latent cache.",
+            ));
+        };
+        if decode.tree_mask.is_some() {
+            return Err(APIError::new_str(
+                "Tree attention is not supported with multi-head latent attention.",
+            ));
+        }
+        mla_decode_attention(
+            query,
+            kv_cache.clone(),
+            decode.block_tables.clone(),
+            decode.context_lens.clone(),
+            self.dims.kv_lora_rank,
+            self.scale,
+        )
+    }
+
+    /// Attention within the packed prompts on the latents, with the prompts padded to the longest
+    /// one. All heads share the single latent KV head, so the heads are folded into the query
+    /// rows. The output is packed again, shape = [num_prompt_tokens, num_heads, kv_lora_rank].
+    fn prompt_attention(
+        &self,
+        query: Tensor,
+        kv_latent: Tensor,
+        k_pe: Tensor,
+        input_metadata: &InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let prompt = input_metadata.prompt.as_ref().unwrap();
+        let num_prompts = prompt.prompt_lens.len();
+        let seq_len = prompt.max_prompt_len;
+        let entry_size = self.dims.cache_entry_size();
+        let pad_indices = prompt.pad_indices(query.device())?;
+        let unpad_indices = prompt.unpad_indices(query.device())?;
+
+        // [num_prompts, num_heads * seq_len, entry_size]
+        let query = try_api!(try_api!(query.index_select(&pad_indices, 0)).reshape((
+            num_prompts,
+            seq_len,
+            self.num_heads,
+            entry_size
+        )));
+        let query = try_api!(
+            try_api!(try_api!(query.transpose(1, 2)).contiguous()).reshape((
+                num_prompts,
+                self.num_heads * seq_len,
+                entry_size
+            ))
+        );
+        let value = try_api!(try_api!(kv_latent.index_select(&pad_indices, 0)).reshape((
+            num_prompts,
+            seq_len,
+            self.dims.kv_lora_rank
+        )));
+        let key =
+            try_api!(try_api!(Tensor::cat(&[&kv_latent, &k_pe], D::Minus1))
+                .index_select(&pad_indices, 0));
+        let key = try_api!(try_api!(key.reshape((num_prompts, seq_len, entry_size))).t());
+
+        // The mask of each prompt, causal if not given.
+        let bias = prompt
+            .attn_bias
+            .as_ref()
+            .unwrap_or(&AttentionBias::Causal)
+            .materialize(num_prompts, seq_len, query.dtype(), query.device(), None)?;
+        let bias =
+            try_api!(try_api!(bias.reshape(((), 1, seq_len, seq_len))).to_device(query.device()));
+
+        let attn_weights =
+            try_api!(try_api!(query.matmul(&try_api!(key.contiguous()))) * f64::from(self.scale));
+        let attn_weights =
+            try_api!(attn_weights.reshape((num_prompts, self.num_heads, seq_len, seq_len)));
+        let attn_weights = try_api!(attn_weights.broadcast_add(&bias));
+        let attn_weights = try_api!(candle_nn::ops::softmax_last_dim(&attn_weights));
+        let attn_weights =
+            try_api!(attn_weights.reshape((num_prompts, self.num_heads * seq_len, seq_len)));
+
+        // [num_prompts, num_heads, seq_len, kv_lora_rank] -> packed tokens
+        let output = try_api!(try_api!(attn_weights.matmul(&value)).reshape((
+            num_prompts,
+            self.num_heads,
+            seq_len,
+            self.dims.kv_lora_rank
+        )));
+        let output = try_api!(
+            try_api!(try_api!(output.transpose(1, 2)).contiguous()).reshape((
+                num_prompts * seq_len,
+                self.num_heads,
+                self.dims.kv_lora_rank
+            ))
+        );
+        output
+            .index_select(&unpad_indices, 0)
+            .map_err(APIError::from)
+    }
+}
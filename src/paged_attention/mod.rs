@@ -1,19 +1,31 @@
 use candle_core::{DType, Device, Tensor};
 
 use crate::{
-    backend::{paged_attention_v1, paged_attention_v2, reshape_and_cache},
+    backend::{
+        is_nhd_layout, reshape_and_cache, reshape_and_cache_fp8, reshape_and_cache_int8,
+        rotary_embedding, rotary_embedding_and_cache,
+    },
     openai::responses::APIError,
     try_api,
 };
 
-use self::input_metadata::InputMetadata;
-mod attn_bias;
-pub(crate) mod input_metadata;
+use self::{
+    attention_backend::{select_decode_backend, select_prompt_backend},
+    input_metadata::{DecodeMetadata, InputMetadata},
+};
+pub mod attention_backend;
+pub(crate) mod attn_bias;
+mod blockwise_attention;
+#[cfg(feature = "cuda")]
+mod flash_attention;
+pub mod input_metadata;
 mod memory_efficient_attention;
+pub mod mla;
 use memory_efficient_attention::_memory_efficient_attention;
+pub mod sparse;
 pub(crate) mod utils;
 
-const _PARTITION_SIZE: usize = 512;
+use sparse::SparseAttentionConfig;
 
 #[allow(dead_code)]
 pub struct PagedAttention {
@@ -24,6 +36,10 @@ pub struct PagedAttention {
     sliding_window: Option<usize>,
     num_queries_per_kv: usize,
     alibi_slopes: Option<Tensor>,
+    sparse_attention: Option<SparseAttentionConfig>,
+    kv_cache_scales: Option<Tensor>,
+    rope_theta: Option<f32>,
+    logits_soft_cap: Option<f32>,
 }
 
 impl PagedAttention {
@@ -37,6 +53,11 @@ impl PagedAttention {
         alibi_slopes: Option<Vec<f64>>,
     ) -> Result<Self, APIError> {
         let num_key_value_heads = num_key_value_heads.unwrap_or(num_attention_heads);
+        if num_attention_heads % num_key_value_heads != 0 {
+            return Err(APIError::new(format!(
+                "The number of attention heads ({num_attention_heads}) must be a multiple of the number of KV heads ({num_key_value_heads})."
+            )));
+        }
         let num_queries_per_kv = num_attention_heads / num_key_value_heads;
         let alibi_slopes = if let Some(alibi_slopes) = alibi_slopes {
             Some(try_api!(Tensor::new(alibi_slopes, &device)))
@@ -51,13 +72,55 @@ impl PagedAttention {
             sliding_window,
             num_queries_per_kv,
             alibi_slopes,
+            sparse_attention: None,
+            kv_cache_scales: None,
+            rope_theta: None,
+            logits_soft_cap: None,
         })
     }
 
+    /// Use block-sparse attention for long contexts during decoding, see `sparse`.
+    pub fn with_sparse_attention(
+        mut self,
+        sparse_attention: Option<SparseAttentionConfig>,
+    ) -> Self {
+        self.sparse_attention = sparse_attention;
+        self
+    }
+
+    /// Per-head scales of an FP8 KV cache, shape = [2, num_kv_heads] with the key scales
+    /// followed by the value scales. A scale of 1 is used if not given.
+    pub fn with_kv_cache_scales(mut self, kv_cache_scales: Option<Tensor>) -> Self {
+        self.kv_cache_scales = kv_cache_scales;
+        self
+    }
+
+    /// The base of the rotary embedding (rotate-half layout) applied to the keys, needed to move
+    /// cached keys to another position, see `InputMetadata::block_position_shifts`.
+    pub fn with_rope_theta(mut self, rope_theta: Option<f32>) -> Self {
+        self.rope_theta = rope_theta;
+        self
+    }
+
+    /// Soft-cap the scaled attention scores to (-cap, cap) with `cap * tanh(score / cap)`, e.g.
+    /// `attn_logit_softcapping` of Gemma-2.
+    pub fn with_logits_soft_cap(mut self, logits_soft_cap: Option<f32>) -> Self {
+        self.logits_soft_cap = logits_soft_cap;
+        self
+    }
+
+    /// The attention window of this layer. A window of the layer itself (e.g. for models which
+    /// alternate between local and global layers) takes precedence over the model-wide one.
+    fn get_sliding_window(&self, input_metadata: &InputMetadata) -> Option<usize> {
+        self.sliding_window.or(input_metadata.sliding_window)
+    }
+
     /// Args:
     /// output: shape = [num_generation_tokens, num_heads, head_size]
     ///
-    /// query: shape = [num_generation_tokens, num_heads, head_size]
+    /// query: shape = [num_generation_tokens, num_heads, head_size], with the same number of
+    ///     generation tokens for each sequence. Several tokens per sequence (e.g. the candidates
+    ///     of speculative decoding) are verified in one launch, causally among each other.
     ///
     /// key_cache: shape = [num_blocks, num_kv_heads, head_size/x,
     ///     block_size, x]
@@ -65,7 +128,7 @@ impl PagedAttention {
     /// value_cache: shape = [num_blocks, num_kv_heads, head_size,
     ///     block_size]
     ///
-    /// input_metadata: metadata for paged attention.
+    /// input_metadata: metadata for paged attention, with a decode slice.
     ///
     /// alibi_slopes: shape = [num_heads]
     pub fn _paged_attention(
@@ -76,55 +139,95 @@ impl PagedAttention {
         input_metadata: &mut InputMetadata,
         alibi_slopes: Option<Tensor>,
     ) -> Result<Tensor, APIError> {
-        let block_size = *value_cache.shape().dims().get(3).unwrap();
-        let (num_seqs, num_heads, _head_size) = try_api!(query.shape().dims3());
-        let max_num_partitions =
-            (input_metadata.max_context_len.unwrap() + _PARTITION_SIZE - 1) / _PARTITION_SIZE;
-
-        let use_v1 = input_metadata.max_context_len.unwrap() <= 8192
-            && (max_num_partitions == 1 || num_seqs * num_heads > 512);
-        let output = if use_v1 {
-            //Run PagedAttention V1
-            paged_attention_v1(
-                query,
-                key_cache,
-                value_cache,
-                self.num_key_value_heads.try_into().unwrap(),
-                self.scale,
-                input_metadata.block_tables.as_ref().unwrap().clone(),
-                input_metadata.context_lens.as_ref().unwrap().clone(),
-                block_size,
-                input_metadata.max_context_len.unwrap(),
-                alibi_slopes,
-                &input_metadata.kv_cache_dtype,
-            )?
-        } else {
-            //Run PagedAttention V2
-            assert_eq!(_PARTITION_SIZE % block_size, 0);
+        self.attend_cache(
+            query,
+            key_cache,
+            value_cache,
+            input_metadata.decode.as_ref().unwrap(),
+            &input_metadata.kv_cache_dtype,
+            self.get_sliding_window(input_metadata),
+            alibi_slopes,
+            input_metadata,
+        )
+    }
 
-            let exp_sums = try_api!(Tensor::zeros(
-                (num_seqs, num_heads, max_num_partitions),
-                DType::F32,
-                query.device(),
-            ));
-            let max_logits = try_api!(exp_sums.zeros_like());
-
-            paged_attention_v2(
-                exp_sums,
-                max_logits,
-                query,
-                key_cache,
-                value_cache,
-                self.num_key_value_heads.try_into().unwrap(),
-                self.scale,
-                input_metadata.block_tables.as_ref().unwrap().clone(),
-                input_metadata.context_lens.as_ref().unwrap().clone(),
-                block_size,
-                input_metadata.max_context_len.unwrap(),
-                alibi_slopes,
-            )
+    /// Attention of each query over the cached context described by `decode`, see
+    /// `_paged_attention`.
+    #[allow(clippy::too_many_arguments)]
+    fn attend_cache(
+        &self,
+        query: Tensor,
+        key_cache: Tensor,
+        value_cache: Tensor,
+        decode: &DecodeMetadata,
+        kv_cache_dtype: &str,
+        sliding_window: Option<usize>,
+        alibi_slopes: Option<Tensor>,
+        input_metadata: &InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let num_queries = try_api!(query.dim(0));
+        let mut max_context_len = decode.max_context_len;
+        let mut block_tables = decode.block_tables.clone();
+        let mut context_lens = decode.context_lens.clone();
+        // Block selection scores the cached keys directly, which it cannot do for a quantized
+        // cache or keys which still have to be moved to their position. It selects the blocks
+        // of a sequence for a single query token.
+        if let Some(sparse) = self
+            .sparse_attention
+            .as_ref()
+            .filter(|sparse| max_context_len >= sparse.min_context_len)
+            .filter(|_| kv_cache_dtype == "auto")
+            .filter(|_| decode.block_position_shifts.is_none())
+            .filter(|_| {
+                context_lens
+                    .dims1()
+                    .is_ok_and(|num_seqs| num_seqs == num_queries)
+            })
+        {
+            let selected =
+                sparse.select_blocks(&query, &key_cache, &block_tables, &context_lens)?;
+            block_tables = selected.block_tables;
+            context_lens = selected.context_lens;
+            max_context_len = selected.max_context_len;
+        }
+        let decode = DecodeMetadata {
+            max_context_len,
+            block_tables,
+            context_lens,
+            block_position_shifts: decode.block_position_shifts.clone(),
+            tree_mask: decode.tree_mask.clone(),
         };
-        Ok(output)
+        select_decode_backend(
+            input_metadata.attention_backend,
+            self,
+            &query,
+            max_context_len,
+            input_metadata.v2_min_context_len,
+        )?
+        .decode_attention(
+            self,
+            query,
+            key_cache,
+            value_cache,
+            &decode,
+            kv_cache_dtype,
+            sliding_window,
+            alibi_slopes,
+            input_metadata.attention_compute,
+        )
+    }
+
+    /// Attention within the packed prompts, with the backend picked by `select_prompt_backend`.
+    fn _prompt_attention(
+        &self,
+        query: Tensor,
+        key: Tensor,
+        value: Tensor,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let prompt = input_metadata.prompt.as_ref().unwrap();
+        select_prompt_backend(input_metadata.attention_backend, self, &query, prompt)
+            .prompt_attention(self, query, key, value, input_metadata)
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -161,7 +264,8 @@ impl PagedAttention {
     ///     block_size, x]
     /// value_cache: shape = [num_blocks, num_kv_heads, head_size,
     ///     block_size]
-    /// input_metadata: metadata for paged attention.
+    /// input_metadata: metadata for paged attention. The batch_size * seq_len tokens are the
+    ///     prompt slice followed by the decode slice, see `InputMetadata`.
     pub fn forward(
         &mut self,
         query: Tensor,
@@ -182,40 +286,307 @@ impl PagedAttention {
             .flatten(0, input_metadata.slot_mapping.dims().len()));
 
         if key_cache.as_ref().is_some_and(|_| value_cache.is_some()) {
-            try_api!(unsafe {
-                reshape_and_cache(
+            self.write_cache(
+                &key,
+                &value,
+                key_cache.as_mut().unwrap(),
+                value_cache.as_mut().unwrap(),
+                slot_mapping,
+                input_metadata,
+            )?;
+        }
+
+        self.attend(
+            query,
+            key,
+            value,
+            key_cache,
+            value_cache,
+            input_metadata,
+            (batch_size, seq_len, hidden_size),
+        )
+    }
+
+    /// Like `forward`, but first applies the rotary embedding (see `rotary_embedding`) to the
+    /// query and key. With an unquantized cache in the split layout, the rotation and the cache
+    /// write are fused into a single kernel.
+    #[allow(clippy::too_many_arguments)]
+    #[allow(unused_variables)]
+    pub fn forward_with_rotary_embedding(
+        &mut self,
+        query: Tensor,
+        key: Tensor,
+        value: Tensor,
+        positions: &Tensor,
+        cos_sin_cache: &Tensor,
+        is_neox: bool,
+        mut key_cache: Option<Tensor>,
+        mut value_cache: Option<Tensor>,
+        input_metadata: &mut InputMetadata,
+        dtype: DType,
+        device: Device,
+    ) -> Result<Tensor, APIError> {
+        let (batch_size, seq_len, hidden_size) = try_api!(query.shape().dims3());
+        let mut query = try_api!(query.reshape(((), self.num_attention_heads, self.head_dim)));
+        let mut key = try_api!(key.reshape(((), self.num_key_value_heads, self.head_dim)));
+        let value = try_api!(value.reshape(((), self.num_key_value_heads, self.head_dim)));
+        let positions = try_api!(positions.flatten_all());
+        let slot_mapping = try_api!(input_metadata
+            .slot_mapping
+            .flatten(0, input_metadata.slot_mapping.dims().len()));
+
+        match (key_cache.as_mut(), value_cache.as_mut()) {
+            // The fused kernel only writes the split cache layout.
+            (Some(key_cache), Some(value_cache))
+                if input_metadata.kv_cache_dtype == "auto" && !is_nhd_layout(key_cache) =>
+            {
+                try_api!(unsafe {
+                    rotary_embedding_and_cache(
+                        positions,
+                        &mut query,
+                        &mut key,
+                        value.clone(),
+                        key_cache,
+                        value_cache,
+                        slot_mapping,
+                        cos_sin_cache.clone(),
+                        is_neox,
+                    )
+                });
+            }
+            caches => {
+                // The kernels take the heads of a token as one row.
+                let mut query_rows = try_api!(query.flatten_from(1));
+                let mut key_rows = try_api!(key.flatten_from(1));
+                try_api!(unsafe {
+                    rotary_embedding(
+                        positions,
+                        &mut query_rows,
+                        &mut key_rows,
+                        self.head_dim,
+                        cos_sin_cache.clone(),
+                        is_neox,
+                    )
+                });
+                query = try_api!(query_rows.reshape(query.shape()));
+                key = try_api!(key_rows.reshape(key.shape()));
+                if let (Some(key_cache), Some(value_cache)) = caches {
+                    self.write_cache(
+                        &key,
+                        &value,
+                        key_cache,
+                        value_cache,
+                        slot_mapping,
+                        input_metadata,
+                    )?;
+                }
+            }
+        }
+
+        self.attend(
+            query,
+            key,
+            value,
+            key_cache,
+            value_cache,
+            input_metadata,
+            (batch_size, seq_len, hidden_size),
+        )
+    }
+
+    /// Cross-attention of an encoder-decoder model: every query attends all encoder outputs of its
+    /// sequence, see `InputMetadata::cross`.
+    ///
+    /// query: shape = [batch_size, seq_len, num_heads * head_size], the tokens of the step.
+    /// key: shape = [num_prompts, max_encoder_len, num_kv_heads * head_size], the projected
+    ///     encoder outputs of the prompts. They are written into the cross blocks and attended
+    ///     from the cache afterwards, so they are only needed with a prompt slice.
+    /// value: shape = [num_prompts, max_encoder_len, num_kv_heads * head_size]
+    /// key_cache, value_cache: the caches of the layer, which hold the cross blocks.
+    #[allow(clippy::too_many_arguments)]
+    pub fn forward_cross_attention(
+        &mut self,
+        query: Tensor,
+        key: Option<Tensor>,
+        value: Option<Tensor>,
+        mut key_cache: Tensor,
+        mut value_cache: Tensor,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        let (batch_size, seq_len, hidden_size) = try_api!(query.shape().dims3());
+        let query = try_api!(query.reshape(((), self.num_attention_heads, self.head_dim)));
+        let Some(cross) = input_metadata.cross.as_ref() else {
+            return Err(APIError::new_str(
+                "Cross-attention requires the encoder metadata of the step.",
+            ));
+        };
+        match (key, value, cross.slot_mapping.as_ref()) {
+            (Some(key), Some(value), Some(slot_mapping)) => {
+                let key = try_api!(key.reshape(((), self.num_key_value_heads, self.head_dim)));
+                let value = try_api!(value.reshape(((), self.num_key_value_heads, self.head_dim)));
+                self.write_cache(
+                    &key,
+                    &value,
+                    &mut key_cache,
+                    &mut value_cache,
+                    slot_mapping.clone(),
+                    input_metadata,
+                )?;
+            }
+            (None, None, None) => {}
+            _ => {
+                return Err(APIError::new_str(
+                    "The encoder outputs must be given exactly for the steps with prompts.",
+                ))
+            }
+        }
+
+        // Each query is attended as its own sequence over all of its encoder outputs, so the
+        // attention is not causal.
+        let num_tokens = try_api!(query.dim(0));
+        let seq_indices = input_metadata.token_seq_indices(num_tokens, query.device())?;
+        let decode = DecodeMetadata {
+            max_context_len: cross.max_encoder_len,
+            block_tables: try_api!(cross.block_tables.index_select(&seq_indices, 0)),
+            context_lens: try_api!(cross.context_lens.index_select(&seq_indices, 0)),
+            block_position_shifts: None,
+            tree_mask: None,
+        };
+        let output = self.attend_cache(
+            query,
+            key_cache,
+            value_cache,
+            &decode,
+            &input_metadata.kv_cache_dtype,
+            None,
+            None,
+            input_metadata,
+        )?;
+        output
+            .reshape((batch_size, seq_len, hidden_size))
+            .map_err(APIError::from)
+    }
+
+    /// Write the new keys and values into the caches, quantizing them for an FP8 or INT8 cache.
+    fn write_cache(
+        &self,
+        key: &Tensor,
+        value: &Tensor,
+        key_cache: &mut Tensor,
+        value_cache: &mut Tensor,
+        slot_mapping: Tensor,
+        input_metadata: &InputMetadata,
+    ) -> Result<(), APIError> {
+        try_api!(unsafe {
+            match input_metadata.kv_cache_dtype.as_str() {
+                "fp8_e4m3" => reshape_and_cache_fp8(
                     key.clone(),
                     value.clone(),
-                    key_cache.as_mut().unwrap(),
-                    value_cache.as_mut().unwrap(),
+                    key_cache,
+                    value_cache,
                     slot_mapping,
-                )
-            });
-        }
+                    self.kv_cache_scales.as_ref(),
+                ),
+                "int8" => reshape_and_cache_int8(
+                    key.clone(),
+                    value.clone(),
+                    key_cache,
+                    value_cache,
+                    slot_mapping,
+                ),
+                _ => reshape_and_cache(
+                    key.clone(),
+                    value.clone(),
+                    key_cache,
+                    value_cache,
+                    slot_mapping,
+                ),
+            }
+        });
+        Ok(())
+    }
 
-        let output = if input_metadata.is_prompt {
-            self._normal_attention(
-                query,
-                key,
-                value,
+    /// The attention within the prompt slice and over the caches for the decode slice, once the
+    /// new keys and values are cached. Returns the output in the shape of the query given to
+    /// `forward`.
+    #[allow(clippy::too_many_arguments)]
+    fn attend(
+        &mut self,
+        query: Tensor,
+        key: Tensor,
+        value: Tensor,
+        key_cache: Option<Tensor>,
+        value_cache: Option<Tensor>,
+        input_metadata: &mut InputMetadata,
+        (batch_size, seq_len, hidden_size): (usize, usize, usize),
+    ) -> Result<Tensor, APIError> {
+        let query = match input_metadata.query_scale_factor(self.scale) {
+            Some(factor) => try_api!(query * factor),
+            None => query,
+        };
+        let num_tokens = try_api!(query.dim(0));
+        let num_prompt_tokens = input_metadata.num_prompt_tokens();
+        let mut outputs = Vec::with_capacity(2);
+        let cached_context = input_metadata
+            .prompt
+            .as_ref()
+            .and_then(|prompt| prompt.cached_context.as_ref());
+        if let Some(cached_context) = cached_context {
+            // The prompts reuse cached tokens which are not in the slice, so the new tokens
+            // attend to their prompts in the cache, where they were just written.
+            let (Some(key_cache), Some(value_cache)) = (&key_cache, &value_cache) else {
+                return Err(APIError::new_str(
+                    "Prompts with cached tokens require the KV cache.",
+                ));
+            };
+            outputs.push(self.attend_cache(
+                slice_rows(&query, 0, num_prompt_tokens)?,
+                key_cache.clone(),
+                value_cache.clone(),
+                cached_context,
+                &input_metadata.kv_cache_dtype,
+                self.get_sliding_window(input_metadata),
+                self.alibi_slopes.clone(),
                 input_metadata,
-                seq_len,
-                batch_size,
-                &device,
-                dtype,
-            )?
-        } else {
-            self._paged_attention(
-                query,
+            )?);
+        } else if input_metadata.prompt.is_some() {
+            outputs.push(self._prompt_attention(
+                slice_rows(&query, 0, num_prompt_tokens)?,
+                slice_rows(&key, 0, num_prompt_tokens)?,
+                slice_rows(&value, 0, num_prompt_tokens)?,
+                input_metadata,
+            )?);
+        }
+        if input_metadata.decode.is_some() {
+            outputs.push(self._paged_attention(
+                slice_rows(&query, num_prompt_tokens, num_tokens - num_prompt_tokens)?,
                 key_cache.as_ref().unwrap().clone(),
                 value_cache.as_ref().unwrap().clone(),
                 input_metadata,
                 None,
-            )?
-        };
+            )?);
+        }
 
+        let output = match outputs.len() {
+            1 => outputs.pop().unwrap(),
+            _ => try_api!(Tensor::cat(&outputs, 0)),
+        };
         output
             .reshape((batch_size, seq_len, hidden_size))
             .map_err(APIError::from)
     }
 }
+
+/// Rows [start, start + len) of `x`. A slice which does not start at the first row is copied, as
+/// the CUDA kernels ignore the offset of a tensor into its storage.
+fn slice_rows(x: &Tensor, start: usize, len: usize) -> Result<Tensor, APIError> {
+    if start == 0 && len == try_api!(x.dim(0)) {
+        return Ok(x.clone());
+    }
+    let rows = try_api!(x.narrow(0, start, len));
+    if start == 0 {
+        Ok(rows)
+    } else {
+        rows.copy().map_err(APIError::from)
+    }
+}
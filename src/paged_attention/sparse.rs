@@ -0,0 +1,187 @@
+//! Block-sparse decode attention for very long contexts.
+//!
+//! Instead of attending to every cached block, each sequence attends to a few "sink" blocks at
+//! the start of the context, the most recent "local" blocks, and the `top_k_blocks` blocks in
+//! between whose mean key scores highest against the current query. The selected blocks are
+//! passed to the regular paged attention kernels as a shortened block table.
+//!
+//! Quality tradeoff: attention mass on unselected blocks is dropped, so retrieval of facts which
+//! are spread thinly across a long document degrades first. Mean keys are a coarse summary;
+//! blocks whose tokens disagree strongly can be ranked low even if one token matters. Keep
+//! `top_k_blocks` large enough that the selected tokens cover the attention pattern of the
+//! model (a few thousand tokens is a reasonable starting point), and only enable this past the
+//! context length where dense attention dominates the step time. Scoring still reads every
+//! key once per step, but skips the values and the per-token softmax of the dense kernels.
+
+use candle_core::{DType, IndexOp, Tensor, D};
+use serde::Deserialize;
+
+use crate::{
+    backend::{is_nhd_layout, key_cache_block_dims},
+    openai::responses::APIError,
+    try_api,
+};
+
+/// Selected per model, see `PagedAttention::with_sparse_attention`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SparseAttentionConfig {
+    /// Sequences with shorter contexts use dense attention.
+    #[serde(default = "default_min_context_len")]
+    pub min_context_len: usize,
+    /// Number of blocks selected by score, besides the sink and local blocks.
+    #[serde(default = "default_top_k_blocks")]
+    pub top_k_blocks: usize,
+    /// Leading blocks which are always attended to. Many models place a large share of
+    /// attention on the first tokens.
+    #[serde(default = "default_num_sink_blocks")]
+    pub num_sink_blocks: usize,
+    /// Trailing blocks which are always attended to, including the block being written.
+    #[serde(default = "default_num_local_blocks")]
+    pub num_local_blocks: usize,
+}
+
+fn default_min_context_len() -> usize {
+    65536
+}
+
+fn default_top_k_blocks() -> usize {
+    256
+}
+
+fn default_num_sink_blocks() -> usize {
+    1
+}
+
+fn default_num_local_blocks() -> usize {
+    8
+}
+
+impl Default for SparseAttentionConfig {
+    fn default() -> Self {
+        Self {
+            min_context_len: default_min_context_len(),
+            top_k_blocks: default_top_k_blocks(),
+            num_sink_blocks: default_num_sink_blocks(),
+            num_local_blocks: default_num_local_blocks(),
+        }
+    }
+}
+
+/// The shortened inputs for the paged attention kernels.
+pub(crate) struct SparseBlockTables {
+    /// [num_seqs, max_num_selected_blocks], I64
+    pub block_tables: Tensor,
+    /// [num_seqs], I64
+    pub context_lens: Tensor,
+    pub max_context_len: usize,
+}
+
+impl SparseAttentionConfig {
+    /// Select the blocks each sequence attends to.
+    ///
+    /// - query: [num_seqs, num_heads, head_size]
+    /// - key_cache: [num_blocks, num_kv_heads, head_size/x, block_size, x]
+    /// - block_tables: [num_seqs, max_num_blocks_per_seq]
+    /// - context_lens: [num_seqs]
+    pub(crate) fn select_blocks(
+        &self,
+        query: &Tensor,
+        key_cache: &Tensor,
+        block_tables: &Tensor,
+        context_lens: &Tensor,
+    ) -> Result<SparseBlockTables, APIError> {
+        let (num_seqs, num_heads, head_size) = try_api!(query.dims3());
+        let (block_size, num_kv_heads) = key_cache_block_dims(key_cache);
+        let num_queries_per_kv = num_heads / num_kv_heads;
+        let tables = try_api!(try_api!(
+            try_api!(block_tables.reshape((num_seqs, ()))).to_dtype(DType::I64)
+        )
+        .to_vec2::<i64>());
+        let lens = try_api!(try_api!(context_lens.to_dtype(DType::I64)).to_vec1::<i64>());
+
+        let mut selected_tables = Vec::with_capacity(num_seqs);
+        let mut selected_lens = Vec::with_capacity(num_seqs);
+        for (seq, (table, context_len)) in tables.into_iter().zip(lens).enumerate() {
+            let context_len = context_len as usize;
+            let num_blocks = context_len.div_ceil(block_size);
+            let table = &table[..num_blocks];
+            let budget = self.num_sink_blocks + self.top_k_blocks + self.num_local_blocks;
+            if context_len < self.min_context_len || num_blocks <= budget {
+                selected_tables.push(table.to_vec());
+                selected_lens.push(context_len);
+                continue;
+            }
+
+            let candidates = &table[self.num_sink_blocks..num_blocks - self.num_local_blocks];
+            let candidate_ids = try_api!(Tensor::from_vec(
+                candidates.iter().map(|x| *x as u32).collect::<Vec<_>>(),
+                candidates.len(),
+                key_cache.device(),
+            ));
+            // [c, kv_heads, head_size/x, block_size, x] or [c, block_size, kv_heads, head_size]
+            // -> [c, kv_heads, head_size]
+            let summaries =
+                try_api!(try_api!(key_cache.index_select(&candidate_ids, 0)).to_dtype(DType::F32));
+            let token_dim = if is_nhd_layout(key_cache) { 1 } else { 3 };
+            let summaries = try_api!(try_api!(summaries.mean(token_dim)).reshape((
+                candidates.len(),
+                num_kv_heads,
+                1,
+                head_size
+            )));
+            // [num_heads, head_size] -> [1, kv_heads, queries_per_kv, head_size]
+            let q = try_api!(
+                try_api!(try_api!(query.i(seq)).to_dtype(DType::F32)).reshape((
+                    1,
+                    num_kv_heads,
+                    num_queries_per_kv,
+                    head_size
+                ))
+            );
+            // A block is kept if any head attends to it strongly.
+            let scores = try_api!(
+                try_api!(try_api!(summaries.broadcast_mul(&q)).sum(D::Minus1)).flatten_from(1)
+            );
+            let scores = try_api!(try_api!(scores.max(D::Minus1)).to_vec1::<f32>());
+
+            let mut ranked = (0..candidates.len()).collect::<Vec<_>>();
+            ranked.sort_by(|a, b| scores[*b].total_cmp(&scores[*a]));
+            ranked.truncate(self.top_k_blocks);
+            ranked.sort_unstable();
+
+            let mut selected = table[..self.num_sink_blocks].to_vec();
+            selected.extend(ranked.into_iter().map(|i| candidates[i]));
+            selected.extend_from_slice(&table[num_blocks - self.num_local_blocks..]);
+            // Only the last block is partially filled.
+            let last_block_len = context_len - (num_blocks - 1) * block_size;
+            selected_lens.push((selected.len() - 1) * block_size + last_block_len);
+            selected_tables.push(selected);
+        }
+
+        let max_num_selected = selected_tables.iter().map(Vec::len).max().unwrap_or(0);
+        let padded = selected_tables
+            .into_iter()
+            .flat_map(|mut table| {
+                table.resize(max_num_selected, 0);
+                table
+            })
+            .collect::<Vec<_>>();
+        let max_context_len = selected_lens.iter().copied().max().unwrap_or(0);
+        Ok(SparseBlockTables {
+            block_tables: try_api!(Tensor::from_vec(
+                padded,
+                (num_seqs, max_num_selected),
+                query.device()
+            )),
+            context_lens: try_api!(Tensor::from_vec(
+                selected_lens
+                    .into_iter()
+                    .map(|x| x as i64)
+                    .collect::<Vec<_>>(),
+                num_seqs,
+                query.device()
+            )),
+            max_context_len,
+        })
+    }
+}
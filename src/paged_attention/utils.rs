@@ -48,10 +48,11 @@ pub(crate) fn materialize_causal_mask(
 
     let mut mask = try_api!(apply_triangular(&tensor, shift.try_into().unwrap(), false));
     if let Some(window_size) = window_size {
+        // Mask out the keys more than `window_size - 1` positions before the query.
         mask = try_api!(apply_triangular(
             &mask,
-            (shift - window_size + 1).try_into().unwrap(),
-            false
+            shift as isize - window_size as isize + 1,
+            true
         ));
     }
     try_api!(mask.log()).to_dtype(dtype).map_err(APIError::from)
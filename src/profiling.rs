@@ -0,0 +1,167 @@
+//! Profiler ranges around the phases of an engine step (scheduling, cache operations, prefill,
+//! decode and sampling). With the `nvtx` feature each range is also pushed as an NVTX range, so
+//! Nsight Systems traces show where the step time goes. `profile_steps` captures the ranges of
+//! the next steps into a Chrome trace (chrome://tracing or Perfetto).
+//!
+//! The ranges measure host time. The device work of a step is waited for when the sampled tokens
+//! are copied back, so the `sample` range of a step also covers the tail of its forward pass.
+
+use std::{
+    cell::Cell,
+    fs::File,
+    io::BufWriter,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex, MutexGuard,
+    },
+    time::Instant,
+};
+
+use serde::Serialize;
+
+use crate::{log_warning, openai::responses::APIError, try_api};
+
+static PROFILING: AtomicBool = AtomicBool::new(false);
+static STEP_PROFILER: Mutex<Option<StepProfiler>> = Mutex::new(None);
+static NEXT_THREAD_ID: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    static THREAD_ID: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// A complete event of the Chrome trace format.
+#[derive(Serialize)]
+struct TraceEvent {
+    name: &'static str,
+    ph: &'static str,
+    /// Start in microseconds since the capture started.
+    ts: f64,
+    /// Duration in microseconds.
+    dur: f64,
+    pid: u32,
+    tid: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ChromeTrace {
+    trace_events: Vec<TraceEvent>,
+    display_time_unit: &'static str,
+}
+
+/// Captures the ranges of a number of engine steps and writes them as a Chrome trace.
+struct StepProfiler {
+    remaining_steps: usize,
+    path: PathBuf,
+    start: Instant,
+    events: Vec<TraceEvent>,
+}
+
+impl StepProfiler {
+    fn write(self) -> Result<(), APIError> {
+        let file = try_api!(File::create(&self.path));
+        let trace = ChromeTrace {
+            trace_events: self.events,
+            display_time_unit: "ms",
+        };
+        try_api!(serde_json::to_writer(BufWriter::new(file), &trace));
+        Ok(())
+    }
+}
+
+fn step_profiler() -> MutexGuard<'static, Option<StepProfiler>> {
+    STEP_PROFILER
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn thread_id() -> u64 {
+    THREAD_ID.with(|id| match id.get() {
+        Some(tid) => tid,
+        None => {
+            let tid = NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed);
+            id.set(Some(tid));
+            tid
+        }
+    })
+}
+
+/// Capture the ranges of the next `num_steps` engine steps (of all engines) and write them as a
+/// Chrome trace to `path` once done.
+pub fn profile_steps(num_steps: usize, path: impl Into<PathBuf>) {
+    if num_steps == 0 {
+        return;
+    }
+    *step_profiler() = Some(StepProfiler {
+        remaining_steps: num_steps,
+        path: path.into(),
+        start: Instant::now(),
+        events: Vec::new(),
+    });
+    PROFILING.store(true, Ordering::Release);
+}
+
+/// Mark the end of an engine step, writing the trace after the last captured step.
+pub fn end_step() {
+    if !PROFILING.load(Ordering::Acquire) {
+        return;
+    }
+    let mut profiler = step_profiler();
+    let Some(current) = profiler.as_mut() else {
+        return;
+    };
+    current.remaining_steps -= 1;
+    if current.remaining_steps > 0 {
+        return;
+    }
+    PROFILING.store(false, Ordering::Release);
+    let finished = profiler.take().unwrap();
+    let path = finished.path.clone();
+    match finished.write() {
+        Ok(()) => println!("Wrote the step profile to {}.", path.display()),
+        Err(e) => log_warning(&format!(
+            "Failed to write the step profile to {}: {e}",
+            path.display()
+        )),
+    }
+}
+
+/// A profiler range, closed when dropped.
+#[must_use = "the range is closed when dropped"]
+pub struct ProfileRange {
+    name: &'static str,
+    start: Instant,
+}
+
+/// Open a range named `name` until the returned guard is dropped.
+pub fn range(name: &'static str) -> ProfileRange {
+    #[cfg(feature = "nvtx")]
+    nvtx::range_push!("{}", name);
+    ProfileRange {
+        name,
+        start: Instant::now(),
+    }
+}
+
+impl Drop for ProfileRange {
+    fn drop(&mut self) {
+        #[cfg(feature = "nvtx")]
+        nvtx::range_pop!();
+        if !PROFILING.load(Ordering::Acquire) {
+            return;
+        }
+        let end = Instant::now();
+        if let Some(profiler) = step_profiler().as_mut() {
+            let start = self.start.saturating_duration_since(profiler.start);
+            profiler.events.push(TraceEvent {
+                name: self.name,
+                ph: "X",
+                ts: start.as_secs_f64() * 1e6,
+                dur: end.duration_since(self.start).as_secs_f64() * 1e6,
+                pid: std::process::id(),
+                tid: thread_id(),
+            });
+        }
+    }
+}
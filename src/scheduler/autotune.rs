@@ -0,0 +1,270 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use candle_core::{DType, Device, Tensor};
+use serde::{Deserialize, Serialize};
+
+use super::cache_engine::{CacheConfig, CacheEngine, KVCacheLayout};
+use crate::{
+    backend::{
+        compute_capability, device_name, num_kv_splits, paged_attention_v1, paged_attention_v2,
+        AttentionComputeDtype,
+    },
+    log_warning,
+    openai::{models::ConfigLike, responses::APIError},
+    try_api,
+};
+
+/// Candidate block sizes, all of which the attention kernels are compiled for.
+const BLOCK_SIZES: [usize; 3] = [8, 16, 32];
+/// Context lengths the kernels are timed at, from chat turns to long documents.
+const CONTEXT_LENS: [usize; 4] = [512, 2048, 8192, 16384];
+/// Number of sequences decoded in each timed launch.
+const NUM_SEQS: usize = 8;
+const WARMUP_ITERS: usize = 2;
+const TIMED_ITERS: usize = 10;
+
+/// The launch settings of the paged attention kernels found by `autotune`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AutotuneResult {
+    pub block_size: usize,
+    pub cache_layout: KVCacheLayout,
+    /// The shortest timed context length at which the partitioned (V2) kernel beat V1,
+    /// `usize::MAX` if it never did. Replaces the V1/V2 heuristic of the decode attention.
+    pub v2_min_context_len: usize,
+}
+
+impl AutotuneResult {
+    /// Apply the tuned block size and layout to `cache_config`.
+    pub fn apply(&self, cache_config: &mut CacheConfig) {
+        cache_config.block_size = self.block_size;
+        cache_config.cache_layout = self.cache_layout;
+    }
+}
+
+/// Benchmark the decode attention kernels for the head dimensions of `model_config` on `device`:
+/// every candidate block size and cache layout with V1 and V2 at typical context lengths. The
+/// fastest settings are cached on disk, keyed by the GPU and the model, so that later startups
+/// skip the benchmark.
+///
+/// Settings which the benchmark does not cover (e.g. the cache dtype) are taken from
+/// `cache_config`.
+pub fn autotune(
+    model_id: &str,
+    model_config: &dyn ConfigLike,
+    cache_config: &CacheConfig,
+    dtype: DType,
+    device: &Device,
+) -> Result<AutotuneResult, APIError> {
+    let Device::Cuda(dev) = device else {
+        return Err(APIError::new_str(
+            "Kernel autotuning requires a CUDA device.",
+        ));
+    };
+    if model_config.get_mla_dims().is_some() {
+        return Err(APIError::new_str(
+            "Kernel autotuning does not support multi-head latent attention.",
+        ));
+    }
+    let (major, minor) = compute_capability(dev.ordinal())?;
+    let key = format!(
+        "{} (sm_{major}{minor})/{model_id}/{dtype:?}/{:?}/heads={},kv_heads={},head_size={}",
+        device_name(dev.ordinal())?,
+        cache_config.cache_dtype,
+        model_config.get_num_attention_heads(),
+        model_config.get_num_kv_heads(),
+        model_config.get_head_size(),
+    );
+
+    let path = cache_path();
+    let mut results = path
+        .as_ref()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|contents| {
+            serde_json::from_str::<HashMap<String, AutotuneResult>>(&contents).ok()
+        })
+        .unwrap_or_default();
+    if let Some(result) = results.get(&key) {
+        return Ok(*result);
+    }
+
+    let layouts = if cache_config.cache_dtype.kernel_name() == "auto" {
+        vec![KVCacheLayout::Split, KVCacheLayout::Nhd]
+    } else {
+        vec![KVCacheLayout::Split]
+    };
+    let mut best: Option<(Duration, AutotuneResult)> = None;
+    for block_size in BLOCK_SIZES {
+        for cache_layout in &layouts {
+            let mut candidate = cache_config.clone();
+            candidate.block_size = block_size;
+            candidate.cache_layout = *cache_layout;
+            let mut total = Duration::ZERO;
+            let mut v2_min_context_len = usize::MAX;
+            for context_len in CONTEXT_LENS {
+                let (v1, v2) =
+                    time_decode_attention(model_config, &candidate, dtype, device, context_len)?;
+                if v2 < v1 {
+                    v2_min_context_len = v2_min_context_len.min(context_len);
+                }
+                total += v1.min(v2);
+            }
+            if best
+                .as_ref()
+                .map_or(true, |(best_total, _)| total < *best_total)
+            {
+                best = Some((
+                    total,
+                    AutotuneResult {
+                        block_size,
+                        cache_layout: *cache_layout,
+                        v2_min_context_len,
+                    },
+                ));
+            }
+        }
+    }
+    let (_, result) = best.unwrap();
+
+    results.insert(key, result);
+    if let Some(path) = path {
+        // Failing to cache the result only costs another benchmark at the next startup.
+        let written = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(&path, serde_json::to_string_pretty(&results).unwrap()));
+        if let Err(err) = written {
+            log_warning(&format!(
+                "Failed to cache the autotuning result in {}: {err}",
+                path.display()
+            ));
+        }
+    }
+    Ok(result)
+}
+
+/// The file holding the autotuning results of all GPUs and models.
+fn cache_path() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("candle-vllm").join("autotune.json"))
+}
+
+/// The mean time of one V1 and one V2 launch decoding `NUM_SEQS` sequences of `context_len`
+/// tokens with the settings of `cache_config`.
+fn time_decode_attention(
+    model_config: &dyn ConfigLike,
+    cache_config: &CacheConfig,
+    dtype: DType,
+    device: &Device,
+    context_len: usize,
+) -> Result<(Duration, Duration), APIError> {
+    let num_heads = model_config.get_num_attention_heads();
+    let head_size = model_config.get_head_size();
+    let block_size = cache_config.block_size;
+    let blocks_per_seq = context_len.div_ceil(block_size);
+    let num_blocks = NUM_SEQS * blocks_per_seq;
+    let cache_dtype = cache_config.cache_dtype.storage_dtype(dtype)?;
+
+    let key_cache = try_api!(Tensor::zeros(
+        CacheEngine::key_cache_shape(model_config, cache_config, dtype, num_blocks)?,
+        cache_dtype,
+        device,
+    ));
+    let value_cache = try_api!(Tensor::zeros(
+        CacheEngine::value_cache_shape(model_config, cache_config, num_blocks),
+        cache_dtype,
+        device,
+    ));
+    let query = try_api!(
+        Tensor::randn(0f32, 1., (NUM_SEQS, num_heads, head_size), device)
+            .and_then(|query| query.to_dtype(dtype))
+    );
+    let block_tables = try_api!(Tensor::arange(0, num_blocks as i64, device)
+        .and_then(|blocks| blocks.reshape((NUM_SEQS, blocks_per_seq))));
+    let context_lens = try_api!(Tensor::from_vec(
+        vec![context_len as i64; NUM_SEQS],
+        NUM_SEQS,
+        device,
+    ));
+    let num_kv_heads = model_config.get_num_kv_heads().try_into().unwrap();
+    let scale = 1. / (head_size as f32).sqrt();
+    let kv_cache_dtype = cache_config.cache_dtype.kernel_name();
+    let num_splits = num_kv_splits(NUM_SEQS, num_heads, context_len, device)?;
+
+    let v1 = time_launches(device, || {
+        paged_attention_v1(
+            query.clone(),
+            key_cache.clone(),
+            value_cache.clone(),
+            num_kv_heads,
+            scale,
+            block_tables.clone(),
+            context_lens.clone(),
+            block_size,
+            context_len,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            kv_cache_dtype,
+            AttentionComputeDtype::Full,
+        )
+    })?;
+    let v2 = time_launches(device, || {
+        let exp_sums = try_api!(Tensor::zeros(
+            (NUM_SEQS, num_heads, num_splits),
+            DType::F32,
+            device,
+        ));
+        let max_logits = try_api!(exp_sums.zeros_like());
+        paged_attention_v2(
+            exp_sums,
+            max_logits,
+            query.clone(),
+            key_cache.clone(),
+            value_cache.clone(),
+            num_kv_heads,
+            scale,
+            block_tables.clone(),
+            context_lens.clone(),
+            block_size,
+            context_len,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            kv_cache_dtype,
+            AttentionComputeDtype::Full,
+        )
+    })?;
+    Ok((v1, v2))
+}
+
+/// The mean time of a launch of `launch`, after a few warmup launches.
+fn time_launches(
+    device: &Device,
+    mut launch: impl FnMut() -> Result<Tensor, APIError>,
+) -> Result<Duration, APIError> {
+    let Device::Cuda(dev) = device else {
+        unreachable!("Kernels are only timed on CUDA devices.");
+    };
+    for _ in 0..WARMUP_ITERS {
+        launch()?;
+    }
+    try_api!(dev.synchronize());
+    let start = Instant::now();
+    for _ in 0..TIMED_ITERS {
+        launch()?;
+    }
+    try_api!(dev.synchronize());
+    Ok(start.elapsed() / TIMED_ITERS as u32)
+}
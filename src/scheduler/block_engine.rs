@@ -1,13 +1,19 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    collections::{hash_map::Entry, HashMap, HashSet},
     hash::Hash,
+    iter::zip,
     marker::PhantomData,
     ops::Deref,
     sync::{Arc, Mutex, MutexGuard},
 };
 
-use super::sequence::{Sequence, SequenceGroup};
+use super::{
+    cache_engine::AttentionSinks,
+    sequence::{_Sequence, Sequence, SequenceGroup},
+    StateMap,
+};
 
+#[derive(Clone)]
 pub struct LogicalTokenBlock {
     tokens: Vec<usize>,
     block_size: usize,
@@ -38,6 +44,10 @@ impl LogicalTokenBlock {
             self.append_token_id(*token);
         }
     }
+
+    pub fn get_token_ids(&self) -> &[usize] {
+        &self.tokens[..self.num_tokens]
+    }
 }
 
 #[derive(Hash, PartialEq, Eq)]
@@ -75,6 +85,15 @@ impl Hash for PhysicalTokenBlock {
 impl Eq for PhysicalTokenBlock {}
 
 type BlockTable = Vec<Arc<PhysicalTokenBlock>>;
+
+/// The static blocks holding the encoder outputs of a sequence of an encoder-decoder model. They
+/// are written once with the prompt, never appended to, and stay on the GPU until the sequence
+/// is freed.
+#[derive(Clone)]
+pub struct CrossBlockTable {
+    pub blocks: BlockTable,
+    pub num_encoder_tokens: usize,
+}
 struct GPUAllocator;
 struct CPUAllocator;
 
@@ -97,8 +116,54 @@ impl Deref for CPUAllocatorWrapper {
     }
 }
 
+/// The free blocks of an allocator, indexed by block id so that a specific block can be taken out
+/// without scanning the free list.
+struct FreeBlocks {
+    blocks: BlockTable,
+    /// Block id -> position in `blocks`.
+    positions: StateMap<usize, usize>,
+}
+
+impl FreeBlocks {
+    fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    fn push(&mut self, block: Arc<PhysicalTokenBlock>) {
+        self.positions
+            .insert(block.deref_mut().block_id, self.blocks.len());
+        self.blocks.push(block);
+    }
+
+    fn pop(&mut self) -> Option<Arc<PhysicalTokenBlock>> {
+        let block = self.blocks.pop()?;
+        self.positions.remove(&block.deref_mut().block_id);
+        Some(block)
+    }
+
+    fn remove(&mut self, block_id: usize) -> Option<Arc<PhysicalTokenBlock>> {
+        let idx = self.positions.remove(&block_id)?;
+        let block = self.blocks.swap_remove(idx);
+        if let Some(moved) = self.blocks.get(idx) {
+            self.positions.insert(moved.deref_mut().block_id, idx);
+        }
+        Some(block)
+    }
+}
+
+impl From<BlockTable> for FreeBlocks {
+    fn from(blocks: BlockTable) -> Self {
+        let positions = blocks
+            .iter()
+            .enumerate()
+            .map(|(idx, block)| (block.deref_mut().block_id, idx))
+            .collect();
+        Self { blocks, positions }
+    }
+}
+
 struct Allocator<T> {
-    free_blocks: BlockTable,
+    free_blocks: FreeBlocks,
     _ghost: PhantomData<T>,
 }
 
@@ -109,6 +174,14 @@ impl<T> Allocator<T> {
         block
     }
 
+    /// Take a specific block out of the free list, for example to reuse a freed block which
+    /// still holds a cached prefix.
+    fn take_free_block(&mut self, block: &Arc<PhysicalTokenBlock>) {
+        let block_id = block.deref_mut().block_id;
+        self.free_blocks.remove(block_id).unwrap();
+        block.deref_mut().refcount = 1;
+    }
+
     fn free_block(&mut self, block: Arc<PhysicalTokenBlock>) {
         if block.deref_mut().refcount == 0 {
             panic!(
@@ -137,7 +210,7 @@ impl Allocator<GPUAllocator> {
             ))))
         }
         Allocator {
-            free_blocks,
+            free_blocks: free_blocks.into(),
             _ghost: PhantomData,
         }
     }
@@ -156,12 +229,12 @@ impl Allocator<CPUAllocator> {
                     block_id: id,
                     block_size,
                     refcount: 0,
-                    is_gpu: true,
+                    is_gpu: false,
                 },
             ))))
         }
         Allocator {
-            free_blocks,
+            free_blocks: free_blocks.into(),
             _ghost: PhantomData,
         }
     }
@@ -183,22 +256,146 @@ pub struct BlockEngine {
     num_gpu_blocks: usize,
     gpu_allocator: Allocator<GPUAllocator>,
     cpu_allocator: Allocator<CPUAllocator>,
-    pub block_tables: HashMap<SeqID, BlockTable>,
+    pub block_tables: StateMap<SeqID, BlockTable>,
+    /// The cross-attention blocks of the sequences of encoder-decoder models, allocated from the
+    /// same GPU pool as the block tables.
+    pub cross_block_tables: StateMap<SeqID, CrossBlockTable>,
+    enable_prefix_caching: bool,
+    /// Full GPU blocks keyed by the hash of their tokens and all preceding tokens, see
+    /// `_Sequence::get_block_hashes`. Freed blocks stay cached until they are reallocated.
+    cached_blocks: StateMap<u64, CachedBlock>,
+    /// Block id -> (hash, namespace) of the cached blocks.
+    cached_block_hashes: StateMap<usize, (u64, Option<String>)>,
+    /// Maximum number of cached blocks per namespace, unlimited if `None`.
+    prefix_cache_quota: Option<usize>,
+    namespace_cached_blocks: StateMap<Option<String>, usize>,
+    block_size: usize,
+    attention_sinks: Option<AttentionSinks>,
+    /// Eviction state of each sequence with attention sinks.
+    sink_states: StateMap<SeqID, SinkState>,
+    /// Number of leading prompt tokens of each allocated sequence which were already in the
+    /// prefix cache, so that only the rest of the prompt is prefilled.
+    cached_prompt_tokens: StateMap<SeqID, usize>,
+}
+
+/// The blocks a sequence evicted behind its attention sinks. Keys are cached rotated to the
+/// position of their token within the block table at the time they were written, which decreases
+/// by `block_size` with each evicted block.
+#[derive(Clone, Default)]
+struct SinkState {
+    num_evicted_tokens: usize,
+    /// Value of `num_evicted_tokens` when each block of the block table was written.
+    block_evicted_tokens: Vec<usize>,
+}
+
+/// A block of the prefix cache. A hit must match its tokens and namespace besides the hash, so
+/// that a hash collision cannot serve the keys and values of other tokens or another tenant.
+#[derive(Clone)]
+struct CachedBlock {
+    block: Arc<PhysicalTokenBlock>,
+    token_ids: Vec<usize>,
+    namespace: Option<String>,
+}
+
+impl CachedBlock {
+    fn matches(&self, token_ids: &[usize], namespace: Option<&str>) -> bool {
+        self.token_ids == token_ids && self.namespace.as_deref() == namespace
+    }
+}
+
+/// The allocation state of a `BlockEngine`, see `BlockEngine::checkpoint`.
+pub struct BlockEngineCheckpoint {
+    gpu_free_blocks: BlockTable,
+    cpu_free_blocks: BlockTable,
+    block_tables: StateMap<SeqID, BlockTable>,
+    cross_block_tables: StateMap<SeqID, CrossBlockTable>,
+    cached_blocks: StateMap<u64, CachedBlock>,
+    cached_block_hashes: StateMap<usize, (u64, Option<String>)>,
+    namespace_cached_blocks: StateMap<Option<String>, usize>,
+    sink_states: StateMap<SeqID, SinkState>,
+    cached_prompt_tokens: StateMap<SeqID, usize>,
+    /// Refcount of every physical block, each of which is either free or in a block table.
+    refcounts: Vec<(Arc<PhysicalTokenBlock>, usize)>,
 }
 
 impl BlockEngine {
     #[must_use]
-    pub fn new(block_size: usize, num_gpu_blocks: usize, num_cpu_blocks: usize) -> Self {
+    pub fn new(
+        block_size: usize,
+        num_gpu_blocks: usize,
+        num_cpu_blocks: usize,
+        enable_prefix_caching: bool,
+        prefix_cache_quota: Option<usize>,
+        attention_sinks: Option<AttentionSinks>,
+    ) -> Self {
         Self {
             num_gpu_blocks,
             gpu_allocator: Allocator::<GPUAllocator>::new(block_size, num_gpu_blocks),
             cpu_allocator: Allocator::<CPUAllocator>::new(block_size, num_cpu_blocks),
-            block_tables: HashMap::new(),
+            block_tables: StateMap::default(),
+            cross_block_tables: StateMap::default(),
+            enable_prefix_caching,
+            cached_blocks: StateMap::default(),
+            cached_block_hashes: StateMap::default(),
+            prefix_cache_quota,
+            namespace_cached_blocks: StateMap::default(),
+            block_size,
+            attention_sinks,
+            sink_states: StateMap::default(),
+            cached_prompt_tokens: StateMap::default(),
+        }
+    }
+
+    /// Record the allocation state, so that the changes made afterwards can be undone with
+    /// `restore`. The physical blocks are shared, only their refcounts are copied.
+    pub fn checkpoint(&self) -> BlockEngineCheckpoint {
+        let refcounts = self
+            .gpu_allocator
+            .free_blocks
+            .blocks
+            .iter()
+            .chain(&self.cpu_allocator.free_blocks.blocks)
+            .chain(self.block_tables.values().flatten())
+            .chain(
+                self.cross_block_tables
+                    .values()
+                    .flat_map(|table| &table.blocks),
+            )
+            .map(|block| (block.clone(), block.deref_mut().refcount))
+            .collect();
+        BlockEngineCheckpoint {
+            gpu_free_blocks: self.gpu_allocator.free_blocks.blocks.clone(),
+            cpu_free_blocks: self.cpu_allocator.free_blocks.blocks.clone(),
+            block_tables: self.block_tables.clone(),
+            cross_block_tables: self.cross_block_tables.clone(),
+            cached_blocks: self.cached_blocks.clone(),
+            cached_block_hashes: self.cached_block_hashes.clone(),
+            namespace_cached_blocks: self.namespace_cached_blocks.clone(),
+            sink_states: self.sink_states.clone(),
+            cached_prompt_tokens: self.cached_prompt_tokens.clone(),
+            refcounts,
+        }
+    }
+
+    /// Undo all allocations, frees, swaps and prefix cache updates since `checkpoint`.
+    pub fn restore(&mut self, checkpoint: BlockEngineCheckpoint) {
+        for (block, refcount) in checkpoint.refcounts {
+            block.deref_mut().refcount = refcount;
         }
+        self.gpu_allocator.free_blocks = checkpoint.gpu_free_blocks.into();
+        self.cpu_allocator.free_blocks = checkpoint.cpu_free_blocks.into();
+        self.block_tables = checkpoint.block_tables;
+        self.cross_block_tables = checkpoint.cross_block_tables;
+        self.cached_blocks = checkpoint.cached_blocks;
+        self.cached_block_hashes = checkpoint.cached_block_hashes;
+        self.namespace_cached_blocks = checkpoint.namespace_cached_blocks;
+        self.sink_states = checkpoint.sink_states;
+        self.cached_prompt_tokens = checkpoint.cached_prompt_tokens;
     }
 
     pub fn can_allocate(&self, seq_group: &SequenceGroup) -> AllocStatus {
-        let num_required_blocks = seq_group.get_total_logical_token_blocks();
+        let num_required_blocks = seq_group.get_total_logical_token_blocks()
+            + seq_group.get_num_encoder_tokens().div_ceil(self.block_size);
         let num_free_gpu_blocks = self.gpu_allocator.get_num_free_blocks();
 
         if self.num_gpu_blocks > *num_free_gpu_blocks + num_required_blocks {
@@ -211,51 +408,472 @@ impl BlockEngine {
     }
 
     pub fn allocate(&mut self, seq_group: &SequenceGroup) {
+        let seqs = seq_group.get_seqs();
+        // All sequences of a waiting group share the same prompt, so they share one block table.
+        let namespace = seq_group.get_cache_namespace();
+        let (num_logical_blocks, block_hashes, block_token_ids, prompt_len) =
+            match seqs.values().next() {
+                Some(seq) => {
+                    let seq = seq.deref_mut();
+                    (
+                        seq.get_logical_token_blocks(),
+                        seq.get_block_hashes(namespace),
+                        seq.get_block_token_ids(),
+                        seq.get_len(),
+                    )
+                }
+                None => return,
+            };
         let mut block_table = Vec::new();
-        for _logcical_idx in 0..seq_group.get_total_logical_token_blocks() {
-            block_table.push(self.gpu_allocator.allocate());
+        // The leading blocks which are already cached. Blocks cached by a prompt of the same
+        // step are written before any attention of the step reads them.
+        let mut num_cached_blocks = 0;
+        for logical_idx in 0..num_logical_blocks {
+            let block = match block_hashes.get(logical_idx) {
+                Some(hash) if self.enable_prefix_caching && seq_group.uses_prefix_cache() => {
+                    let token_ids = &block_token_ids[logical_idx];
+                    if num_cached_blocks == logical_idx
+                        && self.get_cached_block(*hash, token_ids, namespace).is_some()
+                    {
+                        num_cached_blocks += 1;
+                    }
+                    self.allocate_cached_block(*hash, token_ids, namespace)
+                }
+                _ => self.allocate_gpu_block(),
+            };
+            block.deref_mut().refcount += seqs.len() - 1;
+            block_table.push(block);
         }
-        for seq_id in seq_group.get_seqs().keys() {
+        for seq_id in seqs.keys() {
+            if self.attention_sinks.is_some() {
+                let state = SinkState {
+                    num_evicted_tokens: 0,
+                    block_evicted_tokens: vec![0; block_table.len()],
+                };
+                self.sink_states.insert(*seq_id, state);
+            }
             self.block_tables.insert(*seq_id, block_table.clone());
+            // The last prompt token is always computed for its logits.
+            let num_cached_tokens = (num_cached_blocks * self.block_size).min(prompt_len - 1);
+            if num_cached_tokens > 0 {
+                self.cached_prompt_tokens.insert(*seq_id, num_cached_tokens);
+            }
+        }
+
+        let num_encoder_tokens = seq_group.get_num_encoder_tokens();
+        if num_encoder_tokens > 0 {
+            let blocks = (0..num_encoder_tokens.div_ceil(self.block_size))
+                .map(|_| {
+                    let block = self.allocate_gpu_block();
+                    block.deref_mut().refcount += seqs.len() - 1;
+                    block
+                })
+                .collect::<Vec<_>>();
+            for seq_id in seqs.keys() {
+                let table = CrossBlockTable {
+                    blocks: blocks.clone(),
+                    num_encoder_tokens,
+                };
+                self.cross_block_tables.insert(*seq_id, table);
+            }
+        }
+    }
+
+    /// Number of leading prompt tokens of the sequence which were served from the prefix cache
+    /// when it was allocated. Only the tokens after them are prefilled.
+    pub fn get_num_cached_prompt_tokens(&self, seq_id: SeqID) -> usize {
+        self.cached_prompt_tokens.get(&seq_id).copied().unwrap_or(0)
+    }
+
+    /// Number of tokens `sequence` evicted behind its attention sinks. The tokens after the
+    /// sinks are found this many positions earlier in the block table.
+    pub fn get_num_evicted_tokens(&self, seq_id: SeqID) -> usize {
+        self.sink_states
+            .get(&seq_id)
+            .map_or(0, |state| state.num_evicted_tokens)
+    }
+
+    /// For each block in the block table of the sequence, the number of positions its keys were
+    /// cached ahead of their current position, see `InputMetadata::block_position_shifts`.
+    pub fn get_block_position_shifts(&self, seq_id: SeqID) -> Option<Vec<usize>> {
+        let state = self.sink_states.get(&seq_id)?;
+        Some(
+            state
+                .block_evicted_tokens
+                .iter()
+                .map(|evicted| state.num_evicted_tokens - evicted)
+                .collect(),
+        )
+    }
+
+    /// Free the oldest blocks after the attention sinks of the sequence until it holds at most
+    /// `num_window_blocks` blocks after them.
+    fn evict_window_blocks(&mut self, seq_id: SeqID) {
+        let Some(sinks) = self.attention_sinks else {
+            return;
+        };
+        let table = self.block_tables.get_mut(&seq_id).unwrap();
+        let state = self.sink_states.get_mut(&seq_id).unwrap();
+        while table.len() > sinks.num_sink_blocks + sinks.num_window_blocks {
+            let block = table.remove(sinks.num_sink_blocks);
+            state.block_evicted_tokens.remove(sinks.num_sink_blocks);
+            state.num_evicted_tokens += self.block_size;
+            self.gpu_allocator.free_block(block);
+        }
+    }
+
+    /// Number of leading full blocks of the group's prompt which are already in the prefix cache.
+    pub fn get_num_cached_prefix_blocks(&self, seq_group: &SequenceGroup) -> usize {
+        if !self.enable_prefix_caching || !seq_group.uses_prefix_cache() {
+            return 0;
+        }
+        match seq_group.get_seqs().values().next() {
+            Some(seq) => {
+                self.get_num_cached_blocks(&seq.deref_mut(), seq_group.get_cache_namespace())
+            }
+            None => 0,
+        }
+    }
+
+    /// Number of leading full blocks of `seq` in the prefix cache of `namespace`.
+    pub fn get_num_cached_blocks(&self, seq: &_Sequence, namespace: Option<&str>) -> usize {
+        if !self.enable_prefix_caching {
+            return 0;
+        }
+        zip(seq.get_block_hashes(namespace), seq.get_block_token_ids())
+            .take_while(|(hash, token_ids)| {
+                self.get_cached_block(*hash, token_ids, namespace).is_some()
+            })
+            .count()
+    }
+
+    /// The cached block for `hash`, if it holds `token_ids` of `namespace`.
+    fn get_cached_block(
+        &self,
+        hash: u64,
+        token_ids: &[usize],
+        namespace: Option<&str>,
+    ) -> Option<&Arc<PhysicalTokenBlock>> {
+        self.cached_blocks
+            .get(&hash)
+            .filter(|cached| cached.matches(token_ids, namespace))
+            .map(|cached| &cached.block)
+    }
+
+    /// Number of distinct physical blocks held by the sequences of the group.
+    pub fn get_num_allocated_blocks(&self, seq_group: &SequenceGroup) -> usize {
+        seq_group
+            .get_seqs()
+            .keys()
+            .filter_map(|id| self.block_tables.get(id))
+            .flatten()
+            .map(|block| block.deref_mut().block_id)
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    pub fn get_num_free_gpu_blocks(&self) -> usize {
+        *self.gpu_allocator.get_num_free_blocks()
+    }
+
+    /// Fraction of the group's prompt blocks which would be served from the prefix cache.
+    pub fn get_prefix_cache_hit_ratio(&self, seq_group: &SequenceGroup) -> f32 {
+        let num_blocks = match seq_group.get_seqs().values().next() {
+            Some(seq) => seq.deref_mut().get_logical_token_blocks(),
+            None => 0,
+        };
+        if num_blocks == 0 {
+            return 0.;
         }
+        self.get_num_cached_prefix_blocks(seq_group) as f32 / num_blocks as f32
     }
 
-    pub fn can_append_token_to_seq(&self, seq_group: &SequenceGroup) -> bool {
+    /// Whether the sequences of `seq_group` can take another token and, after it,
+    /// `num_lookahead_slots` more, see `append_lookahead_slots_to_seq`.
+    pub fn can_append_token_to_seq(
+        &self,
+        seq_group: &SequenceGroup,
+        num_lookahead_slots: usize,
+    ) -> bool {
         let free_blocks = self.gpu_allocator.get_num_free_blocks();
+        let lookahead_blocks = seq_group
+            .get_seqs()
+            .values()
+            .map(|seq| self.get_num_lookahead_blocks(seq, num_lookahead_slots))
+            .sum::<usize>();
         // Physical blocks = logical blocks
-        seq_group.total_blocks_to_add_new_tok() <= *free_blocks
+        seq_group.total_blocks_to_add_new_tok() + lookahead_blocks <= *free_blocks
+    }
+
+    /// Upper bound of the blocks `append_lookahead_slots_to_seq` allocates for `sequence`: the
+    /// missing blocks and the shared blocks to copy.
+    fn get_num_lookahead_blocks(&self, sequence: &Sequence, num_lookahead_slots: usize) -> usize {
+        if num_lookahead_slots == 0 {
+            return 0;
+        }
+        let Some(table) = self.block_tables.get(&sequence.deref_mut().get_id()) else {
+            return 0;
+        };
+        let len = sequence.deref_mut().get_len();
+        let num_shared_blocks = table
+            .iter()
+            .skip((len - 1) / self.block_size)
+            .filter(|block| block.deref_mut().refcount > 1)
+            .count();
+        (len + num_lookahead_slots)
+            .div_ceil(self.block_size)
+            .saturating_sub(table.len())
+            + num_shared_blocks
     }
 
+    /// Free the blocks of `sequence`. Sequences without allocated blocks (e.g. still waiting) are ignored.
     pub fn free_sequence(&mut self, sequence: &Sequence) {
-        let block_table = self
-            .block_tables
-            .get(&sequence.deref_mut().get_id())
-            .unwrap();
+        let Some(block_table) = self.block_tables.remove(&sequence.deref_mut().get_id()) else {
+            return;
+        };
+
+        self.sink_states.remove(&sequence.deref_mut().get_id());
+        self.cached_prompt_tokens
+            .remove(&sequence.deref_mut().get_id());
+        if let Some(cross_table) = self
+            .cross_block_tables
+            .remove(&sequence.deref_mut().get_id())
+        {
+            for block in cross_table.blocks {
+                self.gpu_allocator.free_block(block);
+            }
+        }
 
         // Free from block table
         for block in block_table {
             if block.deref_mut().is_gpu {
-                self.gpu_allocator.free_block(block.clone())
+                self.gpu_allocator.free_block(block)
+            } else {
+                self.cpu_allocator.free_block(block)
+            }
+        }
+    }
+
+    /// Share the physical blocks of `parent` with `child`. A shared block is copied on write
+    /// once either sequence appends to it, see `append_token_slot_to_seq`. A child forked before
+    /// its prompt is prefilled reads the prompt tokens written by its parent in the same step,
+    /// and only computes the last one for logits of its own.
+    pub fn fork(&mut self, parent: &Sequence, child: &Sequence) {
+        let table = self
+            .block_tables
+            .get(&parent.deref_mut().get_id())
+            .unwrap()
+            .clone();
+        for block in &table {
+            block.deref_mut().refcount += 1;
+        }
+        if let Some(state) = self.sink_states.get(&parent.deref_mut().get_id()).cloned() {
+            self.sink_states.insert(child.deref_mut().get_id(), state);
+        }
+        if let Some(cross_table) = self
+            .cross_block_tables
+            .get(&parent.deref_mut().get_id())
+            .cloned()
+        {
+            for block in &cross_table.blocks {
+                block.deref_mut().refcount += 1;
+            }
+            self.cross_block_tables
+                .insert(child.deref_mut().get_id(), cross_table);
+        }
+        self.block_tables.insert(child.deref_mut().get_id(), table);
+        if child.deref_mut().is_prompt() {
+            let num_cached_tokens = child.deref_mut().get_len() - 1;
+            if num_cached_tokens > 0 {
+                self.cached_prompt_tokens
+                    .insert(child.deref_mut().get_id(), num_cached_tokens);
+            }
+        }
+    }
+
+    /// Whether `dst` has enough free GPU blocks to take over the blocks of `seq_group`.
+    pub fn can_migrate_seq_group(&self, dst: &BlockEngine, seq_group: &SequenceGroup) -> bool {
+        let seqs = seq_group.get_seqs();
+        let blocks_required: usize = self
+            .block_tables
+            .iter()
+            .filter(|(id, _)| seqs.contains_key(id))
+            .map(|(_, table)| table.len())
+            .sum::<usize>()
+            + self
+                .cross_block_tables
+                .iter()
+                .filter(|(id, _)| seqs.contains_key(id))
+                .map(|(_, table)| table.blocks.len())
+                .sum::<usize>();
+        blocks_required <= dst.gpu_allocator.free_blocks.len()
+    }
+
+    /// Move the GPU blocks of `seq_group` from this block engine into `dst`, which manages the
+    /// blocks of another device. Returns the mapping of block ids (this device -> `dst`).
+    pub fn migrate(
+        &mut self,
+        dst: &mut BlockEngine,
+        seq_group: &SequenceGroup,
+    ) -> HashMap<usize, usize> {
+        let mut new_mapping = HashMap::new();
+        for seq_id in seq_group.get_seqs().keys() {
+            let block_table = self.block_tables.remove(seq_id).unwrap();
+            let new_block_table = self.migrate_blocks(dst, block_table, &mut new_mapping);
+            dst.block_tables.insert(*seq_id, new_block_table);
+            if let Some(state) = self.sink_states.remove(seq_id) {
+                dst.sink_states.insert(*seq_id, state);
+            }
+            self.cached_prompt_tokens.remove(seq_id);
+            if let Some(cross_table) = self.cross_block_tables.remove(seq_id) {
+                let blocks = self.migrate_blocks(dst, cross_table.blocks, &mut new_mapping);
+                let cross_table = CrossBlockTable {
+                    blocks,
+                    num_encoder_tokens: cross_table.num_encoder_tokens,
+                };
+                dst.cross_block_tables.insert(*seq_id, cross_table);
+            }
+        }
+
+        new_mapping
+            .iter()
+            .map(|(k, v)| (*k, v.deref_mut().block_id))
+            .collect::<HashMap<_, _>>()
+    }
+
+    /// Register the blocks of `seq_group` in the prefix cache once `migrate` moved them here and
+    /// their contents were copied.
+    pub fn cache_migrated_seq_group(&mut self, seq_group: &SequenceGroup) {
+        if self.enable_prefix_caching && seq_group.uses_prefix_cache() {
+            self.cache_seq_group_blocks(seq_group);
+        }
+    }
+
+    /// Replace `block_table` by blocks of `dst`, sharing the blocks already moved in `new_mapping`.
+    fn migrate_blocks(
+        &mut self,
+        dst: &mut BlockEngine,
+        block_table: BlockTable,
+        new_mapping: &mut HashMap<usize, Arc<PhysicalTokenBlock>>,
+    ) -> BlockTable {
+        let mut new_block_table = Vec::new();
+        for src_block in block_table {
+            assert!(src_block.deref_mut().is_gpu);
+            let dst_block =
+                if let Entry::Vacant(e) = new_mapping.entry(src_block.deref_mut().block_id) {
+                    // Create a new block
+                    let dst_block = dst.allocate_gpu_block();
+                    e.insert(dst_block.clone());
+                    dst_block
+                } else {
+                    // Reuse a block
+                    let dst_block = new_mapping
+                        .get(&src_block.deref_mut().block_id)
+                        .unwrap()
+                        .clone();
+                    dst_block.deref_mut().refcount += 1;
+                    dst_block
+                };
+            new_block_table.push(dst_block);
+            self.gpu_allocator.free_block(src_block);
+        }
+        new_block_table
+    }
+
+    /// Pop a free GPU block, dropping any stale prefix cache entry which still points at it.
+    fn allocate_gpu_block(&mut self) -> Arc<PhysicalTokenBlock> {
+        let block = self.gpu_allocator.allocate();
+        let block_id = block.deref_mut().block_id;
+        self.uncache_block(block_id);
+        block
+    }
+
+    /// Drop the prefix cache entry of the block `block_id` if it has one, releasing it from the
+    /// quota of its namespace.
+    fn uncache_block(&mut self, block_id: usize) {
+        let Some((hash, namespace)) = self.cached_block_hashes.remove(&block_id) else {
+            return;
+        };
+        if self
+            .cached_blocks
+            .get(&hash)
+            .is_some_and(|cached| cached.block.deref_mut().block_id == block_id)
+        {
+            self.cached_blocks.remove(&hash);
+        }
+        if let Some(num_cached) = self.namespace_cached_blocks.get_mut(&namespace) {
+            *num_cached -= 1;
+        }
+    }
+
+    /// Reuse the cached block for `hash` if it holds `token_ids`, otherwise allocate a block and
+    /// cache it unless `namespace` has used up its quota or the hash is taken by other tokens.
+    fn allocate_cached_block(
+        &mut self,
+        hash: u64,
+        token_ids: &[usize],
+        namespace: Option<&str>,
+    ) -> Arc<PhysicalTokenBlock> {
+        if let Some(block) = self.get_cached_block(hash, token_ids, namespace).cloned() {
+            if block.deref_mut().refcount == 0 {
+                self.gpu_allocator.take_free_block(&block);
             } else {
-                self.cpu_allocator.free_block(block.clone())
+                block.deref_mut().refcount += 1;
             }
+            return block;
+        }
+        let block = self.allocate_gpu_block();
+        if !self.cached_blocks.contains_key(&hash) {
+            self.cache_block(hash, token_ids, namespace, &block);
         }
+        block
+    }
 
-        self.block_tables.remove(&sequence.deref_mut().get_id());
+    /// Register `block` holding `token_ids` in the prefix cache under `hash`, unless `namespace`
+    /// has used up its quota.
+    fn cache_block(
+        &mut self,
+        hash: u64,
+        token_ids: &[usize],
+        namespace: Option<&str>,
+        block: &Arc<PhysicalTokenBlock>,
+    ) {
+        let namespace = namespace.map(str::to_string);
+        let num_cached = self
+            .namespace_cached_blocks
+            .entry(namespace.clone())
+            .or_insert(0);
+        if self
+            .prefix_cache_quota
+            .is_some_and(|quota| *num_cached >= quota)
+        {
+            return;
+        }
+        *num_cached += 1;
+        let cached = CachedBlock {
+            block: block.clone(),
+            token_ids: token_ids.to_vec(),
+            namespace: namespace.clone(),
+        };
+        self.cached_blocks.insert(hash, cached);
+        self.cached_block_hashes
+            .insert(block.deref_mut().block_id, (hash, namespace));
     }
 
     pub fn can_swap_out_seq_group(&self, seq_group: &SequenceGroup) -> bool {
+        let seqs = seq_group.get_seqs();
         let blocks_required: usize = self
             .block_tables
             .iter()
-            .filter(|(id, _)| seq_group.get_seqs().contains_key(id))
+            .filter(|(id, _)| seqs.contains_key(id))
             .map(|(_, table)| table.len())
             .sum();
         blocks_required <= self.cpu_allocator.free_blocks.len()
     }
 
     /// Update the block table so that the sequence does no longer reserve any GPU
-    /// physical blocks, and only has CPU physical blocks.
+    /// physical blocks, and only has CPU physical blocks. The static cross-attention blocks stay
+    /// on the GPU.
     pub fn swap_out(&mut self, seq_group: &SequenceGroup) -> HashMap<usize, usize> {
         // GPU block to a CPU block
         let mut new_mapping = HashMap::new();
@@ -294,28 +912,36 @@ impl BlockEngine {
     // Returns the COW mapping (src, dst).
     // COW is performed if there are multiple references to the last physical block.
     pub fn append_token_slot_to_seq(&mut self, sequence: &Sequence) -> Option<(usize, usize)> {
-        let table = self
-            .block_tables
-            .get_mut(&sequence.deref_mut().get_id())
-            .unwrap();
-
+        let seq_id = sequence.deref_mut().get_id();
         match sequence.deref_mut().blocks_to_add_new_tok() {
             1 => {
-                table.push(self.gpu_allocator.allocate());
+                let new_block = self.allocate_gpu_block();
+                self.block_tables.get_mut(&seq_id).unwrap().push(new_block);
+                if self.attention_sinks.is_some() {
+                    self.evict_window_blocks(seq_id);
+                    let state = self.sink_states.get_mut(&seq_id).unwrap();
+                    let num_evicted_tokens = state.num_evicted_tokens;
+                    state.block_evicted_tokens.push(num_evicted_tokens);
+                }
                 None
             }
             0 => {
-                let last_block = table.last_mut().unwrap();
+                let last_block = self.block_tables[&seq_id].last().unwrap().clone();
                 assert!(last_block.deref_mut().is_gpu);
                 if last_block.deref_mut().refcount == 1 {
                     None
                 } else {
                     // We would be writing into shared, so COW.
-                    let new_block = self.gpu_allocator.allocate();
+                    let new_block = self.allocate_gpu_block();
                     self.gpu_allocator.free_block(last_block.clone());
                     let old_number = last_block.deref_mut().block_id;
                     let new_number = new_block.deref_mut().block_id;
-                    *last_block = new_block;
+                    *self
+                        .block_tables
+                        .get_mut(&seq_id)
+                        .unwrap()
+                        .last_mut()
+                        .unwrap() = new_block;
                     Some((old_number, new_number))
                 }
             }
@@ -325,11 +951,40 @@ impl BlockEngine {
         }
     }
 
+    /// Reserve the slots of the `num_lookahead_slots` tokens after the last token of `sequence`,
+    /// e.g. the draft tokens of speculative decoding, which are written before they are accepted.
+    /// The slots of rejected tokens are overwritten by the next step. Shared blocks from the last
+    /// token on are copied first. Returns the COW mappings (src, dst).
+    pub fn append_lookahead_slots_to_seq(
+        &mut self,
+        sequence: &Sequence,
+        num_lookahead_slots: usize,
+    ) -> Vec<(usize, usize)> {
+        let seq_id = sequence.deref_mut().get_id();
+        let len = sequence.deref_mut().get_len();
+        let mut copies = Vec::new();
+        for i in (len - 1) / self.block_size..self.block_tables[&seq_id].len() {
+            let block = self.block_tables[&seq_id][i].clone();
+            if block.deref_mut().refcount > 1 {
+                let new_block = self.allocate_gpu_block();
+                self.gpu_allocator.free_block(block.clone());
+                copies.push((block.deref_mut().block_id, new_block.deref_mut().block_id));
+                self.block_tables.get_mut(&seq_id).unwrap()[i] = new_block;
+            }
+        }
+        while self.block_tables[&seq_id].len() * self.block_size < len + num_lookahead_slots {
+            let new_block = self.allocate_gpu_block();
+            self.block_tables.get_mut(&seq_id).unwrap().push(new_block);
+        }
+        copies
+    }
+
     pub fn can_swap_in_seq_group(&self, seq_group: &SequenceGroup) -> bool {
+        let seqs = seq_group.get_seqs();
         let blocks_required: usize = self
             .block_tables
             .iter()
-            .filter(|(id, _)| seq_group.get_seqs().contains_key(id))
+            .filter(|(id, _)| seqs.contains_key(id))
             .map(|(_, table)| table.len())
             .sum();
         blocks_required <= self.gpu_allocator.free_blocks.len()
@@ -342,13 +997,13 @@ impl BlockEngine {
         let mut new_mapping = HashMap::new();
         for seq_id in seq_group.get_seqs().keys() {
             let mut new_block_table = Vec::new();
-            let block_table = self.block_tables.get(seq_id).unwrap();
+            let block_table = self.block_tables.remove(seq_id).unwrap();
 
             for cpu_block in block_table {
                 let gpu_block =
                     if let Entry::Vacant(e) = new_mapping.entry(cpu_block.deref_mut().block_id) {
                         // Create a new block
-                        let gpu_block = self.cpu_allocator.allocate();
+                        let gpu_block = self.allocate_gpu_block();
                         e.insert(gpu_block.clone());
                         gpu_block
                     } else {
@@ -361,14 +1016,34 @@ impl BlockEngine {
                         gpu_block
                     };
                 new_block_table.push(gpu_block);
-                self.gpu_allocator.free_block(cpu_block.clone());
+                self.cpu_allocator.free_block(cpu_block);
             }
             self.block_tables.insert(*seq_id, new_block_table);
         }
+        if self.enable_prefix_caching && seq_group.uses_prefix_cache() {
+            self.cache_seq_group_blocks(seq_group);
+        }
 
         new_mapping
             .iter()
             .map(|(k, v)| (*k, v.deref_mut().block_id))
             .collect::<HashMap<_, _>>()
     }
+
+    /// Register the full blocks of `seq_group` which are not cached yet in the prefix cache.
+    fn cache_seq_group_blocks(&mut self, seq_group: &SequenceGroup) {
+        let namespace = seq_group.get_cache_namespace();
+        for (seq_id, seq) in seq_group.get_seqs() {
+            let (block_hashes, block_token_ids) = {
+                let seq = seq.deref_mut();
+                (seq.get_block_hashes(namespace), seq.get_block_token_ids())
+            };
+            let block_table = self.block_tables[&seq_id].clone();
+            for ((hash, token_ids), block) in zip(zip(block_hashes, block_token_ids), block_table) {
+                if !self.cached_blocks.contains_key(&hash) {
+                    self.cache_block(hash, &token_ids, namespace, &block);
+                }
+            }
+        }
+    }
 }
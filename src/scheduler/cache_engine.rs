@@ -3,20 +3,124 @@ use std::{
     sync::{Arc, Mutex, MutexGuard},
 };
 
-use candle_core::{DType, Device, Tensor};
+use candle_core::{DType, Device};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    backend::{copy_blocks, swap_blocks},
-    openai::{models::ConfigLike, responses::APIError},
-    try_api,
+    log_warning,
+    openai::{
+        models::ConfigLike,
+        responses::APIError,
+        utils::{get_total_host_memory, GIB},
+    },
+    profiling, try_api,
 };
 
+use super::{kv_cache_manager::KVCacheManager, state_cache::SsmDims};
+
+/// Storage type of the KV cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum KVCacheDtype {
+    /// The data type of the model.
+    #[default]
+    Auto,
+    /// f16, requires an f16 model.
+    Fp16,
+    /// bf16, requires a bf16 model.
+    Bf16,
+    /// INT8 with a scale per token and head, computed when the token is written. Halves the
+    /// memory of a 16-bit cache without calibration and works on GPUs without FP8 support.
+    Int8,
+    /// FP8 (e4m3) with per-head scales. Halves the memory of a 16-bit cache at a small accuracy
+    /// cost, best suited to Hopper and Ada GPUs.
+    Fp8,
+}
+
+impl KVCacheDtype {
+    /// The dtype of the cache tensors for a model running in `dtype`.
+    pub fn storage_dtype(&self, dtype: DType) -> Result<DType, APIError> {
+        match (self, dtype) {
+            (Self::Auto, _) | (Self::Fp16, DType::F16) | (Self::Bf16, DType::BF16) => Ok(dtype),
+            (Self::Fp16 | Self::Bf16, _) => Err(APIError::new(format!(
+                "A {self:?} KV cache cannot be used with a {dtype:?} model."
+            ))),
+            (Self::Int8 | Self::Fp8, _) => Ok(DType::U8),
+        }
+    }
+
+    /// The name of the cache format used to select the attention kernels.
+    pub fn kernel_name(&self) -> &'static str {
+        match self {
+            Self::Auto | Self::Fp16 | Self::Bf16 => "auto",
+            Self::Int8 => "int8",
+            Self::Fp8 => "fp8_e4m3",
+        }
+    }
+
+    /// Number of `x`-element rows appended to each head of a key block. An INT8 cache stores the
+    /// f32 scales of the tokens of the block in this row.
+    fn key_scale_rows(&self) -> usize {
+        match self {
+            Self::Int8 => 1,
+            _ => 0,
+        }
+    }
+
+    /// Number of rows appended to each head of a value block. An INT8 cache stores the f32
+    /// scales of the tokens of the block in the first of these (byte) rows, which are padded to
+    /// the size of the key scale row as the attention kernels use the same strides for both.
+    fn value_scale_rows(&self) -> usize {
+        match self {
+            Self::Int8 => 16,
+            _ => 0,
+        }
+    }
+}
+
+/// Memory layout of the blocks of the KV cache. The kernels tell the layouts apart by the rank of
+/// the key cache, see `backend::is_nhd_layout`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KVCacheLayout {
+    /// Keys as [num_blocks, num_kv_heads, head_size/x, block_size, x] and values as
+    /// [num_blocks, num_kv_heads, head_size, block_size], so that the attention kernels load 16
+    /// bytes of a key and several tokens of a value at once.
+    #[default]
+    Split,
+    /// Keys and values as [num_blocks, block_size, num_kv_heads, head_size], so each new token
+    /// is written contiguously. Only supported for an unquantized cache on CUDA devices.
+    Nhd,
+}
+
+/// StreamingLLM-style attention sinks: the first `num_sink_blocks` blocks of each sequence are
+/// kept for its whole lifetime, while the blocks after them are evicted once a sequence holds
+/// more than `num_window_blocks` of them. Sequences can then grow without bound in a fixed amount
+/// of KV cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AttentionSinks {
+    pub num_sink_blocks: usize,
+    pub num_window_blocks: usize,
+}
+
 #[derive(Clone)]
 pub struct CacheConfig {
     pub block_size: usize,
     pub num_gpu_blocks: Option<usize>, // Set after profiling init
     pub num_cpu_blocks: Option<usize>, // Set after profiling init
     pub fully_init: bool,
+    /// Host memory reserved for swapped out blocks, used to size the CPU block pool.
+    pub swap_space_bytes: usize,
+    /// Reuse the GPU blocks of identical prompt prefixes across requests.
+    pub enable_prefix_caching: bool,
+    /// Maximum number of prefix cache blocks per tenant namespace, unlimited if `None`.
+    pub prefix_cache_quota: Option<usize>,
+    /// Storage type of the KV cache.
+    pub cache_dtype: KVCacheDtype,
+    /// Memory layout of the KV cache blocks.
+    pub cache_layout: KVCacheLayout,
+    /// Evict the blocks between the attention sinks and the most recent blocks, see
+    /// `AttentionSinks`.
+    pub attention_sinks: Option<AttentionSinks>,
 }
 
 impl CacheConfig {
@@ -34,12 +138,22 @@ impl CacheConfig {
     }
 }
 
-pub type KVCache = (Tensor, Tensor);
+/// Swap space above this fraction of the host RAM is rejected.
+const MAX_SWAP_SPACE_FRACTION: f64 = 0.7;
+/// Swap space above this fraction of the host RAM is allowed with a warning.
+const WARN_SWAP_SPACE_FRACTION: f64 = 0.4;
+
+fn spin_lock(cache: &Mutex<KVCacheManager>) -> MutexGuard<'_, KVCacheManager> {
+    loop {
+        if let Ok(v) = cache.try_lock() {
+            return v;
+        }
+    }
+}
 
 pub struct CacheEngine {
-    gpu_cache: Arc<Mutex<Vec<KVCache>>>,
-    cpu_cache: Vec<KVCache>,
-    num_layers: usize,
+    gpu_cache: Arc<Mutex<KVCacheManager>>,
+    cpu_cache: KVCacheManager,
 }
 
 impl CacheEngine {
@@ -48,189 +162,266 @@ impl CacheEngine {
         cache_config: CacheConfig,
         dtype: DType,
     ) -> Result<Self, APIError> {
+        Self::new_on_device(
+            model_config,
+            cache_config,
+            dtype,
+            try_api!(Device::new_cuda(0)),
+        )
+    }
+
+    /// Create a cache engine whose GPU cache lives on `device`.
+    pub fn new_on_device(
+        model_config: Box<dyn ConfigLike>,
+        cache_config: CacheConfig,
+        dtype: DType,
+        device: Device,
+    ) -> Result<Self, APIError> {
+        assert!(cache_config.fully_init);
+        Self::check_device_support(&*model_config, &cache_config, &device)?;
         Ok(Self {
-            gpu_cache: Arc::new(Mutex::new(Self::allocate_gpu_cache(
+            gpu_cache: Arc::new(Mutex::new(KVCacheManager::new(
                 &*model_config,
                 &cache_config,
                 dtype,
+                cache_config.num_gpu_blocks.unwrap(),
+                &device,
             )?)),
-            cpu_cache: Self::allocate_cpu_cache(&*model_config, &cache_config, dtype)?,
-            num_layers: model_config.get_num_hidden_layers(),
+            cpu_cache: KVCacheManager::new(
+                &*model_config,
+                &cache_config,
+                dtype,
+                cache_config.num_cpu_blocks.unwrap(),
+                &Device::Cpu,
+            )?,
         })
     }
 
-    pub fn get_kv_cache(&self) -> MutexGuard<'_, Vec<KVCache>> {
-        loop {
-            if let Ok(v) = self.gpu_cache.try_lock() {
-                return v;
+    pub fn get_kv_cache(&self) -> MutexGuard<'_, KVCacheManager> {
+        spin_lock(&self.gpu_cache)
+    }
+
+    /// Size in bytes of the GPU cache and of the CPU swap space.
+    pub fn memory_usage(&self) -> (usize, usize) {
+        (
+            self.get_kv_cache().memory_usage(),
+            self.cpu_cache.memory_usage(),
+        )
+    }
+
+    /// Fails for a cache the kernels of `device` cannot attend to.
+    fn check_device_support(
+        model_config: &dyn ConfigLike,
+        cache_config: &CacheConfig,
+        device: &Device,
+    ) -> Result<(), APIError> {
+        if model_config.get_mla_dims().is_some() {
+            if !device.is_cuda() {
+                return Err(APIError::new_str(
+                    "The latent KV cache is only supported on CUDA devices.",
+                ));
+            }
+            if cache_config.cache_dtype.kernel_name() != "auto" {
+                return Err(APIError::new(format!(
+                    "A {:?} KV cache is not supported with multi-head latent attention.",
+                    cache_config.cache_dtype
+                )));
+            }
+        } else if cache_config.cache_layout == KVCacheLayout::Nhd {
+            if !device.is_cuda() {
+                return Err(APIError::new_str(
+                    "The NHD KV cache layout is only supported on CUDA devices.",
+                ));
+            }
+            if cache_config.cache_dtype.kernel_name() != "auto" {
+                return Err(APIError::new(format!(
+                    "A {:?} KV cache does not support the NHD cache layout.",
+                    cache_config.cache_dtype
+                )));
             }
         }
+        Ok(())
     }
+}
 
-    fn allocate_gpu_cache(
+impl CacheEngine {
+    /// Size in bytes of one block of the KV cache, across all layers and tensor-parallel ranks.
+    pub fn get_cache_block_size(
         model_config: &dyn ConfigLike,
         cache_config: &CacheConfig,
         dtype: DType,
-    ) -> Result<Vec<KVCache>, APIError> {
-        assert!(cache_config.fully_init);
+    ) -> Result<usize, APIError> {
+        let (num_heads, key_rows, block_size, x) =
+            Self::calculate_key_block_shape(model_config, cache_config, dtype)?;
+        let (_, value_rows, _) = Self::calculate_value_block_shape(model_config, cache_config);
+        let block_elements = match model_config.get_mla_dims() {
+            Some(mla_dims) => block_size * mla_dims.cache_entry_size(),
+            None => num_heads * block_size * (key_rows * x + value_rows),
+        };
+        Ok(block_elements
+            * model_config.get_num_kv_cache_layers()
+            * model_config.get_tensor_parallel_size()
+            * cache_config
+                .cache_dtype
+                .storage_dtype(dtype)?
+                .size_in_bytes())
+    }
 
-        let key_block_shape =
-            Self::calculate_key_block_shape(model_config, dtype, cache_config.block_size);
-        let value_block_shape =
-            Self::calculate_value_block_shape(model_config, cache_config.block_size);
-        let mut gpu_cache = Vec::new();
-        for _ in 0..model_config.get_num_hidden_layers() {
-            let cuda_device = try_api!(Device::new_cuda(0));
-            let key_blocks = try_api!(Tensor::zeros(
-                (
-                    cache_config.num_gpu_blocks.unwrap(),
-                    key_block_shape.0,
-                    key_block_shape.1,
-                    key_block_shape.2,
-                    key_block_shape.3,
-                ),
-                dtype,
-                &cuda_device,
-            ));
-            let value_blocks = try_api!(Tensor::zeros(
-                (
-                    cache_config.num_gpu_blocks.unwrap(),
-                    value_block_shape.0,
-                    value_block_shape.1,
-                    value_block_shape.2,
-                ),
-                dtype,
-                &cuda_device,
-            ));
-            gpu_cache.push((key_blocks, value_blocks));
+    /// Number of CPU blocks which fit into `cache_config.swap_space_bytes`. Fails if the swap
+    /// space would take up too much of the host RAM.
+    pub fn get_num_cpu_blocks(
+        model_config: &dyn ConfigLike,
+        cache_config: &CacheConfig,
+        dtype: DType,
+    ) -> Result<usize, APIError> {
+        if let Some(total_memory) = get_total_host_memory() {
+            let fraction = cache_config.swap_space_bytes as f64 / total_memory as f64;
+            if fraction > MAX_SWAP_SPACE_FRACTION {
+                return Err(APIError::new(format!(
+                    "Too large swap space: {:.2} GiB out of {:.2} GiB of host memory is allocated for the swap space.",
+                    cache_config.swap_space_bytes as f64 / GIB,
+                    total_memory as f64 / GIB
+                )));
+            } else if fraction > WARN_SWAP_SPACE_FRACTION {
+                log_warning(&format!(
+                    "Possibly too large swap space: {:.2} GiB out of {:.2} GiB of host memory is allocated for the swap space.",
+                    cache_config.swap_space_bytes as f64 / GIB,
+                    total_memory as f64 / GIB
+                ));
+            }
         }
-        Ok(gpu_cache)
+        let block_bytes = Self::get_cache_block_size(model_config, cache_config, dtype)?;
+        // Pure state-space models have no KV cache to swap.
+        if block_bytes == 0 {
+            return Ok(0);
+        }
+        Ok(cache_config.swap_space_bytes / block_bytes)
     }
 
-    fn allocate_cpu_cache(
+    /// The shape of the key cache of a layer with `num_blocks` blocks in the configured layout,
+    /// on one tensor-parallel rank.
+    /// The latent cache of multi-head latent attention is [num_blocks, block_size, 1,
+    /// kv_lora_rank + qk_rope_head_dim] in either layout.
+    pub(crate) fn key_cache_shape(
         model_config: &dyn ConfigLike,
         cache_config: &CacheConfig,
         dtype: DType,
-    ) -> Result<Vec<KVCache>, APIError> {
-        assert!(cache_config.fully_init);
+        num_blocks: usize,
+    ) -> Result<Vec<usize>, APIError> {
+        let (num_heads, key_rows, block_size, x) =
+            Self::calculate_key_block_shape(model_config, cache_config, dtype)?;
+        if let Some(mla_dims) = model_config.get_mla_dims() {
+            return Ok(vec![num_blocks, block_size, 1, mla_dims.cache_entry_size()]);
+        }
+        Ok(match cache_config.cache_layout {
+            KVCacheLayout::Split => vec![num_blocks, num_heads, key_rows, block_size, x],
+            KVCacheLayout::Nhd => vec![
+                num_blocks,
+                block_size,
+                num_heads,
+                model_config.get_head_size(),
+            ],
+        })
+    }
 
-        let key_block_shape =
-            Self::calculate_key_block_shape(model_config, dtype, cache_config.block_size);
-        let value_block_shape =
-            Self::calculate_value_block_shape(model_config, cache_config.block_size);
-        let mut cpu_cache = Vec::new();
-        for _ in 0..model_config.get_num_hidden_layers() {
-            let cuda_device = try_api!(Device::new_cuda(0));
-            let key_blocks = try_api!(Tensor::zeros(
-                (
-                    cache_config.num_cpu_blocks.unwrap(),
-                    key_block_shape.0,
-                    key_block_shape.1,
-                    key_block_shape.2,
-                    key_block_shape.3,
-                ),
-                dtype,
-                &cuda_device,
-            ));
-            let value_blocks = try_api!(Tensor::zeros(
-                (
-                    cache_config.num_cpu_blocks.unwrap(),
-                    value_block_shape.0,
-                    value_block_shape.1,
-                    value_block_shape.2,
-                ),
-                dtype,
-                &cuda_device,
-            ));
-            cpu_cache.push((key_blocks, value_blocks));
+    /// The shape of the value cache of a layer with `num_blocks` blocks in the configured layout.
+    pub(crate) fn value_cache_shape(
+        model_config: &dyn ConfigLike,
+        cache_config: &CacheConfig,
+        num_blocks: usize,
+    ) -> Vec<usize> {
+        let (num_heads, value_rows, block_size) =
+            Self::calculate_value_block_shape(model_config, cache_config);
+        if let Some(mla_dims) = model_config.get_mla_dims() {
+            return vec![num_blocks, block_size, 1, mla_dims.cache_entry_size()];
+        }
+        match cache_config.cache_layout {
+            KVCacheLayout::Split => vec![num_blocks, num_heads, value_rows, block_size],
+            KVCacheLayout::Nhd => vec![num_blocks, block_size, num_heads, value_rows],
         }
-        Ok(cpu_cache)
     }
-}
 
-impl CacheEngine {
     fn calculate_key_block_shape(
         model_config: &dyn ConfigLike,
+        cache_config: &CacheConfig,
         dtype: DType,
-        block_size: usize,
-    ) -> (usize, usize, usize, usize) {
-        let element_size = dtype.size_in_bytes();
+    ) -> Result<(usize, usize, usize, usize), APIError> {
+        let element_size = cache_config
+            .cache_dtype
+            .storage_dtype(dtype)?
+            .size_in_bytes();
         let x = 16 / element_size;
-        (
-            model_config.get_num_kv_heads(),
-            model_config.get_head_size() / x,
-            block_size,
+        Ok((
+            model_config.get_num_kv_heads() / model_config.get_tensor_parallel_size(),
+            model_config.get_head_size() / x + cache_config.cache_dtype.key_scale_rows(),
+            cache_config.block_size,
             x,
-        )
+        ))
     }
 
     fn calculate_value_block_shape(
         model_config: &dyn ConfigLike,
-        block_size: usize,
+        cache_config: &CacheConfig,
     ) -> (usize, usize, usize) {
         (
-            model_config.get_num_kv_heads(),
-            model_config.get_head_size(),
-            block_size,
+            model_config.get_num_kv_heads() / model_config.get_tensor_parallel_size(),
+            model_config.get_head_size() + cache_config.cache_dtype.value_scale_rows(),
+            cache_config.block_size,
         )
     }
 }
 
 impl CacheEngine {
     pub fn swap_in(&self, src_to_dst: HashMap<usize, usize>) -> Result<(), APIError> {
-        for i in 0..self.num_layers {
-            let (src_key_cache, src_value_cache) = self.cpu_cache.get(i).unwrap();
-            let mut gpu_cache = self.get_kv_cache();
-            let (dst_key_cache, dst_value_cache) = gpu_cache.get_mut(i).unwrap();
-            // Swap (copy) key blocks
-            try_api!(swap_blocks(
-                src_key_cache.clone(),
-                dst_key_cache,
-                src_to_dst.clone()
-            ));
-            // Swap (copy) key blocks
-            try_api!(swap_blocks(
-                src_value_cache.clone(),
-                dst_value_cache,
-                src_to_dst.clone()
-            ));
-        }
-        Ok(())
+        let _range = profiling::range("swap_in");
+        self.get_kv_cache().swap_from(&self.cpu_cache, &src_to_dst)
     }
 
     pub fn swap_out(&mut self, src_to_dst: HashMap<usize, usize>) -> Result<(), APIError> {
-        for i in 0..self.num_layers {
-            let gpu_cache = self.get_kv_cache();
-            let (src_key_cache, src_value_cache) = gpu_cache.get(i).unwrap().clone();
-            drop(gpu_cache);
-
-            let (dst_key_cache, dst_value_cache) = self.cpu_cache.get_mut(i).unwrap();
-            // Swap (copy) key blocks
-            try_api!(swap_blocks(
-                src_key_cache.clone(),
-                dst_key_cache,
-                src_to_dst.clone()
-            ));
-            // Swap (copy) key blocks
-            try_api!(swap_blocks(
-                src_value_cache.clone(),
-                dst_value_cache,
-                src_to_dst.clone()
-            ));
-        }
-        Ok(())
+        let _range = profiling::range("swap_out");
+        let gpu_cache = spin_lock(&self.gpu_cache);
+        self.cpu_cache.swap_from(&gpu_cache, &src_to_dst)
+    }
+
+    /// Copy migrated blocks (see `Scheduler::migrate_session`) into the GPU cache of `dst`,
+    /// which lives on another device.
+    pub fn migrate_to(
+        &self,
+        dst: &CacheEngine,
+        src_to_dst: HashMap<usize, usize>,
+    ) -> Result<(), APIError> {
+        let src_cache = self.get_kv_cache();
+        let mut dst_cache = dst.get_kv_cache();
+        src_cache.migrate_to(&mut dst_cache, &src_to_dst)
     }
 
     pub fn copy(&mut self, src_to_dst: HashMap<usize, Vec<usize>>) -> Result<(), APIError> {
-        let mut gpu_cache = self.get_kv_cache();
-        #[allow(clippy::map_identity)]
-        let caches: (Vec<&mut Tensor>, Vec<&mut Tensor>) =
-            gpu_cache.iter_mut().map(|(a, b)| (a, b)).unzip();
-        let (key_caches, value_caches) = caches;
+        let _range = profiling::range("copy_blocks");
+        self.get_kv_cache().copy_blocks(src_to_dst)
+    }
 
-        // NOTE(EricLBuehler): This may synchronize the CPU and GPU
-        try_api!(unsafe { copy_blocks(key_caches, value_caches, src_to_dst) });
+    /// Copy the keys and values of token slots (source slot, destination slot) within the GPU
+    /// cache, e.g. of the accepted candidates of a token tree to the positions of their tokens.
+    pub fn copy_slots(&self, src_to_dst: &[(usize, usize)]) -> Result<(), APIError> {
+        let _range = profiling::range("copy_slots");
+        self.get_kv_cache().copy_slots(src_to_dst)
+    }
 
-        Ok(())
+    /// Allocate the recurrent state slots of the state-space layers in the GPU cache, see
+    /// `scheduler::state_cache`. They are not swapped, so the CPU cache has none.
+    pub fn allocate_state_cache(
+        &self,
+        ssm_dims: SsmDims,
+        num_slots: usize,
+        dtype: DType,
+    ) -> Result<(), APIError> {
+        self.get_kv_cache()
+            .allocate_ssm_states(ssm_dims, num_slots, dtype)
+    }
+
+    /// Copy state slots (source slot, destination slot), e.g. of forked sequences.
+    pub fn copy_states(&self, src_to_dst: &[(usize, usize)]) -> Result<(), APIError> {
+        let _range = profiling::range("copy_states");
+        self.get_kv_cache().copy_states(src_to_dst)
     }
 }
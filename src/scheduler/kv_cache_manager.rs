@@ -0,0 +1,438 @@
+//! The KV cache tensors of all layers of a model on one device. The `CacheEngine` owns one
+//! manager for the GPU cache and one for the CPU swap space, and the models attend through the
+//! typed per-layer accessors. A tensor-parallel model has a pool per rank, each holding the heads
+//! of its rank on the device of the rank. A pipeline-parallel model has a pool per stage, each
+//! holding the layers of its stage on the device of the stage.
+
+use std::{collections::HashMap, iter::zip};
+
+use candle_core::{DType, Device, Tensor};
+
+use crate::{
+    backend::{copy_blocks, gather_cached_slots, migrate_blocks, reshape_and_cache, swap_blocks},
+    openai::{
+        models::{pipeline_parallel::stage_layers, tensor_parallel::rank_devices, ConfigLike},
+        responses::APIError,
+    },
+    try_api,
+};
+
+use super::{
+    cache_engine::{CacheConfig, CacheEngine},
+    state_cache::SsmDims,
+};
+
+/// The cache of one layer.
+pub enum LayerKVCache {
+    /// Separate key and value caches in the layout of `KVCacheLayout`.
+    Paged {
+        key_cache: Tensor,
+        value_cache: Tensor,
+    },
+    /// The latent cache of multi-head latent attention, shape = [num_blocks, block_size, 1,
+    /// kv_lora_rank + qk_rope_head_dim], see `paged_attention::mla`.
+    Latent { kv_cache: Tensor },
+}
+
+impl LayerKVCache {
+    /// The key and value cache of the layer. Both are the latent cache for multi-head latent
+    /// attention.
+    pub fn key_value(&self) -> (&Tensor, &Tensor) {
+        match self {
+            Self::Paged {
+                key_cache,
+                value_cache,
+            } => (key_cache, value_cache),
+            Self::Latent { kv_cache } => (kv_cache, kv_cache),
+        }
+    }
+
+    /// The latent cache, None for separate key and value caches.
+    pub fn latent(&self) -> Option<&Tensor> {
+        match self {
+            Self::Paged { .. } => None,
+            Self::Latent { kv_cache } => Some(kv_cache),
+        }
+    }
+
+    /// Size in bytes of the cache tensors of the layer.
+    pub fn memory_usage(&self) -> usize {
+        self.tensors()
+            .iter()
+            .map(|tensor| tensor.elem_count() * tensor.dtype().size_in_bytes())
+            .sum()
+    }
+
+    /// The distinct tensors of the layer.
+    fn tensors(&self) -> Vec<&Tensor> {
+        match self {
+            Self::Paged {
+                key_cache,
+                value_cache,
+            } => vec![key_cache, value_cache],
+            Self::Latent { kv_cache } => vec![kv_cache],
+        }
+    }
+
+    fn tensors_mut(&mut self) -> Vec<&mut Tensor> {
+        match self {
+            Self::Paged {
+                key_cache,
+                value_cache,
+            } => vec![key_cache, value_cache],
+            Self::Latent { kv_cache } => vec![kv_cache],
+        }
+    }
+}
+
+/// The recurrent state of one state-space layer, a slot per sequence, see
+/// `scheduler::state_cache`.
+pub struct LayerSsmState {
+    /// The last `d_conv - 1` inputs of the causal convolution, shape = [num_slots, d_inner,
+    /// d_conv - 1].
+    pub conv_state: Tensor,
+    /// shape = [num_slots, d_inner, d_state] (f32).
+    pub ssm_state: Tensor,
+}
+
+pub struct KVCacheManager {
+    layers: Vec<LayerKVCache>,
+    /// The states of the state-space layers, empty for models without them.
+    ssm_states: Vec<LayerSsmState>,
+    num_blocks: usize,
+    device: Device,
+    /// The pools of the other tensor-parallel ranks, with the same blocks.
+    ranks: Vec<KVCacheManager>,
+    /// The pools of the other pipeline stages, with the same blocks.
+    stages: Vec<KVCacheManager>,
+}
+
+impl KVCacheManager {
+    /// Allocate `num_blocks` zeroed blocks of each layer on `device`, in the storage type and
+    /// layout of `cache_config`. A tensor-parallel model gets a pool on the device of each rank,
+    /// a pipeline-parallel model a pool on the device of each stage.
+    pub fn new(
+        model_config: &dyn ConfigLike,
+        cache_config: &CacheConfig,
+        dtype: DType,
+        num_blocks: usize,
+        device: &Device,
+    ) -> Result<Self, APIError> {
+        let pipeline_parallel_size = model_config.get_pipeline_parallel_size();
+        let layers = stage_layers(
+            model_config.get_num_kv_cache_layers(),
+            pipeline_parallel_size,
+        );
+        let devices = rank_devices(device, pipeline_parallel_size)?;
+        let mut stages = zip(layers, &devices)
+            .map(|(layers, device)| {
+                Self::new_stage(
+                    model_config,
+                    cache_config,
+                    dtype,
+                    num_blocks,
+                    layers.len(),
+                    device,
+                )
+            })
+            .collect::<Result<Vec<_>, APIError>>()?;
+        let mut manager = stages.remove(0);
+        manager.stages = stages;
+        Ok(manager)
+    }
+
+    /// The pool of `num_layers` layers of a stage, with the pools of the other tensor-parallel
+    /// ranks.
+    fn new_stage(
+        model_config: &dyn ConfigLike,
+        cache_config: &CacheConfig,
+        dtype: DType,
+        num_blocks: usize,
+        num_layers: usize,
+        device: &Device,
+    ) -> Result<Self, APIError> {
+        let devices = rank_devices(device, model_config.get_tensor_parallel_size())?;
+        let mut ranks = devices
+            .iter()
+            .map(|device| {
+                Ok(Self {
+                    layers: Self::allocate_layers(
+                        model_config,
+                        cache_config,
+                        dtype,
+                        num_blocks,
+                        num_layers,
+                        device,
+                    )?,
+                    ssm_states: Vec::new(),
+                    num_blocks,
+                    device: device.clone(),
+                    ranks: Vec::new(),
+                    stages: Vec::new(),
+                })
+            })
+            .collect::<Result<Vec<_>, APIError>>()?;
+        let mut manager = ranks.remove(0);
+        manager.ranks = ranks;
+        Ok(manager)
+    }
+
+    fn allocate_layers(
+        model_config: &dyn ConfigLike,
+        cache_config: &CacheConfig,
+        dtype: DType,
+        num_blocks: usize,
+        num_layers: usize,
+        device: &Device,
+    ) -> Result<Vec<LayerKVCache>, APIError> {
+        let key_shape =
+            CacheEngine::key_cache_shape(model_config, cache_config, dtype, num_blocks)?;
+        let value_shape = CacheEngine::value_cache_shape(model_config, cache_config, num_blocks);
+        let dtype = cache_config.cache_dtype.storage_dtype(dtype)?;
+        let mut layers = Vec::with_capacity(num_layers);
+        for _ in 0..num_layers {
+            let key_cache = try_api!(Tensor::zeros(key_shape.clone(), dtype, device));
+            layers.push(if model_config.get_mla_dims().is_some() {
+                LayerKVCache::Latent {
+                    kv_cache: key_cache,
+                }
+            } else {
+                LayerKVCache::Paged {
+                    key_cache,
+                    value_cache: try_api!(Tensor::zeros(value_shape.clone(), dtype, device)),
+                }
+            });
+        }
+        Ok(layers)
+    }
+
+    /// Allocate `num_slots` zeroed state slots of each state-space layer, the convolution states
+    /// in `dtype` and the SSM states in f32.
+    pub fn allocate_ssm_states(
+        &mut self,
+        ssm_dims: SsmDims,
+        num_slots: usize,
+        dtype: DType,
+    ) -> Result<(), APIError> {
+        self.ssm_states = (0..ssm_dims.num_layers)
+            .map(|_| {
+                Ok(LayerSsmState {
+                    conv_state: try_api!(Tensor::zeros(
+                        ssm_dims.conv_state_shape(num_slots),
+                        dtype,
+                        &self.device
+                    )),
+                    ssm_state: try_api!(Tensor::zeros(
+                        ssm_dims.ssm_state_shape(num_slots),
+                        DType::F32,
+                        &self.device
+                    )),
+                })
+            })
+            .collect::<Result<Vec<_>, APIError>>()?;
+        Ok(())
+    }
+
+    pub fn num_layers(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.num_blocks
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    /// The pool of tensor-parallel rank `rank`, this one for the first rank.
+    pub fn rank(&self, rank: usize) -> &KVCacheManager {
+        match rank {
+            0 => self,
+            rank => &self.ranks[rank - 1],
+        }
+    }
+
+    /// The pool of pipeline stage `stage`, this one for the first stage. Its layers are indexed
+    /// from the first layer of the stage.
+    pub fn stage(&self, stage: usize) -> &KVCacheManager {
+        match stage {
+            0 => self,
+            stage => &self.stages[stage - 1],
+        }
+    }
+
+    pub fn layer(&self, layer: usize) -> &LayerKVCache {
+        &self.layers[layer]
+    }
+
+    pub fn layers(&self) -> &[LayerKVCache] {
+        &self.layers
+    }
+
+    /// The state of the state-space layer `layer`, counting only the state-space layers.
+    pub fn ssm_state(&self, layer: usize) -> &LayerSsmState {
+        &self.ssm_states[layer]
+    }
+
+    pub fn ssm_states(&self) -> &[LayerSsmState] {
+        &self.ssm_states
+    }
+
+    /// Size in bytes of the cache tensors and recurrent states of all layers.
+    pub fn memory_usage(&self) -> usize {
+        let states = self
+            .ssm_states
+            .iter()
+            .flat_map(|layer| [&layer.conv_state, &layer.ssm_state])
+            .map(|tensor| tensor.elem_count() * tensor.dtype().size_in_bytes())
+            .sum::<usize>();
+        self.layers
+            .iter()
+            .map(LayerKVCache::memory_usage)
+            .sum::<usize>()
+            + states
+            + self
+                .ranks
+                .iter()
+                .chain(&self.stages)
+                .map(Self::memory_usage)
+                .sum::<usize>()
+    }
+
+    /// Copy the blocks of `src` on another device (the swap space or the GPU cache) into this
+    /// cache, mapping source to destination block numbers.
+    pub fn swap_from(
+        &mut self,
+        src: &KVCacheManager,
+        src_to_dst: &HashMap<usize, usize>,
+    ) -> Result<(), APIError> {
+        for (src_layer, dst_layer) in zip(&src.layers, &mut self.layers) {
+            for (src_cache, dst_cache) in zip(src_layer.tensors(), dst_layer.tensors_mut()) {
+                swap_blocks(src_cache.clone(), dst_cache, src_to_dst.clone())?;
+            }
+        }
+        let pools = zip(
+            src.ranks.iter().chain(&src.stages),
+            self.ranks.iter_mut().chain(&mut self.stages),
+        );
+        for (src, dst) in pools {
+            dst.swap_from(src, src_to_dst)?;
+        }
+        Ok(())
+    }
+
+    /// Copy blocks into the cache `dst` on another GPU, over the peer link where available.
+    pub fn migrate_to(
+        &self,
+        dst: &mut KVCacheManager,
+        src_to_dst: &HashMap<usize, usize>,
+    ) -> Result<(), APIError> {
+        for (src_layer, dst_layer) in zip(&self.layers, &mut dst.layers) {
+            for (src_cache, dst_cache) in zip(src_layer.tensors(), dst_layer.tensors_mut()) {
+                migrate_blocks(src_cache, dst_cache, src_to_dst)?;
+            }
+        }
+        let pools = zip(
+            self.ranks.iter().chain(&self.stages),
+            dst.ranks.iter_mut().chain(&mut dst.stages),
+        );
+        for (src, dst) in pools {
+            src.migrate_to(dst, src_to_dst)?;
+        }
+        Ok(())
+    }
+
+    /// Copy blocks within the cache, each source block into all of its destination blocks.
+    pub fn copy_blocks(&mut self, src_to_dst: HashMap<usize, Vec<usize>>) -> Result<(), APIError> {
+        if self.layers.is_empty() {
+            return Ok(());
+        }
+        // The kernel copies the key and value cache of each layer, so a latent cache is passed as
+        // both. Both copies write the same blocks, which is harmless.
+        let (mut key_caches, mut value_caches): (Vec<Tensor>, Vec<Tensor>) = self
+            .layers
+            .iter()
+            .map(|layer| {
+                let (key_cache, value_cache) = layer.key_value();
+                (key_cache.clone(), value_cache.clone())
+            })
+            .unzip();
+        for pool in self.ranks.iter_mut().chain(&mut self.stages) {
+            pool.copy_blocks(src_to_dst.clone())?;
+        }
+        // NOTE(EricLBuehler): This may synchronize the CPU and GPU
+        try_api!(unsafe {
+            copy_blocks(
+                key_caches.iter_mut().collect(),
+                value_caches.iter_mut().collect(),
+                src_to_dst,
+            )
+        });
+        Ok(())
+    }
+
+    /// Copy the key and value of each source token slot into its destination slot, in each
+    /// layer. All sources are read before any destination is written. Only unquantized caches
+    /// with separate keys and values are supported.
+    pub fn copy_slots(&mut self, src_to_dst: &[(usize, usize)]) -> Result<(), APIError> {
+        if src_to_dst.is_empty() {
+            return Ok(());
+        }
+        let (src_slots, dst_slots): (Vec<usize>, Vec<i64>) = src_to_dst
+            .iter()
+            .map(|(src, dst)| (*src, *dst as i64))
+            .unzip();
+        let slot_mapping = try_api!(Tensor::new(dst_slots, &self.device));
+        for layer in &mut self.layers {
+            let LayerKVCache::Paged {
+                key_cache,
+                value_cache,
+            } = layer
+            else {
+                return Err(APIError::new_str(
+                    "Token slots cannot be copied within a latent cache.",
+                ));
+            };
+            if key_cache.dtype() == DType::U8 {
+                return Err(APIError::new_str(
+                    "Token slots cannot be copied within a quantized KV cache.",
+                ));
+            }
+            let (key, value) = gather_cached_slots(key_cache, value_cache, &src_slots)?;
+            unsafe { reshape_and_cache(key, value, key_cache, value_cache, slot_mapping.clone()) }?;
+        }
+        for pool in self.ranks.iter_mut().chain(&mut self.stages) {
+            pool.copy_slots(src_to_dst)?;
+        }
+        Ok(())
+    }
+
+    /// Copy the state of each source slot into its destination slot, in each state-space layer.
+    pub fn copy_states(&mut self, src_to_dst: &[(usize, usize)]) -> Result<(), APIError> {
+        if self.ssm_states.is_empty() || src_to_dst.is_empty() {
+            return Ok(());
+        }
+        let mut mapping: HashMap<usize, Vec<usize>> = HashMap::new();
+        for (src, dst) in src_to_dst {
+            mapping.entry(*src).or_default().push(*dst);
+        }
+        // The slots are copied like blocks. The states have no value cache, so each state is
+        // passed as both caches, which copies it twice.
+        for select in [
+            |layer: &LayerSsmState| layer.conv_state.clone(),
+            |layer: &LayerSsmState| layer.ssm_state.clone(),
+        ] {
+            let mut states = self.ssm_states.iter().map(select).collect::<Vec<_>>();
+            let mut aliases = states.clone();
+            try_api!(unsafe {
+                copy_blocks(
+                    states.iter_mut().collect(),
+                    aliases.iter_mut().collect(),
+                    mapping.clone(),
+                )
+            });
+        }
+        Ok(())
+    }
+}
@@ -2,6 +2,8 @@
 //! primary method `schedule` returns the batched sequences as inputs, as well as the
 //! operations to be executed on the cache by the CacheEngine.
 
+/// Benchmarks the attention kernel launch settings for the loaded model at startup.
+pub mod autotune;
 /// The higher-level manager of the blocks allocated. Operations performed by the block engine do
 /// not directly change memory.
 pub mod block_engine;
@@ -9,7 +11,15 @@ pub mod block_engine;
 /// actually allocates the KV cache for the CPU and GPU. It is used by the LLMEngine to execute
 /// operations issued by the scheduler.
 pub mod cache_engine;
+/// The KV cache tensors of all layers on one device, with typed per-layer accessors.
+pub mod kv_cache_manager;
+pub mod observer;
+/// Named scheduler configurations for common workloads.
+pub mod preset;
 pub mod sequence;
+/// The recurrent state slots of the sequences of state-space models, managed alongside the block
+/// engine.
+pub mod state_cache;
 
 type CPUBlockFrom = usize;
 type GPUBlockFrom = usize;
@@ -18,64 +28,310 @@ type GPUBlockTo = usize;
 type SrcBlockFrom = usize;
 type DstBlocksTo = Vec<usize>;
 
+/// Hasher of the maps holding scheduling state (block tables, prefix cache, sessions, the
+/// sequences of a group). With the `deterministic` feature it uses a fixed seed, so that their
+/// iteration order, and with it block allocation, eviction and scheduling order, is reproducible
+/// across runs.
+#[cfg(feature = "deterministic")]
+pub type StateHasher = BuildHasherDefault<DefaultHasher>;
+#[cfg(not(feature = "deterministic"))]
+pub type StateHasher = RandomState;
+
+pub type StateMap<K, V> = HashMap<K, V, StateHasher>;
+
+#[cfg(not(feature = "deterministic"))]
+use std::collections::hash_map::RandomState;
+#[cfg(feature = "deterministic")]
+use std::{collections::hash_map::DefaultHasher, hash::BuildHasherDefault};
 use std::{
     collections::{HashMap, VecDeque},
     sync::Arc,
+    time::{Duration, Instant},
 };
 
-use crate::{
-    log_warning,
-    scheduler::{block_engine::AllocStatus, sequence::SequenceStatus},
-};
+use crate::{log_warning, scheduler::block_engine::AllocStatus};
 
-use self::{block_engine::BlockEngine, cache_engine::CacheConfig, sequence::SequenceGroup};
+use self::{
+    block_engine::{BlockEngine, BlockEngineCheckpoint},
+    cache_engine::CacheConfig,
+    observer::{SchedulerEvent, SchedulerObserver},
+    sequence::{CancellationReason, Sequence, SequenceGroup, SequenceStatus},
+    state_cache::StateCache,
+};
 
 pub struct SchedulerOutput {
     pub scheduled: Arc<VecDeque<Arc<SequenceGroup>>>,
     pub blocks_to_swap_in: HashMap<CPUBlockFrom, GPUBlockTo>,
     pub blocks_to_swap_out: HashMap<GPUBlockFrom, CPUBlockTo>,
     pub blocks_to_copy: HashMap<SrcBlockFrom, DstBlocksTo>,
+    /// The state slots to copy (source slot, destination slot), e.g. for forked sequences.
+    pub states_to_copy: Vec<(usize, usize)>,
     pub ignored_seq_groups: Arc<VecDeque<Arc<SequenceGroup>>>,
+    pub num_prefill_tokens: usize,
+    pub num_decode_tokens: usize,
+}
+
+impl SchedulerOutput {
+    /// Whether each sequence of `seq_ids` may be decoded several tokens per step, for a step
+    /// whose groups hold the sequences. Sequences of groups which opted out of speculation are
+    /// decoded one token at a time alongside the others. None if the step cannot verify several
+    /// tokens of any sequence: it prefills prompts, decodes forked sequences (`best_of` > 1) or
+    /// no group uses speculation.
+    pub fn speculation_mask(&self, seq_ids: &[usize]) -> Option<Vec<bool>> {
+        if self.num_prefill_tokens > 0
+            || self
+                .scheduled
+                .iter()
+                .any(|group| group.get_seqs().len() != 1)
+        {
+            return None;
+        }
+        let speculative = self
+            .scheduled
+            .iter()
+            .filter(|group| group.uses_speculation())
+            .flat_map(|group| group.get_seqs().into_keys())
+            .collect::<Vec<_>>();
+        if speculative.is_empty() {
+            return None;
+        }
+        Some(
+            seq_ids
+                .iter()
+                .map(|seq_id| speculative.contains(seq_id))
+                .collect(),
+        )
+    }
+}
+
+/// Number of recent steps considered when limiting the share of prefill steps.
+const PREFILL_FAIRNESS_WINDOW: usize = 20;
+/// Weight of the latest measurement in the prefill throughput estimate.
+const PREFILL_THROUGHPUT_SMOOTHING: f64 = 0.2;
+
+/// The order in which waiting sequence groups are admitted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum SchedulingPolicy {
+    /// First come, first served.
+    Fcfs,
+    /// Admit the waiting groups with the highest prefix cache hit ratio first, breaking ties by
+    /// arrival. Requires prefix caching to be enabled.
+    CacheAware,
 }
 
 pub struct SchedulerConfig {
     pub max_num_seqs: usize,
+    pub policy: SchedulingPolicy,
+    /// Keep the blocks of finished session groups for follow-up requests. A session which is idle
+    /// for this long is swapped out, and dropped after being idle for twice as long. Sessions are
+    /// not retained if `None`.
+    pub session_ttl: Option<Duration>,
+    /// Maximum number of tokens processed in one step.
+    pub max_num_batched_tokens: usize,
+    /// While there are running sequences, cap the prefill tokens of a step to this fraction of
+    /// `max_num_batched_tokens` and only run a prefill step if less than this fraction of the
+    /// recent steps were prefill steps. This keeps prompt-heavy traffic from starving decoding.
+    pub max_prefill_fraction: Option<f32>,
+    /// Maximum number of prompt tokens prefilled in one step, regardless of the decode load.
+    /// Bounds the latency a burst of long prompts adds to running sequences.
+    pub max_num_prefill_tokens: Option<usize>,
+}
+
+/// A session, scoped to the cache namespace of its requests so that clients of different
+/// namespaces cannot resume each other's sessions.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct SessionKey {
+    cache_namespace: Option<String>,
+    session_id: String,
+}
+
+impl SessionKey {
+    fn new(cache_namespace: Option<&str>, session_id: &str) -> Self {
+        Self {
+            cache_namespace: cache_namespace.map(str::to_string),
+            session_id: session_id.to_string(),
+        }
+    }
+}
+
+/// The finished sequence group of a session, kept so that a follow-up request can reuse its
+/// blocks through the prefix cache.
+struct ParkedSession {
+    seq_group: Arc<SequenceGroup>,
+    last_active: Instant,
+    swapped_out: bool,
+}
+
+/// The state before the step returned by the last call to `Scheduler::schedule`, kept until the
+/// step is committed or rolled back.
+struct UncommittedStep {
+    waiting: VecDeque<Arc<SequenceGroup>>,
+    num_recomputing: usize,
+    running: VecDeque<Arc<SequenceGroup>>,
+    swapped_out: VecDeque<Arc<SequenceGroup>>,
+    pending_swap_in: HashMap<CPUBlockFrom, GPUBlockTo>,
+    pending_swap_out: HashMap<GPUBlockFrom, CPUBlockTo>,
+    recent_steps: VecDeque<bool>,
+    statuses: Vec<(Arc<Sequence>, SequenceStatus)>,
+    block_engine: BlockEngineCheckpoint,
+    state_cache: Option<StateCache>,
+    pending_state_copies: Vec<(usize, usize)>,
+    /// The sequences forked during the step, removed from their groups on rollback.
+    forked: Vec<(Arc<SequenceGroup>, usize)>,
+    /// Events of the step, delivered to the observers on commit.
+    events: Vec<(String, Instant, SchedulerEvent)>,
 }
 
 pub struct Scheduler {
     waiting: VecDeque<Arc<SequenceGroup>>,
+    /// Number of groups at the front of `waiting` which were preempted by recomputation. They
+    /// are scheduled before new groups, whatever the policy.
+    num_recomputing: usize,
     running: VecDeque<Arc<SequenceGroup>>,
     swapped_out: VecDeque<Arc<SequenceGroup>>,
+    sessions: StateMap<SessionKey, ParkedSession>,
+    /// Swaps issued outside of `schedule` (e.g. for sessions), executed with the next step.
+    pending_swap_in: HashMap<CPUBlockFrom, GPUBlockTo>,
+    pending_swap_out: HashMap<GPUBlockFrom, CPUBlockTo>,
+    /// Whether each of the recent steps was a prefill step.
+    recent_steps: VecDeque<bool>,
+    /// Moving average of the prefill throughput, used to estimate time to first token.
+    prefill_tokens_per_sec: Option<f64>,
+    /// Groups which were shed because their TTFT deadline cannot be met.
+    slo_missed: Vec<Arc<SequenceGroup>>,
+    observers: Vec<Box<dyn SchedulerObserver>>,
+    uncommitted: Option<UncommittedStep>,
     config: SchedulerConfig,
     pub block_engine: BlockEngine,
+    /// The state slots of the sequences of state-space models, None for models without
+    /// state-space layers.
+    pub state_cache: Option<StateCache>,
+    /// State copies issued outside of `schedule` (e.g. for forks), executed with the next step.
+    pending_state_copies: Vec<(usize, usize)>,
+    /// Whether the sequences hold KV cache blocks, false for models without a KV cache.
+    kv_blocks: bool,
+    /// Slots reserved after the last token of each running sequence, see
+    /// `set_num_lookahead_slots`.
+    num_lookahead_slots: usize,
 }
 
 impl Scheduler {
     pub fn new(config: SchedulerConfig, cache_config: &CacheConfig) -> Self {
         assert!(cache_config.fully_init);
+        if config.policy == SchedulingPolicy::CacheAware && !cache_config.enable_prefix_caching {
+            log_warning("The cache-aware scheduling policy has no effect without prefix caching.");
+        }
+        // The block tables of sequences with evicted blocks no longer line up with their tokens.
+        let enable_prefix_caching =
+            cache_config.enable_prefix_caching && cache_config.attention_sinks.is_none();
+        if cache_config.enable_prefix_caching && !enable_prefix_caching {
+            log_warning("Prefix caching is disabled with attention sinks.");
+        }
         Self {
             waiting: VecDeque::new(),
+            num_recomputing: 0,
             running: VecDeque::new(),
             swapped_out: VecDeque::new(),
+            sessions: StateMap::default(),
+            pending_swap_in: HashMap::new(),
+            pending_swap_out: HashMap::new(),
+            recent_steps: VecDeque::new(),
+            prefill_tokens_per_sec: None,
+            slo_missed: Vec::new(),
+            observers: Vec::new(),
+            uncommitted: None,
             config,
             block_engine: BlockEngine::new(
                 cache_config.block_size,
                 cache_config.num_gpu_blocks.unwrap(),
                 cache_config.num_cpu_blocks.unwrap(),
+                enable_prefix_caching,
+                cache_config.prefix_cache_quota,
+                cache_config.attention_sinks,
             ),
+            state_cache: None,
+            pending_state_copies: Vec::new(),
+            kv_blocks: true,
+            num_lookahead_slots: 0,
         }
     }
 
+    /// Schedule the sequences of a state-space model, which hold one of `num_slots` recurrent
+    /// state slots instead of growing block tables. The blocks are only allocated if the model
+    /// also has attention layers (`uses_kv_blocks`). State slots cannot be swapped, so sequences
+    /// are always preempted by recomputation.
+    #[must_use]
+    pub fn with_state_cache(mut self, num_slots: usize, uses_kv_blocks: bool) -> Self {
+        self.state_cache = Some(StateCache::new(num_slots, uses_kv_blocks));
+        self
+    }
+
+    /// Schedule the sequences of an encoder-only model, which are encoded in a single step and
+    /// hold no blocks. The batches are only limited by `SchedulerConfig`.
+    #[must_use]
+    pub fn without_kv_blocks(mut self) -> Self {
+        self.kv_blocks = false;
+        self
+    }
+
+    /// Reserve `num_lookahead_slots` slots after the last token of each running sequence in every
+    /// decode step, for the draft tokens verified by speculative decoding.
+    pub fn set_num_lookahead_slots(&mut self, num_lookahead_slots: usize) {
+        self.num_lookahead_slots = num_lookahead_slots;
+    }
+
     pub fn add_sequence(&mut self, seq_group: SequenceGroup) {
+        self.notify(
+            &seq_group,
+            SchedulerEvent::Arrived {
+                num_prompt_tokens: seq_group.get_prompt_len(),
+            },
+        );
         self.waiting.push_back(Arc::new(seq_group));
     }
 
+    pub fn add_observer(&mut self, observer: Box<dyn SchedulerObserver>) {
+        self.observers.push(observer);
+    }
+
+    /// Report the latency of an engine step to the observers of the scheduled groups.
+    pub fn record_step_latency(
+        &mut self,
+        scheduled: &VecDeque<Arc<SequenceGroup>>,
+        latency: Duration,
+        is_prefill: bool,
+    ) {
+        for seq_group in scheduled {
+            self.notify(
+                seq_group,
+                SchedulerEvent::Step {
+                    latency_ms: latency.as_secs_f64() * 1000.,
+                    is_prefill,
+                },
+            );
+        }
+    }
+
+    /// Propose the next step. Its allocations, swaps and state changes are applied right away so
+    /// that the inputs can be prepared, but stay provisional until `commit` is called once the
+    /// step was executed. If executing it fails, `rollback` undoes them.
     pub fn schedule(&mut self) -> SchedulerOutput {
+        self.commit();
+        self.evict_idle_sessions(Instant::now());
+        self.shed_missed_ttft_deadlines(Instant::now());
+        self.begin_step();
+
         // If there are no swapped seqs (they have higher priority), add seqs that are in the
         // waiting queue to the running queue.
-        if self.swapped_out.is_empty() {
+        if self.swapped_out.is_empty() && self.can_schedule_prefill() {
             let mut scheduled = VecDeque::new();
             let mut ignored_seq_groups = VecDeque::new();
+            let prefill_token_budget = self.get_prefill_token_budget();
+            let mut num_prefill_tokens = 0;
+            if self.config.policy == SchedulingPolicy::CacheAware {
+                self.sort_waiting_by_prefix_cache_hit_ratio();
+            }
+            self.sort_waiting_by_ttft_deadline();
             while !self.waiting.is_empty() {
                 let seq_group = self.waiting.front().unwrap().clone();
 
@@ -91,8 +347,16 @@ impl Scheduler {
                     break;
                 }
 
+                // Always admit at least one group, even if its prompt exceeds the budget.
+                let num_prompt_tokens = seq_group.get_prompt_len() * seq_group.get_seqs().len();
+                if !scheduled.is_empty()
+                    && num_prefill_tokens + num_prompt_tokens > prefill_token_budget
+                {
+                    break;
+                }
+
                 // If we cannot allocate either now or in the future, either do not continue or remove the sequence.
-                let can_allocate = self.block_engine.can_allocate(&seq_group);
+                let can_allocate = self.can_allocate(&seq_group);
                 match can_allocate {
                     AllocStatus::Later => break, //If we can only allocate later, do not bother iterating over the rest.
                     AllocStatus::Impossible => {
@@ -101,33 +365,47 @@ impl Scheduler {
                             seq_group.get_prompt_len())
                         );
                         seq_group.set_status(SequenceStatus::FinishedIgnored);
-                        ignored_seq_groups.push_back(self.waiting.pop_front().unwrap());
+                        self.notify(&seq_group, SchedulerEvent::Ignored);
+                        ignored_seq_groups.push_back(self.pop_waiting());
+                        continue;
                     }
                     _ => {}
                 }
 
                 seq_group.set_status(SequenceStatus::Running);
+                seq_group.set_first_scheduled_time(Instant::now());
                 self._allocate(&seq_group);
+                num_prefill_tokens += num_prompt_tokens;
+                self.notify(
+                    &seq_group,
+                    SchedulerEvent::Scheduled {
+                        num_blocks: self.block_engine.get_num_allocated_blocks(&seq_group),
+                    },
+                );
 
-                let seq_group = self.waiting.pop_front().unwrap();
+                let seq_group = self.pop_waiting();
                 self.running.push_back(seq_group.clone());
                 scheduled.push_back(seq_group);
             }
 
             // If we did schedule, or we ignored sequences.
             if !scheduled.is_empty() || !ignored_seq_groups.is_empty() {
+                self.record_step(true);
                 return SchedulerOutput {
                     scheduled: Arc::new(scheduled),
-                    blocks_to_swap_in: HashMap::new(),
+                    blocks_to_swap_in: std::mem::take(&mut self.pending_swap_in),
                     blocks_to_copy: HashMap::new(),
-                    blocks_to_swap_out: HashMap::new(),
+                    blocks_to_swap_out: std::mem::take(&mut self.pending_swap_out),
+                    states_to_copy: std::mem::take(&mut self.pending_state_copies),
                     ignored_seq_groups: Arc::new(ignored_seq_groups),
+                    num_prefill_tokens,
+                    num_decode_tokens: 0,
                 };
             }
         }
 
-        let mut blocks_to_swap_out = HashMap::new();
-        let mut blocks_to_swap_in = HashMap::new();
+        let mut blocks_to_swap_out = std::mem::take(&mut self.pending_swap_out);
+        let mut blocks_to_swap_in = std::mem::take(&mut self.pending_swap_in);
         let mut blocks_to_copy = HashMap::new();
 
         // Reserve token slots for the running sequence groups, preempting the lowest (earliest) first.
@@ -144,7 +422,7 @@ impl Scheduler {
         while !self.running.is_empty() {
             let seq_group = self.running.pop_front().unwrap();
             let mut finished_with_break = false;
-            while !self.block_engine.can_append_token_to_seq(&seq_group) {
+            while !self.can_append_token_to_seq(&seq_group) {
                 // If we cannot, now we need to preempt some seqs
                 if !self.running.is_empty() {
                     // There is something to preempt.
@@ -186,6 +464,12 @@ impl Scheduler {
                 let seq_group = self.swapped_out.pop_front().unwrap();
                 // Swap in the blocks
                 let to_swap_in = self.block_engine.swap_in(&seq_group);
+                self.notify(
+                    &seq_group,
+                    SchedulerEvent::SwappedIn {
+                        num_blocks: to_swap_in.len(),
+                    },
+                );
                 blocks_to_swap_in.extend(to_swap_in);
                 // Reserve a new slot
                 self._append_token_slot_to_seq_group(&seq_group, &mut blocks_to_copy);
@@ -193,17 +477,290 @@ impl Scheduler {
             }
         }
 
+        self.record_step(false);
         SchedulerOutput {
             scheduled: self.running.clone().into(),
             blocks_to_swap_in,
             blocks_to_copy,
             blocks_to_swap_out,
+            states_to_copy: std::mem::take(&mut self.pending_state_copies),
             ignored_seq_groups: Arc::new(VecDeque::new()),
+            num_prefill_tokens: 0,
+            num_decode_tokens: self
+                .running
+                .iter()
+                .map(|group| group.get_seqs().len())
+                .sum(),
+        }
+    }
+
+    /// Keep the scheduling decisions of the last step, after it was executed successfully.
+    pub fn commit(&mut self) {
+        let Some(step) = self.uncommitted.take() else {
+            return;
+        };
+        for (request_id, time, event) in step.events {
+            for observer in &mut self.observers {
+                observer.on_event(&request_id, time, &event);
+            }
+        }
+    }
+
+    /// Undo the scheduling decisions of the last step, after executing it failed. Block tables,
+    /// queues and sequence states are restored, so the `scheduled` groups are requeued where they
+    /// were before the step. Its swaps and copies are scheduled again with the next step.
+    pub fn rollback(&mut self, scheduled: &VecDeque<Arc<SequenceGroup>>) {
+        let Some(step) = self.uncommitted.take() else {
+            return;
+        };
+        self.waiting = step.waiting;
+        self.num_recomputing = step.num_recomputing;
+        self.running = step.running;
+        self.swapped_out = step.swapped_out;
+        self.pending_swap_in = step.pending_swap_in;
+        self.pending_swap_out = step.pending_swap_out;
+        self.recent_steps = step.recent_steps;
+        self.pending_state_copies = step.pending_state_copies;
+        for (seq, status) in step.statuses {
+            seq.deref_mut().deref().set_status(status);
+        }
+        for (seq_group, seq_id) in step.forked {
+            seq_group.remove_seq(seq_id);
+        }
+        self.block_engine.restore(step.block_engine);
+        self.state_cache = step.state_cache;
+        for seq_group in scheduled {
+            self.notify(seq_group, SchedulerEvent::RolledBack);
         }
     }
 
     pub fn has_unfinished_sequences(&self) -> bool {
-        !self.running.is_empty()
+        !self.running.is_empty() || !self.waiting.is_empty() || !self.swapped_out.is_empty()
+    }
+
+    /// Update the prefill throughput estimate with a prefill step of `num_tokens` tokens.
+    pub fn record_prefill_throughput(&mut self, num_tokens: usize, elapsed: Duration) {
+        if num_tokens == 0 || elapsed.is_zero() {
+            return;
+        }
+        let throughput = num_tokens as f64 / elapsed.as_secs_f64();
+        self.prefill_tokens_per_sec = Some(match self.prefill_tokens_per_sec {
+            Some(avg) => {
+                PREFILL_THROUGHPUT_SMOOTHING * throughput
+                    + (1. - PREFILL_THROUGHPUT_SMOOTHING) * avg
+            }
+            None => throughput,
+        });
+    }
+
+    /// Forget the recent steps and the prefill throughput estimate, e.g. after a warmup whose
+    /// first steps are slowed down by kernel loading.
+    pub fn reset_step_history(&mut self) {
+        self.recent_steps.clear();
+        self.prefill_tokens_per_sec = None;
+    }
+
+    /// Moving average of the prefill throughput in tokens per second, `None` until a prefill
+    /// step has been measured.
+    pub fn get_prefill_throughput(&self) -> Option<f64> {
+        self.prefill_tokens_per_sec
+    }
+
+    fn estimate_prefill_time(&self, num_tokens: usize) -> Option<Duration> {
+        self.prefill_tokens_per_sec
+            .map(|throughput| Duration::from_secs_f64(num_tokens as f64 / throughput))
+    }
+
+    /// The groups which were shed since the last call because they could not meet their TTFT
+    /// deadline. Their sequences are finished with `FinishedAborted`.
+    pub fn take_slo_missed(&mut self) -> Vec<Arc<SequenceGroup>> {
+        std::mem::take(&mut self.slo_missed)
+    }
+
+    /// Abort the sequence group of `request_id`, wherever it is queued, and free its blocks.
+    /// Returns the aborted group, whose sequences are finished with `FinishedAborted`.
+    pub fn abort_request(
+        &mut self,
+        request_id: &str,
+        reason: CancellationReason,
+    ) -> Option<Arc<SequenceGroup>> {
+        let seq_group = self
+            .waiting
+            .iter()
+            .chain(&self.running)
+            .chain(&self.swapped_out)
+            .find(|group| group.get_request_id() == request_id)?
+            .clone();
+        self._abort_seq_group(&seq_group, reason);
+        Some(seq_group)
+    }
+
+    /// Abort all unfinished sequence groups, returning them.
+    pub fn abort_all(&mut self, reason: CancellationReason) -> Vec<Arc<SequenceGroup>> {
+        let seq_groups = self
+            .waiting
+            .iter()
+            .chain(&self.running)
+            .chain(&self.swapped_out)
+            .cloned()
+            .collect::<Vec<_>>();
+        for seq_group in &seq_groups {
+            self._abort_seq_group(seq_group, reason);
+        }
+        seq_groups
+    }
+
+    /// Fork the sequence `parent_id` of `seq_group` into a new sequence `child_id`, which shares
+    /// the parent's physical blocks until either of them writes to a shared block. A fork during
+    /// a step is undone by `rollback`.
+    pub fn fork_seq(
+        &mut self,
+        seq_group: &Arc<SequenceGroup>,
+        parent_id: usize,
+        child_id: usize,
+    ) -> Option<Arc<Sequence>> {
+        let parent = seq_group.get_seqs().get(&parent_id)?.clone();
+        if self
+            .state_cache
+            .as_ref()
+            .is_some_and(|state_cache| state_cache.get_num_free_slots() == 0)
+        {
+            return None;
+        }
+        let child = seq_group.fork_seq(parent_id, child_id)?;
+        if self.uses_kv_blocks() {
+            self.block_engine.fork(&parent, &child);
+        }
+        if let Some(state_cache) = &mut self.state_cache {
+            let copy = state_cache.fork(&parent, &child).unwrap();
+            self.pending_state_copies.push(copy);
+        }
+        if let Some(step) = &mut self.uncommitted {
+            step.forked.push((seq_group.clone(), child_id));
+        }
+        Some(child)
+    }
+
+    /// Hand the parked group of `session_id` in `cache_namespace` over to the scheduler `dst` of another replica, e.g.
+    /// to the replica a follow-up request of the session was placed on. `copy` copies the blocks
+    /// (this device -> `dst` device) with `CacheEngine::migrate_to`, after which they are
+    /// registered in the prefix cache of `dst`. If it fails, the session is dropped. Returns
+    /// whether the session was migrated: it is not if it is not parked on the GPU here, `dst`
+    /// lacks space or holds a more recent group of the session, or a step of either scheduler is
+    /// uncommitted, since rolling that step back would restore the blocks moved since.
+    pub fn migrate_session<E>(
+        &mut self,
+        dst: &mut Scheduler,
+        cache_namespace: Option<&str>,
+        session_id: &str,
+        copy: impl FnOnce(HashMap<usize, usize>) -> Result<(), E>,
+    ) -> Result<bool, E> {
+        if self.uncommitted.is_some() || dst.uncommitted.is_some() {
+            return Ok(false);
+        }
+        let key = SessionKey::new(cache_namespace, session_id);
+        let Some(session) = self.sessions.get(&key) else {
+            return Ok(false);
+        };
+        if session.swapped_out
+            || dst
+                .sessions
+                .get(&key)
+                .is_some_and(|parked| parked.last_active >= session.last_active)
+            || !self
+                .block_engine
+                .can_migrate_seq_group(&dst.block_engine, &session.seq_group)
+        {
+            return Ok(false);
+        }
+        let session = self.sessions.remove(&key).unwrap();
+        let mapping = self
+            .block_engine
+            .migrate(&mut dst.block_engine, &session.seq_group);
+        let seq_group = session.seq_group.clone();
+        dst.park_session(session.seq_group);
+        if let Err(err) = copy(mapping) {
+            dst.drop_session(&key);
+            return Err(err);
+        }
+        dst.block_engine.cache_migrated_seq_group(&seq_group);
+        Ok(true)
+    }
+
+    /// Keep the blocks of the finished `seq_group` for its session, replacing the previously
+    /// parked group of that session.
+    pub fn park_session(&mut self, seq_group: Arc<SequenceGroup>) {
+        let key = SessionKey::new(
+            seq_group.get_cache_namespace(),
+            seq_group.get_session_id().unwrap(),
+        );
+        let parked = ParkedSession {
+            seq_group,
+            last_active: Instant::now(),
+            swapped_out: false,
+        };
+        if let Some(previous) = self.sessions.insert(key, parked) {
+            self._free(&previous.seq_group);
+        }
+    }
+
+    /// Bring the parked group of `session_id` in `cache_namespace` back onto the GPU, so that a
+    /// follow-up request can share its blocks. Returns whether the session was found.
+    pub fn resume_session(&mut self, cache_namespace: Option<&str>, session_id: &str) -> bool {
+        let key = SessionKey::new(cache_namespace, session_id);
+        let Some(session) = self.sessions.get(&key) else {
+            return false;
+        };
+        let seq_group = session.seq_group.clone();
+        if session.swapped_out {
+            if !self.block_engine.can_swap_in_seq_group(&seq_group) {
+                self.drop_session(&key);
+                return false;
+            }
+            let to_swap_in = self.block_engine.swap_in(&seq_group);
+            self.pending_swap_in.extend(to_swap_in);
+        }
+        let session = self.sessions.get_mut(&key).unwrap();
+        session.swapped_out = false;
+        session.last_active = Instant::now();
+        true
+    }
+
+    fn drop_session(&mut self, key: &SessionKey) {
+        if let Some(session) = self.sessions.remove(key) {
+            self._free(&session.seq_group);
+        }
+    }
+
+    /// Swap out the sessions which have been idle for longer than the TTL and drop those idle for
+    /// longer than twice the TTL.
+    fn evict_idle_sessions(&mut self, now: Instant) {
+        let Some(ttl) = self.config.session_ttl else {
+            return;
+        };
+        let idle = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| now.duration_since(session.last_active) > ttl)
+            .map(|(key, session)| {
+                let expired = now.duration_since(session.last_active) > 2 * ttl;
+                (key.clone(), expired, session.swapped_out)
+            })
+            .collect::<Vec<_>>();
+        for (key, expired, swapped_out) in idle {
+            if expired {
+                self.drop_session(&key);
+            } else if !swapped_out {
+                let seq_group = self.sessions[&key].seq_group.clone();
+                if !self.block_engine.can_swap_out_seq_group(&seq_group) {
+                    self.drop_session(&key);
+                    continue;
+                }
+                let to_swap_out = self.block_engine.swap_out(&seq_group);
+                self.pending_swap_out.extend(to_swap_out);
+                self.sessions.get_mut(&key).unwrap().swapped_out = true;
+            }
+        }
     }
 
     pub fn free_finished_sequence_groups(&mut self) {
@@ -222,12 +779,99 @@ impl Scheduler {
             .cloned()
             .collect::<VecDeque<_>>();
         for group in to_free {
-            self._free(&group);
+            self.notify(&group, SchedulerEvent::Finished);
+            // Sessions are resumed through the prefix cache, which cannot restore a recurrent
+            // state.
+            if self.config.session_ttl.is_some()
+                && self.state_cache.is_none()
+                && group.get_session_id().is_some()
+                && group.uses_prefix_cache()
+            {
+                self.park_session(group);
+            } else {
+                self._free(&group);
+            }
         }
     }
 }
 
 impl Scheduler {
+    /// Whether this step may be a prefill step, see `SchedulerConfig::max_prefill_fraction`.
+    fn can_schedule_prefill(&self) -> bool {
+        match self.config.max_prefill_fraction {
+            Some(fraction) if !self.running.is_empty() && !self.recent_steps.is_empty() => {
+                let num_prefill_steps = self.recent_steps.iter().filter(|x| **x).count();
+                (num_prefill_steps as f32 / self.recent_steps.len() as f32) < fraction
+            }
+            _ => true,
+        }
+    }
+
+    fn get_prefill_token_budget(&self) -> usize {
+        let budget = match self.config.max_prefill_fraction {
+            Some(fraction) if !self.running.is_empty() => {
+                (self.config.max_num_batched_tokens as f32 * fraction) as usize
+            }
+            _ => self.config.max_num_batched_tokens,
+        };
+        match self.config.max_num_prefill_tokens {
+            Some(max_num_prefill_tokens) => budget.min(max_num_prefill_tokens),
+            None => budget,
+        }
+    }
+
+    /// Checkpoint the state which `schedule` changes, see `rollback`.
+    fn begin_step(&mut self) {
+        let statuses = self
+            .waiting
+            .iter()
+            .chain(&self.running)
+            .chain(&self.swapped_out)
+            .flat_map(|group| group.get_seqs().into_values())
+            .map(|seq| {
+                let status = seq.deref_mut().get_status();
+                (seq, status)
+            })
+            .collect();
+        self.uncommitted = Some(UncommittedStep {
+            waiting: self.waiting.clone(),
+            num_recomputing: self.num_recomputing,
+            running: self.running.clone(),
+            swapped_out: self.swapped_out.clone(),
+            pending_swap_in: self.pending_swap_in.clone(),
+            pending_swap_out: self.pending_swap_out.clone(),
+            recent_steps: self.recent_steps.clone(),
+            statuses,
+            block_engine: self.block_engine.checkpoint(),
+            state_cache: self.state_cache.clone(),
+            pending_state_copies: self.pending_state_copies.clone(),
+            forked: Vec::new(),
+            events: Vec::new(),
+        });
+    }
+
+    fn notify(&mut self, seq_group: &SequenceGroup, event: SchedulerEvent) {
+        if self.observers.is_empty() {
+            return;
+        }
+        let now = Instant::now();
+        if let Some(step) = &mut self.uncommitted {
+            step.events
+                .push((seq_group.get_request_id().clone(), now, event));
+            return;
+        }
+        for observer in &mut self.observers {
+            observer.on_event(seq_group.get_request_id(), now, &event);
+        }
+    }
+
+    fn record_step(&mut self, is_prefill: bool) {
+        if self.recent_steps.len() == PREFILL_FAIRNESS_WINDOW {
+            self.recent_steps.pop_front();
+        }
+        self.recent_steps.push_back(is_prefill);
+    }
+
     fn remove_seq_group(&mut self, seq_group: &SequenceGroup) {
         // Remove it if it is in waiting
         if let Some(idx) = self
@@ -236,6 +880,9 @@ impl Scheduler {
             .position(|grp| grp.get_id() == seq_group.get_id())
         {
             self.waiting.remove(idx);
+            if idx < self.num_recomputing {
+                self.num_recomputing -= 1;
+            }
         };
         // Remove it if it is in running
         if let Some(idx) = self
@@ -259,6 +906,11 @@ impl Scheduler {
         seq_group: &SequenceGroup,
         blocks_to_copy: &mut HashMap<usize, Vec<usize>>,
     ) {
+        if !self.uses_kv_blocks() {
+            return;
+        }
+        let num_blocks = (!self.observers.is_empty())
+            .then(|| self.block_engine.get_num_allocated_blocks(seq_group));
         for seq in seq_group.get_seqs().values() {
             let op = self.block_engine.append_token_slot_to_seq(seq);
             if let Some((src_block, dst_block)) = op {
@@ -270,16 +922,37 @@ impl Scheduler {
                     blocks_to_copy.get_mut(&src_block).unwrap().push(dst_block);
                 }
             }
+            if self.num_lookahead_slots > 0 {
+                let copies = self
+                    .block_engine
+                    .append_lookahead_slots_to_seq(seq, self.num_lookahead_slots);
+                for (src_block, dst_block) in copies {
+                    blocks_to_copy.entry(src_block).or_default().push(dst_block);
+                }
+            }
+        }
+        if let Some(num_blocks) = num_blocks {
+            let num_new_blocks = self.block_engine.get_num_allocated_blocks(seq_group) - num_blocks;
+            if num_new_blocks > 0 {
+                self.notify(
+                    seq_group,
+                    SchedulerEvent::BlocksAllocated {
+                        num_blocks: num_new_blocks,
+                    },
+                );
+            }
         }
     }
 
-    fn _abort_seq_group(&mut self, seq_group: &SequenceGroup) {
+    fn _abort_seq_group(&mut self, seq_group: &SequenceGroup, reason: CancellationReason) {
         self.remove_seq_group(seq_group);
-        seq_group.set_status(SequenceStatus::FinishedAborted);
+        seq_group.set_status(SequenceStatus::FinishedAborted(reason));
         self._free(seq_group);
+        self.notify(seq_group, SchedulerEvent::Aborted { reason });
     }
 
     /// Preempt either by recomputation (for single sequence), or by swapping (for multiple).
+    /// State slots cannot be swapped, so groups with recurrent states are always recomputed.
     fn _preempt(
         &mut self,
         seq_group: Arc<SequenceGroup>,
@@ -287,6 +960,7 @@ impl Scheduler {
     ) {
         match seq_group.get_seqs().len() {
             1 => self._preempt_by_recompute(seq_group),
+            _ if self.state_cache.is_some() => self._preempt_by_recompute(seq_group),
             _ => self._preempt_by_swap(seq_group, blocks_to_swap_out),
         }
     }
@@ -294,7 +968,9 @@ impl Scheduler {
     fn _preempt_by_recompute(&mut self, seq_group: Arc<SequenceGroup>) {
         seq_group.set_status(SequenceStatus::Waiting);
         self._free(&seq_group);
+        self.notify(&seq_group, SchedulerEvent::PreemptedByRecompute);
         self.waiting.push_front(seq_group);
+        self.num_recomputing += 1;
     }
 
     fn _preempt_by_swap(
@@ -304,37 +980,143 @@ impl Scheduler {
     ) {
         if !self.block_engine.can_swap_out_seq_group(&seq_group) {
             // If we cannot swap it out, abort the sequence group.
-            self._abort_seq_group(&seq_group);
+            self._abort_seq_group(&seq_group, CancellationReason::PreemptionExhausted);
             return;
         }
         let new_to_swap = self.block_engine.swap_out(&seq_group);
+        self.notify(
+            &seq_group,
+            SchedulerEvent::SwappedOut {
+                num_blocks: new_to_swap.len(),
+            },
+        );
         blocks_to_swap_out.extend(new_to_swap);
         seq_group.set_status(SequenceStatus::Swapped);
 
         self.swapped_out.push_back(seq_group);
     }
 
+    /// Whether the sequences hold KV cache blocks, false for pure state-space models and
+    /// encoder-only models.
+    fn uses_kv_blocks(&self) -> bool {
+        self.kv_blocks
+            && self
+                .state_cache
+                .as_ref()
+                .map_or(true, StateCache::uses_kv_blocks)
+    }
+
+    fn can_allocate(&self, seq_group: &SequenceGroup) -> AllocStatus {
+        let blocks = if self.uses_kv_blocks() {
+            self.block_engine.can_allocate(seq_group)
+        } else {
+            AllocStatus::Ok
+        };
+        let slots = match &self.state_cache {
+            Some(state_cache) => state_cache.can_allocate(seq_group),
+            None => AllocStatus::Ok,
+        };
+        match (blocks, slots) {
+            (AllocStatus::Impossible, _) | (_, AllocStatus::Impossible) => AllocStatus::Impossible,
+            (AllocStatus::Later, _) | (_, AllocStatus::Later) => AllocStatus::Later,
+            _ => AllocStatus::Ok,
+        }
+    }
+
+    /// Whether the sequences of `seq_group` can take another token. The state of a state-space
+    /// layer does not grow, so only the blocks of attention layers are checked.
+    fn can_append_token_to_seq(&self, seq_group: &SequenceGroup) -> bool {
+        !self.uses_kv_blocks()
+            || self
+                .block_engine
+                .can_append_token_to_seq(seq_group, self.num_lookahead_slots)
+    }
+
     fn _allocate(&mut self, seq_group: &SequenceGroup) {
-        self.block_engine.allocate(seq_group)
+        if self.uses_kv_blocks() {
+            self.block_engine.allocate(seq_group);
+        }
+        if let Some(state_cache) = &mut self.state_cache {
+            state_cache.allocate(seq_group);
+        }
     }
 
     fn _free(&mut self, seq_group: &SequenceGroup) {
         for seq in seq_group.get_seqs().values() {
             self.block_engine.free_sequence(seq);
+            if let Some(state_cache) = &mut self.state_cache {
+                state_cache.free_sequence(seq);
+            }
         }
     }
 
     fn sort_running_by_priority_fcfs(&mut self) {
         self.running
             .make_contiguous()
-            .sort_by_key(|seq_group| seq_group.arrival_time());
+            .sort_by_key(|seq_group| (seq_group.arrival_time(), *seq_group.get_id()));
         self.running.make_contiguous().reverse();
     }
 
+    /// Shed the waiting groups whose TTFT deadline cannot be met even if they are prefilled next.
+    fn shed_missed_ttft_deadlines(&mut self, now: Instant) {
+        let missed = self
+            .waiting
+            .iter()
+            // Groups preempted by recomputation have already produced their first token.
+            .filter(|group| {
+                group
+                    .get_seqs()
+                    .values()
+                    .all(|seq| seq.deref_mut().is_prompt())
+            })
+            .filter(|group| {
+                group.get_ttft_deadline().is_some_and(|deadline| {
+                    let prefill_time = self
+                        .estimate_prefill_time(group.get_prompt_len() * group.get_seqs().len())
+                        .unwrap_or_default();
+                    now + prefill_time > deadline
+                })
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        for seq_group in missed {
+            self._abort_seq_group(&seq_group, CancellationReason::DeadlineMissed);
+            self.slo_missed.push(seq_group);
+        }
+    }
+
+    fn pop_waiting(&mut self) -> Arc<SequenceGroup> {
+        self.num_recomputing = self.num_recomputing.saturating_sub(1);
+        self.waiting.pop_front().unwrap()
+    }
+
+    /// Earliest deadline first, groups without a deadline keep their order behind them. Groups
+    /// preempted by recomputation stay in front.
+    fn sort_waiting_by_ttft_deadline(&mut self) {
+        self.waiting.make_contiguous()[self.num_recomputing..].sort_by_key(|group| {
+            (
+                group.get_ttft_deadline().is_none(),
+                group.get_ttft_deadline(),
+            )
+        });
+    }
+
+    /// Stable sort, so groups with equal hit ratios keep their arrival order. Groups preempted by
+    /// recomputation stay in front.
+    fn sort_waiting_by_prefix_cache_hit_ratio(&mut self) {
+        let block_engine = &self.block_engine;
+        self.waiting.make_contiguous()[self.num_recomputing..].sort_by(|a, b| {
+            block_engine
+                .get_prefix_cache_hit_ratio(b)
+                .partial_cmp(&block_engine.get_prefix_cache_hit_ratio(a))
+                .unwrap()
+        });
+    }
+
     fn sort_swapped_out_by_priority_fcfs(&mut self) {
         self.swapped_out
             .make_contiguous()
-            .sort_by_key(|seq_group| seq_group.arrival_time());
+            .sort_by_key(|seq_group| (seq_group.arrival_time(), *seq_group.get_id()));
         self.swapped_out.make_contiguous().reverse();
     }
 }
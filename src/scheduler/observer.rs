@@ -0,0 +1,127 @@
+//! Hooks for observing the decisions of the `Scheduler`, for example to trace requests for
+//! scheduling benchmarks. Register an observer with `Scheduler::add_observer`.
+
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    time::Instant,
+};
+
+use serde::Serialize;
+
+use crate::{log_warning, openai::responses::APIError, try_api};
+
+use super::sequence::CancellationReason;
+
+/// A scheduling decision or state change of the sequence group of one request.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SchedulerEvent {
+    /// The request was added to the waiting queue.
+    Arrived {
+        num_prompt_tokens: usize,
+    },
+    /// The group was admitted from the waiting queue for prefill, allocating `num_blocks` blocks.
+    Scheduled {
+        num_blocks: usize,
+    },
+    /// The prompt can never fit in the cache, the request is dropped.
+    Ignored,
+    /// The group's blocks were freed and it was put back to the waiting queue.
+    PreemptedByRecompute,
+    SwappedOut {
+        num_blocks: usize,
+    },
+    SwappedIn {
+        num_blocks: usize,
+    },
+    /// New blocks were allocated for the generated tokens.
+    BlocksAllocated {
+        num_blocks: usize,
+    },
+    /// An engine step including the group finished.
+    Step {
+        latency_ms: f64,
+        is_prefill: bool,
+    },
+    /// The engine step including the group failed. The scheduling decisions of the step were
+    /// undone and the group was put back to the queue it was scheduled from.
+    RolledBack,
+    Aborted {
+        reason: CancellationReason,
+    },
+    Finished,
+}
+
+impl SchedulerEvent {
+    /// Whether no further events follow for the request.
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, Self::Ignored | Self::Aborted { .. } | Self::Finished)
+    }
+}
+
+pub trait SchedulerObserver: Send {
+    fn on_event(&mut self, request_id: &str, time: Instant, event: &SchedulerEvent);
+}
+
+#[derive(Serialize)]
+struct TraceEntry {
+    /// Milliseconds since the exporter was created.
+    time_ms: f64,
+    #[serde(flatten)]
+    event: SchedulerEvent,
+}
+
+#[derive(Serialize)]
+struct RequestTrace<'a> {
+    request_id: &'a str,
+    events: &'a [TraceEntry],
+}
+
+/// Records the events of each request and appends its trace as one JSON line to a file once the
+/// request finishes, is aborted or ignored.
+pub struct TraceExporter {
+    start: Instant,
+    traces: HashMap<String, Vec<TraceEntry>>,
+    file: File,
+}
+
+impl TraceExporter {
+    pub fn new(path: impl AsRef<Path>) -> Result<Self, APIError> {
+        let file = try_api!(OpenOptions::new().create(true).append(true).open(path));
+        Ok(Self {
+            start: Instant::now(),
+            traces: HashMap::new(),
+            file,
+        })
+    }
+
+    fn export(&mut self, request_id: &str, events: &[TraceEntry]) -> Result<(), APIError> {
+        let line = try_api!(serde_json::to_string(&RequestTrace { request_id, events }));
+        try_api!(writeln!(self.file, "{line}"));
+        Ok(())
+    }
+}
+
+impl SchedulerObserver for TraceExporter {
+    fn on_event(&mut self, request_id: &str, time: Instant, event: &SchedulerEvent) {
+        let entry = TraceEntry {
+            time_ms: time.saturating_duration_since(self.start).as_secs_f64() * 1000.,
+            event: event.clone(),
+        };
+        self.traces
+            .entry(request_id.to_string())
+            .or_default()
+            .push(entry);
+        if event.is_terminal() {
+            let events = self.traces.remove(request_id).unwrap();
+            if let Err(e) = self.export(request_id, &events) {
+                log_warning(&format!(
+                    "Failed to export the trace of request `{request_id}`: {e}"
+                ));
+            }
+        }
+    }
+}
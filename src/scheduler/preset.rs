@@ -0,0 +1,39 @@
+use super::{SchedulerConfig, SchedulingPolicy};
+
+/// A named combination of scheduler settings tuned for one kind of workload. Each setting of a
+/// preset can still be overridden individually.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum EnginePreset {
+    /// General purpose defaults.
+    #[default]
+    Balanced,
+    /// Few concurrent sequences and small prefill chunks, so that new prompts barely delay the
+    /// running sequences.
+    LowLatency,
+    /// Large batches without prefill limits, maximizing the tokens generated per second.
+    MaxThroughput,
+    /// Few concurrent sequences with large steps, so that long prompts are prefilled in few
+    /// chunks without evicting each other.
+    LongContext,
+}
+
+impl EnginePreset {
+    /// The scheduler configuration of this preset. Sessions are not retained.
+    pub fn scheduler_config(&self) -> SchedulerConfig {
+        let (max_num_seqs, max_num_batched_tokens, max_prefill_fraction, max_num_prefill_tokens) =
+            match self {
+                Self::Balanced => (256, 4096, None, None),
+                Self::LowLatency => (32, 2048, Some(0.3), Some(512)),
+                Self::MaxThroughput => (512, 8192, None, None),
+                Self::LongContext => (16, 16384, None, Some(8192)),
+            };
+        SchedulerConfig {
+            max_num_seqs,
+            policy: SchedulingPolicy::Fcfs,
+            session_ttl: None,
+            max_num_batched_tokens,
+            max_prefill_fraction,
+            max_num_prefill_tokens,
+        }
+    }
+}
@@ -1,11 +1,17 @@
 use std::{
-    collections::HashMap,
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
     sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
 };
 
+use candle_core::Tensor;
 use candle_sampling::logits_processor::Logprobs;
+use serde::{Deserialize, Serialize};
 
-use super::block_engine::LogicalTokenBlock;
+use crate::openai::{responses::APIError, IncrementalDetokenizer, TokenizerWrapper};
+
+use super::{block_engine::LogicalTokenBlock, StateMap};
 
 #[derive(Clone)]
 pub enum SequenceStatus {
@@ -13,10 +19,29 @@ pub enum SequenceStatus {
     Waiting,
     Running,
     Swapped,
-    FinishedAborted,
+    FinishedAborted(CancellationReason),
     Finished(String),
 }
 
+/// Why a request was aborted, reported to the client so it can decide whether to retry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CancellationReason {
+    /// Cancelled through the API, or the client disconnected.
+    ClientRequest,
+    /// Preempted while its blocks fit neither on the GPU nor in the swap space.
+    PreemptionExhausted,
+    /// Shed because it could not meet its time to first token objective.
+    DeadlineMissed,
+    /// The forward pass of its batch ran out of device memory.
+    OutOfMemory,
+    /// The server is shutting down.
+    Shutdown,
+    /// Stopped by a moderation service.
+    Moderation,
+}
+
+#[derive(Clone)]
 pub struct SequenceData {
     prompt_token_ids: Vec<usize>,
     output_token_ids: Vec<Logprobs>,
@@ -48,6 +73,46 @@ impl SequenceData {
     }
 }
 
+/// Timestamps recorded over the lifetime of a sequence, used to derive TTFT and
+/// inter-token latency.
+#[derive(Clone, Debug)]
+pub struct SequenceTimings {
+    pub arrival_time: Instant,
+    pub first_scheduled_time: Option<Instant>,
+    pub token_times: Vec<Instant>,
+}
+
+impl SequenceTimings {
+    fn new() -> Self {
+        Self {
+            arrival_time: Instant::now(),
+            first_scheduled_time: None,
+            token_times: Vec::new(),
+        }
+    }
+
+    /// Time spent waiting before the sequence was first scheduled.
+    pub fn queue_time(&self) -> Option<Duration> {
+        self.first_scheduled_time
+            .map(|scheduled| scheduled.duration_since(self.arrival_time))
+    }
+
+    /// Time from arrival until the first token was generated.
+    pub fn time_to_first_token(&self) -> Option<Duration> {
+        self.token_times
+            .first()
+            .map(|first| first.duration_since(self.arrival_time))
+    }
+
+    /// Time between each pair of consecutive generated tokens.
+    pub fn inter_token_latencies(&self) -> Vec<Duration> {
+        self.token_times
+            .windows(2)
+            .map(|pair| pair[1].duration_since(pair[0]))
+            .collect()
+    }
+}
+
 /// A Sequence holds information about the data it contains (the tokens), and the logical token blocks
 /// to which it is mapped.
 pub struct _Sequence {
@@ -55,15 +120,19 @@ pub struct _Sequence {
     seq_id: usize,
     logical_token_blocks: Vec<LogicalTokenBlock>,
     block_size: usize,
+    timings: SequenceTimings,
+    detokenizer: IncrementalDetokenizer,
 }
 
 impl _Sequence {
     pub fn new(prompt_token_ids: Vec<usize>, seq_id: usize, block_size: usize) -> Self {
         let mut this = Self {
+            detokenizer: IncrementalDetokenizer::new(prompt_token_ids.len()),
             data: Mutex::new(SequenceData::new(prompt_token_ids.clone())),
             seq_id,
             logical_token_blocks: Vec::new(),
             block_size,
+            timings: SequenceTimings::new(),
         };
         this.append_tokens_to_blocks(prompt_token_ids);
         this
@@ -72,6 +141,18 @@ impl _Sequence {
     pub fn add_token(&mut self, logprobs: Logprobs) {
         self.append_token_to_blocks(logprobs.token);
         self.deref_mut().append_token_id(logprobs);
+        self.timings.token_times.push(Instant::now());
+    }
+
+    /// Record the first time this sequence was scheduled. Later calls are ignored.
+    pub fn set_first_scheduled_time(&mut self, now: Instant) {
+        if self.timings.first_scheduled_time.is_none() {
+            self.timings.first_scheduled_time = Some(now);
+        }
+    }
+
+    pub fn get_timings(&self) -> &SequenceTimings {
+        &self.timings
     }
 
     pub fn blocks_to_add_new_tok(&mut self) -> usize {
@@ -88,6 +169,32 @@ impl _Sequence {
         self.logical_token_blocks.len()
     }
 
+    /// Hashes of the full logical blocks, each covering the block's tokens and all tokens
+    /// before it. Two sequences with equal hashes at some index share the whole prefix.
+    /// Hashes from different `namespace`s never collide on purpose, isolating tenants.
+    /// `DefaultHasher::new` uses fixed keys, so the hashes are stable across runs.
+    pub fn get_block_hashes(&self, namespace: Option<&str>) -> Vec<u64> {
+        let mut hasher = DefaultHasher::new();
+        namespace.hash(&mut hasher);
+        self.logical_token_blocks
+            .iter()
+            .take_while(|block| block.is_full())
+            .map(|block| {
+                block.get_token_ids().hash(&mut hasher);
+                hasher.finish()
+            })
+            .collect()
+    }
+
+    /// The token ids of the full logical blocks, in the order of `get_block_hashes`.
+    pub fn get_block_token_ids(&self) -> Vec<Vec<usize>> {
+        self.logical_token_blocks
+            .iter()
+            .take_while(|block| block.is_full())
+            .map(|block| block.get_token_ids().to_vec())
+            .collect()
+    }
+
     pub fn get_id(&self) -> usize {
         self.seq_id
     }
@@ -116,6 +223,49 @@ impl _Sequence {
         res
     }
 
+    pub fn get_prompt_token_ids(&self) -> Vec<usize> {
+        self.deref().prompt_token_ids.clone()
+    }
+
+    pub fn get_output_token_ids(&self) -> Vec<usize> {
+        self.deref()
+            .output_token_ids
+            .iter()
+            .map(|logprobs| logprobs.token)
+            .collect()
+    }
+
+    /// Decode the tokens generated since the last call, see `IncrementalDetokenizer`. Only the
+    /// tokens from the prefix offset of the detokenizer on are decoded.
+    pub fn detokenize_new_tokens<'s>(
+        &mut self,
+        tokenizer: &dyn TokenizerWrapper<'s, String>,
+    ) -> Result<(), APIError> {
+        let start = self.detokenizer.prefix_offset();
+        let token_ids = {
+            let data = self.deref();
+            let prompt = &data.prompt_token_ids;
+            prompt[start.min(prompt.len())..]
+                .iter()
+                .copied()
+                .chain(
+                    data.output_token_ids[start.saturating_sub(prompt.len())..]
+                        .iter()
+                        .map(|logprobs| logprobs.token),
+                )
+                .collect::<Vec<_>>()
+        };
+        let finished = self.is_finished();
+        self.detokenizer
+            .decode_next(tokenizer, &token_ids, finished)?;
+        Ok(())
+    }
+
+    /// The text of the generated tokens decoded by `detokenize_new_tokens` so far.
+    pub fn get_output_text(&self) -> &str {
+        self.detokenizer.text()
+    }
+
     pub fn get_last_token_id(&self) -> usize {
         if self.deref().output_token_ids.is_empty() {
             *self.deref().prompt_token_ids.last().unwrap()
@@ -127,7 +277,7 @@ impl _Sequence {
     pub fn is_finished(&self) -> bool {
         matches!(
             self.deref().status,
-            SequenceStatus::FinishedAborted
+            SequenceStatus::FinishedAborted(_)
                 | SequenceStatus::FinishedIgnored
                 | SequenceStatus::Finished(_)
         )
@@ -137,6 +287,19 @@ impl _Sequence {
         self.deref().get_cumulative_logprob()
     }
 
+    /// Create a new sequence with id `new_seq_id` which is a copy of this one. The physical
+    /// blocks are not copied, see `BlockEngine::fork`.
+    pub fn fork(&self, new_seq_id: usize) -> Self {
+        Self {
+            data: Mutex::new(self.deref().clone()),
+            seq_id: new_seq_id,
+            logical_token_blocks: self.logical_token_blocks.clone(),
+            block_size: self.block_size,
+            timings: self.timings.clone(),
+            detokenizer: self.detokenizer.clone(),
+        }
+    }
+
     pub fn set_finish_reason(&mut self, finish_reason: String) {
         self.deref()
             .set_status(SequenceStatus::Finished(finish_reason.clone()));
@@ -145,7 +308,7 @@ impl _Sequence {
     pub fn get_finish_reason(&self) -> String {
         match &self.deref().status {
             SequenceStatus::Finished(state) => state.clone(),
-            SequenceStatus::FinishedAborted => "abort".to_string(),
+            SequenceStatus::FinishedAborted(_) => "abort".to_string(),
             SequenceStatus::FinishedIgnored => "length".to_string(),
             _ => {
                 unreachable!("No finish reason.")
@@ -153,6 +316,18 @@ impl _Sequence {
         }
     }
 
+    pub fn get_status(&self) -> SequenceStatus {
+        self.deref().status.clone()
+    }
+
+    /// Why the sequence was aborted, `None` unless it was.
+    pub fn get_cancellation_reason(&self) -> Option<CancellationReason> {
+        match &self.deref().status {
+            SequenceStatus::FinishedAborted(reason) => Some(*reason),
+            _ => None,
+        }
+    }
+
     #[must_use]
     /// Clones the internal logprobs.
     pub fn get_output_tokens(&self) -> Vec<Logprobs> {
@@ -217,11 +392,18 @@ type SeqID = usize;
 /// A SequenceGroup holds the `n` (see SamplingParams) sequences generated from a single prompt.
 /// A SequenceGroup contains only sequences with the same prompt. They will always be scheduled together.
 pub struct SequenceGroup {
-    seqs: HashMap<SeqID, Arc<Sequence>>,
+    seqs: Mutex<StateMap<SeqID, Arc<Sequence>>>,
     arrival_time: u64,
     group_id: usize,
     request_id: String,
     created: u64,
+    cache_namespace: Option<String>,
+    session_id: Option<String>,
+    ttft_deadline: Option<Instant>,
+    prefix_caching: bool,
+    speculation: bool,
+    encoder_prompt: Vec<usize>,
+    images: Vec<Tensor>,
 }
 
 impl SequenceGroup {
@@ -231,47 +413,133 @@ impl SequenceGroup {
         group_id: usize,
         request_id: String,
         created: u64,
+        cache_namespace: Option<String>,
+        session_id: Option<String>,
     ) -> Self {
-        let mut seq_map = HashMap::new();
+        let mut seq_map = StateMap::default();
         for seq in seqs {
             seq_map.insert(seq.deref_mut().get_id(), seq.clone());
         }
         Self {
-            seqs: seq_map,
+            seqs: Mutex::new(seq_map),
             arrival_time,
             group_id,
             request_id,
             created,
+            cache_namespace,
+            session_id,
+            ttft_deadline: None,
+            prefix_caching: true,
+            speculation: true,
+            encoder_prompt: Vec::new(),
+            images: Vec::new(),
+        }
+    }
+
+    /// Whether the group may use the prefix cache, see `uses_prefix_cache`.
+    pub fn with_prefix_caching(mut self, prefix_caching: bool) -> Self {
+        self.prefix_caching = prefix_caching;
+        self
+    }
+
+    /// Whether the group may be decoded speculatively, see `uses_speculation`.
+    pub fn with_speculation(mut self, speculation: bool) -> Self {
+        self.speculation = speculation;
+        self
+    }
+
+    /// Require the first token to be produced before `deadline`, see `Scheduler::schedule`.
+    pub fn with_ttft_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.ttft_deadline = deadline;
+        self
+    }
+
+    /// The prompt tokens of an encoder-decoder model, which are encoded with the first step of
+    /// the group. Their encoder outputs are cached in static cross-attention blocks, see
+    /// `BlockEngine::cross_block_tables`.
+    pub fn with_encoder_prompt(mut self, encoder_prompt: Vec<usize>) -> Self {
+        self.encoder_prompt = encoder_prompt;
+        self
+    }
+
+    /// The pixels of the images of the prompt of a vision-language model, in prompt order. They
+    /// are encoded with each prefill of the group, including recomputations after preemption.
+    pub fn with_images(mut self, images: Vec<Tensor>) -> Self {
+        self.images = images;
+        self
+    }
+
+    fn seqs(&self) -> MutexGuard<'_, StateMap<SeqID, Arc<Sequence>>> {
+        loop {
+            if let Ok(v) = self.seqs.try_lock() {
+                return v;
+            }
         }
     }
 
     pub fn set_status(&self, status: SequenceStatus) {
-        for seq in self.seqs.values() {
+        for seq in self.seqs().values() {
             seq.deref_mut().deref().set_status(status.clone());
         }
     }
 
+    pub fn set_first_scheduled_time(&self, now: Instant) {
+        for seq in self.seqs().values() {
+            seq.deref_mut().set_first_scheduled_time(now);
+        }
+    }
+
     /// Blocks to add one new token to each sequence
     pub fn total_blocks_to_add_new_tok(&self) -> usize {
-        self.seqs
+        self.seqs()
             .values()
             .map(|seq| seq.deref_mut().blocks_to_add_new_tok())
             .sum()
     }
 
     pub fn get_prompt_len(&self) -> usize {
-        self.seqs.len()
+        self.seqs()
+            .values()
+            .next()
+            .map_or(0, |seq| seq.deref_mut().get_prompt_len())
+    }
+
+    pub fn get_num_encoder_tokens(&self) -> usize {
+        self.encoder_prompt.len()
+    }
+
+    pub fn get_encoder_prompt(&self) -> &[usize] {
+        &self.encoder_prompt
+    }
+
+    pub fn get_images(&self) -> &[Tensor] {
+        &self.images
     }
 
     pub fn get_total_logical_token_blocks(&self) -> usize {
-        self.seqs
+        self.seqs()
             .values()
             .map(|seq| seq.deref_mut().get_logical_token_blocks())
             .sum()
     }
 
-    pub fn get_seqs(&self) -> &HashMap<SeqID, Arc<Sequence>> {
-        &self.seqs
+    /// Returns a snapshot of the sequences currently in this group. The set of sequences
+    /// grows when the prompt is forked for `best_of` samples, see `fork_seq`.
+    pub fn get_seqs(&self) -> StateMap<SeqID, Arc<Sequence>> {
+        self.seqs().clone()
+    }
+
+    /// Add a copy of the sequence `parent_id` with id `new_seq_id` to this group.
+    pub fn fork_seq(&self, parent_id: SeqID, new_seq_id: SeqID) -> Option<Arc<Sequence>> {
+        let parent = self.seqs().get(&parent_id)?.clone();
+        let child = Arc::new(Sequence(Mutex::new(parent.deref_mut().fork(new_seq_id))));
+        self.seqs().insert(new_seq_id, child.clone());
+        Some(child)
+    }
+
+    /// Remove a sequence from this group, e.g. a fork which was rolled back.
+    pub fn remove_seq(&self, seq_id: SeqID) -> Option<Arc<Sequence>> {
+        self.seqs().remove(&seq_id)
     }
 
     pub fn arrival_time(&self) -> u64 {
@@ -283,7 +551,7 @@ impl SequenceGroup {
     }
 
     pub fn is_finished(&self) -> bool {
-        self.seqs.iter().all(|(_, x)| x.deref_mut().is_finished())
+        self.seqs().iter().all(|(_, x)| x.deref_mut().is_finished())
     }
 
     pub fn get_request_id(&self) -> &String {
@@ -293,4 +561,34 @@ impl SequenceGroup {
     pub fn get_created_time(&self) -> u64 {
         self.created
     }
+
+    /// The prefix cache namespace (tenant) of this group. Cached blocks are only shared within
+    /// a namespace.
+    pub fn get_cache_namespace(&self) -> Option<&str> {
+        self.cache_namespace.as_deref()
+    }
+
+    /// Whether the group reuses cached prefix blocks and shares its own blocks through the prefix
+    /// cache. Groups which opted out are also not kept around for their session. The blocks of
+    /// an encoder-decoder group depend on its encoder prompt, which the block hashes do not cover,
+    /// and the image tokens of all images share the same token id.
+    pub fn uses_prefix_cache(&self) -> bool {
+        self.prefix_caching && self.encoder_prompt.is_empty() && self.images.is_empty()
+    }
+
+    /// Whether the group may be decoded several tokens per step by a draft model, EAGLE or
+    /// Medusa heads or prompt lookup.
+    pub fn uses_speculation(&self) -> bool {
+        self.speculation
+    }
+
+    pub fn get_ttft_deadline(&self) -> Option<Instant> {
+        self.ttft_deadline
+    }
+
+    /// The session this group belongs to, if any. Finished groups of a session are kept around
+    /// for follow-up requests, see `Scheduler::park_session`.
+    pub fn get_session_id(&self) -> Option<&str> {
+        self.session_id.as_deref()
+    }
 }
@@ -0,0 +1,116 @@
+use candle_core::DType;
+
+use super::{
+    block_engine::AllocStatus,
+    sequence::{Sequence, SequenceGroup},
+    StateMap,
+};
+
+type SeqID = usize;
+
+/// The dimensions of the recurrent state of the state-space (Mamba) layers of a model. Each
+/// layer keeps a convolution state of the last `d_conv - 1` inputs and an SSM state of
+/// `d_inner x d_state` per sequence, independent of the sequence length.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SsmDims {
+    /// Number of state-space layers.
+    pub num_layers: usize,
+    pub d_inner: usize,
+    pub d_conv: usize,
+    pub d_state: usize,
+}
+
+impl SsmDims {
+    /// Shape of the convolution state of a layer with `num_slots` slots.
+    pub fn conv_state_shape(&self, num_slots: usize) -> (usize, usize, usize) {
+        (num_slots, self.d_inner, self.d_conv - 1)
+    }
+
+    /// Shape of the SSM state of a layer with `num_slots` slots. It is kept in f32.
+    pub fn ssm_state_shape(&self, num_slots: usize) -> (usize, usize, usize) {
+        (num_slots, self.d_inner, self.d_state)
+    }
+
+    /// Size in bytes of the state of one sequence, across all layers.
+    pub fn slot_size(&self, dtype: DType) -> usize {
+        let conv = self.d_inner * (self.d_conv - 1) * dtype.size_in_bytes();
+        let ssm = self.d_inner * self.d_state * DType::F32.size_in_bytes();
+        (conv + ssm) * self.num_layers
+    }
+}
+
+/// A StateCache maps each Sequence of a state-space model to a slot of the recurrent state
+/// tensors, in place of the block tables of the `BlockEngine`. A slot is held from the prompt to
+/// the end of the sequence and never grows, so the sequences need no new blocks while decoding.
+/// Hybrid models (e.g. Jamba) hold both a slot and blocks for their attention layers.
+#[derive(Clone)]
+pub struct StateCache {
+    num_slots: usize,
+    free_slots: Vec<usize>,
+    slots: StateMap<SeqID, usize>,
+    /// Whether the model also has attention layers, whose KV cache is managed by the
+    /// `BlockEngine`.
+    uses_kv_blocks: bool,
+}
+
+impl StateCache {
+    #[must_use]
+    pub fn new(num_slots: usize, uses_kv_blocks: bool) -> Self {
+        Self {
+            num_slots,
+            // Hand out the lowest slots first.
+            free_slots: (0..num_slots).rev().collect(),
+            slots: StateMap::default(),
+            uses_kv_blocks,
+        }
+    }
+
+    pub fn uses_kv_blocks(&self) -> bool {
+        self.uses_kv_blocks
+    }
+
+    pub fn get_num_free_slots(&self) -> usize {
+        self.free_slots.len()
+    }
+
+    pub fn can_allocate(&self, seq_group: &SequenceGroup) -> AllocStatus {
+        let num_required_slots = seq_group.get_seqs().len();
+        if num_required_slots > self.num_slots {
+            AllocStatus::Impossible
+        } else if num_required_slots > self.free_slots.len() {
+            AllocStatus::Later
+        } else {
+            AllocStatus::Ok
+        }
+    }
+
+    /// Give each sequence of the waiting `seq_group` a slot. The states of a new prompt start
+    /// from zero, see `StateMetadata::num_prompts`.
+    pub fn allocate(&mut self, seq_group: &SequenceGroup) {
+        for seq_id in seq_group.get_seqs().keys() {
+            let slot = self.free_slots.pop().expect("No free state slot.");
+            self.slots.insert(*seq_id, slot);
+        }
+    }
+
+    /// The state slot of the sequence, None if it has none (e.g. still waiting).
+    pub fn get_slot(&self, seq_id: SeqID) -> Option<usize> {
+        self.slots.get(&seq_id).copied()
+    }
+
+    /// Free the slot of `sequence`. Sequences without a slot are ignored.
+    pub fn free_sequence(&mut self, sequence: &Sequence) {
+        if let Some(slot) = self.slots.remove(&sequence.deref_mut().get_id()) {
+            self.free_slots.push(slot);
+        }
+    }
+
+    /// Give `child` a slot of its own. The state of `parent` must be copied into it, the returned
+    /// pair is (parent slot, child slot). None if there is no free slot.
+    pub fn fork(&mut self, parent: &Sequence, child: &Sequence) -> Option<(usize, usize)> {
+        let src = self.get_slot(parent.deref_mut().get_id())?;
+        let dst = self.free_slots.pop()?;
+        self.slots.insert(child.deref_mut().get_id(), dst);
+        Some((src, dst))
+    }
+}
@@ -0,0 +1,161 @@
+use std::sync::{Arc, Mutex};
+
+use candle_vllm::scheduler::{
+    block_engine::BlockEngine,
+    sequence::{_Sequence, Sequence, SequenceGroup},
+};
+
+const BLOCK_SIZE: usize = 4;
+
+/// A group of a single sequence with `prompt`, in the prefix cache namespace `namespace`.
+fn seq_group(
+    seq_id: usize,
+    prompt: Vec<usize>,
+    namespace: Option<&str>,
+) -> (Arc<Sequence>, SequenceGroup) {
+    let seq = Arc::new(Sequence(Mutex::new(_Sequence::new(
+        prompt, seq_id, BLOCK_SIZE,
+    ))));
+    let group = SequenceGroup::new(
+        &[seq.clone()],
+        0,
+        seq_id,
+        format!("request-{seq_id}"),
+        0,
+        namespace.map(str::to_string),
+        None,
+    );
+    (seq, group)
+}
+
+fn num_cached_blocks(engine: &BlockEngine, seq: &Sequence, namespace: Option<&str>) -> usize {
+    engine.get_num_cached_blocks(&seq.deref_mut(), namespace)
+}
+
+#[test]
+fn prefix_cache_quota_is_released_on_eviction() {
+    let mut engine = BlockEngine::new(BLOCK_SIZE, 3, 0, true, Some(2), None);
+
+    // Two full blocks and a partial one, caching the full blocks fills the quota.
+    let (first, group) = seq_group(0, (0..9).collect(), Some("tenant"));
+    engine.allocate(&group);
+    assert_eq!(num_cached_blocks(&engine, &first, Some("tenant")), 2);
+    engine.free_sequence(&first);
+
+    // A group outside of the prefix cache reallocates every block, evicting the cached ones.
+    let (evicting, group) = seq_group(1, (100..109).collect(), None);
+    let group = group.with_prefix_caching(false);
+    engine.allocate(&group);
+    assert_eq!(num_cached_blocks(&engine, &first, Some("tenant")), 0);
+    engine.free_sequence(&evicting);
+
+    // The evicted blocks no longer count towards the quota of the tenant.
+    let (second, group) = seq_group(2, (200..209).collect(), Some("tenant"));
+    engine.allocate(&group);
+    assert_eq!(num_cached_blocks(&engine, &second, Some("tenant")), 2);
+}
+
+/// Fork `parent` into a new sequence `child_id`, sharing its blocks.
+fn fork(engine: &mut BlockEngine, parent: &Sequence, child_id: usize) -> Arc<Sequence> {
+    let child = Arc::new(Sequence(Mutex::new(parent.deref_mut().fork(child_id))));
+    engine.fork(parent, &child);
+    child
+}
+
+fn block_ids(engine: &BlockEngine, seq_id: usize) -> Vec<usize> {
+    engine.block_tables[&seq_id]
+        .iter()
+        .map(|block| block.deref_mut().block_id)
+        .collect()
+}
+
+#[test]
+fn fork_shares_the_blocks_of_the_parent() {
+    let mut engine = BlockEngine::new(BLOCK_SIZE, 8, 0, false, None, None);
+    let (parent, group) = seq_group(0, (0..6).collect(), None);
+    engine.allocate(&group);
+    let child = fork(&mut engine, &parent, 1);
+
+    assert_eq!(block_ids(&engine, 0), block_ids(&engine, 1));
+    assert_eq!(engine.get_num_free_gpu_blocks(), 6);
+    // The child reads the prompt written by its parent and only computes the last token.
+    assert_eq!(engine.get_num_cached_prompt_tokens(1), 5);
+
+    // The shared blocks are freed once both sequences released them.
+    engine.free_sequence(&parent);
+    assert_eq!(engine.get_num_free_gpu_blocks(), 6);
+    engine.free_sequence(&child);
+    assert_eq!(engine.get_num_free_gpu_blocks(), 8);
+}
+
+#[test]
+fn appending_to_a_shared_last_block_copies_it() {
+    let mut engine = BlockEngine::new(BLOCK_SIZE, 8, 0, false, None, None);
+    // The last block holds two of four tokens.
+    let (parent, group) = seq_group(0, (0..6).collect(), None);
+    engine.allocate(&group);
+    let child = fork(&mut engine, &parent, 1);
+    let shared = block_ids(&engine, 0);
+
+    let (src, dst) = engine.append_token_slot_to_seq(&child).unwrap();
+    assert_eq!(src, shared[1]);
+    assert_eq!(block_ids(&engine, 1), vec![shared[0], dst]);
+    assert_eq!(engine.get_num_free_gpu_blocks(), 5);
+
+    // The parent is the last holder of its block, which is written in place.
+    assert_eq!(engine.append_token_slot_to_seq(&parent), None);
+    assert_eq!(block_ids(&engine, 0), shared);
+}
+
+#[test]
+fn freeing_a_fork_keeps_the_blocks_of_the_parent() {
+    let mut engine = BlockEngine::new(BLOCK_SIZE, 8, 0, false, None, None);
+    let (parent, group) = seq_group(0, (0..6).collect(), None);
+    engine.allocate(&group);
+    let child = fork(&mut engine, &parent, 1);
+    engine.append_token_slot_to_seq(&child).unwrap();
+
+    // Only the copied block was held by the child alone.
+    engine.free_sequence(&child);
+    assert_eq!(engine.get_num_free_gpu_blocks(), 6);
+    assert_eq!(engine.append_token_slot_to_seq(&parent), None);
+    engine.free_sequence(&parent);
+    assert_eq!(engine.get_num_free_gpu_blocks(), 8);
+}
+
+#[test]
+fn freed_prefix_is_taken_out_of_the_free_list() {
+    let mut engine = BlockEngine::new(BLOCK_SIZE, 8, 0, true, None, None);
+    let (first, group) = seq_group(0, (0..8).collect(), None);
+    engine.allocate(&group);
+    let cached = block_ids(&engine, 0);
+    let (other, group) = seq_group(1, (100..104).collect(), None);
+    engine.allocate(&group.with_prefix_caching(false));
+    let other_block = block_ids(&engine, 1);
+    // The block of `other` is freed last, so the cached blocks are below it in the free list.
+    engine.free_sequence(&first);
+    engine.free_sequence(&other);
+
+    let (second, group) = seq_group(2, (0..8).collect(), None);
+    engine.allocate(&group);
+    assert_eq!(block_ids(&engine, 2), cached);
+    assert_eq!(engine.get_num_cached_prompt_tokens(2), 7);
+    assert_eq!(engine.get_num_free_gpu_blocks(), 6);
+
+    // The free list stays consistent after taking blocks out of its middle.
+    let (_, group) = seq_group(3, (200..204).collect(), None);
+    engine.allocate(&group.with_prefix_caching(false));
+    assert_eq!(block_ids(&engine, 3), other_block);
+    engine.free_sequence(&second);
+    assert_eq!(engine.get_num_free_gpu_blocks(), 7);
+}
+
+#[test]
+fn prefix_cache_hits_require_the_same_namespace() {
+    let mut engine = BlockEngine::new(BLOCK_SIZE, 8, 0, true, None, None);
+    let (first, group) = seq_group(0, (0..8).collect(), Some("tenant"));
+    engine.allocate(&group);
+    assert_eq!(num_cached_blocks(&engine, &first, Some("tenant")), 2);
+    assert_eq!(num_cached_blocks(&engine, &first, Some("other")), 0);
+    assert_eq!(num_cached_blocks(&engine, &first, None), 0);
+}
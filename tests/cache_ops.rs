@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, Tensor};
+use candle_vllm::backend::cpu::{copy_blocks_cpu, reshape_and_cache_cpu, swap_blocks_cpu};
+
+/// A cache of `num_blocks` blocks of `block_numel` elements, each element holding
+/// `block * 100 + index` so that blocks can be told apart.
+fn numbered_cache(num_blocks: usize, block_numel: usize) -> Tensor {
+    let data = (0..num_blocks)
+        .flat_map(|block| (0..block_numel).map(move |i| (block * 100 + i) as f32))
+        .collect::<Vec<_>>();
+    Tensor::from_vec(data, (num_blocks, block_numel), &Device::Cpu).unwrap()
+}
+
+fn blocks(cache: &Tensor) -> Vec<Vec<f32>> {
+    cache.to_vec2::<f32>().unwrap()
+}
+
+#[test]
+fn copy_blocks_copies_each_source_to_its_destinations() {
+    let mut key_cache = numbered_cache(4, 3);
+    let mut value_cache = numbered_cache(4, 3);
+    let expected = blocks(&key_cache);
+
+    let mapping = HashMap::from([(1, vec![2, 3])]);
+    copy_blocks_cpu(vec![&mut key_cache], vec![&mut value_cache], mapping).unwrap();
+
+    for cache in [&key_cache, &value_cache] {
+        assert_eq!(
+            blocks(cache),
+            vec![
+                expected[0].clone(),
+                expected[1].clone(),
+                expected[1].clone(),
+                expected[1].clone(),
+            ]
+        );
+    }
+}
+
+#[test]
+fn swap_blocks_moves_blocks_to_their_new_ids() {
+    let src = numbered_cache(3, 2);
+    let mut dst = Tensor::zeros((3, 2), DType::F32, &Device::Cpu).unwrap();
+
+    let mapping = HashMap::from([(0, 2), (2, 1)]);
+    swap_blocks_cpu(&src, &mut dst, &mapping).unwrap();
+
+    assert_eq!(
+        blocks(&dst),
+        vec![vec![0., 0.], vec![200., 201.], vec![0., 1.]]
+    );
+}
+
+#[test]
+fn reshape_and_cache_writes_tokens_at_their_slots() {
+    // One head of size 4, blocks of 2 tokens and keys split into chunks of x = 2.
+    let key = Tensor::from_vec(
+        vec![1f32, 2., 3., 4., 5., 6., 7., 8.],
+        (2, 1, 4),
+        &Device::Cpu,
+    )
+    .unwrap();
+    let value = (&key * 10.).unwrap();
+    let mut key_cache = Tensor::zeros((2, 1, 2, 2, 2), DType::F32, &Device::Cpu).unwrap();
+    let mut value_cache = Tensor::zeros((2, 1, 4, 2), DType::F32, &Device::Cpu).unwrap();
+    // The first token goes to the second slot of block 1, the padded second token is skipped.
+    let slot_mapping = Tensor::new(&[3i64, -1], &Device::Cpu).unwrap();
+
+    reshape_and_cache_cpu(
+        &key,
+        &value,
+        &mut key_cache,
+        &mut value_cache,
+        &slot_mapping,
+    )
+    .unwrap();
+
+    let key_cache = key_cache.flatten_all().unwrap().to_vec1::<f32>().unwrap();
+    let value_cache = value_cache.flatten_all().unwrap().to_vec1::<f32>().unwrap();
+    assert_eq!(
+        key_cache,
+        [vec![0.; 8], vec![0., 0., 1., 2., 0., 0., 3., 4.]].concat()
+    );
+    assert_eq!(
+        value_cache,
+        [vec![0.; 8], vec![0., 10., 0., 20., 0., 30., 0., 40.]].concat()
+    );
+}
@@ -0,0 +1,107 @@
+use std::path::PathBuf;
+
+use candle_vllm::openai::conversation::chat_template::ChatTemplate;
+use serde_json::{json, Value};
+
+/// Write a `tokenizer_config.json` with `config` to a file of its own in the temp directory.
+fn tokenizer_config(name: &str, config: Value) -> PathBuf {
+    let filename = std::env::temp_dir().join(format!(
+        "candle-vllm-{}-{name}-tokenizer_config.json",
+        std::process::id()
+    ));
+    std::fs::write(&filename, config.to_string()).unwrap();
+    filename
+}
+
+const TEMPLATE: &str = "{{ bos_token }}{% for message in messages %}<|{{ message.role }}|>{{ message.content }}{{ eos_token }}{% endfor %}{% if add_generation_prompt %}<|assistant|>{% endif %}";
+
+#[test]
+fn renders_the_messages_with_the_special_tokens() {
+    let filename = tokenizer_config(
+        "single",
+        json!({
+            "chat_template": TEMPLATE,
+            "bos_token": "<s>",
+            "eos_token": { "content": "</s>", "lstrip": false },
+        }),
+    );
+    let template = ChatTemplate::from_tokenizer_config(&filename)
+        .unwrap()
+        .unwrap();
+    assert_eq!(template.eos_token(), Some("</s>"));
+    let messages = [
+        json!({ "role": "system", "content": "Be brief." }),
+        json!({ "role": "user", "content": "Hi" }),
+    ];
+    assert_eq!(
+        template.render(&messages, None, true).unwrap(),
+        "<s><|system|>Be brief.</s><|user|>Hi</s><|assistant|>"
+    );
+    assert_eq!(
+        template.render(&messages, None, false).unwrap(),
+        "<s><|system|>Be brief.</s><|user|>Hi</s>"
+    );
+}
+
+#[test]
+fn requests_with_tools_use_the_tool_use_template() {
+    let filename = tokenizer_config(
+        "named",
+        json!({
+            "chat_template": [
+                { "name": "default", "template": "default" },
+                { "name": "tool_use", "template": "{% for tool in tools %}{{ tool.name }} {% endfor %}" },
+            ],
+        }),
+    );
+    let template = ChatTemplate::from_tokenizer_config(&filename)
+        .unwrap()
+        .unwrap();
+    assert_eq!(template.eos_token(), None);
+    let tools = [json!({ "name": "search" }), json!({ "name": "fetch" })];
+    assert_eq!(
+        template.render(&[], Some(&tools), false).unwrap(),
+        "search fetch "
+    );
+    assert_eq!(template.render(&[], None, false).unwrap(), "default");
+}
+
+#[test]
+fn raise_exception_fails_the_rendering() {
+    let filename = tokenizer_config(
+        "raise",
+        json!({
+            "chat_template": "{% if messages[0].role != 'user' %}{{ raise_exception('Conversations must start with a user message.') }}{% endif %}ok",
+        }),
+    );
+    let template = ChatTemplate::from_tokenizer_config(&filename)
+        .unwrap()
+        .unwrap();
+    assert!(template
+        .render(
+            &[json!({ "role": "assistant", "content": "Hi" })],
+            None,
+            false
+        )
+        .is_err());
+    assert_eq!(
+        template
+            .render(&[json!({ "role": "user", "content": "Hi" })], None, false)
+            .unwrap(),
+        "ok"
+    );
+}
+
+#[test]
+fn configs_without_a_default_template_are_handled() {
+    let filename = tokenizer_config("none", json!({ "bos_token": "<s>" }));
+    assert!(ChatTemplate::from_tokenizer_config(&filename)
+        .unwrap()
+        .is_none());
+
+    let filename = tokenizer_config(
+        "unnamed",
+        json!({ "chat_template": [{ "name": "tool_use", "template": "" }] }),
+    );
+    assert!(ChatTemplate::from_tokenizer_config(&filename).is_err());
+}
@@ -0,0 +1,40 @@
+use std::collections::HashMap;
+
+use candle_vllm::openai::conversation_store::ConversationStore;
+
+fn message(role: &str, content: &str) -> HashMap<String, String> {
+    HashMap::from([
+        ("role".to_string(), role.to_string()),
+        ("content".to_string(), content.to_string()),
+    ])
+}
+
+#[test]
+fn conversations_are_scoped_to_their_cache_namespace() {
+    let store = ConversationStore::new(4);
+    let id = store
+        .create(Some("tenant-a"), vec![message("user", "Hi")])
+        .id;
+
+    assert!(store.get(Some("tenant-b"), &id).is_none());
+    assert!(store.get(None, &id).is_none());
+    assert!(!store.append(Some("tenant-b"), &id, vec![message("user", "Hello")]));
+    assert!(!store.remove(Some("tenant-b"), &id));
+
+    assert!(store.append(Some("tenant-a"), &id, vec![message("assistant", "Hello")]));
+    let conversation = store.get(Some("tenant-a"), &id).unwrap();
+    assert_eq!(
+        conversation.messages,
+        vec![message("user", "Hi"), message("assistant", "Hello")]
+    );
+    assert!(store.remove(Some("tenant-a"), &id));
+    assert!(store.get(Some("tenant-a"), &id).is_none());
+}
+
+#[test]
+fn conversations_without_an_api_key_are_only_found_without_one() {
+    let store = ConversationStore::new(4);
+    let id = store.create(None, Vec::new()).id;
+    assert!(store.get(Some("tenant-a"), &id).is_none());
+    assert!(store.get(None, &id).is_some());
+}
@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+
+use candle_vllm::openai::{IncrementalDetokenizer, TokenizerWrapper};
+use tokenizers::{
+    decoders::{
+        byte_fallback::ByteFallback, fuse::Fuse, sequence::Sequence as DecoderSequence,
+        strip::Strip,
+    },
+    models::bpe::BPE,
+    normalizers::Replace,
+    pre_tokenizers::byte_level::ByteLevel,
+    DecoderWrapper, Tokenizer,
+};
+
+/// A tokenizer of the `tokens`, as the pipelines expose it.
+fn tokenizer(
+    tokens: &[&str],
+    decoder: DecoderWrapper,
+) -> Box<dyn TokenizerWrapper<'static, String>> {
+    let vocab = tokens
+        .iter()
+        .enumerate()
+        .map(|(id, token)| (token.to_string(), id as u32))
+        .collect::<HashMap<_, _>>();
+    let bpe = BPE::builder()
+        .vocab_and_merges(vocab, Vec::new())
+        .build()
+        .unwrap();
+    let mut tokenizer = Tokenizer::new(bpe);
+    tokenizer.with_decoder(decoder);
+    Box::new(tokenizer)
+}
+
+#[test]
+fn byte_level_tokens_are_mapped_to_their_bytes() {
+    // The bytes E2 82 of the euro sign split from its last byte AC, and a space.
+    let tokenizer = tokenizer(
+        &["\u{e2}\u{124}", "\u{ac}", "\u{120}a"],
+        DecoderWrapper::ByteLevel(ByteLevel::default()),
+    );
+    assert_eq!(tokenizer.detokenize_bytes(&[0]).unwrap(), [0xE2, 0x82]);
+    assert_eq!(
+        tokenizer.detokenize_bytes(&[2, 0, 1]).unwrap(),
+        " a\u{20ac}".as_bytes()
+    );
+}
+
+#[test]
+fn sentencepiece_tokens_keep_their_fallback_bytes() {
+    let tokenizer = tokenizer(
+        &["\u{2581}hi", "<0xE2>", "<0x82>"],
+        DecoderWrapper::ByteFallback(ByteFallback::new()),
+    );
+    assert_eq!(
+        tokenizer.detokenize_bytes(&[0, 1, 2]).unwrap(),
+        [b' ', b'h', b'i', 0xE2, 0x82]
+    );
+}
+
+#[test]
+fn unknown_token_ids_are_rejected() {
+    let tokenizer = tokenizer(&["a"], DecoderWrapper::ByteLevel(ByteLevel::default()));
+    assert!(tokenizer.detokenize_bytes(&[1]).is_err());
+}
+
+#[test]
+fn incomplete_characters_are_held_back_until_completed() {
+    let tokenizer = tokenizer(
+        &["a", "\u{e2}\u{124}", "\u{ac}", "\u{120}b"],
+        DecoderWrapper::ByteLevel(ByteLevel::default()),
+    );
+    // The prompt is the first token.
+    let mut detokenizer = IncrementalDetokenizer::new(1);
+    let mut token_ids = vec![0];
+    let mut deltas = Vec::new();
+    for token_id in [1, 2, 3] {
+        token_ids.push(token_id);
+        let start = detokenizer.prefix_offset();
+        let delta = detokenizer
+            .decode_next(&*tokenizer, &token_ids[start..], false)
+            .unwrap();
+        deltas.push(delta.to_string());
+    }
+    assert_eq!(deltas, ["", "\u{20ac}", " b"]);
+    assert_eq!(detokenizer.text(), "\u{20ac} b");
+}
+
+#[test]
+fn an_incomplete_character_is_flushed_once_finished() {
+    let tokenizer = tokenizer(
+        &["a", "\u{e2}\u{124}"],
+        DecoderWrapper::ByteLevel(ByteLevel::default()),
+    );
+    let mut detokenizer = IncrementalDetokenizer::new(1);
+    assert_eq!(
+        detokenizer
+            .decode_next(&*tokenizer, &[0, 1], false)
+            .unwrap(),
+        ""
+    );
+    assert_eq!(
+        detokenizer.decode_next(&*tokenizer, &[0, 1], true).unwrap(),
+        "\u{fffd}"
+    );
+}
+
+#[test]
+fn tokens_are_decoded_in_the_context_of_the_previous_tokens() {
+    // SentencePiece strips the leading space of the decoded text.
+    let tokenizer = tokenizer(
+        &["\u{2581}a", "\u{2581}b", "\u{2581}c"],
+        DecoderWrapper::Sequence(DecoderSequence::new(vec![
+            DecoderWrapper::Replace(Replace::new("\u{2581}", " ").unwrap()),
+            DecoderWrapper::Fuse(Fuse::new()),
+            DecoderWrapper::Strip(Strip::new(' ', 1, 0)),
+        ])),
+    );
+    let mut detokenizer = IncrementalDetokenizer::new(1);
+    assert_eq!(
+        detokenizer
+            .decode_next(&*tokenizer, &[0, 1], false)
+            .unwrap(),
+        " b"
+    );
+    // Only the tokens from the prefix offset on are needed.
+    assert_eq!(detokenizer.prefix_offset(), 1);
+    assert_eq!(
+        detokenizer.decode_next(&*tokenizer, &[1, 2], true).unwrap(),
+        " c"
+    );
+    assert_eq!(detokenizer.text(), " b c");
+}
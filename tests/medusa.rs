@@ -0,0 +1,34 @@
+use candle_vllm::{backend::MAX_TREE_QUERY_TOKENS, openai::pipelines::medusa::CandidateTree};
+
+#[test]
+fn the_tree_holds_the_most_likely_paths() {
+    // The paths by rank: [], [0], [0, 0], [1], [0, 1].
+    let tree = CandidateTree::new(2, 4);
+    assert_eq!(tree.masks().len(), 5);
+    assert_eq!(
+        (0..5).map(|node| tree.depth(node)).collect::<Vec<_>>(),
+        vec![0, 1, 2, 1, 2]
+    );
+    assert_eq!(tree.max_depth(), 2);
+    // Each node attends to itself and its ancestors.
+    assert_eq!(tree.masks(), [0b1, 0b11, 0b111, 0b1001, 0b10011]);
+    assert_eq!(
+        tree.tokens(100, &[vec![10, 11], vec![20, 21]]),
+        vec![100, 10, 20, 11, 21]
+    );
+}
+
+#[test]
+fn a_single_head_proposes_its_candidates_by_rank() {
+    let tree = CandidateTree::new(1, 3);
+    assert_eq!(tree.max_depth(), 1);
+    assert_eq!(tree.masks(), [0b1, 0b11, 0b101, 0b1001]);
+    assert_eq!(tree.tokens(7, &[vec![1, 2, 3]]), vec![7, 1, 2, 3]);
+}
+
+#[test]
+fn the_tree_fits_the_tree_attention() {
+    let tree = CandidateTree::new(4, 1000);
+    assert_eq!(tree.masks().len(), MAX_TREE_QUERY_TOKENS);
+    assert!(tree.max_depth() <= 4);
+}
@@ -0,0 +1,120 @@
+use std::{sync::Arc, time::Duration};
+
+use candle_vllm::openai::placement::{
+    PlacementStrategy, ReplicaCounters, ReplicaLoad, ReplicaPlacer,
+};
+
+fn load(outstanding_tokens: usize, free_gpu_blocks: usize) -> ReplicaLoad {
+    ReplicaLoad {
+        outstanding_tokens,
+        free_gpu_blocks,
+        cached_prefix_blocks: 0,
+        saturated: false,
+        estimated_prefill_wait: None,
+    }
+}
+
+fn saturated(load: ReplicaLoad) -> ReplicaLoad {
+    ReplicaLoad {
+        saturated: true,
+        ..load
+    }
+}
+
+#[test]
+fn round_robin_skips_saturated_replicas() {
+    let mut placer = ReplicaPlacer::new(PlacementStrategy::RoundRobin);
+    let loads = [load(0, 8), saturated(load(0, 8)), load(0, 8)];
+    let placed = (0..4).map(|_| placer.place(&loads)).collect::<Vec<_>>();
+    assert_eq!(placed, vec![0, 2, 0, 2]);
+}
+
+#[test]
+fn least_outstanding_tokens_picks_the_least_loaded_replica() {
+    let mut placer = ReplicaPlacer::new(PlacementStrategy::LeastOutstandingTokens);
+    assert_eq!(placer.place(&[load(30, 8), load(10, 8), load(20, 8)]), 1);
+    // A saturated replica is avoided even if it has the fewest outstanding tokens.
+    assert_eq!(
+        placer.place(&[load(30, 8), saturated(load(10, 8)), load(20, 8)]),
+        2
+    );
+}
+
+#[test]
+fn prefix_affinity_prefers_cached_prefixes_then_load() {
+    let mut placer = ReplicaPlacer::new(PlacementStrategy::PrefixAffinity);
+    let cached = |outstanding_tokens, cached_prefix_blocks| ReplicaLoad {
+        cached_prefix_blocks,
+        ..load(outstanding_tokens, 8)
+    };
+    assert_eq!(
+        placer.place(&[cached(0, 1), cached(50, 3), cached(0, 2)]),
+        1
+    );
+    assert_eq!(
+        placer.place(&[cached(40, 2), cached(10, 2), cached(0, 0)]),
+        1
+    );
+}
+
+#[test]
+fn weighted_free_blocks_places_in_proportion_to_free_blocks() {
+    let mut placer = ReplicaPlacer::new(PlacementStrategy::WeightedFreeBlocks);
+    let loads = [load(0, 30), load(0, 10)];
+    let placed = (0..8).map(|_| placer.place(&loads)).collect::<Vec<_>>();
+    assert_eq!(placed.iter().filter(|idx| **idx == 0).count(), 6);
+    assert_eq!(placed.iter().filter(|idx| **idx == 1).count(), 2);
+}
+
+#[test]
+fn all_saturated_replicas_fall_back_to_the_least_loaded() {
+    for strategy in [
+        PlacementStrategy::RoundRobin,
+        PlacementStrategy::LeastOutstandingTokens,
+        PlacementStrategy::PrefixAffinity,
+        PlacementStrategy::WeightedFreeBlocks,
+    ] {
+        let mut placer = ReplicaPlacer::new(strategy);
+        let loads = [
+            saturated(load(30, 0)),
+            saturated(load(5, 0)),
+            saturated(load(20, 0)),
+        ];
+        assert_eq!(placer.place(&loads), 1);
+    }
+}
+
+#[test]
+fn admitted_requests_count_until_dropped() {
+    let counters = Arc::new(ReplicaCounters::default());
+    let first = counters.admit(100);
+    let second = counters.admit(20);
+    assert_eq!(counters.get_outstanding_tokens(), 120);
+    drop(first);
+    assert_eq!(counters.get_outstanding_tokens(), 20);
+    drop(second);
+    assert_eq!(counters.get_outstanding_tokens(), 0);
+}
+
+#[test]
+fn load_is_derived_from_the_published_engine_state() {
+    let counters = Arc::new(ReplicaCounters::default());
+    counters.publish(4, 16, None);
+    let _admitted = counters.admit(100);
+
+    let load = counters.get_load(64, 2);
+    assert_eq!(load.outstanding_tokens, 100);
+    assert_eq!(load.free_gpu_blocks, 4);
+    assert_eq!(load.cached_prefix_blocks, 2);
+    assert!(!load.saturated);
+    assert_eq!(load.estimated_prefill_wait, None);
+    // The prompt needs 5 blocks of 16 tokens.
+    assert!(counters.get_load(65, 0).saturated);
+
+    // The prompt is prefilled after the outstanding tokens.
+    counters.publish(4, 16, Some(1000.));
+    assert_eq!(
+        counters.get_load(100, 0).estimated_prefill_wait,
+        Some(Duration::from_millis(200))
+    );
+}
@@ -0,0 +1,46 @@
+use candle_vllm::openai::pipelines::prompt_lookup::PromptLookup;
+
+#[test]
+fn invalid_settings_are_rejected() {
+    assert!(PromptLookup::new(0, 1, 3).is_err());
+    assert!(PromptLookup::new(4, 0, 3).is_err());
+    assert!(PromptLookup::new(4, 3, 2).is_err());
+    assert!(PromptLookup::new(4, 2, 2).is_ok());
+}
+
+#[test]
+fn proposes_the_tokens_after_the_latest_match() {
+    let lookup = PromptLookup::new(2, 1, 3).unwrap();
+    // The bigram `1 2` occurs twice before the end, the latest occurrence is followed by `5 6`.
+    assert_eq!(lookup.propose(&[1, 2, 3, 4, 1, 2, 5, 6, 1, 2]), vec![5, 6]);
+    assert_eq!(lookup.propose(&[7, 1, 2, 9, 1, 2]), vec![9, 1]);
+    // The proposal is cut off at the end of the sequence.
+    let lookup = PromptLookup::new(3, 1, 3).unwrap();
+    assert_eq!(lookup.propose(&[5, 7, 8, 7]), vec![8, 7]);
+}
+
+#[test]
+fn prefers_the_longest_matching_ngram() {
+    let lookup = PromptLookup::new(1, 1, 3).unwrap();
+    // The unigram `3` last occurs before `4`, but the trigram `1 2 3` only before `5`.
+    assert_eq!(lookup.propose(&[1, 2, 3, 5, 3, 4, 1, 2, 3]), vec![5]);
+}
+
+#[test]
+fn proposes_nothing_without_a_match() {
+    let lookup = PromptLookup::new(4, 2, 3).unwrap();
+    assert!(lookup.propose(&[]).is_empty());
+    assert!(lookup.propose(&[1]).is_empty());
+    // Only n-grams of at least two tokens are matched.
+    assert!(lookup.propose(&[1, 2, 3, 1]).is_empty());
+}
+
+#[test]
+fn batch_proposals_are_padded_to_the_longest() {
+    let lookup = PromptLookup::new(3, 1, 1).unwrap();
+    let proposals = lookup
+        .propose_batch(&[vec![1, 2, 3, 4, 1], vec![5, 6, 5], vec![7, 8]])
+        .unwrap();
+    assert_eq!(proposals, vec![vec![2, 3, 4], vec![6, 5, 5], vec![8, 8, 8]]);
+    assert_eq!(lookup.propose_batch(&[vec![1, 2], vec![3]]), None);
+}
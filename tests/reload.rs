@@ -0,0 +1,49 @@
+use std::sync::Arc;
+
+use candle_vllm::openai::{
+    requests::ReloadRequest, responses::APIError, LoadEngines, ModelReloader,
+};
+
+fn reloader(allowed: &[&str]) -> ModelReloader<'static> {
+    let load: Arc<LoadEngines<'static>> =
+        Arc::new(|_: &ReloadRequest| Err(APIError::new_str("Not loaded in tests.")));
+    ModelReloader::new(
+        load,
+        "secret".to_string(),
+        allowed.iter().map(|id| id.to_string()).collect(),
+    )
+}
+
+fn request(model: Option<&str>, lora: Option<&str>) -> ReloadRequest {
+    ReloadRequest {
+        model: model.map(str::to_string),
+        revision: None,
+        lora: lora.map(str::to_string),
+        disable_lora: false,
+    }
+}
+
+#[test]
+fn only_the_admin_key_is_authorized() {
+    let reloader = reloader(&[]);
+    assert!(reloader.authorize(Some("secret")));
+    assert!(!reloader.authorize(Some("secre")));
+    assert!(!reloader.authorize(Some("secret2")));
+    assert!(!reloader.authorize(Some("Secret")));
+    assert!(!reloader.authorize(None));
+}
+
+#[test]
+fn only_allowlisted_models_and_adapters_are_loaded() {
+    let reloader = reloader(&["org/model", "org/adapter"]);
+    assert!(reloader.check_allowed(&request(None, None)).is_ok());
+    assert!(reloader
+        .check_allowed(&request(Some("org/model"), Some("org/adapter")))
+        .is_ok());
+    assert!(reloader
+        .check_allowed(&request(Some("other/model"), None))
+        .is_err());
+    assert!(reloader
+        .check_allowed(&request(Some("org/model"), Some("other/adapter")))
+        .is_err());
+}
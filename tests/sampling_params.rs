@@ -0,0 +1,136 @@
+use candle_vllm::openai::sampling_params::{
+    GenerationConfig, SamplingOptions, SamplingParamsError,
+};
+
+const MAX_MODEL_LEN: usize = 4096;
+
+/// The defaults of the generation config of Qwen2, which set both top_k and top_p.
+fn qwen2_generation_config() -> GenerationConfig {
+    GenerationConfig {
+        temperature: Some(0.7),
+        top_p: Some(0.8),
+        top_k: Some(20),
+        repetition_penalty: Some(1.05),
+        max_new_tokens: None,
+    }
+}
+
+#[test]
+fn conflicting_generation_config_defaults_keep_top_p() {
+    let params = SamplingOptions::default()
+        .normalize(&qwen2_generation_config(), 10, MAX_MODEL_LEN)
+        .unwrap();
+    assert_eq!(params.top_p, 0.8);
+    assert_eq!(params.top_k, -1);
+}
+
+#[test]
+fn request_top_k_overrides_generation_config_top_p() {
+    let options = SamplingOptions {
+        top_k: Some(5),
+        ..Default::default()
+    };
+    let params = options
+        .normalize(&qwen2_generation_config(), 10, MAX_MODEL_LEN)
+        .unwrap();
+    assert_eq!(params.top_k, 5);
+    assert_eq!(params.top_p, 1.);
+}
+
+#[test]
+fn request_top_k_with_top_p_is_rejected() {
+    let options = SamplingOptions {
+        top_k: Some(5),
+        top_p: Some(0.9),
+        ..Default::default()
+    };
+    assert_eq!(
+        options
+            .normalize(&GenerationConfig::default(), 10, MAX_MODEL_LEN)
+            .unwrap_err(),
+        SamplingParamsError::TopKWithTopP {
+            top_k: 5,
+            top_p: 0.9
+        }
+    );
+}
+
+#[test]
+fn request_top_p_overrides_generation_config_top_k() {
+    let options = SamplingOptions {
+        top_p: Some(0.5),
+        ..Default::default()
+    };
+    let params = options
+        .normalize(&qwen2_generation_config(), 10, MAX_MODEL_LEN)
+        .unwrap();
+    assert_eq!(params.top_p, 0.5);
+    assert_eq!(params.top_k, -1);
+}
+
+#[test]
+fn zero_top_k_is_disabled() {
+    let options = SamplingOptions {
+        top_k: Some(0),
+        top_p: Some(0.9),
+        ..Default::default()
+    };
+    let params = options
+        .normalize(&GenerationConfig::default(), 10, MAX_MODEL_LEN)
+        .unwrap();
+    assert_eq!(params.top_k, -1);
+    assert_eq!(params.top_p, 0.9);
+}
+
+#[test]
+fn greedy_sampling_resets_top_k_and_top_p() {
+    let options = SamplingOptions {
+        temperature: Some(0.),
+        ..Default::default()
+    };
+    let params = options
+        .normalize(&qwen2_generation_config(), 10, MAX_MODEL_LEN)
+        .unwrap();
+    assert_eq!(params.temperature, 0.);
+    assert_eq!(params.top_p, 1.);
+    assert_eq!(params.top_k, -1);
+}
+
+#[test]
+fn max_tokens_defaults_to_the_remaining_context() {
+    let params = SamplingOptions::default()
+        .normalize(&GenerationConfig::default(), 96, MAX_MODEL_LEN)
+        .unwrap();
+    assert_eq!(params.max_tokens, MAX_MODEL_LEN - 96);
+
+    let generation_config = GenerationConfig {
+        max_new_tokens: Some(256),
+        ..Default::default()
+    };
+    let params = SamplingOptions::default()
+        .normalize(&generation_config, 96, MAX_MODEL_LEN)
+        .unwrap();
+    assert_eq!(params.max_tokens, 256);
+    let params = SamplingOptions::default()
+        .normalize(&generation_config, MAX_MODEL_LEN - 16, MAX_MODEL_LEN)
+        .unwrap();
+    assert_eq!(params.max_tokens, 16);
+}
+
+#[test]
+fn max_tokens_exceeding_the_context_is_rejected() {
+    let options = SamplingOptions {
+        max_tokens: Some(MAX_MODEL_LEN),
+        ..Default::default()
+    };
+    assert_eq!(
+        options
+            .normalize(&GenerationConfig::default(), 10, MAX_MODEL_LEN)
+            .unwrap_err(),
+        SamplingParamsError::ContextLengthExceeded {
+            max_model_len: MAX_MODEL_LEN,
+            prompt_len: 10,
+            max_tokens: MAX_MODEL_LEN
+        }
+    );
+}
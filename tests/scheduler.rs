@@ -0,0 +1,394 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use candle_vllm::scheduler::{
+    cache_engine::{CacheConfig, KVCacheDtype, KVCacheLayout},
+    sequence::{_Sequence, CancellationReason, Sequence, SequenceGroup, SequenceStatus},
+    Scheduler, SchedulerConfig, SchedulingPolicy,
+};
+
+const BLOCK_SIZE: usize = 4;
+const NUM_GPU_BLOCKS: usize = 8;
+
+fn config() -> SchedulerConfig {
+    SchedulerConfig {
+        max_num_seqs: 16,
+        policy: SchedulingPolicy::Fcfs,
+        session_ttl: None,
+        max_num_batched_tokens: 256,
+        max_prefill_fraction: None,
+        max_num_prefill_tokens: None,
+    }
+}
+
+fn scheduler_with(config: SchedulerConfig) -> Scheduler {
+    Scheduler::new(
+        config,
+        &CacheConfig {
+            block_size: BLOCK_SIZE,
+            num_gpu_blocks: Some(NUM_GPU_BLOCKS),
+            num_cpu_blocks: Some(NUM_GPU_BLOCKS),
+            fully_init: true,
+            swap_space_bytes: 0,
+            enable_prefix_caching: true,
+            prefix_cache_quota: None,
+            cache_dtype: KVCacheDtype::Auto,
+            cache_layout: KVCacheLayout::Split,
+            attention_sinks: None,
+        },
+    )
+}
+
+fn scheduler(session_ttl: Option<Duration>) -> Scheduler {
+    scheduler_with(SchedulerConfig {
+        session_ttl,
+        ..config()
+    })
+}
+
+/// A group of a single sequence with `prompt`, in the session `session_id` of `cache_namespace`.
+fn seq_group(
+    seq_id: usize,
+    prompt: Vec<usize>,
+    cache_namespace: Option<&str>,
+    session_id: Option<&str>,
+) -> (Arc<Sequence>, SequenceGroup) {
+    let seq = Arc::new(Sequence(Mutex::new(_Sequence::new(
+        prompt, seq_id, BLOCK_SIZE,
+    ))));
+    let group = SequenceGroup::new(
+        &[seq.clone()],
+        seq_id as u64,
+        seq_id,
+        format!("request-{seq_id}"),
+        0,
+        cache_namespace.map(str::to_string),
+        session_id.map(str::to_string),
+    );
+    (seq, group)
+}
+
+/// Run the prompt of a group of `session_id` in `cache_namespace` and finish it, parking it for
+/// the session.
+fn park_session(
+    scheduler: &mut Scheduler,
+    seq_id: usize,
+    cache_namespace: Option<&str>,
+    session_id: &str,
+) -> Arc<Sequence> {
+    let (seq, group) = seq_group(seq_id, (0..10).collect(), cache_namespace, Some(session_id));
+    scheduler.add_sequence(group);
+    let output = scheduler.schedule();
+    assert_eq!(output.scheduled.len(), 1);
+    scheduler.commit();
+    seq.deref_mut().set_finish_reason("stop".to_string());
+    scheduler.free_finished_sequence_groups();
+    seq
+}
+
+#[test]
+fn migrated_session_moves_its_blocks_to_the_destination() {
+    let ttl = Some(Duration::from_secs(60));
+    let (mut src, mut dst) = (scheduler(ttl), scheduler(ttl));
+    let seq = park_session(&mut src, 0, None, "session");
+    assert_eq!(
+        src.block_engine.get_num_free_gpu_blocks(),
+        NUM_GPU_BLOCKS - 3
+    );
+
+    let mut copied = HashMap::new();
+    let migrated = src.migrate_session(&mut dst, None, "session", |src_to_dst| {
+        copied = src_to_dst;
+        Ok::<_, ()>(())
+    });
+    assert_eq!(migrated, Ok(true));
+    assert_eq!(copied.len(), 3);
+    assert_eq!(src.block_engine.get_num_free_gpu_blocks(), NUM_GPU_BLOCKS);
+    assert_eq!(
+        dst.block_engine.get_num_free_gpu_blocks(),
+        NUM_GPU_BLOCKS - 3
+    );
+    assert!(!src.resume_session(None, "session"));
+    assert!(dst.resume_session(None, "session"));
+
+    // A follow-up request on the destination reuses the full blocks through the prefix cache.
+    assert_eq!(
+        dst.block_engine
+            .get_num_cached_blocks(&seq.deref_mut(), None),
+        2
+    );
+}
+
+#[test]
+fn failed_copy_drops_the_migrated_session() {
+    let ttl = Some(Duration::from_secs(60));
+    let (mut src, mut dst) = (scheduler(ttl), scheduler(ttl));
+    let seq = park_session(&mut src, 0, None, "session");
+
+    let migrated = src.migrate_session(&mut dst, None, "session", |_| Err("copy failed"));
+    assert_eq!(migrated, Err("copy failed"));
+    assert!(!dst.resume_session(None, "session"));
+    assert_eq!(dst.block_engine.get_num_free_gpu_blocks(), NUM_GPU_BLOCKS);
+    // The blocks were never written, so they must not be served from the prefix cache.
+    assert_eq!(
+        dst.block_engine
+            .get_num_cached_blocks(&seq.deref_mut(), None),
+        0
+    );
+}
+
+#[test]
+fn session_is_not_migrated_during_an_uncommitted_step() {
+    let ttl = Some(Duration::from_secs(60));
+    let (mut src, mut dst) = (scheduler(ttl), scheduler(ttl));
+    park_session(&mut src, 0, None, "session");
+    let (_, group) = seq_group(1, (100..104).collect(), None, None);
+    src.add_sequence(group);
+    let output = src.schedule();
+
+    // Rolling the step back would restore the blocks of the session on the source.
+    let migrated = src.migrate_session(&mut dst, None, "session", |_| Ok::<_, ()>(()));
+    assert_eq!(migrated, Ok(false));
+    src.rollback(&output.scheduled);
+    assert!(src.resume_session(None, "session"));
+    assert!(!dst.resume_session(None, "session"));
+
+    src.schedule();
+    src.commit();
+    let migrated = src.migrate_session(&mut dst, None, "session", |_| Ok::<_, ()>(()));
+    assert_eq!(migrated, Ok(true));
+}
+
+#[test]
+fn older_session_does_not_replace_a_more_recent_one() {
+    let ttl = Some(Duration::from_secs(60));
+    let (mut src, mut dst) = (scheduler(ttl), scheduler(ttl));
+    park_session(&mut src, 0, None, "session");
+    park_session(&mut dst, 1, None, "session");
+
+    let migrated = src.migrate_session(&mut dst, None, "session", |_| Ok::<_, ()>(()));
+    assert_eq!(migrated, Ok(false));
+    assert!(src.resume_session(None, "session"));
+}
+
+#[test]
+fn sessions_are_scoped_to_their_cache_namespace() {
+    let ttl = Some(Duration::from_secs(60));
+    let (mut src, mut dst) = (scheduler(ttl), scheduler(ttl));
+    park_session(&mut src, 0, Some("tenant-a"), "session");
+
+    assert!(!src.resume_session(None, "session"));
+    assert!(!src.resume_session(Some("tenant-b"), "session"));
+    let migrated = src.migrate_session(&mut dst, Some("tenant-b"), "session", |_| Ok::<_, ()>(()));
+    assert_eq!(migrated, Ok(false));
+
+    // The same session id in another namespace is parked separately.
+    park_session(&mut src, 1, Some("tenant-b"), "session");
+    assert!(src.resume_session(Some("tenant-a"), "session"));
+    assert!(src.resume_session(Some("tenant-b"), "session"));
+    assert_eq!(
+        src.block_engine.get_num_free_gpu_blocks(),
+        NUM_GPU_BLOCKS - 6
+    );
+}
+
+fn ids(groups: &VecDeque<Arc<SequenceGroup>>) -> Vec<usize> {
+    groups.iter().map(|group| *group.get_id()).collect()
+}
+
+#[test]
+fn waiting_groups_are_prefilled_by_earliest_ttft_deadline() {
+    let mut scheduler = scheduler(None);
+    let now = Instant::now();
+    let (_, group) = seq_group(0, (0..3).collect(), None, None);
+    scheduler.add_sequence(group);
+    for (seq_id, deadline) in [(1, 20), (2, 10)] {
+        let (_, group) = seq_group(
+            seq_id,
+            (seq_id * 100..seq_id * 100 + 3).collect(),
+            None,
+            None,
+        );
+        scheduler.add_sequence(group.with_ttft_deadline(Some(now + Duration::from_secs(deadline))));
+    }
+
+    let output = scheduler.schedule();
+    assert_eq!(ids(&output.scheduled), vec![2, 1, 0]);
+}
+
+#[test]
+fn recomputed_group_is_prefilled_before_deadline_groups() {
+    let mut scheduler = scheduler(None);
+    let mut seqs = Vec::new();
+    // Two prompts of full blocks take all the blocks, so decoding preempts one of them.
+    for seq_id in 0..2 {
+        let (seq, group) = seq_group(
+            seq_id,
+            (seq_id * 100..seq_id * 100 + 16).collect(),
+            None,
+            None,
+        );
+        scheduler.add_sequence(group);
+        seqs.push(seq);
+    }
+    assert_eq!(scheduler.schedule().scheduled.len(), 2);
+    scheduler.commit();
+    let output = scheduler.schedule();
+    assert_eq!(ids(&output.scheduled), vec![1]);
+    scheduler.commit();
+    seqs[1].deref_mut().set_finish_reason("stop".to_string());
+    scheduler.free_finished_sequence_groups();
+
+    let (_, group) = seq_group(2, (200..203).collect(), None, None);
+    let deadline = Instant::now() + Duration::from_secs(60);
+    scheduler.add_sequence(group.with_ttft_deadline(Some(deadline)));
+    let output = scheduler.schedule();
+    assert_eq!(ids(&output.scheduled), vec![0, 2]);
+}
+
+#[test]
+fn groups_which_cannot_meet_their_ttft_deadline_are_shed() {
+    let mut scheduler = scheduler(None);
+    scheduler.record_prefill_throughput(100, Duration::from_secs(1));
+    let now = Instant::now();
+    // 20 tokens take 200ms to prefill.
+    let (missed, group) = seq_group(0, (0..20).collect(), None, None);
+    scheduler.add_sequence(group.with_ttft_deadline(Some(now + Duration::from_millis(50))));
+    let (_, group) = seq_group(1, (100..104).collect(), None, None);
+    scheduler.add_sequence(group.with_ttft_deadline(Some(now + Duration::from_secs(10))));
+
+    let output = scheduler.schedule();
+    assert_eq!(ids(&output.scheduled), vec![1]);
+    let shed = scheduler.take_slo_missed();
+    assert_eq!(
+        shed.iter().map(|group| *group.get_id()).collect::<Vec<_>>(),
+        vec![0]
+    );
+    assert_eq!(
+        missed.deref_mut().get_cancellation_reason(),
+        Some(CancellationReason::DeadlineMissed)
+    );
+}
+
+#[test]
+fn prefill_steps_are_limited_to_the_prefill_fraction() {
+    let mut scheduler = scheduler_with(SchedulerConfig {
+        max_prefill_fraction: Some(0.5),
+        ..config()
+    });
+    let (_, group) = seq_group(0, (0..3).collect(), None, None);
+    scheduler.add_sequence(group);
+    assert_eq!(scheduler.schedule().num_prefill_tokens, 3);
+    scheduler.commit();
+
+    let (_, group) = seq_group(1, (100..103).collect(), None, None);
+    scheduler.add_sequence(group);
+    // Half of the recent steps were prefill steps, so the running group is decoded.
+    for _ in 0..2 {
+        let output = scheduler.schedule();
+        assert_eq!(ids(&output.scheduled), vec![0]);
+        assert_eq!(output.num_prefill_tokens, 0);
+        scheduler.commit();
+    }
+    let output = scheduler.schedule();
+    assert_eq!(ids(&output.scheduled), vec![1]);
+    assert_eq!(output.num_prefill_tokens, 3);
+}
+
+#[test]
+fn prefill_steps_are_limited_to_the_prefill_token_budget() {
+    let mut scheduler = scheduler_with(SchedulerConfig {
+        max_num_prefill_tokens: Some(8),
+        ..config()
+    });
+    for seq_id in 0..3 {
+        let (_, group) = seq_group(
+            seq_id,
+            (seq_id * 100..seq_id * 100 + 3).collect(),
+            None,
+            None,
+        );
+        scheduler.add_sequence(group);
+    }
+    let (_, group) = seq_group(3, (300..312).collect(), None, None);
+    scheduler.add_sequence(group);
+
+    let mut steps = Vec::new();
+    for _ in 0..3 {
+        let output = scheduler.schedule();
+        steps.push((ids(&output.scheduled), output.num_prefill_tokens));
+        scheduler.commit();
+    }
+    // A prompt exceeding the budget is prefilled on its own.
+    assert_eq!(steps, vec![(vec![0, 1], 6), (vec![2], 3), (vec![3], 12)]);
+}
+
+#[test]
+fn prompts_exceeding_the_cache_are_ignored_alongside_scheduled_ones() {
+    let mut scheduler = scheduler(None);
+    let (_, group) = seq_group(0, (0..3).collect(), None, None);
+    scheduler.add_sequence(group);
+    let (oversized, group) = seq_group(
+        1,
+        (100..100 + BLOCK_SIZE * NUM_GPU_BLOCKS + 1).collect(),
+        None,
+        None,
+    );
+    scheduler.add_sequence(group);
+    let (_, group) = seq_group(2, (200..203).collect(), None, None);
+    scheduler.add_sequence(group);
+
+    let output = scheduler.schedule();
+    assert_eq!(ids(&output.scheduled), vec![0, 2]);
+    assert_eq!(ids(&output.ignored_seq_groups), vec![1]);
+    assert!(matches!(
+        oversized.deref_mut().get_status(),
+        SequenceStatus::FinishedIgnored
+    ));
+    assert!(output.ignored_seq_groups[0].is_finished());
+}
+
+#[test]
+fn opted_out_groups_decode_alongside_speculative_ones() {
+    let mut scheduler = scheduler(None);
+    scheduler.set_num_lookahead_slots(2);
+    let (_, group) = seq_group(0, (0..3).collect(), None, None);
+    scheduler.add_sequence(group.with_speculation(false));
+    let (_, group) = seq_group(1, (100..103).collect(), None, None);
+    scheduler.add_sequence(group);
+
+    // A prefill step never speculates.
+    let output = scheduler.schedule();
+    assert_eq!(output.speculation_mask(&[0, 1]), None);
+    scheduler.commit();
+
+    // The opted-out group is decoded in the same step as the speculative one, which still
+    // speculates.
+    let output = scheduler.schedule();
+    assert_eq!(ids(&output.scheduled), vec![0, 1]);
+    assert_eq!(output.speculation_mask(&[0, 1]), Some(vec![false, true]));
+    assert_eq!(output.speculation_mask(&[1, 0]), Some(vec![true, false]));
+}
+
+#[test]
+fn steps_of_opted_out_groups_only_do_not_speculate() {
+    let mut scheduler = scheduler(None);
+    for seq_id in 0..2 {
+        let (_, group) = seq_group(
+            seq_id,
+            (seq_id * 100..seq_id * 100 + 3).collect(),
+            None,
+            None,
+        );
+        scheduler.add_sequence(group.with_speculation(false));
+    }
+    scheduler.schedule();
+    scheduler.commit();
+
+    let output = scheduler.schedule();
+    assert_eq!(output.scheduled.len(), 2);
+    assert_eq!(output.speculation_mask(&[0, 1]), None);
+}
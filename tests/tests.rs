@@ -1,6 +1,6 @@
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, RwLock},
 };
 
 use actix_web::{http::header::ContentType, test, web::Data, App};
@@ -8,10 +8,19 @@ use candle_core::{DType, Device};
 use candle_vllm::{
     get_model_loader,
     openai::{
-        self, openai_server::chat_completions, pipelines::llm_engine::LLMEngine,
-        requests::Messages, responses::APIError, OpenAIServerData,
+        self,
+        openai_server::chat_completions,
+        pipelines::{llm_engine::LLMEngine, LoadOptions},
+        placement::{PlacementStrategy, ReplicaPlacer},
+        requests::Messages,
+        responses::APIError,
+        sampling_params::{GenerationConfig, SamplingOptions},
+        OpenAIServerData,
+    },
+    scheduler::{
+        cache_engine::{CacheConfig, KVCacheDtype, KVCacheLayout},
+        SchedulerConfig, SchedulingPolicy,
     },
-    scheduler::{cache_engine::CacheConfig, SchedulerConfig},
     ModelSelected,
 };
 
@@ -24,22 +33,45 @@ async fn test_llama() -> Result<(), APIError> {
         Some(std::env::var("TESTS_HF_TOKEN").unwrap()),
         None,
     )?;
-    let model = loader.load_model(paths, DType::F16, Device::Cpu)?;
+    let model = loader.load_model(paths, DType::F16, Device::Cpu, &LoadOptions::default())?;
     let llm_engine = LLMEngine::new(
         model.0,
-        SchedulerConfig { max_num_seqs: 256 },
+        SchedulerConfig {
+            max_num_seqs: 256,
+            policy: SchedulingPolicy::Fcfs,
+            session_ttl: None,
+            max_num_batched_tokens: 4096,
+            max_prefill_fraction: None,
+            max_num_prefill_tokens: None,
+        },
         CacheConfig {
             block_size: 16,
             num_gpu_blocks: None,
             num_cpu_blocks: None,
             fully_init: false,
+            swap_space_bytes: 4 << 30,
+            enable_prefix_caching: false,
+            prefix_cache_quota: None,
+            cache_dtype: KVCacheDtype::Auto,
+            cache_layout: KVCacheLayout::Split,
+            attention_sinks: None,
         },
     )?;
 
     let server_data = OpenAIServerData {
-        pipeline_config: model.1,
+        pipeline_config: Arc::new(RwLock::new(model.1)),
+        abort_handle: llm_engine.get_abort_handle(),
+        metrics: llm_engine.get_metrics_handle(),
+        counters: llm_engine.get_replica_counters(),
         model: Arc::new(Mutex::new(llm_engine)),
         device: Device::Cpu,
+        replicas: Vec::new(),
+        placement: Arc::new(Mutex::new(ReplicaPlacer::new(
+            PlacementStrategy::RoundRobin,
+        ))),
+        conversations: None,
+        reloader: None,
+        other_models: Vec::new(),
     };
 
     let app = test::init_service(
@@ -84,6 +116,19 @@ async fn test_llama() -> Result<(), APIError> {
             skip_special_tokens: None,
             ignore_eos: None,
             stop_token_ids: None,
+            return_timings: None,
+            session_id: None,
+            ttft_slo_ms: None,
+            detokenize: None,
+            disable_prefix_cache: None,
+            disable_speculation: None,
+            stream_max_tokens_per_sec: None,
+            max_thinking_tokens: None,
+            attention_scale: None,
+            attention_temperature: None,
+            suffix: None,
+            tools: None,
+            add_generation_prompt: None,
         })
         .to_request();
 
@@ -92,3 +137,63 @@ async fn test_llama() -> Result<(), APIError> {
     println!("{:?}", resp.into_body());
     Ok(())
 }
+
+#[test]
+fn test_llama_ignores_prompts_exceeding_the_cache() -> Result<(), APIError> {
+    let (loader, model_id) = get_model_loader(ModelSelected::Llama7b { repeat_last_n: 64 });
+    let paths = loader.download_model(
+        model_id,
+        None,
+        Some(std::env::var("TESTS_HF_TOKEN").unwrap()),
+        None,
+    )?;
+    let model = loader.load_model(paths, DType::F16, Device::Cpu, &LoadOptions::default())?;
+    // The KV cache holds 64 tokens.
+    let mut llm_engine = LLMEngine::new(
+        model.0,
+        SchedulerConfig {
+            max_num_seqs: 256,
+            policy: SchedulingPolicy::Fcfs,
+            session_ttl: None,
+            max_num_batched_tokens: 4096,
+            max_prefill_fraction: None,
+            max_num_prefill_tokens: None,
+        },
+        CacheConfig {
+            block_size: 16,
+            num_gpu_blocks: Some(4),
+            num_cpu_blocks: Some(4),
+            fully_init: true,
+            swap_space_bytes: 0,
+            enable_prefix_caching: false,
+            prefix_cache_quota: None,
+            cache_dtype: KVCacheDtype::Auto,
+            cache_layout: KVCacheLayout::Split,
+            attention_sinks: None,
+        },
+    )?;
+    let sampling_params = SamplingOptions {
+        temperature: Some(0.),
+        max_tokens: Some(4),
+        ..Default::default()
+    }
+    .normalize(&GenerationConfig::default(), 0, usize::MAX)?;
+
+    let outputs = llm_engine.generate(
+        vec![
+            "Rust is".to_string(),
+            "Rust is a great language. ".repeat(32),
+        ],
+        sampling_params,
+    )?;
+    assert_eq!(outputs.len(), 2);
+    assert!(outputs.iter().all(|output| output.finished));
+    assert!(!outputs[0].outputs[0].token_ids.is_empty());
+    // The prompt which can never fit is finished without generating.
+    assert!(outputs[1].outputs[0].token_ids.is_empty());
+    assert_eq!(
+        outputs[1].outputs[0].finish_reason.as_deref(),
+        Some("length")
+    );
+    Ok(())
+}
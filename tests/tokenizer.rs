@@ -0,0 +1,102 @@
+use std::path::PathBuf;
+
+use base64::Engine;
+use candle_vllm::openai::pipelines::tokenizer::load_tokenizer;
+
+/// Write `bytes` to a file named `name` of its own in the temp directory.
+fn write_file(name: &str, bytes: &[u8]) -> PathBuf {
+    let filename = std::env::temp_dir().join(format!("candle-vllm-{}-{name}", std::process::id()));
+    std::fs::write(&filename, bytes).unwrap();
+    filename
+}
+
+/// A protobuf field with a varint value.
+fn varint_field(field: u64, value: u64) -> Vec<u8> {
+    let mut bytes = varint(field << 3);
+    bytes.extend(varint(value));
+    bytes
+}
+
+/// A protobuf field with a length-delimited value.
+fn bytes_field(field: u64, value: &[u8]) -> Vec<u8> {
+    let mut bytes = varint(field << 3 | 2);
+    bytes.extend(varint(value.len() as u64));
+    bytes.extend(value);
+    bytes
+}
+
+fn varint(mut value: u64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    while value >= 0x80 {
+        bytes.push((value as u8 & 0x7F) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+    bytes
+}
+
+/// A serialized SentencePiece piece of type `kind` (1 normal, 2 unknown, 3 control).
+fn piece(piece: &str, score: f32, kind: u64) -> Vec<u8> {
+    let mut bytes = bytes_field(1, piece.as_bytes());
+    bytes.extend(varint(2 << 3 | 5));
+    bytes.extend(score.to_bits().to_le_bytes());
+    bytes.extend(varint_field(3, kind));
+    bytes
+}
+
+#[test]
+fn sentencepiece_bpe_models_are_converted() {
+    let pieces = [
+        piece("<unk>", 0., 2),
+        piece("<s>", 0., 3),
+        piece("</s>", 0., 3),
+        piece("\u{2581}", 0., 1),
+        piece("a", 0., 1),
+        piece("b", 0., 1),
+        piece("\u{2581}a", -3., 1),
+        piece("ab", -4., 1),
+        piece("\u{2581}ab", -1., 1),
+    ];
+    let mut model = Vec::new();
+    for piece in &pieces {
+        model.extend(bytes_field(1, piece));
+    }
+    // A BPE model, with the dummy prefix.
+    model.extend(bytes_field(2, &varint_field(3, 2)));
+    model.extend(bytes_field(3, &varint_field(3, 1)));
+    let tokenizer = load_tokenizer(&write_file("tokenizer.model", &model)).unwrap();
+
+    // The merges apply in the order of the scores of their pieces.
+    let encoding = tokenizer.encode("ab", false).unwrap();
+    assert_eq!(encoding.get_ids(), [8]);
+    assert_eq!(tokenizer.decode(&[8], false).unwrap(), "ab");
+    assert_eq!(tokenizer.decode(&[3, 4, 5], false).unwrap(), "ab");
+    assert_eq!(tokenizer.token_to_id("</s>"), Some(2));
+}
+
+#[test]
+fn tiktoken_vocabularies_are_converted() {
+    let vocab = ["a", "b", "ab"]
+        .iter()
+        .enumerate()
+        .map(|(rank, token)| {
+            format!(
+                "{} {rank}\n",
+                base64::engine::general_purpose::STANDARD.encode(token)
+            )
+        })
+        .collect::<String>();
+    let tokenizer = load_tokenizer(&write_file("qwen.tiktoken", vocab.as_bytes())).unwrap();
+
+    let encoding = tokenizer.encode("abab", false).unwrap();
+    assert_eq!(encoding.get_ids(), [2, 2]);
+    assert_eq!(tokenizer.decode(&[2, 0, 1], false).unwrap(), "abab");
+    // The special tokens follow the vocabulary.
+    assert_eq!(tokenizer.token_to_id("<|endoftext|>"), Some(3));
+    assert_eq!(tokenizer.token_to_id("<|im_end|>"), Some(5));
+}
+
+#[test]
+fn other_files_are_rejected() {
+    assert!(load_tokenizer(&write_file("invalid.model", b"not a tokenizer")).is_err());
+}